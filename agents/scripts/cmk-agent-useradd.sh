@@ -29,13 +29,38 @@ WARNING: The agent controller is operating in an insecure mode! To secure the co
 HERE
 }
 
+_useradd() {
+    case "$(uname -s)" in
+        FreeBSD)
+            pw useradd "cmk-agent" -c "${comment}" -d "${HOMEDIR}" -s "${usershell}"
+            ;;
+        OpenBSD)
+            useradd -c "${comment}" -d "${HOMEDIR}" -s "${usershell}" -g =uid -L daemon "cmk-agent"
+            ;;
+        *)
+            useradd \
+                --comment "${comment}" \
+                --system \
+                --home-dir "${HOMEDIR}" \
+                --no-create-home \
+                --user-group \
+                --shell "${usershell}" \
+                "cmk-agent"
+            ;;
+    esac
+}
+
 main() {
     [ "$1" ] && usage
 
     # add cmk-agent system user
     echo "Creating/updating cmk-agent user account ..."
     comment="Checkmk agent system user"
-    usershell="/bin/false"
+    case "$(uname -s)" in
+        FreeBSD) usershell="/usr/sbin/nologin" ;;
+        OpenBSD) usershell="/sbin/nologin" ;;
+        *) usershell="/bin/false" ;;
+    esac
 
     if id "cmk-agent" >/dev/null 2>&1; then
         # check that the existing user is as expected
@@ -48,14 +73,7 @@ main() {
         fi
         unset existing expected
     else
-        useradd \
-            --comment "${comment}" \
-            --system \
-            --home-dir "${HOMEDIR}" \
-            --no-create-home \
-            --user-group \
-            --shell "${usershell}" \
-            "cmk-agent" || exit 1
+        _useradd || exit 1
         user_is_new="yes"
     fi
 
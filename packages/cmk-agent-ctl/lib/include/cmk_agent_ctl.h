@@ -0,0 +1,102 @@
+#ifndef CMK_AGENT_CTL_H
+#define CMK_AGENT_CTL_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit. Regenerate with ./run --library */
+
+#include <stdbool.h>
+#include <stdint.h>
+
+typedef enum CmkAgentCtlResult {
+  CMK_AGENT_CTL_RESULT_OK = 0,
+  CMK_AGENT_CTL_RESULT_ERROR = 1,
+} CmkAgentCtlResult;
+
+typedef struct CmkAgentCtlRegistration {
+  const char *server;
+  const char *site;
+  /**
+   * Port of the agent receiver, 0 queries it from the site
+   */
+  uint16_t port;
+  const char *user;
+  const char *password;
+  /**
+   * Host to register for, NULL lets the site create the host
+   */
+  const char *host_name;
+  /**
+   * Root certificate (PEM) to verify the server certificate with, may be NULL
+   */
+  const char *root_certificate;
+  /**
+   * Trust the server certificate without verifying it
+   */
+  bool trust_server_cert;
+  /**
+   * Use the proxy settings configured on this system
+   */
+  bool detect_proxy;
+} CmkAgentCtlRegistration;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Register at a site and save the new connection.
+ *
+ * # Safety
+ *
+ * home_dir and the strings of registration are NULL or NUL-terminated, error is NULL or
+ * writable.
+ */
+CmkAgentCtlResult cmk_agent_ctl_register(const char *home_dir,
+                                         const struct CmkAgentCtlRegistration *registration,
+                                         char **error);
+
+/**
+ * Delete the connection with the given site ID (server/site) or UUID.
+ *
+ * # Safety
+ *
+ * home_dir and connection are NULL or NUL-terminated, error is NULL or writable.
+ */
+CmkAgentCtlResult cmk_agent_ctl_delete(const char *home_dir, const char *connection, char **error);
+
+/**
+ * Renew the certificate of the connection with the given site ID (server/site) or UUID.
+ *
+ * # Safety
+ *
+ * home_dir and connection are NULL or NUL-terminated, error is NULL or writable.
+ */
+CmkAgentCtlResult cmk_agent_ctl_renew_certificate(const char *home_dir,
+                                                  const char *connection,
+                                                  char **error);
+
+/**
+ * The status of all connections as JSON, as reported by the status mode with --json.
+ *
+ * # Safety
+ *
+ * home_dir is NULL or NUL-terminated, status_json and error are NULL or writable.
+ */
+CmkAgentCtlResult cmk_agent_ctl_status_json(const char *home_dir,
+                                            bool query_remote,
+                                            char **status_json,
+                                            char **error);
+
+/**
+ * Release a string handed out by the controller.
+ *
+ * # Safety
+ *
+ * string is NULL or was handed out by the controller and is not released yet.
+ */
+void cmk_agent_ctl_free_string(char *string);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* CMK_AGENT_CTL_H */
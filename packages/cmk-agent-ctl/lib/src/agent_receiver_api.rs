@@ -0,0 +1,1541 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::{
+    certs, cli, config, constants, grpc, happy_eyeballs, http_trace, proxy, response_cache, types,
+};
+use anyhow::{Context, Result as AnyhowResult};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_with::DisplayFromStr;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::SystemTime;
+
+#[derive(Serialize)]
+struct RenewCertificateBody {
+    csr: String,
+}
+
+#[derive(Deserialize)]
+pub struct RenewCertificateResponse {
+    pub agent_cert: String,
+}
+
+#[serde_with::serde_as]
+#[derive(Serialize)]
+struct RegisterExistingBody {
+    #[serde_as(as = "DisplayFromStr")]
+    uuid: uuid::Uuid,
+    csr: String,
+    host_name: String,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterExistingResponse {
+    pub root_cert: String,
+    pub agent_cert: String,
+    pub connection_mode: config::ConnectionMode,
+}
+
+#[serde_with::serde_as]
+#[derive(Serialize)]
+struct RegisterNewBody {
+    #[serde_as(as = "DisplayFromStr")]
+    uuid: uuid::Uuid,
+    csr: String,
+    agent_labels: types::AgentLabels,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    approval_callback_url: Option<String>,
+}
+
+/// What is sent along with the CSR of a new host
+pub struct NewHost<'a> {
+    pub agent_labels: &'a types::AgentLabels,
+    pub approval_callback_url: Option<&'a reqwest::Url>,
+}
+
+#[derive(Serialize)]
+struct RenameHostBody<'a> {
+    host_name: &'a str,
+}
+
+#[derive(Serialize)]
+struct LabelUpdateBody<'a> {
+    agent_labels: &'a types::AgentLabels,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterNewResponse {
+    pub root_cert: String,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterNewOngoingResponseDeclined {
+    pub reason: String,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterNewOngoingResponseSuccess {
+    pub agent_cert: String,
+    pub connection_mode: config::ConnectionMode,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "status")]
+pub enum RegisterNewOngoingResponse {
+    InProgress,
+    Declined(RegisterNewOngoingResponseDeclined),
+    Success(RegisterNewOngoingResponseSuccess),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "status")]
+pub enum RegistrationStatusV2Response {
+    NotRegistered,
+    Registered(RegistrationStatusV2ResponseRegistered),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RegistrationStatusV2ResponseRegistered {
+    pub hostname: String,
+    pub connection_mode: config::ConnectionMode,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    pub detail: String,
+}
+
+pub trait Registration {
+    fn register_existing(
+        &self,
+        base_url: &reqwest::Url,
+        root_cert: &Option<&str>,
+        credentials: &types::Credentials,
+        uuid: &uuid::Uuid,
+        csr: &str,
+        host_name: &str,
+    ) -> AnyhowResult<RegisterExistingResponse>;
+
+    fn register_new(
+        &self,
+        base_url: &reqwest::Url,
+        root_cert: &Option<&str>,
+        credentials: &types::Credentials,
+        uuid: &uuid::Uuid,
+        csr: &str,
+        new_host: &NewHost,
+    ) -> AnyhowResult<RegisterNewResponse>;
+
+    /// The receiver may hold back its answer for up to `wait` seconds, until the registration is
+    /// approved or declined. Receivers without long polling answer right away.
+    fn register_new_ongoing(
+        &self,
+        base_url: &reqwest::Url,
+        root_cert: &str,
+        credentials: &types::Credentials,
+        uuid: &uuid::Uuid,
+        wait: u64,
+    ) -> AnyhowResult<RegisterNewOngoingResponse>;
+}
+
+/// Unexpected HTTP status returned by the agent receiver
+#[derive(Debug)]
+pub struct ResponseError {
+    pub status: StatusCode,
+    description: String,
+}
+
+impl ResponseError {
+    pub fn new(status: StatusCode, body: Option<String>) -> Self {
+        Self {
+            status,
+            description: Api::error_response_description(status, body),
+        }
+    }
+}
+
+impl std::fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.description)
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
+/// HTTP status of the receiver's response, if the error was caused by one
+pub fn response_status(error: &anyhow::Error) -> Option<StatusCode> {
+    if let Some(response_error) = error.downcast_ref::<ResponseError>() {
+        return Some(response_error.status);
+    }
+    error
+        .is::<UnsupportedCompression>()
+        .then_some(StatusCode::BAD_REQUEST)
+}
+
+/// The agent receiver rejected the compression algorithm of pushed agent data
+#[derive(Debug)]
+pub struct UnsupportedCompression(pub String);
+
+impl std::fmt::Display for UnsupportedCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedCompression {}
+
+pub trait AgentData {
+    fn agent_data(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        compression_algorithm: &str,
+        monitoring_data: &[u8],
+        collected_at: u64,
+    ) -> AnyhowResult<()>;
+}
+
+/// Push only the changes of the agent output since the last push, see the delta module.
+/// Receivers without support answer 404, those not holding the previous output 409.
+pub trait AgentDataDelta {
+    fn agent_data_delta(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        compression_algorithm: &str,
+        delta: &[u8],
+        collected_at: u64,
+    ) -> AnyhowResult<()>;
+}
+
+/// Identifies a chunked upload of an agent output. The ID is the same for every attempt to upload
+/// the same data, st. the receiver can resume it.
+pub struct ChunkedUpload<'a> {
+    pub id: String,
+    pub compression_algorithm: &'a str,
+    pub total_length: u64,
+    pub collected_at: u64,
+    /// Signature of the whole upload, see `payload_signature`
+    pub signature: Option<String>,
+}
+
+/// Push a large agent output in chunks, which the receiver keeps across dropped connections. The
+/// receiver answers every chunk with the number of bytes of the upload it holds, also if the
+/// chunk does not start there, st. the upload continues at that offset. The upload is complete
+/// once the receiver holds all of it. Receivers without support answer 404.
+pub trait AgentDataChunk {
+    fn agent_data_chunk(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        upload: &ChunkedUpload,
+        offset: u64,
+        chunk: &[u8],
+    ) -> AnyhowResult<u64>;
+}
+
+#[derive(Deserialize)]
+struct AgentDataChunkResponse {
+    offset: u64,
+}
+
+pub trait RealtimeData {
+    fn realtime_data(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        monitoring_data: &[u8],
+        collected_at: u64,
+    ) -> AnyhowResult<()>;
+}
+
+pub trait RegistrationStatusV2 {
+    fn registration_status_v2(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+    ) -> AnyhowResult<RegistrationStatusV2Response>;
+}
+
+pub trait ServerTime {
+    /// Current time of the agent receiver, taken from the Date header of its response
+    fn server_time(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+    ) -> AnyhowResult<std::time::SystemTime>;
+}
+
+pub trait RenewCertificate {
+    fn renew_certificate(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        csr: String,
+    ) -> AnyhowResult<RenewCertificateResponse>;
+}
+
+pub trait HostRename {
+    /// Assign a registered connection to another host of the site, after the host was renamed
+    fn rename_host(
+        &self,
+        base_url: &reqwest::Url,
+        root_cert: &str,
+        credentials: &types::Credentials,
+        uuid: &uuid::Uuid,
+        host_name: &str,
+    ) -> AnyhowResult<()>;
+}
+
+/// A controller binary the site offers instead of the running one
+#[derive(Deserialize)]
+pub struct ControllerUpdateResponse {
+    pub version: String,
+    /// Base64 encoded
+    pub binary: String,
+    /// Base64 encoded JSON of version, platform and hash of the binary, see
+    /// `self_update::Manifest`
+    pub manifest: String,
+    /// Base64 encoded signature of the manifest
+    pub signature: String,
+}
+
+pub trait ControllerUpdate {
+    /// The controller binary for the given platform, if the site has another version than the
+    /// running one. Whether it is newer is up to the caller.
+    fn controller_update(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        platform: &str,
+        version: &str,
+    ) -> AnyhowResult<Option<ControllerUpdateResponse>>;
+}
+
+pub trait LabelUpdate {
+    /// Send the current agent labels of a registered host, eg. after they changed since the
+    /// registration
+    fn update_labels(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        agent_labels: &types::AgentLabels,
+    ) -> AnyhowResult<()>;
+}
+
+pub trait CredentialsCheck {
+    /// Authenticated request without side effects, asks for the status of a registration which
+    /// does not exist. Returns the status code of the response.
+    fn check_credentials(
+        &self,
+        base_url: &reqwest::Url,
+        credentials: &types::Credentials,
+    ) -> AnyhowResult<StatusCode>;
+}
+
+/// Client of a trusted connection, together with the credentials and settings it was built with
+struct CachedClient {
+    certificate: String,
+    root_cert: String,
+    source_address: Option<IpAddr>,
+    pinned: Option<happy_eyeballs::Pinned>,
+    proxy_mode: proxy::ProxyMode,
+    request_headers: reqwest::header::HeaderMap,
+    timeouts: config::NetworkTimeouts,
+    http2_only: bool,
+    client: reqwest::blocking::Client,
+}
+
+/// Clients of trusted connections by connection UUID. Each client keeps a pool of idle
+/// connections to its receiver, so requests to the same receiver reuse the established TLS
+/// connection instead of doing another handshake from another ephemeral port. The client
+/// certificates differ, so connections to the same receiver cannot share a client.
+#[derive(Clone, Default)]
+struct ClientPool(Arc<Mutex<HashMap<uuid::Uuid, CachedClient>>>);
+
+impl ClientPool {
+    /// Shared by all instances of Api in the process, st. eg. the push and the renewal tasks of
+    /// the daemon use the same connections
+    fn shared() -> Self {
+        static SHARED: OnceLock<ClientPool> = OnceLock::new();
+        SHARED.get_or_init(ClientPool::default).clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<uuid::Uuid, CachedClient>> {
+        match self.0.lock() {
+            Ok(clients) => clients,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// With HTTP/2, concurrent requests to a receiver are multiplexed over a single connection, see
+/// ClientPool.
+pub struct Api {
+    proxy_mode: proxy::ProxyMode,
+    source_address: Option<IpAddr>,
+    ip_preference: happy_eyeballs::IpPreference,
+    request_headers: reqwest::header::HeaderMap,
+    timeouts: config::NetworkTimeouts,
+    clients: ClientPool,
+    response_cache: response_cache::ResponseCache,
+    clock_skew_observer: Option<ClockSkewObserver>,
+    sign_payloads: bool,
+    /// Protocol of registrations, trusted connections use the one they were registered with
+    receiver_protocol: cli::ReceiverProtocol,
+    /// Speak HTTP/2 without negotiating it, see transport
+    http2_only: bool,
+}
+
+/// Called with the clock skew measured for a trusted connection, see `clock_skew`
+type ClockSkewObserver = Box<dyn Fn(&uuid::Uuid, i64) + Send + Sync>;
+
+/// Signature of a pushed agent output, independent of the transport, st. receivers behind
+/// TLS-terminating load balancers can still verify it against the certificate of the connection.
+/// It is the base64-encoded SHA-256 signature of `<collected-at>\n<compression>\n<payload>` with
+/// the private key of the connection, sent in the `signature` header.
+pub fn payload_signature(
+    connection: &config::TrustedConnection,
+    compression_algorithm: &str,
+    collected_at: u64,
+    payload: &[u8],
+) -> AnyhowResult<String> {
+    let signature = certs::sign(
+        &connection.private_key,
+        &[
+            format!("{collected_at}\n{compression_algorithm}\n").as_bytes(),
+            payload,
+        ],
+    )
+    .context("Failed to sign agent output")?;
+    Ok(openssl::base64::encode_block(&signature))
+}
+
+/// Add the signature header, if the payload is signed
+fn with_signature(
+    request: reqwest::blocking::RequestBuilder,
+    signature: Option<String>,
+) -> reqwest::blocking::RequestBuilder {
+    match signature {
+        Some(signature) => request.header("signature", signature),
+        None => request,
+    }
+}
+
+/// Clock skew (in seconds) versus the agent receiver according to the Date header of its
+/// response, positive if the receiver is ahead. The header has a resolution of one second.
+pub fn clock_skew(headers: &reqwest::header::HeaderMap, local: SystemTime) -> Option<i64> {
+    let remote =
+        httpdate::parse_http_date(headers.get(reqwest::header::DATE)?.to_str().ok()?).ok()?;
+    Some(match remote.duration_since(local) {
+        Ok(ahead) => ahead.as_secs() as i64,
+        Err(behind) => -(behind.duration().as_secs() as i64),
+    })
+}
+
+impl Api {
+    pub fn new(client_config: &config::ClientConfig) -> Self {
+        Self {
+            proxy_mode: client_config.proxy_mode,
+            source_address: client_config.source_address,
+            ip_preference: client_config.ip_preference,
+            request_headers: client_config.request_headers.clone(),
+            timeouts: client_config.timeouts,
+            clients: ClientPool::shared(),
+            response_cache: response_cache::ResponseCache::default(),
+            clock_skew_observer: None,
+            sign_payloads: false,
+            receiver_protocol: cli::ReceiverProtocol::Rest,
+            http2_only: false,
+        }
+    }
+
+    /// Register via gRPC instead of REST, see grpc
+    pub fn with_receiver_protocol(self, receiver_protocol: cli::ReceiverProtocol) -> Self {
+        Self {
+            receiver_protocol,
+            ..self
+        }
+    }
+
+    /// Only speak HTTP/2, for the transport of the same name
+    pub fn with_http2_only(self, http2_only: bool) -> Self {
+        Self { http2_only, ..self }
+    }
+
+    /// Send a signature with every pushed agent output, see `payload_signature`
+    pub fn with_payload_signing(self, sign_payloads: bool) -> Self {
+        Self {
+            sign_payloads,
+            ..self
+        }
+    }
+
+    pub fn signature(
+        &self,
+        connection: &config::TrustedConnection,
+        compression_algorithm: &str,
+        collected_at: u64,
+        payload: &[u8],
+    ) -> AnyhowResult<Option<String>> {
+        self.sign_payloads
+            .then(|| payload_signature(connection, compression_algorithm, collected_at, payload))
+            .transpose()
+    }
+
+    /// Measure the clock skew on every response to a trusted connection
+    pub fn with_clock_skew_observer(
+        self,
+        observer: impl Fn(&uuid::Uuid, i64) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            clock_skew_observer: Some(Box::new(observer)),
+            ..self
+        }
+    }
+
+    /// Local address to connect from, the one of the connection takes precedence
+    pub fn source_address(&self, connection: &config::TrustedConnection) -> Option<IpAddr> {
+        connection.source_address.or(self.source_address)
+    }
+
+    pub fn ip_preference(&self) -> happy_eyeballs::IpPreference {
+        self.ip_preference
+    }
+
+    /// Resolved again for every request, st. the clients follow changes of the DNS
+    fn trusted_client(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+    ) -> AnyhowResult<reqwest::blocking::Client> {
+        let pinned = happy_eyeballs::Pinned::new(base_url, self.ip_preference);
+        let mut clients = self.clients.lock();
+        if let Some(cached) = clients.get(&connection.uuid) {
+            // Renewed certificates require a new client, as do Apis configured differently
+            if cached.certificate == connection.certificate
+                && cached.root_cert == connection.root_cert
+                && cached.source_address == self.source_address(connection)
+                && cached.pinned == pinned
+                && cached.proxy_mode == self.proxy_mode
+                && cached.request_headers == self.request_headers
+                && cached.timeouts == self.timeouts
+                && cached.http2_only == self.http2_only
+            {
+                return Ok(cached.client.clone());
+            }
+        }
+        let client = certs::client(
+            Some(connection.tls_handshake_credentials()?),
+            self.proxy_mode,
+            self.source_address(connection),
+            pinned.as_ref(),
+            &self.request_headers,
+            &self.timeouts,
+            self.http2_only,
+        )?;
+        clients.insert(
+            connection.uuid,
+            CachedClient {
+                certificate: connection.certificate.clone(),
+                root_cert: connection.root_cert.clone(),
+                source_address: self.source_address(connection),
+                pinned,
+                proxy_mode: self.proxy_mode,
+                request_headers: self.request_headers.clone(),
+                timeouts: self.timeouts,
+                http2_only: self.http2_only,
+                client: client.clone(),
+            },
+        );
+        Ok(client)
+    }
+
+    fn endpoint_url(
+        base_url: &reqwest::Url,
+        endpoint_segments: &[&str],
+    ) -> AnyhowResult<reqwest::Url> {
+        // The API of reqwest::Url for extending existing urls is strange (reqwest::Url::join), see
+        // also https://github.com/servo/rust-url/issues/333
+        reqwest::Url::parse(
+            &(base_url.to_string() + "/agent-receiver/" + &endpoint_segments.join("/")),
+        )
+        .context(format!(
+            "Failed to construct agent receiver API endpoint URL from base URL {} and segments {}",
+            base_url,
+            endpoint_segments.join(", ")
+        ))
+    }
+
+    /// Sends the request. With HTTP tracing enabled, the request and the response are logged.
+    fn send(
+        client: &reqwest::blocking::Client,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> reqwest::Result<reqwest::blocking::Response> {
+        if !http_trace::enabled() {
+            return request.send();
+        }
+        let request = request.build()?;
+        http_trace::log_request(&request);
+        match client.execute(request) {
+            Ok(response) => http_trace::log_response(response),
+            Err(err) => {
+                http_trace::log_failure(&err);
+                Err(err)
+            }
+        }
+    }
+
+    fn send_trusted(
+        &self,
+        connection: &config::TrustedConnection,
+        client: &reqwest::blocking::Client,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> reqwest::Result<reqwest::blocking::Response> {
+        let response = Self::send(client, request)?;
+        if let Some(observer) = &self.clock_skew_observer {
+            if let Some(skew) = clock_skew(response.headers(), SystemTime::now()) {
+                observer(&connection.uuid, skew);
+            }
+        }
+        Ok(response)
+    }
+
+    /// GET an endpoint whose responses are revalidated instead of transferred again, see
+    /// response_cache
+    fn get_cached<T>(
+        &self,
+        connection: &config::TrustedConnection,
+        client: &reqwest::blocking::Client,
+        url: reqwest::Url,
+        deserializer: fn(&str) -> serde_json::Result<T>,
+    ) -> AnyhowResult<T> {
+        let response = self.send_trusted(
+            connection,
+            client,
+            client
+                .get(url.clone())
+                .headers(self.response_cache.validators(&url)),
+        )?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.text().context("Failed to obtain response body")?;
+        let Some(body) = self
+            .response_cache
+            .resolve(&url, status, &headers, body.clone())
+        else {
+            return Err(ResponseError::new(status, Some(body)).into());
+        };
+        deserializer(&body).context(format!("Error parsing this response body: {body}"))
+    }
+
+    fn deserialize_json_response<T>(
+        response: reqwest::blocking::Response,
+        deserializer: fn(&str) -> serde_json::Result<T>,
+    ) -> AnyhowResult<T> {
+        let status = response.status();
+        if status != StatusCode::OK {
+            return Err(ResponseError::new(status, response.text().ok()).into());
+        }
+        let body = response.text().context("Failed to obtain response body")?;
+        deserializer(&body).context(format!("Error parsing this response body: {body}"))
+    }
+
+    fn error_response_description(status: StatusCode, body: Option<String>) -> String {
+        match body {
+            None => format!("Request failed with code {status}, could not obtain response body"),
+            Some(body) => format!(
+                "Request failed with code {}: {}",
+                status,
+                if let Ok(error_response) = serde_json::from_str::<ErrorResponse>(&body) {
+                    error_response.detail
+                } else {
+                    body
+                }
+            ),
+        }
+    }
+
+    fn check_response_204(response: reqwest::blocking::Response) -> AnyhowResult<()> {
+        let status = response.status();
+        if status == StatusCode::NO_CONTENT {
+            Ok(())
+        } else {
+            Err(ResponseError::new(status, response.text().ok()).into())
+        }
+    }
+
+    fn check_agent_data_response(response: reqwest::blocking::Response) -> AnyhowResult<()> {
+        if response.status() == StatusCode::BAD_REQUEST {
+            return Err(Api::agent_data_error(
+                ResponseError::new(response.status(), response.text().ok()).into(),
+            ));
+        }
+        Api::check_response_204(response)
+    }
+
+    /// A rejected compression algorithm is reported as UnsupportedCompression
+    fn agent_data_error(error: anyhow::Error) -> anyhow::Error {
+        match error.downcast::<ResponseError>() {
+            Ok(error)
+                if error.status == StatusCode::BAD_REQUEST
+                    && error
+                        .description
+                        .contains("Unsupported compression algorithm") =>
+            {
+                UnsupportedCompression(error.description).into()
+            }
+            Ok(error) => error.into(),
+            Err(error) => error,
+        }
+    }
+
+    fn uses_grpc(connection: &config::TrustedConnection) -> bool {
+        connection.receiver_protocol == cli::ReceiverProtocol::Grpc
+    }
+
+    fn call_grpc_trusted<T>(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        call: grpc::Call<T>,
+    ) -> AnyhowResult<T> {
+        let client = self.trusted_client(base_url, connection)?;
+        call.response(self.send_trusted(connection, &client, call.request(&client, base_url)?)?)
+    }
+
+    fn call_grpc_registration<T>(
+        &self,
+        base_url: &reqwest::Url,
+        root_cert: &Option<&str>,
+        credentials: &types::Credentials,
+        call: grpc::Call<T>,
+        timeouts: &config::NetworkTimeouts,
+    ) -> AnyhowResult<T> {
+        let client = certs::client(
+            root_cert.map(|r| certs::HandshakeCredentials {
+                server_root_cert: r,
+                client_identity: None,
+            }),
+            self.proxy_mode,
+            self.source_address,
+            happy_eyeballs::Pinned::new(base_url, self.ip_preference).as_ref(),
+            &self.request_headers,
+            timeouts,
+            self.http2_only,
+        )?;
+        call.response(
+            Self::send(
+                &client,
+                call.request(&client, base_url)?
+                    .basic_auth(&credentials.username, Some(&credentials.password)),
+            )
+            .context("Calling registration method failed")?,
+        )
+    }
+}
+
+impl RenewCertificate for Api {
+    fn renew_certificate(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        csr: String,
+    ) -> AnyhowResult<RenewCertificateResponse> {
+        if Self::uses_grpc(connection) {
+            return self.call_grpc_trusted(
+                base_url,
+                connection,
+                grpc::renew_certificate(&connection.uuid, &csr),
+            );
+        }
+        let client = self.trusted_client(base_url, connection)?;
+        Self::deserialize_json_response(
+            self.send_trusted(
+                connection,
+                &client,
+                client
+                    .post(Self::endpoint_url(
+                        base_url,
+                        &["renew_certificate", &connection.uuid.to_string()],
+                    )?)
+                    .json(&RenewCertificateBody { csr }),
+            )?,
+            |body| serde_json::from_str::<RenewCertificateResponse>(body),
+        )
+    }
+}
+
+impl Registration for Api {
+    fn register_existing(
+        &self,
+        base_url: &reqwest::Url,
+        root_cert: &Option<&str>,
+        credentials: &types::Credentials,
+        uuid: &uuid::Uuid,
+        csr: &str,
+        host_name: &str,
+    ) -> AnyhowResult<RegisterExistingResponse> {
+        if self.receiver_protocol == cli::ReceiverProtocol::Grpc {
+            return self.call_grpc_registration(
+                base_url,
+                root_cert,
+                credentials,
+                grpc::register_existing(uuid, csr, host_name),
+                &self.timeouts,
+            );
+        }
+        self.call_registration_init_endpoint(
+            Self::endpoint_url(base_url, &["register_existing"])?,
+            root_cert,
+            credentials,
+            &RegisterExistingBody {
+                uuid: uuid.to_owned(),
+                csr: csr.to_owned(),
+                host_name: String::from(host_name),
+            },
+            |body| serde_json::from_str::<RegisterExistingResponse>(body),
+        )
+    }
+
+    fn register_new(
+        &self,
+        base_url: &reqwest::Url,
+        root_cert: &Option<&str>,
+        credentials: &types::Credentials,
+        uuid: &uuid::Uuid,
+        csr: &str,
+        new_host: &NewHost,
+    ) -> AnyhowResult<RegisterNewResponse> {
+        if self.receiver_protocol == cli::ReceiverProtocol::Grpc {
+            return self.call_grpc_registration(
+                base_url,
+                root_cert,
+                credentials,
+                grpc::register_new(uuid, csr, new_host),
+                &self.timeouts,
+            );
+        }
+        self.call_registration_init_endpoint(
+            Self::endpoint_url(base_url, &["register_new"])?,
+            root_cert,
+            credentials,
+            &RegisterNewBody {
+                uuid: uuid.to_owned(),
+                csr: csr.to_owned(),
+                agent_labels: new_host.agent_labels.clone(),
+                approval_callback_url: new_host.approval_callback_url.map(|url| url.to_string()),
+            },
+            |body| serde_json::from_str::<RegisterNewResponse>(body),
+        )
+    }
+
+    fn register_new_ongoing(
+        &self,
+        base_url: &reqwest::Url,
+        root_cert: &str,
+        credentials: &types::Credentials,
+        uuid: &uuid::Uuid,
+        wait: u64,
+    ) -> AnyhowResult<RegisterNewOngoingResponse> {
+        let timeouts = self.timeouts.long_poll(wait);
+        if self.receiver_protocol == cli::ReceiverProtocol::Grpc {
+            return self.call_grpc_registration(
+                base_url,
+                &Some(root_cert),
+                credentials,
+                grpc::register_new_ongoing(uuid, wait),
+                &timeouts,
+            );
+        }
+        let client = certs::client(
+            Some(certs::HandshakeCredentials {
+                server_root_cert: root_cert,
+                client_identity: None,
+            }),
+            self.proxy_mode,
+            self.source_address,
+            happy_eyeballs::Pinned::new(base_url, self.ip_preference).as_ref(),
+            &self.request_headers,
+            &timeouts,
+            self.http2_only,
+        )?;
+        let mut url = Self::endpoint_url(base_url, &["register_new_ongoing", &uuid.to_string()])?;
+        if wait > 0 {
+            url.query_pairs_mut().append_pair("wait", &wait.to_string());
+        }
+        Self::deserialize_json_response(
+            Self::send(
+                &client,
+                client
+                    .post(url)
+                    .basic_auth(&credentials.username, Some(&credentials.password)),
+            )
+            .context("Calling register_new_ongoing endpoint failed")?,
+            |body| serde_json::from_str::<RegisterNewOngoingResponse>(body),
+        )
+    }
+}
+
+impl CredentialsCheck for Api {
+    fn check_credentials(
+        &self,
+        base_url: &reqwest::Url,
+        credentials: &types::Credentials,
+    ) -> AnyhowResult<StatusCode> {
+        let client = certs::client(
+            None,
+            self.proxy_mode,
+            self.source_address,
+            happy_eyeballs::Pinned::new(base_url, self.ip_preference).as_ref(),
+            &self.request_headers,
+            &self.timeouts,
+            self.http2_only,
+        )?;
+        let uuid = uuid::Uuid::new_v4();
+        if self.receiver_protocol == cli::ReceiverProtocol::Grpc {
+            return grpc::status(
+                &Self::send(
+                    &client,
+                    grpc::register_new_ongoing(&uuid, 0)
+                        .request(&client, base_url)?
+                        .basic_auth(&credentials.username, Some(&credentials.password)),
+                )
+                .context("Calling RegisterNewOngoing method failed")?,
+            );
+        }
+        Ok(Self::send(
+            &client,
+            client
+                .post(Self::endpoint_url(
+                    base_url,
+                    &["register_new_ongoing", &uuid.to_string()],
+                )?)
+                .basic_auth(&credentials.username, Some(&credentials.password)),
+        )
+        .context("Calling register_new_ongoing endpoint failed")?
+        .status())
+    }
+}
+
+impl Api {
+    fn call_registration_init_endpoint<'a, T>(
+        &self,
+        url: reqwest::Url,
+        root_cert: &Option<&str>,
+        credentials: &types::Credentials,
+        body: &impl Serialize,
+        deserializer: fn(&str) -> serde_json::Result<T>,
+    ) -> AnyhowResult<T>
+    where
+        T: Deserialize<'a>,
+    {
+        let client = certs::client(
+            root_cert.map(|r| certs::HandshakeCredentials {
+                server_root_cert: r,
+                client_identity: None,
+            }),
+            self.proxy_mode,
+            self.source_address,
+            happy_eyeballs::Pinned::new(&url, self.ip_preference).as_ref(),
+            &self.request_headers,
+            &self.timeouts,
+            self.http2_only,
+        )?;
+        Self::deserialize_json_response(
+            Self::send(
+                &client,
+                client
+                    .post(url)
+                    .basic_auth(&credentials.username, Some(&credentials.password))
+                    .json(body),
+            )
+            .context("Calling registration endpoint failed")?,
+            deserializer,
+        )
+    }
+}
+
+impl AgentData for Api {
+    fn agent_data(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        compression_algorithm: &str,
+        monitoring_data: &[u8],
+        collected_at: u64,
+    ) -> AnyhowResult<()> {
+        let signature = self.signature(
+            connection,
+            compression_algorithm,
+            collected_at,
+            monitoring_data,
+        )?;
+        let client = self.trusted_client(base_url, connection)?;
+        if Self::uses_grpc(connection) {
+            let call = grpc::upload_agent_data(
+                &connection.uuid,
+                compression_algorithm,
+                collected_at,
+                signature.as_deref(),
+                monitoring_data,
+            );
+            return call
+                .response(
+                    self.send_trusted(
+                        connection,
+                        &client,
+                        call.request(&client, base_url)?
+                            .timeout(self.timeouts.capped(constants::PUSH_TIMEOUT)),
+                    )?,
+                )
+                .map_err(Api::agent_data_error);
+        }
+        let response = self.send_trusted(
+            connection,
+            &client,
+            with_signature(
+                client
+                    .post(Self::endpoint_url(
+                        base_url,
+                        &["agent_data", &connection.uuid.to_string()],
+                    )?)
+                    .timeout(self.timeouts.capped(constants::PUSH_TIMEOUT))
+                    .header("compression", compression_algorithm)
+                    // Unix timestamp of the collection, differs from the time of sending for
+                    // replayed data
+                    .header("collected-at", collected_at),
+                signature,
+            )
+            .multipart(
+                reqwest::blocking::multipart::Form::new().part(
+                    "monitoring_data",
+                    reqwest::blocking::multipart::Part::bytes(monitoring_data.to_owned())
+                        // Note: We need to set the file name, otherwise the request won't have
+                        // the right format. However, the value itself does not matter.
+                        .file_name("agent_data"),
+                ),
+            ),
+        )?;
+        Api::check_agent_data_response(response)
+    }
+}
+
+impl AgentDataDelta for Api {
+    fn agent_data_delta(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        compression_algorithm: &str,
+        delta: &[u8],
+        collected_at: u64,
+    ) -> AnyhowResult<()> {
+        if Self::uses_grpc(connection) {
+            return Err(grpc::unsupported("Delta upload"));
+        }
+        let signature = self.signature(connection, compression_algorithm, collected_at, delta)?;
+        let client = self.trusted_client(base_url, connection)?;
+        let response = self.send_trusted(
+            connection,
+            &client,
+            with_signature(
+                client
+                    .post(Self::endpoint_url(
+                        base_url,
+                        &["agent_data_delta", &connection.uuid.to_string()],
+                    )?)
+                    .timeout(self.timeouts.capped(constants::PUSH_TIMEOUT))
+                    .header("compression", compression_algorithm)
+                    .header("collected-at", collected_at),
+                signature,
+            )
+            .multipart(
+                reqwest::blocking::multipart::Form::new().part(
+                    "monitoring_data_delta",
+                    reqwest::blocking::multipart::Part::bytes(delta.to_owned())
+                        .file_name("agent_data_delta"),
+                ),
+            ),
+        )?;
+        Api::check_agent_data_response(response)
+    }
+}
+
+impl AgentDataChunk for Api {
+    fn agent_data_chunk(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        upload: &ChunkedUpload,
+        offset: u64,
+        chunk: &[u8],
+    ) -> AnyhowResult<u64> {
+        if Self::uses_grpc(connection) {
+            return Err(grpc::unsupported("Chunked upload"));
+        }
+        let client = self.trusted_client(base_url, connection)?;
+        let response = self.send_trusted(
+            connection,
+            &client,
+            with_signature(
+                client
+                    .put(Self::endpoint_url(
+                        base_url,
+                        &[
+                            "agent_data_upload",
+                            &connection.uuid.to_string(),
+                            &upload.id,
+                        ],
+                    )?)
+                    .timeout(self.timeouts.capped(constants::PUSH_TIMEOUT))
+                    .header("compression", upload.compression_algorithm)
+                    .header("collected-at", upload.collected_at)
+                    .header("upload-offset", offset)
+                    .header("upload-length", upload.total_length),
+                upload.signature.clone(),
+            )
+            .body(chunk.to_owned()),
+        )?;
+        if response.status() == StatusCode::BAD_REQUEST {
+            Api::check_agent_data_response(response)?;
+            anyhow::bail!("Agent receiver rejected the chunk");
+        }
+        Ok(Self::deserialize_json_response(response, |body| {
+            serde_json::from_str::<AgentDataChunkResponse>(body)
+        })?
+        .offset)
+    }
+}
+
+impl RealtimeData for Api {
+    fn realtime_data(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        monitoring_data: &[u8],
+        collected_at: u64,
+    ) -> AnyhowResult<()> {
+        if Self::uses_grpc(connection) {
+            return Err(grpc::unsupported("Real-time data"));
+        }
+        let client = self.trusted_client(base_url, connection)?;
+        Api::check_response_204(
+            self.send_trusted(
+                connection,
+                &client,
+                client
+                    .post(Self::endpoint_url(
+                        base_url,
+                        &["realtime_data", &connection.uuid.to_string()],
+                    )?)
+                    .timeout(self.timeouts.capped(constants::REALTIME_TIMEOUT))
+                    .header("compression", "zlib")
+                    .header("collected-at", collected_at)
+                    .body(monitoring_data.to_owned()),
+            )?,
+        )
+    }
+}
+
+impl HostRename for Api {
+    fn rename_host(
+        &self,
+        base_url: &reqwest::Url,
+        root_cert: &str,
+        credentials: &types::Credentials,
+        uuid: &uuid::Uuid,
+        host_name: &str,
+    ) -> AnyhowResult<()> {
+        if self.receiver_protocol == cli::ReceiverProtocol::Grpc {
+            return Err(grpc::unsupported("Renaming hosts"));
+        }
+        let client = certs::client(
+            Some(certs::HandshakeCredentials {
+                server_root_cert: root_cert,
+                client_identity: None,
+            }),
+            self.proxy_mode,
+            self.source_address,
+            happy_eyeballs::Pinned::new(base_url, self.ip_preference).as_ref(),
+            &self.request_headers,
+            &self.timeouts,
+            self.http2_only,
+        )?;
+        Api::check_response_204(
+            Self::send(
+                &client,
+                client
+                    .post(Self::endpoint_url(
+                        base_url,
+                        &["rename_host", &uuid.to_string()],
+                    )?)
+                    .basic_auth(&credentials.username, Some(&credentials.password))
+                    .json(&RenameHostBody { host_name }),
+            )
+            .context("Calling rename_host endpoint failed")?,
+        )
+    }
+}
+
+impl ControllerUpdate for Api {
+    fn controller_update(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        platform: &str,
+        version: &str,
+    ) -> AnyhowResult<Option<ControllerUpdateResponse>> {
+        if Self::uses_grpc(connection) {
+            return Err(grpc::unsupported("Controller update"));
+        }
+        let client = self.trusted_client(base_url, connection)?;
+        let response = self.send_trusted(
+            connection,
+            &client,
+            client
+                .get(Self::endpoint_url(
+                    base_url,
+                    &["controller_update", &connection.uuid.to_string()],
+                )?)
+                .query(&[("platform", platform), ("version", version)]),
+        )?;
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Self::deserialize_json_response(response, |body| {
+            serde_json::from_str::<ControllerUpdateResponse>(body)
+        })
+        .map(Some)
+    }
+}
+
+impl LabelUpdate for Api {
+    fn update_labels(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        agent_labels: &types::AgentLabels,
+    ) -> AnyhowResult<()> {
+        if Self::uses_grpc(connection) {
+            return Err(grpc::unsupported("Label update"));
+        }
+        let client = self.trusted_client(base_url, connection)?;
+        Api::check_response_204(
+            self.send_trusted(
+                connection,
+                &client,
+                client
+                    .post(Self::endpoint_url(
+                        base_url,
+                        &["labels", &connection.uuid.to_string()],
+                    )?)
+                    .json(&LabelUpdateBody { agent_labels }),
+            )?,
+        )
+    }
+}
+
+impl RegistrationStatusV2 for Api {
+    fn registration_status_v2(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+    ) -> AnyhowResult<RegistrationStatusV2Response> {
+        if Self::uses_grpc(connection) {
+            return self.call_grpc_trusted(
+                base_url,
+                connection,
+                grpc::registration_status(&connection.uuid),
+            );
+        }
+        let client = self.trusted_client(base_url, connection)?;
+        self.get_cached(
+            connection,
+            &client,
+            Self::endpoint_url(
+                base_url,
+                &["registration_status_v2", &connection.uuid.to_string()],
+            )?,
+            |body| serde_json::from_str::<RegistrationStatusV2Response>(body),
+        )
+    }
+}
+
+impl ServerTime for Api {
+    fn server_time(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+    ) -> AnyhowResult<std::time::SystemTime> {
+        let client = self.trusted_client(base_url, connection)?;
+        let request = match Self::uses_grpc(connection) {
+            true => grpc::registration_status(&connection.uuid).request(&client, base_url)?,
+            false => client.get(Self::endpoint_url(
+                base_url,
+                &["registration_status_v2", &connection.uuid.to_string()],
+            )?),
+        };
+        let response = self.send_trusted(connection, &client, request)?;
+        let date = response
+            .headers()
+            .get(reqwest::header::DATE)
+            .context("Response of agent receiver has no Date header")?;
+        httpdate::parse_http_date(date.to_str()?)
+            .context(format!("Failed to parse Date header {:?}", date))
+    }
+}
+
+#[cfg(test)]
+mod test_api {
+    use super::*;
+
+    #[test]
+    fn test_trusted_client_is_reused() {
+        let api = Api {
+            clients: ClientPool::default(),
+            ..Api::new(&client_config(None))
+        };
+        let base_url = reqwest::Url::parse("https://localhost:8000/site").unwrap();
+        let mut connection = config::TrustedConnection {
+            uuid: uuid::Uuid::new_v4(),
+            private_key: certs::make_csr("heute").unwrap().1,
+            certificate: String::from(constants::TEST_CERT_OK),
+            root_cert: String::from(constants::TEST_ROOT_CERT),
+            source_address: None,
+            receiver_protocol: Default::default(),
+            transport: Default::default(),
+            data_source: None,
+        };
+        api.trusted_client(&base_url, &connection).unwrap();
+        api.trusted_client(&base_url, &connection).unwrap();
+        assert_eq!(api.clients.lock().len(), 1);
+
+        connection.certificate = String::from(constants::TEST_CERT_CN_UUID);
+        api.trusted_client(&base_url, &connection).unwrap();
+        {
+            let clients = api.clients.lock();
+            assert_eq!(clients.len(), 1);
+            assert_eq!(
+                clients[&connection.uuid].certificate,
+                constants::TEST_CERT_CN_UUID
+            );
+        }
+
+        // Shared with other Apis, as long as they are configured the same
+        let other = Api {
+            clients: api.clients.clone(),
+            ..Api::new(&client_config(None))
+        };
+        other.trusted_client(&base_url, &connection).unwrap();
+        assert_eq!(api.clients.lock()[&connection.uuid].source_address, None);
+        let other = Api {
+            clients: api.clients.clone(),
+            ..Api::new(&client_config(Some(IpAddr::from([127, 0, 0, 1]))))
+        };
+        other.trusted_client(&base_url, &connection).unwrap();
+        assert_eq!(
+            api.clients.lock()[&connection.uuid].source_address,
+            Some(IpAddr::from([127, 0, 0, 1]))
+        );
+    }
+
+    fn client_config(source_address: Option<IpAddr>) -> config::ClientConfig {
+        config::ClientConfig {
+            proxy_mode: proxy::ProxyMode::Direct,
+            validate_api_cert: false,
+            source_address,
+            ip_preference: happy_eyeballs::IpPreference::Ipv6,
+            request_headers: Default::default(),
+            timeouts: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_source_address() {
+        let mut connection =
+            config::TrustedConnection::from("0096abd7-83c9-42f8-8b3a-3ffba7ba959d");
+        let global = IpAddr::from([10, 0, 0, 1]);
+        let own = IpAddr::from([192, 168, 0, 1]);
+        assert_eq!(
+            Api::new(&client_config(None)).source_address(&connection),
+            None
+        );
+        assert_eq!(
+            Api::new(&client_config(Some(global))).source_address(&connection),
+            Some(global)
+        );
+        connection.source_address = Some(own);
+        assert_eq!(
+            Api::new(&client_config(Some(global))).source_address(&connection),
+            Some(own)
+        );
+    }
+
+    #[test]
+    fn test_grpc_unsupported_falls_back() {
+        let connection = config::TrustedConnection {
+            receiver_protocol: cli::ReceiverProtocol::Grpc,
+            ..config::test_helpers::trusted_connection()
+        };
+        let error = Api::new(&client_config(None))
+            .agent_data_delta(
+                &reqwest::Url::parse("https://server:8000/site").unwrap(),
+                &connection,
+                "zlib",
+                b"delta",
+                1000,
+            )
+            .unwrap_err();
+        assert_eq!(response_status(&error), Some(StatusCode::NOT_FOUND));
+    }
+
+    #[test]
+    fn test_agent_data_error() {
+        let error = Api::agent_data_error(
+            ResponseError::new(
+                StatusCode::BAD_REQUEST,
+                Some(String::from("Unsupported compression algorithm zstd")),
+            )
+            .into(),
+        );
+        assert!(error.is::<UnsupportedCompression>());
+        let error = Api::agent_data_error(
+            ResponseError::new(StatusCode::BAD_REQUEST, Some(String::from("Bad"))).into(),
+        );
+        assert!(error.is::<ResponseError>());
+    }
+
+    #[test]
+    fn test_signature() {
+        let (root_cert, root_key) = certs::make_ca("root", 1).unwrap();
+        let (csr, private_key) = certs::make_csr("heute").unwrap();
+        let connection = config::TrustedConnection {
+            uuid: uuid::Uuid::new_v4(),
+            private_key,
+            certificate: certs::sign_csr(&root_cert, &root_key, &csr, 1).unwrap(),
+            root_cert,
+            source_address: None,
+            receiver_protocol: Default::default(),
+            transport: Default::default(),
+            data_source: None,
+        };
+        let api = Api::new(&client_config(None));
+        assert_eq!(
+            api.signature(&connection, "zlib", 1000, b"data").unwrap(),
+            None
+        );
+        let signature = api
+            .with_payload_signing(true)
+            .signature(&connection, "zlib", 1000, b"data")
+            .unwrap()
+            .unwrap();
+        let public_key = openssl::x509::X509::from_pem(connection.certificate.as_bytes())
+            .unwrap()
+            .public_key()
+            .unwrap();
+        let verify = |message: &[u8]| {
+            let mut verifier =
+                openssl::sign::Verifier::new(openssl::hash::MessageDigest::sha256(), &public_key)
+                    .unwrap();
+            verifier.update(message).unwrap();
+            verifier
+                .verify(&openssl::base64::decode_block(&signature).unwrap())
+                .unwrap()
+        };
+        assert!(verify(b"1000\nzlib\ndata"));
+        assert!(!verify(b"1001\nzlib\ndata"));
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let local = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        let headers = |date: &str| {
+            let mut headers = reqwest::header::HeaderMap::new();
+            headers.insert(reqwest::header::DATE, date.parse().unwrap());
+            headers
+        };
+        assert_eq!(
+            clock_skew(&headers("Sun, 06 Nov 1994 08:51:37 GMT"), local),
+            Some(120)
+        );
+        assert_eq!(
+            clock_skew(&headers("Sun, 06 Nov 1994 08:49:00 GMT"), local),
+            Some(-37)
+        );
+        assert_eq!(clock_skew(&headers("yesterday"), local), None);
+        assert_eq!(clock_skew(&reqwest::header::HeaderMap::new(), local), None);
+    }
+
+    #[test]
+    fn test_endpoint_url() {
+        assert_eq!(
+            Api::endpoint_url(
+                &reqwest::Url::parse("https://my_server:7766/site2").unwrap(),
+                &["some", "endpoint"]
+            )
+            .unwrap()
+            .to_string(),
+            "https://my_server:7766/site2/agent-receiver/some/endpoint"
+        );
+    }
+
+    #[test]
+    fn test_error_response_description_body_missing() {
+        assert_eq!(
+            Api::error_response_description(StatusCode::INTERNAL_SERVER_ERROR, None,),
+            "Request failed with code 500 Internal Server Error, could not obtain response body"
+        )
+    }
+
+    #[test]
+    fn test_error_response_description_body_parsable() {
+        assert_eq!(
+            Api::error_response_description(
+                StatusCode::BAD_REQUEST,
+                Some(String::from("{\"detail\": \"Something went wrong\"}")),
+            ),
+            "Request failed with code 400 Bad Request: Something went wrong"
+        )
+    }
+
+    #[test]
+    fn test_error_response_description_body_not_parsable() {
+        assert_eq!(
+            Api::error_response_description(
+                StatusCode::NOT_FOUND,
+                Some(String::from("{\"detail\": {\"title\": \"whatever\"}}")),
+            ),
+            "Request failed with code 404 Not Found: {\"detail\": {\"title\": \"whatever\"}}"
+        )
+    }
+
+    #[test]
+    fn test_check_response_204_ok() {
+        assert!(Api::check_response_204(reqwest::blocking::Response::from(
+            http::Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body("")
+                .unwrap(),
+        ))
+        .is_ok());
+    }
+
+    #[test]
+    fn test_check_response_204_error() {
+        let error_value = Api::check_response_204(reqwest::blocking::Response::from(
+            http::Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body("{\"detail\": \"Insufficient permissions\"}")
+                .unwrap(),
+        ))
+        .unwrap_err();
+        assert_eq!(
+            format!("{error_value}"),
+            "Request failed with code 401 Unauthorized: Insufficient permissions"
+        );
+    }
+}
@@ -0,0 +1,705 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::{config, constants, fips, happy_eyeballs, openssl_tls, proxy};
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, PKey, PKeyRef};
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::extension::{
+    AuthorityKeyIdentifier, BasicConstraints, KeyUsage, SubjectAlternativeName,
+    SubjectKeyIdentifier,
+};
+use openssl::x509::{X509Builder, X509Name, X509NameRef, X509Req, X509};
+use reqwest::blocking::{Client, ClientBuilder};
+use rustls::{
+    client::ServerCertVerified, client::ServerCertVerifier, client::ServerName,
+    client::WebPkiVerifier, Certificate, Certificate as RustlsCertificate, CertificateError,
+    Error as RusttlsError, PrivateKey as RustlsPrivateKey, RootCertStore,
+};
+use rustls_pemfile::Item;
+use std::net::{IpAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+use x509_parser::traits::FromDer;
+
+pub fn make_csr(cn: &str) -> AnyhowResult<(String, String)> {
+    // https://github.com/sfackler/rust-openssl/blob/master/openssl/examples/mk_certs.rs
+    let rsa = Rsa::generate(2048)?;
+    let key_pair = PKey::from_rsa(rsa)?;
+
+    let mut name = X509Name::builder()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, cn)?;
+    let name = name.build();
+
+    let mut crt_builder = X509Req::builder()?;
+    crt_builder.set_subject_name(&name)?;
+    crt_builder.set_pubkey(&key_pair)?;
+    crt_builder.sign(&key_pair, MessageDigest::sha256())?;
+
+    Ok((
+        String::from_utf8(crt_builder.build().to_pem()?)?,
+        String::from_utf8(key_pair.private_key_to_pem_pkcs8()?)?,
+    ))
+}
+
+/// Create a self-signed CA. Returns the certificate and the private key (PEM).
+pub fn make_ca(cn: &str, validity_days: u32) -> AnyhowResult<(String, String)> {
+    let key_pair = PKey::from_rsa(Rsa::generate(2048)?)?;
+    let mut name = X509Name::builder()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, cn)?;
+    let name = name.build();
+
+    let mut cert_builder = cert_builder(&name, &key_pair, validity_days)?;
+    cert_builder.set_issuer_name(&name)?;
+    cert_builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+    cert_builder.append_extension(
+        KeyUsage::new()
+            .critical()
+            .key_cert_sign()
+            .crl_sign()
+            .build()?,
+    )?;
+    cert_builder.append_extension(
+        SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(None, None))?,
+    )?;
+    cert_builder.sign(&key_pair, MessageDigest::sha256())?;
+
+    Ok((
+        String::from_utf8(cert_builder.build().to_pem()?)?,
+        String::from_utf8(key_pair.private_key_to_pem_pkcs8()?)?,
+    ))
+}
+
+/// Create a certificate issued by the given CA. The CN is also the DNS name of the certificate,
+/// st. it can be served for a connection requested via SNI. Returns the certificate and the
+/// private key (PEM).
+pub fn make_signed_cert(
+    ca_cert: &str,
+    ca_key: &str,
+    cn: &str,
+    validity_days: u32,
+) -> AnyhowResult<(String, String)> {
+    let key_pair = PKey::from_rsa(Rsa::generate(2048)?)?;
+    let mut name = X509Name::builder()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, cn)?;
+    let name = name.build();
+    let cert = issue_cert(ca_cert, ca_key, &name, &key_pair, validity_days)?;
+
+    Ok((
+        String::from_utf8(cert.to_pem()?)?,
+        String::from_utf8(key_pair.private_key_to_pem_pkcs8()?)?,
+    ))
+}
+
+/// Issue a certificate for the given CSR, as the agent receiver does when registering. Returns
+/// the certificate (PEM).
+pub fn sign_csr(
+    ca_cert: &str,
+    ca_key: &str,
+    csr: &str,
+    validity_days: u32,
+) -> AnyhowResult<String> {
+    let csr = X509Req::from_pem(csr.as_bytes()).context("Invalid CSR")?;
+    let public_key = csr.public_key()?;
+    if !csr.verify(&public_key)? {
+        bail!("Invalid signature of CSR");
+    }
+    let cert = issue_cert(
+        ca_cert,
+        ca_key,
+        csr.subject_name(),
+        &public_key,
+        validity_days,
+    )?;
+    Ok(String::from_utf8(cert.to_pem()?)?)
+}
+
+/// Create a self-signed certificate which is no CA. Such a certificate can be trusted as is, like
+/// the controller does with the certificate of the agent receiver when registering. Returns the
+/// certificate and the private key (PEM).
+pub fn make_self_signed_cert(cn: &str, validity_days: u32) -> AnyhowResult<(String, String)> {
+    let key_pair = PKey::from_rsa(Rsa::generate(2048)?)?;
+    let mut name = X509Name::builder()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, cn)?;
+    let name = name.build();
+
+    let mut cert_builder = cert_builder(&name, &key_pair, validity_days)?;
+    cert_builder.set_issuer_name(&name)?;
+    cert_builder.append_extension(BasicConstraints::new().build()?)?;
+    cert_builder.append_extension(
+        SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(None, None))?,
+    )?;
+    cert_builder.append_extension(
+        SubjectAlternativeName::new()
+            .dns(cn)
+            .build(&cert_builder.x509v3_context(None, None))?,
+    )?;
+    cert_builder.sign(&key_pair, MessageDigest::sha256())?;
+
+    Ok((
+        String::from_utf8(cert_builder.build().to_pem()?)?,
+        String::from_utf8(key_pair.private_key_to_pem_pkcs8()?)?,
+    ))
+}
+
+/// The CN of the subject is also the DNS name of the certificate
+fn issue_cert<T: HasPublic>(
+    ca_cert: &str,
+    ca_key: &str,
+    subject: &X509NameRef,
+    public_key: &PKeyRef<T>,
+    validity_days: u32,
+) -> AnyhowResult<X509> {
+    let ca_cert = X509::from_pem(ca_cert.as_bytes()).context("Invalid CA certificate")?;
+    let ca_key = PKey::private_key_from_pem(ca_key.as_bytes()).context("Invalid CA key")?;
+    let cn = String::from_utf8_lossy(
+        subject
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .context("Subject has no CN")?
+            .data()
+            .as_slice(),
+    )
+    .to_string();
+
+    let mut cert_builder = cert_builder(subject, public_key, validity_days)?;
+    cert_builder.set_issuer_name(ca_cert.subject_name())?;
+    cert_builder.append_extension(BasicConstraints::new().build()?)?;
+    cert_builder.append_extension(
+        KeyUsage::new()
+            .critical()
+            .digital_signature()
+            .key_encipherment()
+            .build()?,
+    )?;
+    let subject_key_identifier =
+        SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(Some(&ca_cert), None))?;
+    cert_builder.append_extension(subject_key_identifier)?;
+    let authority_key_identifier = AuthorityKeyIdentifier::new()
+        .keyid(false)
+        .issuer(false)
+        .build(&cert_builder.x509v3_context(Some(&ca_cert), None))?;
+    cert_builder.append_extension(authority_key_identifier)?;
+    let subject_alt_name = SubjectAlternativeName::new()
+        .dns(&cn)
+        .build(&cert_builder.x509v3_context(Some(&ca_cert), None))?;
+    cert_builder.append_extension(subject_alt_name)?;
+    cert_builder.sign(&ca_key, MessageDigest::sha256())?;
+    Ok(cert_builder.build())
+}
+
+fn cert_builder<T: HasPublic>(
+    subject: &X509NameRef,
+    public_key: &PKeyRef<T>,
+    validity_days: u32,
+) -> AnyhowResult<X509Builder> {
+    let mut cert_builder = X509::builder()?;
+    cert_builder.set_version(2)?;
+    let mut serial = BigNum::new()?;
+    serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
+    let serial_number = serial.to_asn1_integer()?;
+    cert_builder.set_serial_number(&serial_number)?;
+    cert_builder.set_subject_name(subject)?;
+    cert_builder.set_pubkey(public_key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    cert_builder.set_not_before(&not_before)?;
+    let not_after = Asn1Time::days_from_now(validity_days)?;
+    cert_builder.set_not_after(&not_after)?;
+    Ok(cert_builder)
+}
+
+/// Check that the certificate belongs to the private key, is issued by the root certificate and
+/// has not expired
+pub fn check_identity(certificate: &str, private_key: &str, root_cert: &str) -> AnyhowResult<()> {
+    let certificate = X509::from_pem(certificate.as_bytes()).context("Invalid certificate")?;
+    let private_key =
+        PKey::private_key_from_pem(private_key.as_bytes()).context("Invalid private key")?;
+    let root_cert = X509::from_pem(root_cert.as_bytes()).context("Invalid root certificate")?;
+    if !certificate.public_key()?.public_eq(&private_key) {
+        bail!("Certificate does not belong to the private key");
+    }
+    if !is_issued_by(&certificate, &root_cert)? {
+        bail!("Certificate is not issued by the root certificate");
+    }
+    if certificate.not_after() < Asn1Time::days_from_now(0)? {
+        bail!("Certificate expired on {}", certificate.not_after());
+    }
+    Ok(())
+}
+
+/// Check that the certificate is signed by the root certificate, both PEM-encoded
+pub fn is_issued_by_pem(certificate: &str, root_cert: &str) -> AnyhowResult<bool> {
+    is_issued_by(
+        &X509::from_pem(certificate.as_bytes()).context("Invalid certificate")?,
+        &X509::from_pem(root_cert.as_bytes()).context("Invalid root certificate")?,
+    )
+}
+
+fn is_issued_by(certificate: &X509, root_cert: &X509) -> AnyhowResult<bool> {
+    Ok(certificate.verify(root_cert.public_key()?.as_ref())?)
+}
+
+/// Detached SHA-256 signature of the concatenated parts with the private key (PEM)
+pub fn sign(private_key: &str, parts: &[&[u8]]) -> AnyhowResult<Vec<u8>> {
+    let private_key =
+        PKey::private_key_from_pem(private_key.as_bytes()).context("Invalid private key")?;
+    let mut signer = openssl::sign::Signer::new(MessageDigest::sha256(), &private_key)?;
+    for part in parts {
+        signer.update(part)?;
+    }
+    Ok(signer.sign_to_vec()?)
+}
+
+pub fn root_cert_store<'a>(
+    root_certs: impl Iterator<Item = &'a str>,
+) -> AnyhowResult<RootCertStore> {
+    let mut cert_store = RootCertStore::empty();
+
+    for root_cert in root_certs {
+        cert_store.add(&rustls_certificate(root_cert)?)?;
+    }
+
+    Ok(cert_store)
+}
+
+pub struct CNCheckerUUID {
+    cn: String,
+}
+
+impl CNCheckerUUID {
+    pub fn cn(&self) -> &str {
+        &self.cn
+    }
+
+    pub fn cn_is_uuid(&self) -> bool {
+        uuid::Uuid::parse_str(&self.cn).is_ok()
+    }
+}
+
+impl std::convert::TryFrom<&Certificate> for CNCheckerUUID {
+    type Error = RusttlsError;
+
+    fn try_from(certificate: &Certificate) -> Result<Self, RusttlsError> {
+        let (_rem, cert) =
+            x509_parser::certificate::X509Certificate::from_der(certificate.as_ref())
+                .map_err(|_e| RusttlsError::InvalidCertificate(CertificateError::BadEncoding))?;
+
+        let common_names =
+            common_names(cert.subject()).map_err(|e| RusttlsError::General(e.to_string()))?;
+
+        if common_names.len() != 1 {
+            return Err(RusttlsError::General(format!(
+                "Expected exactly one CN in certificate, found: {}",
+                common_names.join(", ")
+            )));
+        }
+
+        Ok(Self {
+            cn: String::from(common_names[0]),
+        })
+    }
+}
+
+struct CnIsNoUuidAcceptAnyHostname {
+    verifier: Box<dyn ServerCertVerifier>,
+}
+
+impl CnIsNoUuidAcceptAnyHostname {
+    pub fn from_roots(roots: RootCertStore) -> Arc<dyn ServerCertVerifier> {
+        Arc::new(Self {
+            verifier: Box::new(WebPkiVerifier::new(roots, None)),
+        })
+    }
+}
+
+impl ServerCertVerifier for CnIsNoUuidAcceptAnyHostname {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, RusttlsError> {
+        let cn_checker = CNCheckerUUID::try_from(end_entity)?;
+        if cn_checker.cn_is_uuid() {
+            return Err(RusttlsError::General(format!(
+                "CN in server certificate is a valid UUID: {}",
+                cn_checker.cn()
+            )));
+        }
+        self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            // emulate reqwest::ClientBuilder::danger_accept_invalid_hostnames
+            &ServerName::try_from(cn_checker.cn()).map_err(|e| {
+                RusttlsError::General(format!(
+                    "CN in server certificate cannot be used as server name: {e}"
+                ))
+            })?,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+pub struct TLSIdentity {
+    pub cert_chain: Vec<rustls::Certificate>,
+    pub key_der: rustls::PrivateKey,
+}
+
+pub struct HandshakeCredentials<'a> {
+    pub server_root_cert: &'a str,
+    pub client_identity: Option<TLSIdentity>,
+}
+
+fn tls_config(handshake_credentials: HandshakeCredentials) -> AnyhowResult<rustls::ClientConfig> {
+    let builder = fips::tls_defaults(rustls::ClientConfig::builder())?
+        .with_custom_certificate_verifier(CnIsNoUuidAcceptAnyHostname::from_roots(
+            root_cert_store([handshake_credentials.server_root_cert].into_iter())?,
+        ));
+    let mut config = match handshake_credentials.client_identity {
+        Some(identity) => builder.with_client_auth_cert(identity.cert_chain, identity.key_der)?,
+        None => builder.with_no_client_auth(),
+    };
+    // reqwest does not set up ALPN for preconfigured TLS. Offer HTTP/2 and let receivers which
+    // do not support it fall back to HTTP/1.1.
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// For connections upgraded to WebSocket, which requires HTTP/1.1
+pub fn websocket_tls_config(
+    handshake_credentials: HandshakeCredentials,
+) -> AnyhowResult<rustls::ClientConfig> {
+    let mut config = tls_config(handshake_credentials)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// For QUIC, whose application protocols are not HTTP
+pub fn quic_tls_config(
+    handshake_credentials: HandshakeCredentials,
+    protocol: &[u8],
+) -> AnyhowResult<rustls::ClientConfig> {
+    let mut config = tls_config(handshake_credentials)?;
+    config.alpn_protocols = vec![protocol.to_vec()];
+    Ok(config)
+}
+
+pub fn client(
+    handshake_credentials: Option<HandshakeCredentials>,
+    proxy_mode: proxy::ProxyMode,
+    source_address: Option<IpAddr>,
+    pinned: Option<&happy_eyeballs::Pinned>,
+    request_headers: &reqwest::header::HeaderMap,
+    timeouts: &config::NetworkTimeouts,
+    http2_only: bool,
+) -> AnyhowResult<Client> {
+    let mut client_builder = ClientBuilder::new()
+        .local_address(source_address)
+        .default_headers(request_headers.clone())
+        .connect_timeout(timeouts.connect())
+        .timeout(timeouts.total())
+        // Idle connections are kept across push intervals, but not more of them than there can
+        // be concurrent pushes. Keepalive probes detect connections dropped by firewalls meanwhile.
+        .pool_max_idle_per_host(constants::MAX_OUTBOUND_REQUESTS)
+        .pool_idle_timeout(Duration::from_secs(constants::CLIENT_POOL_IDLE_TIMEOUT))
+        .tcp_keepalive(Duration::from_secs(constants::TCP_KEEPALIVE_INTERVAL));
+    if let Some(pinned) = pinned {
+        client_builder = pinned.apply(client_builder);
+    }
+
+    client_builder = match handshake_credentials {
+        Some(handshake_credentials) if fips::enabled() => {
+            client_builder.use_preconfigured_tls(openssl_tls::connector(handshake_credentials)?)
+        }
+        Some(handshake_credentials) => {
+            let mut config = tls_config(handshake_credentials)?;
+            if http2_only {
+                config.alpn_protocols = vec![b"h2".to_vec()];
+            }
+            client_builder.use_preconfigured_tls(config)
+        }
+        None => client_builder.danger_accept_invalid_certs(true),
+    };
+    if http2_only {
+        client_builder = client_builder.http2_prior_knowledge();
+    }
+
+    Ok(proxy::configure(client_builder, proxy_mode).build()?)
+}
+
+pub fn fetch_server_cert_pem(
+    server: &str,
+    port: &u16,
+    ip_preference: happy_eyeballs::IpPreference,
+    timeouts: &config::NetworkTimeouts,
+) -> AnyhowResult<String> {
+    let tcp_stream = happy_eyeballs::connect_timeout(
+        &happy_eyeballs::resolve(server, *port, ip_preference)?,
+        timeouts.connect(),
+    )
+    .with_context(|| format!("Cannot connect to {server}:{port}"))?;
+    // Otherwise, a server accepting the connection but never answering the handshake stalls us
+    tcp_stream.set_read_timeout(Some(timeouts.read()))?;
+    tcp_stream.set_write_timeout(Some(timeouts.read()))?;
+    let mut ssl_connector_builder = SslConnector::builder(SslMethod::tls())?;
+    ssl_connector_builder.set_verify(SslVerifyMode::NONE);
+    let mut ssl_stream = ssl_connector_builder.build().connect(server, tcp_stream)?;
+
+    let server_cert = ssl_stream
+        .ssl()
+        .peer_cert_chain()
+        .context("Failed fetching peer cert chain")?
+        .iter()
+        .next()
+        .context("Failed unpacking peer cert chain")?
+        .to_pem()?;
+
+    ssl_stream.shutdown()?;
+
+    Ok(String::from_utf8(server_cert)?)
+}
+
+/// TLS handshake without verifying the server, for diagnostic purposes. Returns the negotiated
+/// protocol version and the certificate chain presented by the server (PEM-encoded, server
+/// certificate first).
+pub fn probe_tls(tcp_stream: TcpStream, server: &str) -> AnyhowResult<(String, Vec<String>)> {
+    let mut ssl_connector_builder = SslConnector::builder(SslMethod::tls())?;
+    ssl_connector_builder.set_verify(SslVerifyMode::NONE);
+    let mut ssl_stream = ssl_connector_builder.build().connect(server, tcp_stream)?;
+
+    let chain = ssl_stream
+        .ssl()
+        .peer_cert_chain()
+        .context("Server did not present a certificate")?
+        .iter()
+        .map(|cert| Ok(String::from_utf8(cert.to_pem()?)?))
+        .collect::<AnyhowResult<Vec<String>>>()?;
+    let version = String::from(ssl_stream.ssl().version_str());
+
+    // Only the handshake matters, the server may well close the connection first
+    let _ = ssl_stream.shutdown();
+
+    Ok((version, chain))
+}
+
+pub fn parse_pem(cert: &str) -> AnyhowResult<x509_parser::pem::Pem> {
+    x509_parser::pem::Pem::iter_from_buffer(cert.as_bytes())
+        .next()
+        .context("Input data does not contain a PEM block")?
+        .context("PEM data invalid")
+}
+
+pub fn common_names<'a>(x509_name: &'a x509_parser::x509::X509Name) -> AnyhowResult<Vec<&'a str>> {
+    x509_name
+        .iter_common_name()
+        .map(|n| {
+            n.as_str()
+                .map_err(|e| anyhow!(format!("Failed to parse CN to string: {e}")))
+        })
+        .collect::<AnyhowResult<Vec<_>>>()
+}
+
+pub fn rustls_private_key(key_pem: &str) -> AnyhowResult<RustlsPrivateKey> {
+    if let Item::PKCS8Key(it) = rustls_pemfile::read_one(&mut key_pem.to_owned().as_bytes())?
+        .context("Could not load private key")?
+    {
+        Ok(RustlsPrivateKey(it))
+    } else {
+        bail!("Could not process private key")
+    }
+}
+
+pub fn rustls_certificate(cert_pem: &str) -> AnyhowResult<RustlsCertificate> {
+    if let Item::X509Certificate(it) =
+        rustls_pemfile::read_one(&mut cert_pem.to_owned().as_bytes())?
+            .context("Could not load certificate")?
+    {
+        Ok(RustlsCertificate(it))
+    } else {
+        bail!("Could not process certificate")
+    }
+}
+
+#[cfg(test)]
+mod test_cn_no_uuid {
+    use super::*;
+
+    #[test]
+    fn test_csr_version() {
+        let (csr, _key_pair) = make_csr("stuff").unwrap();
+        let csr_obj = X509Req::from_pem(csr.as_bytes()).unwrap();
+        // A CSR is a simple x509 structure without any extensions, and must be of version 1,
+        // which equals to a raw version value of 0.
+        // See also https://www.rfc-editor.org/rfc/rfc2986 .
+        // This is actually tested in recent versions of python-cryptography and a registration call
+        // with a non-compliant CSR would fail.
+        assert!(csr_obj.version() == 0)
+    }
+
+    #[test]
+    fn test_cn_extraction() {
+        let cn_checker =
+            CNCheckerUUID::try_from(&rustls_certificate(constants::TEST_CERT_OK).unwrap()).unwrap();
+        assert_eq!(cn_checker.cn(), "heute");
+    }
+
+    #[test]
+    fn test_verify_no_uuid() {
+        let cn_checker =
+            CNCheckerUUID::try_from(&rustls_certificate(constants::TEST_CERT_OK).unwrap()).unwrap();
+        assert!(!cn_checker.cn_is_uuid());
+    }
+
+    #[test]
+    fn test_verify_uiid() {
+        let cn_checker =
+            CNCheckerUUID::try_from(&rustls_certificate(constants::TEST_CERT_CN_UUID).unwrap())
+                .unwrap();
+        assert!(cn_checker.cn_is_uuid());
+    }
+
+    #[test]
+    fn test_tls_config_offers_http2() {
+        let config = tls_config(HandshakeCredentials {
+            server_root_cert: constants::TEST_ROOT_CERT,
+            client_identity: None,
+        })
+        .unwrap();
+        assert_eq!(
+            config.alpn_protocols,
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_websocket_tls_config_offers_http1_only() {
+        let config = websocket_tls_config(HandshakeCredentials {
+            server_root_cert: constants::TEST_ROOT_CERT,
+            client_identity: None,
+        })
+        .unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"http/1.1".to_vec()]);
+    }
+
+    fn verifier() -> Arc<dyn ServerCertVerifier> {
+        CnIsNoUuidAcceptAnyHostname::from_roots(
+            root_cert_store([constants::TEST_ROOT_CERT].into_iter()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_verify_server_cert_ok() {
+        assert!(verifier()
+            .verify_server_cert(
+                &rustls_certificate(constants::TEST_CERT_OK).unwrap(),
+                &[],
+                &ServerName::try_from("lsdafhgldfhg").unwrap(),
+                &mut [].into_iter(),
+                &[],
+                std::time::SystemTime::now(),
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_verify_self_signed_server_cert() {
+        let (cert, key) = make_self_signed_cert("receiver", 1).unwrap();
+        let verifier = CnIsNoUuidAcceptAnyHostname::from_roots(
+            root_cert_store([cert.as_str()].into_iter()).unwrap(),
+        );
+        assert!(verifier
+            .verify_server_cert(
+                &rustls_certificate(&cert).unwrap(),
+                &[],
+                &ServerName::try_from("lsdafhgldfhg").unwrap(),
+                &mut [].into_iter(),
+                &[],
+                std::time::SystemTime::now(),
+            )
+            .is_ok());
+        let (csr, _) = make_csr("client").unwrap();
+        let client_cert =
+            X509::from_pem(sign_csr(&cert, &key, &csr, 1).unwrap().as_bytes()).unwrap();
+        assert!(client_cert
+            .verify(
+                &X509::from_pem(cert.as_bytes())
+                    .unwrap()
+                    .public_key()
+                    .unwrap()
+            )
+            .unwrap());
+    }
+
+    #[test]
+    fn test_check_identity() {
+        let (root_cert, root_key) = make_ca("root", 1).unwrap();
+        let (csr, key) = make_csr("client").unwrap();
+        let cert = sign_csr(&root_cert, &root_key, &csr, 1).unwrap();
+        assert!(check_identity(&cert, &key, &root_cert).is_ok());
+        let (_, other_key) = make_csr("client").unwrap();
+        assert!(check_identity(&cert, &other_key, &root_cert).is_err());
+        let (other_root_cert, _) = make_ca("root", 1).unwrap();
+        assert!(check_identity(&cert, &key, &other_root_cert).is_err());
+    }
+
+    #[test]
+    fn test_sign() {
+        let (root_cert, root_key) = make_ca("root", 1).unwrap();
+        let (csr, key) = make_csr("client").unwrap();
+        let cert =
+            X509::from_pem(sign_csr(&root_cert, &root_key, &csr, 1).unwrap().as_bytes()).unwrap();
+        let signature = sign(&key, &[b"collected", b" data"]).unwrap();
+        let public_key = cert.public_key().unwrap();
+        let mut verifier =
+            openssl::sign::Verifier::new(MessageDigest::sha256(), &public_key).unwrap();
+        verifier.update(b"collected data").unwrap();
+        assert!(verifier.verify(&signature).unwrap());
+        assert!(sign("no key", &[b"data"]).is_err());
+    }
+
+    #[test]
+    fn test_verify_server_cert_cn_is_uuid() {
+        assert_eq!(
+            match verifier()
+                .verify_server_cert(
+                    &rustls_certificate(constants::TEST_CERT_CN_UUID).unwrap(),
+                    &[],
+                    &ServerName::try_from("lsdafhgldfhg").unwrap(),
+                    &mut [].into_iter(),
+                    &[],
+                    std::time::SystemTime::now(),
+                )
+                .unwrap_err()
+            {
+                rustls::Error::General(s) => s,
+                _ => panic!("Wrong error type"),
+            },
+            "CN in server certificate is a valid UUID: cf771eeb-b666-4673-95c9-683960fb2939"
+        )
+    }
+
+    #[test]
+    fn test_verify_server_cert_invalid_signature() {
+        assert!(verifier()
+            .verify_server_cert(
+                &rustls_certificate(constants::TEST_CERT_INVALID_SIGNATURE).unwrap(),
+                &[],
+                &ServerName::try_from("lsdafhgldfhg").unwrap(),
+                &mut [].into_iter(),
+                &[],
+                std::time::SystemTime::now(),
+            )
+            .is_err());
+    }
+}
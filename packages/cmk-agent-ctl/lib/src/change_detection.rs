@@ -0,0 +1,165 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::config;
+use crate::monitoring_data::section_header;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Hash of the agent output, leaving out the ignored sections
+pub fn digest(mon_data: &[u8], ignored_sections: &[String]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let mut ignored = false;
+    for line in mon_data.split(|byte| *byte == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if let Some(section) = section_header(line) {
+            ignored = ignored_sections
+                .iter()
+                .any(|ignored_section| ignored_section.as_bytes() == section);
+        }
+        if !ignored {
+            line.hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+struct LastPush {
+    digest: u64,
+    at: Instant,
+}
+
+/// Decides whether uploading the agent output to a connection can be skipped, because nothing
+/// relevant changed since the last successful push. The full agent output is still pushed once
+/// the last upload is older than the maximum age, st. the data on the site does not go stale.
+pub struct ChangeDetection {
+    enabled: bool,
+    ignored_sections: Vec<String>,
+    max_age: Duration,
+    last_push: Mutex<HashMap<uuid::Uuid, LastPush>>,
+}
+
+impl ChangeDetection {
+    pub fn new(push_config: &config::PushConfig) -> Self {
+        Self {
+            enabled: push_config.conditional_push,
+            ignored_sections: push_config.conditional_push_ignored_sections.clone(),
+            max_age: Duration::from_secs(push_config.conditional_push_max_age),
+            last_push: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn last_push(&self) -> MutexGuard<'_, HashMap<uuid::Uuid, LastPush>> {
+        match self.last_push.lock() {
+            Ok(last_push) => last_push,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    pub fn digest(&self, mon_data: &[u8]) -> Option<u64> {
+        self.enabled
+            .then(|| digest(mon_data, &self.ignored_sections))
+    }
+
+    pub fn is_unchanged(&self, uuid: &uuid::Uuid, digest: Option<u64>, now: Instant) -> bool {
+        let Some(digest) = digest else {
+            return false;
+        };
+        self.last_push().get(uuid).is_some_and(|last_push| {
+            last_push.digest == digest && now.duration_since(last_push.at) < self.max_age
+        })
+    }
+
+    pub fn record_push(&self, uuid: &uuid::Uuid, digest: Option<u64>, now: Instant) {
+        if let Some(digest) = digest {
+            self.last_push().insert(*uuid, LastPush { digest, at: now });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_change_detection {
+    use super::*;
+    use std::str::FromStr;
+
+    const OUTPUT: &[u8] =
+        b"<<<check_mk>>>\nVersion: 2.3.0\n<<<uptime>>>\n1234\n<<<df:sep(9)>>>\n/ 42%\n";
+
+    fn uuid() -> uuid::Uuid {
+        uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap()
+    }
+
+    fn change_detection(enabled: bool, ignored_sections: &[&str]) -> ChangeDetection {
+        ChangeDetection::new(&config::PushConfig {
+            push_interval: 60,
+            push_jitter: 0,
+            align_push: false,
+            push_spool_size: 0,
+            push_spool_max_size: None,
+            max_outbound_requests: 8,
+            push_compression: crate::monitoring_data::PushCompression::Zlib,
+            conditional_push: enabled,
+            conditional_push_ignored_sections: ignored_sections
+                .iter()
+                .map(|section| String::from(*section))
+                .collect(),
+            conditional_push_max_age: 300,
+            delta_push: false,
+            push_chunk_size: None,
+            sign_push: false,
+            quic: false,
+            push_failover: HashMap::new(),
+            section_filter: config::SectionFilterConfig::default(),
+            post_processors: vec![],
+            fallback_command: None,
+            payload_size: config::PayloadSizeConfig::default(),
+            realtime: config::RealtimeConfig::default(),
+            reregistration: config::ReregistrationConfig::default(),
+            hardware_labels: false,
+            status_section: false,
+        })
+    }
+
+    #[test]
+    fn test_digest_ignores_sections() {
+        let changed_uptime =
+            b"<<<check_mk>>>\nVersion: 2.3.0\n<<<uptime>>>\n1294\n<<<df:sep(9)>>>\n/ 42%\n";
+        let changed_df =
+            b"<<<check_mk>>>\nVersion: 2.3.0\n<<<uptime>>>\n1234\n<<<df:sep(9)>>>\n/ 43%\n";
+        assert_ne!(digest(OUTPUT, &[]), digest(changed_uptime, &[]));
+        let ignored = [String::from("uptime")];
+        assert_eq!(digest(OUTPUT, &ignored), digest(changed_uptime, &ignored));
+        assert_ne!(digest(OUTPUT, &ignored), digest(changed_df, &ignored));
+    }
+
+    #[test]
+    fn test_is_unchanged() {
+        let change_detection = change_detection(true, &["uptime"]);
+        let now = Instant::now();
+        let digest = change_detection.digest(OUTPUT);
+        assert!(!change_detection.is_unchanged(&uuid(), digest, now));
+        change_detection.record_push(&uuid(), digest, now);
+        assert!(change_detection.is_unchanged(&uuid(), digest, now + Duration::from_secs(60)));
+        assert!(!change_detection.is_unchanged(
+            &uuid(),
+            change_detection.digest(b"<<<check_mk>>>\nVersion: 2.3.1\n"),
+            now + Duration::from_secs(60)
+        ));
+        // The full output is pushed again once the last upload is too old
+        assert!(!change_detection.is_unchanged(&uuid(), digest, now + Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let change_detection = change_detection(false, &[]);
+        let now = Instant::now();
+        let digest = change_detection.digest(OUTPUT);
+        assert_eq!(digest, None);
+        change_detection.record_push(&uuid(), digest, now);
+        assert!(!change_detection.is_unchanged(&uuid(), digest, now));
+    }
+}
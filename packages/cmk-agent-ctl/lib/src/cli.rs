@@ -0,0 +1,1350 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+#[cfg(windows)]
+use super::types;
+use super::{cloud_metadata, constants, dns, host_name, site_spec, transport};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+#[derive(Parser)]
+#[command(
+    name = "cmk-agent-ctl",
+    about = "Checkmk agent controller.",
+    version = constants::VERSION,
+    after_help = "Use --help-json for a description of all commands and options in JSON."
+)]
+pub struct Cli {
+    /// Enable verbose output. Use once (-v) for logging level INFO and twice (-vv) for logging
+    /// level DEBUG. Three times (-vvv) additionally logs requests to and responses from the
+    /// agent receiver, with credentials redacted.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Format of log messages. Overrides the setting "log_format" in cmk-agent-ctl.toml.
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Language of prompts and of the human-readable status. Defaults to the language of the
+    /// locale (LC_ALL, LC_MESSAGES or LANG), English if there are no messages for it.
+    #[arg(long, value_enum)]
+    pub language: Option<Language>,
+
+    /// Run in a container, eg. as sidecar of a Kubernetes pod: The home dir is taken from
+    /// CMK_AGENT_CTL_HOME (default /var/lib/cmk-agent-ctl), the config from the file given by
+    /// CMK_AGENT_CTL_CONFIG_FILE and from env vars like CMK_AGENT_CTL_SETTING_PUSH_INTERVAL.
+    /// The user is not switched and the daemon logs JSON to stdout. If CMK_AGENT_CTL_IDENTITY_FILE
+    /// points to registration parameters in the format of the bootstrap mode, the daemon registers
+    /// with them and registers again whenever they change.
+    #[arg(long)]
+    pub container: bool,
+
+    /// Format of the results of register, register-new, status, delete, delete-all, import, tag
+    /// and rename-host, for scripts. Has to be given before the mode, eg. 'cmk-agent-ctl --output json status'.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    pub output: OutputFormat,
+
+    /// Connect to ADDRESS instead of resolving HOST, for connections to PORT, like curl does.
+    /// Can be given several times. Replaces the setting "dns.resolve" in cmk-agent-ctl.toml for
+    /// the same host and port.
+    #[arg(long, value_name = "HOST:PORT:ADDRESS")]
+    pub resolve: Vec<dns::ResolveOverride>,
+
+    #[command(subcommand)]
+    pub mode: Mode,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Messages for humans
+    #[default]
+    Human,
+    Json,
+    Yaml,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Language {
+    #[default]
+    #[value(name = "en")]
+    English,
+    #[value(name = "de")]
+    German,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PromptFormat {
+    /// Questions on standard error, answers typed in
+    #[default]
+    Text,
+    /// Questions and answers as JSON documents, one per line
+    Json,
+}
+
+/// Protocol spoken with the agent receiver, chosen per connection
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiverProtocol {
+    /// JSON over HTTPS
+    #[default]
+    Rest,
+    /// gRPC over HTTP/2, eg. behind a gRPC gateway
+    Grpc,
+}
+
+impl ReceiverProtocol {
+    pub fn is_rest(&self) -> bool {
+        *self == Self::Rest
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Mode {
+    /// Register with a Checkmk site
+    ///
+    /// Register with a Checkmk instance for monitoring. The required information
+    /// can be read from a config file or must be passed via command line.
+    Register(RegisterOpts),
+
+    /// Register with a Checkmk site, automatically creating a new host.
+    ///
+    /// Register with a Checkmk instance for monitoring. A new host will be created
+    /// in the target Checkmk instance. This mode is only available if the target
+    /// is a Cloud edition.
+    RegisterNew(RegisterNewOpts),
+
+    /// Register with a Checkmk site on behalf of another host
+    ///
+    /// This allows a registration by proxy for hosts which cannot register themselves.
+    /// The gathered connection information is written to standard output, or, with --tenant,
+    /// stored encrypted in the vault.
+    ProxyRegister(ProxyRegisterOpts),
+
+    /// Register with the Checkmk site given in the user data of this cloud instance
+    ///
+    /// Reads the registration parameters from the key "cmk_agent_ctl" of the user data, eg. a
+    /// cloud-init config, of an AWS (IMDSv2), Azure or GCP instance. Under GCP, the custom
+    /// metadata key "cmk-agent-ctl" is read first. Nothing is done if this host is already
+    /// registered with the site, so this can run on every boot, and images need no credentials.
+    /// Example:
+    /// 'cmk_agent_ctl: {server: checkmk.example.com, site: mysite, user: agent_registration,
+    /// password: ..., labels: {env: prod}}'. With "hostname" or "hostname_from", an existing host
+    /// is registered, otherwise the site creates the host with the given labels. The root
+    /// certificate of the site can be given as "root_cert", or "trust_cert: true" set. Under
+    /// Windows, the parameters can be read from the registry instead, see --from-registry.
+    Bootstrap(BootstrapOpts),
+
+    /// Register with the Checkmk site the agent updater is set up for
+    ///
+    /// Eases the migration from agents before Checkmk 2.1: The server, the site, the host name and
+    /// the user are read from the config of the agent updater (written by the agent bakery) and
+    /// its state (written by 'cmk-update-agent register'). If one of the certificates trusted by
+    /// the agent updater issued the certificate of the agent receiver, the site is trusted without
+    /// asking. The password has to be given or is asked for, the agent updater does not keep it.
+    MigrateUpdater(MigrateUpdaterOpts),
+
+    /// Push monitoring data to all Checkmk sites configured for 'push'
+    ///
+    /// This command will collect monitoring data, send them to all
+    /// Checkmk site configured for 'push' and exit.
+    Push(ClientOpts),
+
+    /// Handle incoming connections from Checkmk sites collecting monitoring data
+    ///
+    /// This command will listen for incoming connections
+    Pull(PullOpts),
+
+    /// Pull monitoring data from this host via a pull connection, acting as the Checkmk site
+    ///
+    /// This command will connect to the locally running agent controller using the
+    /// credentials of the given connection, perform the TLS handshake and write the
+    /// received monitoring data to standard output. Useful for debugging pull connections.
+    PullOnce(PullOnceOpts),
+
+    /// Run as daemon and handle all pull and push connections
+    ///
+    /// Listen for incoming connections (as the 'pull' command does),
+    /// and send data to all Checkmk sites configured for 'push'
+    /// (as the 'push' command does) once a minute.
+    Daemon(DaemonOpts),
+
+    /// Collect monitoring data and write it to standard output
+    Dump,
+
+    /// Diagnose common problems
+    ///
+    /// Checks the configuration, the agent socket, the proxy settings and the connection registry,
+    /// and for every connection the certificate, the reachability of the agent receiver, the
+    /// registration at the site and the clock skew versus the site. Exits with 0 if no problems
+    /// were found, 1 on warnings and 2 on errors. Under Windows, --verify-service-account also
+    /// checks the rights of the account the service runs as.
+    Doctor(DoctorOpts),
+
+    /// Test the connection to a Checkmk site before registering
+    ///
+    /// Performs the DNS resolution of the server, connects to the agent receiver, does a TLS
+    /// handshake (showing the certificate chain presented by the server) and, given an API user,
+    /// an authenticated request without side effects. Each step is timed and reported separately.
+    /// Exits with 0 if all steps succeeded and with 2 otherwise.
+    TestConnection(TestConnectionOpts),
+
+    /// Query the registration status of this host
+    ///
+    /// Exits with 0 if everything is fine, with 1 if there are warnings (such as a certificate
+    /// about to expire or a mismatching connection mode) and with 2 if there are errors (such as
+    /// an unreachable site or a connection unknown to the site).
+    Status(StatusOpts),
+
+    /// Show the locations the controller reads and writes
+    ///
+    /// Lists the home dir, the config file, the state dir with the registry, the push spool, the
+    /// control socket, the log file and the agent socket, as resolved from the defaults and the
+    /// settings "state_dir", "push_spool_dir", "control_socket" and "log_file" of the config
+    /// file. Moving the state out of the home dir eases confining the controller with mandatory
+    /// access control policies (SELinux, AppArmor).
+    Paths(PathsOpts),
+
+    /// Delete a connection to a Checkmk instance
+    Delete(ConnectionOpts),
+
+    /// Delete all connections to Checkmk sites
+    DeleteAll(DeleteAllOpts),
+
+    /// Import a pull connection from file or standard input
+    ///
+    /// A connection is imported from the JSON-encoded connection information.
+    /// A compatible dataset can be created using the 'proxy-register' command.
+    Import(ImportOpts),
+
+    /// Configure the push interval of a connection
+    ///
+    /// Overrides the global push interval (setting "push_interval" in cmk-agent-ctl.toml,
+    /// 60 seconds by default) for a single connection.
+    PushInterval(PushIntervalOpts),
+
+    /// Configure the local address a connection connects from
+    ///
+    /// Overrides the global source address (setting "source_address" in cmk-agent-ctl.toml,
+    /// chosen by the operating system by default) for a single connection, eg. on hosts with
+    /// several interfaces of which only one may reach the monitoring network.
+    SourceAddress(SourceAddressOpts),
+
+    /// Configure the local data source of a connection
+    ///
+    /// Serves the output of another agent socket, a command or a static file to the site of a
+    /// single connection instead of the output of the agent socket, eg. a reduced set of sections
+    /// to the site of an external SOC. The post-processing applies nevertheless.
+    DataSource(DataSourceOpts),
+
+    /// Set or remove tags of a connection
+    ///
+    /// Tags are arbitrary metadata in the form KEY=VALUE, eg. "env=prod" or "owner=team-x". They
+    /// are shown by 'status' and can be used to select connections with 'status --tag' and
+    /// 'delete-all --tag'. Without arguments, the tags of the connection are shown.
+    Tag(TagOpts),
+
+    /// Assign a connection to the renamed host in the Checkmk site
+    ///
+    /// After a monitored host was renamed in the site, its connections still refer to the old
+    /// name. This updates the host of the given connection at the site, which requires an API
+    /// user, and the host name kept locally, eg. for automatic re-registrations.
+    RenameHost(RenameHostOpts),
+
+    /// Push monitoring data right away
+    ///
+    /// Asks the running daemon to push to the given connection, or to all push connections,
+    /// without waiting for the next push cycle. The daemon collects fresh monitoring data for
+    /// this, which is uploaded even if it did not change since the last push.
+    PushNow(PushNowOpts),
+
+    /// Inspect and manage the agent outputs spooled for push connections
+    ///
+    /// Agent outputs which could not be pushed are kept in the push spool and replayed with the
+    /// next successful push. After long outages, the backlog can be listed, dropped or replayed
+    /// right away.
+    Spool(SpoolOpts),
+
+    /// Change the log level of the running daemon
+    ///
+    /// Takes effect immediately and lasts until the daemon is restarted. The level is given in the
+    /// same format as the setting "log_level" in cmk-agent-ctl.toml, e.g.
+    /// "info, cmk_agent_ctl_lib::modes::push=debug".
+    LogLevel(LogLevelOpts),
+
+    /// Make the running daemon re-read its config file
+    ///
+    /// Applies the setting "log_level" in cmk-agent-ctl.toml, which also ends a log level set
+    /// with 'log-level'. Other settings take effect once the daemon is restarted.
+    Reload,
+
+    /// Manage the unencrypted legacy pull mode
+    ///
+    /// In legacy pull mode, monitoring data is served via plain TCP, just as without the
+    /// agent controller. This is only possible as long as no connection is registered.
+    LegacyPull(LegacyPullOpts),
+
+    /// Move this host from legacy pull mode to a TLS-encrypted pull connection
+    ///
+    /// Registers this host with the site like 'register', waits until the site has pulled via TLS
+    /// and reports what changed. The daemon has to be running. Legacy pull mode ends with the
+    /// registration, since the site pulls either encrypted or not. It stays disabled only once
+    /// the TLS pull succeeded: Otherwise, the new connection is deleted locally and legacy pull
+    /// mode is enabled again, so that the host remains monitored.
+    MigratePullToTls(MigratePullToTlsOpts),
+
+    /// Renew the certificate for a connection to a Checkmk instance.
+    ///
+    /// Only possible for non-imported connections. To renew imported connections,
+    /// please proxy-register and import again.
+    RenewCertificate(RenewCertificateOpts),
+
+    /// Access the connections proxy-register stored for tenants
+    ///
+    /// Each tenant has its own key, kept in the directory of the tenant and readable by the
+    /// owner only. The keys are wrapped with the vault key, the file "vault_key" in the home
+    /// dir. Grant access to the connections of a tenant via the permissions of its directory
+    /// and of the vault key.
+    Vault(VaultOpts),
+
+    /// Relay pull requests of a Checkmk site to agents in another network
+    ///
+    /// A relay, eg. in a DMZ, accepts the pull requests of the site for the hosts of the agents
+    /// it is paired with and forwards them. The agents only trust the relay, the site only
+    /// connects to the relay.
+    Relay(RelayOpts),
+
+    /// Replace the controller with the binary offered by the Checkmk site
+    ///
+    /// Asks the sites of the registered connections for a newer controller. Its version,
+    /// platform and hash have to be signed with the key configured as "self_update_key" in
+    /// cmk-agent-ctl.toml, and self-update has to be enabled with "self_update". The previous
+    /// binary is restored if the new one does not run, and kept with the suffix ".old".
+    SelfUpdate(SelfUpdateOpts),
+
+    /// Write a shell completion script to standard output
+    ///
+    /// Completes commands, options and their values, including the registered connections.
+    /// For example, for bash: 'cmk-agent-ctl completions bash > /etc/bash_completion.d/cmk-agent-ctl'.
+    Completions(CompletionsOpts),
+
+    /// Collect logs, config and statistics for a support ticket
+    ///
+    /// Writes a compressed tar archive with the log files, the config with passwords and other
+    /// secrets redacted, the registered connections without keys and certificates, and the usage
+    /// and connection statistics. Nothing is sent anywhere, attach the archive to the ticket.
+    SupportBundle(SupportBundleOpts),
+
+    /// Serve the registration endpoints of an agent receiver, for testing
+    ///
+    /// Answers registration requests and queries of the registration status without a Checkmk
+    /// site, st. registration flows can be tested in isolation. Any credentials are accepted. The
+    /// server certificate is generated on startup, so register with --trust-cert. Registrations
+    /// are only kept in memory.
+    #[cfg(feature = "mock-receiver")]
+    MockReceiver(MockReceiverOpts),
+}
+
+#[derive(Parser)]
+// #[command(author, version, about, long_about = None)]
+pub struct RegisterOpts {
+    #[clap(flatten)]
+    pub connection_opts: RegistrationConnectionOpts,
+
+    /// Name of this host in the monitoring site
+    // We are consistent with agent updater, which uses "hostname", not "host-name".
+    #[arg(long, short = 'H', long, value_parser = clap::value_parser!(String))]
+    pub hostname: Option<String>,
+
+    /// Derive the name of this host instead of passing it with --hostname. Either one of "fqdn",
+    /// "short", "cloud-instance-id" (AWS, Azure or GCP) and "reverse-dns" (of the primary
+    /// address), or a template combining them, eg. "{short}-{cloud-instance-id}". Can also be
+    /// configured as "hostname_from" in cmk-agent-ctl.toml. Only possible when registering this
+    /// host.
+    #[arg(long, conflicts_with = "hostname", value_parser = clap::value_parser!(host_name::HostNameSource))]
+    pub hostname_from: Option<host_name::HostNameSource>,
+
+    #[clap(flatten)]
+    pub host_creation_opts: HostCreationOpts,
+
+    /// Store the credentials, readable by the owner only, st. the daemon can register this host
+    /// again if the site rejects the connection later on, eg. after the host was recreated.
+    /// Requires "auto_reregister" in cmk-agent-ctl.toml. Only possible when registering this
+    /// host.
+    #[arg(long)]
+    pub keep_credentials: bool,
+}
+
+#[derive(Parser)]
+pub struct ProxyRegisterOpts {
+    #[clap(flatten)]
+    pub register_opts: RegisterOpts,
+
+    /// Store the connection encrypted in the vault of this tenant instead of writing it to
+    /// standard output. Hand it over with 'vault export'.
+    #[arg(long, short = 't')]
+    pub tenant: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct HostCreationOpts {
+    /// Create the host via the REST API of the site before registering, or, if it exists, update
+    /// its folder and labels. The changes still have to be activated in the site.
+    #[arg(long)]
+    pub create_host: bool,
+
+    /// Folder of the host, eg. "/linux/web". New hosts are created in the main folder by
+    /// default, existing hosts are not moved.
+    #[arg(long, requires = "create_host")]
+    pub folder: Option<String>,
+
+    /// Labels to add to the host
+    #[arg(long = "host-labels", value_name = "KEY:VALUE", value_parser = parse_agent_labels, requires = "create_host")]
+    pub host_labels: Vec<(String, String)>,
+
+    /// URL of the REST API, "https://<server>/<site>/check_mk/api/1.0" by default
+    #[arg(long, requires = "create_host")]
+    pub api_url: Option<reqwest::Url>,
+
+    /// User for the REST API, the user for registration by default
+    #[arg(long, requires = "create_host")]
+    pub api_user: Option<String>,
+
+    /// Password for the REST API user. Prompted for if not given.
+    #[arg(long, requires = "api_user")]
+    pub api_password: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct RegistrationConnectionOpts {
+    /// Address of the Checkmk site in the format "<server>" or "<server>:<port>"
+    #[arg(long = "server", short = 's', value_parser = clap::value_parser!(site_spec::ServerSpec))]
+    pub server_spec: site_spec::ServerSpec,
+
+    /// Name of the Checkmk site
+    #[arg(long, short = 'i')]
+    pub site: String,
+
+    /// API user to use for registration
+    #[arg(long, short = 'U')]
+    pub user: String,
+
+    /// Password for API user. Can also be entered interactively.
+    #[arg(long, short = 'P')]
+    pub password: Option<String>,
+
+    /// Blindly trust the server certificate of the Checkmk site
+    // We are consistent with agent updater, which uses "trust-cert"
+    #[arg(long = "trust-cert")]
+    pub trust_server_cert: bool,
+
+    /// How to ask whether to trust the server certificate and for the password. With "json", each
+    /// question is written to standard output as a JSON document in one line, eg.
+    /// {"question": "trust_server_certificate", "server": ..., "port": ..., "certificate": {...}},
+    /// and the answer is read from standard input as a JSON document in one line, eg.
+    /// {"trust": true} or {"password": "..."}. For installers showing their own dialogs.
+    #[arg(long, value_enum, default_value_t = PromptFormat::Text)]
+    pub prompt_format: PromptFormat,
+
+    /// Tag the connection with metadata in the form KEY=VALUE, eg. "env=prod". Can be given
+    /// several times. Tags of a previous registration with the site are kept, see also the mode
+    /// "tag".
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = parse_tag)]
+    pub tags: Vec<(String, String)>,
+
+    /// Register temporarily, eg. for lab machines and CI runners: The connection expires this
+    /// many seconds after the registration. Expired connections are neither pushed to nor served
+    /// anymore and can be removed with 'delete-all --expired'.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub expires_in: Option<u64>,
+
+    #[clap(flatten)]
+    pub client_opts: ClientOpts,
+
+    #[clap(flatten)]
+    pub reg_client_opts: RegistrationClientOpts,
+}
+
+#[derive(Parser)]
+pub struct DoctorOpts {
+    #[clap(flatten)]
+    pub client_opts: ClientOpts,
+
+    /// Check that the account the Windows service runs as, eg. a group Managed Service Account,
+    /// has the rights the controller requires: access to the home directory and the registry.
+    #[cfg(windows)]
+    #[arg(long)]
+    pub verify_service_account: bool,
+}
+
+#[derive(Parser)]
+pub struct ClientOpts {
+    /// Detect and use proxy settings configured on this system for outgoing HTTPS connections.
+    /// The default is to ignore configured proxies and to connect directly.
+    #[arg(short = 'd', long)]
+    pub detect_proxy: bool,
+
+    /// Determine the proxy per target URL, as configured on this system for split networks: Via
+    /// the Internet options under Windows, via the proxy auto-config (PAC) script given by
+    /// CMK_AGENT_CTL_PAC_URL or found via WPAD otherwise. Without such configuration, the proxy
+    /// settings of the environment are used.
+    #[arg(long, conflicts_with = "detect_proxy")]
+    pub auto_proxy: bool,
+}
+
+#[derive(Parser)]
+pub struct RegistrationClientOpts {
+    /// Enable TLS certificate validation for querying the agent receiver port from the Checkmk
+    /// REST API. By default, certificate validation is disabled because it is not security-relevant
+    /// at this stage, see werk #14715.
+    #[arg(long)]
+    pub validate_api_cert: bool,
+
+    /// Local IP address to connect to the site from, eg. on hosts with several interfaces of which
+    /// only one may reach the monitoring network. Kept for the registered connection, see also
+    /// the mode "source-address".
+    #[arg(long, value_name = "IP")]
+    pub source_address: Option<IpAddr>,
+
+    /// Protocol to speak with the agent receiver, for this registration and the registered
+    /// connection. gRPC requires a receiver or gateway serving the service
+    /// cmk.agent_receiver.v1.AgentReceiver, see proto/agent_receiver.proto, which sends the gRPC
+    /// status with the response headers.
+    #[arg(long, value_enum, default_value_t)]
+    pub receiver_protocol: ReceiverProtocol,
+
+    /// Transport to reach the agent receiver with, kept for the registered connection. The
+    /// registration itself always uses HTTPS.
+    #[arg(long, value_enum, default_value_t)]
+    pub transport: transport::TransportKind,
+}
+
+#[derive(Parser)]
+pub struct RegisterNewOpts {
+    #[clap(flatten)]
+    pub connection_opts: RegistrationConnectionOpts,
+
+    /// User-defined agent labels in the form KEY:VALUE. These labels supersede the automatic labels.
+    #[arg(long = "agent-labels", name = "KEY:VALUE",  value_parser = parse_agent_labels, )]
+    pub agent_labels_raw: Vec<(String, String)>,
+
+    /// Add labels describing the hardware, read from SMBIOS/DMI (Linux only): cmk/hw-vendor,
+    /// cmk/hw-model, cmk/hw-serial and cmk/firmware-version. Can also be configured as
+    /// "hardware_labels" in cmk-agent-ctl.toml, with which the daemon keeps them up to date.
+    #[arg(long)]
+    pub hardware_labels: bool,
+
+    /// Stop waiting for the site to approve the new host after this many seconds. Waits forever
+    /// by default.
+    #[arg(long, value_name = "SECONDS")]
+    pub approval_timeout: Option<u64>,
+
+    /// URL the site calls with its decision on the new host, eg. to notify a provisioning system.
+    /// Requires a site supporting approval callbacks.
+    #[arg(long, value_name = "URL")]
+    pub approval_callback_url: Option<reqwest::Url>,
+
+    /// Program to run once the site approved or declined the new host, or the approval timed
+    /// out. It gets the decision ("approved", "declined" or "timeout") in the environment
+    /// variable CMK_APPROVAL_DECISION, the UUID of the connection in CMK_APPROVAL_UUID and the
+    /// reason of a declined registration in CMK_APPROVAL_REASON.
+    #[arg(long, value_name = "PROGRAM")]
+    pub approval_hook: Option<std::path::PathBuf>,
+}
+
+//https://github.com/clap-rs/clap/blob/master/examples/tutorial_derive/04_02_validate.rs
+fn parse_agent_labels(s: &str) -> Result<(String, String), String> {
+    // TODO(sk): better to use something more rust, splitn: split_once and collect_tuple
+    match s.splitn(2, ':').collect::<Vec<&str>>()[..] {
+        [a, b] => Ok((a.to_owned(), b.to_owned())),
+        _ => Err(format!("invalid <KEY:VALUE>: no `:` found in `{s}`")),
+    }
+}
+
+pub fn parse_tag(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!(
+            "invalid <KEY=VALUE>: expected a non-empty key before `=` in `{s}`"
+        )),
+    }
+}
+
+#[cfg(unix)]
+#[derive(Parser)]
+pub struct PullOpts {
+    /// TCP port to listen on for incoming pull connections
+    #[arg(long, short = 'P', value_parser = site_spec::parse_port)]
+    pub port: Option<u16>,
+}
+
+#[cfg(windows)]
+#[derive(Parser)]
+pub struct PullOpts {
+    /// TCP port to listen on for incoming pull connections
+    #[arg(long, short = 'P', value_parser = site_spec::parse_port)]
+    pub port: Option<u16>,
+
+    /// Connection in format "type/peer"
+    /// where
+    ///     type is either "np", "ms" or "ip"
+    ///     peer is correct named pipe, mailslot address or ip address
+    /// examples
+    ///     "np/checkmk_agent"
+    ///     "ms/Global\\WinAgent_13"
+    ///     "ip/localhost:28250"
+    /// None means default behavior
+    #[arg(long, value_parser = clap::value_parser!(types::AgentChannel))]
+    pub agent_channel: Option<types::AgentChannel>,
+}
+
+#[derive(Parser)]
+pub struct PullOnceOpts {
+    #[clap(flatten)]
+    pub connection_opts: ConnectionOpts,
+
+    /// TCP port the agent controller listens on for incoming pull connections
+    #[arg(long, short = 'P', value_parser = site_spec::parse_port)]
+    pub port: Option<u16>,
+
+    /// Address the agent controller listens on for incoming pull connections
+    #[arg(long, default_value = "localhost")]
+    pub address: String,
+}
+
+#[derive(Parser)]
+pub struct DaemonOpts {
+    #[clap(flatten)]
+    pub pull_opts: PullOpts,
+
+    #[clap(flatten)]
+    pub client_opts: ClientOpts,
+
+    #[clap(flatten)]
+    pub reg_client_opts: RegistrationClientOpts,
+
+    #[cfg(windows)]
+    #[clap(flatten)]
+    pub service_opts: ServiceOpts,
+}
+
+#[cfg(windows)]
+#[derive(Parser)]
+pub struct ServiceOpts {
+    /// Run as Windows service, controlled by the service control manager
+    #[arg(long, conflicts_with = "service_debug")]
+    pub service: bool,
+
+    /// Run the service in the foreground for troubleshooting. Ctrl+C stops it, Ctrl+Break
+    /// pauses and continues the push scheduling.
+    #[arg(long)]
+    pub service_debug: bool,
+}
+
+#[derive(Parser)]
+pub struct TestConnectionOpts {
+    /// Checkmk site in the format "<server>/<site>" or "<server>:<port>/<site>". Without a
+    /// port, the port of the agent receiver is queried from the Checkmk REST API.
+    #[arg(name = "COORDINATES", value_parser = clap::value_parser!(site_spec::Coordinates))]
+    pub coordinates: site_spec::Coordinates,
+
+    /// API user for the authenticated request. Without a user, this step is skipped.
+    #[arg(long, short = 'U')]
+    pub user: Option<String>,
+
+    /// Password for API user. Can also be entered interactively.
+    #[arg(long, short = 'P', requires = "user")]
+    pub password: Option<String>,
+
+    #[clap(flatten)]
+    pub client_opts: ClientOpts,
+
+    #[clap(flatten)]
+    pub reg_client_opts: RegistrationClientOpts,
+}
+
+#[derive(Parser)]
+pub struct PathsOpts {
+    /// Write output in JSON format
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Parser)]
+pub struct StatusOpts {
+    /// Write output in JSON format, same as --output json
+    #[arg(long)]
+    pub json: bool,
+
+    /// Do not query the remote about our status.
+    /// Results of earlier queries are shown instead, if available.
+    #[arg(long)]
+    pub no_query_remote: bool,
+
+    /// Show the results of earlier remote queries instead of querying the remote again,
+    /// as long as they are at most this old (in seconds)
+    #[arg(long, value_name = "SECONDS", conflicts_with = "no_query_remote")]
+    pub max_age: Option<u64>,
+
+    /// Give up on querying a remote after this many seconds. The remotes are queried
+    /// concurrently, st. unreachable ones delay the status by this at most.
+    #[arg(long, value_name = "SECONDS", conflicts_with = "no_query_remote", value_parser = clap::value_parser!(u64).range(1..))]
+    pub query_timeout: Option<u64>,
+
+    /// Only report the given connection, specified either by its site address or its UUID
+    #[arg(long)]
+    pub connection: Option<String>,
+
+    /// Only report connections with this tag. Can be given several times, then all tags have to
+    /// match.
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = parse_tag)]
+    pub tags: Vec<(String, String)>,
+
+    /// Also report the state of the running daemon, such as its uptime and log level
+    #[arg(long)]
+    pub live: bool,
+
+    /// Keep refreshing the status and highlight changes, until interrupted.
+    /// Together with --json, one line of JSON is written per refresh.
+    #[arg(long)]
+    pub watch: bool,
+
+    /// Refresh interval of --watch in seconds
+    #[arg(long, default_value_t = 2, requires = "watch", value_parser = clap::value_parser!(u64).range(1..))]
+    pub interval: u64,
+
+    #[clap(flatten)]
+    pub client_opts: ClientOpts,
+}
+
+#[derive(Parser)]
+pub struct ConnectionOpts {
+    /// Target connection,
+    /// specified either by its site address or its UUID.
+    /// The site address is '<servername>/<site>', see the output of the
+    /// status command.
+    #[arg(name = "CONNECTION")]
+    pub connection: String,
+}
+
+#[derive(Parser)]
+pub struct DeleteAllOpts {
+    /// Enable insecure connections (no TLS, agent output will be accessible via TCP agent port without encryption)
+    #[arg(long)]
+    pub enable_insecure_connections: bool,
+
+    /// Only delete the connections with this tag, eg. "env=test". Can be given several times,
+    /// then all tags have to match. Imported connections have no tags and are kept.
+    #[arg(
+        long = "tag",
+        value_name = "KEY=VALUE",
+        value_parser = parse_tag,
+        conflicts_with = "enable_insecure_connections"
+    )]
+    pub tags: Vec<(String, String)>,
+
+    /// Only delete the connections which expired, see 'register --expires-in'. Together with
+    /// --tag, only the expired connections with the tags are deleted.
+    #[arg(long, conflicts_with = "enable_insecure_connections")]
+    pub expired: bool,
+}
+
+#[derive(Parser)]
+pub struct BootstrapOpts {
+    /// Only query the instance metadata service of this cloud provider, all are tried by default
+    #[arg(long, value_enum)]
+    pub provider: Option<cloud_metadata::CloudProvider>,
+
+    /// Read the user data from this file instead of the instance metadata service
+    #[arg(long, conflicts_with = "provider", value_hint = clap::ValueHint::FilePath)]
+    pub user_data_file: Option<std::path::PathBuf>,
+
+    /// Read the parameters from the registry key
+    /// HKEY_LOCAL_MACHINE\SOFTWARE\checkmk\agent\registration, as written by the MSI installer
+    /// from its properties or deployed via a group policy. The values are named like the keys of
+    /// the user data, "labels" is a multi-string value of KEY:VALUE lines and "trust_cert" a
+    /// DWORD. The password is deleted from the registry afterwards.
+    #[cfg(windows)]
+    #[arg(long, conflicts_with_all = ["provider", "user_data_file"])]
+    pub from_registry: bool,
+
+    #[clap(flatten)]
+    pub client_opts: ClientOpts,
+
+    #[clap(flatten)]
+    pub reg_client_opts: RegistrationClientOpts,
+}
+
+#[derive(Parser)]
+pub struct MigrateUpdaterOpts {
+    /// Config file of the agent updater, "cmk-update-agent.cfg" in the agent config dir by default
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub updater_config: Option<std::path::PathBuf>,
+
+    /// State file of the agent updater, "cmk-update-agent.state" by default
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub updater_state: Option<std::path::PathBuf>,
+
+    /// Name of this host in the monitoring site, instead of the one the agent updater knows
+    #[arg(long, short = 'H')]
+    pub hostname: Option<String>,
+
+    /// API user to use for registration, instead of the one the agent updater registered with
+    #[arg(long, short = 'U')]
+    pub user: Option<String>,
+
+    /// Password for API user. Can also be entered interactively.
+    #[arg(long, short = 'P')]
+    pub password: Option<String>,
+
+    /// Blindly trust the server certificate of the Checkmk site, unless it is issued by a
+    /// certificate the agent updater trusts
+    #[arg(long = "trust-cert")]
+    pub trust_server_cert: bool,
+
+    /// Only show what was read from the agent updater, without registering
+    #[arg(long)]
+    pub dry_run: bool,
+
+    #[clap(flatten)]
+    pub client_opts: ClientOpts,
+
+    #[clap(flatten)]
+    pub reg_client_opts: RegistrationClientOpts,
+}
+
+#[derive(Parser)]
+pub struct ImportOpts {
+    /// The file to import. If not provided, data is read from standard input.
+    #[arg(name = "CONNECTION_FILE")]
+    pub conn_file: Option<std::path::PathBuf>,
+
+    /// Do not import the connection right away, but stage it for a golden image. It is validated
+    /// and imported when the daemon first starts on a machine created from the image.
+    #[arg(long)]
+    pub for_image: bool,
+
+    /// Site the connection was registered with, eg. "server/site". The connection is then
+    /// imported as a pull connection of this site, whose certificate can be renewed.
+    #[arg(long, requires = "for_image", value_parser = clap::value_parser!(site_spec::SiteID))]
+    pub site: Option<site_spec::SiteID>,
+
+    /// Port of the agent receiver of the site, discovered on first start if not given
+    #[arg(long, requires = "site", value_parser = site_spec::parse_port)]
+    pub receiver_port: Option<u16>,
+
+    /// Replace the private key and the certificate of the connection on first start, so that
+    /// machines created from the image do not share them
+    #[arg(long, requires = "site")]
+    pub rekey: bool,
+}
+
+#[derive(Parser)]
+pub struct PushIntervalOpts {
+    #[clap(flatten)]
+    pub connection_opts: ConnectionOpts,
+
+    /// Push interval in seconds. Omit to use the global push interval again.
+    #[arg(name = "SECONDS", value_parser = clap::value_parser!(u64).range(constants::MIN_PUSH_INTERVAL..))]
+    pub push_interval: Option<u64>,
+}
+
+#[derive(Parser)]
+pub struct SourceAddressOpts {
+    #[clap(flatten)]
+    pub connection_opts: ConnectionOpts,
+
+    /// Local IP address. Omit to use the global source address again.
+    #[arg(name = "IP")]
+    pub source_address: Option<IpAddr>,
+}
+
+#[derive(Parser)]
+pub struct DataSourceOpts {
+    #[clap(flatten)]
+    pub connection_opts: ConnectionOpts,
+
+    /// Agent socket to collect from, an agent channel like "ms/..." or "localhost:6556" under
+    /// Windows
+    #[arg(long, conflicts_with_all = ["command", "file"])]
+    pub socket: Option<String>,
+
+    /// Command printing the agent output, given as program and arguments
+    #[arg(long, num_args = 1.., allow_hyphen_values = true, conflicts_with = "file")]
+    pub command: Option<Vec<String>>,
+
+    /// File containing the agent output
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub file: Option<std::path::PathBuf>,
+}
+
+#[derive(Parser)]
+pub struct TagOpts {
+    #[clap(flatten)]
+    pub connection_opts: ConnectionOpts,
+
+    /// Tags to set, existing tags with the same key are replaced
+    #[arg(name = "KEY=VALUE", value_parser = parse_tag)]
+    pub set: Vec<(String, String)>,
+
+    /// Key of a tag to remove. Can be given several times.
+    #[arg(long, value_name = "KEY")]
+    pub remove: Vec<String>,
+}
+
+#[derive(Parser)]
+pub struct RenameHostOpts {
+    #[clap(flatten)]
+    pub connection_opts: ConnectionOpts,
+
+    /// New name of the host in the monitoring site
+    #[arg(name = "HOSTNAME")]
+    pub host_name: String,
+
+    /// API user allowed to change the host
+    #[arg(long, short = 'U')]
+    pub user: String,
+
+    /// Password for API user. Can also be entered interactively.
+    #[arg(long, short = 'P')]
+    pub password: Option<String>,
+
+    #[clap(flatten)]
+    pub client_opts: ClientOpts,
+}
+
+#[derive(Parser)]
+pub struct PushNowOpts {
+    /// Target connection,
+    /// specified either by its site address or its UUID.
+    /// Omit to push to all push connections.
+    #[arg(name = "CONNECTION")]
+    pub connection: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct SpoolOpts {
+    #[command(subcommand)]
+    pub action: SpoolAction,
+}
+
+#[derive(Subcommand)]
+pub enum SpoolAction {
+    /// List the spooled agent outputs with their collection time and size
+    List(SpoolListOpts),
+
+    /// Remove spooled agent outputs without pushing them
+    Drop(SpoolDropOpts),
+
+    /// Push the spooled agent outputs, oldest first
+    ///
+    /// Stops at the first failure of a connection, the remaining agent outputs stay spooled.
+    Replay(SpoolReplayOpts),
+}
+
+#[derive(Parser)]
+pub struct SpoolListOpts {
+    /// Target connection,
+    /// specified either by its site address or its UUID.
+    /// Omit to list the spools of all push connections.
+    #[arg(name = "CONNECTION")]
+    pub connection: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct SpoolDropOpts {
+    #[clap(flatten)]
+    pub connection_opts: ConnectionOpts,
+
+    /// Only drop the agent output collected at this Unix timestamp, as shown by 'spool list'
+    #[arg(long)]
+    pub collected_at: Option<u64>,
+}
+
+#[derive(Parser)]
+pub struct SpoolReplayOpts {
+    /// Target connection,
+    /// specified either by its site address or its UUID.
+    /// Omit to replay the spools of all push connections.
+    #[arg(name = "CONNECTION")]
+    pub connection: Option<String>,
+
+    #[clap(flatten)]
+    pub client_opts: ClientOpts,
+}
+
+#[derive(Parser)]
+pub struct LogLevelOpts {
+    /// New log level, either a single level or a comma-separated list of module-specific levels.
+    /// Omit to restore the configured log level.
+    #[arg(name = "LEVEL")]
+    pub spec: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct LegacyPullOpts {
+    #[command(subcommand)]
+    pub action: LegacyPullAction,
+}
+
+#[derive(Subcommand)]
+pub enum LegacyPullAction {
+    /// Enable legacy pull mode
+    ///
+    /// Legacy pull mode is disabled automatically once the grace period has expired or a
+    /// connection is registered, whichever happens first.
+    Enable(LegacyPullEnableOpts),
+
+    /// Disable legacy pull mode
+    Disable,
+
+    /// Show whether legacy pull mode is enabled and when it was disabled
+    Status,
+}
+
+#[derive(Parser)]
+pub struct LegacyPullEnableOpts {
+    /// Disable legacy pull mode automatically after this number of hours.
+    /// Without this option, it stays enabled until the first registration.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub grace_period: Option<u64>,
+}
+
+#[derive(Parser)]
+pub struct MigratePullToTlsOpts {
+    #[clap(flatten)]
+    pub register_opts: RegisterOpts,
+
+    /// How long to wait for the site to pull via TLS, in seconds. Sites pull once a minute by
+    /// default.
+    #[arg(long, value_name = "SECONDS", default_value_t = 300)]
+    pub confirm_timeout: u64,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+#[derive(Parser)]
+pub struct SelfUpdateOpts {
+    #[clap(flatten)]
+    pub client_opts: ClientOpts,
+}
+
+#[derive(Parser)]
+pub struct CompletionsOpts {
+    #[arg(value_enum, required_unless_present = "connections")]
+    pub shell: Option<Shell>,
+
+    /// List the registered connections, called by the completion scripts
+    #[arg(long, hide = true, conflicts_with = "shell")]
+    pub connections: bool,
+}
+
+#[derive(Parser)]
+pub struct SupportBundleOpts {
+    /// Write the archive to this file instead of standard output
+    #[arg(long, short = 'o', value_hint = clap::ValueHint::FilePath)]
+    pub output: Option<std::path::PathBuf>,
+}
+
+#[derive(Parser)]
+pub struct RenewCertificateOpts {
+    #[clap(flatten)]
+    pub connection_opts: ConnectionOpts,
+
+    #[clap(flatten)]
+    pub client_opts: ClientOpts,
+}
+
+#[derive(Parser)]
+pub struct VaultOpts {
+    #[command(subcommand)]
+    pub action: VaultAction,
+}
+
+#[derive(Subcommand)]
+pub enum VaultAction {
+    /// List the tenants, or the stored connections of a tenant
+    List(VaultListOpts),
+
+    /// Write a stored connection to standard output, to import it on the host
+    Export(VaultHostOpts),
+
+    /// Remove a stored connection
+    Remove(VaultHostOpts),
+}
+
+#[derive(Parser)]
+pub struct VaultListOpts {
+    /// List the stored connections of this tenant
+    #[arg(long, short = 't')]
+    pub tenant: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct VaultHostOpts {
+    /// Tenant the connection is stored for
+    #[arg(long, short = 't')]
+    pub tenant: String,
+
+    /// Name of the host in the monitoring site
+    #[arg(long, short = 'H')]
+    pub hostname: String,
+}
+
+#[derive(Parser)]
+pub struct RelayOpts {
+    #[command(subcommand)]
+    pub action: RelayAction,
+}
+
+#[derive(Subcommand)]
+// Parsed once, its size does not matter
+#[allow(clippy::large_enum_variant)]
+pub enum RelayAction {
+    /// Pair with an agent
+    ///
+    /// Writes the connection to import on the host of the agent to standard output. Pairing an
+    /// agent again replaces its previous pairing.
+    Pair(RelayPairOpts),
+
+    /// Register the host of a paired agent with a Checkmk site
+    ///
+    /// The site then pulls the relay via this connection. The host has to exist in the site
+    /// and be configured for pull.
+    Register(RelayRegisterOpts),
+
+    /// Remove a paired agent, together with its connection to the site
+    Remove(RelayAgentOpts),
+
+    /// List the paired agents and their connections
+    List,
+
+    /// Serve pull requests of the sites
+    Serve(RelayServeOpts),
+}
+
+#[derive(Parser)]
+pub struct RelayAgentOpts {
+    /// Name under which the agent is paired
+    #[arg(long, short = 'n')]
+    pub name: String,
+}
+
+#[derive(Parser)]
+pub struct RelayPairOpts {
+    #[clap(flatten)]
+    pub agent_opts: RelayAgentOpts,
+
+    /// Address of the agent in the format "<server>" or "<server>:<port>", where port is the
+    /// pull port of the agent
+    #[arg(long, short = 'a', value_parser = clap::value_parser!(site_spec::ServerSpec))]
+    pub address: site_spec::ServerSpec,
+}
+
+#[derive(Parser)]
+pub struct RelayRegisterOpts {
+    #[clap(flatten)]
+    pub agent_opts: RelayAgentOpts,
+
+    #[clap(flatten)]
+    pub register_opts: RegisterOpts,
+}
+
+#[derive(Parser)]
+pub struct RelayServeOpts {
+    /// TCP port to listen on for pull requests of the sites
+    #[arg(long, short = 'P', value_parser = site_spec::parse_port)]
+    pub port: Option<u16>,
+}
+
+#[cfg(feature = "mock-receiver")]
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MockBehaviour {
+    /// Accept all requests
+    Ok,
+    /// Decline all registrations
+    Decline,
+    /// Answer each request after the given delay
+    Slow,
+    /// Fail requests at random with the given failure rate
+    Flaky,
+}
+
+#[cfg(feature = "mock-receiver")]
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MockConnectionMode {
+    Push,
+    Pull,
+}
+
+#[cfg(feature = "mock-receiver")]
+#[derive(Parser)]
+pub struct MockReceiverOpts {
+    /// TCP port to listen on, register with "--server <address>:<port>"
+    #[arg(long, short = 'P', default_value_t = constants::DEFAULT_MOCK_RECEIVER_PORT, value_parser = site_spec::parse_port)]
+    pub port: u16,
+
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1")]
+    pub address: String,
+
+    /// Name of the mocked Checkmk site
+    #[arg(long, short = 'i', default_value = "mock")]
+    pub site: String,
+
+    /// How to answer requests
+    #[arg(long, value_enum, default_value_t = MockBehaviour::Ok)]
+    pub behaviour: MockBehaviour,
+
+    /// Delay (in seconds) of the answers of a slow receiver
+    #[arg(long, default_value_t = constants::DEFAULT_MOCK_RECEIVER_DELAY)]
+    pub delay: u64,
+
+    /// Share of requests failing with 503 Service Unavailable in a flaky receiver
+    #[arg(long, default_value_t = 0.5, value_parser = parse_failure_rate)]
+    pub failure_rate: f64,
+
+    /// Connection mode handed out on registration
+    #[arg(long, value_enum, default_value_t = MockConnectionMode::Push)]
+    pub connection_mode: MockConnectionMode,
+}
+
+#[cfg(feature = "mock-receiver")]
+fn parse_failure_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!(
+            "invalid failure rate `{s}`, expected a number from 0 to 1"
+        )),
+    }
+}
+
+impl Cli {
+    /// Whether the log level was set explicitly on the command line
+    pub fn verbosity_given(&self) -> bool {
+        self.verbose > 0
+    }
+
+    pub fn logging_level(&self) -> String {
+        String::from(match self.verbose {
+            3.. => "debug, cmk_agent_ctl::http=trace",
+            2 => "debug",
+            1 => "info",
+            _ => "warn",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logging_level() {
+        assert_eq!(
+            (Cli {
+                verbose: 0,
+                log_format: None,
+                language: None,
+                container: false,
+                output: OutputFormat::Human,
+                resolve: vec![],
+                mode: Mode::Dump
+            })
+            .logging_level(),
+            "warn"
+        );
+        assert_eq!(
+            (Cli {
+                verbose: 1,
+                log_format: None,
+                language: None,
+                container: false,
+                output: OutputFormat::Human,
+                resolve: vec![],
+                mode: Mode::Dump
+            })
+            .logging_level(),
+            "info"
+        );
+        assert_eq!(
+            (Cli {
+                verbose: 2,
+                log_format: None,
+                language: None,
+                container: false,
+                output: OutputFormat::Human,
+                resolve: vec![],
+                mode: Mode::Dump
+            })
+            .logging_level(),
+            "debug"
+        );
+        assert_eq!(
+            (Cli {
+                verbose: 3,
+                log_format: None,
+                language: None,
+                container: false,
+                output: OutputFormat::Human,
+                resolve: vec![],
+                mode: Mode::Dump
+            })
+            .logging_level(),
+            "debug, cmk_agent_ctl::http=trace"
+        );
+    }
+
+    #[test]
+    fn test_parse_agent_labels_ok() {
+        assert_eq!(
+            parse_agent_labels("a:b").unwrap(),
+            (String::from("a"), String::from("b"))
+        );
+        assert_eq!(
+            parse_agent_labels("abc-123:456:def").unwrap(),
+            (String::from("abc-123"), String::from("456:def"))
+        );
+        assert_eq!(
+            parse_agent_labels("abc-123456-def").unwrap_err(),
+            "invalid <KEY:VALUE>: no `:` found in `abc-123456-def`"
+        );
+    }
+
+    #[test]
+    fn test_parse_agent_labels_error() {
+        assert!(parse_agent_labels("missing-colon").is_err(),);
+    }
+
+    #[test]
+    fn test_parse_tag() {
+        assert_eq!(
+            parse_tag("env=prod").unwrap(),
+            (String::from("env"), String::from("prod"))
+        );
+        assert_eq!(
+            parse_tag("query=a=b").unwrap(),
+            (String::from("query"), String::from("a=b"))
+        );
+        assert_eq!(parse_tag("empty=").unwrap().1, "");
+        assert!(parse_tag("=prod").is_err());
+        assert!(parse_tag("env").is_err());
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Description of all commands and options, derived from the command line definition. It is
+//! written by --help-json for tools generating wrappers and is the basis of the shell completions.
+
+use super::cli;
+use clap::CommandFactory;
+
+/// What the value of an argument is, st. completions can offer suitable candidates
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueKind {
+    /// One of the possible values
+    Choice,
+    /// Site address or UUID of a registered connection
+    Connection,
+    Path,
+    Other,
+}
+
+#[derive(serde::Serialize)]
+pub struct ArgSpec {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub long: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub short: Option<char>,
+    pub positional: bool,
+    pub required: bool,
+    /// Flags take no value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<ValueSpec>,
+    pub help: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct ValueSpec {
+    pub name: String,
+    pub kind: ValueKind,
+    pub multiple: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub possible_values: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub default_values: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct CommandSpec {
+    pub name: String,
+    pub about: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub long_about: String,
+    pub args: Vec<ArgSpec>,
+    pub subcommands: Vec<CommandSpec>,
+}
+
+impl CommandSpec {
+    pub fn of_cli() -> Self {
+        let mut command = cli::Cli::command();
+        // Adds the help and version flags
+        command.build();
+        Self::from(&command)
+    }
+
+    /// All options, eg. ["--verbose", "-v"]
+    pub fn option_names(&self) -> Vec<String> {
+        self.args
+            .iter()
+            .flat_map(|arg| arg.names())
+            .collect::<Vec<String>>()
+    }
+
+    /// Positional argument taking a connection, if any
+    pub fn completes_connection(&self) -> bool {
+        self.args.iter().any(|arg| {
+            arg.positional
+                && arg
+                    .value
+                    .as_ref()
+                    .is_some_and(|value| value.kind == ValueKind::Connection)
+        })
+    }
+
+    /// The command and all of its subcommands, along with the names leading to them
+    pub fn walk(&self) -> Vec<(Vec<&str>, &CommandSpec)> {
+        let mut commands = vec![(vec![self.name.as_str()], self)];
+        for subcommand in &self.subcommands {
+            for (mut path, command) in subcommand.walk() {
+                path.insert(0, self.name.as_str());
+                commands.push((path, command));
+            }
+        }
+        commands
+    }
+}
+
+impl ArgSpec {
+    pub fn names(&self) -> Vec<String> {
+        self.long
+            .iter()
+            .map(|long| format!("--{long}"))
+            .chain(self.short.iter().map(|short| format!("-{short}")))
+            .collect()
+    }
+}
+
+fn first_line(text: Option<&clap::builder::StyledStr>) -> String {
+    text.map(|text| text.to_string())
+        .unwrap_or_default()
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+impl From<&clap::Arg> for ArgSpec {
+    fn from(arg: &clap::Arg) -> Self {
+        Self {
+            id: arg.get_id().to_string(),
+            long: arg.get_long().map(String::from),
+            short: arg.get_short(),
+            positional: arg.is_positional(),
+            required: arg.is_required_set(),
+            value: arg
+                .get_action()
+                .takes_values()
+                .then(|| ValueSpec::from(arg)),
+            help: arg
+                .get_help()
+                .map(|help| help.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl From<&clap::Arg> for ValueSpec {
+    fn from(arg: &clap::Arg) -> Self {
+        let possible_values: Vec<String> = arg
+            .get_possible_values()
+            .iter()
+            .filter(|value| !value.is_hide_set())
+            .map(|value| value.get_name().to_string())
+            .collect();
+        let kind = if arg.get_id().as_str().eq_ignore_ascii_case("connection") {
+            ValueKind::Connection
+        } else if !possible_values.is_empty() {
+            ValueKind::Choice
+        } else if matches!(
+            arg.get_value_hint(),
+            clap::ValueHint::AnyPath | clap::ValueHint::FilePath | clap::ValueHint::DirPath
+        ) {
+            ValueKind::Path
+        } else {
+            ValueKind::Other
+        };
+        Self {
+            name: arg
+                .get_value_names()
+                .and_then(|names| names.first())
+                .map(|name| name.to_string())
+                .unwrap_or_else(|| arg.get_id().to_string().to_uppercase()),
+            kind,
+            multiple: matches!(arg.get_action(), clap::ArgAction::Append),
+            possible_values,
+            default_values: arg
+                .get_default_values()
+                .iter()
+                .map(|value| value.to_string_lossy().to_string())
+                .collect(),
+        }
+    }
+}
+
+impl From<&clap::Command> for CommandSpec {
+    fn from(command: &clap::Command) -> Self {
+        Self {
+            name: command.get_name().to_string(),
+            about: first_line(command.get_about()),
+            long_about: command
+                .get_long_about()
+                .map(|about| about.to_string())
+                .unwrap_or_default(),
+            args: command
+                .get_arguments()
+                .filter(|arg| !arg.is_hide_set())
+                .map(ArgSpec::from)
+                .collect(),
+            subcommands: command
+                .get_subcommands()
+                .filter(|subcommand| !subcommand.is_hide_set())
+                .map(CommandSpec::from)
+                .collect(),
+        }
+    }
+}
+
+/// Output of --help-json
+pub fn help_json() -> String {
+    serde_json::json!({
+        "version": super::constants::VERSION,
+        "command": CommandSpec::of_cli(),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod test_cli_spec {
+    use super::*;
+
+    fn command<'a>(spec: &'a CommandSpec, path: &[&str]) -> &'a CommandSpec {
+        path.iter().fold(spec, |spec, name| {
+            spec.subcommands
+                .iter()
+                .find(|subcommand| subcommand.name == *name)
+                .unwrap()
+        })
+    }
+
+    #[test]
+    fn test_spec() {
+        let spec = CommandSpec::of_cli();
+        assert_eq!(spec.name, "cmk-agent-ctl");
+        assert!(spec.option_names().contains(&String::from("--verbose")));
+        assert!(spec.option_names().contains(&String::from("-v")));
+
+        let delete = command(&spec, &["delete"]);
+        assert_eq!(delete.about, "Delete a connection to a Checkmk instance");
+        assert!(delete.completes_connection());
+        assert!(!command(&spec, &["delete-all"]).completes_connection());
+
+        let status = command(&spec, &["status"]);
+        let connection = status
+            .args
+            .iter()
+            .find(|arg| arg.long.as_deref() == Some("connection"))
+            .unwrap();
+        assert_eq!(
+            connection.value.as_ref().unwrap().kind,
+            ValueKind::Connection
+        );
+        assert!(!status.completes_connection());
+        let json = status
+            .args
+            .iter()
+            .find(|arg| arg.long.as_deref() == Some("json"))
+            .unwrap();
+        assert!(json.value.is_none());
+
+        let log_format = spec
+            .args
+            .iter()
+            .find(|arg| arg.long.as_deref() == Some("log-format"))
+            .unwrap()
+            .value
+            .as_ref()
+            .unwrap();
+        assert_eq!(log_format.kind, ValueKind::Choice);
+        assert_eq!(log_format.possible_values, vec!["text", "json"]);
+
+        assert!(spec
+            .walk()
+            .iter()
+            .any(|(path, _)| path == &["cmk-agent-ctl", "legacy-pull", "enable"]));
+    }
+
+    #[test]
+    fn test_help_json() {
+        let help: serde_json::Value = serde_json::from_str(&help_json()).unwrap();
+        assert_eq!(help["command"]["name"], "cmk-agent-ctl");
+        assert!(help["command"]["subcommands"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|subcommand| subcommand["name"] == "register"));
+    }
+}
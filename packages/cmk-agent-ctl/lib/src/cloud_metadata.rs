@@ -0,0 +1,220 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Queries of the instance metadata services of AWS (IMDSv2), Azure and GCP, which all answer on
+//! the same link-local address.
+
+use crate::constants;
+use anyhow::{bail, Context, Result as AnyhowResult};
+use std::time::Duration;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloudProvider {
+    Aws,
+    Azure,
+    Gcp,
+}
+
+impl CloudProvider {
+    pub const ALL: [Self; 3] = [Self::Aws, Self::Azure, Self::Gcp];
+}
+
+impl std::fmt::Display for CloudProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Aws => "AWS",
+                Self::Azure => "Azure",
+                Self::Gcp => "GCP",
+            }
+        )
+    }
+}
+
+pub struct MetadataService {
+    client: reqwest::blocking::Client,
+    base_url: String,
+}
+
+impl MetadataService {
+    pub fn new() -> AnyhowResult<Self> {
+        Self::with_base_url(format!("http://{}", constants::CLOUD_METADATA_ADDRESS))
+    }
+
+    pub fn with_base_url(base_url: String) -> AnyhowResult<Self> {
+        Ok(Self {
+            client: reqwest::blocking::Client::builder()
+                .no_proxy()
+                .timeout(Duration::from_secs(constants::CLOUD_METADATA_TIMEOUT))
+                .build()?,
+            base_url,
+        })
+    }
+
+    pub fn instance_id(&self) -> AnyhowResult<String> {
+        self.first_answer(&CloudProvider::ALL, |provider| match provider {
+            CloudProvider::Aws => self.aws("meta-data/instance-id"),
+            CloudProvider::Azure => self.azure("compute/vmId"),
+            CloudProvider::Gcp => self.gcp("instance/id"),
+        })
+        .map(|(_, instance_id)| instance_id)
+    }
+
+    /// User data of the instance, or under GCP, the value of the custom metadata key
+    /// "cmk-agent-ctl", falling back to "user-data"
+    pub fn user_data(&self, providers: &[CloudProvider]) -> AnyhowResult<(CloudProvider, String)> {
+        self.first_answer(providers, |provider| match provider {
+            CloudProvider::Aws => self.aws("user-data"),
+            CloudProvider::Azure => {
+                // Base64-encoded, unlike all other answers
+                let encoded = self.azure("compute/userData")?;
+                let decoded = openssl::base64::decode_block(encoded.trim())
+                    .context("User data is not base64-encoded")?;
+                Ok(String::from_utf8(decoded).context("User data is not UTF-8-encoded")?)
+            }
+            CloudProvider::Gcp => self
+                .gcp("instance/attributes/cmk-agent-ctl")
+                .or_else(|_| self.gcp("instance/attributes/user-data")),
+        })
+    }
+
+    fn first_answer(
+        &self,
+        providers: &[CloudProvider],
+        query: impl Fn(CloudProvider) -> AnyhowResult<String>,
+    ) -> AnyhowResult<(CloudProvider, String)> {
+        let mut errors = vec![];
+        for provider in providers {
+            match query(*provider).and_then(non_empty) {
+                Ok(answer) => return Ok((*provider, answer)),
+                Err(error) => errors.push(format!("{provider}: {error}")),
+            }
+        }
+        bail!(
+            "No instance metadata service of {} answered ({})",
+            providers
+                .iter()
+                .map(|provider| provider.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            errors.join(", ")
+        )
+    }
+
+    fn aws(&self, path: &str) -> AnyhowResult<String> {
+        // IMDSv2, requires a session token
+        let token = self
+            .client
+            .put(format!("{}/latest/api/token", self.base_url))
+            .header("X-aws-ec2-metadata-token-ttl-seconds", "60")
+            .send()?
+            .error_for_status()?
+            .text()?;
+        Ok(self
+            .client
+            .get(format!("{}/latest/{path}", self.base_url))
+            .header("X-aws-ec2-metadata-token", token)
+            .send()?
+            .error_for_status()?
+            .text()?)
+    }
+
+    fn azure(&self, path: &str) -> AnyhowResult<String> {
+        Ok(self
+            .client
+            .get(format!(
+                "{}/metadata/instance/{path}?api-version=2021-02-01&format=text",
+                self.base_url
+            ))
+            .header("Metadata", "true")
+            .send()?
+            .error_for_status()?
+            .text()?)
+    }
+
+    fn gcp(&self, path: &str) -> AnyhowResult<String> {
+        Ok(self
+            .client
+            .get(format!("{}/computeMetadata/v1/{path}", self.base_url))
+            .header("Metadata-Flavor", "Google")
+            .send()?
+            .error_for_status()?
+            .text()?)
+    }
+}
+
+fn non_empty(text: String) -> AnyhowResult<String> {
+    match text.trim() {
+        "" => bail!("Empty answer"),
+        trimmed => Ok(String::from(trimmed)),
+    }
+}
+
+#[cfg(test)]
+mod test_cloud_metadata {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Answers the paths with the given bodies, all other requests with 404
+    fn serve(answers: &'static [(&'static str, &'static str)]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+                let mut reader = BufReader::new(&stream);
+                reader.read_line(&mut request_line).unwrap();
+                // Skip the headers
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let path = request_line.split(' ').nth(1).unwrap_or_default();
+                let response = match answers.iter().find(|(prefix, _)| path.starts_with(prefix)) {
+                    Some((_, body)) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    ),
+                    None => String::from(
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    ),
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        format!("http://{address}")
+    }
+
+    #[test]
+    fn test_azure_user_data() {
+        let service = MetadataService::with_base_url(serve(&[(
+            "/metadata/instance/compute/userData",
+            // "site: mysite"
+            "c2l0ZTogbXlzaXRl",
+        )]))
+        .unwrap();
+        assert_eq!(
+            service.user_data(&CloudProvider::ALL).unwrap(),
+            (CloudProvider::Azure, String::from("site: mysite"))
+        );
+        assert!(service.user_data(&[CloudProvider::Gcp]).is_err());
+    }
+
+    #[test]
+    fn test_gcp_user_data() {
+        let service = MetadataService::with_base_url(serve(&[(
+            "/computeMetadata/v1/instance/attributes/user-data",
+            "site: mysite\n",
+        )]))
+        .unwrap();
+        assert_eq!(
+            service.user_data(&[CloudProvider::Gcp]).unwrap(),
+            (CloudProvider::Gcp, String::from("site: mysite"))
+        );
+    }
+}
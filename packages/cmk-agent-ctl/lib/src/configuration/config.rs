@@ -0,0 +1,4126 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::{
+    certs, cli, constants, data_source, dns, error_code, happy_eyeballs, host_name, key_store,
+    misc, monitoring_data, proxy, realtime, setup, site_spec, transport, types,
+};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_with::DisplayFromStr;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use string_enum::StringEnum;
+
+pub trait JSONLoader: DeserializeOwned {
+    fn load(path: &Path) -> AnyhowResult<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+pub trait JSONLoaderMissingSafe: JSONLoader + Default {
+    fn load_missing_safe(path: &Path) -> AnyhowResult<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Self::load(path)
+    }
+}
+
+pub trait TOMLLoader: DeserializeOwned {
+    fn load(path: &Path) -> AnyhowResult<Self> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+pub trait TOMLLoaderMissingSafe: TOMLLoader + Default {
+    fn load_missing_safe(path: &Path) -> AnyhowResult<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Self::load(path)
+    }
+}
+
+/// For the global and the per-connection push interval, which the command line restricts likewise
+fn deserialize_push_interval<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    let push_interval = Option::<u64>::deserialize(deserializer)?;
+    match push_interval {
+        Some(seconds) if seconds < constants::MIN_PUSH_INTERVAL => {
+            Err(serde::de::Error::custom(format!(
+                "Push interval of {} seconds is below the minimum of {} seconds",
+                seconds,
+                constants::MIN_PUSH_INTERVAL
+            )))
+        }
+        _ => Ok(push_interval),
+    }
+}
+
+pub struct RegisterExistingConfig {
+    pub connection_config: RegistrationConnectionConfig,
+    pub host_name: String,
+    /// Create or update the host via the REST API of the site before registering
+    pub host_creation: Option<HostCreationConfig>,
+    /// Store the credentials for registering again automatically, see reregistration
+    pub keep_credentials: bool,
+}
+
+impl RegisterExistingConfig {
+    /// Register this host, its name may be derived from the system
+    pub fn new(
+        runtime_config: RuntimeConfig,
+        register_opts: cli::RegisterOpts,
+    ) -> AnyhowResult<Self> {
+        let host_name = match (
+            &register_opts.hostname,
+            register_opts
+                .hostname_from
+                .as_ref()
+                .or(runtime_config.hostname_from.as_ref()),
+        ) {
+            (Some(host_name), _) => host_name.clone(),
+            (None, Some(host_name_source)) => {
+                let host_name = host_name_source
+                    .derive(&host_name::System)
+                    .context("Failed to derive the host name")?;
+                info!("Derived host name {}", host_name);
+                host_name
+            }
+            (None, None) => bail!(
+                "No host name given, please use --hostname or --hostname-from, or set \"hostname_from\" in {}",
+                constants::CONFIG_FILE
+            ),
+        };
+        Self::with_host_name(runtime_config, register_opts, host_name)
+    }
+
+    /// Register another host, whose name cannot be derived here
+    pub fn new_for_other_host(
+        runtime_config: RuntimeConfig,
+        register_opts: cli::RegisterOpts,
+    ) -> AnyhowResult<Self> {
+        if register_opts.hostname_from.is_some() {
+            bail!("Host names can only be derived when registering this host");
+        }
+        if register_opts.keep_credentials {
+            bail!("Credentials can only be kept when registering this host");
+        }
+        let host_name = register_opts
+            .hostname
+            .clone()
+            .context("Please specify the name of the host to register with --hostname")?;
+        Self::with_host_name(runtime_config, register_opts, host_name)
+    }
+
+    fn with_host_name(
+        runtime_config: RuntimeConfig,
+        register_opts: cli::RegisterOpts,
+        host_name: String,
+    ) -> AnyhowResult<Self> {
+        let connection_config =
+            RegistrationConnectionConfig::new(runtime_config, register_opts.connection_opts)?;
+        Ok(Self {
+            host_creation: HostCreationConfig::new(
+                &connection_config.site_id,
+                register_opts.host_creation_opts,
+            )?,
+            connection_config,
+            host_name,
+            keep_credentials: register_opts.keep_credentials,
+        })
+    }
+}
+
+pub struct HostCreationConfig {
+    pub api_url: reqwest::Url,
+    pub folder: Option<String>,
+    pub labels: types::AgentLabels,
+    /// Credentials for the REST API, the ones for registration if unset
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl HostCreationConfig {
+    fn new(
+        site_id: &site_spec::SiteID,
+        host_creation_opts: cli::HostCreationOpts,
+    ) -> AnyhowResult<Option<Self>> {
+        if !host_creation_opts.create_host {
+            return Ok(None);
+        }
+        let api_url = match host_creation_opts.api_url {
+            Some(api_url) => api_url,
+            None => reqwest::Url::parse(&format!(
+                "https://{}/{}/check_mk/api/1.0",
+                site_id.server, site_id.site
+            ))
+            .context(format!("Failed to construct the REST API URL of {site_id}"))?,
+        };
+        Ok(Some(Self {
+            api_url,
+            folder: host_creation_opts.folder,
+            labels: host_creation_opts.host_labels.into_iter().collect(),
+            username: host_creation_opts.api_user,
+            password: host_creation_opts.api_password,
+        }))
+    }
+}
+
+pub struct RegisterNewConfig {
+    pub connection_config: RegistrationConnectionConfig,
+    pub agent_labels: types::AgentLabels,
+    pub approval: ApprovalConfig,
+}
+
+/// Waiting for the site to approve or decline a new host
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ApprovalConfig {
+    /// Give up after this many seconds, wait forever otherwise
+    pub timeout: Option<u64>,
+    /// Passed to the site, which calls it with its decision
+    pub callback_url: Option<reqwest::Url>,
+    /// Run locally with the decision
+    pub hook: Option<PathBuf>,
+}
+
+impl RegisterNewConfig {
+    pub fn new(
+        connection_config: RegistrationConnectionConfig,
+        agent_labels: types::AgentLabels,
+    ) -> AnyhowResult<Self> {
+        Ok(Self {
+            connection_config,
+            agent_labels: Self::enrich_with_automatic_agent_labels(agent_labels)?,
+            approval: ApprovalConfig::default(),
+        })
+    }
+
+    pub fn with_approval(self, approval: ApprovalConfig) -> Self {
+        Self { approval, ..self }
+    }
+
+    fn automatic_agent_labels() -> AnyhowResult<types::AgentLabels> {
+        Ok(types::AgentLabels::from([
+            (
+                String::from("cmk/hostname-simple"),
+                String::from(
+                    gethostname::gethostname()
+                        .to_str()
+                        .context("Failed to transform host name to str")?,
+                ),
+            ),
+            (
+                String::from("cmk/os-family"),
+                String::from(std::env::consts::OS),
+            ),
+        ]))
+    }
+
+    fn enrich_with_automatic_agent_labels(
+        user_defined_agent_labels: types::AgentLabels,
+    ) -> AnyhowResult<types::AgentLabels> {
+        let mut agent_labels = Self::automatic_agent_labels()?;
+        agent_labels.extend(user_defined_agent_labels);
+        Ok(agent_labels)
+    }
+}
+
+#[derive(Clone)]
+pub struct RegistrationConnectionConfig {
+    pub site_id: site_spec::SiteID,
+    pub receiver_port: u16,
+    pub username: String,
+    pub password: Option<String>,
+    pub root_certificate: Option<String>,
+    pub trust_server_cert: bool,
+    pub prompt_format: cli::PromptFormat,
+    pub client_config: ClientConfig,
+    /// Local address given for this connection, kept in the registry
+    pub source_address: Option<IpAddr>,
+    /// Protocol given for this connection, kept in the registry
+    pub receiver_protocol: cli::ReceiverProtocol,
+    /// Transport given for this connection, kept in the registry
+    pub transport: transport::TransportKind,
+    /// Tags given for this connection, added to the ones of a previous registration
+    pub tags: BTreeMap<String, String>,
+    /// Let the connection expire this many seconds after the registration
+    pub expires_in: Option<u64>,
+}
+
+impl RegistrationConnectionConfig {
+    pub fn new(
+        runtime_config: RuntimeConfig,
+        registration_connection_opts: cli::RegistrationConnectionOpts,
+    ) -> AnyhowResult<Self> {
+        let site_id = site_spec::SiteID {
+            server: registration_connection_opts.server_spec.server,
+            site: registration_connection_opts.site,
+        };
+        let source_address = registration_connection_opts.reg_client_opts.source_address;
+        let receiver_protocol = registration_connection_opts
+            .reg_client_opts
+            .receiver_protocol;
+        let transport = registration_connection_opts.reg_client_opts.transport;
+        let client_config = ClientConfig::new(
+            runtime_config,
+            registration_connection_opts.client_opts,
+            Some(registration_connection_opts.reg_client_opts),
+        );
+        let receiver_port = (if let Some(p) = registration_connection_opts.server_spec.port {
+            Ok(p)
+        } else {
+            site_spec::discover_receiver_port(&site_id, &client_config)
+        })?;
+        Ok(Self {
+            site_id,
+            receiver_port,
+            username: registration_connection_opts.user,
+            password: registration_connection_opts.password,
+            root_certificate: None,
+            trust_server_cert: registration_connection_opts.trust_server_cert,
+            prompt_format: registration_connection_opts.prompt_format,
+            client_config,
+            source_address,
+            receiver_protocol,
+            transport,
+            tags: registration_connection_opts.tags.into_iter().collect(),
+            expires_in: registration_connection_opts.expires_in,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PreConfiguredConnections {
+    pub connections: HashMap<site_spec::SiteID, PreConfiguredConnection>,
+    pub agent_labels: types::AgentLabels,
+    pub keep_existing_connections: bool,
+}
+
+impl JSONLoader for PreConfiguredConnections {}
+
+#[derive(Deserialize, Clone)]
+pub struct PreConfiguredConnection {
+    pub port: Option<u16>,
+    pub credentials: types::Credentials,
+    pub root_cert: String,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct RuntimeConfig {
+    #[serde(default)]
+    allowed_ip: Option<Vec<String>>,
+
+    #[serde(default)]
+    pull_port: Option<u16>,
+
+    #[serde(default)]
+    legacy_pull_passphrase: Option<String>,
+
+    #[serde(default)]
+    pull_tunnel: Option<bool>,
+
+    #[serde(default)]
+    reverse_connection: Option<bool>,
+
+    #[serde(default)]
+    quic: Option<bool>,
+
+    #[serde(default)]
+    relay_port: Option<u16>,
+
+    #[serde(default)]
+    hostname_from: Option<host_name::HostNameSource>,
+
+    #[serde(default)]
+    detect_proxy: Option<bool>,
+
+    #[serde(default)]
+    auto_proxy: Option<bool>,
+
+    #[serde(default)]
+    source_address: Option<IpAddr>,
+
+    #[serde(default)]
+    ip_preference: Option<happy_eyeballs::IpPreference>,
+
+    #[serde(default)]
+    dns: Option<dns::DnsConfig>,
+
+    #[serde(default)]
+    user_agent: Option<UserAgent>,
+
+    #[serde(default)]
+    request_headers: Option<RequestHeaders>,
+
+    #[serde(default)]
+    network_timeouts: Option<NetworkTimeouts>,
+
+    #[serde(default)]
+    validate_api_cert: Option<bool>,
+
+    #[serde(default, deserialize_with = "deserialize_push_interval")]
+    push_interval: Option<u64>,
+
+    #[serde(default)]
+    push_jitter: Option<u64>,
+
+    #[serde(default)]
+    align_push: Option<bool>,
+
+    #[serde(default)]
+    push_spool_size: Option<usize>,
+
+    #[serde(default)]
+    push_spool_max_size: Option<u64>,
+
+    #[serde(default)]
+    max_outbound_requests: Option<usize>,
+
+    #[serde(default)]
+    max_payload_memory: Option<usize>,
+
+    #[serde(default)]
+    push_compression: Option<monitoring_data::PushCompression>,
+
+    #[serde(default)]
+    included_sections: Option<Vec<String>>,
+
+    #[serde(default)]
+    excluded_sections: Option<Vec<String>>,
+
+    #[serde(default)]
+    max_section_size: Option<usize>,
+
+    #[serde(default)]
+    max_section_sizes: Option<HashMap<String, usize>>,
+
+    #[serde(default)]
+    post_processors: Option<Vec<PostProcessorConfig>>,
+
+    #[serde(default)]
+    fallback_command: Option<Vec<String>>,
+
+    #[serde(default)]
+    fallback_command_timeout: Option<u64>,
+
+    #[serde(default)]
+    fallback_command_max_size: Option<usize>,
+
+    #[serde(default)]
+    payload_size_levels: Option<(u64, u64)>,
+
+    #[serde(default)]
+    section_size_levels: Option<(u64, u64)>,
+
+    #[serde(default)]
+    conditional_push: Option<bool>,
+
+    #[serde(default)]
+    conditional_push_ignored_sections: Option<Vec<String>>,
+
+    #[serde(default)]
+    conditional_push_max_age: Option<u64>,
+
+    #[serde(default)]
+    delta_push: Option<bool>,
+
+    #[serde(default)]
+    push_chunk_size: Option<usize>,
+
+    #[serde(default)]
+    sign_push: Option<bool>,
+
+    #[serde(default)]
+    push_failover: Option<HashMap<String, Vec<String>>>,
+
+    #[serde(default)]
+    auto_reregister: Option<bool>,
+
+    #[serde(default)]
+    hardware_labels: Option<bool>,
+
+    #[serde(default)]
+    status_section: Option<bool>,
+
+    #[serde(default)]
+    self_update: Option<bool>,
+
+    #[serde(default)]
+    self_update_key: Option<PathBuf>,
+
+    #[serde(default)]
+    realtime_sections: Option<Vec<realtime::RealtimeSection>>,
+
+    #[serde(default)]
+    realtime_interval: Option<u64>,
+
+    #[serde(default)]
+    metrics_port: Option<u16>,
+
+    #[serde(default)]
+    metrics_bind_address: Option<IpAddr>,
+
+    #[serde(default)]
+    log_level: Option<String>,
+
+    #[serde(default)]
+    log_format: Option<cli::LogFormat>,
+
+    #[serde(default)]
+    log_file: Option<PathBuf>,
+
+    #[serde(default)]
+    log_rotate_size: Option<u64>,
+
+    #[serde(default)]
+    log_rotate_age: Option<LogRotationAge>,
+
+    #[serde(default)]
+    log_keep_files: Option<usize>,
+
+    #[serde(default)]
+    log_compress: Option<bool>,
+
+    #[serde(default)]
+    log_backend: Option<LogBackend>,
+
+    #[serde(default)]
+    run_as_user: Option<String>,
+
+    #[serde(default)]
+    run_as_group: Option<String>,
+
+    #[serde(default)]
+    state_dir: Option<PathBuf>,
+
+    #[serde(default)]
+    push_spool_dir: Option<PathBuf>,
+
+    #[serde(default)]
+    control_socket: Option<PathBuf>,
+
+    #[serde(default)]
+    fips: Option<bool>,
+
+    #[serde(default)]
+    private_key_storage: Option<KeyStorage>,
+
+    #[serde(default)]
+    quarantine_invalid_connections: Option<bool>,
+}
+
+impl RuntimeConfig {
+    /// The log level can change while the daemon runs, see LoggingConfig for the one at startup
+    pub fn log_level(&self) -> Option<&str> {
+        self.log_level.as_deref()
+    }
+
+    /// Registrations of new hosts add the hardware labels, the daemon keeps them up to date
+    pub fn hardware_labels(&self) -> bool {
+        self.hardware_labels.unwrap_or(false)
+    }
+
+    pub fn dns(&self) -> dns::DnsConfig {
+        self.dns.clone().unwrap_or_default()
+    }
+
+    /// Move invalid entries of the registry aside instead of refusing to load it
+    pub fn quarantine_invalid_connections(&self) -> bool {
+        self.quarantine_invalid_connections.unwrap_or(false)
+    }
+}
+
+impl TOMLLoader for RuntimeConfig {}
+impl TOMLLoaderMissingSafe for RuntimeConfig {}
+
+/// Headers set by the agent controller itself, they cannot be configured
+const RESERVED_REQUEST_HEADERS: [&str; 11] = [
+    "authorization",
+    "collected-at",
+    "compression",
+    "content-encoding",
+    "content-length",
+    "content-type",
+    "host",
+    "signature",
+    "transfer-encoding",
+    "upload-length",
+    "upload-offset",
+];
+
+/// User-Agent sent to the agent receivers, validated when loading the config
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "String")]
+pub struct UserAgent(reqwest::header::HeaderValue);
+
+impl TryFrom<String> for UserAgent {
+    type Error = String;
+
+    fn try_from(user_agent: String) -> Result<Self, Self::Error> {
+        reqwest::header::HeaderValue::from_str(&user_agent)
+            .map(UserAgent)
+            .map_err(|_| format!("Invalid user agent '{user_agent}'"))
+    }
+}
+
+/// Extra headers sent to the agent receivers, eg. to pass WAF rules and API gateways or to
+/// identify the tenant in hosted environments
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(try_from = "BTreeMap<String, String>")]
+pub struct RequestHeaders(reqwest::header::HeaderMap);
+
+impl TryFrom<BTreeMap<String, String>> for RequestHeaders {
+    type Error = String;
+
+    fn try_from(headers: BTreeMap<String, String>) -> Result<Self, Self::Error> {
+        let mut header_map = reqwest::header::HeaderMap::new();
+        for (name, value) in headers {
+            let header_name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("Invalid header name '{name}'"))?;
+            if RESERVED_REQUEST_HEADERS.contains(&header_name.as_str()) {
+                return Err(format!("Header '{name}' cannot be configured"));
+            }
+            let header_value = reqwest::header::HeaderValue::from_str(&value)
+                .map_err(|_| format!("Invalid value of header '{name}'"))?;
+            header_map.insert(header_name, header_value);
+        }
+        Ok(RequestHeaders(header_map))
+    }
+}
+
+/// Timeouts (in seconds) of the calls to the agent receivers. Every call ends within the total
+/// timeout, st. a hanging receiver or proxy cannot stall a mode or a task of the daemon.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkTimeouts {
+    /// Establishing the TCP connection
+    pub connect: u64,
+    /// Waiting for data on an established connection without HTTP, eg. when fetching the
+    /// certificate of the receiver. HTTP requests are bounded by the total timeout instead.
+    pub read: u64,
+    /// The whole request, including the response body
+    pub total: u64,
+}
+
+impl Default for NetworkTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: setup::connection_timeout(),
+            read: constants::READ_TIMEOUT,
+            total: constants::REQUEST_TIMEOUT,
+        }
+    }
+}
+
+impl NetworkTimeouts {
+    pub fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect)
+    }
+
+    pub fn read(&self) -> Duration {
+        Duration::from_secs(self.read)
+    }
+
+    pub fn total(&self) -> Duration {
+        Duration::from_secs(self.total)
+    }
+
+    /// Shorter timeouts of single requests, eg. pushes, do not extend the total timeout
+    pub fn capped(&self, timeout: u64) -> Duration {
+        Duration::from_secs(timeout.min(self.total))
+    }
+
+    /// For long polls, which the receiver answers only after up to `wait` seconds
+    pub fn long_poll(&self, wait: u64) -> Self {
+        Self {
+            total: self.total.saturating_add(wait),
+            ..*self
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientConfig {
+    pub proxy_mode: proxy::ProxyMode,
+    pub validate_api_cert: bool,
+    /// Local address to connect from, eg. on hosts with several interfaces of which only one
+    /// reaches the sites. Connections may have their own.
+    pub source_address: Option<IpAddr>,
+    /// Address family to try first when connecting to the receivers, see happy_eyeballs
+    pub ip_preference: happy_eyeballs::IpPreference,
+    /// Sent with every request to the receivers, including the configured User-Agent
+    pub request_headers: reqwest::header::HeaderMap,
+    pub timeouts: NetworkTimeouts,
+}
+
+impl ClientConfig {
+    pub fn new(
+        runtime_config: RuntimeConfig,
+        client_opts: cli::ClientOpts,
+        reg_client_opts: Option<cli::RegistrationClientOpts>,
+    ) -> ClientConfig {
+        ClientConfig {
+            proxy_mode: if client_opts.auto_proxy || runtime_config.auto_proxy.unwrap_or(false) {
+                proxy::ProxyMode::Auto
+            } else if client_opts.detect_proxy || runtime_config.detect_proxy.unwrap_or(false) {
+                proxy::ProxyMode::Environment
+            } else {
+                proxy::ProxyMode::Direct
+            },
+            validate_api_cert: (if let Some(reg_client_opts) = &reg_client_opts {
+                reg_client_opts.validate_api_cert
+            } else {
+                false
+            }) || runtime_config.validate_api_cert.unwrap_or(false),
+            source_address: reg_client_opts
+                .and_then(|reg_client_opts| reg_client_opts.source_address)
+                .or(runtime_config.source_address),
+            ip_preference: runtime_config.ip_preference.unwrap_or_default(),
+            request_headers: {
+                let mut headers = runtime_config.request_headers.unwrap_or_default().0;
+                if let Some(UserAgent(user_agent)) = runtime_config.user_agent {
+                    headers.insert(reqwest::header::USER_AGENT, user_agent);
+                }
+                headers
+            },
+            timeouts: runtime_config.network_timeouts.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PushConfig {
+    pub push_interval: u64,
+    /// Upper limit (in seconds) of the random delay added to each push
+    pub push_jitter: u64,
+    /// Push at multiples of the push interval since the Unix epoch, e.g. at the full minute
+    pub align_push: bool,
+    /// Maximum number of failed pushes kept on disk per connection for replay, 0 disables spooling
+    pub push_spool_size: usize,
+    /// Maximum size (in bytes) of all spooled pushes together, the oldest ones are dropped first
+    pub push_spool_max_size: Option<u64>,
+    /// Maximum number of pushes to the agent receivers running at the same time
+    pub max_outbound_requests: usize,
+    /// Preferred compression of pushed agent data, receivers without support get zlib
+    pub push_compression: monitoring_data::PushCompression,
+    /// Skip uploading unchanged agent output, only send a heartbeat instead
+    pub conditional_push: bool,
+    /// Sections which are left out when checking the agent output for changes
+    pub conditional_push_ignored_sections: Vec<String>,
+    /// Maximum age (in seconds) of the last upload, before unchanged agent output is pushed anyway
+    pub conditional_push_max_age: u64,
+    /// Upload only the blocks which changed since the last push to receivers supporting it
+    pub delta_push: bool,
+    /// Upload compressed agent outputs larger than this (in bytes) in resumable chunks of this
+    /// size to receivers supporting it
+    pub push_chunk_size: Option<usize>,
+    /// Sign pushed agent outputs with the private key of the connection, st. receivers behind
+    /// TLS-terminating load balancers can verify them
+    pub sign_push: bool,
+    /// Push via QUIC to receivers supporting it (experimental)
+    pub quic: bool,
+    /// Further endpoints ("host" or "host:port") per site ID, pushed to while the registered one
+    /// is unavailable, eg. the standby receivers of an HA setup
+    pub push_failover: HashMap<String, Vec<String>>,
+    pub section_filter: SectionFilterConfig,
+    pub post_processors: Vec<PostProcessorConfig>,
+    pub fallback_command: Option<FallbackCommandConfig>,
+    pub payload_size: PayloadSizeConfig,
+    pub realtime: RealtimeConfig,
+    pub reregistration: ReregistrationConfig,
+    /// Send changed hardware labels to the sites, see hardware_labels
+    pub hardware_labels: bool,
+    /// Append the state of the controller to the pushed agent output, see status_section
+    pub status_section: bool,
+}
+
+impl PushConfig {
+    pub fn new(runtime_config: &RuntimeConfig) -> PushConfig {
+        PushConfig {
+            push_interval: runtime_config
+                .push_interval
+                .unwrap_or(constants::PUSH_INTERVAL),
+            push_jitter: runtime_config.push_jitter.unwrap_or(0),
+            align_push: runtime_config.align_push.unwrap_or(false),
+            push_spool_size: runtime_config
+                .push_spool_size
+                .unwrap_or(constants::PUSH_SPOOL_SIZE),
+            push_spool_max_size: runtime_config.push_spool_max_size,
+            max_outbound_requests: runtime_config
+                .max_outbound_requests
+                .unwrap_or(constants::MAX_OUTBOUND_REQUESTS)
+                .max(1),
+            push_compression: runtime_config.push_compression.unwrap_or_default(),
+            conditional_push: runtime_config.conditional_push.unwrap_or(false),
+            conditional_push_ignored_sections: runtime_config
+                .conditional_push_ignored_sections
+                .clone()
+                .unwrap_or_default(),
+            conditional_push_max_age: runtime_config
+                .conditional_push_max_age
+                .unwrap_or(constants::CONDITIONAL_PUSH_MAX_AGE),
+            delta_push: runtime_config.delta_push.unwrap_or(false),
+            push_chunk_size: runtime_config
+                .push_chunk_size
+                .map(|size| size.max(constants::MIN_PUSH_CHUNK_SIZE)),
+            sign_push: runtime_config.sign_push.unwrap_or(false),
+            quic: runtime_config.quic.unwrap_or(false),
+            push_failover: runtime_config.push_failover.clone().unwrap_or_default(),
+            section_filter: SectionFilterConfig::new(runtime_config),
+            post_processors: runtime_config.post_processors.clone().unwrap_or_default(),
+            fallback_command: FallbackCommandConfig::new(runtime_config),
+            payload_size: PayloadSizeConfig::new(runtime_config),
+            realtime: RealtimeConfig::new(runtime_config),
+            reregistration: ReregistrationConfig::new(runtime_config),
+            hardware_labels: runtime_config.hardware_labels(),
+            status_section: runtime_config.status_section.unwrap_or(false),
+        }
+    }
+
+    pub fn push_interval_for(&self, connection: &TrustedConnectionWithRemote) -> Duration {
+        Duration::from_secs(connection.push_interval.unwrap_or(self.push_interval))
+    }
+
+    /// Delay from now until the next push to the given connection. With alignment, this is the
+    /// time until the next multiple of the push interval on the wall clock. The jitter is capped
+    /// by the push interval, st. each interval still sees exactly one push.
+    pub fn next_push_delay(
+        &self,
+        connection: &TrustedConnectionWithRemote,
+        since_epoch: Duration,
+        rng: &mut impl rand::Rng,
+    ) -> Duration {
+        let interval = self.push_interval_for(connection);
+        let delay = if self.align_push {
+            interval - Duration::from_nanos((since_epoch.as_nanos() % interval.as_nanos()) as u64)
+        } else {
+            interval
+        };
+        let max_jitter = Duration::from_secs(self.push_jitter).min(interval);
+        if max_jitter.is_zero() {
+            return delay;
+        }
+        delay + rng.gen_range(Duration::ZERO..max_jitter)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoggingConfig {
+    /// Log level from the config file, None if overridden by the command line or RUST_LOG
+    pub level: Option<String>,
+    pub level_overridden: bool,
+    pub format: cli::LogFormat,
+}
+
+impl LoggingConfig {
+    pub fn new(runtime_config: &RuntimeConfig, cli: &cli::Cli) -> LoggingConfig {
+        let level_overridden = cli.verbosity_given() || std::env::var_os("RUST_LOG").is_some();
+        LoggingConfig {
+            level: if level_overridden {
+                None
+            } else {
+                runtime_config.log_level.clone()
+            },
+            level_overridden,
+            format: cli
+                .log_format
+                .or(runtime_config.log_format)
+                .unwrap_or(if cli.container {
+                    cli::LogFormat::Json
+                } else {
+                    cli::LogFormat::Text
+                }),
+        }
+    }
+}
+
+/// Rotate the log file at least this often, in addition to rotating it by size
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotationAge {
+    Hour,
+    Day,
+}
+
+/// Logging facility of the operating system the daemon logs to
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogBackend {
+    Journald,
+    Syslog,
+    EventLog,
+}
+
+/// Where the private keys of the registered connections are kept
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyStorage {
+    /// In the registry file
+    #[default]
+    File,
+    /// In the macOS Keychain, the registry file only refers to them
+    Keychain,
+}
+
+impl KeyStorage {
+    pub fn new(runtime_config: &RuntimeConfig) -> KeyStorage {
+        runtime_config.private_key_storage.unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogFileConfig {
+    /// Log file of the daemon under Unix, which logs to stderr if unset. Under Windows, the
+    /// location of the log file is fixed.
+    pub path: Option<PathBuf>,
+    /// Size (in bytes) at which the log file is rotated
+    pub rotate_size: u64,
+    pub rotate_age: Option<LogRotationAge>,
+    /// Number of rotated log files to keep, older ones are deleted
+    pub keep_files: usize,
+    /// Compress rotated log files
+    pub compress: bool,
+    /// Log to the system log in addition to the log file, or instead of stderr (Unix) or the
+    /// agent service (Windows)
+    pub backend: Option<LogBackend>,
+}
+
+impl LogFileConfig {
+    pub fn new(runtime_config: &RuntimeConfig) -> LogFileConfig {
+        LogFileConfig {
+            path: runtime_config.log_file.clone(),
+            rotate_size: runtime_config
+                .log_rotate_size
+                .unwrap_or(constants::log::FILE_MAX_SIZE),
+            rotate_age: runtime_config.log_rotate_age,
+            keep_files: runtime_config
+                .log_keep_files
+                .unwrap_or(constants::log::FILE_KEEP),
+            compress: runtime_config.log_compress.unwrap_or(false),
+            backend: runtime_config.log_backend,
+        }
+    }
+
+    /// Logging is set up before the config file is loaded regularly. Problems with the config
+    /// file are reported later on, until then, the defaults are used.
+    pub fn load(config_path: &Path) -> LogFileConfig {
+        LogFileConfig::new(&RuntimeConfig::load_missing_safe(config_path).unwrap_or_default())
+    }
+
+    pub fn criterion(&self) -> flexi_logger::Criterion {
+        match self.rotate_age {
+            None => flexi_logger::Criterion::Size(self.rotate_size),
+            Some(age) => flexi_logger::Criterion::AgeOrSize(
+                match age {
+                    LogRotationAge::Hour => flexi_logger::Age::Hour,
+                    LogRotationAge::Day => flexi_logger::Age::Day,
+                },
+                self.rotate_size,
+            ),
+        }
+    }
+
+    pub fn cleanup(&self) -> flexi_logger::Cleanup {
+        if self.compress {
+            flexi_logger::Cleanup::KeepCompressedFiles(self.keep_files)
+        } else {
+            flexi_logger::Cleanup::KeepLogFiles(self.keep_files)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricsConfig {
+    /// Port of the local endpoint serving metrics and health checks, disabled if unset
+    pub port: Option<u16>,
+    pub bind_address: IpAddr,
+}
+
+impl MetricsConfig {
+    pub fn new(runtime_config: &RuntimeConfig) -> MetricsConfig {
+        MetricsConfig {
+            port: runtime_config.metrics_port,
+            bind_address: runtime_config
+                .metrics_bind_address
+                .unwrap_or(constants::DEFAULT_METRICS_BIND_ADDRESS),
+        }
+    }
+
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.port
+            .map(|port| SocketAddr::new(self.bind_address, port))
+    }
+}
+
+/// Registering connections again with stored credentials once their site rejects them
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct ReregistrationConfig {
+    pub enabled: bool,
+}
+
+impl ReregistrationConfig {
+    pub fn new(runtime_config: &RuntimeConfig) -> ReregistrationConfig {
+        ReregistrationConfig {
+            enabled: runtime_config.auto_reregister.unwrap_or(false),
+        }
+    }
+}
+
+/// Replacing the controller binary with a newer one offered by the site
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelfUpdateConfig {
+    /// Public key (PEM) the binaries have to be signed with
+    pub signing_key: PathBuf,
+}
+
+impl SelfUpdateConfig {
+    /// Only available if enabled with "self_update" and a signing key is pinned
+    pub fn new(runtime_config: &RuntimeConfig) -> AnyhowResult<SelfUpdateConfig> {
+        if !runtime_config.self_update.unwrap_or(false) {
+            bail!("Self-update is disabled, enable it with \"self_update\" in the config")
+        }
+        Ok(SelfUpdateConfig {
+            signing_key: runtime_config.self_update_key.clone().context(
+                "No signing key for self-update, configure one with \"self_update_key\"",
+            )?,
+        })
+    }
+}
+
+/// Lightweight sections pushed at sub-minute intervals, separately from the full agent output
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct RealtimeConfig {
+    /// Real-time data is only pushed if any sections are configured
+    pub sections: Vec<realtime::RealtimeSection>,
+    pub interval: Duration,
+}
+
+impl RealtimeConfig {
+    pub fn new(runtime_config: &RuntimeConfig) -> RealtimeConfig {
+        RealtimeConfig {
+            sections: runtime_config.realtime_sections.clone().unwrap_or_default(),
+            interval: Duration::from_secs(
+                runtime_config
+                    .realtime_interval
+                    .unwrap_or(constants::REALTIME_INTERVAL)
+                    .max(1),
+            ),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.sections.is_empty()
+    }
+}
+
+/// Sections of the agent output which are stripped or truncated before it is dumped, pulled or
+/// pushed. Patterns match section names exactly, or by prefix if they end with "*". The section
+/// check_mk is always kept.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct SectionFilterConfig {
+    /// Only sections matching one of these patterns are kept, all if empty
+    pub included_sections: Vec<String>,
+    pub excluded_sections: Vec<String>,
+    /// Maximum size (in bytes) of a section, larger ones are truncated
+    pub max_section_size: Option<usize>,
+    /// Maximum sizes of the sections matching the patterns, overriding the general one
+    pub max_section_sizes: HashMap<String, usize>,
+}
+
+impl SectionFilterConfig {
+    pub fn new(runtime_config: &RuntimeConfig) -> SectionFilterConfig {
+        SectionFilterConfig {
+            included_sections: runtime_config.included_sections.clone().unwrap_or_default(),
+            excluded_sections: runtime_config.excluded_sections.clone().unwrap_or_default(),
+            max_section_size: runtime_config.max_section_size,
+            max_section_sizes: runtime_config.max_section_sizes.clone().unwrap_or_default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A step of the post-processing of the agent output before it is dumped, pulled or pushed. The
+/// steps are applied in the configured order, after the section filter.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PostProcessorConfig {
+    /// Replace the given user names by pseudonyms, which are stable across runs
+    AnonymizeUsers { users: Vec<String> },
+    /// Drop the lines matching the regular expression, only in the sections matching one of the
+    /// patterns if any are given
+    DropLines {
+        pattern: String,
+        #[serde(default)]
+        sections: Vec<String>,
+    },
+    /// Add a section with the given host labels
+    LabelSection { labels: types::AgentLabels },
+    /// Pipe the agent output through an external command, given as program and arguments
+    Command {
+        command: Vec<String>,
+        /// In seconds
+        #[serde(default)]
+        timeout: Option<u64>,
+    },
+}
+
+/// Command run if the agent socket is unreachable. Its output is served along with a section
+/// describing the error, st. the site sees why the agent is down instead of a failed connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FallbackCommandConfig {
+    /// Program and arguments
+    pub command: Vec<String>,
+    /// In seconds
+    pub timeout: u64,
+    /// Maximum size (in bytes) of the output, larger outputs are dropped
+    pub max_size: usize,
+}
+
+impl FallbackCommandConfig {
+    pub fn new(runtime_config: &RuntimeConfig) -> Option<FallbackCommandConfig> {
+        Some(FallbackCommandConfig {
+            command: runtime_config
+                .fallback_command
+                .clone()
+                .filter(|command| !command.is_empty())?,
+            timeout: runtime_config
+                .fallback_command_timeout
+                .unwrap_or(constants::FALLBACK_COMMAND_TIMEOUT),
+            max_size: runtime_config
+                .fallback_command_max_size
+                .unwrap_or(constants::FALLBACK_COMMAND_MAX_SIZE),
+        })
+    }
+}
+
+/// Levels (warning, critical, in bytes) for the size of the collected agent output. If any are
+/// configured, the sizes are recorded and reported in a local section and by the status mode.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct PayloadSizeConfig {
+    /// Levels for the whole agent output
+    pub total_levels: Option<(u64, u64)>,
+    /// Levels for every single section, piggybacked data counts per host
+    pub section_levels: Option<(u64, u64)>,
+}
+
+impl PayloadSizeConfig {
+    pub fn new(runtime_config: &RuntimeConfig) -> PayloadSizeConfig {
+        PayloadSizeConfig {
+            total_levels: runtime_config.payload_size_levels,
+            section_levels: runtime_config.section_size_levels,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.total_levels.is_some() || self.section_levels.is_some()
+    }
+}
+
+#[cfg(unix)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrivilegesConfig {
+    /// User the controller runs as. A daemon started as root switches to it once it has bound
+    /// its sockets.
+    pub user: String,
+    /// Group the controller runs as, the primary group of the user if unset
+    pub group: Option<String>,
+}
+
+#[cfg(unix)]
+impl PrivilegesConfig {
+    pub fn new(runtime_config: &RuntimeConfig) -> PrivilegesConfig {
+        PrivilegesConfig {
+            user: runtime_config
+                .run_as_user
+                .clone()
+                .unwrap_or_else(|| String::from(constants::CMK_AGENT_USER)),
+            group: runtime_config.run_as_group.clone(),
+        }
+    }
+
+    /// The user is switched before the config file is loaded regularly, like with
+    /// LogFileConfig::load, problems with the config file are reported later on.
+    pub fn load(config_path: &Path) -> PrivilegesConfig {
+        PrivilegesConfig::new(&RuntimeConfig::load_missing_safe(config_path).unwrap_or_default())
+    }
+}
+
+/// Locations of the files the controller writes, deviating from the home dir, eg. st. mandatory
+/// access control policies (SELinux, AppArmor) can confine the state separately. The directories
+/// have to exist and be writable for the user the controller runs as.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PathsConfig {
+    /// Registry, statistics and caches
+    pub state_dir: Option<PathBuf>,
+    /// Data which could not be pushed, the directory "push_spool" in the state dir by default
+    pub push_spool_dir: Option<PathBuf>,
+    /// Control socket of the daemon under Unix, in the state dir by default
+    pub control_socket: Option<PathBuf>,
+}
+
+impl PathsConfig {
+    pub fn new(runtime_config: &RuntimeConfig) -> PathsConfig {
+        PathsConfig {
+            state_dir: runtime_config.state_dir.clone(),
+            push_spool_dir: runtime_config.push_spool_dir.clone(),
+            control_socket: runtime_config.control_socket.clone(),
+        }
+    }
+
+    /// The paths are resolved before the config file is loaded regularly, like with
+    /// LogFileConfig::load, problems with the config file are reported later on.
+    pub fn load(config_path: &Path) -> PathsConfig {
+        PathsConfig::new(&RuntimeConfig::load_missing_safe(config_path).unwrap_or_default())
+    }
+}
+
+/// FIPS mode, always for builds with the feature "fips". It has to be enabled before anything
+/// uses OpenSSL, ie. before the config file is loaded regularly. Unlike with LogFileConfig::load,
+/// problems with the config file are reported right away, they must not disable FIPS mode.
+pub fn fips_mode(config_path: &Path) -> AnyhowResult<bool> {
+    Ok(cfg!(feature = "fips")
+        || RuntimeConfig::load_missing_safe(config_path)?
+            .fips
+            .unwrap_or(false))
+}
+
+#[derive(Clone)]
+pub struct PullConfig {
+    pub allowed_ip: Vec<String>,
+    pub port: u16,
+    pub max_connections: usize,
+    pub connection_timeout: u64,
+    pub agent_channel: types::AgentChannel,
+    /// Maximum memory (in bytes) taken by agent outputs buffered for pull requests, requests
+    /// exceeding it are rejected
+    pub max_payload_memory: Option<usize>,
+    /// Encrypt the agent output served in legacy pull mode, see legacy_encryption
+    pub legacy_pull_passphrase: Option<String>,
+    /// Also serve pull requests through tunnels opened to the agent receivers
+    pub pull_tunnel: bool,
+    /// Keep tunnels open to the agent receivers of all connections, over which the site also
+    /// triggers pushes
+    pub reverse_connection: bool,
+    /// Also serve pull requests via QUIC on the pull port (experimental)
+    pub quic: bool,
+    pub section_filter: SectionFilterConfig,
+    pub post_processors: Vec<PostProcessorConfig>,
+    pub fallback_command: Option<FallbackCommandConfig>,
+    pub payload_size: PayloadSizeConfig,
+    /// Append the state of the controller to the served agent output, see status_section
+    pub status_section: bool,
+    registry: Registry,
+    /// Number of expired connections when the pull connections were last handed out
+    expired: usize,
+}
+
+impl PullConfig {
+    pub fn new(
+        runtime_config: RuntimeConfig,
+        pull_opts: cli::PullOpts,
+        registry: Registry,
+    ) -> AnyhowResult<PullConfig> {
+        let section_filter = SectionFilterConfig::new(&runtime_config);
+        let fallback_command = FallbackCommandConfig::new(&runtime_config);
+        let payload_size = PayloadSizeConfig::new(&runtime_config);
+        let allowed_ip = runtime_config.allowed_ip.unwrap_or_default();
+        let port = pull_opts
+            .port
+            .or(runtime_config.pull_port)
+            .unwrap_or(constants::DEFAULT_PULL_PORT);
+        #[cfg(unix)]
+        let agent_channel = setup::agent_channel();
+        #[cfg(windows)]
+        let agent_channel = pull_opts.agent_channel.unwrap_or_else(setup::agent_channel);
+        Ok(PullConfig {
+            allowed_ip,
+            port,
+            max_connections: setup::max_connections(),
+            connection_timeout: setup::connection_timeout(),
+            agent_channel,
+            max_payload_memory: runtime_config.max_payload_memory,
+            legacy_pull_passphrase: runtime_config.legacy_pull_passphrase,
+            pull_tunnel: runtime_config.pull_tunnel.unwrap_or(false),
+            reverse_connection: runtime_config.reverse_connection.unwrap_or(false),
+            quic: runtime_config.quic.unwrap_or(false),
+            section_filter,
+            post_processors: runtime_config.post_processors.unwrap_or_default(),
+            fallback_command,
+            payload_size,
+            status_section: runtime_config.status_section.unwrap_or(false),
+            registry,
+            expired: 0,
+        })
+    }
+
+    /// Re-read the registry. Also reports a change once another connection expired, st. it is no
+    /// longer served.
+    pub fn refresh(&mut self) -> AnyhowResult<bool> {
+        if self.registry.enforce_legacy_pull_grace_period()? {
+            warn!("Grace period for legacy pull mode has expired, disabled legacy pull mode.");
+        }
+        let changed = self.registry.refresh()?;
+        let expired = self.registry.count_expired(misc::unix_now());
+        if expired > self.expired {
+            warn!(
+                "{} connection(s) expired and are no longer served, remove them with \
+                 'cmk-agent-ctl delete-all --expired'",
+                expired
+            );
+        }
+        let expired_changed = expired != self.expired;
+        self.expired = expired;
+        Ok(changed || expired_changed)
+    }
+
+    pub fn allow_legacy_pull(&self) -> bool {
+        self.registry.is_legacy_pull_active()
+    }
+
+    /// The pull connections to serve, without the expired ones
+    pub fn get_pull_connections(&self) -> impl Iterator<Item = &TrustedConnection> {
+        self.registry.get_active_pull_connections(misc::unix_now())
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn has_connections(&self) -> bool {
+        !self.registry.is_pull_empty()
+    }
+}
+
+pub struct RelayConfig {
+    pub port: u16,
+    pub allowed_ip: Vec<String>,
+    pub connection_timeout: u64,
+}
+
+impl RelayConfig {
+    pub fn new(runtime_config: &RuntimeConfig, relay_serve_opts: &cli::RelayServeOpts) -> Self {
+        RelayConfig {
+            port: relay_serve_opts
+                .port
+                .or(runtime_config.relay_port)
+                .unwrap_or(constants::DEFAULT_RELAY_PORT),
+            allowed_ip: runtime_config.allowed_ip.clone().unwrap_or_default(),
+            connection_timeout: setup::connection_timeout(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Registry {
+    connections: RegisteredConnections,
+    path: PathBuf,
+    last_reload: Option<SystemTime>,
+    legacy_pull_marker: LegacyPullMarker,
+    key_storage: KeyStorage,
+}
+
+impl Registry {
+    #[cfg(test)]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn new(path: impl AsRef<Path>) -> AnyhowResult<Self> {
+        Ok(Self {
+            connections: RegisteredConnections::default(),
+            path: PathBuf::from(path.as_ref()),
+            last_reload: None,
+            legacy_pull_marker: LegacyPullMarker::new(Self::make_path_legacy_pull_marker(path)?),
+            key_storage: KeyStorage::default(),
+        })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> AnyhowResult<Self> {
+        Ok(Self {
+            connections: RegisteredConnections::load_missing_safe(path.as_ref())?
+                .map_private_keys(|connection| key_store::resolve(&connection.private_key))?,
+            path: PathBuf::from(path.as_ref()),
+            last_reload: mtime(path.as_ref())?,
+            legacy_pull_marker: LegacyPullMarker::new(Self::make_path_legacy_pull_marker(path)?),
+            key_storage: KeyStorage::default(),
+        })
+    }
+
+    /// Where the private keys are kept when saving. Keys kept in the Keychain are always loaded.
+    pub fn set_key_storage(&mut self, key_storage: KeyStorage) {
+        self.key_storage = key_storage;
+    }
+
+    pub fn refresh(&mut self) -> AnyhowResult<bool> {
+        match (mtime(&self.path)?, self.last_reload) {
+            (Some(now), Some(then)) => {
+                match now.duration_since(then) {
+                    Ok(time) if time.is_zero() => {
+                        // No change.
+                        Ok(false)
+                    }
+                    _ => {
+                        // This also covers Err(_), which means "negative time".
+                        // This may occur due to clock adjustments.
+                        // Force reload in this case.
+                        // Otherwise, we have a regular posive duration, which means
+                        // that our registration was touched.
+                        self.reload()?;
+                        Ok(true)
+                    }
+                }
+            }
+
+            (None, None) => {
+                // Still no file there -> No change.
+                Ok(false)
+            }
+
+            _ => {
+                // File was deleted or is new
+                self.reload()?;
+                Ok(true)
+            }
+        }
+    }
+
+    pub fn save(&self) -> AnyhowResult<()> {
+        // Eg. a legacy registry which is being migrated cannot refer to keys in the Keychain
+        let previous_key_references = RegisteredConnections::load_missing_safe(&self.path)
+            .map(|connections| connections.key_references())
+            .unwrap_or_default();
+        let connections = match self.key_storage {
+            KeyStorage::File => self.connections.clone(),
+            KeyStorage::Keychain => self.connections.clone().map_private_keys(|connection| {
+                key_store::store(&connection.uuid, &connection.private_key)
+            })?,
+        };
+        Self::write_connections(&self.path, &connections)?;
+        for uuid in previous_key_references.difference(&connections.key_references()) {
+            if let Err(err) = key_store::forget(uuid) {
+                warn!("Failed to remove private key of deleted connection {uuid}: {err:?}");
+            }
+        }
+        self.legacy_pull_marker.remove(if self.is_empty() {
+            "All connections were deleted"
+        } else {
+            "A connection was registered"
+        })?;
+        Ok(())
+    }
+
+    /// Load the registry, moving entries which cannot be loaded to the quarantine file instead of
+    /// failing. The registry is rewritten without them, the valid connections stay usable.
+    pub fn from_file_quarantining(path: impl AsRef<Path>) -> AnyhowResult<Self> {
+        let error = match Self::from_file(path.as_ref()) {
+            Ok(registry) => return Ok(registry),
+            Err(error) => error,
+        };
+        let content = fs::read_to_string(path.as_ref())?;
+        let (connections, quarantined) = match serde_json::from_str(&content) {
+            Ok(serde_json::Value::Object(sections)) => RegisteredConnections::partition(sections),
+            // Nothing to salvage
+            _ => (
+                RegisteredConnections::default(),
+                vec![QuarantinedConnection::new(
+                    "registry",
+                    None,
+                    serde_json::Value::String(content),
+                    &error,
+                )],
+            ),
+        };
+        for entry in &quarantined {
+            warn!(
+                "Quarantining invalid entry {} of registry: {}",
+                entry.describe(),
+                entry.reason
+            );
+        }
+        let quarantine_path = Self::quarantine_path(path.as_ref());
+        let mut quarantine = Quarantine::load_missing_safe(&quarantine_path)
+            .context("Failed to load quarantined connections")?;
+        quarantine.0.extend(quarantined);
+        quarantine.save(&quarantine_path)?;
+        Self::write_connections(path.as_ref(), &connections)?;
+        Self::from_file(path)
+    }
+
+    /// Where invalid entries of the registry are moved to, next to the registry
+    pub fn quarantine_path(registry_path: &Path) -> PathBuf {
+        let mut path = registry_path.as_os_str().to_owned();
+        path.push(".quarantine");
+        PathBuf::from(path)
+    }
+
+    /// Where registrations with rejected credentials are tracked, next to the registry
+    pub fn registration_throttle_path(&self) -> PathBuf {
+        self.path
+            .with_file_name(constants::REGISTRATION_THROTTLE_FILE)
+    }
+
+    /// Where the credentials for automatic re-registrations are stored, next to the registry
+    pub fn reregistration_credentials_path(&self) -> PathBuf {
+        self.path
+            .with_file_name(constants::REREGISTRATION_CREDENTIALS_FILE)
+    }
+
+    /// Where the checksum of the registry is written to when saving
+    pub fn checksum_path(registry_path: &Path) -> PathBuf {
+        let mut path = registry_path.as_os_str().to_owned();
+        path.push(".sha256");
+        PathBuf::from(path)
+    }
+
+    /// Check that the registry file was not modified other than by saving it. A registry without
+    /// checksum, eg. written by an older version, passes.
+    pub fn verify_checksum(&self) -> AnyhowResult<()> {
+        let expected = match fs::read_to_string(Self::checksum_path(&self.path)) {
+            Ok(expected) => expected,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).context("Failed to read checksum of registry"),
+        };
+        let content = fs::read(&self.path).context("Failed to read registry")?;
+        if registry_checksum(&content) != expected.trim() {
+            bail!(
+                "Registry {:?} does not match its checksum, it was modified or is corrupted",
+                self.path
+            );
+        }
+        Ok(())
+    }
+
+    pub fn is_standard_pull_empty(&self) -> bool {
+        self.connections.pull.is_empty()
+    }
+
+    pub fn is_imported_pull_empty(&self) -> bool {
+        self.connections.pull_imported.is_empty()
+    }
+
+    pub fn is_pull_empty(&self) -> bool {
+        self.is_standard_pull_empty() & self.is_imported_pull_empty()
+    }
+
+    pub fn is_push_empty(&self) -> bool {
+        self.connections.push.is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.is_push_empty() & self.is_pull_empty()
+    }
+
+    pub fn get_standard_pull_connections(
+        &self,
+    ) -> impl Iterator<Item = (&site_spec::SiteID, &TrustedConnectionWithRemote)> {
+        self.connections.pull.iter()
+    }
+
+    pub fn get_imported_pull_connections(&self) -> impl Iterator<Item = &TrustedConnection> {
+        self.connections.pull_imported.iter()
+    }
+
+    pub fn get_pull_connections(&self) -> impl Iterator<Item = &TrustedConnection> {
+        self.connections
+            .pull
+            .values()
+            .map(|c| &c.trust)
+            .chain(self.connections.pull_imported.iter())
+    }
+
+    pub fn get_push_connections(
+        &self,
+    ) -> impl Iterator<Item = (&site_spec::SiteID, &TrustedConnectionWithRemote)> {
+        self.connections.push.iter()
+    }
+
+    /// Push connections which did not expire at the given time
+    pub fn get_active_push_connections(
+        &self,
+        now: u64,
+    ) -> impl Iterator<Item = (&site_spec::SiteID, &TrustedConnectionWithRemote)> {
+        self.connections
+            .push
+            .iter()
+            .filter(move |(_, connection)| !connection.is_expired(now))
+    }
+
+    /// Pull connections which did not expire at the given time, imported ones never expire
+    pub fn get_active_pull_connections(
+        &self,
+        now: u64,
+    ) -> impl Iterator<Item = &TrustedConnection> {
+        self.connections
+            .pull
+            .values()
+            .filter(move |connection| !connection.is_expired(now))
+            .map(|c| &c.trust)
+            .chain(self.connections.pull_imported.iter())
+    }
+
+    pub fn count_expired(&self, now: u64) -> usize {
+        self.connections
+            .push
+            .values()
+            .chain(self.connections.pull.values())
+            .filter(|connection| connection.is_expired(now))
+            .count()
+    }
+
+    pub fn get_standard_connections_as_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (&site_spec::SiteID, &mut TrustedConnectionWithRemote)> {
+        self.connections
+            .push
+            .iter_mut()
+            .chain(self.connections.pull.iter_mut())
+    }
+
+    pub fn get_registered_site_ids(&self) -> impl Iterator<Item = &site_spec::SiteID> {
+        self.connections
+            .pull
+            .keys()
+            .chain(self.connections.push.keys())
+    }
+
+    pub fn get_connection_as_mut(
+        &mut self,
+        site_id: &site_spec::SiteID,
+    ) -> Option<&mut TrustedConnectionWithRemote> {
+        self.connections
+            .pull
+            .get_mut(site_id)
+            .or_else(|| self.connections.push.get_mut(site_id))
+    }
+
+    pub fn register_connection(
+        &mut self,
+        connection_mode: &ConnectionMode,
+        site_id: &site_spec::SiteID,
+        connection: TrustedConnectionWithRemote,
+    ) {
+        let (insert_connections, remove_connections) = match connection_mode {
+            ConnectionMode::Push => (&mut self.connections.push, &mut self.connections.pull),
+            ConnectionMode::Pull => (&mut self.connections.pull, &mut self.connections.push),
+        };
+        remove_connections.remove(site_id);
+        insert_connections.insert(site_id.clone(), connection);
+    }
+
+    pub fn register_imported_connection(&mut self, connection: TrustedConnection) {
+        self.connections.pull_imported.insert(connection);
+    }
+
+    /// Returns the mode and the UUID of the deleted connection
+    pub fn delete_standard_connection(
+        &mut self,
+        site_id: &site_spec::SiteID,
+    ) -> AnyhowResult<(ConnectionMode, uuid::Uuid)> {
+        if let Some(connection) = self.connections.push.remove(site_id) {
+            return Ok((ConnectionMode::Push, connection.trust.uuid));
+        }
+        if let Some(connection) = self.connections.pull.remove(site_id) {
+            return Ok((ConnectionMode::Pull, connection.trust.uuid));
+        }
+        Err(error_code::CodedError::new(
+            error_code::ErrorCode::UnknownConnection,
+            format!("Connection '{site_id}' not found"),
+        )
+        .into())
+    }
+
+    pub fn delete_imported_connection(&mut self, uuid: &uuid::Uuid) -> AnyhowResult<()> {
+        if self.connections.pull_imported.remove(uuid) {
+            return Ok(());
+        };
+        Err(error_code::CodedError::new(
+            error_code::ErrorCode::UnknownConnection,
+            format!("Imported pull connection with UUID {uuid} not found"),
+        )
+        .into())
+    }
+
+    pub fn clear(&mut self) {
+        self.connections.push.clear();
+        self.connections.pull.clear();
+        self.clear_imported();
+    }
+
+    pub fn clear_imported(&mut self) {
+        self.connections.pull_imported.clear();
+    }
+
+    pub fn is_legacy_pull_active(&self) -> bool {
+        self.is_empty() && self.legacy_pull_marker.exists() && !self.legacy_pull_marker.is_expired()
+    }
+
+    /// Enable legacy pull mode, either for the given grace period or until the first registration.
+    pub fn activate_legacy_pull(&self, grace_period: Option<Duration>) -> AnyhowResult<()> {
+        if !self.is_empty() {
+            bail!("Cannot enable legacy pull mode since there are registered connections")
+        }
+        self.legacy_pull_marker
+            .create(grace_period.map(|period| misc::unix_now() + period.as_secs()))
+            .context("Failed to activate legacy pull mode")
+    }
+
+    pub fn deactivate_legacy_pull(&self, reason: &str) -> AnyhowResult<()> {
+        self.legacy_pull_marker
+            .remove(reason)
+            .context("Failed to deactivate legacy pull mode")
+    }
+
+    /// Disable legacy pull mode if its grace period has expired. Returns true if it was disabled
+    /// by this call.
+    pub fn enforce_legacy_pull_grace_period(&self) -> AnyhowResult<bool> {
+        if !(self.legacy_pull_marker.exists() && self.legacy_pull_marker.is_expired()) {
+            return Ok(false);
+        }
+        self.deactivate_legacy_pull("Grace period expired")?;
+        Ok(true)
+    }
+
+    pub fn legacy_pull_window(&self) -> AnyhowResult<LegacyPullWindow> {
+        self.legacy_pull_marker.window()
+    }
+
+    pub fn retrieve_standard_connection_by_uuid(
+        &self,
+        uuid: &uuid::Uuid,
+    ) -> Option<site_spec::SiteID> {
+        for (site_id, connection) in self
+            .get_push_connections()
+            .chain(self.get_standard_pull_connections())
+        {
+            if &connection.trust.uuid == uuid {
+                return Some(site_id.clone());
+            }
+        }
+        None
+    }
+
+    fn write_connections(path: &Path, connections: &RegisteredConnections) -> AnyhowResult<()> {
+        let tmp_path = Self::make_tmp_path_for_save(path);
+        let content = serde_json::to_string_pretty(connections)?;
+        fs::write(&tmp_path, &content)?;
+        fs::rename(&tmp_path, path)?;
+        #[cfg(unix)]
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        #[cfg(windows)]
+        crate::service_account::restrict_access(path)
+            .context(format!("Failed to restrict access to {path:?}"))?;
+        fs::write(
+            Self::checksum_path(path),
+            registry_checksum(content.as_bytes()),
+        )?;
+        Ok(())
+    }
+
+    fn make_tmp_path_for_save(path: &Path) -> PathBuf {
+        let mut tmp_path = PathBuf::from(path);
+        let mut ext = tmp_path
+            .extension()
+            .map(|ext| ext.to_owned())
+            .unwrap_or_else(|| ffi::OsString::from(""));
+        ext.push(".tmp");
+        tmp_path.set_extension(ext);
+        tmp_path
+    }
+
+    fn make_path_legacy_pull_marker(registry_path: impl AsRef<Path>) -> AnyhowResult<PathBuf> {
+        Ok(registry_path
+            .as_ref()
+            .parent()
+            .context("Failed to determine parent path of connection registry")?
+            .join("allow-legacy-pull"))
+    }
+
+    fn reload(&mut self) -> AnyhowResult<()> {
+        self.connections = RegisteredConnections::load_missing_safe(&self.path)?
+            .map_private_keys(|connection| key_store::resolve(&connection.private_key))?;
+        self.last_reload = mtime(&self.path)?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+struct RegisteredConnections {
+    #[serde(default)]
+    push: HashMap<site_spec::SiteID, TrustedConnectionWithRemote>,
+
+    #[serde(default)]
+    pull: HashMap<site_spec::SiteID, TrustedConnectionWithRemote>,
+
+    #[serde(default)]
+    pull_imported: std::collections::HashSet<TrustedConnection>,
+}
+
+impl JSONLoader for RegisteredConnections {}
+impl JSONLoaderMissingSafe for RegisteredConnections {}
+
+impl RegisteredConnections {
+    fn map_private_keys(
+        self,
+        f: impl Fn(&TrustedConnection) -> AnyhowResult<String>,
+    ) -> AnyhowResult<Self> {
+        let map = |mut connection: TrustedConnection| -> AnyhowResult<TrustedConnection> {
+            connection.private_key = f(&connection)?;
+            Ok(connection)
+        };
+        let map_with_remote =
+            |(site_id, mut connection): (site_spec::SiteID, TrustedConnectionWithRemote)| {
+                connection.trust = map(connection.trust)?;
+                Ok((site_id, connection))
+            };
+        Ok(Self {
+            push: self
+                .push
+                .into_iter()
+                .map(map_with_remote)
+                .collect::<AnyhowResult<_>>()?,
+            pull: self
+                .pull
+                .into_iter()
+                .map(map_with_remote)
+                .collect::<AnyhowResult<_>>()?,
+            pull_imported: self
+                .pull_imported
+                .into_iter()
+                .map(map)
+                .collect::<AnyhowResult<_>>()?,
+        })
+    }
+
+    /// Split the sections of a registry into the entries which can be loaded and the ones which
+    /// cannot. Private keys kept in the Keychain stay references.
+    fn partition(
+        sections: serde_json::Map<String, serde_json::Value>,
+    ) -> (Self, Vec<QuarantinedConnection>) {
+        let mut connections = Self::default();
+        let mut quarantined = vec![];
+        for (section, entries) in sections {
+            let result = match (section.as_str(), entries) {
+                ("push" | "pull", serde_json::Value::Object(entries)) => {
+                    for (site_id, entry) in entries {
+                        match Self::validate_with_remote(&site_id, entry.clone()) {
+                            Ok((site_id, connection)) if section == "push" => {
+                                connections.push.insert(site_id, connection);
+                            }
+                            Ok((site_id, connection)) => {
+                                connections.pull.insert(site_id, connection);
+                            }
+                            Err(error) => quarantined.push(QuarantinedConnection::new(
+                                &section,
+                                Some(site_id),
+                                entry,
+                                &error,
+                            )),
+                        }
+                    }
+                    Ok(())
+                }
+                ("pull_imported", serde_json::Value::Array(entries)) => {
+                    for entry in entries {
+                        match serde_json::from_value::<TrustedConnection>(entry.clone())
+                            .map_err(anyhow::Error::from)
+                            .and_then(|connection| connection.validate().map(|_| connection))
+                        {
+                            Ok(connection) => {
+                                connections.pull_imported.insert(connection);
+                            }
+                            Err(error) => quarantined
+                                .push(QuarantinedConnection::new(&section, None, entry, &error)),
+                        }
+                    }
+                    Ok(())
+                }
+                (_, entries) => Err((entries, anyhow::anyhow!("Unknown or malformed section"))),
+            };
+            if let Err((entries, error)) = result {
+                quarantined.push(QuarantinedConnection::new(&section, None, entries, &error));
+            }
+        }
+        (connections, quarantined)
+    }
+
+    fn validate_with_remote(
+        site_id: &str,
+        entry: serde_json::Value,
+    ) -> AnyhowResult<(site_spec::SiteID, TrustedConnectionWithRemote)> {
+        let site_id: site_spec::SiteID = site_id.parse()?;
+        let connection: TrustedConnectionWithRemote = serde_json::from_value(entry)?;
+        connection.trust.validate()?;
+        Ok((site_id, connection))
+    }
+
+    /// UUIDs of the connections whose private keys are kept in the Keychain
+    fn key_references(&self) -> std::collections::HashSet<uuid::Uuid> {
+        self.push
+            .values()
+            .chain(self.pull.values())
+            .map(|connection| &connection.trust)
+            .chain(self.pull_imported.iter())
+            .filter(|connection| key_store::is_reference(&connection.private_key))
+            .map(|connection| connection.uuid)
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Eq, Debug, Clone)]
+pub struct TrustedConnectionWithRemote {
+    #[serde(flatten)]
+    pub trust: TrustedConnection,
+    pub receiver_port: u16,
+    /// Overrides the global push interval (in seconds) for this connection
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_push_interval"
+    )]
+    pub push_interval: Option<u64>,
+    /// Last hostname the receiver reported for this connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<KnownHostname>,
+    /// User-defined metadata, eg. "env" -> "prod"
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    /// Unix timestamp after which the connection is neither pushed to nor served anymore, for
+    /// temporary registrations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+impl PartialEq for TrustedConnectionWithRemote {
+    fn eq(&self, other: &Self) -> bool {
+        self.trust == other.trust
+    }
+}
+
+impl TrustedConnectionWithRemote {
+    /// Remember the hostname the receiver reported at the given time. Returns whether anything
+    /// changed, an unchanged hostname only gets a new timestamp once in a while, st. the registry
+    /// is rarely rewritten.
+    pub fn record_hostname(&mut self, hostname: &str, seen_at: u64) -> bool {
+        if let Some(known) = &self.hostname {
+            let unchanged = known.hostname == hostname
+                && seen_at.saturating_sub(known.last_seen)
+                    < constants::KNOWN_HOSTNAME_REFRESH_INTERVAL;
+            if unchanged || seen_at < known.last_seen {
+                return false;
+            }
+        }
+        self.hostname = Some(KnownHostname {
+            hostname: String::from(hostname),
+            last_seen: seen_at,
+        });
+        true
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires.map(|expires| now >= expires).unwrap_or(false)
+    }
+
+    /// Whether the connection carries all the given tags
+    pub fn has_tags(&self, tags: &[(String, String)]) -> bool {
+        tags.iter()
+            .all(|(key, value)| self.tags.get(key) == Some(value))
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct KnownHostname {
+    pub hostname: String,
+    /// Unix timestamp of the last time the receiver reported the hostname
+    pub last_seen: u64,
+}
+
+#[serde_with::serde_as]
+#[derive(Serialize, Deserialize, Eq, Debug, Clone)]
+pub struct TrustedConnection {
+    #[serde_as(as = "DisplayFromStr")]
+    pub uuid: uuid::Uuid,
+    pub private_key: String,
+    pub certificate: String,
+    pub root_cert: String,
+    /// Local address to connect to the receiver from, overrides the setting "source_address"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_address: Option<IpAddr>,
+    /// Protocol to speak with the receiver
+    #[serde(default, skip_serializing_if = "cli::ReceiverProtocol::is_rest")]
+    pub receiver_protocol: cli::ReceiverProtocol,
+    /// Transport to reach the receiver with, see transport
+    #[serde(default, skip_serializing_if = "transport::TransportKind::is_https")]
+    pub transport: transport::TransportKind,
+    /// Local source of the agent output for this connection, the agent socket if none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_source: Option<data_source::DataSource>,
+}
+
+impl PartialEq for TrustedConnection {
+    fn eq(&self, other: &Self) -> bool {
+        self.uuid == other.uuid
+    }
+}
+
+impl std::hash::Hash for TrustedConnection {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.uuid.hash(state);
+    }
+}
+
+impl std::borrow::Borrow<uuid::Uuid> for TrustedConnection {
+    fn borrow(&self) -> &uuid::Uuid {
+        &self.uuid
+    }
+}
+
+impl TrustedConnection {
+    /// Check that the connection can be used, for quarantining it otherwise
+    fn validate(&self) -> AnyhowResult<()> {
+        certs::rustls_certificate(&self.root_cert).context("Invalid root certificate")?;
+        certs::rustls_certificate(&self.certificate).context("Invalid certificate")?;
+        certs::rustls_private_key(&key_store::resolve(&self.private_key)?)
+            .context("Invalid private key")?;
+        Ok(())
+    }
+
+    pub fn tls_handshake_credentials(&self) -> AnyhowResult<certs::HandshakeCredentials> {
+        Ok(certs::HandshakeCredentials {
+            server_root_cert: &self.root_cert,
+            client_identity: Some(self.identity()?),
+        })
+    }
+
+    fn identity(&self) -> AnyhowResult<certs::TLSIdentity> {
+        Ok(certs::TLSIdentity {
+            cert_chain: vec![certs::rustls_certificate(&self.certificate)?],
+            key_der: certs::rustls_private_key(&self.private_key)?,
+        })
+    }
+}
+
+/// An entry of the registry which could not be loaded, as kept in the quarantine file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuarantinedConnection {
+    /// Section of the registry the entry was found in, eg. push
+    pub section: String,
+    /// Site ID of push and pull connections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site_id: Option<String>,
+    /// Unix timestamp
+    pub quarantined_at: u64,
+    pub reason: String,
+    /// The entry as found in the registry, for restoring it manually
+    pub entry: serde_json::Value,
+}
+
+impl QuarantinedConnection {
+    fn new(
+        section: &str,
+        site_id: Option<String>,
+        entry: serde_json::Value,
+        error: &anyhow::Error,
+    ) -> Self {
+        Self {
+            section: String::from(section),
+            site_id,
+            quarantined_at: misc::unix_now(),
+            reason: format!("{error:#}"),
+            entry,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match &self.site_id {
+            Some(site_id) => format!("{} ({})", site_id, self.section),
+            None => self.section.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Quarantine(pub Vec<QuarantinedConnection>);
+
+impl JSONLoader for Quarantine {}
+impl JSONLoaderMissingSafe for Quarantine {}
+
+impl Quarantine {
+    fn save(&self, path: &Path) -> AnyhowResult<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?).context(format!(
+            "Failed to write quarantined connections to {path:?}"
+        ))?;
+        #[cfg(unix)]
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        Ok(())
+    }
+}
+
+/// Records when legacy pull mode was enabled, until when it may stay enabled and when and why it
+/// was disabled. All timestamps are seconds since the Unix epoch.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct LegacyPullWindow {
+    #[serde(default)]
+    pub enabled_at: Option<u64>,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub cutoff: Option<LegacyPullCutoff>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LegacyPullCutoff {
+    pub at: u64,
+    pub reason: String,
+}
+
+impl JSONLoader for LegacyPullWindow {}
+impl JSONLoaderMissingSafe for LegacyPullWindow {}
+
+impl LegacyPullWindow {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LegacyPullMarker(std::path::PathBuf);
+
+impl LegacyPullMarker {
+    fn new(path: impl AsRef<Path>) -> Self {
+        Self(PathBuf::from(path.as_ref()))
+    }
+
+    fn exists(&self) -> bool {
+        self.0.exists()
+    }
+
+    fn window_path(&self) -> PathBuf {
+        self.0.with_extension("json")
+    }
+
+    fn window(&self) -> AnyhowResult<LegacyPullWindow> {
+        LegacyPullWindow::load_missing_safe(&self.window_path())
+    }
+
+    fn save_window(&self, window: &LegacyPullWindow) -> std::io::Result<()> {
+        fs::write(self.window_path(), serde_json::to_string_pretty(window)?)
+    }
+
+    fn is_expired(&self) -> bool {
+        // A marker without (readable) window stems from older versions and never expires
+        self.window()
+            .map(|window| window.is_expired(misc::unix_now()))
+            .unwrap_or(false)
+    }
+
+    fn remove(&self, reason: &str) -> std::io::Result<()> {
+        if !&self.exists() {
+            return Ok(());
+        }
+
+        fs::remove_file(&self.0)?;
+        let mut window = self.window().unwrap_or_default();
+        window.cutoff = Some(LegacyPullCutoff {
+            at: misc::unix_now(),
+            reason: String::from(reason),
+        });
+        self.save_window(&window)
+    }
+
+    fn create(&self, expires_at: Option<u64>) -> std::io::Result<()> {
+        fs::write(
+            &self.0,
+            "This file has been placed as a marker for cmk-agent-ctl\n\
+            to allow unencrypted legacy agent pull mode.\n\
+            It will be removed automatically on first successful agent registration\n\
+            or once the grace period set via `cmk-agent-ctl legacy-pull enable` expires.\n\
+            You can remove it manually to disallow legacy mode, but note that\n\
+            for regular operation you need to register the agent anyway.\n\
+            \n\
+            To secure the connection run `cmk-agent-ctl register`.\n",
+        )?;
+        self.save_window(&LegacyPullWindow {
+            enabled_at: Some(misc::unix_now()),
+            expires_at,
+            cutoff: None,
+        })
+    }
+}
+
+#[derive(StringEnum, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub enum ConnectionMode {
+    /// `push-agent`
+    #[serde(rename = "push-agent")]
+    Push,
+    /// `pull-agent`
+    #[serde(rename = "pull-agent")]
+    Pull,
+}
+
+fn registry_checksum(content: &[u8]) -> String {
+    openssl::sha::sha256(content)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn mtime(path: &Path) -> AnyhowResult<Option<SystemTime>> {
+    Ok(if path.exists() {
+        Some(fs::metadata(path)?.modified()?)
+    } else {
+        None
+    })
+}
+
+#[cfg(test)]
+pub mod test_helpers {
+    use crate::config::{ConnectionMode, Registry, TrustedConnection, TrustedConnectionWithRemote};
+    use crate::site_spec;
+    use std::collections::BTreeMap;
+    use std::convert::From;
+    use std::str::FromStr;
+
+    pub struct TestRegistry {
+        pub registry: Registry,
+        _dir: Option<tempfile::TempDir>,
+    }
+
+    impl Drop for TestRegistry {
+        fn drop(&mut self) {
+            // We can manage to move the TempDir out (close() needs to) by replacing it with None.
+            // drop(self._dir);
+            self._dir.take().unwrap().close().unwrap();
+        }
+    }
+
+    impl Default for TestRegistry {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl TestRegistry {
+        pub fn new() -> Self {
+            let test_dir = Some(tempfile::tempdir().unwrap());
+            let registry = TestRegistry::create_registry(&test_dir);
+            Self {
+                registry,
+                _dir: test_dir,
+            }
+        }
+
+        pub fn create_registry(dir: &Option<tempfile::TempDir>) -> Registry {
+            Registry::new(
+                dir.as_ref()
+                    .unwrap()
+                    .path()
+                    .join("registered_connections.json"),
+            )
+            .unwrap()
+        }
+
+        pub fn add_connection<T>(
+            mut self,
+            mode: &ConnectionMode,
+            site_name: &str,
+            uuid: T, //uuid::Uuid,
+        ) -> Self
+        where
+            TrustedConnectionWithRemote: From<T>,
+        {
+            self.registry.register_connection(
+                mode,
+                &site_spec::SiteID::from_str(site_name).unwrap(),
+                TrustedConnectionWithRemote::from(uuid),
+            );
+
+            self
+        }
+        pub fn add_imported_connection<T>(mut self, uuid: T) -> Self
+        where
+            TrustedConnection: From<T>,
+        {
+            self.registry
+                .register_imported_connection(TrustedConnection::from(uuid));
+
+            self
+        }
+
+        pub fn fill_registry(self) -> Self {
+            self.add_connection(
+                &ConnectionMode::Push,
+                "server/push-site",
+                trusted_connection_with_remote(),
+            )
+            .add_connection(
+                &ConnectionMode::Pull,
+                "server/pull-site",
+                trusted_connection_with_remote(),
+            )
+            .add_imported_connection(trusted_connection())
+        }
+    }
+
+    pub fn trusted_connection_with_remote() -> TrustedConnectionWithRemote {
+        TrustedConnectionWithRemote::from(uuid::Uuid::new_v4())
+    }
+
+    pub fn trusted_connection() -> TrustedConnection {
+        TrustedConnection::from(uuid::Uuid::new_v4())
+    }
+
+    impl From<uuid::Uuid> for TrustedConnection {
+        fn from(u: uuid::Uuid) -> Self {
+            Self {
+                uuid: u,
+                private_key: String::from("private_key"),
+                certificate: String::from("certificate"),
+                root_cert: String::from("root_cert"),
+                source_address: None,
+                receiver_protocol: Default::default(),
+                transport: Default::default(),
+                data_source: None,
+            }
+        }
+    }
+
+    impl From<&str> for TrustedConnection {
+        fn from(s: &str) -> Self {
+            Self::from(uuid::Uuid::from_str(s).unwrap())
+        }
+    }
+
+    impl From<uuid::Uuid> for TrustedConnectionWithRemote {
+        fn from(u: uuid::Uuid) -> Self {
+            Self {
+                trust: TrustedConnection::from(u),
+                receiver_port: 8000,
+                push_interval: None,
+                hostname: None,
+                tags: BTreeMap::new(),
+                expires: None,
+            }
+        }
+    }
+
+    impl From<&str> for TrustedConnectionWithRemote {
+        fn from(s: &str) -> Self {
+            Self::from(uuid::Uuid::from_str(s).unwrap())
+        }
+    }
+
+    impl Registry {
+        pub fn get(&self, site_id: &site_spec::SiteID) -> Option<&TrustedConnectionWithRemote> {
+            self.connections
+                .pull
+                .get(site_id)
+                .or_else(|| self.connections.push.get(site_id))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_registration_config {
+    use super::*;
+
+    fn registration_connection_opts() -> cli::RegistrationConnectionOpts {
+        cli::RegistrationConnectionOpts {
+            server_spec: site_spec::ServerSpec {
+                server: String::from("server"),
+                port: Some(8000),
+            },
+            site: String::from("site"),
+            user: String::from("user"),
+            password: None,
+            trust_server_cert: false,
+            prompt_format: cli::PromptFormat::Text,
+            tags: vec![(String::from("env"), String::from("prod"))],
+            expires_in: None,
+            client_opts: cli::ClientOpts {
+                detect_proxy: false,
+                auto_proxy: false,
+            },
+            reg_client_opts: cli::RegistrationClientOpts {
+                validate_api_cert: false,
+                source_address: None,
+                receiver_protocol: cli::ReceiverProtocol::Rest,
+                transport: Default::default(),
+            },
+        }
+    }
+
+    fn runtime_config() -> RuntimeConfig {
+        RuntimeConfig {
+            allowed_ip: None,
+            pull_port: None,
+            legacy_pull_passphrase: None,
+            pull_tunnel: None,
+            reverse_connection: None,
+            quic: None,
+            relay_port: None,
+            hostname_from: None,
+            included_sections: None,
+            excluded_sections: None,
+            max_section_size: None,
+            max_section_sizes: None,
+            post_processors: None,
+            fallback_command: None,
+            fallback_command_timeout: None,
+            fallback_command_max_size: None,
+            payload_size_levels: None,
+            section_size_levels: None,
+            detect_proxy: None,
+            auto_proxy: None,
+            source_address: None,
+            ip_preference: None,
+            user_agent: None,
+            request_headers: None,
+            validate_api_cert: None,
+            push_interval: None,
+            push_jitter: None,
+            align_push: None,
+            push_spool_size: None,
+            push_spool_max_size: None,
+            max_outbound_requests: None,
+            max_payload_memory: None,
+            push_compression: None,
+            conditional_push: None,
+            conditional_push_ignored_sections: None,
+            conditional_push_max_age: None,
+            delta_push: None,
+            push_chunk_size: None,
+            sign_push: None,
+            push_failover: None,
+            auto_reregister: None,
+            hardware_labels: None,
+            status_section: None,
+            self_update: None,
+            self_update_key: None,
+            realtime_sections: None,
+            realtime_interval: None,
+            metrics_port: None,
+            metrics_bind_address: None,
+            log_level: None,
+            log_format: None,
+            log_file: None,
+            log_rotate_size: None,
+            log_rotate_age: None,
+            log_keep_files: None,
+            log_compress: None,
+            log_backend: None,
+            run_as_user: None,
+            run_as_group: None,
+            state_dir: None,
+            push_spool_dir: None,
+            control_socket: None,
+            fips: None,
+            private_key_storage: None,
+            quarantine_invalid_connections: None,
+            dns: None,
+            network_timeouts: None,
+        }
+    }
+
+    #[test]
+    fn test_connection_config() {
+        let connection_config =
+            RegistrationConnectionConfig::new(runtime_config(), registration_connection_opts())
+                .unwrap();
+        assert_eq!(connection_config.site_id.server, "server");
+        assert_eq!(connection_config.site_id.site, "site");
+        assert_eq!(connection_config.receiver_port, 8000);
+        assert_eq!(connection_config.username, "user");
+        assert!(connection_config.password.is_none());
+        assert_eq!(
+            connection_config.tags,
+            BTreeMap::from([(String::from("env"), String::from("prod"))])
+        );
+    }
+
+    #[test]
+    fn test_host_name_config() {
+        assert_eq!(
+            RegisterExistingConfig::new(
+                runtime_config(),
+                cli::RegisterOpts {
+                    connection_opts: registration_connection_opts(),
+                    hostname: Some(String::from("host_name")),
+                    hostname_from: None,
+                    host_creation_opts: host_creation_opts(false),
+                    keep_credentials: false,
+                },
+            )
+            .unwrap()
+            .host_name,
+            "host_name"
+        );
+    }
+
+    fn register_opts(hostname: Option<&str>, hostname_from: Option<&str>) -> cli::RegisterOpts {
+        cli::RegisterOpts {
+            connection_opts: registration_connection_opts(),
+            hostname: hostname.map(String::from),
+            hostname_from: hostname_from.map(|source| source.parse().unwrap()),
+            host_creation_opts: host_creation_opts(false),
+            keep_credentials: false,
+        }
+    }
+
+    #[test]
+    fn test_host_name_config_derived() {
+        let short_name = gethostname::gethostname()
+            .to_str()
+            .unwrap()
+            .split('.')
+            .next()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            RegisterExistingConfig::new(runtime_config(), register_opts(None, Some("{short}-x")))
+                .unwrap()
+                .host_name,
+            format!("{short_name}-x")
+        );
+        let mut runtime_config = runtime_config();
+        runtime_config.hostname_from = Some("short".parse().unwrap());
+        assert_eq!(
+            RegisterExistingConfig::new(runtime_config.clone(), register_opts(None, None))
+                .unwrap()
+                .host_name,
+            short_name
+        );
+        assert_eq!(
+            RegisterExistingConfig::new(runtime_config, register_opts(Some("host_name"), None))
+                .unwrap()
+                .host_name,
+            "host_name"
+        );
+        assert!(
+            RegisterExistingConfig::new(RuntimeConfig::default(), register_opts(None, None))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_host_name_config_other_host() {
+        assert_eq!(
+            RegisterExistingConfig::new_for_other_host(
+                runtime_config(),
+                register_opts(Some("host_name"), None)
+            )
+            .unwrap()
+            .host_name,
+            "host_name"
+        );
+        assert!(RegisterExistingConfig::new_for_other_host(
+            runtime_config(),
+            register_opts(None, Some("short"))
+        )
+        .is_err());
+        let mut keeping_credentials = register_opts(Some("host_name"), None);
+        keeping_credentials.keep_credentials = true;
+        assert!(
+            RegisterExistingConfig::new_for_other_host(runtime_config(), keeping_credentials)
+                .is_err()
+        );
+    }
+
+    fn host_creation_opts(create_host: bool) -> cli::HostCreationOpts {
+        cli::HostCreationOpts {
+            create_host,
+            folder: Some(String::from("/linux")),
+            host_labels: vec![(String::from("a"), String::from("1"))],
+            api_url: None,
+            api_user: None,
+            api_password: None,
+        }
+    }
+
+    #[test]
+    fn test_host_creation_config() {
+        let config = RegisterExistingConfig::new(
+            runtime_config(),
+            cli::RegisterOpts {
+                connection_opts: registration_connection_opts(),
+                hostname: Some(String::from("host_name")),
+                hostname_from: None,
+                host_creation_opts: host_creation_opts(true),
+                keep_credentials: false,
+            },
+        )
+        .unwrap();
+        let host_creation = config.host_creation.unwrap();
+        assert_eq!(
+            host_creation.api_url.as_str(),
+            "https://server/site/check_mk/api/1.0"
+        );
+        assert_eq!(host_creation.folder.as_deref(), Some("/linux"));
+        assert_eq!(host_creation.labels["a"], "1");
+        assert!(host_creation.username.is_none());
+    }
+
+    #[test]
+    fn test_automatic_agent_labels() {
+        let agent_labels = RegisterNewConfig::new(
+            RegistrationConnectionConfig::new(runtime_config(), registration_connection_opts())
+                .unwrap(),
+            types::AgentLabels::new(),
+        )
+        .unwrap()
+        .agent_labels;
+
+        let mut keys = agent_labels.keys().collect::<Vec<&String>>();
+        keys.sort();
+        assert_eq!(keys, ["cmk/hostname-simple", "cmk/os-family"]);
+    }
+
+    #[test]
+    fn test_user_defined_labels() {
+        let agent_labels = RegisterNewConfig::new(
+            RegistrationConnectionConfig::new(runtime_config(), registration_connection_opts())
+                .unwrap(),
+            types::AgentLabels::from([
+                (
+                    String::from("cmk/hostname-simple"),
+                    String::from("custom-name"),
+                ),
+                (String::from("a"), String::from("b")),
+            ]),
+        )
+        .unwrap()
+        .agent_labels;
+
+        let mut keys = agent_labels.keys().collect::<Vec<&String>>();
+        keys.sort();
+        assert_eq!(keys, ["a", "cmk/hostname-simple", "cmk/os-family"]);
+        assert_eq!(agent_labels["cmk/hostname-simple"], "custom-name");
+        assert_eq!(agent_labels["a"], "b");
+    }
+}
+
+#[cfg(test)]
+mod test_legacy_pull_marker {
+    use super::*;
+
+    fn legacy_pull_marker() -> LegacyPullMarker {
+        LegacyPullMarker::new(tempfile::NamedTempFile::new().unwrap())
+    }
+
+    #[test]
+    fn test_exists() {
+        let lpm = legacy_pull_marker();
+        assert!(!lpm.exists());
+        lpm.create(None).unwrap();
+        assert!(lpm.exists());
+        lpm.remove("test").unwrap();
+    }
+
+    #[test]
+    fn test_remove() {
+        let lpm = legacy_pull_marker();
+        assert!(lpm.remove("test").is_ok());
+        assert!(lpm.window().unwrap().cutoff.is_none());
+        lpm.create(None).unwrap();
+        assert!(lpm.remove("test").is_ok());
+        assert!(!lpm.exists());
+        assert_eq!(lpm.window().unwrap().cutoff.unwrap().reason, "test");
+        // clean up
+        fs::remove_file(lpm.window_path()).unwrap();
+    }
+
+    #[test]
+    fn test_create() {
+        let lpm = legacy_pull_marker();
+        lpm.create(Some(123)).unwrap();
+        assert!(lpm.0.is_file());
+        let window = lpm.window().unwrap();
+        assert!(window.enabled_at.is_some());
+        assert_eq!(window.expires_at, Some(123));
+        assert!(window.cutoff.is_none());
+        assert!(lpm.is_expired());
+        // clean up
+        lpm.remove("test").unwrap();
+        fs::remove_file(lpm.window_path()).unwrap();
+    }
+
+    #[test]
+    fn test_window_is_expired() {
+        let window = LegacyPullWindow {
+            enabled_at: Some(100),
+            expires_at: Some(200),
+            cutoff: None,
+        };
+        assert!(!window.is_expired(199));
+        assert!(window.is_expired(200));
+        assert!(!LegacyPullWindow::default().is_expired(u64::MAX));
+    }
+}
+
+#[cfg(test)]
+mod test_push_config {
+    use super::*;
+    use test_helpers::trusted_connection_with_remote;
+
+    #[test]
+    fn test_push_interval_for() {
+        let push_config = PushConfig::new(&RuntimeConfig {
+            push_interval: Some(300),
+            ..RuntimeConfig::default()
+        });
+        let mut connection = trusted_connection_with_remote();
+        assert_eq!(
+            push_config.push_interval_for(&connection),
+            Duration::from_secs(300)
+        );
+        connection.push_interval = Some(30);
+        assert_eq!(
+            push_config.push_interval_for(&connection),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            PushConfig::new(&RuntimeConfig::default()).push_interval,
+            constants::PUSH_INTERVAL
+        );
+    }
+
+    #[test]
+    fn test_push_interval_minimum() {
+        assert!(toml::from_str::<RuntimeConfig>("push_interval = 0").is_err());
+        assert!(toml::from_str::<RuntimeConfig>("push_interval = 9").is_err());
+        assert_eq!(
+            toml::from_str::<RuntimeConfig>("push_interval = 10")
+                .unwrap()
+                .push_interval,
+            Some(10)
+        );
+        let mut entry = serde_json::to_value(trusted_connection_with_remote()).unwrap();
+        entry["push_interval"] = serde_json::Value::from(0);
+        assert!(serde_json::from_value::<TrustedConnectionWithRemote>(entry.clone()).is_err());
+        entry["push_interval"] = serde_json::Value::from(30);
+        assert_eq!(
+            serde_json::from_value::<TrustedConnectionWithRemote>(entry)
+                .unwrap()
+                .push_interval,
+            Some(30)
+        );
+    }
+
+    #[test]
+    fn test_record_hostname() {
+        let mut connection = trusted_connection_with_remote();
+        assert!(connection.record_hostname("host", 1000));
+        assert!(!connection.record_hostname("host", 1010));
+        assert!(connection.record_hostname("renamed", 1020));
+        // Reported before the currently known hostname
+        assert!(!connection.record_hostname("host", 1015));
+        assert!(connection
+            .record_hostname("renamed", 1020 + constants::KNOWN_HOSTNAME_REFRESH_INTERVAL));
+        assert_eq!(
+            connection.hostname,
+            Some(KnownHostname {
+                hostname: String::from("renamed"),
+                last_seen: 1020 + constants::KNOWN_HOSTNAME_REFRESH_INTERVAL,
+            })
+        );
+    }
+
+    #[test]
+    fn test_has_tags() {
+        let mut connection = trusted_connection_with_remote();
+        assert!(connection.has_tags(&[]));
+        let env_prod = (String::from("env"), String::from("prod"));
+        assert!(!connection.has_tags(std::slice::from_ref(&env_prod)));
+        connection.tags = BTreeMap::from([
+            env_prod.clone(),
+            (String::from("owner"), String::from("team-x")),
+        ]);
+        assert!(connection.has_tags(std::slice::from_ref(&env_prod)));
+        assert!(!connection.has_tags(&[env_prod, (String::from("owner"), String::from("team-y"))]));
+        // Tags are only written to the registry if there are any
+        let serialized = serde_json::to_string(&trusted_connection_with_remote()).unwrap();
+        assert!(!serialized.contains("tags"));
+        let serialized = serde_json::to_string(&connection).unwrap();
+        let deserialized: TrustedConnectionWithRemote = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.tags, connection.tags);
+    }
+
+    #[test]
+    fn test_expired_connections() {
+        let mut expiring = trusted_connection_with_remote();
+        expiring.expires = Some(1000);
+        assert!(!expiring.is_expired(999));
+        assert!(expiring.is_expired(1000));
+        assert!(!trusted_connection_with_remote().is_expired(u64::MAX));
+
+        let expiring_uuid = expiring.trust.uuid;
+        let r = test_helpers::TestRegistry::new()
+            .add_connection(&ConnectionMode::Push, "server/push-site", expiring.clone())
+            .add_connection(
+                &ConnectionMode::Push,
+                "server/other-site",
+                uuid::Uuid::new_v4(),
+            )
+            .add_connection(&ConnectionMode::Pull, "server/pull-site", expiring)
+            .add_imported_connection(uuid::Uuid::new_v4());
+        let registry = &r.registry;
+        assert_eq!(registry.count_expired(999), 0);
+        assert_eq!(registry.get_active_push_connections(999).count(), 2);
+        assert_eq!(registry.get_active_pull_connections(999).count(), 2);
+        assert_eq!(registry.count_expired(1000), 2);
+        let active_push: Vec<_> = registry.get_active_push_connections(1000).collect();
+        assert_eq!(active_push.len(), 1);
+        assert_eq!(active_push[0].0.to_string(), "server/other-site");
+        assert!(registry
+            .get_active_pull_connections(1000)
+            .all(|connection| connection.uuid != expiring_uuid));
+        assert_eq!(registry.get_active_pull_connections(1000).count(), 1);
+    }
+
+    #[test]
+    fn test_push_chunk_size() {
+        assert_eq!(
+            PushConfig::new(&RuntimeConfig::default()).push_chunk_size,
+            None
+        );
+        let runtime_config: RuntimeConfig = toml::from_str("push_chunk_size = 1048576").unwrap();
+        assert_eq!(
+            PushConfig::new(&runtime_config).push_chunk_size,
+            Some(1048576)
+        );
+        let runtime_config: RuntimeConfig = toml::from_str("push_chunk_size = 1").unwrap();
+        assert_eq!(
+            PushConfig::new(&runtime_config).push_chunk_size,
+            Some(constants::MIN_PUSH_CHUNK_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_push_compression() {
+        let runtime_config: RuntimeConfig = toml::from_str("push_compression = \"zstd\"").unwrap();
+        assert_eq!(
+            PushConfig::new(&runtime_config).push_compression,
+            monitoring_data::PushCompression::Zstd
+        );
+        assert_eq!(
+            PushConfig::new(&RuntimeConfig::default()).push_compression,
+            monitoring_data::PushCompression::Zlib
+        );
+        assert!(toml::from_str::<RuntimeConfig>("push_compression = \"lzma\"").is_err());
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let push_config = PushConfig::new(&RuntimeConfig::default());
+        assert_eq!(push_config.push_spool_max_size, None);
+        assert_eq!(
+            push_config.max_outbound_requests,
+            constants::MAX_OUTBOUND_REQUESTS
+        );
+        let runtime_config: RuntimeConfig =
+            toml::from_str("push_spool_max_size = 1000000\nmax_outbound_requests = 0").unwrap();
+        let push_config = PushConfig::new(&runtime_config);
+        assert_eq!(push_config.push_spool_max_size, Some(1000000));
+        // Pushing at all requires one request
+        assert_eq!(push_config.max_outbound_requests, 1);
+    }
+
+    #[test]
+    fn test_payload_size_config() {
+        assert!(!PushConfig::new(&RuntimeConfig::default())
+            .payload_size
+            .is_enabled());
+        let runtime_config: RuntimeConfig =
+            toml::from_str("section_size_levels = [1000000, 5000000]").unwrap();
+        let payload_size = PushConfig::new(&runtime_config).payload_size;
+        assert!(payload_size.is_enabled());
+        assert_eq!(payload_size.total_levels, None);
+        assert_eq!(payload_size.section_levels, Some((1000000, 5000000)));
+    }
+
+    #[test]
+    fn test_realtime_config() {
+        assert!(!PushConfig::new(&RuntimeConfig::default())
+            .realtime
+            .is_enabled());
+        let runtime_config: RuntimeConfig =
+            toml::from_str("realtime_sections = [\"cpu\", \"mem\"]\nrealtime_interval = 0")
+                .unwrap();
+        let realtime = PushConfig::new(&runtime_config).realtime;
+        assert!(realtime.is_enabled());
+        assert_eq!(
+            realtime.sections,
+            [
+                realtime::RealtimeSection::Cpu,
+                realtime::RealtimeSection::Mem
+            ]
+        );
+        assert_eq!(realtime.interval, Duration::from_secs(1));
+        assert!(toml::from_str::<RuntimeConfig>("realtime_sections = [\"df\"]").is_err());
+    }
+
+    #[test]
+    fn test_self_update_config() {
+        assert!(SelfUpdateConfig::new(&RuntimeConfig::default()).is_err());
+        let runtime_config: RuntimeConfig = toml::from_str("self_update = true").unwrap();
+        assert!(SelfUpdateConfig::new(&runtime_config).is_err());
+        let runtime_config: RuntimeConfig =
+            toml::from_str("self_update = true\nself_update_key = \"/etc/check_mk/update.pem\"")
+                .unwrap();
+        assert_eq!(
+            SelfUpdateConfig::new(&runtime_config).unwrap().signing_key,
+            PathBuf::from("/etc/check_mk/update.pem")
+        );
+    }
+
+    #[test]
+    fn test_post_processors() {
+        let runtime_config: RuntimeConfig = toml::from_str(
+            "[[post_processors]]\n\
+             type = \"drop_lines\"\n\
+             pattern = \"password=\"\n\
+             [[post_processors]]\n\
+             type = \"command\"\n\
+             command = [\"/usr/local/bin/scrub\", \"-q\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            PushConfig::new(&runtime_config).post_processors,
+            [
+                PostProcessorConfig::DropLines {
+                    pattern: String::from("password="),
+                    sections: vec![],
+                },
+                PostProcessorConfig::Command {
+                    command: vec![String::from("/usr/local/bin/scrub"), String::from("-q")],
+                    timeout: None,
+                },
+            ]
+        );
+        assert!(
+            toml::from_str::<RuntimeConfig>("[[post_processors]]\ntype = \"rot13\"\n").is_err()
+        );
+    }
+
+    fn push_config(push_jitter: u64, align_push: bool) -> PushConfig {
+        PushConfig {
+            push_interval: 60,
+            push_jitter,
+            align_push,
+            push_spool_size: 0,
+            push_spool_max_size: None,
+            max_outbound_requests: 8,
+            push_compression: monitoring_data::PushCompression::Zlib,
+            conditional_push: false,
+            conditional_push_ignored_sections: vec![],
+            conditional_push_max_age: 600,
+            delta_push: false,
+            push_chunk_size: None,
+            sign_push: false,
+            quic: false,
+            push_failover: HashMap::new(),
+            section_filter: SectionFilterConfig::default(),
+            post_processors: vec![],
+            fallback_command: None,
+            payload_size: PayloadSizeConfig::default(),
+            realtime: RealtimeConfig::default(),
+            reregistration: ReregistrationConfig::default(),
+            hardware_labels: false,
+            status_section: false,
+        }
+    }
+
+    #[test]
+    fn test_next_push_delay() {
+        let mut rng = rand::thread_rng();
+        let connection = trusted_connection_with_remote();
+        let since_epoch = Duration::from_millis(1_700_000_035_500);
+        assert_eq!(
+            push_config(0, false).next_push_delay(&connection, since_epoch, &mut rng),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            push_config(0, true).next_push_delay(&connection, since_epoch, &mut rng),
+            Duration::from_millis(4500)
+        );
+    }
+
+    #[test]
+    fn test_next_push_delay_jitter() {
+        let mut rng = rand::thread_rng();
+        let connection = trusted_connection_with_remote();
+        let since_epoch = Duration::from_secs(1_700_000_020);
+        for _ in 0..100 {
+            let delay = push_config(10, true).next_push_delay(&connection, since_epoch, &mut rng);
+            assert!(delay >= Duration::from_secs(20) && delay < Duration::from_secs(30));
+            let delay = push_config(600, false).next_push_delay(&connection, since_epoch, &mut rng);
+            assert!(delay >= Duration::from_secs(60) && delay < Duration::from_secs(120));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_logging_config {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_from_runtime_config() {
+        let runtime_config: RuntimeConfig = toml::from_str(
+            "log_level = \"info, cmk_agent_ctl_lib::modes::push=debug\"\nlog_format = \"json\"",
+        )
+        .unwrap();
+        let logging_config = LoggingConfig::new(
+            &runtime_config,
+            &cli::Cli::parse_from(["cmk-agent-ctl", "dump"]),
+        );
+        assert_eq!(
+            logging_config.level.as_deref(),
+            Some("info, cmk_agent_ctl_lib::modes::push=debug")
+        );
+        assert_eq!(logging_config.format, cli::LogFormat::Json);
+    }
+
+    #[test]
+    fn test_command_line_wins() {
+        let runtime_config: RuntimeConfig =
+            toml::from_str("log_level = \"info\"\nlog_format = \"json\"").unwrap();
+        let logging_config = LoggingConfig::new(
+            &runtime_config,
+            &cli::Cli::parse_from(["cmk-agent-ctl", "-v", "--log-format", "text", "dump"]),
+        );
+        assert!(logging_config.level.is_none());
+        assert_eq!(logging_config.format, cli::LogFormat::Text);
+    }
+}
+
+#[cfg(test)]
+mod test_log_file_config {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let log_file_config = LogFileConfig::new(&RuntimeConfig::default());
+        assert!(log_file_config.path.is_none());
+        assert!(matches!(
+            log_file_config.criterion(),
+            flexi_logger::Criterion::Size(constants::log::FILE_MAX_SIZE)
+        ));
+        assert!(matches!(
+            log_file_config.cleanup(),
+            flexi_logger::Cleanup::KeepLogFiles(constants::log::FILE_KEEP)
+        ));
+    }
+
+    #[test]
+    fn test_from_runtime_config() {
+        let runtime_config: RuntimeConfig = toml::from_str(
+            "log_file = \"/var/log/cmk-agent-ctl.log\"\n\
+             log_rotate_size = 1000\n\
+             log_rotate_age = \"day\"\n\
+             log_keep_files = 10\n\
+             log_compress = true",
+        )
+        .unwrap();
+        let log_file_config = LogFileConfig::new(&runtime_config);
+        assert_eq!(
+            log_file_config.path.as_deref(),
+            Some(Path::new("/var/log/cmk-agent-ctl.log"))
+        );
+        assert!(matches!(
+            log_file_config.criterion(),
+            flexi_logger::Criterion::AgeOrSize(flexi_logger::Age::Day, 1000)
+        ));
+        assert!(matches!(
+            log_file_config.cleanup(),
+            flexi_logger::Cleanup::KeepCompressedFiles(10)
+        ));
+        assert!(toml::from_str::<RuntimeConfig>("log_rotate_age = \"week\"").is_err());
+    }
+
+    #[test]
+    fn test_backend() {
+        assert!(LogFileConfig::new(&RuntimeConfig::default())
+            .backend
+            .is_none());
+        for (value, backend) in [
+            ("journald", LogBackend::Journald),
+            ("syslog", LogBackend::Syslog),
+            ("eventlog", LogBackend::EventLog),
+        ] {
+            let runtime_config: RuntimeConfig =
+                toml::from_str(&format!("log_backend = \"{value}\"")).unwrap();
+            assert_eq!(LogFileConfig::new(&runtime_config).backend, Some(backend));
+        }
+        assert!(toml::from_str::<RuntimeConfig>("log_backend = \"stdout\"").is_err());
+    }
+
+    #[test]
+    fn test_load_falls_back_to_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cmk-agent-ctl.toml");
+        std::fs::write(&path, "log_keep_files = \"many\"").unwrap();
+        assert_eq!(
+            LogFileConfig::load(&path),
+            LogFileConfig::new(&RuntimeConfig::default())
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_metrics_config {
+    use super::*;
+
+    #[test]
+    fn test_disabled_by_default() {
+        assert!(MetricsConfig::new(&RuntimeConfig::default())
+            .address()
+            .is_none());
+    }
+
+    #[test]
+    fn test_address() {
+        assert_eq!(
+            MetricsConfig::new(&RuntimeConfig {
+                metrics_port: Some(9100),
+                ..RuntimeConfig::default()
+            })
+            .address()
+            .unwrap()
+            .to_string(),
+            "127.0.0.1:9100"
+        );
+        assert_eq!(
+            MetricsConfig::new(&RuntimeConfig {
+                metrics_port: Some(9100),
+                metrics_bind_address: Some(IpAddr::from([0, 0, 0, 0])),
+                ..RuntimeConfig::default()
+            })
+            .address()
+            .unwrap()
+            .to_string(),
+            "0.0.0.0:9100"
+        );
+        let runtime_config: RuntimeConfig = toml::from_str(
+            "metrics_port = 9100
+metrics_bind_address = \"::1\"",
+        )
+        .unwrap();
+        assert_eq!(
+            MetricsConfig::new(&runtime_config)
+                .address()
+                .unwrap()
+                .to_string(),
+            "[::1]:9100"
+        );
+        assert!(toml::from_str::<RuntimeConfig>("metrics_bind_address = \"localhost\"").is_err());
+    }
+}
+
+#[cfg(test)]
+#[cfg(unix)]
+mod test_privileges_config {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(
+            PrivilegesConfig::new(&RuntimeConfig::default()),
+            PrivilegesConfig {
+                user: String::from("cmk-agent"),
+                group: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("cmk-agent-ctl.toml");
+        std::fs::write(
+            &config_path,
+            "run_as_user = \"monitoring\"\nrun_as_group = \"nogroup\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            PrivilegesConfig::load(&config_path),
+            PrivilegesConfig {
+                user: String::from("monitoring"),
+                group: Some(String::from("nogroup")),
+            }
+        );
+        assert_eq!(
+            PrivilegesConfig::load(&dir.path().join("missing.toml")).user,
+            "cmk-agent"
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_paths_config {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("cmk-agent-ctl.toml");
+        std::fs::write(
+            &config_path,
+            "state_dir = \"/var/lib/cmk-agent-ctl\"\ncontrol_socket = \"/run/cmk-agent-ctl/cmk-agent-ctl.sock\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            PathsConfig::load(&config_path),
+            PathsConfig {
+                state_dir: Some(PathBuf::from("/var/lib/cmk-agent-ctl")),
+                push_spool_dir: None,
+                control_socket: Some(PathBuf::from("/run/cmk-agent-ctl/cmk-agent-ctl.sock")),
+            }
+        );
+        assert_eq!(
+            PathsConfig::load(&dir.path().join("missing.toml")),
+            PathsConfig::default()
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_client_config {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let client_config = ClientConfig::new(
+            RuntimeConfig {
+                allowed_ip: None,
+                pull_port: None,
+                legacy_pull_passphrase: None,
+                pull_tunnel: None,
+                reverse_connection: None,
+                quic: None,
+                relay_port: None,
+                hostname_from: None,
+                included_sections: None,
+                excluded_sections: None,
+                max_section_size: None,
+                max_section_sizes: None,
+                post_processors: None,
+                fallback_command: None,
+                fallback_command_timeout: None,
+                fallback_command_max_size: None,
+                payload_size_levels: None,
+                section_size_levels: None,
+                detect_proxy: None,
+                auto_proxy: None,
+                source_address: None,
+                ip_preference: None,
+                user_agent: None,
+                request_headers: None,
+                validate_api_cert: None,
+                push_interval: None,
+                push_jitter: None,
+                align_push: None,
+                push_spool_size: None,
+                push_spool_max_size: None,
+                max_outbound_requests: None,
+                max_payload_memory: None,
+                push_compression: None,
+                conditional_push: None,
+                conditional_push_ignored_sections: None,
+                conditional_push_max_age: None,
+                delta_push: None,
+                push_chunk_size: None,
+                sign_push: None,
+                push_failover: None,
+                auto_reregister: None,
+                hardware_labels: None,
+                status_section: None,
+                self_update: None,
+                self_update_key: None,
+                realtime_sections: None,
+                realtime_interval: None,
+                metrics_port: None,
+                metrics_bind_address: None,
+                log_level: None,
+                log_format: None,
+                log_file: None,
+                log_rotate_size: None,
+                log_rotate_age: None,
+                log_keep_files: None,
+                log_compress: None,
+                log_backend: None,
+                run_as_user: None,
+                run_as_group: None,
+                state_dir: None,
+                push_spool_dir: None,
+                control_socket: None,
+                fips: None,
+                private_key_storage: None,
+                quarantine_invalid_connections: None,
+                dns: None,
+                network_timeouts: None,
+            },
+            cli::ClientOpts {
+                detect_proxy: false,
+                auto_proxy: false,
+            },
+            None,
+        );
+        assert_eq!(client_config.proxy_mode, proxy::ProxyMode::Direct);
+        assert!(!client_config.validate_api_cert);
+    }
+
+    #[test]
+    fn test_from_runtime_config() {
+        let client_config = ClientConfig::new(
+            RuntimeConfig {
+                allowed_ip: None,
+                pull_port: None,
+                legacy_pull_passphrase: None,
+                pull_tunnel: None,
+                reverse_connection: None,
+                quic: None,
+                relay_port: None,
+                hostname_from: None,
+                included_sections: None,
+                excluded_sections: None,
+                max_section_size: None,
+                max_section_sizes: None,
+                post_processors: None,
+                fallback_command: None,
+                fallback_command_timeout: None,
+                fallback_command_max_size: None,
+                payload_size_levels: None,
+                section_size_levels: None,
+                detect_proxy: Some(true),
+                auto_proxy: None,
+                source_address: None,
+                ip_preference: None,
+                user_agent: None,
+                request_headers: None,
+                validate_api_cert: Some(true),
+                push_interval: None,
+                push_jitter: None,
+                align_push: None,
+                push_spool_size: None,
+                push_spool_max_size: None,
+                max_outbound_requests: None,
+                max_payload_memory: None,
+                push_compression: None,
+                conditional_push: None,
+                conditional_push_ignored_sections: None,
+                conditional_push_max_age: None,
+                delta_push: None,
+                push_chunk_size: None,
+                sign_push: None,
+                push_failover: None,
+                auto_reregister: None,
+                hardware_labels: None,
+                status_section: None,
+                self_update: None,
+                self_update_key: None,
+                realtime_sections: None,
+                realtime_interval: None,
+                metrics_port: None,
+                metrics_bind_address: None,
+                log_level: None,
+                log_format: None,
+                log_file: None,
+                log_rotate_size: None,
+                log_rotate_age: None,
+                log_keep_files: None,
+                log_compress: None,
+                log_backend: None,
+                run_as_user: None,
+                run_as_group: None,
+                state_dir: None,
+                push_spool_dir: None,
+                control_socket: None,
+                fips: None,
+                private_key_storage: None,
+                quarantine_invalid_connections: None,
+                dns: None,
+                network_timeouts: None,
+            },
+            cli::ClientOpts {
+                detect_proxy: false,
+                auto_proxy: false,
+            },
+            Some(cli::RegistrationClientOpts {
+                validate_api_cert: false,
+                source_address: None,
+                receiver_protocol: cli::ReceiverProtocol::Rest,
+                transport: Default::default(),
+            }),
+        );
+        assert_eq!(client_config.proxy_mode, proxy::ProxyMode::Environment);
+        assert!(client_config.validate_api_cert);
+    }
+
+    #[test]
+    fn test_from_client_opts() {
+        let client_config = ClientConfig::new(
+            RuntimeConfig {
+                allowed_ip: None,
+                pull_port: None,
+                legacy_pull_passphrase: None,
+                pull_tunnel: None,
+                reverse_connection: None,
+                quic: None,
+                relay_port: None,
+                hostname_from: None,
+                included_sections: None,
+                excluded_sections: None,
+                max_section_size: None,
+                max_section_sizes: None,
+                post_processors: None,
+                fallback_command: None,
+                fallback_command_timeout: None,
+                fallback_command_max_size: None,
+                payload_size_levels: None,
+                section_size_levels: None,
+                detect_proxy: None,
+                auto_proxy: None,
+                source_address: None,
+                ip_preference: None,
+                user_agent: None,
+                request_headers: None,
+                validate_api_cert: None,
+                push_interval: None,
+                push_jitter: None,
+                align_push: None,
+                push_spool_size: None,
+                push_spool_max_size: None,
+                max_outbound_requests: None,
+                max_payload_memory: None,
+                push_compression: None,
+                conditional_push: None,
+                conditional_push_ignored_sections: None,
+                conditional_push_max_age: None,
+                delta_push: None,
+                push_chunk_size: None,
+                sign_push: None,
+                push_failover: None,
+                auto_reregister: None,
+                hardware_labels: None,
+                status_section: None,
+                self_update: None,
+                self_update_key: None,
+                realtime_sections: None,
+                realtime_interval: None,
+                metrics_port: None,
+                metrics_bind_address: None,
+                log_level: None,
+                log_format: None,
+                log_file: None,
+                log_rotate_size: None,
+                log_rotate_age: None,
+                log_keep_files: None,
+                log_compress: None,
+                log_backend: None,
+                run_as_user: None,
+                run_as_group: None,
+                state_dir: None,
+                push_spool_dir: None,
+                control_socket: None,
+                fips: None,
+                private_key_storage: None,
+                quarantine_invalid_connections: None,
+                dns: None,
+                network_timeouts: None,
+            },
+            cli::ClientOpts {
+                detect_proxy: true,
+                auto_proxy: false,
+            },
+            Some(cli::RegistrationClientOpts {
+                validate_api_cert: true,
+                source_address: None,
+                receiver_protocol: cli::ReceiverProtocol::Rest,
+                transport: Default::default(),
+            }),
+        );
+        assert_eq!(client_config.proxy_mode, proxy::ProxyMode::Environment);
+        assert!(client_config.validate_api_cert);
+    }
+
+    #[test]
+    fn test_auto_proxy() {
+        let client_opts = || cli::ClientOpts {
+            detect_proxy: false,
+            auto_proxy: true,
+        };
+        assert_eq!(
+            ClientConfig::new(RuntimeConfig::default(), client_opts(), None).proxy_mode,
+            proxy::ProxyMode::Auto
+        );
+        assert_eq!(
+            ClientConfig::new(
+                toml::from_str("detect_proxy = true\nauto_proxy = true").unwrap(),
+                cli::ClientOpts {
+                    detect_proxy: false,
+                    auto_proxy: false,
+                },
+                None
+            )
+            .proxy_mode,
+            proxy::ProxyMode::Auto
+        );
+    }
+
+    #[test]
+    fn test_request_headers() {
+        let client_config = ClientConfig::new(
+            toml::from_str(
+                "user_agent = \"acme-provisioning/1.0\"\n\
+                 [request_headers]\n\
+                 X-Tenant = \"customer-a\"\n\
+                 x-api-key = \"secret\"",
+            )
+            .unwrap(),
+            cli::ClientOpts {
+                detect_proxy: false,
+                auto_proxy: false,
+            },
+            None,
+        );
+        let headers = &client_config.request_headers;
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers["user-agent"], "acme-provisioning/1.0");
+        assert_eq!(headers["x-tenant"], "customer-a");
+        assert_eq!(headers["x-api-key"], "secret");
+        for invalid in [
+            "user_agent = \"line\\nbreak\"",
+            "[request_headers]\n\"no spaces\" = \"value\"",
+            "[request_headers]\nX-Tenant = \"line\\nbreak\"",
+            "[request_headers]\nAuthorization = \"Bearer token\"",
+        ] {
+            assert!(toml::from_str::<RuntimeConfig>(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_network_timeouts() {
+        let client_config = |config| {
+            ClientConfig::new(
+                toml::from_str(config).unwrap(),
+                cli::ClientOpts {
+                    detect_proxy: false,
+                    auto_proxy: false,
+                },
+                None,
+            )
+        };
+        assert_eq!(client_config("").timeouts, NetworkTimeouts::default());
+        let timeouts = client_config("[network_timeouts]\nconnect = 5\ntotal = 15").timeouts;
+        assert_eq!(
+            timeouts,
+            NetworkTimeouts {
+                connect: 5,
+                read: constants::READ_TIMEOUT,
+                total: 15,
+            }
+        );
+        assert_eq!(timeouts.capped(30), Duration::from_secs(15));
+        assert_eq!(timeouts.capped(5), Duration::from_secs(5));
+        assert!(toml::from_str::<RuntimeConfig>("[network_timeouts]\nidle = 5").is_err());
+    }
+
+    #[test]
+    fn test_source_address() {
+        let client_config = |reg_source_address| {
+            ClientConfig::new(
+                toml::from_str("source_address = \"10.0.0.1\"").unwrap(),
+                cli::ClientOpts {
+                    detect_proxy: false,
+                    auto_proxy: false,
+                },
+                Some(cli::RegistrationClientOpts {
+                    validate_api_cert: false,
+                    source_address: reg_source_address,
+                    receiver_protocol: cli::ReceiverProtocol::Rest,
+                    transport: Default::default(),
+                }),
+            )
+        };
+        assert_eq!(
+            client_config(None).source_address,
+            Some(IpAddr::from([10, 0, 0, 1]))
+        );
+        assert_eq!(
+            client_config(Some(IpAddr::from([192, 168, 0, 1]))).source_address,
+            Some(IpAddr::from([192, 168, 0, 1]))
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_registry {
+    use super::*;
+    use crate::config::test_helpers::{
+        trusted_connection, trusted_connection_with_remote, TestRegistry,
+    };
+    use std::convert::From;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_io() {
+        let test_registry = TestRegistry::new().fill_registry();
+        let reg = &test_registry.registry;
+        assert!(!reg.path.exists());
+
+        reg.save().unwrap();
+        assert!(reg.path.exists());
+        assert!(!Registry::make_tmp_path_for_save(reg.path()).exists());
+        #[cfg(unix)]
+        assert_eq!(
+            fs::metadata(&reg.path).unwrap().permissions().mode(),
+            0o100600 // mode apparently returns the full file mode, not just the permission bits ...
+        );
+
+        let new_reg = Registry::from_file(&reg.path).unwrap();
+        assert_eq!(reg.connections, new_reg.connections);
+        assert_eq!(reg.path, new_reg.path);
+        assert!(new_reg.last_reload.is_some());
+    }
+
+    #[test]
+    fn test_key_storage() {
+        assert_eq!(KeyStorage::new(&RuntimeConfig::default()), KeyStorage::File);
+        let runtime_config: RuntimeConfig =
+            toml::from_str("private_key_storage = \"keychain\"").unwrap();
+        assert_eq!(KeyStorage::new(&runtime_config), KeyStorage::Keychain);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn test_keychain_unavailable() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        reg.set_key_storage(KeyStorage::Keychain);
+        assert!(reg.save().is_err());
+        assert!(!reg.path.exists());
+
+        let mut connections = reg.connections.clone();
+        for connection in connections.push.values_mut() {
+            connection.trust.private_key = format!("keychain:{}", connection.trust.uuid);
+        }
+        assert_eq!(connections.key_references().len(), 1);
+        fs::write(&reg.path, serde_json::to_string(&connections).unwrap()).unwrap();
+        assert!(Registry::from_file(&reg.path).is_err());
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let test_registry = TestRegistry::new().fill_registry();
+        let reg = &test_registry.registry;
+        // Written by an older version
+        fs::write(&reg.path, "{}").unwrap();
+        assert!(reg.verify_checksum().is_ok());
+        reg.save().unwrap();
+        assert!(reg.verify_checksum().is_ok());
+        assert_eq!(
+            fs::read_to_string(Registry::checksum_path(&reg.path))
+                .unwrap()
+                .len(),
+            64
+        );
+
+        fs::write(&reg.path, "{\"push\": {}}").unwrap();
+        assert!(reg.verify_checksum().is_err());
+        fs::remove_file(Registry::checksum_path(&reg.path)).unwrap();
+        assert!(reg.verify_checksum().is_ok());
+    }
+
+    #[test]
+    fn test_from_file_quarantining() {
+        let test_registry = TestRegistry::new();
+        let path = &test_registry.registry.path;
+        let (certificate, private_key) = certs::make_self_signed_cert("site", 1).unwrap();
+        let valid = |uuid: &str| {
+            serde_json::json!({
+                "uuid": uuid,
+                "private_key": private_key,
+                "certificate": certificate,
+                "root_cert": certificate,
+                "receiver_port": 8000,
+            })
+        };
+        fs::write(
+            path,
+            serde_json::json!({
+                "push": {
+                    "server/valid-site": valid("99f56bbc-5965-4b34-bc70-1959ad1d32d6"),
+                    "server/broken-site": {"uuid": "b3501e4d-2820-433c-8e9c-38c69ac20fab"},
+                },
+                "pull": {
+                    "no-site-id": valid("0096abd7-83c9-42f8-8b3a-3ffba7ba959d"),
+                },
+            })
+            .to_string(),
+        )
+        .unwrap();
+        assert!(Registry::from_file(path).is_err());
+
+        let registry = Registry::from_file_quarantining(path).unwrap();
+        assert_eq!(registry.get_push_connections().count(), 1);
+        assert!(registry.is_pull_empty());
+        let quarantine = Quarantine::load(&Registry::quarantine_path(path)).unwrap();
+        let mut quarantined: Vec<String> =
+            quarantine.0.iter().map(|entry| entry.describe()).collect();
+        quarantined.sort();
+        assert_eq!(
+            quarantined,
+            vec!["no-site-id (pull)", "server/broken-site (push)"]
+        );
+        // The rewritten registry loads as is
+        assert!(Registry::from_file(path).is_ok());
+
+        fs::write(path, "not json").unwrap();
+        let registry = Registry::from_file_quarantining(path).unwrap();
+        assert!(registry.is_empty());
+        let quarantine = Quarantine::load(&Registry::quarantine_path(path)).unwrap();
+        assert_eq!(quarantine.0.len(), 3);
+        assert_eq!(quarantine.0[2].entry, serde_json::json!("not json"));
+    }
+
+    #[test]
+    fn test_reload() {
+        let test_registry = TestRegistry::new().fill_registry();
+        let reg = &test_registry.registry;
+        reg.save().unwrap();
+        let mut reg = Registry::from_file(&reg.path).unwrap();
+        assert!(!reg.refresh().unwrap());
+
+        let mtime_before_reload = reg.last_reload.unwrap();
+        // let a mini-bit of time pass st. we actually get a new mtime
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(&reg.path, "{}").unwrap();
+        assert!(reg.refresh().unwrap());
+        assert!(!reg
+            .last_reload
+            .unwrap()
+            .duration_since(mtime_before_reload)
+            .unwrap()
+            .is_zero());
+    }
+
+    #[test]
+    fn test_new() {
+        let reg = Registry::new(tempfile::NamedTempFile::new().unwrap().as_ref()).unwrap();
+        assert!(reg.is_pull_empty() && reg.is_push_empty());
+        assert!(reg.last_reload.is_none());
+    }
+
+    #[test]
+    fn test_register_push_connection_new() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        reg.register_connection(
+            &ConnectionMode::Push,
+            &site_spec::SiteID::from_str("new_server/new-site").unwrap(),
+            trusted_connection_with_remote(),
+        );
+        assert!(reg.connections.push.len() == 2);
+        assert!(reg.connections.pull.len() == 1);
+        assert!(reg.connections.pull_imported.len() == 1);
+    }
+
+    #[test]
+    fn test_register_push_connection_from_pull() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        reg.register_connection(
+            &ConnectionMode::Push,
+            &site_spec::SiteID::from_str("server/pull-site").unwrap(),
+            trusted_connection_with_remote(),
+        );
+        assert!(reg.connections.push.len() == 2);
+        assert!(reg.connections.pull.is_empty());
+        assert!(reg.connections.pull_imported.len() == 1);
+    }
+
+    #[test]
+    fn test_register_pull_connection_new() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        reg.register_connection(
+            &ConnectionMode::Pull,
+            &site_spec::SiteID::from_str("new_server/new-site").unwrap(),
+            trusted_connection_with_remote(),
+        );
+        assert!(reg.connections.push.len() == 1);
+        assert!(reg.connections.pull.len() == 2);
+        assert!(reg.connections.pull_imported.len() == 1);
+    }
+
+    #[test]
+    fn test_register_pull_connection_from_push() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        reg.register_connection(
+            &ConnectionMode::Pull,
+            &site_spec::SiteID::from_str("server/push-site").unwrap(),
+            trusted_connection_with_remote(),
+        );
+        assert!(reg.connections.push.is_empty());
+        assert!(reg.connections.pull.len() == 2);
+        assert!(reg.connections.pull_imported.len() == 1);
+    }
+
+    #[test]
+    fn test_register_imported_connection() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        let conn = trusted_connection();
+        let uuid = conn.uuid;
+        reg.register_imported_connection(conn);
+        assert!(reg.connections.push.len() == 1);
+        assert!(reg.connections.pull.len() == 1);
+        assert!(reg.connections.pull_imported.len() == 2);
+        assert!(reg.connections.pull_imported.contains(&uuid));
+    }
+
+    #[test]
+    fn test_retrieve_standard_connection_by_uuid() {
+        let test_registry = TestRegistry::new().fill_registry();
+        let reg = &test_registry.registry;
+        let uuid_push = reg.get_push_connections().next().unwrap().1.trust.uuid;
+        let uuid_pull = reg
+            .get_standard_pull_connections()
+            .next()
+            .unwrap()
+            .1
+            .trust
+            .uuid;
+        let uuid_imported = reg.get_imported_pull_connections().next().unwrap().uuid;
+
+        assert!(
+            reg.retrieve_standard_connection_by_uuid(&uuid_push)
+                == Some(site_spec::SiteID::from_str("server/push-site").unwrap())
+        );
+        assert!(
+            reg.retrieve_standard_connection_by_uuid(&uuid_pull)
+                == Some(site_spec::SiteID::from_str("server/pull-site").unwrap())
+        );
+        assert!(reg
+            .retrieve_standard_connection_by_uuid(&uuid_imported)
+            .is_none());
+        assert!(reg
+            .retrieve_standard_connection_by_uuid(&uuid::Uuid::new_v4())
+            .is_none());
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        assert!(!reg.is_empty());
+        reg.connections.push.clear();
+        assert!(!reg.is_empty());
+        reg.connections.pull.clear();
+        assert!(!reg.is_empty());
+        reg.connections.pull_imported.clear();
+        assert!(reg.is_empty());
+    }
+
+    #[test]
+    fn test_pull_connections() {
+        let test_registry = TestRegistry::new().fill_registry();
+        let reg = &test_registry.registry;
+        let pull_conns: Vec<&TrustedConnection> = reg.get_pull_connections().collect();
+        assert!(pull_conns.len() == 2);
+        assert!(
+            pull_conns[0]
+                == &reg
+                    .connections
+                    .pull
+                    .get(&site_spec::SiteID::from_str("server/pull-site").unwrap())
+                    .unwrap()
+                    .trust
+        );
+        assert!(reg.connections.pull_imported.contains(pull_conns[1]));
+    }
+
+    #[test]
+    fn test_registered_site_ids() {
+        let test_registry = TestRegistry::new().fill_registry();
+        let reg = &test_registry.registry;
+        let mut reg_site_ids: Vec<String> = reg
+            .get_registered_site_ids()
+            .map(|s| s.to_string())
+            .collect();
+        reg_site_ids.sort_unstable();
+        assert_eq!(reg_site_ids, vec!["server/pull-site", "server/push-site"]);
+    }
+
+    #[test]
+    fn test_get_mutable() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        let pull_conn = reg
+            .get_standard_pull_connections()
+            .next()
+            .unwrap()
+            .1
+            .clone();
+        let push_conn = reg.get_push_connections().next().unwrap().1.clone();
+        assert_eq!(
+            reg.get_connection_as_mut(&site_spec::SiteID::from_str("server/pull-site").unwrap())
+                .unwrap(),
+            &pull_conn
+        );
+        assert_eq!(
+            reg.get_connection_as_mut(&site_spec::SiteID::from_str("server/push-site").unwrap())
+                .unwrap(),
+            &push_conn
+        );
+        assert!(reg
+            .get_connection_as_mut(&site_spec::SiteID::from_str("a/b").unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn test_delete_push() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        assert!(reg
+            .delete_standard_connection(&site_spec::SiteID::from_str("server/push-site").unwrap())
+            .is_ok());
+        assert!(reg.connections.push.is_empty());
+        assert!(reg.connections.pull.len() == 1);
+        assert!(reg.connections.pull_imported.len() == 1);
+    }
+
+    #[test]
+    fn test_delete_pull() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        assert!(reg
+            .delete_standard_connection(&site_spec::SiteID::from_str("server/pull-site").unwrap())
+            .is_ok());
+        assert!(reg.connections.push.len() == 1);
+        assert!(reg.connections.pull.is_empty());
+        assert!(reg.connections.pull_imported.len() == 1);
+    }
+
+    #[test]
+    fn test_delete_missing() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        assert_eq!(
+            format!(
+                "{}",
+                reg.delete_standard_connection(
+                    &site_spec::SiteID::from_str("wiener_schnitzel/pommes").unwrap()
+                )
+                .unwrap_err()
+            ),
+            "Connection 'wiener_schnitzel/pommes' not found"
+        );
+        assert!(reg.connections.push.len() == 1);
+        assert!(reg.connections.pull.len() == 1);
+        assert!(reg.connections.pull_imported.len() == 1);
+    }
+
+    #[test]
+    fn test_delete_imported_connection_ok() {
+        let uuid_first_imported = uuid::Uuid::new_v4();
+        let uuid_second_imported = uuid::Uuid::new_v4();
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        reg.connections.pull_imported.clear();
+        reg.register_imported_connection(TrustedConnection::from(uuid_first_imported));
+        reg.register_imported_connection(TrustedConnection::from(uuid_second_imported));
+        assert!(reg.delete_imported_connection(&uuid_first_imported).is_ok());
+        assert!(reg.connections.pull_imported.len() == 1);
+        assert!(reg
+            .connections
+            .pull_imported
+            .contains(&uuid_second_imported));
+    }
+
+    #[test]
+    fn test_delete_imported_connection_err() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        let uuid = uuid::Uuid::new_v4();
+        assert_eq!(
+            format!("{}", reg.delete_imported_connection(&uuid).unwrap_err()),
+            format!("Imported pull connection with UUID {uuid} not found"),
+        );
+        assert!(reg.connections.push.len() == 1);
+        assert!(reg.connections.pull.len() == 1);
+        assert!(reg.connections.pull_imported.len() == 1);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        reg.clear();
+        assert!(reg.is_empty());
+    }
+
+    #[test]
+    fn test_clear_imported() {
+        let mut test_registry = TestRegistry::new().fill_registry();
+        let reg = &mut test_registry.registry;
+        reg.clear_imported();
+        assert!(reg.is_imported_pull_empty());
+        assert!(!reg.is_empty());
+    }
+
+    #[test]
+    fn test_legacy_pull_marker_handling() {
+        let mut test_registry = TestRegistry::new();
+        let registry = &mut test_registry.registry;
+        assert!(!registry.is_legacy_pull_active());
+        assert!(registry.activate_legacy_pull(None).is_ok());
+        assert!(registry.is_legacy_pull_active());
+        registry.register_connection(
+            &ConnectionMode::Push,
+            &site_spec::SiteID::from_str("server/push-site").unwrap(),
+            trusted_connection_with_remote(),
+        );
+        assert!(!registry.is_legacy_pull_active());
+        assert!(registry.activate_legacy_pull(None).is_err());
+        registry.save().unwrap();
+        assert!(!registry.legacy_pull_marker.exists());
+        assert_eq!(
+            registry
+                .legacy_pull_window()
+                .unwrap()
+                .cutoff
+                .unwrap()
+                .reason,
+            "A connection was registered"
+        );
+    }
+
+    #[test]
+    fn test_legacy_pull_grace_period() {
+        let test_registry = TestRegistry::new();
+        let registry = &test_registry.registry;
+        registry
+            .activate_legacy_pull(Some(Duration::from_secs(3600)))
+            .unwrap();
+        assert!(registry.is_legacy_pull_active());
+        assert!(!registry.enforce_legacy_pull_grace_period().unwrap());
+
+        registry.activate_legacy_pull(Some(Duration::ZERO)).unwrap();
+        assert!(!registry.is_legacy_pull_active());
+        assert!(registry.enforce_legacy_pull_grace_period().unwrap());
+        assert!(!registry.legacy_pull_marker.exists());
+        assert_eq!(
+            registry
+                .legacy_pull_window()
+                .unwrap()
+                .cutoff
+                .unwrap()
+                .reason,
+            "Grace period expired"
+        );
+        assert!(!registry.enforce_legacy_pull_grace_period().unwrap());
+    }
+
+    #[test]
+    fn test_tmp_path_for_save() {
+        let reg = Registry::new(PathBuf::from("/a/b/c.json")).unwrap();
+        assert_ne!(reg.path(), Registry::make_tmp_path_for_save(reg.path()));
+        assert_eq!(
+            reg.path().parent(),
+            Registry::make_tmp_path_for_save(reg.path()).parent()
+        );
+        assert!(Registry::make_tmp_path_for_save(reg.path())
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .contains(reg.path().file_name().unwrap().to_str().unwrap()));
+    }
+
+    #[test]
+    fn test_mode_serialization() {
+        assert_eq!(
+            serde_json::to_string(&ConnectionMode::Pull).unwrap(),
+            "\"pull-agent\""
+        );
+        assert_eq!(
+            serde_json::to_string(&ConnectionMode::Push).unwrap(),
+            "\"push-agent\""
+        );
+        assert_eq!(
+            serde_json::from_str::<ConnectionMode>("\"push-agent\"").unwrap(),
+            ConnectionMode::Push
+        );
+        assert_eq!(
+            serde_json::from_str::<ConnectionMode>("\"pull-agent\"").unwrap(),
+            ConnectionMode::Pull
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_fips_mode {
+    use super::*;
+
+    #[test]
+    fn test_fips_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("cmk-agent-ctl.toml");
+        std::fs::write(&config_path, "fips = true\n").unwrap();
+        assert!(fips_mode(&config_path).unwrap());
+        std::fs::write(&config_path, "fips = \"yes\"\n").unwrap();
+        assert!(fips_mode(&config_path).is_err() || cfg!(feature = "fips"));
+        assert_eq!(
+            fips_mode(&dir.path().join("missing.toml")).unwrap(),
+            cfg!(feature = "fips")
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_fallback_command_config {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        let runtime_config: RuntimeConfig = toml::from_str(
+            "fallback_command = [\"/usr/local/bin/agent-down\", \"--verbose\"]\n\
+             fallback_command_timeout = 5\n",
+        )
+        .unwrap();
+        assert_eq!(
+            FallbackCommandConfig::new(&runtime_config),
+            Some(FallbackCommandConfig {
+                command: vec![
+                    String::from("/usr/local/bin/agent-down"),
+                    String::from("--verbose")
+                ],
+                timeout: 5,
+                max_size: constants::FALLBACK_COMMAND_MAX_SIZE,
+            })
+        );
+        assert_eq!(
+            FallbackCommandConfig::new(&toml::from_str("fallback_command = []").unwrap()),
+            None
+        );
+        assert_eq!(FallbackCommandConfig::new(&RuntimeConfig::default()), None);
+    }
+}
@@ -9,7 +9,7 @@ use anyhow::{bail, Context, Error as AnyhowError, Result as AnyhowResult};
 use config::JSONLoaderMissingSafe;
 use serde::Deserialize;
 use serde_with::DisplayFromStr;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::Into;
 use std::hash::Hash;
 use std::path::Path;
@@ -85,6 +85,10 @@ fn migrate_standard_connection(
         config::TrustedConnectionWithRemote {
             trust: connection.into(),
             receiver_port: coordinates.port,
+            push_interval: None,
+            hostname: None,
+            tags: BTreeMap::new(),
+            expires: None,
         },
     )
 }
@@ -148,6 +152,10 @@ impl Into<config::TrustedConnection> for Connection {
             private_key: self.private_key,
             certificate: self.certificate,
             root_cert: self.root_cert,
+            source_address: None,
+            receiver_protocol: Default::default(),
+            transport: Default::default(),
+            data_source: None,
         }
     }
 }
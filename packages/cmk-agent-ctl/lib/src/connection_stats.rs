@@ -0,0 +1,619 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::config::{JSONLoader, JSONLoaderMissingSafe};
+use crate::payload_stats::PayloadStats;
+use crate::{constants, misc};
+use anyhow::Result as AnyhowResult;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PushAttemptOutcome {
+    Pushed,
+    Unchanged,
+    Failed,
+}
+
+/// A single attempt to push to a connection, as kept in its push history
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PushAttempt {
+    /// Unix timestamp of the start of the attempt
+    pub timestamp: u64,
+    pub duration_ms: u64,
+    pub outcome: PushAttemptOutcome,
+    /// Size of the uploaded, compressed agent output
+    pub payload_bytes: u64,
+    pub http_status: Option<u16>,
+    pub error: Option<String>,
+}
+
+impl PushAttempt {
+    pub fn pushed(timestamp: u64, duration: Duration, bytes: usize) -> Self {
+        Self {
+            timestamp,
+            duration_ms: duration.as_millis() as u64,
+            outcome: PushAttemptOutcome::Pushed,
+            payload_bytes: bytes as u64,
+            // The agent receiver answers uploads with 204 No Content
+            http_status: Some(204),
+            error: None,
+        }
+    }
+
+    pub fn unchanged(timestamp: u64, duration: Duration) -> Self {
+        Self {
+            timestamp,
+            duration_ms: duration.as_millis() as u64,
+            outcome: PushAttemptOutcome::Unchanged,
+            payload_bytes: 0,
+            http_status: None,
+            error: None,
+        }
+    }
+
+    pub fn failed(
+        timestamp: u64,
+        duration: Duration,
+        http_status: Option<u16>,
+        error: String,
+    ) -> Self {
+        Self {
+            timestamp,
+            duration_ms: duration.as_millis() as u64,
+            outcome: PushAttemptOutcome::Failed,
+            payload_bytes: 0,
+            http_status,
+            error: Some(error),
+        }
+    }
+}
+
+/// A single request to the agent receiver, as kept in the window for the latency statistics
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReceiverCall {
+    /// Unix timestamp of the start of the call
+    pub timestamp: u64,
+    pub duration_ms: u64,
+    pub success: bool,
+}
+
+/// Latency percentiles and success ratio of the calls in the window. The percentiles only cover
+/// successful calls, st. a slow site can be told apart from failing calls, eg. due to timeouts.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ReceiverStats {
+    pub calls: usize,
+    pub successful_calls: usize,
+    pub success_ratio: f64,
+    pub latency_p50_ms: Option<u64>,
+    pub latency_p90_ms: Option<u64>,
+    pub latency_p99_ms: Option<u64>,
+}
+
+impl ReceiverStats {
+    pub fn from_calls<'a>(calls: impl IntoIterator<Item = &'a ReceiverCall>) -> Option<Self> {
+        let mut total = 0;
+        let mut durations = vec![];
+        for call in calls {
+            total += 1;
+            if call.success {
+                durations.push(call.duration_ms);
+            }
+        }
+        if total == 0 {
+            return None;
+        }
+        durations.sort_unstable();
+        let percentile = |p: f64| -> Option<u64> {
+            // Nearest-rank method
+            let rank = (p * durations.len() as f64 / 100.0).ceil() as usize;
+            durations.get(rank.max(1) - 1).copied()
+        };
+        Some(Self {
+            calls: total,
+            successful_calls: durations.len(),
+            success_ratio: durations.len() as f64 / total as f64,
+            latency_p50_ms: percentile(50.0),
+            latency_p90_ms: percentile(90.0),
+            latency_p99_ms: percentile(99.0),
+        })
+    }
+}
+
+/// Clock skew versus the agent receiver, measured from the Date header of its most recent response
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockSkew {
+    /// Unix timestamp of the measurement
+    pub timestamp: u64,
+    /// Positive if the agent receiver is ahead
+    pub skew_secs: i64,
+}
+
+impl ClockSkew {
+    /// Certificate validation and the scheduling misbehave on hosts with a skewed clock
+    pub fn is_excessive(&self) -> bool {
+        self.skew_secs.unsigned_abs() > constants::CLOCK_SKEW_TOLERANCE
+    }
+
+    /// How far the clock of the agent receiver is off, eg. "5m ahead"
+    pub fn describe(&self) -> String {
+        format!(
+            "{} {}",
+            misc::human_readable_duration(self.skew_secs.unsigned_abs()),
+            if self.skew_secs < 0 {
+                "behind"
+            } else {
+                "ahead"
+            }
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionCounters {
+    #[serde(default)]
+    pub successful_pulls: u64,
+    #[serde(default)]
+    pub tls_failures: u64,
+    #[serde(default)]
+    pub successful_pushes: u64,
+    #[serde(default)]
+    pub failed_pushes: u64,
+    #[serde(default)]
+    pub skipped_pushes: u64,
+    #[serde(default)]
+    pub bytes_served: u64,
+    #[serde(default)]
+    pub last_peer_address: Option<String>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// The most recent push attempts, oldest first
+    #[serde(default)]
+    pub push_history: VecDeque<PushAttempt>,
+    /// The most recent calls to the agent receiver, oldest first
+    #[serde(default)]
+    pub receiver_calls: VecDeque<ReceiverCall>,
+    #[serde(default)]
+    pub clock_skew: Option<ClockSkew>,
+}
+
+impl ConnectionCounters {
+    pub fn receiver_stats(&self) -> Option<ReceiverStats> {
+        ReceiverStats::from_calls(&self.receiver_calls)
+    }
+}
+
+/// Counters of all connections, keyed by connection UUID.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct CountersByConnection(HashMap<String, ConnectionCounters>);
+
+impl JSONLoader for CountersByConnection {}
+impl JSONLoaderMissingSafe for CountersByConnection {}
+
+impl CountersByConnection {
+    pub fn get(&self, uuid: &uuid::Uuid) -> Option<&ConnectionCounters> {
+        self.0.get(&uuid.to_string())
+    }
+}
+
+/// Collects per-connection counters in the daemon and persists them, st. they can be
+/// reported by the status mode, which runs in a separate process.
+/// Within a tokio runtime, the file is written on the blocking thread pool, so that the
+/// handlers of pulls and pushes do not wait for the disk.
+#[derive(Clone)]
+pub struct ConnectionStats {
+    counters: Arc<PersistedCounters>,
+    payload: PayloadStats,
+}
+
+/// The counters shared by all clones of a ConnectionStats. Whatever was not written yet is
+/// written when the last clone is dropped.
+struct PersistedCounters {
+    path: PathBuf,
+    counters: Mutex<CountersByConnection>,
+    /// Set by updates which are not on disk yet, at most one write is scheduled for them
+    unsaved: AtomicBool,
+    /// Serializes the writes, st. the latest counters end up in the file
+    writing: Mutex<()>,
+}
+
+impl PersistedCounters {
+    fn lock(&self) -> MutexGuard<'_, CountersByConnection> {
+        match self.counters.lock() {
+            Ok(counters) => counters,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn save_unsaved(&self) {
+        let _writing = match self.writing.lock() {
+            Ok(writing) => writing,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if !self.unsaved.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let counters = self.lock().clone();
+        if let Err(err) = self.save(&counters) {
+            warn!(
+                "Failed to write connection counters to {:?}. ({})",
+                self.path, err
+            );
+        }
+    }
+
+    fn save(&self, counters: &CountersByConnection) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(counters)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        #[cfg(unix)]
+        fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600))?;
+        Ok(())
+    }
+}
+
+impl Drop for PersistedCounters {
+    fn drop(&mut self) {
+        self.save_unsaved();
+    }
+}
+
+impl ConnectionStats {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let counters =
+            CountersByConnection::load_missing_safe(path.as_ref()).unwrap_or_else(|err| {
+                warn!(
+                    "Could not load connection counters from {:?}, starting from scratch. ({})",
+                    path.as_ref(),
+                    err
+                );
+                CountersByConnection::default()
+            });
+        Self {
+            counters: Arc::new(PersistedCounters {
+                path: PathBuf::from(path.as_ref()),
+                counters: Mutex::new(counters),
+                unsaved: AtomicBool::new(false),
+                writing: Mutex::new(()),
+            }),
+            payload: PayloadStats::default(),
+        }
+    }
+
+    /// Also persist the sizes of the collected agent outputs, which are kept in memory otherwise
+    pub fn with_payload_stats(self, payload: PayloadStats) -> Self {
+        Self { payload, ..self }
+    }
+
+    pub fn payload(&self) -> PayloadStats {
+        self.payload.clone()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> AnyhowResult<CountersByConnection> {
+        CountersByConnection::load_missing_safe(path.as_ref())
+    }
+
+    pub fn record_pull(&self, uuid: &uuid::Uuid, peer: std::net::IpAddr, bytes: usize) {
+        self.update(uuid, |c| {
+            c.successful_pulls += 1;
+            c.bytes_served += bytes as u64;
+            c.last_peer_address = Some(peer.to_string());
+        })
+    }
+
+    pub fn record_tls_failure(&self, uuid: &uuid::Uuid, peer: std::net::IpAddr, error: &str) {
+        self.update(uuid, |c| {
+            c.tls_failures += 1;
+            c.last_peer_address = Some(peer.to_string());
+            c.last_error = Some(String::from(error));
+        })
+    }
+
+    pub fn record_push(&self, uuid: &uuid::Uuid, attempt: PushAttempt) {
+        self.update(uuid, |c| {
+            match attempt.outcome {
+                PushAttemptOutcome::Pushed => {
+                    c.successful_pushes += 1;
+                    c.bytes_served += attempt.payload_bytes;
+                }
+                PushAttemptOutcome::Unchanged => c.skipped_pushes += 1,
+                PushAttemptOutcome::Failed => {
+                    c.failed_pushes += 1;
+                    c.last_error = attempt.error.clone();
+                }
+            }
+            c.receiver_calls.push_back(ReceiverCall {
+                timestamp: attempt.timestamp,
+                duration_ms: attempt.duration_ms,
+                success: attempt.outcome != PushAttemptOutcome::Failed,
+            });
+            while c.receiver_calls.len() > constants::RECEIVER_CALL_HISTORY_SIZE {
+                c.receiver_calls.pop_front();
+            }
+            c.push_history.push_back(attempt);
+            while c.push_history.len() > constants::PUSH_HISTORY_SIZE {
+                c.push_history.pop_front();
+            }
+        })
+    }
+
+    /// Changes of the clock skew across the tolerance are logged, not every measurement
+    pub fn record_clock_skew(&self, uuid: &uuid::Uuid, skew_secs: i64) {
+        let skew = ClockSkew {
+            timestamp: misc::unix_now(),
+            skew_secs,
+        };
+        self.update(uuid, |c| {
+            let was_excessive = c.clock_skew.map(|previous| previous.is_excessive());
+            if skew.is_excessive() && was_excessive != Some(true) {
+                warn!(
+                    "{}: Clock of the agent receiver is {}, synchronize the clocks, e.g. via NTP",
+                    uuid,
+                    skew.describe()
+                );
+            } else if !skew.is_excessive() && was_excessive == Some(true) {
+                info!("{}: Clock is in sync with the agent receiver again", uuid);
+            }
+            c.clock_skew = Some(skew);
+        })
+    }
+
+    /// Current counters of all connections
+    pub fn snapshot(&self) -> CountersByConnection {
+        self.counters.lock().clone()
+    }
+
+    fn update(&self, uuid: &uuid::Uuid, f: impl FnOnce(&mut ConnectionCounters)) {
+        f(self.counters.lock().0.entry(uuid.to_string()).or_default());
+        if self.counters.unsaved.swap(true, Ordering::SeqCst) {
+            // The write scheduled already picks up this update
+            return;
+        }
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                let counters = self.counters.clone();
+                runtime.spawn_blocking(move || counters.save_unsaved());
+            }
+            Err(_) => self.counters.save_unsaved(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_connection_stats {
+    use super::*;
+    use std::str::FromStr;
+
+    fn uuid() -> uuid::Uuid {
+        uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap()
+    }
+
+    #[test]
+    fn test_record_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("connection_stats.json");
+        let stats = ConnectionStats::new(&path);
+        let peer = std::net::IpAddr::from_str("192.168.1.13").unwrap();
+        stats.record_pull(&uuid(), peer, 100);
+        stats.record_pull(&uuid(), peer, 50);
+        stats.record_tls_failure(&uuid(), peer, "bad certificate");
+
+        assert_eq!(
+            ConnectionStats::load(&path).unwrap().get(&uuid()).unwrap(),
+            &ConnectionCounters {
+                successful_pulls: 2,
+                tls_failures: 1,
+                successful_pushes: 0,
+                failed_pushes: 0,
+                skipped_pushes: 0,
+                bytes_served: 150,
+                last_peer_address: Some(String::from("192.168.1.13")),
+                last_error: Some(String::from("bad certificate")),
+                push_history: VecDeque::new(),
+                receiver_calls: VecDeque::new(),
+                clock_skew: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_record_in_runtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("connection_stats.json");
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let stats = ConnectionStats::new(&path);
+        let peer = std::net::IpAddr::from_str("192.168.1.13").unwrap();
+        runtime.block_on(async {
+            for _ in 0..10 {
+                stats.record_pull(&uuid(), peer, 100);
+            }
+        });
+        drop(stats);
+        drop(runtime);
+
+        let counters = ConnectionStats::load(&path).unwrap();
+        let counters = counters.get(&uuid()).unwrap();
+        assert_eq!(counters.successful_pulls, 10);
+        assert_eq!(counters.bytes_served, 1000);
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("connection_stats.json");
+        let stats = ConnectionStats::new(&path);
+        stats.record_clock_skew(&uuid(), -300);
+        let skew = ConnectionStats::load(&path)
+            .unwrap()
+            .get(&uuid())
+            .unwrap()
+            .clock_skew
+            .unwrap();
+        assert_eq!(skew.skew_secs, -300);
+        assert!(skew.is_excessive());
+        assert_eq!(skew.describe(), "5m behind");
+        stats.record_clock_skew(&uuid(), 1);
+        let skew = stats.snapshot().get(&uuid()).unwrap().clock_skew.unwrap();
+        assert!(!skew.is_excessive());
+        assert_eq!(skew.describe(), "1s ahead");
+    }
+
+    #[test]
+    fn test_continue_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("connection_stats.json");
+        ConnectionStats::new(&path).record_push(
+            &uuid(),
+            PushAttempt::pushed(1000, Duration::from_millis(20), 10),
+        );
+        ConnectionStats::new(&path).record_push(
+            &uuid(),
+            PushAttempt::failed(1060, Duration::from_secs(30), None, String::from("timeout")),
+        );
+
+        let counters = ConnectionStats::load(&path).unwrap();
+        let counters = counters.get(&uuid()).unwrap();
+        assert_eq!(counters.successful_pushes, 1);
+        assert_eq!(counters.failed_pushes, 1);
+        assert_eq!(counters.last_error, Some(String::from("timeout")));
+        assert_eq!(counters.push_history.len(), 2);
+    }
+
+    #[test]
+    fn test_push_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats = ConnectionStats::new(dir.path().join("connection_stats.json"));
+        for timestamp in 0..constants::PUSH_HISTORY_SIZE as u64 + 5 {
+            stats.record_push(
+                &uuid(),
+                PushAttempt::unchanged(timestamp, Duration::from_millis(5)),
+            );
+        }
+        stats.record_push(
+            &uuid(),
+            PushAttempt::failed(
+                100,
+                Duration::from_millis(50),
+                Some(400),
+                String::from("Bad Request"),
+            ),
+        );
+
+        let counters = stats.snapshot();
+        let history = &counters.get(&uuid()).unwrap().push_history;
+        assert_eq!(history.len(), constants::PUSH_HISTORY_SIZE);
+        assert_eq!(history.front().unwrap().timestamp, 6);
+        assert_eq!(
+            history.back().unwrap(),
+            &PushAttempt {
+                timestamp: 100,
+                duration_ms: 50,
+                outcome: PushAttemptOutcome::Failed,
+                payload_bytes: 0,
+                http_status: Some(400),
+                error: Some(String::from("Bad Request")),
+            }
+        );
+    }
+
+    #[test]
+    fn test_receiver_calls_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats = ConnectionStats::new(dir.path().join("connection_stats.json"));
+        for timestamp in 0..constants::RECEIVER_CALL_HISTORY_SIZE as u64 + 10 {
+            stats.record_push(
+                &uuid(),
+                PushAttempt::unchanged(timestamp, Duration::from_millis(5)),
+            );
+        }
+        stats.record_push(
+            &uuid(),
+            PushAttempt::failed(200, Duration::from_secs(30), None, String::from("timeout")),
+        );
+
+        let counters = stats.snapshot();
+        let calls = &counters.get(&uuid()).unwrap().receiver_calls;
+        assert_eq!(calls.len(), constants::RECEIVER_CALL_HISTORY_SIZE);
+        assert_eq!(calls.front().unwrap().timestamp, 11);
+        assert_eq!(
+            calls.back().unwrap(),
+            &ReceiverCall {
+                timestamp: 200,
+                duration_ms: 30000,
+                success: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_receiver_stats() {
+        // 100 successful calls of 1 to 100 ms and 25 timeouts
+        let calls: Vec<ReceiverCall> = (1..=125)
+            .map(|index| ReceiverCall {
+                timestamp: 0,
+                duration_ms: if index <= 100 { index } else { 30000 },
+                success: index <= 100,
+            })
+            .collect();
+        assert_eq!(
+            ReceiverStats::from_calls(&calls).unwrap(),
+            ReceiverStats {
+                calls: 125,
+                successful_calls: 100,
+                success_ratio: 0.8,
+                latency_p50_ms: Some(50),
+                latency_p90_ms: Some(90),
+                latency_p99_ms: Some(99),
+            }
+        );
+    }
+
+    #[test]
+    fn test_receiver_stats_edge_cases() {
+        assert_eq!(ReceiverStats::from_calls(&[]), None);
+        let failed = ReceiverCall {
+            timestamp: 0,
+            duration_ms: 100,
+            success: false,
+        };
+        assert_eq!(
+            ReceiverStats::from_calls(&[failed]).unwrap(),
+            ReceiverStats {
+                calls: 1,
+                successful_calls: 0,
+                success_ratio: 0.0,
+                latency_p50_ms: None,
+                latency_p90_ms: None,
+                latency_p99_ms: None,
+            }
+        );
+        let single = ReceiverCall {
+            success: true,
+            ..failed
+        };
+        let stats = ReceiverStats::from_calls(&[single, failed]).unwrap();
+        assert_eq!(stats.success_ratio, 0.5);
+        assert_eq!(stats.latency_p50_ms, Some(100));
+        assert_eq!(stats.latency_p99_ms, Some(100));
+    }
+
+    #[test]
+    fn test_load_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            ConnectionStats::load(dir.path().join("connection_stats.json")).unwrap(),
+            CountersByConnection::default()
+        );
+    }
+}
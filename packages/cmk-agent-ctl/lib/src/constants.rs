@@ -0,0 +1,306 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+pub const VERSION: &str = "2.3.0b1";
+
+// CONFIGURATION
+pub const DEFAULT_PULL_PORT: u16 = 6556;
+pub const MAX_CONNECTIONS: usize = 3;
+pub const CONNECTION_TIMEOUT: u64 = 20;
+pub const PUSH_INTERVAL: u64 = 60;
+/// Smallest push interval (in seconds), shorter ones are rejected, also in the config files
+pub const MIN_PUSH_INTERVAL: u64 = 10;
+pub const PUSH_SPOOL_SIZE: usize = 60;
+pub const PUSH_HISTORY_SIZE: usize = 20;
+pub const RECEIVER_CALL_HISTORY_SIZE: usize = 100;
+/// Number of restarts per task of the daemon kept for the status
+pub const TASK_RESTART_HISTORY_SIZE: usize = 10;
+/// Number of collected agent outputs whose size is kept, to tell how fast the output grows
+pub const PAYLOAD_HISTORY_SIZE: usize = 30;
+/// Number of days kept in the usage statistics, older days are dropped
+pub const USAGE_STATS_DAYS: usize = 90;
+/// Default timeouts of the calls to the agent receivers, see config::NetworkTimeouts. The total
+/// one is the default of reqwest.
+pub const READ_TIMEOUT: u64 = 20;
+pub const REQUEST_TIMEOUT: u64 = 30;
+pub const PUSH_TIMEOUT: u64 = 30;
+pub const PUSH_TASK_TIMEOUT: u64 = 120;
+pub const MAX_OUTBOUND_REQUESTS: usize = 8;
+/// Idle connections to the agent receivers are closed after this time (in seconds), which is
+/// longer than the default push interval
+pub const CLIENT_POOL_IDLE_TIMEOUT: u64 = 150;
+pub const TCP_KEEPALIVE_INTERVAL: u64 = 60;
+/// Remote queries of the status mode running at the same time
+pub const MAX_PARALLEL_STATUS_QUERIES: usize = 8;
+pub const MAX_BLOCKING_THREADS: usize = 16;
+/// Time running pushes get to finish when the daemon is asked to stop
+pub const STOP_DRAIN_TIMEOUT: u64 = 30;
+pub const STOP_CHECKPOINT_INTERVAL: u64 = 5;
+/// Delay (in seconds) before restarting a failed task of the daemon, doubled with every further
+/// restart within the restart window
+pub const TASK_RESTART_BACKOFF_BASE: u64 = 1;
+pub const TASK_RESTART_BACKOFF_MAX: u64 = 60;
+/// A task failing more often than this within the window (in seconds) stops the daemon
+pub const TASK_RESTART_LIMIT: usize = 5;
+pub const TASK_RESTART_WINDOW: u64 = 600;
+pub const CONDITIONAL_PUSH_MAX_AGE: u64 = 600;
+/// Interval (in seconds) of checking the stored certificates and the registry for integrity
+pub const INTEGRITY_CHECK_INTERVAL: u64 = 3600;
+/// Time (in milliseconds) after which a registry not matching its checksum is checked once more,
+/// st. a registry which is just being saved is not reported
+pub const INTEGRITY_RECHECK_DELAY: u64 = 500;
+/// Time (in seconds) after which the last-seen timestamp of an unchanged hostname is refreshed
+pub const KNOWN_HOSTNAME_REFRESH_INTERVAL: u64 = 3600;
+/// Size (in bytes) of the blocks of the last pushed agent output which delta uploads refer to
+pub const DELTA_BLOCK_SIZE: usize = 256;
+/// Smallest size (in bytes) of the chunks large agent outputs are pushed in
+pub const MIN_PUSH_CHUNK_SIZE: usize = 65536;
+/// Number of times a chunk is sent again after the upload failed, before giving up the push
+pub const PUSH_CHUNK_RETRIES: usize = 3;
+/// Size (in bytes) of the messages agent outputs are streamed in via gRPC, well below the
+/// default limit of 4 MiB of gRPC servers
+pub const GRPC_MESSAGE_SIZE: usize = 65536;
+/// Delay (in seconds) after the second consecutive registration with rejected credentials,
+/// doubled with every further one
+pub const REGISTRATION_BACKOFF_BASE: u64 = 30;
+pub const REGISTRATION_BACKOFF_MAX: u64 = 3600;
+/// Interval (in seconds) of asking whether a new host was approved on the site
+pub const APPROVAL_POLL_INTERVAL: u64 = 20;
+/// Time (in seconds) the receiver may hold back its answer until a new host is approved or
+/// declined, receivers without long polling answer right away
+pub const APPROVAL_LONG_POLL_WAIT: u64 = 60;
+/// Interval (in seconds) of checking whether the sites still accept the connections which are
+/// registered again automatically
+pub const REREGISTRATION_CHECK_INTERVAL: u64 = 900;
+/// Interval (in seconds) of checking whether the site pulled via TLS after migrating from legacy
+/// pull mode
+pub const MIGRATION_CONFIRM_INTERVAL: u64 = 5;
+/// Time (in seconds) after which pushes to a failover endpoint check whether the registered
+/// endpoint is available again
+pub const PUSH_FAILOVER_RECOVERY_INTERVAL: u64 = 300;
+/// Interval (in seconds) of pushing the real-time sections
+pub const REALTIME_INTERVAL: u64 = 10;
+/// A real-time push taking longer is given up, the next one follows soon anyway
+pub const REALTIME_TIMEOUT: u64 = 5;
+/// Time (in seconds) after which real-time data is sent again to a receiver which did not accept it
+pub const REALTIME_RETRY_INTERVAL: u64 = 3600;
+/// Time (in seconds) an external command post-processing the agent output may take
+pub const POST_PROCESSING_COMMAND_TIMEOUT: u64 = 30;
+/// Time (in seconds) a command providing the agent output of a connection may take
+pub const DATA_SOURCE_COMMAND_TIMEOUT: u64 = 60;
+/// Time (in seconds) the command run if the agent socket is unreachable may take
+pub const FALLBACK_COMMAND_TIMEOUT: u64 = 10;
+/// Maximum size (in bytes) of the output of the command run if the agent socket is unreachable
+pub const FALLBACK_COMMAND_MAX_SIZE: usize = 1024 * 1024;
+/// Interval of checking the registry for pull tunnels to open or close
+pub const PULL_TUNNEL_REFRESH_INTERVAL: u64 = 60;
+/// Waiting time before reopening a failed pull tunnel, doubled up to the maximum
+pub const PULL_TUNNEL_RETRY_MIN: u64 = 5;
+pub const PULL_TUNNEL_RETRY_MAX: u64 = 300;
+/// A tunnel without any message for this long is pinged, without an answer, it is reopened
+pub const PULL_TUNNEL_PING_INTERVAL: u64 = 60;
+/// Largest message accepted from the agent receiver through a pull tunnel
+pub const PULL_TUNNEL_MAX_MESSAGE_SIZE: usize = 65536;
+/// Time (in seconds) to wait for a QUIC handshake with an agent receiver before pushing via HTTPS
+pub const QUIC_CONNECT_TIMEOUT: u64 = 5;
+/// Time (in seconds) after which QUIC is tried again for a receiver which did not accept it
+pub const QUIC_RETRY_INTERVAL: u64 = 3600;
+/// Port on which a relay accepts pull requests of the site for the agents paired with it
+pub const DEFAULT_RELAY_PORT: u16 = 6557;
+/// Validity (in days) of the CA of a relay and of the certificates it issues to agents
+pub const RELAY_CERT_VALIDITY_DAYS: u32 = 3650;
+/// Port of the mock agent receiver, the one of the agent receiver of the first site
+#[cfg(feature = "mock-receiver")]
+pub const DEFAULT_MOCK_RECEIVER_PORT: u16 = 8000;
+/// Time (in seconds) a slow mock agent receiver takes for answering a request
+#[cfg(feature = "mock-receiver")]
+pub const DEFAULT_MOCK_RECEIVER_DELAY: u64 = 10;
+/// Largest request body accepted by the mock agent receiver
+#[cfg(feature = "mock-receiver")]
+pub const MOCK_RECEIVER_MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+/// Link-local address of the instance metadata services of AWS, Azure and GCP
+pub const CLOUD_METADATA_ADDRESS: &str = "169.254.169.254";
+/// Time (in seconds) to wait for the instance metadata service of a cloud provider
+pub const CLOUD_METADATA_TIMEOUT: u64 = 2;
+/// Where Linux exposes the SMBIOS/DMI identity of the hardware
+#[cfg(target_os = "linux")]
+pub const DMI_DIR: &str = "/sys/class/dmi/id";
+/// Interval (in seconds) of checking the hardware labels for changes
+pub const HARDWARE_LABELS_REFRESH_INTERVAL: u64 = 3600;
+/// Time (in seconds) after which the proxy auto-config is discovered again
+pub const PROXY_DISCOVERY_CACHE_TIME: u64 = 3600;
+/// Time (in seconds) to wait for a proxy auto-config script
+pub const PROXY_DISCOVERY_TIMEOUT: u64 = 5;
+pub const DEFAULT_METRICS_BIND_ADDRESS: std::net::IpAddr =
+    std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+pub const METRICS_READ_TIMEOUT: u64 = 5;
+/// Size (in bytes) up to which the request line and headers of a metrics request are read
+pub const METRICS_MAX_REQUEST_SIZE: u64 = 8192;
+pub const IPC_READ_TIMEOUT: u64 = 5;
+pub const HTTP_TRACE_MAX_BODY_SIZE: usize = 4096;
+pub const DOCTOR_CONNECT_TIMEOUT: u64 = 5;
+/// Time (in milliseconds) after which the next address of a receiver is tried while the previous
+/// attempts go on, as recommended by RFC 8305
+pub const HAPPY_EYEBALLS_ATTEMPT_DELAY: u64 = 250;
+pub const DNS_PORT: u16 = 53;
+/// Time (in seconds) to wait for the answer of a configured name server
+pub const DNS_QUERY_TIMEOUT: u64 = 2;
+pub const CLOCK_SKEW_TOLERANCE: u64 = 60;
+pub const CERT_VALIDITY_LOWER_LIMIT: u64 = 3888000; // 45 days = 45*24*60*60
+pub const CERT_VALIDITY_UPPER_LIMIT: u64 = 15768000000; // approx. 500 years = 500*365*24*60*60
+#[cfg(unix)]
+pub const CMK_AGENT_USER: &str = "cmk-agent";
+/// Ports below this one can only be bound with root privileges
+#[cfg(unix)]
+pub const PRIVILEGED_PORT_LIMIT: u16 = 1024;
+#[cfg(target_os = "linux")]
+pub const UNIX_AGENT_SOCKET: &str = "/run/check-mk-agent.socket";
+/// macOS and BSD
+#[cfg(all(unix, not(target_os = "linux")))]
+pub const UNIX_AGENT_SOCKET: &str = "/var/run/check-mk-agent.socket";
+/// Named pipe of the agent service, below \\.\pipe\
+#[cfg(windows)]
+pub const WIN_AGENT_PIPE: &str = "checkmk_agent";
+/// How long to wait for a free instance of the named pipe of the agent service
+#[cfg(windows)]
+pub const WIN_AGENT_PIPE_BUSY_TIMEOUT: u64 = 5;
+
+// FILES
+pub const PRE_CONFIGURED_CONNECTIONS_FILE: &str = "pre_configured_connections.json";
+/// Connection baked into a golden image, imported on the first start of the daemon
+pub const IMAGE_CONNECTION_FILE: &str = "image_connection.json";
+pub const REGISTRY_FILE: &str = "registered_connections.json";
+pub const CONFIG_FILE: &str = "cmk-agent-ctl.toml";
+pub const CONNECTION_STATS_FILE: &str = "connection_stats.json";
+pub const PAYLOAD_STATS_FILE: &str = "payload_stats.json";
+pub const USAGE_STATS_FILE: &str = "usage_stats.json";
+pub const REMOTE_STATUS_CACHE_FILE: &str = "remote_status_cache.json";
+pub const INTEGRITY_CHECK_FILE: &str = "integrity_check.json";
+pub const REGISTRATION_THROTTLE_FILE: &str = "registration_throttle.json";
+pub const REREGISTRATION_CREDENTIALS_FILE: &str = "reregistration_credentials.json";
+pub const PUSH_SPOOL_DIR: &str = "push_spool";
+pub const CONTROL_SOCKET_FILE: &str = "cmk-agent-ctl.sock";
+/// Takes the place of the control socket under Windows
+#[cfg(windows)]
+pub const WIN_CONTROL_PIPE: &str = "\\\\.\\pipe\\checkmk_agent_ctl_control";
+pub const RELAY_FILE: &str = "relay.json";
+pub const VAULT_DIR: &str = "vault";
+/// Wraps the keys of the vault tenants. Stays in the home dir, also if the vault is moved along
+/// with the state dir.
+pub const VAULT_KEY_FILE: &str = "vault_key";
+/// Config and state of the agent updater of agents before Checkmk 2.1, see 'migrate-updater'
+pub const UPDATER_CONFIG_FILE: &str = "cmk-update-agent.cfg";
+pub const UPDATER_STATE_FILE: &str = "cmk-update-agent.state";
+#[cfg(unix)]
+pub const UNIX_UPDATER_CONFIG_DIR: &str = "/etc/check_mk";
+#[cfg(unix)]
+pub const UNIX_UPDATER_STATE_DIR: &str = "/etc";
+/// Below the agent home dir, for both files
+#[cfg(windows)]
+pub const WIN_UPDATER_DIR: &str = "config";
+
+// ENVIRONMENT
+#[cfg(windows)]
+pub const ENV_AGENT_LOG_DIR: &str = "MK_LOGDIR";
+#[cfg(windows)]
+pub const ENV_LOG_TO_FILE: &str = "CMK_AGENT_CTL_LOG_TO_FILE";
+/// URL or path of the proxy auto-config script, instead of the one found via WPAD
+#[cfg(unix)]
+pub const ENV_PAC_URL: &str = "CMK_AGENT_CTL_PAC_URL";
+
+// DIRS
+#[cfg(windows)]
+pub const WIN_AGENT_HOME_DIR: &str = "\\checkmk\\agent";
+/// Under macOS, the agent user has no home dir of its own
+#[cfg(target_os = "macos")]
+pub const MACOS_AGENT_HOME_DIR: &str = "/Library/Application Support/checkmk/agent-ctl";
+
+// ENV VARS
+pub const ENV_HOME_DIR: &str = "DEBUG_HOME_DIR";
+pub const ENV_MAX_CONNECTIONS: &str = "DEBUG_MAX_CONNECTIONS";
+pub const ENV_CONNECTION_TIMEOUT: &str = "DEBUG_CONNECTION_TIMEOUT";
+#[cfg(windows)]
+pub const ENV_PROGRAM_DATA: &str = "ProgramData";
+
+// Windows version
+// https://en.wikipedia.org/wiki/List_of_Microsoft_Windows_versions
+// We support only relative new version of Windows because of Rust toolchain:
+// Server 2008 R2 & Windows 7, i.e. 6.1
+#[cfg(windows)]
+pub const MIN_WIN_VERSION_MAJOR: u64 = 6;
+#[cfg(windows)]
+pub const MIN_WIN_VERSION_MINOR: u64 = 1;
+
+// Log Rotation default parameters
+pub mod log {
+    use flexi_logger::Naming;
+    pub const FILE_MAX_SIZE: u64 = 500000;
+    pub const FILE_NAMING: Naming = Naming::Numbers;
+    pub const FILE_KEEP: usize = 5;
+}
+
+// System log backends
+pub mod system_log {
+    pub const IDENTIFIER: &str = "cmk-agent-ctl";
+    #[cfg(unix)]
+    pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+    /// Linux, macOS and BSD, respectively
+    #[cfg(unix)]
+    pub const SYSLOG_SOCKETS: [&str; 3] = ["/dev/log", "/var/run/syslog", "/var/run/log"];
+    #[cfg(unix)]
+    pub const SYSLOG_FACILITY_DAEMON: u8 = 3;
+}
+
+// Windows service
+#[cfg(windows)]
+pub mod windows_service {
+    pub const NAME: &str = "CheckmkAgentCtl";
+    /// Time the service control manager waits for the next checkpoint of a pending operation
+    pub const WAIT_HINT: u64 = 30;
+    /// Reported to the service control manager if the daemon failed
+    pub const FAILURE_EXIT_CODE: u32 = 1;
+}
+
+// systemd service notifications
+#[cfg(unix)]
+pub mod sd_notify {
+    pub const ENV_NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+    pub const ENV_WATCHDOG_USEC: &str = "WATCHDOG_USEC";
+    pub const ENV_WATCHDOG_PID: &str = "WATCHDOG_PID";
+    /// Interval of the status updates if the watchdog is disabled
+    pub const STATUS_INTERVAL: u64 = 60;
+}
+
+// Operation in containers, eg. as sidecar of a Kubernetes pod
+pub mod container {
+    /// Home dir, eg. an emptyDir or a persistent volume
+    pub const ENV_HOME_DIR: &str = "CMK_AGENT_CTL_HOME";
+    pub const HOME_DIR: &str = "/var/lib/cmk-agent-ctl";
+    /// Config file, eg. mounted from a ConfigMap
+    pub const ENV_CONFIG_FILE: &str = "CMK_AGENT_CTL_CONFIG_FILE";
+    /// Prefix of the env vars overriding single settings, eg. CMK_AGENT_CTL_SETTING_PUSH_INTERVAL
+    pub const ENV_SETTING_PREFIX: &str = "CMK_AGENT_CTL_SETTING_";
+    /// Registration parameters in the format of the user data of the bootstrap mode, eg. mounted
+    /// from a Secret
+    pub const ENV_IDENTITY_FILE: &str = "CMK_AGENT_CTL_IDENTITY_FILE";
+    /// Socket of the agent, eg. on a volume shared with the container of the agent
+    pub const ENV_AGENT_SOCKET: &str = "CMK_AGENT_CTL_AGENT_SOCKET";
+    /// Fingerprint of the identity the current connection was registered with
+    pub const IDENTITY_STATE_FILE: &str = "container_identity.json";
+    /// Seconds between two checks of the mounted identity
+    pub const IDENTITY_CHECK_INTERVAL: u64 = 30;
+}
+
+// CA
+#[cfg(test)]
+pub const TEST_ROOT_CERT: &str = "-----BEGIN CERTIFICATE-----\nMIIDFTCCAf2gAwIBAgIUaDlr/3eN2SmBMlpmW9cICSVzcEwwDQYJKoZIhvcNAQEL\nBQAwIDEeMBwGA1UEAwwVU2l0ZSAnaGV1dGUnIGxvY2FsIENBMCAXDTIyMDYxMzEw\nMTQyNVoYDzMwMjAxMDE0MTAxNDI1WjAgMR4wHAYDVQQDDBVTaXRlICdoZXV0ZScg\nbG9jYWwgQ0EwggEiMA0GCSqGSIb3DQEBAQUAA4IBDwAwggEKAoIBAQDwvHoHuD6E\naQNpEaznTKd/6M/jkiopZ8It+zSEi93zBwu2ZsJlv8Kl1KkWim0s6o/YuQx//USQ\nfVR3lAazRr2k4xxwbThzXh+0S2dp5RWRBZCuJElwQ+u+PVmVsq/Zusj+YVl1Jo3F\nZ5xGUwjS+G9+ZElDnGpDi0NG5GNoozE5L0EEnQArsC+V7MoTUKebN+x9zlcc7bPb\nfphcwLrA/IGuJe7Ab6oLbEm/pA3X1LxyY98/pBoUeVXlEjJMo/8SrW+1Y02GyHCJ\nysVWC2+PwFdm4GXMsZVFMy/FE5lElwjgLHiTUDdytClP3yKHvyeJD3E1pw8Dm7QP\nxb9kCOCslRm3AgMBAAGjRTBDMB0GA1UdDgQWBBSyZwy7Z0SxqhbyXTilbcnJJNGP\nkTASBgNVHRMBAf8ECDAGAQH/AgEAMA4GA1UdDwEB/wQEAwIBBjANBgkqhkiG9w0B\nAQsFAAOCAQEA0zbSOS+9QgB3VcBkiRY5/ZGv+l+MCRoxeBm6rsj76dJyu5KYAEvW\nFg0zzg0xdgFMqcd1WBwVP4w1mqmvLXW0+C899F8GNsP089PfRg1qIzbLKP6P/CNv\nUowHzTqEnI0IDcD1RnuJj+Q4Ao04unFSllTO/OWu+wbfqiNKf/RHdiVs91KWS7XU\nFgG5s3A5p91N1JfDboWk/pQDHQihhjxgaOlfjWp8b0KxShMgnRdxTkqbS/APN/9f\nhcmq7hQrXVq2VUknRzrrlv2wBNn83aqFpw54Gnjor91EUbsB0gXWj6Ki/afvyAwi\ndt+OCdh9sbgEVsdwDYowscUHKcmGI3qoGg==\n-----END CERTIFICATE-----\n";
+// standard site certificate signed by CA
+#[cfg(test)]
+pub const TEST_CERT_OK: &str = "-----BEGIN CERTIFICATE-----\nMIIC4jCCAcqgAwIBAgIUToJSkbhbRwHjr/9Uu3FEoidwCWMwDQYJKoZIhvcNAQEL\nBQAwIDEeMBwGA1UEAwwVU2l0ZSAnaGV1dGUnIGxvY2FsIENBMCAXDTIyMDYxMzEw\nMTQyNVoYDzMwMjAxMDE0MTAxNDI1WjAQMQ4wDAYDVQQDDAVoZXV0ZTCCASIwDQYJ\nKoZIhvcNAQEBBQADggEPADCCAQoCggEBAL6Upc1udGIMPymBF8m+Z6PvQA/KPYF/\ne3g9gClSg73376XXdSqO9VkOR80lUM1mW2ZtK+ApF6/gDFaFmUPxThcq5PECgajd\nSug1sdC9GYfd6KQfAxseSR2x59UNYbG3Gt33+lurWHxCy61RL3sGW72CDoqqCGrK\n5hTbkOyNcaVrtXFSFE0N7cFFOK36MLuzopOFHNUeC1S/O0clUwA54kBAA37ARE5B\nfy2myp3A4YAMtD5dbva1WDJ1A9Hg+ivtjBxgrfTOdZF00/AB1vfzOZVktd14eBHZ\nXfmfEwndFmvYe7LsQ4/g9G5P4C1FXEqxcKyJg7EGDAtEZScbwU/6/PcCAwEAAaMi\nMCAwEAYDVR0RBAkwB4IFaGV1dGUwDAYDVR0TAQH/BAIwADANBgkqhkiG9w0BAQsF\nAAOCAQEAjvWil5wjCHz4dYj4Jbwn71/78J/1puX5Uzq2qVp7/UlVGLXeTYYgw9Ax\nH+cbO5Hf7gb7X1pwmjktMru7Utds4RAoQCvHLcJn1rQ0sQAgSN/Piq97ToQfD65+\nfsA5WAQBnlWRgiUhx5YR54La5mRrWbPOUnBddiEt/AOM5emNUEMNLYn0eGG/5cKi\nqC+ygO8KKmhVakFiXOtZjOf2w4DEl+rtEbIXmfGR3MD7oRoEWlfvYkz/mh5TstYr\nLupAH+jnrHlYGSw7tbR2X1LdSykBZgro7SPPSsWyqNxDCIckZbQ0ahYxNO1oCvs0\nPZWBjnJQjlaJVG1iIQBJS8UaZ+hJ5A==\n-----END CERTIFICATE-----\n";
+// certificate signed by CA where CN is a valid UUID
+#[cfg(test)]
+pub const TEST_CERT_CN_UUID: &str = "-----BEGIN CERTIFICATE-----\nMIIDIDCCAgigAwIBAgIUSXJpdJHE2CUaq0x0zO2EEEngX9cwDQYJKoZIhvcNAQEL\nBQAwIDEeMBwGA1UEAwwVU2l0ZSAnaGV1dGUnIGxvY2FsIENBMCAXDTIyMDYxMzE3\nMTA0NloYDzMwMjAxMDE0MTcxMDQ2WjAvMS0wKwYDVQQDDCRjZjc3MWVlYi1iNjY2\nLTQ2NzMtOTVjOS02ODM5NjBmYjI5MzkwggEiMA0GCSqGSIb3DQEBAQUAA4IBDwAw\nggEKAoIBAQCt9G35b5L3/K9AfaFLNGxS46yvbm2rd9FvzbOUospGsvHpN/cHiF8V\nV/fryGhtXWMzns0bB93gxEHd8qs8kvWuBg8FLYkn+H1XEiw7E7Ce80rrq61gaHDt\n3DCi6XID0a3gSQ7LQc2rWQ+Wpg0DjtyNFFXbUI0LV/YKWG6tfWXEFOy4tFUYbCW+\nz/kGshpjzYiK4aVCrYL54U4TAoUp8xvggOo4IXf04QABy7QWpmEDSRajhRtLkmcN\nx4HJ9/fKz7u6mymh4gI62kQWhxXtcVw+54dklp2Xt0ucbALp6T0XdaYFHWUTR1Yq\nSNLIcf79CRzf61vJjHQWVHoulXNpPNZVAgMBAAGjQTA/MC8GA1UdEQQoMCaCJGNm\nNzcxZWViLWI2NjYtNDY3My05NWM5LTY4Mzk2MGZiMjkzOTAMBgNVHRMBAf8EAjAA\nMA0GCSqGSIb3DQEBCwUAA4IBAQDo5JIsjXYAE11w9y1T7d+LzRj6HT7FYt1NLyHm\nMsZJh2y+gExd+k/E6Dlv494PW2/AX/prVG+UsBw+B0aDnrEm32BO3/ottwrdeL9b\nRmX1SQru89UgmfCsbgVpl66P7UGzltI/2vIyWzkcbcwMWP8UA1qAfPoMqnvGAMgu\n+bARCGaTWDT8uO6OCJm4JKMLXLk32kPL54Nd/Pp3lGrwWFOMFnjSbGtiAY2u9UeV\n+3uaganYjbLZkCQ0DP+DKDl6NBz3mrzI5wc+Fcpz8uDNV+UbtrGzreseBulJDLcl\ncr3aR+ZPgQPJDdVD56jXrdlU6hemc58NHJ+cPbw2ISaU9Rop\n-----END CERTIFICATE-----\n";
+// certificate signed by a different CA
+#[cfg(test)]
+pub const TEST_CERT_INVALID_SIGNATURE: &str = "-----BEGIN CERTIFICATE-----\nMIIC3zCCAcegAwIBAgIUdRy8we2If2wSVioU+nu1420ZD4UwDQYJKoZIhvcNAQEL\nBQAwHzEdMBsGA1UEAwwUU2l0ZSAndGVtcCcgbG9jYWwgQ0EwIBcNMjIwNjEzMTcx\nNzUyWhgPMzAyMDEwMTQxNzE3NTJaMA8xDTALBgNVBAMMBHRlbXAwggEiMA0GCSqG\nSIb3DQEBAQUAA4IBDwAwggEKAoIBAQDZMYl6ZTgmeonGMLizBNtAJNrH8O4rOvV3\nX8EvnWOmM26RCixGwy9MVteOlIptcZUa8QHo1EZ58T+1aS5G01LRtkx9OPK0bvPD\n4nnUGwIeUt12W5tHFH8DKpivl9oql/kJYzPTzGjsjeNXfPHGVUXVvq3pmL3U7d0o\nrC7s30fmRH0M0CArNykuJ9OO6kA52pplQmTbXRCceKTwtN7w0tWh7YYuw2343Rd/\nDQh8XvUduLWiMqkb0PC7otvgkQHFV6GylrE+FAxRvIY+2aqaUBziPHR+JU7Uxkpq\nYblNbOWRT3wjtxjBt0iYRrsMvhD8eD9T4sP51EZNk1S9jo2+PXDvAgMBAAGjITAf\nMA8GA1UdEQQIMAaCBHRlbXAwDAYDVR0TAQH/BAIwADANBgkqhkiG9w0BAQsFAAOC\nAQEASBTxFm2BQGQmmfwdouXT/nf071r5PCbr8Bj9AxKAzCiuJ/G8QBhwtTC01HmR\nL5vA1yi1Iiqm3wZTSetuNYQdi30HHDvJlHE3ADnKa1fo4vJFZTb4v+oKF8DUnaML\npmTkaldJuy3Ksl0tgeJlv6eM9/Rx47/XHMeD+0m72vKte13wBmMac18UNT/FNqM5\nM4H8Qvdxxbjfa/907ZPYnXg1baimqmNHvoELQzJNl8fza7fnDRRBn9XCSJoWiAcx\nu3ebRbIdPL3/BVQYHzBJV11zq4RN1IujYjRRTGWFr+zEVuuKmnbNK6hNAe8zwfLN\nJ7+UOWbsApe3/LIGhGJfGxtD+A==\n-----END CERTIFICATE-----\n";
@@ -0,0 +1,306 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Operation in containers, eg. as sidecar of a Kubernetes pod. Everything is configured via the
+//! environment and mounted files, the home dir only holds state, st. it can be an emptyDir.
+
+use crate::config::JSONLoader;
+use crate::constants::container as constants;
+use crate::modes::bootstrap;
+use crate::{cli, config, proxy, setup, site_spec, types};
+use anyhow::{Context, Result as AnyhowResult};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed)
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Paths below the home dir given by the environment. The config file in there is generated from
+/// the environment, st. all parts of the controller read the same settings.
+pub fn setup_paths() -> AnyhowResult<setup::PathResolver> {
+    let home_dir = env::var_os(constants::ENV_HOME_DIR)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(constants::HOME_DIR));
+    fs::create_dir_all(&home_dir)
+        .with_context(|| format!("Failed to create home dir {home_dir:?}"))?;
+    let paths = setup::PathResolver::new(&home_dir);
+    let config_file = env::var_os(constants::ENV_CONFIG_FILE).map(PathBuf::from);
+    let settings = settings(config_file.as_deref(), env::vars())?;
+    fs::write(&paths.config_path, toml::to_string(&settings)?)
+        .with_context(|| format!("Failed to write config to {:?}", paths.config_path))?;
+    Ok(paths)
+}
+
+/// Socket of the agent, if given by the environment
+pub fn agent_channel() -> Option<types::AgentChannel> {
+    enabled()
+        .then(|| env::var_os(constants::ENV_AGENT_SOCKET))
+        .flatten()
+        .map(|socket| PathBuf::from(socket).into())
+}
+
+/// Settings of the config file, if any, overridden by the env vars with the setting prefix. Their
+/// values are TOML values, plain strings need no quotes.
+fn settings(
+    config_file: Option<&Path>,
+    vars: impl Iterator<Item = (String, String)>,
+) -> AnyhowResult<toml::value::Table> {
+    let mut settings = match config_file {
+        Some(path) => toml::from_str(
+            &fs::read_to_string(path)
+                .with_context(|| format!("Failed to read config file {path:?}"))?,
+        )
+        .with_context(|| format!("Invalid config file {path:?}"))?,
+        None => toml::value::Table::new(),
+    };
+    for (name, value) in vars {
+        if let Some(setting) = name.strip_prefix(constants::ENV_SETTING_PREFIX) {
+            settings.insert(setting.to_lowercase(), setting_value(&value));
+        }
+    }
+    // Report invalid settings right away instead of when loading the generated config file
+    toml::Value::Table(settings.clone())
+        .try_into::<config::RuntimeConfig>()
+        .context("Invalid settings in the environment")?;
+    Ok(settings)
+}
+
+fn setting_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::value::Table>(&format!("value = {value}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(String::from(value)))
+}
+
+#[derive(Serialize, Deserialize)]
+struct IdentityState {
+    /// SHA-256 of the mounted identity
+    fingerprint: String,
+    site_id: site_spec::SiteID,
+}
+
+impl JSONLoader for IdentityState {}
+
+/// Keeps the registration in line with the mounted identity: Registers with its site and, once
+/// it changes, eg. because the Secret was updated, registers again.
+pub struct IdentityWatcher {
+    identity_path: PathBuf,
+    state_path: PathBuf,
+    registry_path: PathBuf,
+    runtime_config: config::RuntimeConfig,
+    client_config: config::ClientConfig,
+}
+
+impl IdentityWatcher {
+    /// None unless an identity is mounted
+    pub fn new(
+        paths: &setup::PathResolver,
+        runtime_config: &config::RuntimeConfig,
+        client_config: &config::ClientConfig,
+    ) -> Option<Self> {
+        Some(Self {
+            identity_path: PathBuf::from(env::var_os(constants::ENV_IDENTITY_FILE)?),
+            state_path: paths.home_dir.join(constants::IDENTITY_STATE_FILE),
+            registry_path: paths.registry_path.clone(),
+            runtime_config: runtime_config.clone(),
+            client_config: client_config.clone(),
+        })
+    }
+
+    pub fn sync(&self) -> AnyhowResult<()> {
+        let identity = fs::read_to_string(&self.identity_path)
+            .with_context(|| format!("Failed to read identity from {:?}", self.identity_path))?;
+        let fingerprint = fingerprint(&identity);
+        let mut registry = config::Registry::from_file(&self.registry_path)?;
+        registry.set_key_storage(config::KeyStorage::new(&self.runtime_config));
+        if let Some(previous) = self.previous_state() {
+            if previous.fingerprint != fingerprint
+                && registry
+                    .get_registered_site_ids()
+                    .any(|site_id| site_id == &previous.site_id)
+            {
+                info!(
+                    "Mounted identity changed, deleting connection to {}",
+                    previous.site_id
+                );
+                registry.delete_standard_connection(&previous.site_id)?;
+                registry.save()?;
+            }
+        }
+        let (site_id, registered) = bootstrap::register_from_user_data(
+            &identity,
+            self.runtime_config.clone(),
+            cli::ClientOpts {
+                detect_proxy: self.client_config.proxy_mode == proxy::ProxyMode::Environment,
+                auto_proxy: self.client_config.proxy_mode == proxy::ProxyMode::Auto,
+            },
+            cli::RegistrationClientOpts {
+                validate_api_cert: self.client_config.validate_api_cert,
+                // The setting "source_address" applies anyway
+                source_address: None,
+                receiver_protocol: cli::ReceiverProtocol::Rest,
+                transport: Default::default(),
+            },
+            &mut registry,
+        )
+        .context("Failed to register as given by the mounted identity")?;
+        if registered {
+            info!(
+                "Registered with {} as given by the mounted identity",
+                site_id
+            );
+        }
+        fs::write(
+            &self.state_path,
+            serde_json::to_string(&IdentityState {
+                fingerprint,
+                site_id,
+            })?,
+        )?;
+        Ok(())
+    }
+
+    fn previous_state(&self) -> Option<IdentityState> {
+        if !self.state_path.exists() {
+            return None;
+        }
+        IdentityState::load(&self.state_path)
+            .map_err(|err| {
+                warn!(
+                    "Ignoring invalid state of the mounted identity {:?}. ({})",
+                    self.state_path, err
+                )
+            })
+            .ok()
+    }
+
+    /// Check the mounted identity periodically. The daemon picks up changes of the registry itself.
+    pub fn watch(self) {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(constants::IDENTITY_CHECK_INTERVAL));
+            if let Err(err) = self.sync() {
+                warn!("{:#}", err);
+            }
+        });
+    }
+}
+
+fn fingerprint(identity: &str) -> String {
+    openssl::sha::sha256(identity.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod test_container {
+    use super::*;
+    use crate::config::test_helpers::TestRegistry;
+    use crate::happy_eyeballs;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("cmk-agent-ctl.toml");
+        fs::write(&config_file, "push_interval = 60\nlog_level = \"info\"\n").unwrap();
+        let merged = settings(
+            Some(&config_file),
+            [
+                ("CMK_AGENT_CTL_SETTING_PUSH_INTERVAL", "30"),
+                ("CMK_AGENT_CTL_SETTING_DETECT_PROXY", "true"),
+                ("CMK_AGENT_CTL_SETTING_METRICS_BIND_ADDRESS", "0.0.0.0"),
+                ("CMK_AGENT_CTL_SETTING_ALLOWED_IP", "[\"10.0.0.0/8\"]"),
+                ("CMK_AGENT_CTL_HOME", "/data"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (String::from(name), String::from(value))),
+        )
+        .unwrap();
+        assert_eq!(
+            toml::to_string(&merged).unwrap(),
+            "allowed_ip = [\"10.0.0.0/8\"]\n\
+             detect_proxy = true\n\
+             log_level = \"info\"\n\
+             metrics_bind_address = \"0.0.0.0\"\n\
+             push_interval = 30\n"
+        );
+        assert!(settings(
+            None,
+            std::iter::once((
+                String::from("CMK_AGENT_CTL_SETTING_PUSH_INTERVAL"),
+                String::from("often")
+            ))
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_sync_changed_identity() {
+        let test_registry = TestRegistry::new().add_connection(
+            &config::ConnectionMode::Push,
+            "server/mysite",
+            config::TrustedConnectionWithRemote::from("0096abd7-83c9-42f8-8b3a-3ffba7ba959d"),
+        );
+        test_registry.registry.save().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let watcher = IdentityWatcher {
+            identity_path: dir.path().join("identity.yml"),
+            state_path: dir.path().join(constants::IDENTITY_STATE_FILE),
+            registry_path: PathBuf::from(test_registry.registry.path()),
+            runtime_config: config::RuntimeConfig::default(),
+            client_config: config::ClientConfig {
+                proxy_mode: proxy::ProxyMode::Direct,
+                validate_api_cert: false,
+                source_address: None,
+                ip_preference: happy_eyeballs::IpPreference::Ipv6,
+                request_headers: Default::default(),
+                timeouts: Default::default(),
+            },
+        };
+        let identity =
+            "cmk_agent_ctl: {server: \"server:8000\", site: mysite, user: u, password: p}";
+        fs::write(&watcher.identity_path, identity).unwrap();
+        // Already registered, nothing to do
+        watcher.sync().unwrap();
+        let state = watcher.previous_state().unwrap();
+        assert_eq!(state.fingerprint, fingerprint(identity));
+        assert_eq!(
+            state.site_id,
+            site_spec::SiteID::from_str("server/mysite").unwrap()
+        );
+
+        // Nobody listens on port 1
+        fs::write(
+            &watcher.identity_path,
+            "cmk_agent_ctl: {server: \"localhost:1\", site: mysite, user: u, password: p}",
+        )
+        .unwrap();
+        assert!(watcher.sync().is_err());
+        assert_eq!(
+            config::Registry::from_file(test_registry.registry.path())
+                .unwrap()
+                .get_registered_site_ids()
+                .count(),
+            0
+        );
+        // Registering is retried
+        assert_eq!(
+            watcher.previous_state().unwrap().fingerprint,
+            fingerprint(identity)
+        );
+    }
+}
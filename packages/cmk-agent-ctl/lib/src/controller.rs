@@ -0,0 +1,259 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! The controller as library, for programs which manage the connections of a host themselves,
+//! eg. installers or vendor integrations. Nothing is prompted and nothing is printed, everything
+//! the CLI would ask for has to be given upfront.
+//!
+//! ```no_run
+//! use cmk_agent_ctl_lib::controller::{Controller, Host, Registration};
+//!
+//! let mut controller = Controller::open("/var/lib/cmk-agent")?;
+//! controller.register(
+//!     &Registration {
+//!         server: String::from("checkmk.example.com"),
+//!         site: String::from("mysite"),
+//!         port: None,
+//!         user: String::from("agent_registration"),
+//!         password: String::from("secret"),
+//!         root_certificate: None,
+//!         trust_server_cert: true,
+//!         detect_proxy: false,
+//!     },
+//!     &Host::Existing {
+//!         host_name: String::from("my-host"),
+//!     },
+//! )?;
+//! println!("{}", controller.status_json(true)?);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use crate::configuration::{config, migrate};
+use crate::modes::{delete_connection, registration, renew_certificate, status};
+use crate::{cli, setup, site_spec, types};
+use anyhow::{Context, Result as AnyhowResult};
+use config::TOMLLoaderMissingSafe;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Where and as whom to register
+pub struct Registration {
+    pub server: String,
+    pub site: String,
+    /// The port of the agent receiver, queried from the site if not given
+    pub port: Option<u16>,
+    pub user: String,
+    pub password: String,
+    /// Verify the server certificate with this root certificate (PEM)
+    pub root_certificate: Option<String>,
+    /// Trust the server certificate without verifying it
+    pub trust_server_cert: bool,
+    /// Use the proxy settings configured on this system
+    pub detect_proxy: bool,
+}
+
+pub enum Host {
+    /// Register for a host which already exists in the site
+    Existing { host_name: String },
+    /// Let the site create the host, blocks until the registration is accepted or declined
+    New { agent_labels: types::AgentLabels },
+}
+
+/// The registered connections of one controller home directory
+pub struct Controller {
+    paths: setup::PathResolver,
+    runtime_config: config::RuntimeConfig,
+    registry: config::Registry,
+}
+
+impl Controller {
+    /// Load configuration and registered connections as the CLI does, home_dir is the home
+    /// directory of the controller, eg. /var/lib/cmk-agent
+    pub fn open(home_dir: impl AsRef<Path>) -> AnyhowResult<Self> {
+        let paths = setup::PathResolver::new(home_dir.as_ref());
+        migrate::migrate_registered_connections(&paths.registry_path)?;
+        let runtime_config = config::RuntimeConfig::load_missing_safe(&paths.config_path)?;
+        let mut registry =
+            config::Registry::from_file(&paths.registry_path).with_context(|| {
+                format!(
+                    "Error while loading registered connections from {:?}.",
+                    &paths.registry_path
+                )
+            })?;
+        registry.set_key_storage(config::KeyStorage::new(&runtime_config));
+        Ok(Self {
+            paths,
+            runtime_config,
+            registry,
+        })
+    }
+
+    pub fn registry(&self) -> &config::Registry {
+        &self.registry
+    }
+
+    /// Register at a site and save the new connection
+    pub fn register(&mut self, registration: &Registration, host: &Host) -> AnyhowResult<()> {
+        let connection_config = self.connection_config(registration)?;
+        match host {
+            Host::Existing { host_name } => registration::register_existing_unattended(
+                &config::RegisterExistingConfig {
+                    connection_config,
+                    host_name: host_name.clone(),
+                    host_creation: None,
+                    keep_credentials: false,
+                },
+                &mut self.registry,
+            ),
+            Host::New { agent_labels } => registration::register_new_unattended(
+                &config::RegisterNewConfig::new(connection_config, agent_labels.clone())?,
+                &mut self.registry,
+            ),
+        }
+    }
+
+    /// Delete the connection with the given site ID (server/site) or UUID
+    pub fn delete(&mut self, connection: &str) -> AnyhowResult<()> {
+        delete_connection::delete(&mut self.registry, connection)?;
+        Ok(())
+    }
+
+    pub fn delete_all(&mut self) -> AnyhowResult<()> {
+        delete_connection::delete_all(&mut self.registry, false)?;
+        Ok(())
+    }
+
+    /// Renew the certificate of the connection with the given site ID (server/site) or UUID
+    pub fn renew_certificate(&mut self, connection: &str) -> AnyhowResult<()> {
+        let client_config = self.client_config();
+        renew_certificate::renew_certificate(&mut self.registry, connection, client_config)
+    }
+
+    /// The status of all connections as reported by the status mode with --json
+    pub fn status_json(&mut self, query_remote: bool) -> AnyhowResult<String> {
+        let pull_config = config::PullConfig::new(
+            self.runtime_config.clone(),
+            cli::PullOpts {
+                port: None,
+                #[cfg(windows)]
+                agent_channel: None,
+            },
+            self.registry.clone(),
+        )?;
+        let client_config = self.client_config();
+        let (output, _) = status::report(
+            &mut self.registry,
+            &pull_config,
+            &client_config,
+            &status::StatusOptions {
+                output: cli::OutputFormat::Json,
+                query_remote,
+                connection: None,
+                tags: &[],
+                live: false,
+                watch: None,
+                max_age: None,
+                query_timeout: None,
+            },
+            &self.paths,
+        )?;
+        Ok(output)
+    }
+
+    fn client_config(&self) -> config::ClientConfig {
+        config::ClientConfig::new(
+            self.runtime_config.clone(),
+            cli::ClientOpts {
+                detect_proxy: false,
+                auto_proxy: false,
+            },
+            None,
+        )
+    }
+
+    fn connection_config(
+        &self,
+        registration: &Registration,
+    ) -> AnyhowResult<config::RegistrationConnectionConfig> {
+        let site_id = site_spec::SiteID {
+            server: registration.server.clone(),
+            site: registration.site.clone(),
+        };
+        let client_config = config::ClientConfig::new(
+            self.runtime_config.clone(),
+            cli::ClientOpts {
+                detect_proxy: registration.detect_proxy,
+                auto_proxy: false,
+            },
+            None,
+        );
+        let receiver_port = match registration.port {
+            Some(port) => port,
+            None => site_spec::discover_receiver_port(&site_id, &client_config)?,
+        };
+        Ok(config::RegistrationConnectionConfig {
+            site_id,
+            receiver_port,
+            username: registration.user.clone(),
+            password: Some(registration.password.clone()),
+            root_certificate: registration.root_certificate.clone(),
+            trust_server_cert: registration.trust_server_cert,
+            // Nothing is prompted in unattended registrations
+            prompt_format: cli::PromptFormat::Text,
+            client_config,
+            source_address: None,
+            receiver_protocol: cli::ReceiverProtocol::Rest,
+            transport: Default::default(),
+            tags: BTreeMap::new(),
+            expires_in: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_controller {
+    use super::*;
+
+    fn registration(trust_server_cert: bool) -> Registration {
+        Registration {
+            server: String::from("server"),
+            site: String::from("site"),
+            port: Some(8000),
+            user: String::from("user"),
+            password: String::from("password"),
+            root_certificate: None,
+            trust_server_cert,
+            detect_proxy: false,
+        }
+    }
+
+    #[test]
+    fn test_empty_home_dir() {
+        let home_dir = tempfile::tempdir().unwrap();
+        let mut controller = Controller::open(home_dir.path()).unwrap();
+        assert!(controller.registry().is_empty());
+        let status: serde_json::Value =
+            serde_json::from_str(&controller.status_json(false).unwrap()).unwrap();
+        assert!(status["connections"].as_array().unwrap().is_empty());
+        assert!(controller.delete("server/site").is_err());
+        assert!(controller.renew_certificate("server/site").is_err());
+        controller.delete_all().unwrap();
+    }
+
+    #[test]
+    fn test_register_requires_trust() {
+        let home_dir = tempfile::tempdir().unwrap();
+        let mut controller = Controller::open(home_dir.path()).unwrap();
+        let err = controller
+            .register(
+                &registration(false),
+                &Host::Existing {
+                    host_name: String::from("host"),
+                },
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("Provide the root certificate"));
+        assert!(controller.registry().is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Local source of the agent output served to a connection, st. one controller can provide
+//! different payloads to different sites, eg. a reduced set of sections to the site of an external
+//! SOC. Connections without a data source of their own get the output of the agent socket. The
+//! post-processing applies to all sources alike.
+
+use crate::{config, constants, monitoring_data, post_processing, types};
+use anyhow::{Context, Result as AnyhowResult};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const FALLBACK_SECTION_HEADER: &[u8] = b"<<<cmk_agent_ctl_fallback:sep(0)>>>\n";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DataSource {
+    /// Another agent socket, or agent channel under Windows (eg. "ms/..." or "localhost:6556")
+    Socket { socket: String },
+    /// Output of a command, given as program and arguments
+    Command { command: Vec<String> },
+    /// Contents of a static file
+    File { path: PathBuf },
+}
+
+impl Display for DataSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Socket { socket } => write!(f, "socket {socket}"),
+            Self::Command { command } => write!(f, "command '{}'", command.join(" ")),
+            Self::File { path } => write!(f, "file {}", path.display()),
+        }
+    }
+}
+
+/// Collect the agent output from the given source, from the agent channel if none is given. If
+/// the agent channel or socket is unreachable, the fallback command is run instead, if any.
+pub fn collect(
+    source: Option<&DataSource>,
+    agent_channel: &types::AgentChannel,
+    fallback: Option<&config::FallbackCommandConfig>,
+) -> AnyhowResult<Vec<u8>> {
+    Ok(match source {
+        None => with_fallback(
+            monitoring_data::collect(agent_channel).map_err(anyhow::Error::from),
+            fallback,
+        )?,
+        Some(DataSource::Socket { socket }) => with_fallback(
+            monitoring_data::collect(&types::AgentChannel::from(socket.as_str()))
+                .context(format!("Failed to collect from socket {socket}")),
+            fallback,
+        )?,
+        Some(DataSource::Command { command }) => post_processing::run_command(
+            command,
+            vec![],
+            Duration::from_secs(constants::DATA_SOURCE_COMMAND_TIMEOUT),
+            None,
+        )?,
+        Some(DataSource::File { path }) => {
+            std::fs::read(path).context(format!("Failed to read {}", path.display()))?
+        }
+    })
+}
+
+/// Like collect, passing on the IP address of the requesting site to agent sockets
+pub async fn async_collect(
+    source: Option<&DataSource>,
+    agent_channel: &types::AgentChannel,
+    remote_ip: std::net::IpAddr,
+    fallback: Option<&config::FallbackCommandConfig>,
+) -> AnyhowResult<Vec<u8>> {
+    let collected = match source {
+        None => monitoring_data::async_collect(agent_channel, remote_ip)
+            .await
+            .map_err(anyhow::Error::from),
+        Some(DataSource::Socket { socket }) => {
+            monitoring_data::async_collect(&types::AgentChannel::from(socket.as_str()), remote_ip)
+                .await
+                .context(format!("Failed to collect from socket {socket}"))
+        }
+        // Commands may take a while
+        Some(source) => {
+            let (source, agent_channel) = (source.clone(), agent_channel.clone());
+            return tokio::task::spawn_blocking(move || {
+                collect(Some(&source), &agent_channel, None)
+            })
+            .await?;
+        }
+    };
+    match (collected, fallback) {
+        (Err(error), Some(fallback)) => {
+            let fallback = fallback.clone();
+            Ok(tokio::task::spawn_blocking(move || run_fallback(&fallback, &error)).await?)
+        }
+        (collected, _) => collected,
+    }
+}
+
+fn with_fallback(
+    collected: AnyhowResult<Vec<u8>>,
+    fallback: Option<&config::FallbackCommandConfig>,
+) -> AnyhowResult<Vec<u8>> {
+    match (collected, fallback) {
+        (Err(error), Some(fallback)) => Ok(run_fallback(fallback, &error)),
+        (collected, _) => collected,
+    }
+}
+
+/// The output of the fallback command and a section describing why the agent was unreachable, or
+/// only this section if the fallback command failed as well
+fn run_fallback(fallback: &config::FallbackCommandConfig, error: &anyhow::Error) -> Vec<u8> {
+    warn!(
+        "Agent is unreachable, running fallback command instead. ({:#})",
+        error
+    );
+    let mut lines = vec![format!("Agent unreachable: {error:#}")];
+    let mut output = post_processing::run_command(
+        &fallback.command,
+        vec![],
+        Duration::from_secs(fallback.timeout),
+        Some(fallback.max_size),
+    )
+    .unwrap_or_else(|command_error| {
+        warn!("Fallback command failed. ({:#})", command_error);
+        lines.push(format!("Fallback command failed: {command_error:#}"));
+        vec![]
+    });
+    if !output.is_empty() && !output.ends_with(b"\n") {
+        output.push(b'\n');
+    }
+    output.extend_from_slice(FALLBACK_SECTION_HEADER);
+    for line in lines {
+        // Keep the section intact, whatever the errors say
+        output.extend_from_slice(line.replace('\n', " ").as_bytes());
+        output.push(b'\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod test_data_source {
+    use super::*;
+
+    #[test]
+    fn test_deserialize() {
+        assert_eq!(
+            serde_json::from_str::<DataSource>(
+                r#"{"type": "command", "command": ["/usr/local/bin/soc-agent", "--reduced"]}"#
+            )
+            .unwrap(),
+            DataSource::Command {
+                command: vec![
+                    String::from("/usr/local/bin/soc-agent"),
+                    String::from("--reduced")
+                ]
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<DataSource>(r#"{"type": "file", "path": "/tmp/agent.txt"}"#)
+                .unwrap(),
+            DataSource::File {
+                path: PathBuf::from("/tmp/agent.txt")
+            }
+        );
+    }
+
+    #[test]
+    fn test_collect_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.txt");
+        std::fs::write(&path, "<<<check_mk>>>\nVersion: 2.3.0\n").unwrap();
+        assert_eq!(
+            collect(
+                Some(&DataSource::File { path }),
+                &types::AgentChannel::from("/missing.socket"),
+                None
+            )
+            .unwrap(),
+            b"<<<check_mk>>>\nVersion: 2.3.0\n"
+        );
+        assert!(collect(
+            Some(&DataSource::File {
+                path: dir.path().join("missing.txt")
+            }),
+            &types::AgentChannel::from("/missing.socket"),
+            None
+        )
+        .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_collect_command() {
+        assert_eq!(
+            collect(
+                Some(&DataSource::Command {
+                    command: vec![String::from("echo"), String::from("<<<check_mk>>>")]
+                }),
+                &types::AgentChannel::from("/missing.socket"),
+                None
+            )
+            .unwrap(),
+            b"<<<check_mk>>>\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_collect_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let agent_channel = types::AgentChannel::from(dir.path().join("missing.socket"));
+        let fallback = |command: &[&str]| config::FallbackCommandConfig {
+            command: command.iter().map(|arg| String::from(*arg)).collect(),
+            timeout: 1,
+            max_size: 1024,
+        };
+        assert!(collect(None, &agent_channel, None).is_err());
+        let output = String::from_utf8(
+            collect(
+                None,
+                &agent_channel,
+                Some(&fallback(&["echo", "<<<systemd_units>>>"])),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(output.starts_with(
+            "<<<systemd_units>>>\n<<<cmk_agent_ctl_fallback:sep(0)>>>\nAgent unreachable: "
+        ));
+        let output =
+            String::from_utf8(collect(None, &agent_channel, Some(&fallback(&["false"]))).unwrap())
+                .unwrap();
+        assert!(output.starts_with("<<<cmk_agent_ctl_fallback:sep(0)>>>\nAgent unreachable: "));
+        assert!(output.contains("\nFallback command failed: "));
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Delta encoding of the agent output against the previously pushed one, in the manner of rsync.
+//! The previous output is split into blocks, which are found again in the new output by means of
+//! a rolling checksum. Only the data in between is sent literally.
+//!
+//! Encoded delta (all integers big-endian u32):
+//! * SHA-256 of the previous output (32 bytes), the receiver must hold exactly this output
+//! * block size
+//! * operations, each either `0x00 <first block> <number of blocks>`, copying blocks of the
+//!   previous output, or `0x01 <length> <data>`, inserting data literally
+
+use openssl::sha::sha256;
+use std::collections::HashMap;
+
+const OP_COPY: u8 = 0;
+const OP_LITERAL: u8 = 1;
+
+/// Adler-like checksum of a window, which can be moved forward by one byte in constant time
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, byte) in window.iter().enumerate() {
+            a = a.wrapping_add(u32::from(*byte));
+            b = b.wrapping_add((len - i as u32).wrapping_mul(u32::from(*byte)));
+        }
+        Self { a, b, len }
+    }
+
+    fn roll(&mut self, outgoing: u8, incoming: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(u32::from(outgoing))
+            .wrapping_add(u32::from(incoming));
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(u32::from(outgoing)))
+            .wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Block checksums of a pushed agent output, all it takes to encode the next output against it
+pub struct Signature {
+    block_size: usize,
+    digest: [u8; 32],
+    strong: Vec<[u8; 32]>,
+    weak: HashMap<u32, Vec<u32>>,
+}
+
+impl Signature {
+    pub fn new(data: &[u8], block_size: usize) -> Self {
+        let mut weak: HashMap<u32, Vec<u32>> = HashMap::new();
+        let mut strong = vec![];
+        for (index, block) in data.chunks_exact(block_size).enumerate() {
+            weak.entry(RollingChecksum::new(block).value())
+                .or_default()
+                .push(index as u32);
+            strong.push(sha256(block));
+        }
+        Self {
+            block_size,
+            digest: sha256(data),
+            strong,
+            weak,
+        }
+    }
+
+    /// Block of the signature equal to the window, the expected one if there are several
+    fn find(&self, checksum: u32, window: &[u8], expected: u32) -> Option<u32> {
+        let candidates = self.weak.get(&checksum)?;
+        let window_strong = sha256(window);
+        let mut matches = candidates
+            .iter()
+            .filter(|index| self.strong[**index as usize] == window_strong);
+        let first = *matches.next()?;
+        if first == expected || matches.any(|index| *index == expected) {
+            return Some(expected);
+        }
+        Some(first)
+    }
+}
+
+struct Encoder {
+    encoded: Vec<u8>,
+    /// Blocks copied by the last operation, extended as long as the following blocks match
+    copy: Option<(u32, u32)>,
+}
+
+impl Encoder {
+    fn new(base: &Signature) -> Self {
+        let mut encoded = base.digest.to_vec();
+        encoded.extend((base.block_size as u32).to_be_bytes());
+        Self {
+            encoded,
+            copy: None,
+        }
+    }
+
+    fn flush_copy(&mut self) {
+        if let Some((first, count)) = self.copy.take() {
+            self.encoded.push(OP_COPY);
+            self.encoded.extend(first.to_be_bytes());
+            self.encoded.extend(count.to_be_bytes());
+        }
+    }
+
+    fn copy(&mut self, block: u32) {
+        match &mut self.copy {
+            Some((first, count)) if *first + *count == block => *count += 1,
+            _ => {
+                self.flush_copy();
+                self.copy = Some((block, 1));
+            }
+        }
+    }
+
+    fn literal(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.flush_copy();
+        self.encoded.push(OP_LITERAL);
+        self.encoded.extend((data.len() as u32).to_be_bytes());
+        self.encoded.extend(data);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.flush_copy();
+        self.encoded
+    }
+
+    fn next_block(&self) -> u32 {
+        self.copy.map_or(0, |(first, count)| first + count)
+    }
+}
+
+/// Encode the data as delta against the output the signature was taken of
+pub fn encode(base: &Signature, data: &[u8]) -> Vec<u8> {
+    let block_size = base.block_size;
+    let mut encoder = Encoder::new(base);
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut checksum =
+        (data.len() >= block_size).then(|| RollingChecksum::new(&data[..block_size]));
+    while let Some(current) = &mut checksum {
+        let window = &data[pos..pos + block_size];
+        if let Some(block) = base.find(current.value(), window, encoder.next_block()) {
+            encoder.literal(&data[literal_start..pos]);
+            encoder.copy(block);
+            pos += block_size;
+            literal_start = pos;
+            checksum = (pos + block_size <= data.len())
+                .then(|| RollingChecksum::new(&data[pos..pos + block_size]));
+        } else if pos + block_size < data.len() {
+            current.roll(data[pos], data[pos + block_size]);
+            pos += 1;
+        } else {
+            checksum = None;
+        }
+    }
+    encoder.literal(&data[literal_start..]);
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod test_delta {
+    use super::*;
+    use anyhow::{bail, Context, Result as AnyhowResult};
+
+    /// What the receiver does with an encoded delta
+    fn apply(base: &[u8], delta: &[u8]) -> AnyhowResult<Vec<u8>> {
+        fn read_u32(delta: &[u8], pos: &mut usize) -> AnyhowResult<usize> {
+            let bytes = delta.get(*pos..*pos + 4).context("Truncated delta")?;
+            *pos += 4;
+            Ok(u32::from_be_bytes(bytes.try_into()?) as usize)
+        }
+        if delta.get(..32) != Some(sha256(base).as_slice()) {
+            bail!("Delta does not refer to this base");
+        }
+        let mut pos = 32;
+        let block_size = read_u32(delta, &mut pos)?;
+        let mut data = vec![];
+        while pos < delta.len() {
+            let op = delta[pos];
+            pos += 1;
+            match op {
+                OP_COPY => {
+                    let first = read_u32(delta, &mut pos)?;
+                    let count = read_u32(delta, &mut pos)?;
+                    data.extend(
+                        base.get(first * block_size..(first + count) * block_size)
+                            .context("Block out of range")?,
+                    );
+                }
+                OP_LITERAL => {
+                    let len = read_u32(delta, &mut pos)?;
+                    data.extend(delta.get(pos..pos + len).context("Truncated delta")?);
+                    pos += len;
+                }
+                _ => bail!("Unknown operation {}", op),
+            }
+        }
+        Ok(data)
+    }
+
+    fn agent_output(uptime: u64) -> Vec<u8> {
+        let mut output = format!("<<<check_mk>>>\nVersion: 2.3.0\n<<<uptime>>>\n{uptime}\n");
+        output.push_str("<<<df>>>\n");
+        for i in 0..200 {
+            output.push_str(&format!("/dev/sda{i} ext4 1000 {i} 42% /mnt/{i}\n"));
+        }
+        output.into_bytes()
+    }
+
+    #[test]
+    fn test_rolling_checksum() {
+        let data = b"the quick brown fox jumps over the lazy dog";
+        let mut checksum = RollingChecksum::new(&data[..16]);
+        for pos in 0..data.len() - 16 {
+            assert_eq!(
+                checksum.value(),
+                RollingChecksum::new(&data[pos..pos + 16]).value()
+            );
+            checksum.roll(data[pos], data[pos + 16]);
+        }
+    }
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let base = agent_output(1234);
+        let data = agent_output(98765);
+        let delta = encode(&Signature::new(&base, 64), &data);
+        assert_eq!(apply(&base, &delta).unwrap(), data);
+        assert!(delta.len() < data.len() / 10);
+    }
+
+    #[test]
+    fn test_delta_unchanged() {
+        let base = agent_output(1234);
+        let delta = encode(&Signature::new(&base, 64), &base);
+        assert_eq!(apply(&base, &delta).unwrap(), base);
+        // Digest, block size, a single copy of all full blocks and the remainder
+        assert!(delta.len() <= 32 + 4 + 9 + 5 + 64);
+    }
+
+    #[test]
+    fn test_delta_unrelated() {
+        let base = agent_output(1234);
+        let data = b"<<<check_mk>>>\nsomething else entirely\n".to_vec();
+        let delta = encode(&Signature::new(&base, 64), &data);
+        assert_eq!(apply(&base, &delta).unwrap(), data);
+        assert!(encode(&Signature::new(b"", 64), &data).len() > data.len());
+        assert!(apply(&data, &delta).is_err());
+    }
+}
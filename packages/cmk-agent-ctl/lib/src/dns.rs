@@ -0,0 +1,536 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Resolution of the names of the receivers. The system resolver is used, unless other name
+//! servers are configured for split-DNS environments, which are then queried directly for A and
+//! AAAA records. Single hosts can be pinned to addresses like with curl --resolve, eg. to test a
+//! site before the DNS cutover. Resolved addresses are cached up to a configured time, bounded by
+//! the TTLs of the name servers, if queried directly.
+
+use crate::constants;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DnsConfig {
+    /// Name servers queried instead of the system resolver, in this order
+    #[serde(default)]
+    pub servers: Vec<NameServer>,
+    /// Upper limit (in seconds) for caching resolved addresses, nothing is cached by default
+    #[serde(default)]
+    pub cache_ttl: u64,
+    /// Addresses used instead of resolving the host, for connections to the given port
+    #[serde(default)]
+    pub resolve: Vec<ResolveOverride>,
+}
+
+impl DnsConfig {
+    /// Overrides given on the command line replace the configured ones for the same host and port
+    pub fn with_overrides(mut self, overrides: &[ResolveOverride]) -> Self {
+        self.resolve.retain(|configured| {
+            !overrides
+                .iter()
+                .any(|given| given.matches(&configured.host, configured.port))
+        });
+        self.resolve.extend_from_slice(overrides);
+        self
+    }
+}
+
+/// Address of a name server, port 53 unless given
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct NameServer(SocketAddr);
+
+impl FromStr for NameServer {
+    type Err = String;
+
+    fn from_str(server: &str) -> Result<Self, Self::Err> {
+        server
+            .parse::<SocketAddr>()
+            .or_else(|_| {
+                server
+                    .parse::<IpAddr>()
+                    .map(|address| SocketAddr::new(address, constants::DNS_PORT))
+            })
+            .map(NameServer)
+            .map_err(|_| format!("Invalid name server '{server}'"))
+    }
+}
+
+impl TryFrom<String> for NameServer {
+    type Error = String;
+
+    fn try_from(server: String) -> Result<Self, Self::Error> {
+        server.parse()
+    }
+}
+
+/// HOST:PORT:ADDRESS, IPv6 addresses may be enclosed in brackets
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct ResolveOverride {
+    host: String,
+    port: u16,
+    address: IpAddr,
+}
+
+impl ResolveOverride {
+    fn matches(&self, host: &str, port: u16) -> bool {
+        self.port == port && self.host.eq_ignore_ascii_case(host)
+    }
+}
+
+impl FromStr for ResolveOverride {
+    type Err = String;
+
+    fn from_str(resolve: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid override '{resolve}', expected HOST:PORT:ADDRESS");
+        let mut parts = resolve.splitn(3, ':');
+        let (Some(host), Some(port), Some(address)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            host: String::from(host),
+            port: port.parse().map_err(|_| invalid())?,
+            address: address
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl TryFrom<String> for ResolveOverride {
+    type Error = String;
+
+    fn try_from(resolve: String) -> Result<Self, Self::Error> {
+        resolve.parse()
+    }
+}
+
+struct Cached {
+    addresses: Vec<SocketAddr>,
+    expires: Instant,
+}
+
+struct Resolver {
+    config: DnsConfig,
+    cache: Mutex<HashMap<(String, u16), Cached>>,
+}
+
+static RESOLVER: OnceLock<Resolver> = OnceLock::new();
+
+/// Set once at startup, names are resolved by the system resolver until then
+pub fn init(config: DnsConfig) {
+    let _ = RESOLVER.set(Resolver::new(config));
+}
+
+/// Addresses of the host, in the order of the resolver. Blocks while querying.
+pub fn lookup(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    match RESOLVER.get() {
+        Some(resolver) => resolver.lookup(host, port),
+        None => system_lookup(host, port),
+    }
+}
+
+fn system_lookup(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    Ok((host, port).to_socket_addrs()?.collect())
+}
+
+impl Resolver {
+    fn new(config: DnsConfig) -> Self {
+        Self {
+            config,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(address) = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+        {
+            return Ok(vec![SocketAddr::new(address, port)]);
+        }
+        let overridden: Vec<SocketAddr> = self
+            .config
+            .resolve
+            .iter()
+            .filter(|resolve| resolve.matches(host, port))
+            .map(|resolve| SocketAddr::new(resolve.address, port))
+            .collect();
+        if !overridden.is_empty() {
+            return Ok(overridden);
+        }
+
+        let key = (host.to_ascii_lowercase(), port);
+        let mut cache = match self.cache.lock() {
+            Ok(cache) => cache,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let now = Instant::now();
+        cache.retain(|_, cached| cached.expires > now);
+        if let Some(cached) = cache.get(&key) {
+            return Ok(cached.addresses.clone());
+        }
+        // Not holding the lock while resolving
+        drop(cache);
+        let (addresses, ttl) = match self.config.servers.is_empty() {
+            true => (system_lookup(host, port)?, self.config.cache_ttl),
+            false => {
+                let (addresses, ttl) = query_servers(&self.config.servers, host)?;
+                (
+                    addresses
+                        .into_iter()
+                        .map(|address| SocketAddr::new(address, port))
+                        .collect(),
+                    self.config.cache_ttl.min(ttl.into()),
+                )
+            }
+        };
+        if ttl > 0 {
+            let mut cache = match self.cache.lock() {
+                Ok(cache) => cache,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            cache.insert(
+                key,
+                Cached {
+                    addresses: addresses.clone(),
+                    expires: now + Duration::from_secs(ttl),
+                },
+            );
+        }
+        Ok(addresses)
+    }
+}
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// Addresses of the host according to the first name server answering, and the lowest TTL of
+/// them
+fn query_servers(servers: &[NameServer], host: &str) -> io::Result<(Vec<IpAddr>, u32)> {
+    let mut errors = vec![];
+    for NameServer(server) in servers {
+        match query_server(*server, host) {
+            Ok(answer) => return Ok(answer),
+            // The other servers would not know either
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(err),
+            Err(err) => errors.push(format!("{server}: {err}")),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("No name server answered for {host} ({})", errors.join(", ")),
+    ))
+}
+
+fn query_server(server: SocketAddr, host: &str) -> io::Result<(Vec<IpAddr>, u32)> {
+    let socket = UdpSocket::bind(match server {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    })?;
+    socket.set_read_timeout(Some(Duration::from_secs(constants::DNS_QUERY_TIMEOUT)))?;
+    socket.connect(server)?;
+    let mut addresses = vec![];
+    let mut ttl = u32::MAX;
+    for record_type in [TYPE_AAAA, TYPE_A] {
+        let id: u16 = rand::random();
+        socket.send(&encode_query(id, host, record_type)?)?;
+        let mut buffer = [0; 512];
+        let answer = loop {
+            let len = socket.recv(&mut buffer)?;
+            // Late answers to earlier queries
+            if let Some(answer) = parse_response(id, &buffer[..len])? {
+                break answer;
+            }
+        };
+        for (address, record_ttl) in answer {
+            addresses.push(address);
+            ttl = ttl.min(record_ttl);
+        }
+    }
+    if addresses.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{host} has no addresses"),
+        ));
+    }
+    Ok((addresses, ttl))
+}
+
+fn encode_query(id: u16, host: &str, record_type: u16) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(512);
+    // Recursion desired, one question
+    for field in [id, 0x0100, 1, 0, 0, 0] {
+        query.extend_from_slice(&field.to_be_bytes());
+    }
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid host name {host}"),
+            ));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Malformed DNS response")
+}
+
+fn read_u16(response: &[u8], offset: usize) -> io::Result<u16> {
+    response
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(malformed)
+}
+
+/// Offset after the (possibly compressed) name at the given offset
+fn skip_name(response: &[u8], mut offset: usize) -> io::Result<usize> {
+    loop {
+        let len = *response.get(offset).ok_or_else(malformed)? as usize;
+        match len {
+            0 => return Ok(offset + 1),
+            // Pointer to a name elsewhere
+            len if len & 0xc0 == 0xc0 => return Ok(offset + 2),
+            len => offset += len + 1,
+        }
+    }
+}
+
+/// The addresses and their TTLs, None if the response belongs to another query. CNAMEs are
+/// skipped, recursive name servers answer with the addresses of their targets as well.
+fn parse_response(id: u16, response: &[u8]) -> io::Result<Option<Vec<(IpAddr, u32)>>> {
+    let flags = read_u16(response, 2)?;
+    if read_u16(response, 0)? != id || flags & 0x8000 == 0 {
+        return Ok(None);
+    }
+    match flags & 0x000f {
+        0 => {}
+        RCODE_NXDOMAIN => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Name does not exist",
+            ))
+        }
+        rcode => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Name server failed with response code {rcode}"),
+            ))
+        }
+    }
+    let mut offset = 12;
+    for _ in 0..read_u16(response, 4)? {
+        offset = skip_name(response, offset)? + 4;
+    }
+    let mut answers = vec![];
+    for _ in 0..read_u16(response, 6)? {
+        offset = skip_name(response, offset)?;
+        let record_type = read_u16(response, offset)?;
+        let ttl = (u32::from(read_u16(response, offset + 4)?) << 16)
+            | u32::from(read_u16(response, offset + 6)?);
+        let len = read_u16(response, offset + 8)? as usize;
+        offset += 10;
+        let data = response.get(offset..offset + len).ok_or_else(malformed)?;
+        offset += len;
+        if let Ok(octets) = <[u8; 4]>::try_from(data) {
+            if record_type == TYPE_A {
+                answers.push((IpAddr::from(octets), ttl));
+            }
+        } else if let Ok(octets) = <[u8; 16]>::try_from(data) {
+            if record_type == TYPE_AAAA {
+                answers.push((IpAddr::from(octets), ttl));
+            }
+        }
+    }
+    Ok(Some(answers))
+}
+
+#[cfg(test)]
+mod test_dns {
+    use super::*;
+
+    /// Response to the query with the given records, of the type of the query
+    fn respond(query: &[u8], rdata: &[&[u8]], ttl: u32) -> Vec<u8> {
+        let mut response = Vec::from(&query[..2]);
+        for field in [0x8180, 1, rdata.len() as u16, 0, 0] {
+            response.extend_from_slice(&u16::to_be_bytes(field));
+        }
+        response.extend_from_slice(&query[12..]);
+        let record_type = &query[query.len() - 4..query.len() - 2];
+        for data in rdata {
+            // Pointer to the name of the question
+            response.extend_from_slice(&[0xc0, 12]);
+            response.extend_from_slice(record_type);
+            response.extend_from_slice(&CLASS_IN.to_be_bytes());
+            response.extend_from_slice(&ttl.to_be_bytes());
+            response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            response.extend_from_slice(data);
+        }
+        response
+    }
+
+    #[test]
+    fn test_parse_override() {
+        assert_eq!(
+            ResolveOverride::from_str("checkmk.example.com:8000:10.0.0.1").unwrap(),
+            ResolveOverride {
+                host: String::from("checkmk.example.com"),
+                port: 8000,
+                address: IpAddr::from([10, 0, 0, 1]),
+            }
+        );
+        assert_eq!(
+            ResolveOverride::from_str("checkmk:443:[2001:db8::1]")
+                .unwrap()
+                .address,
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+        for invalid in [
+            "checkmk:8000",
+            ":8000:10.0.0.1",
+            "checkmk:port:10.0.0.1",
+            "a:1:b",
+        ] {
+            assert!(ResolveOverride::from_str(invalid).is_err());
+        }
+        assert_eq!(
+            NameServer::from_str("10.0.0.53").unwrap(),
+            NameServer("10.0.0.53:53".parse().unwrap())
+        );
+        assert_eq!(
+            NameServer::from_str("[2001:db8::53]:5353").unwrap(),
+            NameServer("[2001:db8::53]:5353".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_with_overrides() {
+        let config = DnsConfig {
+            resolve: vec![
+                ResolveOverride::from_str("checkmk:8000:10.0.0.1").unwrap(),
+                ResolveOverride::from_str("checkmk:8001:10.0.0.1").unwrap(),
+            ],
+            ..DnsConfig::default()
+        }
+        .with_overrides(&[ResolveOverride::from_str("CHECKMK:8000:10.0.0.2").unwrap()]);
+        assert_eq!(
+            config.resolve,
+            vec![
+                ResolveOverride::from_str("checkmk:8001:10.0.0.1").unwrap(),
+                ResolveOverride::from_str("CHECKMK:8000:10.0.0.2").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolver_overrides_and_cache() {
+        let resolver = Resolver::new(DnsConfig {
+            cache_ttl: 60,
+            resolve: vec![ResolveOverride::from_str("checkmk:8000:10.0.0.1").unwrap()],
+            ..DnsConfig::default()
+        });
+        assert_eq!(
+            resolver.lookup("Checkmk", 8000).unwrap(),
+            vec!["10.0.0.1:8000".parse().unwrap()]
+        );
+        assert_eq!(
+            resolver.lookup("[::1]", 8000).unwrap(),
+            vec!["[::1]:8000".parse().unwrap()]
+        );
+        assert!(!resolver.lookup("localhost", 8001).unwrap().is_empty());
+        assert!(resolver
+            .cache
+            .lock()
+            .unwrap()
+            .contains_key(&(String::from("localhost"), 8001)));
+    }
+
+    #[test]
+    fn test_encode_and_parse() {
+        let query = encode_query(4711, "checkmk.example.com.", TYPE_A).unwrap();
+        assert_eq!(&query[12..21], b"\x07checkmk\x07");
+        assert!(encode_query(4711, "check..mk", TYPE_A).is_err());
+        let response = respond(&query, &[&[10, 0, 0, 1], &[10, 0, 0, 2]], 300);
+        assert_eq!(
+            parse_response(4711, &response).unwrap().unwrap(),
+            vec![
+                (IpAddr::from([10, 0, 0, 1]), 300),
+                (IpAddr::from([10, 0, 0, 2]), 300)
+            ]
+        );
+        assert!(parse_response(4712, &response).unwrap().is_none());
+        let mut nxdomain = respond(&query, &[], 0);
+        nxdomain[3] |= RCODE_NXDOMAIN as u8;
+        assert_eq!(
+            parse_response(4711, &nxdomain).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(parse_response(4711, &response[..response.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_query_server() {
+        let name_server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = name_server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buffer = [0; 512];
+            for rdata in [
+                vec![&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1][..]],
+                vec![&[10, 0, 0, 1][..]],
+            ] {
+                let (len, client) = name_server.recv_from(&mut buffer).unwrap();
+                let ttl = if rdata[0].len() == 4 { 30 } else { 300 };
+                name_server
+                    .send_to(&respond(&buffer[..len], &rdata, ttl), client)
+                    .unwrap();
+            }
+        });
+        let resolver = Resolver::new(DnsConfig {
+            servers: vec![NameServer(address)],
+            cache_ttl: 60,
+            resolve: vec![],
+        });
+        assert_eq!(
+            resolver.lookup("checkmk.example.com", 8000).unwrap(),
+            vec![
+                "[2001:db8::1]:8000".parse().unwrap(),
+                "10.0.0.1:8000".parse().unwrap()
+            ]
+        );
+        // Cached for the lowest TTL, the name server does not answer anymore
+        let cache = resolver.cache.lock().unwrap();
+        let cached = &cache[&(String::from("checkmk.example.com"), 8000)];
+        assert!(cached.expires <= Instant::now() + Duration::from_secs(30));
+        drop(cache);
+        assert_eq!(
+            resolver.lookup("checkmk.example.com", 8000).unwrap().len(),
+            2
+        );
+    }
+}
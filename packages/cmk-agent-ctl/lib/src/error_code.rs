@@ -0,0 +1,347 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Stable codes for the errors the controller fails with, st. automation can tell apart e.g. a
+//! network problem worth a retry from credentials the receiver does not accept. The codes and
+//! their categories are part of the JSON output and determine the exit code.
+
+use crate::agent_receiver_api;
+use crate::tls_failure::TlsFailure;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    Network,
+    Tls,
+    Auth,
+    Config,
+    Receiver,
+    /// The site declined a new host, or did not decide in time
+    Approval,
+    Other,
+}
+
+impl ErrorCategory {
+    /// Exit code of the controller failing with an error of this category. Uncategorized errors
+    /// keep the generic exit code 1, which scripts rely on. 2 is taken by the status mode.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Other => 1,
+            Self::Network => 3,
+            Self::Tls => 4,
+            Self::Auth => 5,
+            Self::Config => 6,
+            Self::Receiver => 7,
+            Self::Approval => 8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    ConnectionFailed,
+    Timeout,
+    TlsHandshake,
+    InvalidServerCertificate,
+    Unauthorized,
+    Forbidden,
+    InvalidConfig,
+    UnknownConnection,
+    FipsUnavailable,
+    ReceiverRejected,
+    ReceiverFailed,
+    UnsupportedCompression,
+    UnexpectedResponse,
+    RegistrationDeclined,
+    ApprovalTimeout,
+    Unknown,
+}
+
+impl ErrorCode {
+    /// Stable numeric code, the first digit is the category
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::ConnectionFailed => 1001,
+            Self::Timeout => 1002,
+            Self::TlsHandshake => 2001,
+            Self::InvalidServerCertificate => 2002,
+            Self::Unauthorized => 3001,
+            Self::Forbidden => 3002,
+            Self::InvalidConfig => 4001,
+            Self::UnknownConnection => 4002,
+            Self::FipsUnavailable => 4003,
+            Self::ReceiverRejected => 5001,
+            Self::ReceiverFailed => 5002,
+            Self::UnsupportedCompression => 5003,
+            Self::UnexpectedResponse => 5004,
+            Self::RegistrationDeclined => 6001,
+            Self::ApprovalTimeout => 6002,
+            Self::Unknown => 9001,
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self.code() / 1000 {
+            1 => ErrorCategory::Network,
+            2 => ErrorCategory::Tls,
+            3 => ErrorCategory::Auth,
+            4 => ErrorCategory::Config,
+            5 => ErrorCategory::Receiver,
+            6 => ErrorCategory::Approval,
+            _ => ErrorCategory::Other,
+        }
+    }
+
+    /// Classify an error by the errors it was caused by. Explicitly coded errors take precedence,
+    /// then the most specific cause, eg. a TLS error over the failed connection it caused.
+    pub fn of(error: &anyhow::Error) -> ErrorCode {
+        // Searches the contexts as well, unlike the chain
+        if let Some(coded) = error.downcast_ref::<CodedError>() {
+            return coded.code;
+        }
+        let classifiers: [fn(&(dyn std::error::Error + 'static)) -> Option<ErrorCode>; 5] = [
+            Self::of_tls,
+            Self::of_response,
+            |cause| {
+                cause
+                    .downcast_ref::<reqwest::Error>()
+                    .and_then(Self::of_request)
+            },
+            |cause| cause.downcast_ref::<std::io::Error>().and_then(Self::of_io),
+            Self::of_parsing,
+        ];
+        classifiers
+            .iter()
+            .find_map(|classify| error.chain().find_map(classify))
+            .unwrap_or(ErrorCode::Unknown)
+    }
+
+    fn of_tls(cause: &(dyn std::error::Error + 'static)) -> Option<ErrorCode> {
+        // rustls reports through IO errors, which do not expose it as their source
+        let Some(error) = cause.downcast_ref::<rustls::Error>().or_else(|| {
+            cause
+                .downcast_ref::<std::io::Error>()?
+                .get_ref()?
+                .downcast_ref::<rustls::Error>()
+        }) else {
+            // Otherwise, reqwest would report these as failed connections
+            return TlsFailure::of_openssl(cause).map(|failure| match failure {
+                TlsFailure::ExpiredCertificate
+                | TlsFailure::ClockSkew
+                | TlsFailure::UnknownCa
+                | TlsFailure::WrongServerName => ErrorCode::InvalidServerCertificate,
+                _ => ErrorCode::TlsHandshake,
+            });
+        };
+        Some(match error {
+            rustls::Error::InvalidCertificate(..) => ErrorCode::InvalidServerCertificate,
+            _ => ErrorCode::TlsHandshake,
+        })
+    }
+
+    fn of_response(cause: &(dyn std::error::Error + 'static)) -> Option<ErrorCode> {
+        if cause.is::<agent_receiver_api::UnsupportedCompression>() {
+            return Some(ErrorCode::UnsupportedCompression);
+        }
+        let status = cause
+            .downcast_ref::<agent_receiver_api::ResponseError>()?
+            .status;
+        Some(match status {
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            status if status.is_server_error() => ErrorCode::ReceiverFailed,
+            status if status.is_client_error() => ErrorCode::ReceiverRejected,
+            _ => ErrorCode::UnexpectedResponse,
+        })
+    }
+
+    fn of_request(error: &reqwest::Error) -> Option<ErrorCode> {
+        if error.is_timeout() {
+            Some(ErrorCode::Timeout)
+        } else if error.is_connect() || error.is_request() {
+            Some(ErrorCode::ConnectionFailed)
+        } else if error.is_decode() {
+            Some(ErrorCode::UnexpectedResponse)
+        } else {
+            None
+        }
+    }
+
+    fn of_io(error: &std::io::Error) -> Option<ErrorCode> {
+        match error.kind() {
+            std::io::ErrorKind::TimedOut => Some(ErrorCode::Timeout),
+            std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::NotConnected
+            | std::io::ErrorKind::AddrNotAvailable => Some(ErrorCode::ConnectionFailed),
+            _ => None,
+        }
+    }
+
+    /// Files of the controller which cannot be parsed
+    fn of_parsing(cause: &(dyn std::error::Error + 'static)) -> Option<ErrorCode> {
+        (cause.is::<toml::de::Error>() || cause.is::<serde_json::Error>())
+            .then_some(ErrorCode::InvalidConfig)
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "E{}", self.code())
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_u16(self.code())
+    }
+}
+
+/// An error whose code cannot be told from its cause
+#[derive(Debug)]
+pub struct CodedError {
+    pub code: ErrorCode,
+    message: String,
+}
+
+impl CodedError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for CodedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+/// Code and category of an error, for the JSON output
+#[derive(Serialize)]
+pub struct ErrorClassification {
+    pub error_code: ErrorCode,
+    pub error_category: ErrorCategory,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_failure: Option<TlsFailure>,
+}
+
+impl From<&anyhow::Error> for ErrorClassification {
+    fn from(error: &anyhow::Error) -> Self {
+        let code = ErrorCode::of(error);
+        Self {
+            error_code: code,
+            error_category: code.category(),
+            tls_failure: TlsFailure::of(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_error_code {
+    use super::*;
+    use anyhow::{anyhow, Context, Result as AnyhowResult};
+
+    #[test]
+    fn test_coded_error_takes_precedence() {
+        let error = anyhow::Error::from(agent_receiver_api::ResponseError::new(
+            StatusCode::FORBIDDEN,
+            None,
+        ))
+        .context(CodedError::new(ErrorCode::UnknownConnection, "unknown"));
+        assert_eq!(ErrorCode::of(&error), ErrorCode::UnknownConnection);
+        assert_eq!(error.to_string(), "unknown");
+    }
+
+    #[test]
+    fn test_response_status() {
+        let of_status = |status| {
+            ErrorCode::of(
+                &anyhow::Error::from(agent_receiver_api::ResponseError::new(status, None))
+                    .context("Request failed"),
+            )
+        };
+        assert_eq!(of_status(StatusCode::UNAUTHORIZED), ErrorCode::Unauthorized);
+        assert_eq!(of_status(StatusCode::FORBIDDEN), ErrorCode::Forbidden);
+        assert_eq!(
+            of_status(StatusCode::NOT_FOUND),
+            ErrorCode::ReceiverRejected
+        );
+        assert_eq!(
+            of_status(StatusCode::BAD_GATEWAY),
+            ErrorCode::ReceiverFailed
+        );
+        assert_eq!(
+            ErrorCode::of(&anyhow::Error::from(
+                agent_receiver_api::UnsupportedCompression(String::from("zstd"))
+            )),
+            ErrorCode::UnsupportedCompression
+        );
+    }
+
+    #[test]
+    fn test_tls_over_io() {
+        let error = anyhow::Error::from(std::io::Error::new(
+            std::io::ErrorKind::ConnectionAborted,
+            rustls::Error::InvalidCertificate(rustls::CertificateError::Expired),
+        ));
+        assert_eq!(ErrorCode::of(&error), ErrorCode::InvalidServerCertificate);
+        assert_eq!(ErrorCode::of(&error).category(), ErrorCategory::Tls);
+        let error = anyhow!(
+            "error:0A000086:SSL routines:tls_post_process_server_certificate:certificate verify \
+             failed:ssl/statem/statem_clnt.c:1889: (unable to get local issuer certificate)"
+        )
+        .context("error sending request");
+        assert_eq!(ErrorCode::of(&error), ErrorCode::InvalidServerCertificate);
+    }
+
+    #[test]
+    fn test_io_and_parsing() {
+        let refused: AnyhowResult<()> =
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into());
+        let error = refused.context("Failed to connect").unwrap_err();
+        assert_eq!(ErrorCode::of(&error), ErrorCode::ConnectionFailed);
+        assert_eq!(error.to_string(), "Failed to connect");
+        let error = anyhow::Error::from(toml::from_str::<toml::Value>("x = ").unwrap_err());
+        assert_eq!(ErrorCode::of(&error), ErrorCode::InvalidConfig);
+        assert_eq!(ErrorCode::of(&anyhow!("Something")), ErrorCode::Unknown);
+        assert_eq!(ErrorCode::Unknown.category().exit_code(), 1);
+        assert_eq!(ErrorCode::RegistrationDeclined.category().exit_code(), 8);
+    }
+
+    #[test]
+    fn test_classification_json() {
+        assert_eq!(
+            serde_json::to_value(ErrorClassification::from(&anyhow::Error::from(
+                agent_receiver_api::ResponseError::new(StatusCode::UNAUTHORIZED, None)
+            )))
+            .unwrap(),
+            serde_json::json!({"error_code": 3001, "error_category": "auth"})
+        );
+        assert_eq!(
+            serde_json::to_value(ErrorClassification::from(&anyhow::Error::from(
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    rustls::Error::InvalidCertificate(rustls::CertificateError::NotValidYet),
+                )
+            )))
+            .unwrap(),
+            serde_json::json!({
+                "error_code": 2002,
+                "error_category": "tls",
+                "tls_failure": "clock_skew"
+            })
+        );
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! C interface to the controller library, declared in include/cmk_agent_ctl.h. Every call opens
+//! the given home directory of the controller and reports whether it succeeded. On failure, the
+//! reason is handed out via error (unless it is NULL). Strings handed out have to be released with
+//! cmk_agent_ctl_free_string.
+
+use crate::controller::{Controller, Host, Registration};
+use crate::types;
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic;
+
+#[repr(i32)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum CmkAgentCtlResult {
+    Ok = 0,
+    Error = 1,
+}
+
+#[repr(C)]
+pub struct CmkAgentCtlRegistration {
+    pub server: *const c_char,
+    pub site: *const c_char,
+    /// Port of the agent receiver, 0 queries it from the site
+    pub port: u16,
+    pub user: *const c_char,
+    pub password: *const c_char,
+    /// Host to register for, NULL lets the site create the host
+    pub host_name: *const c_char,
+    /// Root certificate (PEM) to verify the server certificate with, may be NULL
+    pub root_certificate: *const c_char,
+    /// Trust the server certificate without verifying it
+    pub trust_server_cert: bool,
+    /// Use the proxy settings configured on this system
+    pub detect_proxy: bool,
+}
+
+unsafe fn optional_string(string: *const c_char, name: &str) -> AnyhowResult<Option<String>> {
+    if string.is_null() {
+        return Ok(None);
+    }
+    Ok(Some(String::from(
+        CStr::from_ptr(string)
+            .to_str()
+            .context(format!("{name} is not valid UTF-8"))?,
+    )))
+}
+
+unsafe fn string(string: *const c_char, name: &str) -> AnyhowResult<String> {
+    optional_string(string, name)?.ok_or_else(|| anyhow!("{name} must not be NULL"))
+}
+
+fn into_c_string(string: String) -> *mut c_char {
+    CString::new(string.replace('\0', ""))
+        .unwrap_or_default()
+        .into_raw()
+}
+
+unsafe fn open(home_dir: *const c_char) -> AnyhowResult<Controller> {
+    Controller::open(string(home_dir, "home_dir")?)
+}
+
+/// Run the call, panics must not unwind into C
+unsafe fn call(
+    error: *mut *mut c_char,
+    call: impl FnOnce() -> AnyhowResult<()>,
+) -> CmkAgentCtlResult {
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(call))
+        .unwrap_or_else(|_| Err(anyhow!("Internal error in agent controller")));
+    match result {
+        Ok(()) => CmkAgentCtlResult::Ok,
+        Err(err) => {
+            if !error.is_null() {
+                *error = into_c_string(format!("{err:#}"));
+            }
+            CmkAgentCtlResult::Error
+        }
+    }
+}
+
+/// Register at a site and save the new connection.
+///
+/// # Safety
+///
+/// home_dir and the strings of registration are NULL or NUL-terminated, error is NULL or
+/// writable.
+#[no_mangle]
+pub unsafe extern "C" fn cmk_agent_ctl_register(
+    home_dir: *const c_char,
+    registration: *const CmkAgentCtlRegistration,
+    error: *mut *mut c_char,
+) -> CmkAgentCtlResult {
+    call(error, || {
+        let registration = registration
+            .as_ref()
+            .context("registration must not be NULL")?;
+        let host = match optional_string(registration.host_name, "host_name")? {
+            Some(host_name) => Host::Existing { host_name },
+            None => Host::New {
+                agent_labels: types::AgentLabels::new(),
+            },
+        };
+        open(home_dir)?.register(
+            &Registration {
+                server: string(registration.server, "server")?,
+                site: string(registration.site, "site")?,
+                port: (registration.port != 0).then_some(registration.port),
+                user: string(registration.user, "user")?,
+                password: string(registration.password, "password")?,
+                root_certificate: optional_string(
+                    registration.root_certificate,
+                    "root_certificate",
+                )?,
+                trust_server_cert: registration.trust_server_cert,
+                detect_proxy: registration.detect_proxy,
+            },
+            &host,
+        )
+    })
+}
+
+/// Delete the connection with the given site ID (server/site) or UUID.
+///
+/// # Safety
+///
+/// home_dir and connection are NULL or NUL-terminated, error is NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn cmk_agent_ctl_delete(
+    home_dir: *const c_char,
+    connection: *const c_char,
+    error: *mut *mut c_char,
+) -> CmkAgentCtlResult {
+    call(error, || {
+        open(home_dir)?.delete(&string(connection, "connection")?)
+    })
+}
+
+/// Renew the certificate of the connection with the given site ID (server/site) or UUID.
+///
+/// # Safety
+///
+/// home_dir and connection are NULL or NUL-terminated, error is NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn cmk_agent_ctl_renew_certificate(
+    home_dir: *const c_char,
+    connection: *const c_char,
+    error: *mut *mut c_char,
+) -> CmkAgentCtlResult {
+    call(error, || {
+        open(home_dir)?.renew_certificate(&string(connection, "connection")?)
+    })
+}
+
+/// The status of all connections as JSON, as reported by the status mode with --json.
+///
+/// # Safety
+///
+/// home_dir is NULL or NUL-terminated, status_json and error are NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn cmk_agent_ctl_status_json(
+    home_dir: *const c_char,
+    query_remote: bool,
+    status_json: *mut *mut c_char,
+    error: *mut *mut c_char,
+) -> CmkAgentCtlResult {
+    call(error, || {
+        if status_json.is_null() {
+            return Err(anyhow!("status_json must not be NULL"));
+        }
+        *status_json = into_c_string(open(home_dir)?.status_json(query_remote)?);
+        Ok(())
+    })
+}
+
+/// Release a string handed out by the controller.
+///
+/// # Safety
+///
+/// string is NULL or was handed out by the controller and is not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn cmk_agent_ctl_free_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+#[cfg(test)]
+mod test_ffi {
+    use super::*;
+    use std::ptr;
+
+    fn c_string(string: &str) -> CString {
+        CString::new(string).unwrap()
+    }
+
+    unsafe fn take(string: *mut c_char) -> String {
+        let taken = String::from(CStr::from_ptr(string).to_str().unwrap());
+        cmk_agent_ctl_free_string(string);
+        taken
+    }
+
+    #[test]
+    fn test_status_json() {
+        let home_dir = tempfile::tempdir().unwrap();
+        let home_dir = c_string(home_dir.path().to_str().unwrap());
+        let mut status_json = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                cmk_agent_ctl_status_json(
+                    home_dir.as_ptr(),
+                    false,
+                    &mut status_json,
+                    ptr::null_mut()
+                ),
+                CmkAgentCtlResult::Ok
+            );
+            let status: serde_json::Value = serde_json::from_str(&take(status_json)).unwrap();
+            assert!(status["connections"].as_array().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let home_dir = tempfile::tempdir().unwrap();
+        let home_dir = c_string(home_dir.path().to_str().unwrap());
+        let mut error = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                cmk_agent_ctl_delete(home_dir.as_ptr(), ptr::null(), &mut error),
+                CmkAgentCtlResult::Error
+            );
+            assert_eq!(take(error), "connection must not be NULL");
+            assert_eq!(
+                cmk_agent_ctl_delete(
+                    home_dir.as_ptr(),
+                    c_string("not-a-connection").as_ptr(),
+                    ptr::null_mut()
+                ),
+                CmkAgentCtlResult::Error
+            );
+            assert_eq!(
+                cmk_agent_ctl_register(home_dir.as_ptr(), ptr::null(), &mut error),
+                CmkAgentCtlResult::Error
+            );
+            assert_eq!(take(error), "registration must not be NULL");
+        }
+    }
+
+    #[test]
+    fn test_register_requires_trust() {
+        let home_dir = tempfile::tempdir().unwrap();
+        let home_dir = c_string(home_dir.path().to_str().unwrap());
+        let (server, site, user, password) = (
+            c_string("server"),
+            c_string("site"),
+            c_string("user"),
+            c_string("password"),
+        );
+        let mut error = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                cmk_agent_ctl_register(
+                    home_dir.as_ptr(),
+                    &CmkAgentCtlRegistration {
+                        server: server.as_ptr(),
+                        site: site.as_ptr(),
+                        port: 8000,
+                        user: user.as_ptr(),
+                        password: password.as_ptr(),
+                        host_name: ptr::null(),
+                        root_certificate: ptr::null(),
+                        trust_server_cert: false,
+                        detect_proxy: false,
+                    },
+                    &mut error,
+                ),
+                CmkAgentCtlResult::Error
+            );
+            assert!(take(error).contains("Provide the root certificate"));
+        }
+    }
+}
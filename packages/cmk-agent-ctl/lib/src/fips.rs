@@ -0,0 +1,107 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! FIPS mode, enabled by "fips = true" in the config file or always by builds with the feature
+//! "fips". OpenSSL, which generates the keys, signs the CSRs and the pushed data and backs the
+//! connections via native-tls, is then restricted to its FIPS provider. The provider module and
+//! its config, as generated by 'openssl fipsinstall', are looked up via OPENSSL_MODULES and
+//! OPENSSL_CONF. Loading it fails, or the self-test at startup does, if the module is missing or
+//! non-approved algorithms are still available, and the controller refuses to run.
+//!
+//! TLS otherwise runs on rustls, whose crypto (ring) is not FIPS-validated. In FIPS mode, the
+//! calls of the agent receiver API, serving pull requests via TCP and pull-once run on OpenSSL
+//! instead, see openssl_tls. QUIC, the pull tunnel and the relay are built on rustls only and
+//! are not available in FIPS mode, pushing falls back to HTTPS.
+
+use crate::error_code::{CodedError, ErrorCode};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::provider::Provider;
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use rustls::{ConfigBuilder, ConfigSide, WantsCipherSuites, WantsVerifier};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+const SELF_TEST_DATA: &[u8] = b"cmk-agent-ctl FIPS self-test";
+
+/// Restrict OpenSSL to its FIPS provider and verify it. Has to happen before anything else uses
+/// OpenSSL, which would load its default provider otherwise.
+pub fn enable() -> AnyhowResult<()> {
+    load_providers().and_then(|_| self_test()).map_err(|err| {
+        CodedError::new(
+            ErrorCode::FipsUnavailable,
+            format!("Cannot run in FIPS mode: {err:#}"),
+        )
+    })?;
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn load_providers() -> AnyhowResult<()> {
+    // Once providers are loaded explicitly, OpenSSL does not load its default provider anymore
+    for name in ["fips", "base"] {
+        let provider = Provider::load(None, name)
+            .with_context(|| format!("Failed to load the OpenSSL {name} provider"))?;
+        // Stays loaded for the lifetime of the process
+        std::mem::forget(provider);
+    }
+    Ok(())
+}
+
+fn self_test() -> AnyhowResult<()> {
+    let key = PKey::from_rsa(Rsa::generate(2048).context("Failed to generate a key")?)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(SELF_TEST_DATA)?;
+    let signature = signer.sign_to_vec().context("Failed to sign")?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+    verifier.update(SELF_TEST_DATA)?;
+    if !verifier.verify(&signature)? {
+        bail!("Self-test failed, signature does not verify");
+    }
+    if openssl::hash::hash(MessageDigest::md5(), SELF_TEST_DATA).is_ok() {
+        bail!(
+            "Self-test failed, non-approved algorithms are available. \
+             The OpenSSL config must not activate the default provider."
+        );
+    }
+    Ok(())
+}
+
+fn rustls_unavailable() -> anyhow::Error {
+    CodedError::new(
+        ErrorCode::FipsUnavailable,
+        "QUIC, the pull tunnel and the relay are not available in FIPS mode, \
+         their TLS provider (rustls) is not FIPS-validated",
+    )
+    .into()
+}
+
+/// Protocol versions, cipher suites and key exchange groups of TLS via rustls. Refused in FIPS
+/// mode, where the features built on rustls only are unavailable.
+pub fn tls_defaults<S: ConfigSide>(
+    builder: ConfigBuilder<S, WantsCipherSuites>,
+) -> AnyhowResult<ConfigBuilder<S, WantsVerifier>> {
+    match enabled() {
+        true => Err(rustls_unavailable()),
+        false => Ok(builder.with_safe_defaults()),
+    }
+}
+
+#[cfg(test)]
+mod test_fips {
+    use super::*;
+
+    #[test]
+    fn test_disabled() {
+        assert!(!enabled());
+        assert!(tls_defaults(rustls::ServerConfig::builder()).is_ok());
+    }
+}
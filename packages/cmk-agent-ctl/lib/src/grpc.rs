@@ -0,0 +1,664 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! gRPC variant of the agent receiver protocol, chosen per connection for environments with gRPC
+//! gateways in front of the sites. The service is described in proto/agent_receiver.proto. Its
+//! methods are served at the root of the receiver port, the site is sent as metadata.
+//! Registrations authenticate with basic auth like with REST, trusted connections with their
+//! client certificate. Agent output is streamed in messages of a single request, which saves the
+//! multipart encoding of the REST variant.
+//!
+//! There are only a few messages, so they are encoded here instead of generating code for them.
+//! The calls are made with the HTTP/2 client of the REST variant, which cannot read trailers. The
+//! receivers (or the gateways in front of them) thus have to send the gRPC status with the headers,
+//! as they do for errors ("Trailers-Only"). Calls without it fail, since an error sent as trailer
+//! would go unnoticed otherwise.
+
+use crate::agent_receiver_api::{
+    NewHost, RegisterExistingResponse, RegisterNewOngoingResponse,
+    RegisterNewOngoingResponseDeclined, RegisterNewOngoingResponseSuccess, RegisterNewResponse,
+    RegistrationStatusV2Response, RegistrationStatusV2ResponseRegistered, RenewCertificateResponse,
+    ResponseError,
+};
+use crate::{config, constants};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use http::StatusCode;
+
+const SERVICE: &str = "cmk.agent_receiver.v1.AgentReceiver";
+/// Metadata naming the site
+const SITE_METADATA: &str = "cmk-site";
+
+const WIRE_TYPE_VARINT: u64 = 0;
+const WIRE_TYPE_FIXED64: u64 = 1;
+const WIRE_TYPE_LEN: u64 = 2;
+const WIRE_TYPE_FIXED32: u64 = 5;
+
+const CONNECTION_MODE_PULL: u64 = 1;
+const CONNECTION_MODE_PUSH: u64 = 2;
+
+/// Protobuf encoding of a message. Fields with default values are left out, as by protoc.
+#[derive(Default, Debug, PartialEq, Eq)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u64) {
+        self.varint(u64::from(field) << 3 | wire_type);
+    }
+
+    fn len_delimited(mut self, field: u32, value: &[u8]) -> Self {
+        self.key(field, WIRE_TYPE_LEN);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn bytes(self, field: u32, value: &[u8]) -> Self {
+        match value.is_empty() {
+            true => self,
+            false => self.len_delimited(field, value),
+        }
+    }
+
+    fn string(self, field: u32, value: &str) -> Self {
+        self.bytes(field, value.as_bytes())
+    }
+
+    fn uint64(mut self, field: u32, value: u64) -> Self {
+        if value != 0 {
+            self.key(field, WIRE_TYPE_VARINT);
+            self.varint(value);
+        }
+        self
+    }
+
+    /// Also if empty, like the entries of maps
+    fn message(self, field: u32, value: Message) -> Self {
+        self.len_delimited(field, &value.0)
+    }
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+fn truncated() -> anyhow::Error {
+    anyhow::anyhow!("Truncated protobuf message")
+}
+
+fn read_varint(message: &[u8], offset: &mut usize) -> AnyhowResult<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *message.get(*offset).ok_or_else(truncated)?;
+        *offset += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Invalid varint in protobuf message")
+}
+
+/// Fields of a protobuf message. Unknown fields are skipped, of repeated scalar fields the last
+/// one counts, as with protoc.
+struct Decoded<'a>(Vec<(u32, Value<'a>)>);
+
+impl<'a> Decoded<'a> {
+    fn parse(message: &'a [u8]) -> AnyhowResult<Self> {
+        let mut fields = vec![];
+        let mut offset = 0;
+        while offset < message.len() {
+            let key = read_varint(message, &mut offset)?;
+            let field = u32::try_from(key >> 3).context("Invalid field number")?;
+            match key & 0x7 {
+                WIRE_TYPE_VARINT => {
+                    fields.push((field, Value::Varint(read_varint(message, &mut offset)?)))
+                }
+                WIRE_TYPE_LEN => {
+                    let len = usize::try_from(read_varint(message, &mut offset)?)?;
+                    let value = message
+                        .get(offset..offset.saturating_add(len))
+                        .ok_or_else(truncated)?;
+                    offset += len;
+                    fields.push((field, Value::Bytes(value)));
+                }
+                WIRE_TYPE_FIXED64 => offset += 8,
+                WIRE_TYPE_FIXED32 => offset += 4,
+                wire_type => bail!("Unsupported wire type {wire_type} in protobuf message"),
+            }
+        }
+        if offset > message.len() {
+            return Err(truncated());
+        }
+        Ok(Self(fields))
+    }
+
+    fn last(&self, field: u32) -> Option<&Value<'a>> {
+        self.0
+            .iter()
+            .rev()
+            .find(|(number, _)| *number == field)
+            .map(|(_, value)| value)
+    }
+
+    fn bytes(&self, field: u32) -> AnyhowResult<&'a [u8]> {
+        match self.last(field) {
+            None => Ok(&[]),
+            Some(Value::Bytes(value)) => Ok(*value),
+            Some(Value::Varint(_)) => bail!("Field {field} of protobuf message is no string"),
+        }
+    }
+
+    fn string(&self, field: u32) -> AnyhowResult<String> {
+        Ok(String::from(
+            std::str::from_utf8(self.bytes(field)?)
+                .with_context(|| format!("Field {field} of protobuf message is no UTF-8"))?,
+        ))
+    }
+
+    fn uint64(&self, field: u32) -> AnyhowResult<u64> {
+        match self.last(field) {
+            None => Ok(0),
+            Some(Value::Varint(value)) => Ok(*value),
+            Some(Value::Bytes(_)) => bail!("Field {field} of protobuf message is no integer"),
+        }
+    }
+
+    /// None if not set, eg. for the other fields of a oneof
+    fn message(&self, field: u32) -> AnyhowResult<Option<Decoded<'a>>> {
+        match self.last(field) {
+            None => Ok(None),
+            Some(Value::Bytes(value)) => Decoded::parse(value).map(Some),
+            Some(Value::Varint(_)) => bail!("Field {field} of protobuf message is no message"),
+        }
+    }
+
+    fn connection_mode(&self, field: u32) -> AnyhowResult<config::ConnectionMode> {
+        match self.uint64(field)? {
+            CONNECTION_MODE_PULL => Ok(config::ConnectionMode::Pull),
+            CONNECTION_MODE_PUSH => Ok(config::ConnectionMode::Push),
+            connection_mode => bail!("Unknown connection mode {connection_mode}"),
+        }
+    }
+}
+
+/// Length-prefixed messages, uncompressed
+fn frame(messages: &[Message]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(messages.iter().map(|message| message.0.len() + 5).sum());
+    for Message(message) in messages {
+        body.push(0);
+        body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        body.extend_from_slice(message);
+    }
+    body
+}
+
+fn unframe(body: &[u8]) -> AnyhowResult<Vec<&[u8]>> {
+    let mut messages = vec![];
+    let mut rest = body;
+    while !rest.is_empty() {
+        let (Some(&compressed), Some(len)) = (rest.first(), rest.get(1..5)) else {
+            bail!("Truncated gRPC message");
+        };
+        if compressed != 0 {
+            bail!("Compressed gRPC messages are not supported");
+        }
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        messages.push(rest.get(5..5 + len).context("Truncated gRPC message")?);
+        rest = &rest[5 + len..];
+    }
+    Ok(messages)
+}
+
+/// HTTP status corresponding to a gRPC status code, st. errors are handled alike for both
+/// variants of the protocol
+fn http_status(code: u32) -> StatusCode {
+    match code {
+        0 => StatusCode::OK,
+        // INVALID_ARGUMENT, FAILED_PRECONDITION, OUT_OF_RANGE
+        3 | 9 | 11 => StatusCode::BAD_REQUEST,
+        4 => StatusCode::GATEWAY_TIMEOUT,
+        // NOT_FOUND, UNIMPLEMENTED
+        5 | 12 => StatusCode::NOT_FOUND,
+        // ALREADY_EXISTS, ABORTED
+        6 | 10 => StatusCode::CONFLICT,
+        7 => StatusCode::FORBIDDEN,
+        8 => StatusCode::TOO_MANY_REQUESTS,
+        14 => StatusCode::SERVICE_UNAVAILABLE,
+        16 => StatusCode::UNAUTHORIZED,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// The status sent with the headers. Only trailers may follow, which we cannot read.
+fn grpc_status(headers: &reqwest::header::HeaderMap) -> AnyhowResult<u32> {
+    headers
+        .get("grpc-status")
+        .context(
+            "The agent receiver sent no grpc-status header. Its gRPC status has to be sent with \
+             the headers, trailers are not evaluated.",
+        )?
+        .to_str()
+        .ok()
+        .and_then(|code| code.parse().ok())
+        .context("Invalid grpc-status header")
+}
+
+/// The grpc-message header is percent-encoded
+fn percent_decode(message: &str) -> String {
+    let bytes = message.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if bytes[pos] == b'%' {
+            if let Some(byte) = message
+                .get(pos + 1..pos + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                decoded.push(byte);
+                pos += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[pos]);
+        pos += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// HTTP status corresponding to the gRPC status of the response
+pub fn status(response: &reqwest::blocking::Response) -> AnyhowResult<StatusCode> {
+    match response.status() {
+        StatusCode::OK => Ok(http_status(grpc_status(response.headers())?)),
+        status => Ok(status),
+    }
+}
+
+/// The single message of the response. Errors are reported as ResponseError, with the HTTP status
+/// corresponding to the gRPC status.
+fn response_message(response: reqwest::blocking::Response) -> AnyhowResult<Vec<u8>> {
+    let status = response.status();
+    if status != StatusCode::OK {
+        return Err(ResponseError::new(status, response.text().ok()).into());
+    }
+    let code = grpc_status(response.headers())?;
+    if code != 0 {
+        let message = response
+            .headers()
+            .get("grpc-message")
+            .and_then(|message| message.to_str().ok())
+            .map_or_else(|| format!("gRPC status {code}"), percent_decode);
+        return Err(ResponseError::new(http_status(code), Some(message)).into());
+    }
+    let body = response.bytes().context("Failed to obtain response body")?;
+    match unframe(&body)?.as_slice() {
+        [message] => Ok(message.to_vec()),
+        [] => bail!("The agent receiver sent no response message"),
+        messages => bail!(
+            "The agent receiver sent {} response messages instead of one",
+            messages.len()
+        ),
+    }
+}
+
+/// Receivers without support for a call answer 404 with REST, so the callers fall back alike
+pub fn unsupported(call: &str) -> anyhow::Error {
+    ResponseError::new(
+        StatusCode::NOT_FOUND,
+        Some(format!("{call} is not available via gRPC")),
+    )
+    .into()
+}
+
+/// A method of the service with its request messages, and how to decode its response
+pub struct Call<T> {
+    method: &'static str,
+    messages: Vec<Message>,
+    decode: fn(&Decoded) -> AnyhowResult<T>,
+}
+
+impl<T> Call<T> {
+    /// POST to the method, the site is taken from the base URL of the receiver
+    pub fn request(
+        &self,
+        client: &reqwest::blocking::Client,
+        base_url: &reqwest::Url,
+    ) -> AnyhowResult<reqwest::blocking::RequestBuilder> {
+        let site = base_url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|site| !site.is_empty())
+            .context(format!("Base URL {base_url} names no site"))?;
+        let mut url = base_url.clone();
+        url.set_path(&format!("{SERVICE}/{}", self.method));
+        Ok(client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/grpc")
+            .header(reqwest::header::TE, "trailers")
+            .header(SITE_METADATA, site)
+            .body(frame(&self.messages)))
+    }
+
+    pub fn response(&self, response: reqwest::blocking::Response) -> AnyhowResult<T> {
+        let message = response_message(response)?;
+        (self.decode)(&Decoded::parse(&message)?)
+            .context(format!("Error parsing the response of {}", self.method))
+    }
+}
+
+pub fn register_existing(
+    uuid: &uuid::Uuid,
+    csr: &str,
+    host_name: &str,
+) -> Call<RegisterExistingResponse> {
+    Call {
+        method: "RegisterExisting",
+        messages: vec![Message::default()
+            .string(1, &uuid.to_string())
+            .string(2, csr)
+            .string(3, host_name)],
+        decode: |response| {
+            Ok(RegisterExistingResponse {
+                root_cert: response.string(1)?,
+                agent_cert: response.string(2)?,
+                connection_mode: response.connection_mode(3)?,
+            })
+        },
+    }
+}
+
+pub fn register_new(uuid: &uuid::Uuid, csr: &str, new_host: &NewHost) -> Call<RegisterNewResponse> {
+    let mut request = Message::default()
+        .string(1, &uuid.to_string())
+        .string(2, csr);
+    for (key, value) in new_host.agent_labels {
+        request = request.message(3, Message::default().string(1, key).string(2, value));
+    }
+    request = request.string(
+        4,
+        new_host
+            .approval_callback_url
+            .map_or("", |url| url.as_str()),
+    );
+    Call {
+        method: "RegisterNew",
+        messages: vec![request],
+        decode: |response| {
+            Ok(RegisterNewResponse {
+                root_cert: response.string(1)?,
+            })
+        },
+    }
+}
+
+pub fn register_new_ongoing(uuid: &uuid::Uuid, wait: u64) -> Call<RegisterNewOngoingResponse> {
+    Call {
+        method: "RegisterNewOngoing",
+        messages: vec![Message::default()
+            .string(1, &uuid.to_string())
+            .uint64(2, wait)],
+        decode: |response| {
+            if let Some(declined) = response.message(2)? {
+                return Ok(RegisterNewOngoingResponse::Declined(
+                    RegisterNewOngoingResponseDeclined {
+                        reason: declined.string(1)?,
+                    },
+                ));
+            }
+            if let Some(success) = response.message(3)? {
+                return Ok(RegisterNewOngoingResponse::Success(
+                    RegisterNewOngoingResponseSuccess {
+                        agent_cert: success.string(1)?,
+                        connection_mode: success.connection_mode(2)?,
+                    },
+                ));
+            }
+            match response.message(1)? {
+                Some(_) => Ok(RegisterNewOngoingResponse::InProgress),
+                None => bail!("No status"),
+            }
+        },
+    }
+}
+
+pub fn registration_status(uuid: &uuid::Uuid) -> Call<RegistrationStatusV2Response> {
+    Call {
+        method: "RegistrationStatus",
+        messages: vec![Message::default().string(1, &uuid.to_string())],
+        decode: |response| {
+            if let Some(registered) = response.message(2)? {
+                return Ok(RegistrationStatusV2Response::Registered(
+                    RegistrationStatusV2ResponseRegistered {
+                        hostname: registered.string(1)?,
+                        connection_mode: registered.connection_mode(2)?,
+                    },
+                ));
+            }
+            match response.message(1)? {
+                Some(_) => Ok(RegistrationStatusV2Response::NotRegistered),
+                None => bail!("No status"),
+            }
+        },
+    }
+}
+
+pub fn renew_certificate(uuid: &uuid::Uuid, csr: &str) -> Call<RenewCertificateResponse> {
+    Call {
+        method: "RenewCertificate",
+        messages: vec![Message::default()
+            .string(1, &uuid.to_string())
+            .string(2, csr)],
+        decode: |response| {
+            Ok(RenewCertificateResponse {
+                agent_cert: response.string(1)?,
+            })
+        },
+    }
+}
+
+/// Client-streaming upload. The first message carries the metadata of the agent output, all of
+/// them carry a part of the data.
+pub fn upload_agent_data(
+    uuid: &uuid::Uuid,
+    compression_algorithm: &str,
+    collected_at: u64,
+    signature: Option<&str>,
+    monitoring_data: &[u8],
+) -> Call<()> {
+    let mut chunks = monitoring_data.chunks(constants::GRPC_MESSAGE_SIZE);
+    let first = Message::default()
+        .string(1, &uuid.to_string())
+        .string(2, compression_algorithm)
+        .uint64(3, collected_at)
+        .string(4, signature.unwrap_or_default())
+        .bytes(5, chunks.next().unwrap_or_default());
+    Call {
+        method: "UploadAgentData",
+        messages: std::iter::once(first)
+            .chain(chunks.map(|chunk| Message::default().bytes(5, chunk)))
+            .collect(),
+        decode: |_| Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test_grpc {
+    use super::*;
+    use std::str::FromStr;
+
+    const UUID: &str = "99f56bbc-5965-4b34-bc70-1959ad1d32d6";
+
+    fn framed(message: Message) -> Vec<u8> {
+        frame(&[message])
+    }
+
+    fn response(status: StatusCode, headers: &[(&str, &str)], body: Vec<u8>) -> AnyhowResult<()> {
+        let mut builder = http::Response::builder().status(status);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        register_new_ongoing(&uuid::Uuid::from_str(UUID).unwrap(), 0)
+            .response(reqwest::blocking::Response::from(
+                builder.body(body).unwrap(),
+            ))
+            .map(|_| ())
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let message = Message::default()
+            .string(1, "hostname")
+            .uint64(2, 300)
+            .string(3, "")
+            .message(4, Message::default().uint64(1, 1));
+        assert_eq!(
+            message.0,
+            b"\x0a\x08hostname\x10\xac\x02\x22\x02\x08\x01".to_vec()
+        );
+        let decoded = Decoded::parse(&message.0).unwrap();
+        assert_eq!(decoded.string(1).unwrap(), "hostname");
+        assert_eq!(decoded.uint64(2).unwrap(), 300);
+        assert_eq!(decoded.string(3).unwrap(), "");
+        assert_eq!(decoded.message(4).unwrap().unwrap().uint64(1).unwrap(), 1);
+        assert!(decoded.message(5).unwrap().is_none());
+        assert!(decoded.uint64(1).is_err());
+        assert!(Decoded::parse(&message.0[..message.0.len() - 1]).is_err());
+        // Unknown fixed-size fields are skipped
+        assert!(Decoded::parse(b"\x09\x01\x02\x03\x04\x05\x06\x07\x08\x10\x01").is_ok());
+    }
+
+    #[test]
+    fn test_frame_unframe() {
+        let body = frame(&[Message::default().string(1, "a"), Message::default()]);
+        assert_eq!(
+            body,
+            b"\x00\x00\x00\x00\x03\x0a\x01a\x00\x00\x00\x00\x00".to_vec()
+        );
+        assert_eq!(unframe(&body).unwrap(), vec![&b"\x0a\x01a"[..], &b""[..]]);
+        assert!(unframe(&body[..body.len() - 1]).is_err());
+        assert!(unframe(b"\x01\x00\x00\x00\x00").is_err());
+    }
+
+    #[test]
+    fn test_request() {
+        let client = reqwest::blocking::Client::new();
+        let request = registration_status(&uuid::Uuid::from_str(UUID).unwrap())
+            .request(
+                &client,
+                &reqwest::Url::parse("https://server:8000/site").unwrap(),
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://server:8000/cmk.agent_receiver.v1.AgentReceiver/RegistrationStatus"
+        );
+        assert_eq!(request.headers()[SITE_METADATA], "site");
+        assert_eq!(request.headers()["content-type"], "application/grpc");
+    }
+
+    #[test]
+    fn test_response() {
+        let in_progress = Message::default().message(1, Message::default());
+        assert!(response(StatusCode::OK, &[("grpc-status", "0")], framed(in_progress)).is_ok());
+        let error = response(
+            StatusCode::OK,
+            &[
+                ("grpc-status", "16"),
+                ("grpc-message", "Invalid%20credentials"),
+            ],
+            vec![],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ResponseError>().unwrap().status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert!(error.to_string().contains("Invalid credentials"));
+        // Status only sent as trailer, which may tell an error
+        let in_progress = Message::default().message(1, Message::default());
+        assert!(response(StatusCode::OK, &[], framed(in_progress)).is_err());
+        assert!(response(StatusCode::OK, &[], vec![]).is_err());
+        assert!(response(StatusCode::OK, &[("grpc-status", "0")], vec![]).is_err());
+        assert_eq!(
+            response(StatusCode::BAD_GATEWAY, &[], vec![])
+                .unwrap_err()
+                .downcast_ref::<ResponseError>()
+                .unwrap()
+                .status,
+            StatusCode::BAD_GATEWAY
+        );
+    }
+
+    #[test]
+    fn test_register_new_ongoing_response() {
+        let call = register_new_ongoing(&uuid::Uuid::from_str(UUID).unwrap(), 60);
+        let success = Message::default().message(
+            3,
+            Message::default()
+                .string(1, "agent_cert")
+                .uint64(2, CONNECTION_MODE_PUSH),
+        );
+        let RegisterNewOngoingResponse::Success(success) =
+            (call.decode)(&Decoded::parse(&success.0).unwrap()).unwrap()
+        else {
+            panic!("Expected success");
+        };
+        assert_eq!(success.agent_cert, "agent_cert");
+        assert!(success.connection_mode == config::ConnectionMode::Push);
+        let declined = Message::default().message(2, Message::default().string(1, "No"));
+        assert!(matches!(
+            (call.decode)(&Decoded::parse(&declined.0).unwrap()).unwrap(),
+            RegisterNewOngoingResponse::Declined(RegisterNewOngoingResponseDeclined { reason })
+                if reason == "No"
+        ));
+    }
+
+    #[test]
+    fn test_upload_agent_data() {
+        let data = vec![b'x'; constants::GRPC_MESSAGE_SIZE * 2 + 1];
+        let call = upload_agent_data(
+            &uuid::Uuid::from_str(UUID).unwrap(),
+            "zlib",
+            1700000000,
+            None,
+            &data,
+        );
+        assert_eq!(call.messages.len(), 3);
+        let first = Decoded::parse(&call.messages[0].0).unwrap();
+        assert_eq!(first.string(1).unwrap(), UUID);
+        assert_eq!(first.uint64(3).unwrap(), 1700000000);
+        assert_eq!(first.string(4).unwrap(), "");
+        assert_eq!(
+            call.messages
+                .iter()
+                .map(|message| Decoded::parse(&message.0).unwrap().bytes(5).unwrap().len())
+                .sum::<usize>(),
+            data.len()
+        );
+        assert_eq!(
+            upload_agent_data(&uuid::Uuid::from_str(UUID).unwrap(), "zlib", 1, None, &[])
+                .messages
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_http_status() {
+        assert_eq!(http_status(5), StatusCode::NOT_FOUND);
+        assert_eq!(http_status(12), StatusCode::NOT_FOUND);
+        assert_eq!(http_status(13), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(percent_decode("a%20b%2"), "a b%2");
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Connection establishment on dual-stack hosts along the lines of RFC 8305 ("Happy Eyeballs
+//! Version 2"): The addresses of the receiver are tried alternating between IPv6 and IPv4, starting
+//! with the preferred family. The next attempt starts as soon as the previous one failed, or after
+//! a short delay at the latest, while the previous attempts go on. A broken route for one family
+//! then only costs this delay instead of a connection timeout.
+
+use crate::{constants, dns};
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use serde::Deserialize;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// Address family to try first
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IpPreference {
+    #[default]
+    Ipv6,
+    Ipv4,
+}
+
+fn attempt_delay() -> Duration {
+    Duration::from_millis(constants::HAPPY_EYEBALLS_ATTEMPT_DELAY)
+}
+
+/// Alternate between the families, starting with the preferred one. Within a family, the order of
+/// the resolver is kept.
+pub fn sort(
+    addresses: impl IntoIterator<Item = SocketAddr>,
+    preference: IpPreference,
+) -> Vec<SocketAddr> {
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses
+        .into_iter()
+        .partition(|address| address.is_ipv6() == (preference == IpPreference::Ipv6));
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut sorted = vec![];
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return sorted,
+            (first, second) => sorted.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+pub fn resolve(host: &str, port: u16, preference: IpPreference) -> AnyhowResult<Vec<SocketAddr>> {
+    Ok(sort(
+        dns::lookup(host, port).with_context(|| format!("Cannot resolve {host}"))?,
+        preference,
+    ))
+}
+
+/// Resolving blocks, see dns
+pub async fn resolve_async(
+    host: &str,
+    port: u16,
+    preference: IpPreference,
+) -> AnyhowResult<Vec<SocketAddr>> {
+    let owned_host = String::from(host);
+    Ok(sort(
+        tokio::task::spawn_blocking(move || dns::lookup(&owned_host, port))
+            .await?
+            .with_context(|| format!("Cannot resolve {host}"))?,
+        preference,
+    ))
+}
+
+/// Addresses of the host of a URL, for the HTTP client. It does not sort them itself, but races
+/// the other family against the one of the first address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pinned {
+    host: String,
+    addresses: Vec<SocketAddr>,
+}
+
+impl Pinned {
+    /// None for IP addresses, and if the name does not resolve, the HTTP client then reports
+    /// the error itself
+    pub fn new(url: &reqwest::Url, preference: IpPreference) -> Option<Self> {
+        let host = url.domain()?;
+        let addresses = resolve(host, url.port_or_known_default()?, preference).ok()?;
+        Some(Self {
+            host: String::from(host),
+            addresses,
+        })
+    }
+
+    pub fn apply(
+        &self,
+        builder: reqwest::blocking::ClientBuilder,
+    ) -> reqwest::blocking::ClientBuilder {
+        builder.resolve_to_addrs(&self.host, &self.addresses)
+    }
+}
+
+/// Connect to the first of the addresses accepting the connection
+pub fn connect_timeout(addresses: &[SocketAddr], timeout: Duration) -> AnyhowResult<TcpStream> {
+    let deadline = Instant::now() + timeout;
+    let (sender, receiver) = mpsc::channel();
+    let mut pending = addresses.iter().copied();
+    let mut running = 0;
+    let mut errors = vec![];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            errors.push(String::from("timed out"));
+            break;
+        }
+        match pending.next() {
+            Some(address) => {
+                let sender = sender.clone();
+                std::thread::spawn(move || {
+                    // Nobody listens anymore if another attempt succeeded before
+                    let _ = sender.send((address, TcpStream::connect_timeout(&address, remaining)));
+                });
+                running += 1;
+            }
+            None if running == 0 => break,
+            None => {}
+        }
+        let wait = if pending.len() > 0 {
+            remaining.min(attempt_delay())
+        } else {
+            remaining
+        };
+        match receiver.recv_timeout(wait) {
+            Ok((_, Ok(stream))) => return Ok(stream),
+            Ok((address, Err(err))) => {
+                running -= 1;
+                errors.push(format!("{address}: {err}"));
+            }
+            Err(_) => {}
+        }
+    }
+    Err(anyhow!(if addresses.is_empty() {
+        String::from("No addresses to connect to")
+    } else {
+        errors.join(", ")
+    }))
+}
+
+/// Resolve the host and connect to the first of its addresses accepting the connection. The
+/// attempts still going on are aborted once done.
+pub async fn connect(
+    host: &str,
+    port: u16,
+    preference: IpPreference,
+) -> AnyhowResult<tokio::net::TcpStream> {
+    let addresses = resolve_async(host, port, preference).await?;
+    let mut pending = addresses.iter().copied();
+    let mut attempts = tokio::task::JoinSet::new();
+    let mut errors = vec![];
+    loop {
+        match pending.next() {
+            Some(address) => {
+                attempts
+                    .spawn(async move { (address, tokio::net::TcpStream::connect(address).await) });
+            }
+            None if attempts.is_empty() => break,
+            None => {}
+        }
+        let attempt = if pending.len() > 0 {
+            match tokio::time::timeout(attempt_delay(), attempts.join_next()).await {
+                Ok(attempt) => attempt,
+                Err(_) => continue,
+            }
+        } else {
+            attempts.join_next().await
+        };
+        match attempt {
+            Some(Ok((_, Ok(stream)))) => return Ok(stream),
+            Some(Ok((address, Err(err)))) => errors.push(format!("{address}: {err}")),
+            Some(Err(err)) => errors.push(err.to_string()),
+            None => {}
+        }
+    }
+    Err(anyhow!(if addresses.is_empty() {
+        format!("{host} has no addresses")
+    } else {
+        errors.join(", ")
+    }))
+}
+
+#[cfg(test)]
+mod test_happy_eyeballs {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn addresses(addresses: &[&str]) -> Vec<SocketAddr> {
+        addresses
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_sort() {
+        let resolved = addresses(&[
+            "[2001:db8::1]:8000",
+            "[2001:db8::2]:8000",
+            "[2001:db8::3]:8000",
+            "192.0.2.1:8000",
+        ]);
+        assert_eq!(
+            sort(resolved.clone(), IpPreference::Ipv6),
+            addresses(&[
+                "[2001:db8::1]:8000",
+                "192.0.2.1:8000",
+                "[2001:db8::2]:8000",
+                "[2001:db8::3]:8000",
+            ])
+        );
+        assert_eq!(
+            sort(resolved, IpPreference::Ipv4),
+            addresses(&[
+                "192.0.2.1:8000",
+                "[2001:db8::1]:8000",
+                "[2001:db8::2]:8000",
+                "[2001:db8::3]:8000",
+            ])
+        );
+        assert!(sort(vec![], IpPreference::Ipv6).is_empty());
+    }
+
+    #[test]
+    fn test_pinned() {
+        assert_eq!(
+            Pinned::new(
+                &reqwest::Url::parse("https://localhost:8000/site").unwrap(),
+                IpPreference::Ipv4
+            )
+            .unwrap()
+            .addresses[0],
+            "127.0.0.1:8000".parse().unwrap()
+        );
+        assert!(Pinned::new(
+            &reqwest::Url::parse("https://127.0.0.1:8000/site").unwrap(),
+            IpPreference::Ipv4
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listening = listener.local_addr().unwrap();
+        // Packets to TEST-NET-1 go nowhere, the attempt only ends by the timeout
+        let unreachable = "192.0.2.1:8000".parse().unwrap();
+        let start = Instant::now();
+        let stream = connect_timeout(&[unreachable, listening], Duration::from_secs(10)).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listening);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        drop(listener);
+        assert!(connect_timeout(&[listening], Duration::from_secs(10)).is_err());
+        assert!(connect_timeout(&[], Duration::from_secs(10)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stream = connect("localhost", port, IpPreference::Ipv6)
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap().port(), port);
+
+        drop(listener);
+        assert!(connect("127.0.0.1", port, IpPreference::Ipv6)
+            .await
+            .is_err());
+    }
+}
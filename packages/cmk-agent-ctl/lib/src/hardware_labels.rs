@@ -0,0 +1,217 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Agent labels describing the hardware and firmware of this host, read from SMBIOS/DMI, st.
+//! asset-matching rules in the site work without extra agent plugins. They are sent along with
+//! register-new and, by the daemon, whenever they changed. Only available under Linux so far.
+
+use crate::agent_receiver_api::{self, LabelUpdate};
+use crate::{config, constants, misc, site_spec, types};
+use anyhow::Result as AnyhowResult;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Labels and the files of the DMI directory they are read from
+const DMI_LABELS: [(&str, &str); 4] = [
+    ("cmk/hw-vendor", "sys_vendor"),
+    ("cmk/hw-model", "product_name"),
+    ("cmk/hw-serial", "product_serial"),
+    ("cmk/firmware-version", "bios_version"),
+];
+
+/// Values firmware vendors leave in fields they did not fill in
+const PLACEHOLDERS: [&str; 8] = [
+    "To Be Filled By O.E.M.",
+    "Default string",
+    "Not Specified",
+    "Not Applicable",
+    "System manufacturer",
+    "System Product Name",
+    "System Serial Number",
+    "None",
+];
+
+/// Labels from the given DMI directory. Unreadable fields are left out, eg. the serial number,
+/// which is only readable by root.
+pub fn from_dmi(dmi_dir: &Path) -> types::AgentLabels {
+    DMI_LABELS
+        .iter()
+        .filter_map(|(label, file)| {
+            let value = std::fs::read_to_string(dmi_dir.join(file)).ok()?;
+            let value = value.trim();
+            (!value.is_empty() && !PLACEHOLDERS.contains(&value))
+                .then(|| (String::from(*label), String::from(value)))
+        })
+        .collect()
+}
+
+pub fn collect() -> types::AgentLabels {
+    #[cfg(target_os = "linux")]
+    return from_dmi(Path::new(constants::DMI_DIR));
+    #[cfg(not(target_os = "linux"))]
+    types::AgentLabels::new()
+}
+
+/// Send the labels to the active connections which did not get them yet. Returns the labels
+/// each connection got.
+fn update_labels(
+    registry: &config::Registry,
+    label_api: &impl LabelUpdate,
+    labels: &types::AgentLabels,
+    mut sent: HashMap<uuid::Uuid, types::AgentLabels>,
+    now: u64,
+) -> HashMap<uuid::Uuid, types::AgentLabels> {
+    for (site_id, connection) in registry
+        .get_push_connections()
+        .chain(registry.get_standard_pull_connections())
+        .filter(|(_, connection)| !connection.is_expired(now))
+    {
+        let uuid = connection.trust.uuid;
+        if sent.get(&uuid) == Some(labels) {
+            continue;
+        }
+        match site_spec::make_site_url(site_id, &connection.receiver_port)
+            .and_then(|url| label_api.update_labels(&url, &connection.trust, labels))
+        {
+            Ok(()) => {
+                info!("{}: Updated hardware labels", site_id);
+                sent.insert(uuid, labels.clone());
+            }
+            Err(err) => warn!("{}: Error updating hardware labels. ({:#})", site_id, err),
+        }
+    }
+    sent
+}
+
+pub async fn daemon(
+    mut registry: config::Registry,
+    client_config: config::ClientConfig,
+) -> AnyhowResult<()> {
+    misc::sleep_randomly().await;
+    let label_api = Arc::new(agent_receiver_api::Api::new(&client_config));
+    let mut sent = HashMap::new();
+    loop {
+        debug!("Checking hardware labels for changes.");
+        registry.refresh()?;
+        let begin = Instant::now();
+        let api = Arc::clone(&label_api);
+        let connections = registry.clone();
+        // The receiver API is blocking
+        sent = tokio::task::spawn_blocking(move || {
+            update_labels(
+                &connections,
+                api.as_ref(),
+                &collect(),
+                sent,
+                misc::unix_now(),
+            )
+        })
+        .await?;
+        tokio::time::sleep(
+            Duration::from_secs(constants::HARDWARE_LABELS_REFRESH_INTERVAL)
+                .saturating_sub(begin.elapsed()),
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod test_hardware_labels {
+    use super::*;
+    use config::test_helpers::TestRegistry;
+    use std::cell::RefCell;
+
+    fn labels(labels: &[(&str, &str)]) -> types::AgentLabels {
+        labels
+            .iter()
+            .map(|(key, value)| (String::from(*key), String::from(*value)))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_dmi() {
+        let dir = tempfile::tempdir().unwrap();
+        for (file, content) in [
+            ("sys_vendor", "ACME Corp.\n"),
+            ("product_name", "Rackserver 3000\n"),
+            ("product_serial", "To Be Filled By O.E.M.\n"),
+            ("bios_version", "  \n"),
+        ] {
+            std::fs::write(dir.path().join(file), content).unwrap();
+        }
+        assert_eq!(
+            from_dmi(dir.path()),
+            labels(&[
+                ("cmk/hw-vendor", "ACME Corp."),
+                ("cmk/hw-model", "Rackserver 3000"),
+            ])
+        );
+        std::fs::write(dir.path().join("product_serial"), "SN-42\n").unwrap();
+        std::fs::write(dir.path().join("bios_version"), "1.2.3\n").unwrap();
+        std::fs::remove_file(dir.path().join("sys_vendor")).unwrap();
+        assert_eq!(
+            from_dmi(dir.path()),
+            labels(&[
+                ("cmk/hw-model", "Rackserver 3000"),
+                ("cmk/hw-serial", "SN-42"),
+                ("cmk/firmware-version", "1.2.3"),
+            ])
+        );
+    }
+
+    #[derive(Default)]
+    struct MockLabelApi {
+        failing: bool,
+        updated: RefCell<Vec<String>>,
+    }
+
+    impl LabelUpdate for MockLabelApi {
+        fn update_labels(
+            &self,
+            base_url: &reqwest::Url,
+            _connection: &config::TrustedConnection,
+            _agent_labels: &types::AgentLabels,
+        ) -> AnyhowResult<()> {
+            self.updated.borrow_mut().push(base_url.to_string());
+            if self.failing {
+                anyhow::bail!("unreachable")
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_update_labels() {
+        let mut expired = config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4());
+        expired.expires = Some(1000);
+        let r = TestRegistry::new()
+            .add_connection(
+                &config::ConnectionMode::Push,
+                "server/push",
+                config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4()),
+            )
+            .add_connection(&config::ConnectionMode::Pull, "server/expired", expired);
+        let hardware = labels(&[("cmk/hw-vendor", "ACME Corp.")]);
+
+        let failing = MockLabelApi {
+            failing: true,
+            ..MockLabelApi::default()
+        };
+        let sent = update_labels(&r.registry, &failing, &hardware, HashMap::new(), 2000);
+        assert!(sent.is_empty());
+
+        let api = MockLabelApi::default();
+        let sent = update_labels(&r.registry, &api, &hardware, sent, 2000);
+        assert_eq!(api.updated.take(), ["https://server:8000/push"]);
+        // Unchanged labels are not sent again
+        let sent = update_labels(&r.registry, &api, &hardware, sent, 2000);
+        assert!(api.updated.take().is_empty());
+        let changed = labels(&[("cmk/hw-vendor", "ACME Inc.")]);
+        update_labels(&r.registry, &api, &changed, sent, 2000);
+        assert_eq!(api.updated.take(), ["https://server:8000/push"]);
+    }
+}
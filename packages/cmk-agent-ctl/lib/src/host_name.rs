@@ -0,0 +1,263 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Derivation of the name under which this host registers, so that provisioning images do not
+//! need per-host parameters.
+
+use crate::cloud_metadata;
+use anyhow::{bail, Context, Error as AnyhowError, Result as AnyhowResult};
+use std::net::{IpAddr, UdpSocket};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostFact {
+    Fqdn,
+    Short,
+    CloudInstanceId,
+    ReverseDns,
+}
+
+impl HostFact {
+    const ALL: [Self; 4] = [
+        Self::Fqdn,
+        Self::Short,
+        Self::CloudInstanceId,
+        Self::ReverseDns,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Fqdn => "fqdn",
+            Self::Short => "short",
+            Self::CloudInstanceId => "cloud-instance-id",
+            Self::ReverseDns => "reverse-dns",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::Fqdn => "fully qualified domain name",
+            Self::Short => "short host name",
+            Self::CloudInstanceId => "cloud instance ID",
+            Self::ReverseDns => "reverse DNS name of the primary address",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|fact| fact.name() == name)
+    }
+
+    fn names() -> String {
+        Self::ALL
+            .map(|fact| format!("\"{}\"", fact.name()))
+            .join(", ")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Fact(HostFact),
+}
+
+/// Either a single strategy, eg. "fqdn", or a template combining them, eg.
+/// "{short}-{cloud-instance-id}"
+#[derive(Debug, Clone, PartialEq, Eq, serde_with::DeserializeFromStr)]
+pub struct HostNameSource(Vec<TemplatePart>);
+
+impl FromStr for HostNameSource {
+    type Err = AnyhowError;
+
+    fn from_str(s: &str) -> AnyhowResult<Self> {
+        if let Some(fact) = HostFact::from_name(s) {
+            return Ok(Self(vec![TemplatePart::Fact(fact)]));
+        }
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(String::from(&rest[..start])));
+            }
+            let end = start
+                + rest[start..]
+                    .find('}')
+                    .context(format!("Unclosed placeholder in host name template '{s}'"))?;
+            let name = &rest[start + 1..end];
+            parts.push(TemplatePart::Fact(HostFact::from_name(name).context(
+                format!(
+                    "Unknown placeholder '{{{name}}}' in host name template '{s}', expected one of {}",
+                    HostFact::names()
+                ),
+            )?));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(String::from(rest)));
+        }
+        if !parts
+            .iter()
+            .any(|part| matches!(part, TemplatePart::Fact(_)))
+        {
+            bail!(
+                "'{s}' is neither one of {} nor a template containing a placeholder like '{{short}}'",
+                HostFact::names()
+            );
+        }
+        Ok(Self(parts))
+    }
+}
+
+impl HostNameSource {
+    pub fn derive(&self, facts: &impl HostFacts) -> AnyhowResult<String> {
+        let mut host_name = String::new();
+        for part in &self.0 {
+            match part {
+                TemplatePart::Literal(literal) => host_name.push_str(literal),
+                TemplatePart::Fact(fact) => host_name.push_str(
+                    &facts
+                        .get(*fact)
+                        .context(format!("Failed to determine the {}", fact.description()))?,
+                ),
+            }
+        }
+        Ok(host_name)
+    }
+}
+
+pub trait HostFacts {
+    fn get(&self, fact: HostFact) -> AnyhowResult<String>;
+}
+
+/// Facts about the host we are running on
+pub struct System;
+
+impl HostFacts for System {
+    fn get(&self, fact: HostFact) -> AnyhowResult<String> {
+        match fact {
+            HostFact::Fqdn => fqdn(),
+            HostFact::Short => Ok(String::from(short_name(&system_host_name()?))),
+            HostFact::CloudInstanceId => cloud_instance_id(),
+            HostFact::ReverseDns => reverse_dns(primary_address()?),
+        }
+    }
+}
+
+fn system_host_name() -> AnyhowResult<String> {
+    Ok(String::from(
+        gethostname::gethostname()
+            .to_str()
+            .context("Failed to transform host name to str")?,
+    ))
+}
+
+fn short_name(host_name: &str) -> &str {
+    host_name.split('.').next().unwrap_or(host_name)
+}
+
+#[cfg(unix)]
+const AI_CANONNAME: i32 = nix::libc::AI_CANONNAME;
+#[cfg(windows)]
+const AI_CANONNAME: i32 = winapi::shared::ws2def::AI_CANONNAME;
+
+fn fqdn() -> AnyhowResult<String> {
+    let host_name = system_host_name()?;
+    let hints = dns_lookup::AddrInfoHints {
+        flags: AI_CANONNAME,
+        ..dns_lookup::AddrInfoHints::default()
+    };
+    let canonical_name = dns_lookup::getaddrinfo(Some(&host_name), None, Some(hints))
+        .ok()
+        .and_then(|mut addr_infos| {
+            addr_infos.find_map(|addr_info| addr_info.ok().and_then(|info| info.canonname))
+        });
+    match canonical_name {
+        Some(name) if name.contains('.') => Ok(name),
+        _ if host_name.contains('.') => Ok(host_name),
+        _ => bail!("Host name {host_name} does not resolve to a fully qualified domain name"),
+    }
+}
+
+/// The source address of the default route, connecting a UDP socket does not send anything
+fn primary_address() -> AnyhowResult<IpAddr> {
+    // Documentation addresses (RFC 5737, RFC 3849), only used for the route lookup
+    for (local, remote) in [("0.0.0.0:0", "192.0.2.1:9"), ("[::]:0", "[2001:db8::1]:9")] {
+        if let Ok(address) = UdpSocket::bind(local)
+            .and_then(|socket| socket.connect(remote).map(|_| socket))
+            .and_then(|socket| socket.local_addr())
+        {
+            return Ok(address.ip());
+        }
+    }
+    bail!("Failed to determine the primary address, no default route")
+}
+
+fn reverse_dns(address: IpAddr) -> AnyhowResult<String> {
+    let name = dns_lookup::lookup_addr(&address)
+        .context(format!("Reverse DNS lookup of {address} failed"))?;
+    // getnameinfo falls back to the numeric address
+    if IpAddr::from_str(&name).is_ok() {
+        bail!("No reverse DNS entry for {address}");
+    }
+    Ok(name)
+}
+
+fn cloud_instance_id() -> AnyhowResult<String> {
+    cloud_metadata::MetadataService::new()?.instance_id()
+}
+
+#[cfg(test)]
+mod test_host_name_source {
+    use super::*;
+
+    struct MockFacts;
+
+    impl HostFacts for MockFacts {
+        fn get(&self, fact: HostFact) -> AnyhowResult<String> {
+            match fact {
+                HostFact::Fqdn => Ok(String::from("web01.example.com")),
+                HostFact::Short => Ok(String::from("web01")),
+                HostFact::CloudInstanceId => Ok(String::from("i-0abc")),
+                HostFact::ReverseDns => bail!("No reverse DNS entry"),
+            }
+        }
+    }
+
+    fn derive(source: &str) -> AnyhowResult<String> {
+        HostNameSource::from_str(source)?.derive(&MockFacts)
+    }
+
+    #[test]
+    fn test_strategy() {
+        assert_eq!(derive("fqdn").unwrap(), "web01.example.com");
+        assert_eq!(derive("short").unwrap(), "web01");
+        assert_eq!(derive("cloud-instance-id").unwrap(), "i-0abc");
+    }
+
+    #[test]
+    fn test_template() {
+        assert_eq!(
+            derive("{short}-{cloud-instance-id}").unwrap(),
+            "web01-i-0abc"
+        );
+        assert_eq!(derive("aws-{short}.prod").unwrap(), "aws-web01.prod");
+    }
+
+    #[test]
+    fn test_invalid() {
+        for source in ["fdqn", "web01", "{short", "{short}-{name}", ""] {
+            assert!(HostNameSource::from_str(source).is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn test_unavailable_fact() {
+        assert!(derive("{short}-{reverse-dns}").is_err());
+    }
+
+    #[test]
+    fn test_short_name() {
+        assert_eq!(short_name("web01.example.com"), "web01");
+        assert_eq!(short_name("web01"), "web01");
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Periodic self-checks of the daemon, st. a certificate no longer issued by its root
+//! certificate, a private key not belonging to its certificate or a modified registry is noticed
+//! before the connections fail.
+
+use crate::config::{self, JSONLoader};
+use crate::{certs, constants, misc};
+use anyhow::Result as AnyhowResult;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Outcome of the last integrity check, reported by the status
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Unix timestamp of the check
+    pub timestamp: u64,
+    pub checked_connections: usize,
+    pub failures: Vec<String>,
+}
+
+impl JSONLoader for IntegrityReport {}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    fn save(&self, path: &Path) -> AnyhowResult<()> {
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        #[cfg(unix)]
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        Ok(())
+    }
+}
+
+/// Check the registry file and the identity of every registered connection
+pub fn check(registry: &config::Registry) -> IntegrityReport {
+    let mut failures = vec![];
+    if let Err(err) = verify_registry(registry) {
+        failures.push(format!("{err:#}"));
+    }
+    let connections = registry
+        .get_push_connections()
+        .chain(registry.get_standard_pull_connections())
+        .map(|(site_id, connection)| (site_id.to_string(), &connection.trust))
+        .chain(
+            registry
+                .get_imported_pull_connections()
+                .map(|connection| (connection.uuid.to_string(), connection)),
+        )
+        .collect::<Vec<_>>();
+    for (name, connection) in connections.iter() {
+        if let Err(err) = certs::check_identity(
+            &connection.certificate,
+            &connection.private_key,
+            &connection.root_cert,
+        ) {
+            failures.push(format!("Connection {name}: {err:#}"));
+        }
+    }
+    IntegrityReport {
+        timestamp: misc::unix_now(),
+        checked_connections: connections.len(),
+        failures,
+    }
+}
+
+/// The registry may be caught while being saved, so a mismatch is only reported if it persists
+fn verify_registry(registry: &config::Registry) -> AnyhowResult<()> {
+    registry.verify_checksum().or_else(|_| {
+        std::thread::sleep(Duration::from_millis(constants::INTEGRITY_RECHECK_DELAY));
+        registry.verify_checksum()
+    })
+}
+
+pub async fn daemon(mut registry: config::Registry, report_path: PathBuf) -> AnyhowResult<()> {
+    loop {
+        let report = match registry.refresh() {
+            Ok(_) => {
+                let registry = registry.clone();
+                tokio::task::spawn_blocking(move || check(&registry)).await?
+            }
+            Err(err) => IntegrityReport {
+                timestamp: misc::unix_now(),
+                checked_connections: 0,
+                failures: vec![format!("Failed to load registry: {err:#}")],
+            },
+        };
+        for failure in report.failures.iter() {
+            error!("Integrity check failed: {}", failure);
+        }
+        if report.is_ok() {
+            debug!(
+                "Integrity check passed for {} connections",
+                report.checked_connections
+            );
+        }
+        if let Err(err) = report.save(&report_path) {
+            warn!(
+                "Failed to save integrity check to {:?}: {:#}",
+                report_path, err
+            );
+        }
+        tokio::time::sleep(Duration::from_secs(constants::INTEGRITY_CHECK_INTERVAL)).await;
+    }
+}
+
+#[cfg(test)]
+mod test_integrity {
+    use super::*;
+    use crate::config::test_helpers::TestRegistry;
+    use crate::config::{ConnectionMode, TrustedConnection, TrustedConnectionWithRemote};
+    use crate::site_spec;
+    use std::str::FromStr;
+
+    fn connection(root_cert: &str, root_key: &str) -> TrustedConnectionWithRemote {
+        let uuid = uuid::Uuid::new_v4();
+        let (csr, private_key) = certs::make_csr(&uuid.to_string()).unwrap();
+        TrustedConnectionWithRemote {
+            trust: TrustedConnection {
+                uuid,
+                private_key,
+                certificate: certs::sign_csr(root_cert, root_key, &csr, 30).unwrap(),
+                root_cert: String::from(root_cert),
+                source_address: None,
+                receiver_protocol: Default::default(),
+                transport: Default::default(),
+                data_source: None,
+            },
+            receiver_port: 8000,
+            push_interval: None,
+            hostname: None,
+            tags: Default::default(),
+            expires: None,
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let (root_cert, root_key) = certs::make_ca("root", 30).unwrap();
+        let mut test_registry = TestRegistry::new();
+        let registry = &mut test_registry.registry;
+        registry.register_connection(
+            &ConnectionMode::Push,
+            &site_spec::SiteID::from_str("server/push-site").unwrap(),
+            connection(&root_cert, &root_key),
+        );
+        registry.save().unwrap();
+        let report = check(registry);
+        assert!(report.is_ok());
+        assert_eq!(report.checked_connections, 1);
+
+        let (other_root_cert, _) = certs::make_ca("other root", 30).unwrap();
+        let mut broken = connection(&root_cert, &root_key);
+        broken.trust.root_cert = other_root_cert;
+        registry.register_connection(
+            &ConnectionMode::Pull,
+            &site_spec::SiteID::from_str("server/pull-site").unwrap(),
+            broken,
+        );
+        let report = check(registry);
+        assert_eq!(report.checked_connections, 2);
+        assert_eq!(
+            report.failures,
+            vec![String::from(
+                "Connection server/pull-site: Certificate is not issued by the root certificate"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_check_modified_registry() {
+        let test_registry = TestRegistry::new();
+        let registry = &test_registry.registry;
+        registry.save().unwrap();
+        assert!(check(registry).is_ok());
+        fs::write(config::Registry::checksum_path(registry.path()), "0").unwrap();
+        let report = check(registry);
+        assert_eq!(report.failures.len(), 1);
+        assert!(report.failures[0].contains("does not match its checksum"));
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(constants::INTEGRITY_CHECK_FILE);
+        let report = IntegrityReport {
+            timestamp: 1000,
+            checked_connections: 2,
+            failures: vec![String::from("Connection server/site: Invalid certificate")],
+        };
+        report.save(&path).unwrap();
+        assert_eq!(IntegrityReport::load(&path).unwrap(), report);
+    }
+}
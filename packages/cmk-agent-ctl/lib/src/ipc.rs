@@ -0,0 +1,289 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+#[cfg(windows)]
+use crate::service_account;
+use crate::{constants, scheduler_state, supervisor};
+use anyhow::{Context, Result as AnyhowResult};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::{BufRead, BufReader, Read, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt};
+
+/// Request to the running daemon. Requests and responses are exchanged as single lines of JSON
+/// via a Unix socket in the home directory, or via a named pipe under Windows.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    /// Push to the given connection (site ID or UUID) or to all push connections right away
+    PushNow { connection: Option<String> },
+    /// Change the log level, or restore the configured one if None
+    SetLogLevel { spec: Option<String> },
+    /// Re-read the config file and apply the settings which do not require a restart
+    Reload,
+    /// Report the state of the daemon itself
+    Status,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub version: String,
+    /// Unix timestamp of the start of the daemon
+    pub started: u64,
+    /// Lifecycle state, eg. running or paused
+    pub state: String,
+    /// Log level in effect
+    pub log_level: String,
+    /// Not reported by daemons of older versions
+    #[serde(default)]
+    pub scheduler: scheduler_state::SchedulerReport,
+    /// Restarted tasks, keyed by task. Not reported by daemons of older versions.
+    #[serde(default)]
+    pub restarts: BTreeMap<String, supervisor::TaskReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct PushResult {
+    pub site_id: String,
+    pub uuid: String,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    PushNow {
+        results: Vec<PushResult>,
+    },
+    LogLevel {
+        spec: String,
+    },
+    /// The log level in effect after reloading
+    Reloaded {
+        log_level: String,
+    },
+    Status {
+        status: DaemonStatus,
+    },
+    Error {
+        message: String,
+    },
+}
+
+async fn handle_client<F: Future<Output = Response>>(
+    stream: impl AsyncRead + AsyncWrite,
+    handler: &impl Fn(Request) -> F,
+) -> AnyhowResult<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut line = String::new();
+    tokio::time::timeout(
+        Duration::from_secs(constants::IPC_READ_TIMEOUT),
+        tokio::io::BufReader::new(reader).read_line(&mut line),
+    )
+    .await
+    .context("Timed out reading request")??;
+    let response = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => handler(request).await,
+        Err(err) => Response::Error {
+            message: format!("Invalid request: {err}"),
+        },
+    };
+    writer
+        .write_all(format!("{}\n", serde_json::to_string(&response)?).as_bytes())
+        .await?;
+    Ok(())
+}
+
+/// Answer requests on the socket at the given path, one at a time.
+#[cfg(unix)]
+pub async fn serve<F: Future<Output = Response>>(
+    path: &Path,
+    handler: impl Fn(Request) -> F,
+) -> AnyhowResult<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if path.exists() {
+        // Left over from a previous daemon
+        std::fs::remove_file(path)?;
+    }
+    let listener =
+        tokio::net::UnixListener::bind(path).context(format!("Failed to bind to {:?}", path))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                if let Err(err) = authorize(&stream) {
+                    warn!("Rejecting IPC request. ({})", err);
+                    continue;
+                }
+                if let Err(err) = handle_client(stream, &handler).await {
+                    warn!("Error handling IPC request. ({})", err);
+                }
+            }
+            Err(err) => warn!("Error accepting IPC connection. ({})", err),
+        }
+    }
+}
+
+/// Only root and the user running the daemon may send requests. The peer credentials are
+/// obtained via SO_PEERCRED under Linux and getpeereid under macOS and BSD.
+#[cfg(unix)]
+fn authorize(stream: &tokio::net::UnixStream) -> AnyhowResult<()> {
+    let uid = stream
+        .peer_cred()
+        .context("Failed to determine peer credentials")?
+        .uid();
+    if uid == 0 || uid == nix::unistd::geteuid().as_raw() {
+        return Ok(());
+    }
+    anyhow::bail!("Peer with user id {uid} is not authorized")
+}
+
+/// Answer requests on the named pipe of the daemon, one at a time. Its security descriptor only
+/// grants access to LocalSystem, the administrators, the account running the daemon and the
+/// service account, see service_account.
+#[cfg(windows)]
+pub async fn serve<F: Future<Output = Response>>(
+    _path: &Path,
+    handler: impl Fn(Request) -> F,
+) -> AnyhowResult<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut security = service_account::PipeSecurity::control_pipe()
+        .context("Failed to set up the security descriptor of the control pipe")?;
+    // Fails if another process, eg. a second daemon, already serves the pipe
+    let mut server = unsafe {
+        ServerOptions::new()
+            .first_pipe_instance(true)
+            .reject_remote_clients(true)
+            .create_with_security_attributes_raw(constants::WIN_CONTROL_PIPE, security.as_raw())
+    }
+    .context(format!("Failed to create {}", constants::WIN_CONTROL_PIPE))?;
+    loop {
+        let connected = server.connect().await;
+        // The next client connects to a new instance of the pipe
+        let client = std::mem::replace(&mut server, unsafe {
+            ServerOptions::new()
+                .reject_remote_clients(true)
+                .create_with_security_attributes_raw(constants::WIN_CONTROL_PIPE, security.as_raw())
+        }?);
+        match connected {
+            Ok(()) => {
+                if let Err(err) = handle_client(client, &handler).await {
+                    warn!("Error handling IPC request. ({})", err);
+                }
+            }
+            Err(err) => warn!("Error accepting IPC connection. ({})", err),
+        }
+    }
+}
+
+fn exchange(mut stream: impl Read + Write, request: &Request) -> AnyhowResult<Response> {
+    writeln!(stream, "{}", serde_json::to_string(request)?)?;
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .context("Failed to receive response from daemon")?;
+    serde_json::from_str(&line).context(format!("Invalid response from daemon: {line}"))
+}
+
+#[cfg(unix)]
+pub fn request(path: &Path, request: &Request) -> AnyhowResult<Response> {
+    let stream = UnixStream::connect(path).context(format!(
+        "Failed to connect to {:?}, is the agent controller daemon running?",
+        path
+    ))?;
+    exchange(stream, request)
+}
+
+#[cfg(windows)]
+pub fn request(_path: &Path, request: &Request) -> AnyhowResult<Response> {
+    let stream = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(constants::WIN_CONTROL_PIPE)
+        .context(format!(
+            "Failed to connect to {}, is the agent controller daemon running?",
+            constants::WIN_CONTROL_PIPE
+        ))?;
+    exchange(stream, request)
+}
+
+#[cfg(all(test, unix))]
+mod test_ipc {
+    use super::*;
+
+    #[test]
+    fn test_request_response() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sock");
+        std::fs::write(&path, "stale").unwrap();
+        let server_path = path.clone();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(serve(
+                &server_path,
+                |request| async move {
+                    match request {
+                        Request::PushNow { connection } => Response::PushNow {
+                            results: vec![PushResult {
+                                site_id: connection.unwrap_or_default(),
+                                uuid: String::from("uuid"),
+                                error: None,
+                            }],
+                        },
+                        Request::SetLogLevel { spec } => Response::LogLevel {
+                            spec: spec.unwrap_or_default(),
+                        },
+                        Request::Reload | Request::Status => Response::Error {
+                            message: String::from("Not supported"),
+                        },
+                    }
+                },
+            ))
+        });
+        // Wait until the stale file was replaced by the socket
+        let response = (0..500)
+            .find_map(|_| {
+                let response = request(
+                    &path,
+                    &Request::PushNow {
+                        connection: Some(String::from("server/site")),
+                    },
+                );
+                if response.is_err() {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                response.ok()
+            })
+            .unwrap();
+
+        assert_eq!(
+            response,
+            Response::PushNow {
+                results: vec![PushResult {
+                    site_id: String::from("server/site"),
+                    uuid: String::from("uuid"),
+                    error: None,
+                }]
+            }
+        );
+    }
+
+    #[test]
+    fn test_request_no_daemon() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(request(
+            &dir.path().join("test.sock"),
+            &Request::PushNow { connection: None }
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,92 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Encryption of the agent output served in legacy pull mode, ie. without TLS. It is the one of
+//! the agents with an encryption passphrase ("03": AES-256-CBC with a key derived via
+//! PBKDF2-HMAC-SHA256), st. the site decrypts it with the passphrase of its agent encryption
+//! settings. The same as 'openssl enc -aes-256-cbc -md sha256 -iter 10000', prefixed with "03".
+
+use anyhow::Result as AnyhowResult;
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::symm::{self, Cipher};
+
+const PROTOCOL: &[u8] = b"03";
+const SALTED: &[u8] = b"Salted__";
+const SALT_LEN: usize = 8;
+const KEY_LEN: usize = 32;
+const IV_LEN: usize = 16;
+const ITERATIONS: usize = 10_000;
+
+fn key_and_iv(passphrase: &str, salt: &[u8]) -> AnyhowResult<([u8; KEY_LEN], [u8; IV_LEN])> {
+    let mut derived = [0; KEY_LEN + IV_LEN];
+    pbkdf2_hmac(
+        passphrase.as_bytes(),
+        salt,
+        ITERATIONS,
+        MessageDigest::sha256(),
+        &mut derived,
+    )?;
+    let mut key = [0; KEY_LEN];
+    let mut iv = [0; IV_LEN];
+    key.copy_from_slice(&derived[..KEY_LEN]);
+    iv.copy_from_slice(&derived[KEY_LEN..]);
+    Ok((key, iv))
+}
+
+fn encrypt_with_salt(passphrase: &str, salt: &[u8], data: &[u8]) -> AnyhowResult<Vec<u8>> {
+    let (key, iv) = key_and_iv(passphrase, salt)?;
+    let mut encrypted = [PROTOCOL, SALTED, salt].concat();
+    encrypted.extend(symm::encrypt(Cipher::aes_256_cbc(), &key, Some(&iv), data)?);
+    Ok(encrypted)
+}
+
+pub fn encrypt(passphrase: &str, data: &[u8]) -> AnyhowResult<Vec<u8>> {
+    let mut salt = [0; SALT_LEN];
+    openssl::rand::rand_bytes(&mut salt)?;
+    encrypt_with_salt(passphrase, &salt, data)
+}
+
+#[cfg(test)]
+mod test_legacy_encryption {
+    use super::*;
+
+    const OUTPUT: &[u8] = b"<<<check_mk>>>\nVersion: 2.3.0\n";
+    const SALT: &[u8] = &[0, 1, 2, 3, 4, 5, 6, 7];
+
+    #[test]
+    fn test_encrypt_with_salt() {
+        // printf '<<<check_mk>>>\nVersion: 2.3.0\n' \
+        //   | openssl enc -aes-256-cbc -md sha256 -iter 10000 -k secret -S 0001020304050607
+        let ciphertext = [
+            0xac, 0x8f, 0x1f, 0xe0, 0xc5, 0x06, 0xf2, 0x77, 0x25, 0x4c, 0xeb, 0xf8, 0x88, 0x85,
+            0x06, 0xf3, 0xfc, 0xb4, 0x69, 0x2f, 0x1a, 0xb3, 0x19, 0x62, 0xe8, 0xf8, 0xab, 0x6e,
+            0x50, 0xf7, 0x80, 0xbf,
+        ];
+        assert_eq!(
+            encrypt_with_salt("secret", SALT, OUTPUT).unwrap(),
+            [b"03Salted__", SALT, &ciphertext].concat()
+        );
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let encrypted = encrypt("secret", OUTPUT).unwrap();
+        assert_eq!(&encrypted[..10], b"03Salted__");
+        let salt = &encrypted[10..10 + SALT_LEN];
+        let (key, iv) = key_and_iv("secret", salt).unwrap();
+        assert_eq!(
+            symm::decrypt(
+                Cipher::aes_256_cbc(),
+                &key,
+                Some(&iv),
+                &encrypted[10 + SALT_LEN..]
+            )
+            .unwrap(),
+            OUTPUT
+        );
+        // Salted with a new salt every time
+        assert_ne!(encrypt("secret", OUTPUT).unwrap(), encrypted);
+    }
+}
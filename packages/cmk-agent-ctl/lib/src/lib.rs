@@ -0,0 +1,464 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! The Checkmk agent controller. Instead of running the cmk-agent-ctl binary, programs can register
+//! and manage the connections of a host in-process: Rust programs via [`controller::Controller`],
+//! programs written in other languages via the C interface in [`ffi`].
+
+mod agent_receiver_api;
+pub mod certs;
+mod change_detection;
+mod cli;
+mod cli_spec;
+mod cloud_metadata;
+pub mod configuration;
+mod connection_stats;
+mod constants;
+mod container;
+pub mod controller;
+mod data_source;
+mod delta;
+mod dns;
+pub mod error_code;
+pub mod ffi;
+mod fips;
+mod grpc;
+mod happy_eyeballs;
+mod hardware_labels;
+mod host_name;
+mod http_trace;
+mod integrity;
+mod ipc;
+mod key_store;
+mod legacy_encryption;
+mod lifecycle;
+#[cfg(windows)]
+mod log_ext;
+#[cfg(windows)]
+pub mod mailslot_transport;
+mod messages;
+mod metrics;
+mod misc;
+pub mod modes;
+mod monitoring_data;
+mod openssl_tls;
+mod output;
+#[cfg(unix)]
+mod pac;
+mod payload_memory;
+mod payload_stats;
+mod post_processing;
+#[cfg(unix)]
+mod privileges;
+mod proxy;
+mod pull_tunnel;
+mod push_failover;
+mod push_spool;
+mod quic;
+mod realtime;
+mod registration_throttle;
+mod relay;
+mod reregistration;
+mod response_cache;
+mod rest_api;
+mod scheduler_state;
+#[cfg(unix)]
+mod sd_notify;
+mod section_filter;
+mod self_update;
+#[cfg(windows)]
+mod service;
+#[cfg(windows)]
+mod service_account;
+mod setup;
+pub mod site_spec;
+mod status_section;
+mod supervisor;
+mod system_log;
+mod tls_failure;
+mod tls_server;
+mod transport;
+pub mod types;
+mod usage_stats;
+mod vault;
+mod websocket;
+#[cfg(windows)]
+mod win_registry;
+use anyhow::{bail, Context, Result as AnyhowResult};
+use configuration::config;
+use configuration::config::TOMLLoaderMissingSafe;
+use log::{info, warn};
+use modes::bootstrap::bootstrap;
+use modes::completions::completions;
+use modes::daemon::daemon;
+use modes::data_source::set_data_source;
+use modes::delete_connection::{delete, delete_all, delete_selected};
+use modes::doctor::doctor;
+use modes::dump::dump;
+use modes::import_connection::import;
+use modes::legacy_pull::legacy_pull;
+use modes::log_level::log_level;
+use modes::migrate_pull_to_tls::migrate_pull_to_tls;
+use modes::migrate_updater::migrate_updater;
+#[cfg(feature = "mock-receiver")]
+use modes::mock_receiver::mock_receiver;
+use modes::pull::pull;
+use modes::pull_once::pull_once;
+use modes::push::handle_push_cycle as push;
+use modes::push::set_push_interval;
+use modes::push_now::push_now;
+use modes::registration;
+use modes::relay::relay;
+use modes::reload::reload;
+use modes::rename_host::rename_host;
+use modes::renew_certificate::renew_certificate;
+use modes::self_update::self_update;
+use modes::source_address::set_source_address;
+use modes::spool::spool;
+use modes::status::{status, StatusOptions};
+use modes::support_bundle::support_bundle;
+use modes::tag::set_tags;
+use modes::test_connection::test_connection;
+use modes::vault::vault;
+pub use setup::{init, log_fatal_error};
+
+#[cfg(windows)]
+pub use misc::validate_elevation;
+
+pub fn run_requested_mode(cli: cli::Cli, paths: setup::PathResolver) -> AnyhowResult<()> {
+    if let cli::Mode::Completions(completions_opts) = &cli.mode {
+        // Before migrating, st. completing a command line never modifies the registry
+        let registry = completions_opts
+            .connections
+            .then(|| config::Registry::from_file(&paths.registry_path))
+            .transpose()?;
+        return completions(completions_opts.shell, registry.as_ref());
+    }
+    if let cli::Mode::Paths(paths_opts) = &cli.mode {
+        // Like completions, without touching the registry
+        return modes::paths::paths(&paths, paths_opts.json);
+    }
+    configuration::migrate::migrate_registered_connections(&paths.registry_path)?;
+    usage_stats::init(&paths.usage_stats_path);
+    agent_socket_operational(&cli.mode)?;
+    if let cli::Mode::Doctor(doctor_opts) = cli.mode {
+        // Before loading configuration and registry, st. problems with them are reported as well
+        return doctor(&paths, doctor_opts);
+    }
+
+    let runtime_config = config::RuntimeConfig::load_missing_safe(&paths.config_path)?;
+    let mut registry = match runtime_config.quarantine_invalid_connections() {
+        true => config::Registry::from_file_quarantining(&paths.registry_path),
+        false => config::Registry::from_file(&paths.registry_path),
+    }
+    .with_context(|| {
+        format!(
+            "Error while loading registered connections from {:?}.",
+            &paths.registry_path
+        )
+    })?;
+    registry.set_key_storage(config::KeyStorage::new(&runtime_config));
+    dns::init(runtime_config.dns().with_overrides(&cli.resolve));
+    setup::apply_logging_config(&config::LoggingConfig::new(&runtime_config, &cli))?;
+    info!(
+        "Loaded config from '{:?}', connection registry from '{:?}'",
+        &paths.config_path, &paths.registry_path
+    );
+    let push_config = config::PushConfig::new(&runtime_config);
+    let push_spool = push_spool::PushSpool::new(
+        &paths.push_spool_path,
+        push_config.push_spool_size,
+        push_config.push_spool_max_size,
+    );
+    match cli.mode {
+        cli::Mode::Register(reg_opts) => registration::register_existing(
+            &config::RegisterExistingConfig::new(runtime_config, reg_opts)?,
+            &mut registry,
+            cli.output,
+        ),
+        cli::Mode::RegisterNew(reg_new_opts) => {
+            // User-defined labels supersede the hardware labels
+            let mut agent_labels =
+                if reg_new_opts.hardware_labels || runtime_config.hardware_labels() {
+                    hardware_labels::collect()
+                } else {
+                    types::AgentLabels::new()
+                };
+            agent_labels.extend(reg_new_opts.agent_labels_raw);
+            registration::register_new(
+                &config::RegisterNewConfig::new(
+                    config::RegistrationConnectionConfig::new(
+                        runtime_config,
+                        reg_new_opts.connection_opts,
+                    )?,
+                    agent_labels,
+                )?
+                .with_approval(config::ApprovalConfig {
+                    timeout: reg_new_opts.approval_timeout,
+                    callback_url: reg_new_opts.approval_callback_url,
+                    hook: reg_new_opts.approval_hook,
+                }),
+                &mut registry,
+                cli.output,
+            )
+        }
+        cli::Mode::ProxyRegister(proxy_reg_opts) => registration::proxy_register(
+            &config::RegisterExistingConfig::new_for_other_host(
+                runtime_config,
+                proxy_reg_opts.register_opts,
+            )?,
+            proxy_reg_opts
+                .tenant
+                .map(|tenant| {
+                    vault::Vault::new(&paths.vault_path, &paths.vault_key_path).tenant(&tenant)
+                })
+                .transpose()?
+                .as_ref(),
+        ),
+        cli::Mode::Bootstrap(bootstrap_opts) => {
+            bootstrap(runtime_config, bootstrap_opts, &mut registry)
+        }
+        cli::Mode::MigratePullToTls(migrate_opts) => migrate_pull_to_tls(
+            runtime_config,
+            migrate_opts,
+            &mut registry,
+            &paths.connection_stats_path,
+            cli.output,
+        ),
+        cli::Mode::MigrateUpdater(migrate_updater_opts) => migrate_updater(
+            runtime_config,
+            migrate_updater_opts,
+            &mut registry,
+            cli.output,
+        ),
+        cli::Mode::Import(import_opts) => import(
+            &mut registry,
+            &import_opts,
+            &paths.image_connection_path,
+            cli.output,
+        ),
+        cli::Mode::Push(client_opts) => push(
+            &registry,
+            &config::ClientConfig::new(runtime_config, client_opts, None),
+            &push_config,
+            &setup::agent_channel(),
+            connection_stats::ConnectionStats::new(&paths.connection_stats_path)
+                .with_payload_stats(payload_stats::PayloadStats::new(&paths.payload_stats_path)),
+            push_spool,
+        ),
+        cli::Mode::Pull(pull_opts) => pull(
+            config::PullConfig::new(runtime_config, pull_opts, registry)?,
+            connection_stats::ConnectionStats::new(&paths.connection_stats_path)
+                .with_payload_stats(payload_stats::PayloadStats::new(&paths.payload_stats_path)),
+        ),
+        cli::Mode::PullOnce(pull_once_opts) => pull_once(
+            &registry,
+            &config::PullConfig::new(
+                runtime_config,
+                cli::PullOpts {
+                    port: pull_once_opts.port,
+                    #[cfg(windows)]
+                    agent_channel: None,
+                },
+                registry.clone(),
+            )?,
+            &pull_once_opts.connection_opts.connection,
+            &pull_once_opts.address,
+        ),
+        cli::Mode::Daemon(daemon_opts) => {
+            #[cfg(windows)]
+            let service_opts = daemon_opts.service_opts;
+            let client_config = config::ClientConfig::new(
+                runtime_config.clone(),
+                daemon_opts.client_opts,
+                Some(daemon_opts.reg_client_opts),
+            );
+            if let Some(identity_watcher) =
+                container::IdentityWatcher::new(&paths, &runtime_config, &client_config)
+            {
+                // Like pre-configured connections, a failed registration does not stop the daemon
+                if let Err(err) = identity_watcher.sync() {
+                    warn!("{:#}", err);
+                }
+                registry.refresh()?;
+                identity_watcher.watch();
+            }
+            let pull_config = config::PullConfig::new(
+                runtime_config.clone(),
+                daemon_opts.pull_opts,
+                registry.clone(),
+            )?;
+            let metrics_config = config::MetricsConfig::new(&runtime_config);
+            let serve = move |lifecycle| {
+                daemon(
+                    &paths,
+                    registry,
+                    pull_config,
+                    push_config,
+                    client_config,
+                    metrics_config,
+                    lifecycle,
+                )
+            };
+            #[cfg(windows)]
+            return service::run(&service_opts, serve);
+            #[cfg(unix)]
+            serve(lifecycle::Lifecycle::default())
+        }
+        // Dump what would be pushed
+        cli::Mode::Dump => dump(
+            &push_config.section_filter,
+            &push_config.post_processors,
+            &push_config.payload_size,
+            push_config.fallback_command.as_ref(),
+        ),
+        cli::Mode::Doctor(..) => unreachable!("The doctor runs before the registry is loaded"),
+        cli::Mode::Completions(..) => {
+            unreachable!("Completions are handled before the registry is loaded")
+        }
+        cli::Mode::Paths(..) => unreachable!("Paths are shown before the registry is loaded"),
+        cli::Mode::TestConnection(test_connection_opts) => {
+            let client_config = config::ClientConfig::new(
+                runtime_config,
+                test_connection_opts.client_opts,
+                Some(test_connection_opts.reg_client_opts),
+            );
+            test_connection(
+                &test_connection_opts.coordinates,
+                &client_config,
+                test_connection_opts.user,
+                test_connection_opts.password,
+                &agent_receiver_api::Api::new(&client_config),
+            )
+        }
+        cli::Mode::Status(status_opts) => status(
+            registry.clone(),
+            &config::PullConfig::new(
+                runtime_config.clone(),
+                // this will vanish once the Windows agent also uses the toml config
+                cli::PullOpts {
+                    port: None,
+                    #[cfg(windows)]
+                    agent_channel: None,
+                },
+                registry,
+            )?,
+            config::ClientConfig::new(runtime_config, status_opts.client_opts, None),
+            &StatusOptions {
+                output: if status_opts.json {
+                    cli::OutputFormat::Json
+                } else {
+                    cli.output
+                },
+                query_remote: !status_opts.no_query_remote,
+                connection: status_opts.connection.as_deref(),
+                tags: &status_opts.tags,
+                live: status_opts.live,
+                watch: status_opts.watch.then_some(status_opts.interval),
+                max_age: status_opts.max_age,
+                query_timeout: status_opts.query_timeout,
+            },
+            &paths,
+        ),
+        cli::Mode::Delete(delete_opts) => {
+            let deleted = delete(&mut registry, &delete_opts.connection)?;
+            output::print(cli.output, &deleted, &deleted.to_string())
+        }
+        cli::Mode::DeleteAll(delete_all_opts)
+            if delete_all_opts.tags.is_empty() && !delete_all_opts.expired =>
+        {
+            output::print(
+                cli.output,
+                &delete_all(&mut registry, delete_all_opts.enable_insecure_connections)?,
+                "",
+            )
+        }
+        cli::Mode::DeleteAll(delete_all_opts) => {
+            let deleted = delete_selected(
+                &mut registry,
+                &delete_all_opts.tags,
+                delete_all_opts.expired.then(misc::unix_now),
+            )?;
+            output::print(cli.output, &deleted, &deleted.to_string())
+        }
+        cli::Mode::PushInterval(push_interval_opts) => set_push_interval(
+            &mut registry,
+            &push_interval_opts.connection_opts.connection,
+            push_interval_opts.push_interval,
+        ),
+        cli::Mode::SourceAddress(source_address_opts) => set_source_address(
+            &mut registry,
+            &source_address_opts.connection_opts.connection,
+            source_address_opts.source_address,
+        ),
+        cli::Mode::DataSource(data_source_opts) => {
+            set_data_source(&mut registry, &data_source_opts)
+        }
+        cli::Mode::Tag(tag_opts) => set_tags(&mut registry, tag_opts, cli.output),
+        cli::Mode::PushNow(push_now_opts) => {
+            push_now(&paths.control_socket_path, push_now_opts.connection)
+        }
+        cli::Mode::Spool(spool_opts) => spool(
+            &registry,
+            runtime_config,
+            &push_config,
+            connection_stats::ConnectionStats::new(&paths.connection_stats_path)
+                .with_payload_stats(payload_stats::PayloadStats::new(&paths.payload_stats_path)),
+            push_spool,
+            spool_opts.action,
+            cli.output,
+        ),
+        cli::Mode::LogLevel(log_level_opts) => {
+            log_level(&paths.control_socket_path, log_level_opts.spec)
+        }
+        cli::Mode::Reload => reload(&paths.control_socket_path),
+        cli::Mode::LegacyPull(legacy_pull_opts) => legacy_pull(&registry, &legacy_pull_opts.action),
+        cli::Mode::RenewCertificate(renew_certificate_opts) => renew_certificate(
+            &mut registry,
+            &renew_certificate_opts.connection_opts.connection,
+            config::ClientConfig::new(runtime_config, renew_certificate_opts.client_opts, None),
+        ),
+        cli::Mode::RenameHost(rename_host_opts) => {
+            rename_host(&mut registry, runtime_config, rename_host_opts, cli.output)
+        }
+        cli::Mode::SelfUpdate(self_update_opts) => {
+            self_update(&registry, runtime_config, self_update_opts.client_opts)
+        }
+        cli::Mode::Vault(vault_opts) => {
+            vault(&paths.vault_path, &paths.vault_key_path, vault_opts.action)
+        }
+        cli::Mode::Relay(relay_opts) => relay(&paths.relay_path, runtime_config, relay_opts.action),
+        cli::Mode::SupportBundle(support_bundle_opts) => support_bundle(
+            &paths,
+            &registry,
+            &config::LogFileConfig::new(&runtime_config),
+            support_bundle_opts.output.as_deref(),
+        ),
+        #[cfg(feature = "mock-receiver")]
+        cli::Mode::MockReceiver(mock_receiver_opts) => mock_receiver(mock_receiver_opts),
+    }
+}
+
+/// This check is currently only useful on Unix. On Windows, the internal agent address can be passed
+/// on the command line, so we cannot easily check this for any mode.
+fn agent_socket_operational(mode: &cli::Mode) -> AnyhowResult<()> {
+    match mode {
+        cli::Mode::Register(_)
+        | cli::Mode::RegisterNew(_)
+        | cli::Mode::Bootstrap(_)
+        | cli::Mode::MigratePullToTls(_)
+        | cli::Mode::MigrateUpdater(_)
+        | cli::Mode::Import(_) => {
+            let agent_channel = setup::agent_channel();
+            if agent_channel.operational() {
+                Ok(())
+            } else {
+                bail!(format!(
+                    "Something seems wrong with the agent socket ({agent_channel}), aborting"
+                ))
+            }
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! The lifecycle of the daemon as seen by its service manager. Pausing suspends the push
+//! scheduling, stopping lets running pushes finish first. Long operations report checkpoints,
+//! st. eg. the Windows service control manager does not consider the daemon hung.
+
+use log::{debug, info};
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Starting,
+    Running,
+    Paused,
+    Stopping,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub state: State,
+    /// Progress of the current state, increased by every checkpoint
+    pub checkpoint: u32,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} (checkpoint {})", self.state, self.checkpoint)
+    }
+}
+
+type Observer = Arc<dyn Fn(Status) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Lifecycle {
+    status: Arc<watch::Sender<Status>>,
+    observer: Option<Observer>,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self {
+            status: Arc::new(
+                watch::channel(Status {
+                    state: State::Starting,
+                    checkpoint: 0,
+                })
+                .0,
+            ),
+            observer: None,
+        }
+    }
+}
+
+impl Lifecycle {
+    /// Report every change of the status to the given observer, eg. a service manager
+    pub fn with_observer(observer: impl Fn(Status) + Send + Sync + 'static) -> Self {
+        Self {
+            observer: Some(Arc::new(observer)),
+            ..Self::default()
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        *self.status.borrow()
+    }
+
+    pub fn state(&self) -> State {
+        self.status().state
+    }
+
+    fn transition(&self, from: &[State], to: State) -> bool {
+        let changed = self.status.send_if_modified(|status| {
+            if !from.contains(&status.state) {
+                return false;
+            }
+            info!("Daemon is {:?}", to);
+            *status = Status {
+                state: to,
+                checkpoint: 0,
+            };
+            true
+        });
+        if changed {
+            self.observe();
+        }
+        changed
+    }
+
+    fn observe(&self) {
+        if let Some(observer) = &self.observer {
+            observer(self.status());
+        }
+    }
+
+    pub fn started(&self) -> bool {
+        self.transition(&[State::Starting], State::Running)
+    }
+
+    pub fn pause(&self) -> bool {
+        self.transition(&[State::Running], State::Paused)
+    }
+
+    pub fn resume(&self) -> bool {
+        self.transition(&[State::Paused], State::Running)
+    }
+
+    pub fn stop(&self) -> bool {
+        self.transition(
+            &[State::Starting, State::Running, State::Paused],
+            State::Stopping,
+        )
+    }
+
+    /// Report progress of a long operation, eg. while starting or stopping
+    pub fn checkpoint(&self, step: &str) {
+        debug!("{}", step);
+        self.status.send_modify(|status| status.checkpoint += 1);
+        self.observe();
+    }
+
+    async fn wait_until(&self, done: impl Fn(State) -> bool) -> State {
+        let mut receiver = self.status.subscribe();
+        loop {
+            let state = receiver.borrow_and_update().state;
+            // The channel cannot be closed, we hold the sender
+            if done(state) || receiver.changed().await.is_err() {
+                return state;
+            }
+        }
+    }
+
+    /// Wait until the daemon is asked to stop
+    pub async fn stopping(&self) {
+        self.wait_until(|state| state == State::Stopping).await;
+    }
+
+    /// Wait until the daemon is no longer running, ie. paused or stopping
+    pub async fn interrupted(&self) {
+        self.wait_until(|state| !matches!(state, State::Starting | State::Running))
+            .await;
+    }
+
+    /// Wait while the daemon is paused. Returns false once it is stopping.
+    pub async fn proceed(&self) -> bool {
+        self.wait_until(|state| state != State::Paused).await != State::Stopping
+    }
+}
+
+#[cfg(test)]
+mod test_lifecycle {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[test]
+    fn test_transitions() {
+        let observed = Arc::new(Mutex::new(vec![]));
+        let lifecycle = Lifecycle::with_observer({
+            let observed = Arc::clone(&observed);
+            move |status| observed.lock().unwrap().push(status)
+        });
+        assert_eq!(lifecycle.state(), State::Starting);
+        assert!(!lifecycle.pause());
+        lifecycle.checkpoint("Binding sockets");
+        assert!(lifecycle.started());
+        assert!(!lifecycle.resume());
+        assert!(lifecycle.pause());
+        assert!(!lifecycle.pause());
+        assert!(lifecycle.resume());
+        assert!(lifecycle.stop());
+        lifecycle.checkpoint("Draining");
+        assert!(!lifecycle.stop());
+        assert!(!lifecycle.resume());
+        let status = |state, checkpoint| Status { state, checkpoint };
+        assert_eq!(
+            *observed.lock().unwrap(),
+            vec![
+                status(State::Starting, 1),
+                status(State::Running, 0),
+                status(State::Paused, 0),
+                status(State::Running, 0),
+                status(State::Stopping, 0),
+                status(State::Stopping, 1),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proceed() {
+        let lifecycle = Lifecycle::default();
+        lifecycle.started();
+        assert!(lifecycle.proceed().await);
+
+        lifecycle.pause();
+        let waiting = tokio::spawn({
+            let lifecycle = lifecycle.clone();
+            async move { lifecycle.proceed().await }
+        });
+        lifecycle.interrupted().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiting.is_finished());
+        lifecycle.resume();
+        assert!(waiting.await.unwrap());
+
+        lifecycle.stop();
+        assert!(!lifecycle.proceed().await);
+        lifecycle.stopping().await;
+    }
+}
@@ -0,0 +1,415 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Catalog of the messages read by people operating the controller: the prompts of the
+//! registration and the human-readable status. Logs and JSON output stay in English, st. they can
+//! be searched for and processed independently of the locale.
+
+use crate::cli::Language;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::OnceLock;
+
+static LANGUAGE: OnceLock<Language> = OnceLock::new();
+
+/// Select the language of all messages. Without an explicit choice, the language of the locale
+/// is used if there are messages for it.
+pub fn select_language(language: Option<Language>) {
+    // Selected once at startup
+    let _ = LANGUAGE.set(language.unwrap_or_else(|| from_locale(locale().as_deref())));
+}
+
+fn language() -> Language {
+    LANGUAGE.get().copied().unwrap_or_default()
+}
+
+/// Locale for messages, with the precedence of gettext
+fn locale() -> Option<String> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|value| !value.is_empty()))
+}
+
+fn from_locale(locale: Option<&str>) -> Language {
+    match locale {
+        Some(locale) if locale.to_lowercase().starts_with("de") => Language::German,
+        _ => Language::English,
+    }
+}
+
+/// Answer to a yes/no question, yes if nothing was typed. "y" is understood in all languages,
+/// st. scripts piping in the answer keep working.
+pub fn parse_yes_no(answer: &str) -> Option<bool> {
+    match (language(), answer.to_lowercase().trim()) {
+        (_, "" | "y") | (Language::German, "j") => Some(true),
+        (_, "n") => Some(false),
+        _ => None,
+    }
+}
+
+pub enum Message<'a> {
+    // Registration
+    RegisteringAt {
+        server: &'a str,
+        port: u16,
+    },
+    PemCertificate,
+    IssuedBy,
+    IssuedTo,
+    Validity,
+    ValidFrom,
+    ValidTo,
+    TrustQuestion,
+    AnswerYesOrNo,
+    NotTrusted {
+        server: &'a str,
+        port: u16,
+    },
+    ReadAnswerFailed,
+    PasswordPrompt {
+        user: &'a str,
+    },
+    PasswordFailed,
+    // Status
+    Version,
+    AgentSocket,
+    Operational,
+    Inoperational,
+    IpAllowlist,
+    AnyAddress,
+    LegacyModeEnabled,
+    AgentOutput,
+    Daemon,
+    DaemonRunning {
+        state: &'a str,
+        pid: u32,
+        uptime: &'a str,
+        log_level: &'a str,
+    },
+    DaemonUnreachable {
+        error: &'a str,
+    },
+    TaskRestarted {
+        task: &'a str,
+        restarts: u64,
+        age: &'a str,
+        reason: &'a str,
+    },
+    IntegrityCheck,
+    Quarantined,
+    IntegrityOk {
+        connections: usize,
+        age: &'a str,
+    },
+    IntegrityFailed {
+        age: &'a str,
+        failures: &'a str,
+    },
+    NoConnections,
+    NoConnectionMatching {
+        connection: &'a str,
+    },
+    Connection,
+    ImportedConnection,
+    Local,
+    Remote,
+    ConnectionMode,
+    ReceiverPort,
+    NoReceiverPort,
+    Transport,
+    CertificateIssuer,
+    CertificateValidity,
+    CertificateParsingFailed,
+    Tags,
+    Expiry,
+    ExpiresIn {
+        remaining: &'a str,
+    },
+    ExpiredAgo {
+        age: &'a str,
+    },
+    Hostname,
+    LastSeen {
+        age: &'a str,
+    },
+    Error,
+    CachedResult {
+        age: &'a str,
+    },
+    QueryFailed,
+    NoRemoteAddress,
+    RemoteQueryDisabled,
+    NotRegistered,
+    Clock,
+    ReceiverCalls {
+        calls: usize,
+    },
+    SuccessfulCalls {
+        percent: f64,
+        successful: usize,
+        calls: usize,
+    },
+    Latency,
+    NotAvailable,
+}
+
+impl Message<'_> {
+    fn english(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::RegisteringAt { server, port } => writeln!(
+                f,
+                "Attempting to register at {server}, port {port}. Server certificate details:"
+            ),
+            Self::PemCertificate => write!(f, "PEM-encoded certificate"),
+            Self::IssuedBy => write!(f, "Issued by"),
+            Self::IssuedTo => write!(f, "Issued to"),
+            Self::Validity => write!(f, "Validity"),
+            Self::ValidFrom => write!(f, "From"),
+            Self::ValidTo => write!(f, "To"),
+            Self::TrustQuestion => write!(f, "Do you want to establish this connection? [Y/n]"),
+            Self::AnswerYesOrNo => write!(f, "Please answer 'y' or 'n'"),
+            Self::NotTrusted { server, port } => {
+                write!(f, "Cannot continue without trusting {server}, port {port}")
+            }
+            Self::ReadAnswerFailed => write!(f, "Failed to read answer from standard input"),
+            Self::PasswordPrompt { user } => write!(f, "Please enter password for '{user}'"),
+            Self::PasswordFailed => write!(f, "Failed to obtain API password"),
+            Self::Version => write!(f, "Version"),
+            Self::AgentSocket => write!(f, "Agent socket"),
+            Self::Operational => write!(f, "operational"),
+            Self::Inoperational => write!(f, "inoperational"),
+            Self::IpAllowlist => write!(f, "IP allowlist"),
+            Self::AnyAddress => write!(f, "any"),
+            Self::LegacyModeEnabled => write!(f, "Legacy mode: enabled"),
+            Self::AgentOutput => write!(f, "Agent output"),
+            Self::Daemon => write!(f, "Daemon"),
+            Self::DaemonRunning {
+                state,
+                pid,
+                uptime,
+                log_level,
+            } => write!(
+                f,
+                "{state} as PID {pid} for {uptime}, log level '{log_level}'"
+            ),
+            Self::DaemonUnreachable { error } => write!(f, "not reachable ({error})"),
+            Self::TaskRestarted {
+                task,
+                restarts,
+                age,
+                reason,
+            } => write!(
+                f,
+                "{task} task restarted {restarts} time(s), last {age} ago: {reason}"
+            ),
+            Self::IntegrityCheck => write!(f, "Integrity check"),
+            Self::Quarantined => write!(f, "Quarantined registry entry"),
+            Self::IntegrityOk { connections, age } => {
+                write!(f, "ok, {connections} connection(s) checked {age} ago")
+            }
+            Self::IntegrityFailed { age, failures } => write!(f, "failed {age} ago: {failures}"),
+            Self::NoConnections => write!(f, "No connections"),
+            Self::NoConnectionMatching { connection } => {
+                write!(f, "No connection matching '{connection}'")
+            }
+            Self::Connection => write!(f, "Connection"),
+            Self::ImportedConnection => write!(f, "Imported connection"),
+            Self::Local => write!(f, "Local"),
+            Self::Remote => write!(f, "Remote"),
+            Self::ConnectionMode => write!(f, "Connection mode"),
+            Self::ReceiverPort => write!(f, "Connecting to receiver port"),
+            Self::NoReceiverPort => write!(f, "None (imported connection)"),
+            Self::Transport => write!(f, "Transport"),
+            Self::CertificateIssuer => write!(f, "Certificate issuer"),
+            Self::CertificateValidity => write!(f, "Certificate validity"),
+            Self::CertificateParsingFailed => write!(f, "Certificate parsing failed"),
+            Self::Tags => write!(f, "Tags"),
+            Self::Expiry => write!(f, "Expiry"),
+            Self::ExpiresIn { remaining } => write!(f, "in {remaining}"),
+            Self::ExpiredAgo { age } => write!(
+                f,
+                "expired {age} ago, no longer used, remove with 'cmk-agent-ctl delete-all --expired'"
+            ),
+            Self::Hostname => write!(f, "Hostname"),
+            Self::LastSeen { age } => write!(f, "last seen {age} ago"),
+            Self::Error => write!(f, "Error"),
+            Self::CachedResult { age } => write!(f, "Cached result from {age} ago"),
+            Self::QueryFailed => write!(f, "Query failed"),
+            Self::NoRemoteAddress => write!(f, "No remote address (imported connection)"),
+            Self::RemoteQueryDisabled => write!(f, "Remote query disabled"),
+            Self::NotRegistered => write!(f, "Not registered"),
+            Self::Clock => write!(f, "Clock"),
+            Self::ReceiverCalls { calls } => write!(f, "Receiver calls (last {calls})"),
+            Self::SuccessfulCalls {
+                percent,
+                successful,
+                calls,
+            } => write!(
+                f,
+                "Successful calls: {percent:.1}% ({successful} of {calls})"
+            ),
+            Self::Latency => write!(f, "Latency"),
+            Self::NotAvailable => write!(f, "n/a"),
+        }
+    }
+
+    fn german(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::RegisteringAt { server, port } => writeln!(
+                f,
+                "Registrierung bei {server}, Port {port}. Details des Serverzertifikats:"
+            ),
+            Self::PemCertificate => write!(f, "PEM-kodiertes Zertifikat"),
+            Self::IssuedBy => write!(f, "Ausgestellt von"),
+            Self::IssuedTo => write!(f, "Ausgestellt für"),
+            Self::Validity => write!(f, "Gültigkeit"),
+            Self::ValidFrom => write!(f, "Von"),
+            Self::ValidTo => write!(f, "Bis"),
+            Self::TrustQuestion => write!(f, "Möchten Sie diese Verbindung herstellen? [J/n]"),
+            Self::AnswerYesOrNo => write!(f, "Bitte mit 'j' oder 'n' antworten"),
+            Self::NotTrusted { server, port } => write!(
+                f,
+                "Ohne Vertrauen in {server}, Port {port} kann nicht fortgefahren werden"
+            ),
+            Self::ReadAnswerFailed => write!(f, "Antwort konnte nicht gelesen werden"),
+            Self::PasswordPrompt { user } => write!(f, "Bitte Passwort für '{user}' eingeben"),
+            Self::PasswordFailed => write!(f, "API-Passwort konnte nicht gelesen werden"),
+            Self::Version => write!(f, "Version"),
+            Self::AgentSocket => write!(f, "Agent-Socket"),
+            Self::Operational => write!(f, "betriebsbereit"),
+            Self::Inoperational => write!(f, "nicht betriebsbereit"),
+            Self::IpAllowlist => write!(f, "Erlaubte IP-Adressen"),
+            Self::AnyAddress => write!(f, "alle"),
+            Self::LegacyModeEnabled => write!(f, "Legacy-Modus: aktiviert"),
+            Self::AgentOutput => write!(f, "Agentenausgabe"),
+            Self::Daemon => write!(f, "Daemon"),
+            Self::DaemonRunning {
+                state,
+                pid,
+                uptime,
+                log_level,
+            } => write!(
+                f,
+                "{state} als PID {pid} seit {uptime}, Log-Level '{log_level}'"
+            ),
+            Self::DaemonUnreachable { error } => write!(f, "nicht erreichbar ({error})"),
+            Self::TaskRestarted {
+                task,
+                restarts,
+                age,
+                reason,
+            } => write!(
+                f,
+                "Task {task} {restarts} Mal neu gestartet, zuletzt vor {age}: {reason}"
+            ),
+            Self::IntegrityCheck => write!(f, "Integritätsprüfung"),
+            Self::Quarantined => write!(f, "Registry-Eintrag in Quarantäne"),
+            Self::IntegrityOk { connections, age } => {
+                write!(f, "ok, {connections} Verbindung(en) vor {age} geprüft")
+            }
+            Self::IntegrityFailed { age, failures } => {
+                write!(f, "vor {age} fehlgeschlagen: {failures}")
+            }
+            Self::NoConnections => write!(f, "Keine Verbindungen"),
+            Self::NoConnectionMatching { connection } => {
+                write!(f, "Keine Verbindung passend zu '{connection}'")
+            }
+            Self::Connection => write!(f, "Verbindung"),
+            Self::ImportedConnection => write!(f, "Importierte Verbindung"),
+            Self::Local => write!(f, "Lokal"),
+            Self::Remote => write!(f, "Entfernt"),
+            Self::ConnectionMode => write!(f, "Verbindungsmodus"),
+            Self::ReceiverPort => write!(f, "Port des Agent Receivers"),
+            Self::NoReceiverPort => write!(f, "Keiner (importierte Verbindung)"),
+            Self::Transport => write!(f, "Transport"),
+            Self::CertificateIssuer => write!(f, "Aussteller des Zertifikats"),
+            Self::CertificateValidity => write!(f, "Gültigkeit des Zertifikats"),
+            Self::CertificateParsingFailed => write!(f, "Zertifikat nicht lesbar"),
+            Self::Tags => write!(f, "Tags"),
+            Self::Expiry => write!(f, "Ablauf"),
+            Self::ExpiresIn { remaining } => write!(f, "in {remaining}"),
+            Self::ExpiredAgo { age } => write!(
+                f,
+                "vor {age} abgelaufen, nicht mehr verwendet, entfernen mit \
+                 'cmk-agent-ctl delete-all --expired'"
+            ),
+            Self::Hostname => write!(f, "Hostname"),
+            Self::LastSeen { age } => write!(f, "zuletzt gesehen vor {age}"),
+            Self::Error => write!(f, "Fehler"),
+            Self::CachedResult { age } => write!(f, "Zwischengespeichertes Ergebnis von vor {age}"),
+            Self::QueryFailed => write!(f, "Abfrage fehlgeschlagen"),
+            Self::NoRemoteAddress => write!(f, "Keine entfernte Adresse (importierte Verbindung)"),
+            Self::RemoteQueryDisabled => write!(f, "Entfernte Abfrage deaktiviert"),
+            Self::NotRegistered => write!(f, "Nicht registriert"),
+            Self::Clock => write!(f, "Uhrzeit"),
+            Self::ReceiverCalls { calls } => write!(f, "Aufrufe des Receivers (letzte {calls})"),
+            Self::SuccessfulCalls {
+                percent,
+                successful,
+                calls,
+            } => write!(
+                f,
+                "Erfolgreiche Aufrufe: {percent:.1} % ({successful} von {calls})"
+            ),
+            Self::Latency => write!(f, "Latenz"),
+            Self::NotAvailable => write!(f, "k. A."),
+        }
+    }
+}
+
+impl Display for Message<'_> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match language() {
+            Language::English => self.english(f),
+            Language::German => self.german(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_messages {
+    use super::*;
+
+    #[test]
+    fn test_from_locale() {
+        assert_eq!(from_locale(Some("de_DE.UTF-8")), Language::German);
+        assert_eq!(from_locale(Some("de_AT")), Language::German);
+        assert_eq!(from_locale(Some("en_US.UTF-8")), Language::English);
+        assert_eq!(from_locale(Some("fr_FR")), Language::English);
+        assert_eq!(from_locale(Some("C")), Language::English);
+        assert_eq!(from_locale(None), Language::English);
+    }
+
+    struct InGerman<'a>(Message<'a>);
+
+    impl Display for InGerman<'_> {
+        fn fmt(&self, f: &mut Formatter) -> FmtResult {
+            self.0.german(f)
+        }
+    }
+
+    #[test]
+    fn test_messages() {
+        let not_trusted = || Message::NotTrusted {
+            server: "server",
+            port: 8000,
+        };
+        assert_eq!(
+            not_trusted().to_string(),
+            "Cannot continue without trusting server, port 8000"
+        );
+        assert_eq!(
+            InGerman(not_trusted()).to_string(),
+            "Ohne Vertrauen in server, Port 8000 kann nicht fortgefahren werden"
+        );
+    }
+
+    #[test]
+    fn test_parse_yes_no() {
+        // Tests run without a selected language
+        assert_eq!(parse_yes_no("Y\n"), Some(true));
+        assert_eq!(parse_yes_no(""), Some(true));
+        assert_eq!(parse_yes_no(" n "), Some(false));
+        assert_eq!(parse_yes_no("j"), None);
+    }
+}
@@ -0,0 +1,569 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::certs;
+use crate::config;
+use crate::connection_stats::{
+    ConnectionCounters, ConnectionStats, CountersByConnection, PushAttemptOutcome,
+};
+use crate::constants;
+use crate::misc;
+use crate::push_spool::PushSpool;
+use anyhow::{bail, Context, Result as AnyhowResult};
+use log::warn;
+use std::fmt::Write as FmtWrite;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// One registered connection, as identified by the labels of its samples
+pub struct Target<'a> {
+    pub mode: &'static str,
+    // Empty for imported connections
+    pub site: String,
+    pub connection: &'a config::TrustedConnection,
+}
+
+impl Target<'_> {
+    fn labels(&self) -> String {
+        format!(
+            "site=\"{}\",uuid=\"{}\",mode=\"{}\"",
+            escape_label_value(&self.site),
+            self.connection.uuid,
+            self.mode
+        )
+    }
+
+    pub fn is_push(&self) -> bool {
+        self.mode == "push"
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub fn targets(registry: &config::Registry) -> Vec<Target<'_>> {
+    let mut targets = vec![];
+    for (site_id, connection) in registry.get_push_connections() {
+        targets.push(Target {
+            mode: "push",
+            site: site_id.to_string(),
+            connection: &connection.trust,
+        });
+    }
+    for (site_id, connection) in registry.get_standard_pull_connections() {
+        targets.push(Target {
+            mode: "pull",
+            site: site_id.to_string(),
+            connection: &connection.trust,
+        });
+    }
+    for connection in registry.get_imported_pull_connections() {
+        targets.push(Target {
+            mode: "pull",
+            site: String::new(),
+            connection,
+        });
+    }
+    targets
+}
+
+struct Family<'a> {
+    name: &'a str,
+    help: &'a str,
+    kind: &'a str,
+    samples: Vec<(String, String)>,
+}
+
+impl Family<'_> {
+    fn write(&self, out: &mut String) {
+        if self.samples.is_empty() {
+            return;
+        }
+        // Writing to a String cannot fail
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.kind);
+        for (labels, value) in &self.samples {
+            let _ = writeln!(out, "{}{{{}}} {}", self.name, labels, value);
+        }
+    }
+}
+
+/// Unix timestamp at which the certificate expires
+pub fn certificate_not_after(certificate: &str) -> AnyhowResult<i64> {
+    let pem = certs::parse_pem(certificate)?;
+    Ok(pem.parse_x509()?.validity().not_after.timestamp())
+}
+
+fn certificate_expiry(certificate: &str, now: i64) -> AnyhowResult<i64> {
+    Ok(certificate_not_after(certificate)? - now)
+}
+
+/// Render the metrics of all registered connections in the Prometheus text format
+fn render(
+    registry: &config::Registry,
+    counters: &CountersByConnection,
+    spool_depth: impl Fn(&uuid::Uuid) -> usize,
+    now: i64,
+) -> String {
+    let targets = targets(registry);
+    let default_counters = ConnectionCounters::default();
+    let counters_of = |target: &Target| {
+        counters
+            .get(&target.connection.uuid)
+            .unwrap_or(&default_counters)
+    };
+    let per_target = |value: &dyn Fn(&Target) -> Option<String>| {
+        targets
+            .iter()
+            .filter_map(|target| value(target).map(|value| (target.labels(), value)))
+            .collect::<Vec<_>>()
+    };
+    let push_targets = || targets.iter().filter(|target| target.is_push());
+
+    let mut pushes = vec![];
+    for target in push_targets() {
+        let counters = counters_of(target);
+        for (outcome, count) in [
+            ("success", counters.successful_pushes),
+            ("failed", counters.failed_pushes),
+            ("skipped", counters.skipped_pushes),
+        ] {
+            pushes.push((
+                format!("{},outcome=\"{}\"", target.labels(), outcome),
+                count.to_string(),
+            ));
+        }
+    }
+
+    let families = [
+        Family {
+            name: "cmk_agent_ctl_pulls_total",
+            help: "Number of successful pulls of the agent output",
+            kind: "counter",
+            samples: per_target(&|target| Some(counters_of(target).successful_pulls.to_string())),
+        },
+        Family {
+            name: "cmk_agent_ctl_pushes_total",
+            help: "Number of push attempts by outcome",
+            kind: "counter",
+            samples: pushes,
+        },
+        Family {
+            name: "cmk_agent_ctl_tls_failures_total",
+            help: "Number of failed TLS handshakes",
+            kind: "counter",
+            samples: per_target(&|target| Some(counters_of(target).tls_failures.to_string())),
+        },
+        Family {
+            name: "cmk_agent_ctl_served_bytes_total",
+            help: "Bytes of agent output served to or uploaded to the site",
+            kind: "counter",
+            samples: per_target(&|target| Some(counters_of(target).bytes_served.to_string())),
+        },
+        Family {
+            name: "cmk_agent_ctl_last_push_duration_seconds",
+            help: "Duration of the most recent push attempt",
+            kind: "gauge",
+            samples: per_target(&|target| {
+                counters_of(target)
+                    .push_history
+                    .back()
+                    .map(|attempt| (attempt.duration_ms as f64 / 1000.0).to_string())
+            }),
+        },
+        Family {
+            name: "cmk_agent_ctl_push_spool_entries",
+            help: "Number of agent outputs waiting in the push spool",
+            kind: "gauge",
+            samples: push_targets()
+                .map(|target| {
+                    (
+                        target.labels(),
+                        spool_depth(&target.connection.uuid).to_string(),
+                    )
+                })
+                .collect(),
+        },
+        Family {
+            name: "cmk_agent_ctl_certificate_expiry_seconds",
+            help: "Seconds until the connection certificate expires",
+            kind: "gauge",
+            samples: per_target(&|target| {
+                certificate_expiry(&target.connection.certificate, now)
+                    .ok()
+                    .map(|remaining| remaining.to_string())
+            }),
+        },
+    ];
+
+    let mut out = String::new();
+    for family in &families {
+        family.write(&mut out);
+    }
+    out
+}
+
+/// A connection counts as operational if its certificate is valid and, for push connections, the
+/// latest push attempt did not fail.
+fn is_operational(target: &Target, counters: Option<&ConnectionCounters>, now: i64) -> bool {
+    let push_failed = target.is_push()
+        && counters
+            .and_then(|counters| counters.push_history.back())
+            .is_some_and(|attempt| attempt.outcome == PushAttemptOutcome::Failed);
+    !push_failed
+        && certificate_expiry(&target.connection.certificate, now)
+            .is_ok_and(|remaining| remaining > 0)
+}
+
+/// Whether the daemon is ready, i.e. at least one connection is operational, and why
+fn readiness(
+    registry: &config::Registry,
+    counters: &CountersByConnection,
+    now: i64,
+) -> (bool, String) {
+    if registry.is_legacy_pull_active() {
+        return (true, String::from("ready: legacy pull mode\n"));
+    }
+    let targets = targets(registry);
+    let operational = targets
+        .iter()
+        .filter(|target| is_operational(target, counters.get(&target.connection.uuid), now))
+        .count();
+    (
+        operational > 0,
+        format!(
+            "{}: {} of {} connections operational\n",
+            if operational > 0 {
+                "ready"
+            } else {
+                "not ready"
+            },
+            operational,
+            targets.len()
+        ),
+    )
+}
+
+/// Data sources of the metrics and health endpoints in the daemon
+pub struct Metrics {
+    registry: Mutex<config::Registry>,
+    connection_stats: ConnectionStats,
+    push_spool: PushSpool,
+}
+
+impl Metrics {
+    pub fn new(
+        registry: config::Registry,
+        connection_stats: ConnectionStats,
+        push_spool: PushSpool,
+    ) -> Self {
+        Self {
+            registry: Mutex::new(registry),
+            connection_stats,
+            push_spool,
+        }
+    }
+
+    fn refreshed_registry(&self) -> std::sync::MutexGuard<'_, config::Registry> {
+        let mut registry = match self.registry.lock() {
+            Ok(registry) => registry,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(err) = registry.refresh() {
+            warn!("Failed to refresh registry for metrics. ({})", err);
+        }
+        registry
+    }
+
+    /// Answer a GET request to the given path, None if there is nothing at that path
+    pub fn handle(&self, path: &str) -> Option<(&'static str, String)> {
+        match path {
+            "/metrics" => Some(("200 OK", self.render())),
+            // Liveness: answering at all means the daemon is up
+            "/health" => Some(("200 OK", String::from("ok\n"))),
+            "/ready" => {
+                let (ready, reason) = readiness(
+                    &self.refreshed_registry(),
+                    &self.connection_stats.snapshot(),
+                    misc::unix_now() as i64,
+                );
+                Some((
+                    if ready {
+                        "200 OK"
+                    } else {
+                        "503 Service Unavailable"
+                    },
+                    reason,
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    fn render(&self) -> String {
+        let registry = self.refreshed_registry();
+        render(
+            &registry,
+            &self.connection_stats.snapshot(),
+            |uuid| {
+                self.push_spool
+                    .entries(uuid)
+                    .map(|entries| entries.len())
+                    .unwrap_or_default()
+            },
+            misc::unix_now() as i64,
+        )
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> std::io::Result<()> {
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await
+}
+
+async fn read_request_line(stream: &mut TcpStream) -> AnyhowResult<String> {
+    let mut reader = BufReader::new(stream).take(constants::METRICS_MAX_REQUEST_SIZE);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    // We do not care about the headers, but the client expects them to be read
+    let mut header = String::new();
+    while reader.read_line(&mut header).await? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+    if reader.limit() == 0 {
+        bail!(
+            "Request exceeds {} bytes",
+            constants::METRICS_MAX_REQUEST_SIZE
+        );
+    }
+    Ok(request_line)
+}
+
+async fn handle_client(
+    mut stream: TcpStream,
+    handler: &impl Fn(&str) -> Option<(&'static str, String)>,
+) -> AnyhowResult<()> {
+    let request_line = tokio::time::timeout(
+        Duration::from_secs(constants::METRICS_READ_TIMEOUT),
+        read_request_line(&mut stream),
+    )
+    .await
+    .context("Timed out reading request")??;
+
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => match handler(path) {
+            Some((status, body)) => respond(&mut stream, status, &body).await?,
+            None => respond(&mut stream, "404 Not Found", "Not found\n").await?,
+        },
+        _ => {
+            respond(
+                &mut stream,
+                "405 Method Not Allowed",
+                "Method not allowed\n",
+            )
+            .await?
+        }
+    }
+    Ok(())
+}
+
+/// Answer GET requests on the given listener, each connection in a task of its own, st. slow
+/// clients do not hold up the others.
+pub async fn serve(
+    listener: std::net::TcpListener,
+    handler: impl Fn(&str) -> Option<(&'static str, String)> + Send + Sync + 'static,
+) -> AnyhowResult<()> {
+    let listener = TcpListener::from_std(listener)?;
+    let handler = Arc::new(handler);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_client(stream, handler.as_ref()).await {
+                        warn!("Error handling metrics request. ({})", err);
+                    }
+                });
+            }
+            Err(err) => warn!("Error accepting metrics connection. ({})", err),
+        }
+    }
+}
+
+/// Binding happens before the runtime is started, st. the daemon can do so before dropping its
+/// root privileges
+pub fn bind(address: &SocketAddr) -> AnyhowResult<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind(address)
+        .context(format!("Failed to bind metrics endpoint to {}", address))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use super::*;
+    use crate::config::test_helpers::TestRegistry;
+    use crate::connection_stats::PushAttempt;
+    use crate::site_spec;
+    use std::io::{Read, Write};
+    use std::str::FromStr;
+
+    const PUSH_UUID: &str = "99f56bbc-5965-4b34-bc70-1959ad1d32d6";
+    const IMPORTED_UUID: &str = "50611369-7a42-4c0b-927e-9a14330401fe";
+
+    fn registry() -> TestRegistry {
+        let mut r = TestRegistry::new()
+            .add_connection(&config::ConnectionMode::Push, "server/site", PUSH_UUID)
+            .add_imported_connection(IMPORTED_UUID);
+        r.registry
+            .get_connection_as_mut(&site_spec::SiteID::from_str("server/site").unwrap())
+            .unwrap()
+            .trust
+            .certificate = String::from(constants::TEST_CERT_OK);
+        r
+    }
+
+    #[test]
+    fn test_render() {
+        let r = registry();
+        let dir = tempfile::tempdir().unwrap();
+        let stats = ConnectionStats::new(dir.path().join("connection_stats.json"));
+        let push_uuid = uuid::Uuid::from_str(PUSH_UUID).unwrap();
+        stats.record_push(
+            &push_uuid,
+            PushAttempt::pushed(0, Duration::from_millis(250), 1000),
+        );
+        stats.record_push(
+            &push_uuid,
+            PushAttempt::failed(0, Duration::from_millis(1500), None, String::from("down")),
+        );
+        let not_after = certificate_expiry(constants::TEST_CERT_OK, 0).unwrap();
+
+        let output = render(&r.registry, &stats.snapshot(), |_| 3, 100);
+        let push_labels = format!("site=\"server/site\",uuid=\"{PUSH_UUID}\",mode=\"push\"");
+        let imported_labels = format!("site=\"\",uuid=\"{IMPORTED_UUID}\",mode=\"pull\"");
+        for expected in [
+            String::from("# TYPE cmk_agent_ctl_pushes_total counter"),
+            format!("cmk_agent_ctl_pushes_total{{{push_labels},outcome=\"success\"}} 1"),
+            format!("cmk_agent_ctl_pushes_total{{{push_labels},outcome=\"failed\"}} 1"),
+            format!("cmk_agent_ctl_pulls_total{{{imported_labels}}} 0"),
+            format!("cmk_agent_ctl_served_bytes_total{{{push_labels}}} 1000"),
+            format!("cmk_agent_ctl_last_push_duration_seconds{{{push_labels}}} 1.5"),
+            format!("cmk_agent_ctl_push_spool_entries{{{push_labels}}} 3"),
+            format!(
+                "cmk_agent_ctl_certificate_expiry_seconds{{{push_labels}}} {}",
+                not_after - 100
+            ),
+        ] {
+            assert!(output.lines().any(|line| line == expected), "{expected}");
+        }
+        // No push history, no spool and no parsable certificate for the imported connection
+        assert!(!output.contains(&format!(
+            "cmk_agent_ctl_last_push_duration_seconds{{{imported_labels}}}"
+        )));
+        assert!(!output.contains(&format!(
+            "cmk_agent_ctl_push_spool_entries{{{imported_labels}}}"
+        )));
+        assert!(!output.contains(&format!(
+            "cmk_agent_ctl_certificate_expiry_seconds{{{imported_labels}}}"
+        )));
+    }
+
+    #[test]
+    fn test_readiness() {
+        let mut r = registry();
+        let dir = tempfile::tempdir().unwrap();
+        let stats = ConnectionStats::new(dir.path().join("connection_stats.json"));
+        let push_uuid = uuid::Uuid::from_str(PUSH_UUID).unwrap();
+        assert_eq!(
+            readiness(&r.registry, &stats.snapshot(), 0),
+            (
+                true,
+                String::from("ready: 1 of 2 connections operational\n")
+            )
+        );
+
+        stats.record_push(
+            &push_uuid,
+            PushAttempt::failed(0, Duration::ZERO, Some(503), String::from("down")),
+        );
+        assert_eq!(
+            readiness(&r.registry, &stats.snapshot(), 0),
+            (
+                false,
+                String::from("not ready: 0 of 2 connections operational\n")
+            )
+        );
+        stats.record_push(&push_uuid, PushAttempt::unchanged(0, Duration::ZERO));
+        assert!(readiness(&r.registry, &stats.snapshot(), 0).0);
+        // Expired certificate
+        assert!(!readiness(&r.registry, &stats.snapshot(), i64::MAX / 2).0);
+
+        r.registry.clear();
+        assert!(!readiness(&r.registry, &stats.snapshot(), 0).0);
+    }
+
+    #[test]
+    fn test_escape_label_value() {
+        assert_eq!(escape_label_value("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    fn get(address: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_serve() {
+        let listener = bind(&SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, |path| {
+            (path == "/metrics").then(|| ("200 OK", String::from("metric 1\n")))
+        }));
+
+        tokio::task::spawn_blocking(move || {
+            let response = get(address, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.contains(&format!("Content-Type: {CONTENT_TYPE}\r\n")));
+            assert!(response.ends_with("\r\n\r\nmetric 1\n"));
+            assert!(get(address, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+            assert!(get(address, "POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+
+            // A client which sends nothing does not hold up the others
+            let _idle = std::net::TcpStream::connect(address).unwrap();
+            assert!(get(address, "GET /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200"));
+
+            // Oversized requests are not answered
+            let mut stream = std::net::TcpStream::connect(address).unwrap();
+            let header = "a".repeat(constants::METRICS_MAX_REQUEST_SIZE as usize);
+            let _ = stream
+                .write_all(format!("GET /metrics HTTP/1.1\r\nX: {header}\r\n\r\n").as_bytes());
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response);
+            assert!(response.is_empty());
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use log::debug;
+use rand::Rng;
+use std::time::{Duration, SystemTime};
+
+#[cfg(windows)]
+use is_elevated::is_elevated;
+
+use crate::tls_failure::TlsFailure;
+use anyhow::Error as AnyhowError;
+#[cfg(windows)]
+use anyhow::{bail, Result as AnyhowResult};
+
+/// Values of keys containing one of these are secrets, wherever JSON is shown to someone
+const SECRET_KEYS: [&str; 5] = ["password", "passwd", "secret", "token", "private_key"];
+
+pub fn anyhow_error_to_human_readable(err: &AnyhowError) -> String {
+    let mut lines: Vec<String> = err.chain().map(|e| e.to_string()).collect();
+    if let Some(tls_failure) = TlsFailure::of(err) {
+        lines.push(format!("{}. {}", tls_failure, tls_failure.guidance()));
+    }
+    lines.join("\n")
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn human_readable_duration(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{seconds}s"),
+        (0, 0, _) => format!("{minutes}m"),
+        (0, _, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h"),
+    }
+}
+
+pub fn human_readable_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next_unit in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+    format!("{size:.1} {unit}")
+}
+
+/// Replaces the values of secrets, and of the `other_keys`, at any depth of the JSON value. Keys
+/// are compared case-insensitively.
+pub fn redact_secrets(value: &mut serde_json::Value, other_keys: &[&str], replacement: &str) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if other_keys.contains(&key.as_str())
+                    || SECRET_KEYS.iter().any(|secret| key.contains(secret))
+                {
+                    *value = serde_json::Value::from(replacement);
+                } else {
+                    redact_secrets(value, other_keys, replacement);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| redact_secrets(value, other_keys, replacement)),
+        _ => {}
+    }
+}
+
+pub async fn sleep_randomly() {
+    let random_period = rand::thread_rng().gen_range(0..59);
+    debug!("Sleeping {}s to avoid DDOSing of sites", random_period);
+    tokio::time::sleep(Duration::from_secs(random_period)).await;
+}
+
+#[cfg(windows)]
+pub fn validate_elevation() -> AnyhowResult<()> {
+    const MESSAGE_NOT_ELEVATED: &str =
+    "You must be elevated to execute the agent controller. Please, try 'Run as administrator...'";
+
+    if is_elevated() {
+        Ok(())
+    } else {
+        // The elevation elevation may happen at any moment, even when the logger is not ready.
+        // Ergo, to avoid strange | duplicated output we write two different message in the log
+        // and stderr thus providing correct information for a user
+        eprintln!("{}", MESSAGE_NOT_ELEVATED);
+        bail!("Not elevated, exiting...".to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_anyhow_error_to_human_readable() {
+        assert_eq!(
+            anyhow_error_to_human_readable(
+                &anyhow!("something went wrong").context("some context")
+            ),
+            "some context\nsomething went wrong"
+        )
+    }
+
+    #[test]
+    fn test_human_readable_duration() {
+        assert_eq!(human_readable_duration(59), "59s");
+        assert_eq!(human_readable_duration(61), "1m");
+        assert_eq!(human_readable_duration(3 * 3600 + 120), "3h 2m");
+        assert_eq!(human_readable_duration(2 * 86400 + 5 * 3600 + 1), "2d 5h");
+    }
+
+    #[test]
+    fn test_human_readable_bytes() {
+        assert_eq!(human_readable_bytes(1023), "1023 B");
+        assert_eq!(human_readable_bytes(1536), "1.5 KiB");
+        assert_eq!(human_readable_bytes(80 * 1024 * 1024), "80.0 MiB");
+    }
+
+    #[test]
+    fn test_redact_secrets() {
+        let mut value = serde_json::json!({
+            "uuid": "1234",
+            "Passwd": "secret",
+            "nested": [{"access_token": "abc", "users": ["alice"]}],
+        });
+        redact_secrets(&mut value, &["users"], "***");
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "uuid": "1234",
+                "Passwd": "***",
+                "nested": [{"access_token": "***", "users": "***"}],
+            })
+        );
+    }
+}
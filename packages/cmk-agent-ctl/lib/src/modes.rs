@@ -0,0 +1,36 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+pub mod bootstrap;
+pub mod completions;
+pub mod daemon;
+pub mod data_source;
+pub mod delete_connection;
+pub mod doctor;
+pub mod dump;
+pub mod import_connection;
+pub mod legacy_pull;
+pub mod log_level;
+pub mod migrate_pull_to_tls;
+pub mod migrate_updater;
+#[cfg(feature = "mock-receiver")]
+pub mod mock_receiver;
+pub mod paths;
+pub mod pull;
+pub mod pull_once;
+pub mod push;
+pub mod push_now;
+pub mod registration;
+pub mod relay;
+pub mod reload;
+pub mod rename_host;
+pub mod renew_certificate;
+pub mod self_update;
+pub mod source_address;
+pub mod spool;
+pub mod status;
+pub mod support_bundle;
+pub mod tag;
+pub mod test_connection;
+pub mod vault;
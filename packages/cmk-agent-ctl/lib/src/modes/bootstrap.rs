@@ -0,0 +1,382 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::registration;
+use crate::cloud_metadata::{CloudProvider, MetadataService};
+#[cfg(windows)]
+use crate::win_registry;
+use crate::{cli, config, host_name, site_spec, types};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use log::info;
+#[cfg(windows)]
+use log::warn;
+use serde::Deserialize;
+use std::fs;
+use std::str::FromStr;
+
+/// Key of the registration parameters in the user data
+const USER_DATA_KEY: &str = "cmk_agent_ctl";
+/// Registry key of the registration parameters, below HKEY_LOCAL_MACHINE
+#[cfg(windows)]
+const REGISTRY_KEY: &str = "SOFTWARE\\checkmk\\agent\\registration";
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct BootstrapParameters {
+    /// "<server>" or "<server>:<port>"
+    server: String,
+    site: String,
+    user: String,
+    /// Password or automation secret of the user
+    #[serde(alias = "token")]
+    password: String,
+    #[serde(default)]
+    hostname: Option<String>,
+    #[serde(default)]
+    hostname_from: Option<host_name::HostNameSource>,
+    /// Only for hosts created by the site
+    #[serde(default)]
+    labels: types::AgentLabels,
+    /// PEM-encoded root certificate of the site
+    #[serde(default)]
+    root_cert: Option<String>,
+    #[serde(default)]
+    trust_cert: bool,
+}
+
+enum BootstrapHost {
+    Existing(String),
+    New(types::AgentLabels),
+}
+
+impl BootstrapParameters {
+    /// The parameters under the key "cmk_agent_ctl" of user data in YAML, eg. a cloud-init
+    /// config, or JSON
+    fn from_user_data(user_data: &str) -> AnyhowResult<Self> {
+        let user_data: serde_yaml::Value = serde_yaml::from_str(user_data)
+            .context("User data is neither YAML nor JSON, multi-part user data is not supported")?;
+        let Some(parameters) = user_data.get(USER_DATA_KEY) else {
+            bail!("No key \"{}\" in the user data", USER_DATA_KEY);
+        };
+        serde_yaml::from_value(parameters.clone())
+            .with_context(|| format!("Invalid parameters under \"{USER_DATA_KEY}\""))
+    }
+
+    /// The parameters as values of the registry key, named like the keys of the user data.
+    /// "labels" is a multi-string of "KEY:VALUE" lines, "trust_cert" a DWORD. Values left empty,
+    /// eg. by the installer for properties which were not given, are ignored.
+    #[cfg(windows)]
+    fn from_registry_values(values: Vec<(String, win_registry::Value)>) -> AnyhowResult<Self> {
+        let mut parameters = serde_yaml::Mapping::new();
+        for (name, value) in values {
+            let name = name.to_lowercase();
+            let value = match (name.as_str(), value) {
+                (_, win_registry::Value::String(value)) if value.is_empty() => continue,
+                ("labels", win_registry::Value::MultiString(labels)) => serde_yaml::Value::Mapping(
+                    labels
+                        .iter()
+                        .map(|label| match label.split_once(':') {
+                            Some((key, value)) => Ok((key.into(), value.into())),
+                            None => bail!("Invalid label \"{label}\", expected KEY:VALUE"),
+                        })
+                        .collect::<AnyhowResult<_>>()?,
+                ),
+                (_, win_registry::Value::MultiString(lines)) => lines.join("\n").into(),
+                (_, win_registry::Value::String(value)) => value.into(),
+                (_, win_registry::Value::Dword(value)) => (value != 0).into(),
+            };
+            parameters.insert(name.into(), value);
+        }
+        serde_yaml::from_value(serde_yaml::Value::Mapping(parameters))
+            .with_context(|| format!("Invalid parameters in HKEY_LOCAL_MACHINE\\{REGISTRY_KEY}"))
+    }
+
+    fn server_spec(&self) -> AnyhowResult<site_spec::ServerSpec> {
+        site_spec::ServerSpec::from_str(&self.server)
+            .with_context(|| format!("Invalid server \"{}\"", self.server))
+    }
+
+    fn host(&self) -> AnyhowResult<BootstrapHost> {
+        let host_name = match (&self.hostname, &self.hostname_from) {
+            (Some(_), Some(_)) => bail!("Either give \"hostname\" or \"hostname_from\", not both"),
+            (Some(host_name), None) => host_name.clone(),
+            (None, Some(host_name_source)) => {
+                let host_name = host_name_source
+                    .derive(&host_name::System)
+                    .context("Failed to derive the host name")?;
+                info!("Derived host name {}", host_name);
+                host_name
+            }
+            (None, None) => return Ok(BootstrapHost::New(self.labels.clone())),
+        };
+        if !self.labels.is_empty() {
+            bail!("Labels can only be given if the site creates the host, ie. without a host name");
+        }
+        Ok(BootstrapHost::Existing(host_name))
+    }
+
+    fn connection_opts(
+        &self,
+        client_opts: cli::ClientOpts,
+        reg_client_opts: cli::RegistrationClientOpts,
+    ) -> AnyhowResult<cli::RegistrationConnectionOpts> {
+        Ok(cli::RegistrationConnectionOpts {
+            server_spec: self.server_spec()?,
+            site: self.site.clone(),
+            user: self.user.clone(),
+            password: Some(self.password.clone()),
+            trust_server_cert: self.trust_cert,
+            // Nothing is prompted
+            prompt_format: cli::PromptFormat::Text,
+            tags: vec![],
+            expires_in: None,
+            client_opts,
+            reg_client_opts,
+        })
+    }
+}
+
+fn read_user_data(opts: &cli::BootstrapOpts) -> AnyhowResult<String> {
+    if let Some(path) = &opts.user_data_file {
+        return fs::read_to_string(path)
+            .with_context(|| format!("Failed to read user data from {path:?}"));
+    }
+    let providers = match opts.provider {
+        Some(provider) => vec![provider],
+        None => CloudProvider::ALL.to_vec(),
+    };
+    let (provider, user_data) = MetadataService::new()?
+        .user_data(&providers)
+        .context("Failed to read the user data of this instance")?;
+    info!("Read user data from the instance metadata service of {provider}");
+    Ok(user_data)
+}
+
+/// Register with the site given in the user data, unless already registered with it. Returns the
+/// site and whether a registration took place.
+pub fn register_from_user_data(
+    user_data: &str,
+    runtime_config: config::RuntimeConfig,
+    client_opts: cli::ClientOpts,
+    reg_client_opts: cli::RegistrationClientOpts,
+    registry: &mut config::Registry,
+) -> AnyhowResult<(site_spec::SiteID, bool)> {
+    register(
+        BootstrapParameters::from_user_data(user_data)?,
+        runtime_config,
+        client_opts,
+        reg_client_opts,
+        registry,
+    )
+}
+
+fn register(
+    parameters: BootstrapParameters,
+    runtime_config: config::RuntimeConfig,
+    client_opts: cli::ClientOpts,
+    reg_client_opts: cli::RegistrationClientOpts,
+    registry: &mut config::Registry,
+) -> AnyhowResult<(site_spec::SiteID, bool)> {
+    let site_id = site_spec::SiteID {
+        server: parameters.server_spec()?.server,
+        site: parameters.site.clone(),
+    };
+    if registry.get_registered_site_ids().any(|id| id == &site_id) {
+        return Ok((site_id, false));
+    }
+    let host = parameters.host()?;
+    let mut connection_config = config::RegistrationConnectionConfig::new(
+        runtime_config,
+        parameters.connection_opts(client_opts, reg_client_opts)?,
+    )?;
+    connection_config.root_certificate = parameters.root_cert;
+    match host {
+        BootstrapHost::Existing(host_name) => registration::register_existing_unattended(
+            &config::RegisterExistingConfig {
+                connection_config,
+                host_name,
+                host_creation: None,
+                keep_credentials: false,
+            },
+            registry,
+        )?,
+        BootstrapHost::New(agent_labels) => registration::register_new_unattended(
+            &config::RegisterNewConfig::new(connection_config, agent_labels)?,
+            registry,
+        )?,
+    }
+    Ok((site_id, true))
+}
+
+#[cfg(windows)]
+fn read_registry() -> AnyhowResult<BootstrapParameters> {
+    BootstrapParameters::from_registry_values(
+        win_registry::values(REGISTRY_KEY)
+            .with_context(|| format!("Failed to read HKEY_LOCAL_MACHINE\\{REGISTRY_KEY}"))?,
+    )
+}
+
+/// The credentials are only needed once, so they do not linger in the registry
+#[cfg(windows)]
+fn forget_credentials() {
+    for name in ["password", "token"] {
+        if let Err(err) = win_registry::delete_value(REGISTRY_KEY, name) {
+            warn!(
+                "Failed to delete {} from HKEY_LOCAL_MACHINE\\{}. ({})",
+                name, REGISTRY_KEY, err
+            );
+        }
+    }
+}
+
+/// Register with the site given in the user data, unless already registered with it
+pub fn bootstrap(
+    runtime_config: config::RuntimeConfig,
+    opts: cli::BootstrapOpts,
+    registry: &mut config::Registry,
+) -> AnyhowResult<()> {
+    #[cfg(windows)]
+    let parameters = match opts.from_registry {
+        true => read_registry()?,
+        false => BootstrapParameters::from_user_data(&read_user_data(&opts)?)?,
+    };
+    #[cfg(unix)]
+    let parameters = BootstrapParameters::from_user_data(&read_user_data(&opts)?)?;
+    let result = register(
+        parameters,
+        runtime_config,
+        opts.client_opts,
+        opts.reg_client_opts,
+        registry,
+    )?;
+    #[cfg(windows)]
+    if opts.from_registry {
+        forget_credentials();
+    }
+    match result {
+        (site_id, false) => println!("Already registered with {site_id}, nothing to do."),
+        (site_id, true) => println!("Registration with {site_id} complete."),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_bootstrap {
+    use super::*;
+
+    #[test]
+    fn test_from_cloud_config() {
+        let parameters = BootstrapParameters::from_user_data(
+            "#cloud-config\n\
+             packages: [check-mk-agent]\n\
+             cmk_agent_ctl:\n  \
+               server: checkmk.example.com:8000\n  \
+               site: mysite\n  \
+               user: agent_registration\n  \
+               token: secret\n  \
+               labels:\n    \
+                 env: prod\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parameters,
+            BootstrapParameters {
+                server: String::from("checkmk.example.com:8000"),
+                site: String::from("mysite"),
+                user: String::from("agent_registration"),
+                password: String::from("secret"),
+                hostname: None,
+                hostname_from: None,
+                labels: types::AgentLabels::from([(String::from("env"), String::from("prod"))]),
+                root_cert: None,
+                trust_cert: false,
+            }
+        );
+        assert_eq!(
+            parameters.server_spec().unwrap(),
+            site_spec::ServerSpec {
+                server: String::from("checkmk.example.com"),
+                port: Some(8000),
+            }
+        );
+        assert!(
+            matches!(parameters.host().unwrap(), BootstrapHost::New(labels) if labels.len() == 1)
+        );
+    }
+
+    #[test]
+    fn test_from_json() {
+        let parameters = BootstrapParameters::from_user_data(
+            r#"{"cmk_agent_ctl": {"server": "checkmk", "site": "mysite", "user": "u", "password": "p", "hostname": "my-host", "trust_cert": true}}"#,
+        )
+        .unwrap();
+        assert!(parameters.trust_cert);
+        assert!(
+            matches!(parameters.host().unwrap(), BootstrapHost::Existing(host_name) if host_name == "my-host")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_from_registry_values() {
+        let parameters = BootstrapParameters::from_registry_values(vec![
+            (
+                String::from("Server"),
+                win_registry::Value::String(String::from("checkmk.example.com")),
+            ),
+            (
+                String::from("Site"),
+                win_registry::Value::String(String::from("mysite")),
+            ),
+            (
+                String::from("User"),
+                win_registry::Value::String(String::from("agent_registration")),
+            ),
+            (
+                String::from("Token"),
+                win_registry::Value::String(String::from("secret")),
+            ),
+            (
+                String::from("Hostname"),
+                win_registry::Value::String(String::new()),
+            ),
+            (
+                String::from("Labels"),
+                win_registry::Value::MultiString(vec![String::from("env:prod")]),
+            ),
+            (String::from("Trust_Cert"), win_registry::Value::Dword(1)),
+        ])
+        .unwrap();
+        assert_eq!(parameters.password, "secret");
+        assert!(parameters.trust_cert);
+        assert!(
+            matches!(parameters.host().unwrap(), BootstrapHost::New(labels) if labels.len() == 1)
+        );
+        assert!(BootstrapParameters::from_registry_values(vec![(
+            String::from("labels"),
+            win_registry::Value::MultiString(vec![String::from("env")]),
+        )])
+        .is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        for user_data in [
+            "#!/bin/sh\necho hello",
+            "#cloud-config\npackages: []\n",
+            "cmk_agent_ctl: {server: checkmk, site: mysite, user: u}",
+            "cmk_agent_ctl: {server: checkmk, site: mysite, user: u, password: p, port: 8000}",
+        ] {
+            assert!(
+                BootstrapParameters::from_user_data(user_data).is_err(),
+                "{user_data}"
+            );
+        }
+        let parameters = BootstrapParameters::from_user_data(
+            "cmk_agent_ctl: {server: checkmk, site: mysite, user: u, password: p, hostname: h, labels: {a: b}}",
+        )
+        .unwrap();
+        assert!(parameters.host().is_err());
+    }
+}
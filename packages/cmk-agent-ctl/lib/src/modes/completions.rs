@@ -0,0 +1,493 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Shell completion scripts. All shells complete along the same lines: the subcommands typed so
+//! far determine the command, whose options and subcommands are offered. Values of options are
+//! completed depending on their kind. Connections are listed by calling the controller, st. they
+//! reflect the registry at the time of completing.
+
+use crate::cli::Shell;
+use crate::cli_spec::{CommandSpec, ValueKind};
+use crate::config;
+use anyhow::Result as AnyhowResult;
+use std::fmt::Write;
+
+pub fn completions(shell: Option<Shell>, registry: Option<&config::Registry>) -> AnyhowResult<()> {
+    match (shell, registry) {
+        (Some(shell), _) => print!("{}", script(shell, &CommandSpec::of_cli())),
+        (None, Some(registry)) => {
+            for connection in connection_identifiers(registry) {
+                println!("{connection}");
+            }
+        }
+        (None, None) => {}
+    }
+    Ok(())
+}
+
+/// Site addresses of all connections and UUIDs of all connections
+fn connection_identifiers(registry: &config::Registry) -> Vec<String> {
+    let standard = || {
+        registry
+            .get_push_connections()
+            .chain(registry.get_standard_pull_connections())
+    };
+    let mut identifiers: Vec<String> = standard().map(|(site_id, _)| site_id.to_string()).collect();
+    identifiers.extend(standard().map(|(_, connection)| connection.trust.uuid.to_string()));
+    identifiers.extend(
+        registry
+            .get_imported_pull_connections()
+            .map(|connection| connection.uuid.to_string()),
+    );
+    identifiers
+}
+
+pub fn script(shell: Shell, spec: &CommandSpec) -> String {
+    let commands = spec
+        .walk()
+        .into_iter()
+        .map(|(path, command)| Command {
+            path: path.join(" "),
+            spec: command,
+        })
+        .collect::<Vec<Command>>();
+    match shell {
+        Shell::Bash => bash(&spec.name, &commands),
+        Shell::Zsh => zsh(&spec.name, &commands),
+        Shell::Fish => fish(&spec.name, &commands),
+        Shell::Powershell => powershell(&spec.name, &commands),
+    }
+}
+
+struct Command<'a> {
+    /// Names of the command and its parents, separated by spaces
+    path: String,
+    spec: &'a CommandSpec,
+}
+
+impl Command<'_> {
+    fn subcommand_paths(&self) -> impl Iterator<Item = (&str, String)> {
+        self.spec.subcommands.iter().map(|subcommand| {
+            (
+                subcommand.name.as_str(),
+                format!("{} {}", self.path, subcommand.name),
+            )
+        })
+    }
+
+    /// Possible values of positional arguments
+    fn positional_choices(&self) -> Vec<String> {
+        self.spec
+            .args
+            .iter()
+            .filter(|arg| arg.positional)
+            .flat_map(|arg| arg.value.iter())
+            .flat_map(|value| value.possible_values.iter().cloned())
+            .collect()
+    }
+
+    /// Options, subcommands and possible values of positional arguments
+    fn candidates(&self) -> Vec<String> {
+        self.spec
+            .option_names()
+            .into_iter()
+            .chain(self.spec.subcommands.iter().map(|sub| sub.name.clone()))
+            .chain(self.positional_choices())
+            .collect()
+    }
+
+    /// Options taking a value, along with the kind of value and its possible values
+    fn value_options(&self) -> impl Iterator<Item = (String, ValueKind, &[String])> {
+        self.spec
+            .args
+            .iter()
+            .filter(|arg| !arg.positional)
+            .flat_map(|arg| {
+                let names = arg.names();
+                arg.value.iter().flat_map(move |value| {
+                    names
+                        .clone()
+                        .into_iter()
+                        .map(|name| (name, value.kind, value.possible_values.as_slice()))
+                })
+            })
+    }
+}
+
+fn function_name(name: &str) -> String {
+    format!("_{}", name.replace('-', "_"))
+}
+
+fn bash(name: &str, commands: &[Command]) -> String {
+    let function = function_name(name);
+    let mut script = format!(
+        "{function}_connections() {{\n\
+         \x20   \"$1\" completions --connections 2>/dev/null\n\
+         }}\n\n\
+         {function}() {{\n\
+         \x20   local cur prev cmd word candidates\n\
+         \x20   cur=\"${{COMP_WORDS[COMP_CWORD]}}\"\n\
+         \x20   prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n\
+         \x20   cmd=\"{name}\"\n\
+         \x20   for word in \"${{COMP_WORDS[@]:1:COMP_CWORD-1}}\"; do\n\
+         \x20       case \"${{cmd}} ${{word}}\" in\n"
+    );
+    for command in commands {
+        for (_, path) in command.subcommand_paths() {
+            let _ = writeln!(script, "            \"{path}\") cmd=\"{path}\" ;;");
+        }
+    }
+    script.push_str("        esac\n    done\n    case \"${cmd} ${prev}\" in\n");
+    for command in commands {
+        for (option, kind, possible_values) in command.value_options() {
+            let completion = match kind {
+                ValueKind::Choice => format!(
+                    "COMPREPLY=($(compgen -W \"{}\" -- \"${{cur}}\"))",
+                    possible_values.join(" ")
+                ),
+                ValueKind::Connection => format!(
+                    "COMPREPLY=($(compgen -W \"$({function}_connections \"${{COMP_WORDS[0]}}\")\" -- \"${{cur}}\"))"
+                ),
+                ValueKind::Path => String::from("COMPREPLY=($(compgen -f -- \"${cur}\"))"),
+                ValueKind::Other => String::from("COMPREPLY=()"),
+            };
+            let _ = writeln!(
+                script,
+                "        \"{} {option}\")\n            {completion}\n            return ;;",
+                command.path
+            );
+        }
+    }
+    script.push_str("    esac\n    case \"${cmd}\" in\n");
+    for command in commands {
+        let connections = match command.spec.completes_connection() {
+            true => format!(" $({function}_connections \"${{COMP_WORDS[0]}}\")"),
+            false => String::new(),
+        };
+        let _ = writeln!(
+            script,
+            "        \"{}\") candidates=\"{}{connections}\" ;;",
+            command.path,
+            command.candidates().join(" ")
+        );
+    }
+    let _ = write!(
+        script,
+        "    esac\n\
+         \x20   COMPREPLY=($(compgen -W \"${{candidates}}\" -- \"${{cur}}\"))\n\
+         }}\n\n\
+         complete -o bashdefault -o default -F {function} {name}\n"
+    );
+    script
+}
+
+fn zsh(name: &str, commands: &[Command]) -> String {
+    let function = function_name(name);
+    let mut script = format!(
+        "#compdef {name}\n\n\
+         {function}() {{\n\
+         \x20   local cmd=\"{name}\" word prev=\"${{words[CURRENT-1]}}\"\n\
+         \x20   local -a candidates\n\
+         \x20   for word in \"${{(@)words[2,CURRENT-1]}}\"; do\n\
+         \x20       case \"${{cmd}} ${{word}}\" in\n"
+    );
+    for command in commands {
+        for (_, path) in command.subcommand_paths() {
+            let _ = writeln!(script, "            (\"{path}\") cmd=\"{path}\" ;;");
+        }
+    }
+    script.push_str("        esac\n    done\n    case \"${cmd} ${prev}\" in\n");
+    let connections = "${(f)\"$(\"${words[1]}\" completions --connections 2>/dev/null)\"}";
+    for command in commands {
+        for (option, kind, possible_values) in command.value_options() {
+            let completion = match kind {
+                ValueKind::Choice => format!("compadd -- {}", possible_values.join(" ")),
+                ValueKind::Connection => format!("compadd -- {connections}"),
+                ValueKind::Path => String::from("_files"),
+                ValueKind::Other => String::from("_message 'value'"),
+            };
+            let _ = writeln!(
+                script,
+                "        (\"{} {option}\")\n            {completion}\n            return ;;",
+                command.path
+            );
+        }
+    }
+    script.push_str("    esac\n    case \"${cmd}\" in\n");
+    for command in commands {
+        let connections = match command.spec.completes_connection() {
+            true => format!(" {connections}"),
+            false => String::new(),
+        };
+        let _ = writeln!(
+            script,
+            "        (\"{}\") candidates=({}{connections}) ;;",
+            command.path,
+            command.candidates().join(" ")
+        );
+    }
+    let _ = write!(
+        script,
+        "    esac\n\
+         \x20   compadd -- \"${{candidates[@]}}\"\n\
+         }}\n\n\
+         if [ \"$funcstack[1]\" = \"{function}\" ]; then\n\
+         \x20   {function} \"$@\"\n\
+         else\n\
+         \x20   compdef {function} {name}\n\
+         fi\n"
+    );
+    script
+}
+
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish(name: &str, commands: &[Command]) -> String {
+    let function = format!("_{function}", function = function_name(name));
+    let mut script = format!(
+        "function {function}_command\n\
+         \x20   set -l words (commandline -opc)\n\
+         \x20   set -e words[1]\n\
+         \x20   set -l cmd \"{name}\"\n\
+         \x20   for word in $words\n\
+         \x20       switch \"$cmd $word\"\n"
+    );
+    for command in commands {
+        for (_, path) in command.subcommand_paths() {
+            let _ = writeln!(
+                script,
+                "            case \"{path}\"\n                set cmd \"{path}\""
+            );
+        }
+    }
+    let _ = write!(
+        script,
+        "        end\n\
+         \x20   end\n\
+         \x20   echo $cmd\n\
+         end\n\n\
+         function {function}_connections\n\
+         \x20   {name} completions --connections 2>/dev/null\n\
+         end\n\n\
+         complete -c {name} -f\n"
+    );
+    for command in commands {
+        let condition = fish_quote(&format!("test ({function}_command) = \"{}\"", command.path));
+        for subcommand in &command.spec.subcommands {
+            let _ = writeln!(
+                script,
+                "complete -c {name} -n {condition} -a {} -d {}",
+                subcommand.name,
+                fish_quote(&subcommand.about)
+            );
+        }
+        for arg in command.spec.args.iter().filter(|arg| !arg.positional) {
+            let mut line = format!("complete -c {name} -n {condition}");
+            if let Some(long) = &arg.long {
+                let _ = write!(line, " -l {long}");
+            }
+            if let Some(short) = arg.short {
+                let _ = write!(line, " -s {short}");
+            }
+            match arg
+                .value
+                .as_ref()
+                .map(|value| (value.kind, &value.possible_values))
+            {
+                Some((ValueKind::Choice, possible_values)) => {
+                    let _ = write!(line, " -x -a {}", fish_quote(&possible_values.join(" ")));
+                }
+                Some((ValueKind::Connection, _)) => {
+                    let _ = write!(line, " -x -a '({function}_connections)'");
+                }
+                Some((ValueKind::Path, _)) => line.push_str(" -r -F"),
+                Some((ValueKind::Other, _)) => line.push_str(" -x"),
+                None => {}
+            }
+            let _ = writeln!(
+                script,
+                "{line} -d {}",
+                fish_quote(arg.help.lines().next().unwrap_or_default())
+            );
+        }
+        if command.spec.completes_connection() {
+            let _ = writeln!(
+                script,
+                "complete -c {name} -n {condition} -a '({function}_connections)'"
+            );
+        }
+        let choices = command.positional_choices();
+        if !choices.is_empty() {
+            let _ = writeln!(
+                script,
+                "complete -c {name} -n {condition} -a {}",
+                fish_quote(&choices.join(" "))
+            );
+        }
+    }
+    script
+}
+
+fn powershell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+fn powershell_list(items: &[String]) -> String {
+    match items.is_empty() {
+        true => String::from("@()"),
+        false => format!(
+            "@({})",
+            items
+                .iter()
+                .map(|item| powershell_quote(item))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn powershell(name: &str, commands: &[Command]) -> String {
+    let mut script = format!(
+        "Register-ArgumentCompleter -Native -CommandName '{name}', '{name}.exe' -ScriptBlock {{\n\
+         \x20   param($wordToComplete, $commandAst, $cursorPosition)\n\
+         \x20   $words = @($commandAst.CommandElements |\n\
+         \x20       Where-Object {{ $_.Extent.EndOffset -lt $cursorPosition }} |\n\
+         \x20       ForEach-Object {{ $_.ToString() }})\n\
+         \x20   $connections = {{ & $words[0] completions --connections 2>$null }}\n\
+         \x20   $cmd = '{name}'\n\
+         \x20   foreach ($word in ($words | Select-Object -Skip 1)) {{\n\
+         \x20       switch -Exact -CaseSensitive (\"$cmd $word\") {{\n"
+    );
+    for command in commands {
+        for (_, path) in command.subcommand_paths() {
+            let _ = writeln!(
+                script,
+                "            {} {{ $cmd = {} }}",
+                powershell_quote(&path),
+                powershell_quote(&path)
+            );
+        }
+    }
+    script.push_str(
+        "        }\n    }\n    $prev = $words[-1]\n    $candidates = $null\n    \
+         switch -Exact -CaseSensitive (\"$cmd $prev\") {\n",
+    );
+    for command in commands {
+        for (option, kind, possible_values) in command.value_options() {
+            let completion = match kind {
+                ValueKind::Choice => format!("$candidates = {}", powershell_list(possible_values)),
+                ValueKind::Connection => String::from("$candidates = & $connections"),
+                // Leaves it to the default completion of file names
+                ValueKind::Path | ValueKind::Other => String::from("return"),
+            };
+            let _ = writeln!(
+                script,
+                "        {} {{ {completion} }}",
+                powershell_quote(&format!("{} {option}", command.path))
+            );
+        }
+    }
+    script.push_str(
+        "    }\n    if ($null -eq $candidates) {\n        switch -Exact -CaseSensitive ($cmd) {\n",
+    );
+    for command in commands {
+        let connections = match command.spec.completes_connection() {
+            true => " + @(& $connections)",
+            false => "",
+        };
+        let _ = writeln!(
+            script,
+            "            {} {{ $candidates = {}{connections} }}",
+            powershell_quote(&command.path),
+            powershell_list(&command.candidates())
+        );
+    }
+    script.push_str(
+        "        }\n    }\n    \
+         $candidates | Where-Object { $_ -like \"$wordToComplete*\" } | ForEach-Object {\n        \
+         [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)\n    \
+         }\n}\n",
+    );
+    script
+}
+
+#[cfg(test)]
+mod test_completions {
+    use super::*;
+    use crate::site_spec;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_connection_identifiers() {
+        let test_registry = config::test_helpers::TestRegistry::new()
+            .add_connection(
+                &config::ConnectionMode::Push,
+                "server/push-site",
+                "0096abd7-8b8f-4b6f-8b5f-3d5d3a2d8e1f",
+            )
+            .add_imported_connection("9fd4a4b3-4d58-4a48-8b6b-6e4c2c9fc3d5");
+        let registry = &test_registry.registry;
+        assert_eq!(
+            connection_identifiers(registry),
+            vec![
+                "server/push-site",
+                "0096abd7-8b8f-4b6f-8b5f-3d5d3a2d8e1f",
+                "9fd4a4b3-4d58-4a48-8b6b-6e4c2c9fc3d5",
+            ]
+        );
+        assert!(site_spec::SiteID::from_str(&connection_identifiers(registry)[0]).is_ok());
+    }
+
+    #[test]
+    fn test_bash() {
+        let script = script(Shell::Bash, &CommandSpec::of_cli());
+        assert!(script.contains(
+            "            \"cmk-agent-ctl legacy-pull\") cmd=\"cmk-agent-ctl legacy-pull\" ;;\n"
+        ));
+        assert!(script.contains(
+            "        \"cmk-agent-ctl --log-format\")\n            COMPREPLY=($(compgen -W \"text json\" -- \"${cur}\"))\n"
+        ));
+        assert!(script.contains(
+            "        \"cmk-agent-ctl delete\") candidates=\"--help -h $(_cmk_agent_ctl_connections \"${COMP_WORDS[0]}\")\" ;;\n"
+        ));
+        assert!(script.contains(
+            "        \"cmk-agent-ctl completions\") candidates=\"--help -h bash zsh fish powershell\" ;;\n"
+        ));
+        assert!(script
+            .ends_with("complete -o bashdefault -o default -F _cmk_agent_ctl cmk-agent-ctl\n"));
+    }
+
+    #[test]
+    fn test_zsh() {
+        let script = script(Shell::Zsh, &CommandSpec::of_cli());
+        assert!(script.starts_with("#compdef cmk-agent-ctl\n"));
+        assert!(script.contains("        (\"cmk-agent-ctl status --connection\")\n            compadd -- ${(f)\"$(\"${words[1]}\" completions --connections 2>/dev/null)\"}\n"));
+    }
+
+    #[test]
+    fn test_fish() {
+        let script = script(Shell::Fish, &CommandSpec::of_cli());
+        assert!(script.contains(
+            "complete -c cmk-agent-ctl -n 'test (__cmk_agent_ctl_command) = \"cmk-agent-ctl\"' -a delete -d 'Delete a connection to a Checkmk instance'\n"
+        ));
+        assert!(script.contains(
+            "complete -c cmk-agent-ctl -n 'test (__cmk_agent_ctl_command) = \"cmk-agent-ctl status\"' -l connection -x -a '(__cmk_agent_ctl_connections)'"
+        ));
+        assert_eq!(fish_quote("it's"), "'it\\'s'");
+    }
+
+    #[test]
+    fn test_powershell() {
+        let script = script(Shell::Powershell, &CommandSpec::of_cli());
+        assert!(script.contains(
+            "        'cmk-agent-ctl --log-format' { $candidates = @('text', 'json') }\n"
+        ));
+        assert!(script.contains(
+            "            'cmk-agent-ctl delete' { $candidates = @('--help', '-h') + @(& $connections) }\n"
+        ));
+        assert_eq!(powershell_quote("it's"), "'it''s'");
+    }
+}
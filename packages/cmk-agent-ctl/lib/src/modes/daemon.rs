@@ -0,0 +1,677 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::config;
+use crate::config::{JSONLoader, TOMLLoaderMissingSafe};
+use crate::connection_stats::ConnectionStats;
+use crate::constants;
+use crate::hardware_labels;
+use crate::integrity;
+use crate::ipc;
+use crate::lifecycle::Lifecycle;
+use crate::metrics;
+use crate::misc;
+use crate::modes::registration;
+use crate::modes::{import_connection, pull, push, renew_certificate};
+use crate::payload_memory::PayloadMemory;
+use crate::payload_stats::PayloadStats;
+use crate::post_processing::Pipeline;
+#[cfg(unix)]
+use crate::privileges;
+use crate::pull_tunnel;
+use crate::push_spool::PushSpool;
+use crate::realtime;
+use crate::reregistration;
+use crate::scheduler_state::SchedulerState;
+#[cfg(unix)]
+use crate::sd_notify;
+use crate::setup;
+use crate::status_section::StatusSection;
+use crate::supervisor::Supervisor;
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use log::{error, info, warn};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use tokio::sync::oneshot;
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+
+/// Send panic information in log.
+/// This is critically important for daemon mode
+fn register_panic_handler() {
+    let default_panic = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |panic_info| {
+        log::error!("Panic in controller '{:?}'", panic_info);
+        default_panic(panic_info);
+    }));
+}
+
+pub fn daemon(
+    paths: &setup::PathResolver,
+    mut registry: config::Registry,
+    pull_config: config::PullConfig,
+    push_config: config::PushConfig,
+    client_config: config::ClientConfig,
+    metrics_config: config::MetricsConfig,
+    lifecycle: Lifecycle,
+) -> AnyhowResult<()> {
+    register_panic_handler();
+    lifecycle.checkpoint("Processing pre-configured connections");
+    process_pre_configured_connections(
+        &paths.pre_configured_connections_path,
+        &mut registry,
+        &client_config,
+    );
+    lifecycle.checkpoint("Importing connection from image");
+    process_image_connection(&paths.image_connection_path, &mut registry, &client_config);
+    lifecycle.checkpoint("Binding sockets");
+    // Like IPC, the metrics endpoint is no reason to stop monitoring
+    let metrics_listener =
+        metrics_config
+            .address()
+            .and_then(|address| match metrics::bind(&address) {
+                Ok(listener) => Some(listener),
+                Err(err) => {
+                    error!(
+                        "Error serving metrics and health checks, endpoint is unavailable. ({})",
+                        err
+                    );
+                    None
+                }
+            });
+    #[cfg(unix)]
+    let pull_listener = if privileges::pending() {
+        let pull_listener = pull::privileged_listener(&pull_config)?;
+        drop_privileges(paths)?;
+        pull_listener
+    } else {
+        None
+    };
+    #[cfg(windows)]
+    let pull_listener = None;
+    // All tasks share one runtime. Blocking calls, i.e. the ones to the agent receiver, go to
+    // its bounded pool of blocking threads.
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .max_blocking_threads(constants::MAX_BLOCKING_THREADS)
+        .build()
+        .context("Failed to start runtime")?
+        .block_on(run(
+            paths,
+            registry,
+            pull_config,
+            push_config,
+            client_config,
+            Listeners {
+                metrics: metrics_listener,
+                pull: pull_listener,
+            },
+            lifecycle,
+        ))
+}
+
+/// Hand the files written by the daemon over to the configured user, then switch to it
+#[cfg(unix)]
+fn drop_privileges(paths: &setup::PathResolver) -> AnyhowResult<()> {
+    let identity =
+        privileges::Identity::resolve(&config::PrivilegesConfig::load(&paths.config_path))?;
+    let registry_checksum_path = config::Registry::checksum_path(&paths.registry_path);
+    // Registering again happens in the daemon
+    let registration_throttle_path = paths
+        .registry_path
+        .with_file_name(constants::REGISTRATION_THROTTLE_FILE);
+    let reregistration_credentials_path = paths
+        .registry_path
+        .with_file_name(constants::REREGISTRATION_CREDENTIALS_FILE);
+    let spooled = std::fs::read_dir(&paths.push_spool_path)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .collect()
+        })
+        .unwrap_or_else(|_| vec![]);
+    for path in [
+        &paths.home_dir,
+        &paths.state_dir,
+        &paths.registry_path,
+        &registry_checksum_path,
+        &paths.connection_stats_path,
+        &paths.payload_stats_path,
+        &paths.remote_status_cache_path,
+        &paths.integrity_check_path,
+        &registration_throttle_path,
+        &reregistration_credentials_path,
+        &paths.push_spool_path,
+    ]
+    .into_iter()
+    .chain(spooled.iter())
+    {
+        match identity.take_ownership(path) {
+            Ok(true) => info!(
+                "Changed owner of {} to {}:{}",
+                path.display(),
+                identity.user.name,
+                identity.group.name
+            ),
+            Ok(false) => {}
+            // The daemon might still get along, eg. if the file is writable for the group
+            Err(err) => warn!("{:#}", err),
+        }
+    }
+    identity.assume().context(format!(
+        "Failed to drop root privileges to user {}",
+        identity.user.name
+    ))?;
+    info!(
+        "Dropped root privileges, running as {}:{}",
+        identity.user.name, identity.group.name
+    );
+    Ok(())
+}
+
+/// Sockets bound before the runtime starts, eg. while the daemon still has root privileges
+struct Listeners {
+    metrics: Option<std::net::TcpListener>,
+    pull: Option<std::net::TcpListener>,
+}
+
+async fn run(
+    paths: &setup::PathResolver,
+    registry: config::Registry,
+    pull_config: config::PullConfig,
+    push_config: config::PushConfig,
+    client_config: config::ClientConfig,
+    listeners: Listeners,
+    lifecycle: Lifecycle,
+) -> AnyhowResult<()> {
+    let connection_stats = ConnectionStats::new(&paths.connection_stats_path)
+        .with_payload_stats(PayloadStats::new(&paths.payload_stats_path));
+    let push_spool = PushSpool::new(
+        &paths.push_spool_path,
+        push_config.push_spool_size,
+        push_config.push_spool_max_size,
+    );
+    if let Some(listener) = listeners.metrics {
+        let metrics = metrics::Metrics::new(
+            registry.clone(),
+            connection_stats.clone(),
+            push_spool.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(listener, move |path| metrics.handle(path)).await {
+                error!(
+                    "Error serving metrics and health checks, endpoint is unavailable. ({})",
+                    err
+                );
+            }
+        });
+    }
+    if push_config.realtime.is_enabled() {
+        let realtime = realtime::push(
+            registry.clone(),
+            push_config.realtime.clone(),
+            client_config.clone(),
+            lifecycle.clone(),
+        );
+        tokio::spawn(async move {
+            // The full agent output is still pushed
+            if let Err(err) = realtime.await {
+                error!(
+                    "Error pushing real-time sections, channel is closed. ({})",
+                    err
+                );
+            }
+        });
+    }
+    if push_config.reregistration.enabled {
+        let reregistration = reregistration::daemon(registry.clone(), client_config.clone());
+        tokio::spawn(async move {
+            // Rejected connections stay as they are, as without automatic re-registration
+            if let Err(err) = reregistration.await {
+                error!(
+                    "Error re-registering rejected connections, re-registration is stopped. ({})",
+                    err
+                );
+            }
+        });
+    }
+    if push_config.hardware_labels {
+        let label_updates = hardware_labels::daemon(registry.clone(), client_config.clone());
+        tokio::spawn(async move {
+            // The labels of the registration stay in place
+            if let Err(err) = label_updates.await {
+                error!(
+                    "Error updating hardware labels, updates are stopped. ({})",
+                    err
+                );
+            }
+        });
+    }
+    let integrity_check = integrity::daemon(registry.clone(), paths.integrity_check_path.clone());
+    tokio::spawn(async move {
+        // Failures are reported, the connections are used regardless
+        if let Err(err) = integrity_check.await {
+            error!(
+                "Error running integrity checks, checks are stopped. ({})",
+                err
+            );
+        }
+    });
+    let (tx_push_now, rx_push_now) = mpsc::channel(1);
+    let tunnel_push_now = tx_push_now.clone();
+    let scheduler = SchedulerState::default();
+    let supervisor = Supervisor::default();
+    let mut push = supervisor.spawn("push", {
+        let registry = registry.clone();
+        let client_config = client_config.clone();
+        let agent_channel = pull_config.agent_channel.clone();
+        let connection_stats = connection_stats.clone();
+        let push_spool = push_spool.clone();
+        let control = push::PushControl {
+            push_now: Arc::new(AsyncMutex::new(rx_push_now)),
+            lifecycle: lifecycle.clone(),
+            scheduler: scheduler.clone(),
+        };
+        move || {
+            push::push(
+                registry.clone(),
+                client_config.clone(),
+                push_config.clone(),
+                agent_channel.clone(),
+                connection_stats.clone(),
+                push_spool.clone(),
+                control.clone(),
+            )
+        }
+    });
+    let path_control_socket = paths.control_socket_path.clone();
+    let control = Control {
+        push_now: tx_push_now,
+        lifecycle: lifecycle.clone(),
+        started: misc::unix_now(),
+        config_path: paths.config_path.clone(),
+        scheduler: scheduler.clone(),
+        push_spool: push_spool.clone(),
+        supervisor: supervisor.clone(),
+    };
+    tokio::spawn(async move {
+        // Not being able to serve IPC requests is no reason to stop monitoring
+        if let Err(err) = ipc::serve(&path_control_socket, |request| {
+            handle_ipc_request(request, &control)
+        })
+        .await
+        {
+            error!(
+                "Error serving IPC requests, push-now is unavailable. ({})",
+                err
+            );
+        }
+    });
+    #[cfg(unix)]
+    let ready = {
+        let (ready, listening) = oneshot::channel();
+        tokio::spawn(notify_service_manager(registry.clone(), listening));
+        Some(ready)
+    };
+    #[cfg(windows)]
+    let ready = None;
+    let status_section = pull_config.status_section.then(|| {
+        StatusSection::new(
+            registry.clone(),
+            connection_stats.clone(),
+            push_spool.clone(),
+        )
+    });
+    // Also kept running without the settings, connections registered with the transport
+    // WebSocket are pulled through tunnels regardless
+    let tunnels = pull_tunnel::serve(
+        registry.clone(),
+        pull_config.pull_tunnel,
+        pull_config.reverse_connection,
+        client_config.ip_preference,
+        pull::AgentOutputCollectorImpl::new(
+            &pull_config.agent_channel,
+            PayloadMemory::new(pull_config.max_payload_memory),
+            Arc::new(
+                Pipeline::new(&pull_config.section_filter, &pull_config.post_processors)?
+                    .with_payload_accounting(&pull_config.payload_size, connection_stats.payload())
+                    .with_status_section(status_section.clone()),
+            ),
+        )
+        .with_fallback_command(pull_config.fallback_command.clone()),
+        connection_stats.clone(),
+        tunnel_push_now,
+    );
+    tokio::spawn(async move {
+        // The pull port still serves pull requests
+        if let Err(err) = tunnels.await {
+            error!(
+                "Error serving pull requests through tunnels, tunnels are closed. ({})",
+                err
+            );
+        }
+    });
+    let pull = supervisor.spawn("pull", {
+        let mut ready = ready;
+        let listener = listeners.pull;
+        move || {
+            pull::async_pull(
+                pull_config.clone(),
+                connection_stats.clone(),
+                status_section.clone(),
+                ready.take(),
+                // Without a copy of the pre-bound listener, a restarted task binds the port again
+                listener
+                    .as_ref()
+                    .and_then(|listener| listener.try_clone().ok()),
+            )
+        }
+    });
+    let renew_certificate = supervisor.spawn("renew-certificate", move || {
+        renew_certificate::daemon(registry.clone(), client_config.clone(), scheduler.clone())
+    });
+    tokio::spawn(control_by_signals(lifecycle.clone()));
+    lifecycle.started();
+
+    // None of the tasks should ever finish, unless it failed too often, see supervisor. In that
+    // case, the error is propagated. Being asked to stop by the service manager ends the daemon
+    // successfully.
+    tokio::select! {
+        result = &mut push => supervised("push", result),
+        result = pull => supervised("pull", result),
+        result = renew_certificate => supervised("renew-certificate", result),
+        () = lifecycle.stopping() => drain(push, &lifecycle).await,
+    }
+}
+
+/// Give running pushes the chance to finish before stopping
+async fn drain(
+    mut push: tokio::task::JoinHandle<AnyhowResult<()>>,
+    lifecycle: &Lifecycle,
+) -> AnyhowResult<()> {
+    info!("Stopping daemon once running pushes have finished");
+    let deadline = Instant::now() + Duration::from_secs(constants::STOP_DRAIN_TIMEOUT);
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            push.abort();
+            warn!(
+                "Running pushes did not finish within {} seconds, stopping anyway",
+                constants::STOP_DRAIN_TIMEOUT
+            );
+            return Ok(());
+        }
+        let interval = Duration::from_secs(constants::STOP_CHECKPOINT_INTERVAL);
+        match tokio::time::timeout(remaining.min(interval), &mut push).await {
+            Ok(result) => return supervised("push", result),
+            Err(_) => lifecycle.checkpoint("Waiting for running pushes to finish"),
+        }
+    }
+}
+
+/// Stop the daemon on SIGTERM, which launchd and systemd send to stop it
+#[cfg(unix)]
+async fn control_by_signals(lifecycle: Lifecycle) {
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            terminate.recv().await;
+            info!("Received SIGTERM");
+            lifecycle.stop();
+        }
+        Err(err) => warn!(
+            "Failed to listen for SIGTERM, cannot stop gracefully. ({})",
+            err
+        ),
+    }
+}
+
+/// Stop the daemon on Ctrl+C, pause and continue it on Ctrl+Break
+#[cfg(windows)]
+async fn control_by_signals(lifecycle: Lifecycle) {
+    let (mut ctrl_c, mut ctrl_break) = match (
+        tokio::signal::windows::ctrl_c(),
+        tokio::signal::windows::ctrl_break(),
+    ) {
+        (Ok(ctrl_c), Ok(ctrl_break)) => (ctrl_c, ctrl_break),
+        (Err(err), _) | (_, Err(err)) => {
+            warn!("Failed to listen for console control events. ({})", err);
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            _ = ctrl_c.recv() => {
+                info!("Received Ctrl+C");
+                lifecycle.stop();
+                return;
+            }
+            _ = ctrl_break.recv() => {
+                info!("Received Ctrl+Break");
+                if !lifecycle.pause() {
+                    lifecycle.resume();
+                }
+            }
+        }
+    }
+}
+
+fn supervised(
+    task: &str,
+    result: Result<AnyhowResult<()>, tokio::task::JoinError>,
+) -> AnyhowResult<()> {
+    result.map_err(|err| anyhow!("The {} task crashed. ({})", task, err))?
+}
+
+/// Tell systemd that the daemon is ready, send keep-alives if its watchdog is enabled and report
+/// the registered connections as status. Running on the shared runtime, the keep-alives stop if
+/// the runtime hangs.
+#[cfg(unix)]
+async fn notify_service_manager(mut registry: config::Registry, listening: oneshot::Receiver<()>) {
+    if listening.await.is_err() {
+        // The pull task failed, the daemon is about to exit
+        return;
+    }
+    let mut status = connections_status(&registry);
+    match sd_notify::notify(&format!("READY=1\nSTATUS={status}")) {
+        Ok(true) => info!("Notified service manager about readiness"),
+        // Not running as systemd service of type notify
+        Ok(false) => return,
+        Err(err) => {
+            warn!("Failed to notify service manager. ({})", err);
+            return;
+        }
+    }
+    let watchdog = sd_notify::watchdog_interval();
+    // systemd recommends sending keep-alives at half the watchdog interval
+    let mut ticks = tokio::time::interval(watchdog.map_or(
+        Duration::from_secs(constants::sd_notify::STATUS_INTERVAL),
+        |interval| interval / 2,
+    ));
+    loop {
+        ticks.tick().await;
+        if watchdog.is_some() {
+            if let Err(err) = sd_notify::notify("WATCHDOG=1") {
+                warn!("Failed to send keep-alive to service manager. ({})", err);
+            }
+        }
+        if let Err(err) = registry.refresh() {
+            warn!("Failed to refresh registry for status. ({})", err);
+        }
+        let current = connections_status(&registry);
+        if current != status {
+            if let Err(err) = sd_notify::notify(&format!("STATUS={current}")) {
+                warn!("Failed to send status to service manager. ({})", err);
+            }
+            status = current;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn connections_status(registry: &config::Registry) -> String {
+    if registry.is_legacy_pull_active() {
+        return String::from("Legacy pull mode");
+    }
+    format!(
+        "Registered connections: {} push, {} pull",
+        registry.get_push_connections().count(),
+        registry.get_pull_connections().count()
+    )
+}
+
+/// What IPC requests are served with
+struct Control {
+    push_now: mpsc::Sender<push::PushNowRequest>,
+    lifecycle: Lifecycle,
+    /// Unix timestamp
+    started: u64,
+    config_path: std::path::PathBuf,
+    scheduler: SchedulerState,
+    push_spool: PushSpool,
+    supervisor: Supervisor,
+}
+
+impl Control {
+    fn status(&self) -> ipc::DaemonStatus {
+        ipc::DaemonStatus {
+            pid: std::process::id(),
+            version: String::from(constants::VERSION),
+            started: self.started,
+            state: format!("{:?}", self.lifecycle.state()).to_lowercase(),
+            log_level: setup::log_level(),
+            scheduler: self.scheduler.report(&self.push_spool),
+            restarts: self.supervisor.report(),
+        }
+    }
+
+    /// Only the log level can change without a restart
+    fn reload(&self) -> AnyhowResult<String> {
+        let runtime_config = config::RuntimeConfig::load_missing_safe(&self.config_path)?;
+        setup::reload_log_level(runtime_config.log_level())
+    }
+}
+
+async fn handle_ipc_request(request: ipc::Request, control: &Control) -> ipc::Response {
+    match request {
+        ipc::Request::PushNow { connection } => {
+            push::request_push_now(connection, &control.push_now).await
+        }
+        ipc::Request::SetLogLevel { spec } => match setup::change_log_level(spec.as_deref()) {
+            Ok(spec) => {
+                info!("Log level changed to '{}'", spec);
+                ipc::Response::LogLevel { spec }
+            }
+            Err(err) => ipc::Response::Error {
+                message: misc::anyhow_error_to_human_readable(&err),
+            },
+        },
+        ipc::Request::Reload => match control.reload() {
+            Ok(log_level) => {
+                info!("Reloaded config, log level is '{}'", log_level);
+                ipc::Response::Reloaded { log_level }
+            }
+            Err(err) => ipc::Response::Error {
+                message: misc::anyhow_error_to_human_readable(&err),
+            },
+        },
+        ipc::Request::Status => ipc::Response::Status {
+            status: control.status(),
+        },
+    }
+}
+
+fn process_pre_configured_connections(
+    path_pre_configured_connections: &std::path::Path,
+    registry: &mut config::Registry,
+    client_config: &config::ClientConfig,
+) {
+    match config::PreConfiguredConnections::load(path_pre_configured_connections) {
+        Ok(pre_configured) => {
+            if let Err(err) =
+                registration::register_pre_configured(&pre_configured, client_config, registry)
+            {
+                error!(
+                    "Error while processing pre-configured connections: {}",
+                    misc::anyhow_error_to_human_readable(&err)
+                )
+            }
+        }
+        Err(err) => info!(
+            "Could not load pre-configured connections from {:?}: {}",
+            path_pre_configured_connections,
+            misc::anyhow_error_to_human_readable(&err)
+        ),
+    }
+}
+
+fn process_image_connection(
+    path_image_connection: &std::path::Path,
+    registry: &mut config::Registry,
+    client_config: &config::ClientConfig,
+) {
+    if !path_image_connection.exists() {
+        return;
+    }
+    if let Err(err) =
+        import_connection::import_image_connection(path_image_connection, registry, client_config)
+    {
+        error!(
+            "Error while importing connection from image: {}",
+            misc::anyhow_error_to_human_readable(&err)
+        )
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test_daemon {
+    use super::*;
+    use crate::config::test_helpers::TestRegistry;
+
+    #[test]
+    fn test_connections_status() {
+        let mut test_registry = TestRegistry::new()
+            .add_connection(
+                &config::ConnectionMode::Push,
+                "server/push-site",
+                config::TrustedConnectionWithRemote::from("99f56bbc-5965-4b34-bc70-1959ad1d32d6"),
+            )
+            .add_imported_connection("b3501e4d-2820-433c-8e9c-38c69ac20fab");
+        assert_eq!(
+            connections_status(&test_registry.registry),
+            "Registered connections: 1 push, 1 pull"
+        );
+        test_registry.registry.clear();
+        test_registry.registry.activate_legacy_pull(None).unwrap();
+        assert_eq!(
+            connections_status(&test_registry.registry),
+            "Legacy pull mode"
+        );
+    }
+
+    #[test]
+    fn test_control() {
+        let dir = tempfile::tempdir().unwrap();
+        let control = Control {
+            push_now: mpsc::channel(1).0,
+            lifecycle: Lifecycle::default(),
+            started: 1000,
+            config_path: dir.path().join(constants::CONFIG_FILE),
+            scheduler: SchedulerState::default(),
+            push_spool: PushSpool::new(dir.path().join("spool"), 10, None),
+            supervisor: Supervisor::default(),
+        };
+        let status = control.status();
+        assert_eq!(status.pid, std::process::id());
+        assert_eq!(status.started, 1000);
+        assert_eq!(status.state, "starting");
+        assert!(status.scheduler.push.is_empty());
+        assert!(status.restarts.is_empty());
+        std::fs::write(&control.config_path, "log_level = \"info\"").unwrap();
+        // Logging is not initialized in the tests
+        assert!(control.reload().is_err());
+        std::fs::write(&control.config_path, "log_level = 1").unwrap();
+        assert!(control.reload().is_err());
+    }
+}
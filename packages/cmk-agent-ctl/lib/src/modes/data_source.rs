@@ -0,0 +1,97 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::renew_certificate;
+use crate::data_source::DataSource;
+use crate::{cli, config};
+use anyhow::Result as AnyhowResult;
+
+fn data_source(opts: &cli::DataSourceOpts) -> Option<DataSource> {
+    if let Some(socket) = &opts.socket {
+        return Some(DataSource::Socket {
+            socket: socket.clone(),
+        });
+    }
+    if let Some(command) = &opts.command {
+        return Some(DataSource::Command {
+            command: command.clone(),
+        });
+    }
+    opts.file
+        .as_ref()
+        .map(|path| DataSource::File { path: path.clone() })
+}
+
+/// Serve the agent output of the given source to a single connection, or the output of the agent
+/// socket again if no source is given.
+pub fn set_data_source(
+    registry: &mut config::Registry,
+    opts: &cli::DataSourceOpts,
+) -> AnyhowResult<()> {
+    let (connection, site_id) =
+        renew_certificate::find_site_for_ident(registry, &opts.connection_opts.connection)?;
+    connection.trust.data_source = data_source(opts);
+    match &connection.trust.data_source {
+        Some(data_source) => println!("Data source for '{site_id}' set to {data_source}"),
+        None => println!("Data source for '{site_id}' reset to the agent socket"),
+    }
+    registry.save()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::site_spec;
+    use config::test_helpers::TestRegistry;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    fn opts(connection: &str, file: Option<&str>) -> cli::DataSourceOpts {
+        cli::DataSourceOpts {
+            connection_opts: cli::ConnectionOpts {
+                connection: String::from(connection),
+            },
+            socket: None,
+            command: None,
+            file: file.map(PathBuf::from),
+        }
+    }
+
+    #[test]
+    fn test_set_data_source() {
+        let mut registry = TestRegistry::new().add_connection(
+            &config::ConnectionMode::Pull,
+            "server/site",
+            config::TrustedConnectionWithRemote::from("0096abd7-83c9-42f8-8b3a-3ffba7ba959d"),
+        );
+        let site_id = site_spec::SiteID::from_str("server/site").unwrap();
+        set_data_source(
+            &mut registry.registry,
+            &opts("server/site", Some("/var/lib/soc/agent.txt")),
+        )
+        .unwrap();
+        assert_eq!(
+            config::Registry::from_file(registry.registry.path())
+                .unwrap()
+                .get(&site_id)
+                .unwrap()
+                .trust
+                .data_source,
+            Some(DataSource::File {
+                path: PathBuf::from("/var/lib/soc/agent.txt")
+            })
+        );
+        set_data_source(
+            &mut registry.registry,
+            &opts("0096abd7-83c9-42f8-8b3a-3ffba7ba959d", None),
+        )
+        .unwrap();
+        assert_eq!(
+            registry.registry.get(&site_id).unwrap().trust.data_source,
+            None
+        );
+        assert!(set_data_source(&mut registry.registry, &opts("server/unknown", None)).is_err());
+    }
+}
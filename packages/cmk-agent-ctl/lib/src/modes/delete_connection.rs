@@ -0,0 +1,344 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use std::str::FromStr;
+
+use crate::{config, site_spec};
+use anyhow::{Context, Result as AnyhowResult};
+use serde::Serialize;
+
+/// A deleted connection, as written with --output
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct DeletedConnection {
+    /// Not known for imported connections
+    pub site_id: Option<String>,
+    pub uuid: String,
+    pub connection_mode: config::ConnectionMode,
+    pub imported: bool,
+}
+
+impl DeletedConnection {
+    fn standard(
+        site_id: &site_spec::SiteID,
+        (mode, uuid): (config::ConnectionMode, uuid::Uuid),
+    ) -> Self {
+        Self {
+            site_id: Some(site_id.to_string()),
+            uuid: uuid.to_string(),
+            connection_mode: mode,
+            imported: false,
+        }
+    }
+
+    fn imported(uuid: &uuid::Uuid) -> Self {
+        Self {
+            site_id: None,
+            uuid: uuid.to_string(),
+            connection_mode: config::ConnectionMode::Pull,
+            imported: true,
+        }
+    }
+}
+
+impl std::fmt::Display for DeletedConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.site_id {
+            Some(site_id) => write!(
+                f,
+                "Deleted {} connection '{}'",
+                match self.connection_mode {
+                    config::ConnectionMode::Push => "push",
+                    config::ConnectionMode::Pull => "pull",
+                },
+                site_id
+            ),
+            None => write!(f, "Deleted imported connection '{}'", self.uuid),
+        }
+    }
+}
+
+/// All connections deleted at once, as written with --output
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct DeletedConnections {
+    pub deleted: Vec<DeletedConnection>,
+}
+
+impl std::fmt::Display for DeletedConnections {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.deleted.is_empty() {
+            return write!(f, "No matching connections");
+        }
+        let lines: Vec<String> = self.deleted.iter().map(|d| d.to_string()).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+fn delete_by_uuid(
+    uuid: &uuid::Uuid,
+    registry: &mut config::Registry,
+) -> AnyhowResult<DeletedConnection> {
+    match registry.retrieve_standard_connection_by_uuid(uuid) {
+        Some(site_id) => Ok(DeletedConnection::standard(
+            &site_id,
+            registry.delete_standard_connection(&site_id)?,
+        )),
+        None => {
+            registry
+                .delete_imported_connection(uuid)
+                .context(format!("No connection with UUID '{uuid}'"))?;
+            Ok(DeletedConnection::imported(uuid))
+        }
+    }
+}
+
+pub fn delete(
+    registry: &mut config::Registry,
+    connection_id: &str,
+) -> AnyhowResult<DeletedConnection> {
+    let deleted = match site_spec::SiteID::from_str(connection_id) {
+        Ok(site_id) => Ok(DeletedConnection::standard(
+            &site_id,
+            registry.delete_standard_connection(&site_id)?,
+        )),
+        Err(_) => delete_by_uuid(
+            &uuid::Uuid::from_str(connection_id).context(
+                "Provided connection identifier is neither a valid site ID nor a valid UUID",
+            )?,
+            registry,
+        ),
+    }?;
+
+    registry.save()?;
+    Ok(deleted)
+}
+
+pub fn delete_all(
+    registry: &mut config::Registry,
+    enable_legacy_mode: bool,
+) -> AnyhowResult<DeletedConnections> {
+    let deleted = registry
+        .get_push_connections()
+        .map(|(site_id, connection)| {
+            DeletedConnection::standard(
+                site_id,
+                (config::ConnectionMode::Push, connection.trust.uuid),
+            )
+        })
+        .chain(
+            registry
+                .get_standard_pull_connections()
+                .map(|(site_id, connection)| {
+                    DeletedConnection::standard(
+                        site_id,
+                        (config::ConnectionMode::Pull, connection.trust.uuid),
+                    )
+                }),
+        )
+        .chain(
+            registry
+                .get_imported_pull_connections()
+                .map(|connection| DeletedConnection::imported(&connection.uuid)),
+        )
+        .collect();
+    registry.clear();
+    registry.save()?;
+    if enable_legacy_mode {
+        registry.activate_legacy_pull(None)?;
+    }
+    Ok(DeletedConnections { deleted })
+}
+
+/// Delete the standard connections carrying all the given tags and, given a time, which expired
+/// by then
+pub fn delete_selected(
+    registry: &mut config::Registry,
+    tags: &[(String, String)],
+    expired_at: Option<u64>,
+) -> AnyhowResult<DeletedConnections> {
+    let site_ids: Vec<site_spec::SiteID> = registry
+        .get_push_connections()
+        .chain(registry.get_standard_pull_connections())
+        .filter(|(_, connection)| {
+            connection.has_tags(tags)
+                && expired_at
+                    .map(|now| connection.is_expired(now))
+                    .unwrap_or(true)
+        })
+        .map(|(site_id, _)| site_id.clone())
+        .collect();
+    let deleted = site_ids
+        .iter()
+        .map(|site_id| {
+            Ok(DeletedConnection::standard(
+                site_id,
+                registry.delete_standard_connection(site_id)?,
+            ))
+        })
+        .collect::<AnyhowResult<Vec<DeletedConnection>>>()?;
+    if !deleted.is_empty() {
+        registry.save()?;
+    }
+    Ok(DeletedConnections { deleted })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::modes::delete_connection::{delete, delete_all, delete_selected, DeletedConnection};
+    use crate::*;
+    use config::test_helpers::TestRegistry;
+    const UUID_PUSH: &str = "0096abd7-83c9-42f8-8b3a-3ffba7ba959d";
+    const UUID_PULL: &str = "b3501e4d-2820-433c-8e9c-38c69ac20faa";
+    const UUID_PULL_IMP1: &str = "00c21714-5086-46d7-848e-5be72c715cfd";
+    const UUID_PULL_IMP2: &str = "3bf83706-8e47-4e38-beb6-b1ce83a4eee1";
+
+    fn registry() -> TestRegistry {
+        TestRegistry::new()
+            .add_connection(
+                &config::ConnectionMode::Push,
+                "server/push-site",
+                config::TrustedConnectionWithRemote::from(UUID_PUSH),
+            )
+            .add_connection(
+                &config::ConnectionMode::Pull,
+                "server/pull-site",
+                config::TrustedConnectionWithRemote::from(UUID_PULL),
+            )
+            .add_imported_connection(config::TrustedConnection::from(UUID_PULL_IMP1))
+            .add_imported_connection(config::TrustedConnection::from(UUID_PULL_IMP2))
+    }
+
+    #[test]
+    fn test_delete_by_site_id_ok() {
+        let mut r = registry();
+        assert!(!r.registry.path().exists());
+        assert!(delete(&mut r.registry, "server/push-site").is_ok());
+        assert!(r.registry.path().exists());
+    }
+
+    #[test]
+    fn test_delete_by_site_id_missing() {
+        let mut r = registry();
+        assert_eq!(
+            format!(
+                "{}",
+                delete(&mut r.registry, "someserver/site").unwrap_err()
+            ),
+            "Connection 'someserver/site' not found"
+        );
+    }
+
+    #[test]
+    fn test_delete_pull_by_uuid_ok() {
+        let mut r = registry();
+        assert!(!r.registry.path().exists());
+        let deleted = delete(&mut r.registry, UUID_PULL).unwrap();
+        assert_eq!(
+            deleted.to_string(),
+            "Deleted pull connection 'server/pull-site'"
+        );
+        assert_eq!(
+            serde_json::to_string(&deleted).unwrap(),
+            format!(
+                r#"{{"site_id":"server/pull-site","uuid":"{UUID_PULL}","connection_mode":"pull-agent","imported":false}}"#
+            )
+        );
+        assert!(r.registry.is_standard_pull_empty());
+        assert!(r.registry.path().exists());
+    }
+
+    #[test]
+    fn test_delete_push_by_uuid_ok() {
+        let mut r = registry();
+        assert!(!r.registry.path().exists());
+        assert!(delete(&mut r.registry, UUID_PUSH).is_ok());
+        assert!(r.registry.is_push_empty());
+        assert!(r.registry.path().exists());
+    }
+
+    #[test]
+    fn test_delete_pull_imported_ok() {
+        let mut r = registry();
+        assert!(!r.registry.path().exists());
+        let deleted = delete(&mut r.registry, UUID_PULL_IMP1).unwrap();
+        assert_eq!(
+            deleted.to_string(),
+            format!("Deleted imported connection '{UUID_PULL_IMP1}'")
+        );
+        assert!(deleted.imported);
+        assert!(r.registry.path().exists());
+    }
+
+    #[test]
+    fn test_delete_by_uuid_missing() {
+        let uuid = uuid::Uuid::new_v4();
+        let mut r = registry();
+        assert_eq!(
+            format!(
+                "{}",
+                delete(&mut r.registry, &uuid.to_string()).unwrap_err()
+            ),
+            format!("No connection with UUID '{}'", &uuid),
+        );
+    }
+
+    #[test]
+    fn test_delete_all_no_legacy_pull() {
+        let mut r = registry();
+        assert!(!r.registry.path().exists());
+        let deleted = delete_all(&mut r.registry, false).unwrap().deleted;
+        assert_eq!(deleted.len(), 4);
+        assert_eq!(
+            deleted[0],
+            DeletedConnection {
+                site_id: Some(String::from("server/push-site")),
+                uuid: String::from(UUID_PUSH),
+                connection_mode: config::ConnectionMode::Push,
+                imported: false,
+            }
+        );
+        assert!(r.registry.path().exists());
+        assert!(!r.registry.is_legacy_pull_active());
+    }
+
+    #[test]
+    fn test_delete_all_with_legacy_pull() {
+        let mut r = registry();
+        assert!(!r.registry.path().exists());
+        assert!(delete_all(&mut r.registry, true).is_ok());
+        assert!(r.registry.path().exists());
+        assert!(r.registry.is_legacy_pull_active());
+    }
+
+    #[test]
+    fn test_delete_selected() {
+        let env_test = (String::from("env"), String::from("test"));
+        let mut r = registry();
+        for (_, connection) in r.registry.get_standard_connections_as_mut() {
+            if connection.trust.uuid.to_string() == UUID_PULL {
+                connection.tags.extend([env_test.clone()]);
+                connection.expires = Some(1000);
+            }
+        }
+        let deleted = delete_selected(
+            &mut r.registry,
+            &[(String::from("env"), String::from("prod"))],
+            None,
+        )
+        .unwrap();
+        assert_eq!(deleted.to_string(), "No matching connections");
+        let deleted = delete_selected(&mut r.registry, &[], Some(999)).unwrap();
+        assert!(deleted.deleted.is_empty());
+        assert!(!r.registry.path().exists());
+
+        let deleted = delete_selected(&mut r.registry, &[env_test], Some(1000)).unwrap();
+        assert_eq!(
+            deleted.to_string(),
+            "Deleted pull connection 'server/pull-site'"
+        );
+        assert!(r.registry.is_standard_pull_empty());
+        assert!(!r.registry.is_push_empty());
+        assert!(!r.registry.is_imported_pull_empty());
+        assert!(r.registry.path().exists());
+    }
+}
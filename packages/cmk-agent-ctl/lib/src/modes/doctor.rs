@@ -0,0 +1,711 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::status::{ProblemsFound, Severity};
+use crate::agent_receiver_api::{self, RegistrationStatusV2, ServerTime};
+use crate::configuration::config::{self, TOMLLoaderMissingSafe};
+#[cfg(windows)]
+use crate::service_account::{self, Access};
+use crate::{certs, cli, constants, happy_eyeballs, misc, proxy, setup, site_spec, types};
+use anyhow::Result as AnyhowResult;
+#[cfg(windows)]
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Result of a single check, with advice on how to fix the problem, if any
+#[derive(Debug)]
+pub struct Finding {
+    pub severity: Severity,
+    pub subject: String,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+impl Finding {
+    pub fn ok(subject: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Ok,
+            subject: subject.into(),
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    pub fn problem(
+        severity: Severity,
+        subject: impl Into<String>,
+        message: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> Self {
+        Self {
+            severity,
+            subject: subject.into(),
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}: {}",
+            match self.severity {
+                Severity::Ok => "[OK]  ",
+                Severity::Warning => "[WARN]",
+                Severity::Error => "[ERR] ",
+            },
+            self.subject,
+            self.message
+        )?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n       -> {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+fn check_config(paths: &setup::PathResolver) -> (Finding, Option<config::RuntimeConfig>) {
+    match config::RuntimeConfig::load_missing_safe(&paths.config_path) {
+        Ok(runtime_config) => (
+            Finding::ok("Configuration", format!("Loaded {:?}", paths.config_path)),
+            Some(runtime_config),
+        ),
+        Err(err) => (
+            Finding::problem(
+                Severity::Error,
+                "Configuration",
+                format!(
+                    "Cannot load {:?}: {}",
+                    paths.config_path,
+                    misc::anyhow_error_to_human_readable(&err)
+                ),
+                "Fix the file or remove it to use the default settings",
+            ),
+            None,
+        ),
+    }
+}
+
+fn check_registry(paths: &setup::PathResolver) -> (Finding, Option<config::Registry>) {
+    let registry = match config::Registry::from_file(&paths.registry_path) {
+        Ok(registry) => registry,
+        Err(err) => {
+            return (
+                Finding::problem(
+                    Severity::Error,
+                    "Registry",
+                    format!(
+                        "Cannot load {:?}: {}",
+                        paths.registry_path,
+                        misc::anyhow_error_to_human_readable(&err)
+                    ),
+                    "Restore the file from a backup, or delete it and register again",
+                ),
+                None,
+            )
+        }
+    };
+    let connections =
+        registry.get_push_connections().count() + registry.get_pull_connections().count();
+    (
+        match connections {
+            0 => Finding::problem(
+                Severity::Warning,
+                "Registry",
+                "No connections registered",
+                "Register with 'cmk-agent-ctl register'",
+            ),
+            _ => Finding::ok(
+                "Registry",
+                format!("{connections} connection(s) registered"),
+            ),
+        },
+        Some(registry),
+    )
+}
+
+fn check_agent_socket(agent_channel: &types::AgentChannel) -> Finding {
+    match agent_channel.operational() {
+        true => Finding::ok("Agent socket", format!("{agent_channel} is operational")),
+        false => Finding::problem(
+            Severity::Error,
+            "Agent socket",
+            format!("{agent_channel} is not operational"),
+            "Make sure the Checkmk agent is installed and its socket or service is running",
+        ),
+    }
+}
+
+/// Proxy for HTTPS connections as configured in the environment
+fn proxy_from_env(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+        .iter()
+        .find_map(|name| var(name).filter(|value| !value.is_empty()))
+}
+
+fn check_proxy(use_proxy: bool, proxy: Option<String>) -> Finding {
+    match (use_proxy, proxy) {
+        (true, Some(proxy)) => match reqwest::Proxy::https(&proxy) {
+            Ok(_) => Finding::ok("Proxy", format!("Connecting via {proxy}")),
+            Err(err) => Finding::problem(
+                Severity::Error,
+                "Proxy",
+                format!("Invalid proxy {proxy}: {err}"),
+                "Fix the proxy settings in the environment (HTTPS_PROXY)",
+            ),
+        },
+        (true, None) => Finding::ok(
+            "Proxy",
+            "Proxy detection enabled, but no proxy configured, connecting directly",
+        ),
+        (false, Some(proxy)) => Finding {
+            severity: Severity::Ok,
+            subject: String::from("Proxy"),
+            message: format!("Ignoring proxy {proxy}, connecting directly"),
+            hint: Some(String::from(
+                "Pass --detect-proxy or set detect_proxy = true in cmk-agent-ctl.toml if the \
+                 sites are only reachable via this proxy, or --auto-proxy if only some are",
+            )),
+        },
+        (false, None) => Finding::ok("Proxy", "No proxy configured, connecting directly"),
+    }
+}
+
+fn check_proxy_discovery(discovery: &AnyhowResult<proxy::Discovery>) -> Finding {
+    match discovery {
+        Ok(discovery) => Finding::ok("Proxy", format!("Automatic discovery: {discovery}")),
+        Err(err) => Finding::problem(
+            Severity::Warning,
+            "Proxy",
+            format!("Automatic discovery failed, using the proxy from the environment: {err:#}"),
+            "Check the proxy auto-config script (CMK_AGENT_CTL_PAC_URL under Unix, Internet \
+             options under Windows)",
+        ),
+    }
+}
+
+fn check_certificate(subject: &str, certificate: &str, now: i64) -> Finding {
+    let not_after = certs::parse_pem(certificate)
+        .and_then(|pem| Ok(pem.parse_x509()?.validity().not_after.timestamp()));
+    let remaining = match not_after {
+        Ok(not_after) => not_after - now,
+        Err(err) => {
+            return Finding::problem(
+                Severity::Error,
+                subject,
+                format!("Cannot parse certificate: {err}"),
+                "Register the connection again",
+            )
+        }
+    };
+    if remaining <= 0 {
+        Finding::problem(
+            Severity::Error,
+            subject,
+            "Certificate expired",
+            "Register the connection again",
+        )
+    } else if remaining < constants::CERT_VALIDITY_LOWER_LIMIT as i64 {
+        Finding::problem(
+            Severity::Warning,
+            subject,
+            format!(
+                "Certificate expires in {}",
+                misc::human_readable_duration(remaining as u64)
+            ),
+            format!(
+                "Renew it with 'cmk-agent-ctl renew-certificate {subject}', this is usually done \
+                 by the daemon"
+            ),
+        )
+    } else {
+        Finding::ok(
+            subject,
+            format!(
+                "Certificate valid for {}",
+                misc::human_readable_duration(remaining as u64)
+            ),
+        )
+    }
+}
+
+fn check_port(
+    site_id: &site_spec::SiteID,
+    port: u16,
+    ip_preference: happy_eyeballs::IpPreference,
+) -> Finding {
+    let subject = site_id.to_string();
+    let addresses = match happy_eyeballs::resolve(&site_id.server, port, ip_preference) {
+        Ok(addresses) => addresses,
+        Err(err) => {
+            return Finding::problem(
+                Severity::Error,
+                subject,
+                format!("{:#}", err),
+                "Check the DNS resolution of the Checkmk server",
+            )
+        }
+    };
+    match happy_eyeballs::connect_timeout(
+        &addresses,
+        Duration::from_secs(constants::DOCTOR_CONNECT_TIMEOUT),
+    )
+    .and_then(|stream| Ok(stream.peer_addr()?))
+    {
+        Ok(address) => Finding::ok(subject, format!("Agent receiver reachable at {address}")),
+        Err(_) => Finding::problem(
+            Severity::Error,
+            subject,
+            format!("Cannot connect to {}:{}", site_id.server, port),
+            "Check the firewalls between this host and the Checkmk server. This check does not \
+             apply if the server is only reachable via a proxy.",
+        ),
+    }
+}
+
+fn check_registration(
+    api: &impl RegistrationStatusV2,
+    site_id: &site_spec::SiteID,
+    connection: &config::TrustedConnectionWithRemote,
+    connection_mode: config::ConnectionMode,
+) -> Finding {
+    let subject = site_id.to_string();
+    match site_spec::make_site_url(site_id, &connection.receiver_port)
+        .and_then(|url| api.registration_status_v2(&url, &connection.trust))
+    {
+        Ok(agent_receiver_api::RegistrationStatusV2Response::Registered(registered))
+            if registered.connection_mode == connection_mode =>
+        {
+            Finding::ok(
+                subject,
+                format!("Registered as host {}", registered.hostname),
+            )
+        }
+        Ok(agent_receiver_api::RegistrationStatusV2Response::Registered(registered)) => {
+            Finding::problem(
+                Severity::Warning,
+                subject,
+                format!(
+                    "Registered in {} mode at the site, but in {} mode locally",
+                    registered.connection_mode, connection_mode
+                ),
+                "Register again to take over the connection mode of the site",
+            )
+        }
+        Ok(agent_receiver_api::RegistrationStatusV2Response::NotRegistered) => Finding::problem(
+            Severity::Error,
+            subject,
+            "The site does not know this connection",
+            "Register again with 'cmk-agent-ctl register'",
+        ),
+        Err(err) => Finding::problem(
+            Severity::Error,
+            subject,
+            format!(
+                "Querying the agent receiver failed: {}",
+                misc::anyhow_error_to_human_readable(&err)
+            ),
+            "Check the reachability of the site and the proxy settings reported above",
+        ),
+    }
+}
+
+fn clock_skew_finding(subject: &str, local: SystemTime, remote: SystemTime) -> Finding {
+    let skew = match remote.duration_since(local) {
+        Ok(ahead) => ahead,
+        Err(behind) => behind.duration(),
+    };
+    if skew > Duration::from_secs(constants::CLOCK_SKEW_TOLERANCE) {
+        Finding::problem(
+            Severity::Warning,
+            subject,
+            format!(
+                "Clock differs from the agent receiver by {}",
+                misc::human_readable_duration(skew.as_secs())
+            ),
+            "Synchronize the clocks, e.g. via NTP. Certificate validation and the timestamps of \
+             monitoring data depend on them.",
+        )
+    } else {
+        Finding::ok(subject, "Clock in sync with the agent receiver")
+    }
+}
+
+fn check_clock(
+    api: &impl ServerTime,
+    site_id: &site_spec::SiteID,
+    connection: &config::TrustedConnectionWithRemote,
+) -> Finding {
+    let subject = site_id.to_string();
+    match site_spec::make_site_url(site_id, &connection.receiver_port)
+        .and_then(|url| api.server_time(&url, &connection.trust))
+    {
+        Ok(remote) => clock_skew_finding(&subject, SystemTime::now(), remote),
+        Err(err) => Finding::problem(
+            Severity::Warning,
+            subject,
+            format!(
+                "Cannot determine the time of the agent receiver: {}",
+                misc::anyhow_error_to_human_readable(&err)
+            ),
+            "Compare the clocks of this host and the Checkmk server manually",
+        ),
+    }
+}
+
+fn check_connections(
+    registry: &config::Registry,
+    api: &(impl RegistrationStatusV2 + ServerTime),
+    ip_preference: happy_eyeballs::IpPreference,
+    report: &mut impl FnMut(Finding),
+) {
+    let now = misc::unix_now() as i64;
+    let standard_connections = registry
+        .get_push_connections()
+        .map(|(site_id, connection)| (site_id, connection, config::ConnectionMode::Push))
+        .chain(
+            registry
+                .get_standard_pull_connections()
+                .map(|(site_id, connection)| (site_id, connection, config::ConnectionMode::Pull)),
+        );
+    for (site_id, connection, connection_mode) in standard_connections {
+        report(check_certificate(
+            &site_id.to_string(),
+            &connection.trust.certificate,
+            now,
+        ));
+        let port = check_port(site_id, connection.receiver_port, ip_preference);
+        let reachable = port.severity == Severity::Ok;
+        report(port);
+        let registration = check_registration(api, site_id, connection, connection_mode);
+        let queried = registration.severity != Severity::Error;
+        report(registration);
+        if reachable && queried {
+            report(check_clock(api, site_id, connection));
+        }
+    }
+    for connection in registry.get_imported_pull_connections() {
+        report(check_certificate(
+            &format!("Imported connection {}", connection.uuid),
+            &connection.certificate,
+            now,
+        ));
+    }
+}
+
+#[cfg(windows)]
+fn check_access(
+    account: &str,
+    path: &Path,
+    required: Access,
+    access: std::io::Result<Access>,
+) -> Finding {
+    let subject = "Service account";
+    match access {
+        Ok(access) if access >= required => {
+            Finding::ok(subject, format!("{account} has {access} to {path:?}"))
+        }
+        Ok(access) => Finding::problem(
+            Severity::Error,
+            subject,
+            format!("{account} has {access} to {path:?}, but requires {required}"),
+            format!(
+                "Grant it with: icacls \"{}\" /grant \"{account}:{}{}\"",
+                path.display(),
+                if path.is_dir() { "(OI)(CI)" } else { "" },
+                match required {
+                    Access::Modify => "M",
+                    _ => "RX",
+                }
+            ),
+        ),
+        Err(err) => Finding::problem(
+            Severity::Warning,
+            subject,
+            format!("Cannot determine the access of {account} to {path:?}: {err}"),
+            "Check the permissions manually",
+        ),
+    }
+}
+
+/// Rights of the account the Windows service runs as, see service_account
+#[cfg(windows)]
+fn check_service_account(paths: &setup::PathResolver, report: &mut impl FnMut(Finding)) {
+    let subject = "Service account";
+    let account = match service_account::service_account() {
+        Ok(Some(account)) => account,
+        Ok(None) => return report(Finding::ok(subject, "Service runs as LocalSystem")),
+        Err(err) => {
+            return report(Finding::problem(
+                Severity::Error,
+                subject,
+                format!("Cannot determine the service account: {err:#}"),
+                "Make sure the agent controller service is installed",
+            ))
+        }
+    };
+    let sid = match service_account::sid_of(&account) {
+        Ok(sid) => sid,
+        Err(err) => {
+            return report(Finding::problem(
+                Severity::Error,
+                subject,
+                format!("Cannot resolve {account}: {err}"),
+                "For a group Managed Service Account, install it on this host \
+                 (Install-ADServiceAccount) and verify it with Test-ADServiceAccount",
+            ))
+        }
+    };
+    report(Finding::ok(subject, format!("Service runs as {account}")));
+    for (path, required) in [
+        (&paths.home_dir, Access::Modify),
+        (&paths.registry_path, Access::Modify),
+        (&paths.config_path, Access::Read),
+    ] {
+        if path.exists() {
+            report(check_access(
+                &account,
+                path,
+                required,
+                service_account::effective_access(path, &sid),
+            ));
+        }
+    }
+}
+
+pub fn doctor(paths: &setup::PathResolver, doctor_opts: cli::DoctorOpts) -> AnyhowResult<()> {
+    let mut severity = Severity::Ok;
+    let mut problems = 0;
+    let mut report = |finding: Finding| {
+        println!("{finding}");
+        if finding.severity != Severity::Ok {
+            problems += 1;
+        }
+        severity = severity.max(finding.severity);
+    };
+
+    let (finding, runtime_config) = check_config(paths);
+    report(finding);
+    let client_config = config::ClientConfig::new(
+        runtime_config.unwrap_or_default(),
+        doctor_opts.client_opts,
+        None,
+    );
+    report(check_agent_socket(&setup::agent_channel()));
+    report(match client_config.proxy_mode {
+        proxy::ProxyMode::Auto => check_proxy_discovery(&proxy::discover()),
+        proxy_mode => check_proxy(
+            proxy_mode == proxy::ProxyMode::Environment,
+            proxy_from_env(|name| std::env::var(name).ok()),
+        ),
+    });
+    let (finding, registry) = check_registry(paths);
+    report(finding);
+    #[cfg(windows)]
+    if doctor_opts.verify_service_account {
+        check_service_account(paths, &mut report);
+    }
+    if let Some(registry) = registry {
+        check_connections(
+            &registry,
+            &agent_receiver_api::Api::new(&client_config),
+            client_config.ip_preference,
+            &mut report,
+        );
+    }
+
+    match severity {
+        Severity::Ok => {
+            println!("\nNo problems found");
+            Ok(())
+        }
+        severity => {
+            println!("\n{problems} problem(s) found");
+            Err(ProblemsFound(severity).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::str::FromStr;
+
+    fn site_id() -> site_spec::SiteID {
+        site_spec::SiteID::from_str("server/site").unwrap()
+    }
+
+    struct MockApi {
+        response: fn() -> AnyhowResult<agent_receiver_api::RegistrationStatusV2Response>,
+    }
+
+    impl RegistrationStatusV2 for MockApi {
+        fn registration_status_v2(
+            &self,
+            _base_url: &reqwest::Url,
+            _connection: &config::TrustedConnection,
+        ) -> AnyhowResult<agent_receiver_api::RegistrationStatusV2Response> {
+            (self.response)()
+        }
+    }
+
+    fn registered() -> AnyhowResult<agent_receiver_api::RegistrationStatusV2Response> {
+        Ok(
+            agent_receiver_api::RegistrationStatusV2Response::Registered(
+                agent_receiver_api::RegistrationStatusV2ResponseRegistered {
+                    hostname: String::from("my-host"),
+                    connection_mode: config::ConnectionMode::Push,
+                },
+            ),
+        )
+    }
+
+    #[test]
+    fn test_finding_display() {
+        assert_eq!(
+            Finding::ok("Registry", "1 connection(s) registered").to_string(),
+            "[OK]   Registry: 1 connection(s) registered"
+        );
+        assert_eq!(
+            Finding::problem(
+                Severity::Error,
+                "server/site",
+                "Certificate expired",
+                "Register the connection again"
+            )
+            .to_string(),
+            "[ERR]  server/site: Certificate expired\n       -> Register the connection again"
+        );
+    }
+
+    #[test]
+    fn test_check_certificate() {
+        assert_eq!(
+            check_certificate("server/site", constants::TEST_CERT_OK, 0).severity,
+            Severity::Ok
+        );
+        let not_after = certs::parse_pem(constants::TEST_CERT_OK)
+            .unwrap()
+            .parse_x509()
+            .unwrap()
+            .validity()
+            .not_after
+            .timestamp();
+        let expiring = check_certificate("server/site", constants::TEST_CERT_OK, not_after - 3600);
+        assert_eq!(expiring.severity, Severity::Warning);
+        assert_eq!(expiring.message, "Certificate expires in 1h 0m");
+        assert_eq!(
+            check_certificate("server/site", constants::TEST_CERT_OK, not_after).severity,
+            Severity::Error
+        );
+        assert_eq!(
+            check_certificate("server/site", "no certificate", 0).severity,
+            Severity::Error
+        );
+    }
+
+    #[test]
+    fn test_check_proxy() {
+        let env = |name: &str| match name {
+            "https_proxy" => Some(String::from("http://proxy:3128")),
+            _ => None,
+        };
+        assert_eq!(proxy_from_env(env), Some(String::from("http://proxy:3128")));
+        assert_eq!(proxy_from_env(|_| Some(String::new())), None);
+
+        let via_proxy = check_proxy(true, proxy_from_env(env));
+        assert_eq!(via_proxy.severity, Severity::Ok);
+        assert_eq!(via_proxy.message, "Connecting via http://proxy:3128");
+        let ignored = check_proxy(false, proxy_from_env(env));
+        assert_eq!(ignored.severity, Severity::Ok);
+        assert!(ignored.hint.is_some());
+        assert_eq!(
+            check_proxy(true, Some(String::from("http://[::1"))).severity,
+            Severity::Error
+        );
+        assert_eq!(
+            check_proxy_discovery(&Ok(proxy::Discovery::Environment)).severity,
+            Severity::Ok
+        );
+        assert_eq!(
+            check_proxy_discovery(&Err(anyhow!("unreachable"))).severity,
+            Severity::Warning
+        );
+    }
+
+    #[test]
+    fn test_check_registration() {
+        let connection =
+            config::TrustedConnectionWithRemote::from("99f56bbc-5965-4b34-bc70-1959ad1d32d6");
+        let check = |response, connection_mode| {
+            check_registration(
+                &MockApi { response },
+                &site_id(),
+                &connection,
+                connection_mode,
+            )
+        };
+        let ok = check(registered, config::ConnectionMode::Push);
+        assert_eq!(ok.severity, Severity::Ok);
+        assert_eq!(ok.message, "Registered as host my-host");
+        assert_eq!(
+            check(registered, config::ConnectionMode::Pull).severity,
+            Severity::Warning
+        );
+        assert_eq!(
+            check(
+                || Ok(agent_receiver_api::RegistrationStatusV2Response::NotRegistered),
+                config::ConnectionMode::Push
+            )
+            .severity,
+            Severity::Error
+        );
+        let unreachable = check(
+            || Err(anyhow!("Connection refused")),
+            config::ConnectionMode::Push,
+        );
+        assert_eq!(unreachable.severity, Severity::Error);
+        assert_eq!(
+            unreachable.message,
+            "Querying the agent receiver failed: Connection refused"
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_check_access() {
+        let path = Path::new("C:\\ProgramData\\checkmk\\agent\\registered_connections.json");
+        let check = |access| check_access("DOMAIN\\cmk$", path, Access::Modify, access);
+        let ok = check(Ok(Access::Modify));
+        assert_eq!(ok.severity, Severity::Ok);
+        let read_only = check(Ok(Access::Read));
+        assert_eq!(read_only.severity, Severity::Error);
+        assert!(read_only
+            .hint
+            .unwrap()
+            .ends_with("/grant \"DOMAIN\\cmk$:M\""));
+        assert_eq!(
+            check(Err(std::io::Error::from_raw_os_error(5))).severity,
+            Severity::Warning
+        );
+    }
+
+    #[test]
+    fn test_clock_skew_finding() {
+        let local = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        assert_eq!(
+            clock_skew_finding("server/site", local, local + Duration::from_secs(5)).severity,
+            Severity::Ok
+        );
+        let behind = clock_skew_finding("server/site", local, local - Duration::from_secs(300));
+        assert_eq!(behind.severity, Severity::Warning);
+        assert_eq!(
+            behind.message,
+            "Clock differs from the agent receiver by 5m"
+        );
+    }
+}
@@ -0,0 +1,31 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::{
+    config, data_source, payload_stats::PayloadStats, post_processing::Pipeline,
+    setup::agent_channel,
+};
+use anyhow::{Context, Result as AnyhowResult};
+use std::io::Write;
+
+pub fn dump(
+    section_filter: &config::SectionFilterConfig,
+    post_processors: &[config::PostProcessorConfig],
+    payload_size: &config::PayloadSizeConfig,
+    fallback_command: Option<&config::FallbackCommandConfig>,
+) -> AnyhowResult<()> {
+    // The sizes of dumped outputs are not recorded
+    let post_processing = Pipeline::new(section_filter, post_processors)?
+        .with_payload_accounting(payload_size, PayloadStats::default());
+    let mon_data = post_processing
+        .process(
+            data_source::collect(None, &agent_channel(), fallback_command)
+                .context("Error collecting monitoring data.")?,
+        )
+        .context("Error post-processing monitoring data.")?;
+    std::io::stdout()
+        .write_all(&mon_data)
+        .context("Error writing monitoring data to stdout.")?;
+    Ok(())
+}
@@ -0,0 +1,411 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::modes::registration::ProxyPullData;
+use crate::modes::renew_certificate;
+use crate::{agent_receiver_api, certs, cli, config, output, site_spec};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use config::JSONLoader;
+use log::info;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A connection baked into a golden image. Plain connection data as written by proxy-register
+/// will do, the site is needed for importing it as renewable pull connection.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ImageConnection {
+    #[serde(flatten)]
+    pub data: ProxyPullData,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site_id: Option<site_spec::SiteID>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receiver_port: Option<u16>,
+    /// Generate a new private key and renew the certificate on first start
+    #[serde(default)]
+    pub rekey: bool,
+}
+
+impl config::JSONLoader for ImageConnection {}
+
+/// Result of an import, as written with --output
+#[derive(serde::Serialize)]
+pub struct Imported {
+    pub uuid: String,
+    /// Only staged for the first start of the daemon on a machine created from the image
+    pub staged_for_image: bool,
+}
+
+trait ImportDataProvider {
+    fn provide(&self) -> AnyhowResult<ProxyPullData>;
+}
+
+struct ImportDataFromFile {
+    path: std::path::PathBuf,
+}
+
+impl ImportDataProvider for ImportDataFromFile {
+    fn provide(&self) -> AnyhowResult<ProxyPullData> {
+        ProxyPullData::load(&self.path)
+            .context(format!("Failed to read file {}", &self.path.display()))
+    }
+}
+
+struct ImportDataFromStdin {}
+
+impl ImportDataProvider for ImportDataFromStdin {
+    fn provide(&self) -> AnyhowResult<ProxyPullData> {
+        let mut buffer = String::new();
+        std::io::stdin()
+            .read_line(&mut buffer)
+            .context("Failed to read from stdin")?;
+        serde_json::from_str(&buffer)
+            .context(format!("Failed to deserialize JSON data:\n{}", &buffer))
+    }
+}
+
+fn _import(
+    registry: &mut config::Registry,
+    import_data_provider: &impl ImportDataProvider,
+) -> AnyhowResult<Imported> {
+    let connection = import_data_provider.provide()?.connection;
+    let uuid = connection.uuid.to_string();
+    registry.register_imported_connection(connection);
+    registry.save()?;
+    Ok(Imported {
+        uuid,
+        staged_for_image: false,
+    })
+}
+
+fn stage_for_image(
+    import_data_provider: &impl ImportDataProvider,
+    import_opts: &cli::ImportOpts,
+    image_connection_path: &Path,
+) -> AnyhowResult<Imported> {
+    let data = import_data_provider.provide()?;
+    let connection = &data.connection;
+    certs::check_identity(
+        &connection.certificate,
+        &connection.private_key,
+        &connection.root_cert,
+    )
+    .context("Invalid connection")?;
+    let image_connection = ImageConnection {
+        data,
+        site_id: import_opts.site.clone(),
+        receiver_port: import_opts.receiver_port,
+        rekey: import_opts.rekey,
+    };
+    std::fs::write(
+        image_connection_path,
+        serde_json::to_string(&image_connection)?,
+    )
+    .context(format!(
+        "Failed to write {}",
+        image_connection_path.display()
+    ))?;
+    Ok(Imported {
+        uuid: image_connection.data.connection.uuid.to_string(),
+        staged_for_image: true,
+    })
+}
+
+pub fn import(
+    registry: &mut config::Registry,
+    import_opts: &cli::ImportOpts,
+    image_connection_path: &Path,
+    output_format: cli::OutputFormat,
+) -> AnyhowResult<()> {
+    let imported = match (&import_opts.conn_file, import_opts.for_image) {
+        (Some(path), false) => _import(
+            registry,
+            &ImportDataFromFile {
+                path: std::path::PathBuf::from(path),
+            },
+        ),
+        (None, false) => _import(registry, &ImportDataFromStdin {}),
+        (Some(path), true) => stage_for_image(
+            &ImportDataFromFile {
+                path: std::path::PathBuf::from(path),
+            },
+            import_opts,
+            image_connection_path,
+        ),
+        (None, true) => {
+            stage_for_image(&ImportDataFromStdin {}, import_opts, image_connection_path)
+        }
+    }?;
+    output::print(
+        output_format,
+        &imported,
+        &if imported.staged_for_image {
+            format!(
+                "Staged connection {} for import on first start of the daemon",
+                imported.uuid
+            )
+        } else {
+            String::new()
+        },
+    )
+}
+
+fn is_registered(registry: &config::Registry, uuid: &uuid::Uuid) -> bool {
+    registry
+        .retrieve_standard_connection_by_uuid(uuid)
+        .is_some()
+        || registry
+            .get_imported_pull_connections()
+            .any(|connection| &connection.uuid == uuid)
+}
+
+fn _import_image_connection(
+    image_connection: ImageConnection,
+    registry: &mut config::Registry,
+    discover_receiver_port: impl Fn(&site_spec::SiteID) -> AnyhowResult<u16>,
+    renew_certificate_api: &impl agent_receiver_api::RenewCertificate,
+) -> AnyhowResult<()> {
+    let connection = image_connection.data.connection;
+    if is_registered(registry, &connection.uuid) {
+        info!(
+            "Connection {} from image is already registered",
+            connection.uuid
+        );
+        return Ok(());
+    }
+    certs::check_identity(
+        &connection.certificate,
+        &connection.private_key,
+        &connection.root_cert,
+    )
+    .context("Invalid connection")?;
+    let uuid = connection.uuid;
+    match image_connection.site_id {
+        Some(site_id) => {
+            let mut connection = config::TrustedConnectionWithRemote {
+                trust: connection,
+                receiver_port: match image_connection.receiver_port {
+                    Some(port) => port,
+                    None => discover_receiver_port(&site_id)?,
+                },
+                push_interval: None,
+                hostname: None,
+                tags: BTreeMap::new(),
+                expires: None,
+            };
+            if image_connection.rekey {
+                renew_certificate::renew_connection_cert(
+                    &site_id,
+                    &mut connection,
+                    renew_certificate_api,
+                )
+                .context("Failed to re-key connection")?;
+            }
+            registry.register_connection(&config::ConnectionMode::Pull, &site_id, connection);
+            info!("Imported connection {} to {} from image", uuid, site_id);
+        }
+        None => {
+            if image_connection.rekey {
+                bail!("Re-keying requires the site of the connection");
+            }
+            registry.register_imported_connection(connection);
+            info!("Imported connection {} from image", uuid);
+        }
+    }
+    registry.save()
+}
+
+/// Import the connection baked into the image, once. The image file is removed afterwards, so that
+/// the private key does not linger.
+pub fn import_image_connection(
+    image_connection_path: &Path,
+    registry: &mut config::Registry,
+    client_config: &config::ClientConfig,
+) -> AnyhowResult<()> {
+    let image_connection = ImageConnection::load(image_connection_path).context(format!(
+        "Failed to read {}",
+        image_connection_path.display()
+    ))?;
+    _import_image_connection(
+        image_connection,
+        registry,
+        |site_id| site_spec::discover_receiver_port(site_id, client_config),
+        &agent_receiver_api::Api::new(client_config),
+    )?;
+    std::fs::remove_file(image_connection_path).context(format!(
+        "Failed to remove {}",
+        image_connection_path.display()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    struct MockImportDataProvider {}
+
+    impl ImportDataProvider for MockImportDataProvider {
+        fn provide(&self) -> AnyhowResult<ProxyPullData> {
+            Ok(ProxyPullData {
+                agent_controller_version: String::from("0.1.0"),
+                connection: config::TrustedConnection {
+                    uuid: uuid::Uuid::from_str("2da53af5-5c06-4195-ab6f-668875710bec").unwrap(),
+                    private_key: String::from("fake private key"),
+                    certificate: String::from("fake cert"),
+                    root_cert: String::from("fake root cert"),
+                    source_address: None,
+                    receiver_protocol: Default::default(),
+                    transport: Default::default(),
+                    data_source: None,
+                },
+            })
+        }
+    }
+
+    #[test]
+    fn test_import() {
+        let mut r = config::test_helpers::TestRegistry::new();
+        let reg = &mut r.registry;
+        assert!(reg.is_empty());
+        assert!(!reg.path().exists());
+        assert!(_import(reg, &MockImportDataProvider {}).is_ok());
+        assert!(!reg.is_empty());
+        assert!(reg.path().exists());
+    }
+
+    const UUID: &str = "2da53af5-5c06-4195-ab6f-668875710bec";
+
+    struct MockRenewCertificate {
+        root_cert: String,
+        root_key: String,
+    }
+
+    impl agent_receiver_api::RenewCertificate for MockRenewCertificate {
+        fn renew_certificate(
+            &self,
+            _base_url: &reqwest::Url,
+            _connection: &config::TrustedConnection,
+            csr: String,
+        ) -> AnyhowResult<agent_receiver_api::RenewCertificateResponse> {
+            Ok(agent_receiver_api::RenewCertificateResponse {
+                agent_cert: certs::sign_csr(&self.root_cert, &self.root_key, &csr, 1)?,
+            })
+        }
+    }
+
+    fn image_connection(
+        site_id: Option<&str>,
+        rekey: bool,
+    ) -> (ImageConnection, MockRenewCertificate) {
+        let (root_cert, root_key) = certs::make_ca("root", 1).unwrap();
+        let (csr, private_key) = certs::make_csr(UUID).unwrap();
+        let certificate = certs::sign_csr(&root_cert, &root_key, &csr, 1).unwrap();
+        (
+            ImageConnection {
+                data: ProxyPullData {
+                    agent_controller_version: String::from("0.1.0"),
+                    connection: config::TrustedConnection {
+                        uuid: uuid::Uuid::from_str(UUID).unwrap(),
+                        private_key,
+                        certificate,
+                        root_cert: root_cert.clone(),
+                        source_address: None,
+                        receiver_protocol: Default::default(),
+                        transport: Default::default(),
+                        data_source: None,
+                    },
+                },
+                site_id: site_id.map(|s| site_spec::SiteID::from_str(s).unwrap()),
+                receiver_port: Some(8000),
+                rekey,
+            },
+            MockRenewCertificate {
+                root_cert,
+                root_key,
+            },
+        )
+    }
+
+    fn no_discovery(_site_id: &site_spec::SiteID) -> AnyhowResult<u16> {
+        bail!("No discovery expected")
+    }
+
+    #[test]
+    fn test_image_connection_from_proxy_pull_data() {
+        let image_connection: ImageConnection = serde_json::from_str(
+            &serde_json::to_string(&MockImportDataProvider {}.provide().unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert!(image_connection.site_id.is_none());
+        assert!(image_connection.receiver_port.is_none());
+        assert!(!image_connection.rekey);
+    }
+
+    #[test]
+    fn test_import_image_connection_imported() {
+        let mut r = config::test_helpers::TestRegistry::new();
+        let (image_connection, api) = image_connection(None, false);
+        _import_image_connection(image_connection, &mut r.registry, no_discovery, &api).unwrap();
+        assert_eq!(r.registry.get_imported_pull_connections().count(), 1);
+        assert!(r.registry.path().exists());
+    }
+
+    #[test]
+    fn test_import_image_connection_rekey() {
+        let mut r = config::test_helpers::TestRegistry::new();
+        let (image_connection, api) = image_connection(Some("server/site"), true);
+        let old_key = image_connection.data.connection.private_key.clone();
+        _import_image_connection(image_connection, &mut r.registry, no_discovery, &api).unwrap();
+        let site_id = r
+            .registry
+            .retrieve_standard_connection_by_uuid(&uuid::Uuid::from_str(UUID).unwrap())
+            .unwrap();
+        assert_eq!(site_id.to_string(), "server/site");
+        let (_, connection) = r.registry.get_standard_pull_connections().next().unwrap();
+        assert_eq!(connection.receiver_port, 8000);
+        assert_ne!(connection.trust.private_key, old_key);
+        assert!(certs::check_identity(
+            &connection.trust.certificate,
+            &connection.trust.private_key,
+            &connection.trust.root_cert
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_import_image_connection_rekey_without_site() {
+        let mut r = config::test_helpers::TestRegistry::new();
+        let (image_connection, api) = image_connection(None, true);
+        assert!(
+            _import_image_connection(image_connection, &mut r.registry, no_discovery, &api)
+                .is_err()
+        );
+        assert!(r.registry.is_empty());
+    }
+
+    #[test]
+    fn test_import_image_connection_already_registered() {
+        let mut r = config::test_helpers::TestRegistry::new();
+        let (image_connection, api) = image_connection(Some("server/site"), false);
+        r.registry
+            .register_imported_connection(image_connection.data.connection.clone());
+        _import_image_connection(image_connection, &mut r.registry, no_discovery, &api).unwrap();
+        assert!(r.registry.is_standard_pull_empty());
+    }
+
+    #[test]
+    fn test_stage_for_image_invalid_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image_connection.json");
+        let import_opts = cli::ImportOpts {
+            conn_file: None,
+            for_image: true,
+            site: None,
+            receiver_port: None,
+            rekey: false,
+        };
+        assert!(stage_for_image(&MockImportDataProvider {}, &import_opts, &path).is_err());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::{cli, config, misc};
+use anyhow::Result as AnyhowResult;
+use std::time::Duration;
+
+pub fn legacy_pull(
+    registry: &config::Registry,
+    action: &cli::LegacyPullAction,
+) -> AnyhowResult<()> {
+    match action {
+        cli::LegacyPullAction::Enable(enable_opts) => {
+            registry.activate_legacy_pull(
+                enable_opts
+                    .grace_period
+                    .map(|hours| Duration::from_secs(hours * 3600)),
+            )?;
+            println!("Enabled legacy pull mode");
+        }
+        cli::LegacyPullAction::Disable => {
+            registry.deactivate_legacy_pull("Disabled manually")?;
+            println!("Disabled legacy pull mode");
+        }
+        cli::LegacyPullAction::Status => {}
+    }
+    println!(
+        "{}",
+        render_status(
+            registry.is_legacy_pull_active(),
+            &registry.legacy_pull_window()?,
+            misc::unix_now()
+        )
+    );
+    Ok(())
+}
+
+fn render_status(active: bool, window: &config::LegacyPullWindow, now: u64) -> String {
+    let mut lines = vec![format!(
+        "Legacy pull mode: {}",
+        if active { "enabled" } else { "disabled" }
+    )];
+    if let Some(enabled_at) = window.enabled_at {
+        lines.push(format!(
+            "Enabled: {} ago",
+            misc::human_readable_duration(now.saturating_sub(enabled_at))
+        ));
+    }
+    if active {
+        lines.push(match window.expires_at {
+            Some(expires_at) => format!(
+                "Expires: in {}",
+                misc::human_readable_duration(expires_at.saturating_sub(now))
+            ),
+            None => String::from("Expires: on first registration"),
+        });
+    }
+    if let Some(cutoff) = &window.cutoff {
+        lines.push(format!(
+            "Disabled: {} ago ({})",
+            misc::human_readable_duration(now.saturating_sub(cutoff.at)),
+            cutoff.reason
+        ));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_status_active() {
+        assert_eq!(
+            render_status(
+                true,
+                &config::LegacyPullWindow {
+                    enabled_at: Some(1000),
+                    expires_at: Some(1000 + 7200),
+                    cutoff: None,
+                },
+                1600
+            ),
+            "Legacy pull mode: enabled\nEnabled: 10m ago\nExpires: in 1h 50m"
+        );
+        assert_eq!(
+            render_status(true, &config::LegacyPullWindow::default(), 1600),
+            "Legacy pull mode: enabled\nExpires: on first registration"
+        );
+    }
+
+    #[test]
+    fn test_render_status_cutoff() {
+        assert_eq!(
+            render_status(
+                false,
+                &config::LegacyPullWindow {
+                    enabled_at: Some(1000),
+                    expires_at: Some(1060),
+                    cutoff: Some(config::LegacyPullCutoff {
+                        at: 1060,
+                        reason: String::from("Grace period expired"),
+                    }),
+                },
+                1090
+            ),
+            "Legacy pull mode: disabled\nEnabled: 1m ago\nDisabled: 30s ago (Grace period expired)"
+        );
+    }
+}
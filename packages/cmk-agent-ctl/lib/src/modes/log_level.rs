@@ -0,0 +1,18 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::ipc;
+use anyhow::{bail, Result as AnyhowResult};
+use std::path::Path;
+
+pub fn log_level(path_control_socket: &Path, spec: Option<String>) -> AnyhowResult<()> {
+    match ipc::request(path_control_socket, &ipc::Request::SetLogLevel { spec })? {
+        ipc::Response::LogLevel { spec } => {
+            println!("Log level of the daemon is now '{spec}'");
+            Ok(())
+        }
+        ipc::Response::Error { message } => bail!(message),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
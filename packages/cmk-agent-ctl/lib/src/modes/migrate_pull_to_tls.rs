@@ -0,0 +1,249 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::registration::{self, Registered};
+use crate::connection_stats::ConnectionStats;
+use crate::{cli, config, constants, misc, output, site_spec};
+use anyhow::{bail, Result as AnyhowResult};
+use serde::Serialize;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// What the migration did, as written with --output
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+struct MigrationReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    site_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
+    /// The site pulled via TLS, or, for push connections, received agent output
+    confirmed: bool,
+    legacy_pull_active: bool,
+    /// In the order of the steps
+    changes: Vec<String>,
+}
+
+/// Register, then keep the connection only if the transfer via TLS is confirmed. Otherwise,
+/// legacy pull mode is enabled again for the rest of its grace period.
+fn migrate(
+    registry: &mut config::Registry,
+    register: impl FnOnce(&mut config::Registry) -> AnyhowResult<Registered>,
+    confirm: impl FnOnce(&uuid::Uuid, &config::ConnectionMode) -> bool,
+) -> AnyhowResult<MigrationReport> {
+    let mut report = MigrationReport::default();
+    if !registry.is_legacy_pull_active() {
+        report.changes.push(String::from(
+            "Legacy pull mode is disabled, nothing is served unencrypted. Nothing to do.",
+        ));
+        return Ok(report);
+    }
+    let window = registry.legacy_pull_window()?;
+
+    let registered = register(registry)?;
+    report.changes.push(format!(
+        "Registered with {} as {} connection {}, which disabled legacy pull mode",
+        registered.site_id, registered.connection_mode, registered.uuid
+    ));
+    report.site_id = Some(registered.site_id.clone());
+    report.uuid = Some(registered.uuid.clone());
+
+    report.confirmed = confirm(
+        &uuid::Uuid::from_str(&registered.uuid)?,
+        &registered.connection_mode,
+    );
+    if report.confirmed {
+        report
+            .changes
+            .push(String::from(match registered.connection_mode {
+                config::ConnectionMode::Pull => "The site pulled via TLS",
+                config::ConnectionMode::Push => "The site received agent output via TLS",
+            }));
+        report
+            .changes
+            .push(String::from("Legacy pull mode stays disabled"));
+        return Ok(report);
+    }
+
+    report
+        .changes
+        .push(String::from("The site did not pull via TLS in time"));
+    registry.delete_standard_connection(&site_spec::SiteID::from_str(&registered.site_id)?)?;
+    registry.save()?;
+    report.changes.push(format!(
+        "Deleted connection {} locally, the site still knows it",
+        registered.uuid
+    ));
+    let now = misc::unix_now();
+    registry.activate_legacy_pull(
+        window
+            .expires_at
+            .map(|expires_at| Duration::from_secs(expires_at.saturating_sub(now).max(1))),
+    )?;
+    report.legacy_pull_active = registry.is_legacy_pull_active();
+    report
+        .changes
+        .push(String::from("Enabled legacy pull mode again"));
+    Ok(report)
+}
+
+/// Wait for the daemon to record a successful pull (or push) of the connection
+fn await_tls_transfer(
+    connection_stats_path: &Path,
+    uuid: &uuid::Uuid,
+    connection_mode: &config::ConnectionMode,
+    timeout: Duration,
+    interval: Duration,
+) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        // The daemon may be writing the file just now
+        if let Ok(counters) = ConnectionStats::load(connection_stats_path) {
+            if counters
+                .get(uuid)
+                .is_some_and(|counters| match connection_mode {
+                    config::ConnectionMode::Pull => counters.successful_pulls > 0,
+                    config::ConnectionMode::Push => counters.successful_pushes > 0,
+                })
+            {
+                return true;
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        std::thread::sleep(interval.min(deadline - now));
+    }
+}
+
+pub fn migrate_pull_to_tls(
+    runtime_config: config::RuntimeConfig,
+    opts: cli::MigratePullToTlsOpts,
+    registry: &mut config::Registry,
+    connection_stats_path: &Path,
+    format: cli::OutputFormat,
+) -> AnyhowResult<()> {
+    let timeout = Duration::from_secs(opts.confirm_timeout);
+    let report = migrate(
+        registry,
+        |registry| {
+            registration::register_existing_interactively(
+                &config::RegisterExistingConfig::new(runtime_config, opts.register_opts)?,
+                registry,
+            )
+        },
+        |uuid, connection_mode| {
+            eprintln!(
+                "Waiting up to {} for the site to pull via TLS ...",
+                misc::human_readable_duration(timeout.as_secs())
+            );
+            await_tls_transfer(
+                connection_stats_path,
+                uuid,
+                connection_mode,
+                timeout,
+                Duration::from_secs(constants::MIGRATION_CONFIRM_INTERVAL),
+            )
+        },
+    )?;
+    output::print(format, &report, &report.changes.join("\n"))?;
+    if report.uuid.is_some() && !report.confirmed {
+        bail!(
+            "Migration rolled back. Make sure that the daemon is running and that the site \
+             monitors this host via TLS, eg. by running a service discovery, then try again."
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_migrate_pull_to_tls {
+    use super::*;
+    use crate::config::test_helpers::TestRegistry;
+
+    const UUID: &str = "99f56bbc-5965-4b34-bc70-1959ad1d32d6";
+
+    fn register(registry: &mut config::Registry) -> AnyhowResult<Registered> {
+        registry.register_connection(
+            &config::ConnectionMode::Pull,
+            &site_spec::SiteID::from_str("server/site").unwrap(),
+            config::TrustedConnectionWithRemote::from(UUID),
+        );
+        registry.save()?;
+        Ok(Registered {
+            site_id: String::from("server/site"),
+            uuid: String::from(UUID),
+            connection_mode: config::ConnectionMode::Pull,
+            expires: None,
+        })
+    }
+
+    #[test]
+    fn test_nothing_to_migrate() {
+        let mut test_registry = TestRegistry::new();
+        let registry = &mut test_registry.registry;
+        let report = migrate(
+            registry,
+            |_| panic!("Nothing to register"),
+            |_, _| panic!("Nothing to confirm"),
+        )
+        .unwrap();
+        assert!(report.uuid.is_none());
+        assert!(!report.confirmed);
+        assert_eq!(report.changes.len(), 1);
+    }
+
+    #[test]
+    fn test_confirmed() {
+        let mut test_registry = TestRegistry::new();
+        let registry = &mut test_registry.registry;
+        registry.activate_legacy_pull(None).unwrap();
+        let report = migrate(registry, register, |uuid, connection_mode| {
+            assert_eq!(uuid.to_string(), UUID);
+            connection_mode == &config::ConnectionMode::Pull
+        })
+        .unwrap();
+        assert!(report.confirmed);
+        assert!(!report.legacy_pull_active);
+        assert!(!registry.is_legacy_pull_active());
+        assert!(!registry.is_standard_pull_empty());
+    }
+
+    #[test]
+    fn test_rolled_back() {
+        let mut test_registry = TestRegistry::new();
+        let registry = &mut test_registry.registry;
+        registry
+            .activate_legacy_pull(Some(Duration::from_secs(3600)))
+            .unwrap();
+        let report = migrate(registry, register, |_, _| false).unwrap();
+        assert!(!report.confirmed);
+        assert!(report.legacy_pull_active);
+        assert_eq!(report.changes.len(), 4);
+        assert!(registry.is_empty());
+        assert!(registry.is_legacy_pull_active());
+        assert!(registry.legacy_pull_window().unwrap().expires_at.is_some());
+    }
+
+    #[test]
+    fn test_await_tls_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("connection_stats.json");
+        let uuid = uuid::Uuid::from_str(UUID).unwrap();
+        let awaited = |connection_mode: config::ConnectionMode| {
+            await_tls_transfer(
+                &path,
+                &uuid,
+                &connection_mode,
+                Duration::ZERO,
+                Duration::ZERO,
+            )
+        };
+        assert!(!awaited(config::ConnectionMode::Pull));
+        ConnectionStats::new(&path).record_pull(&uuid, "10.0.0.1".parse().unwrap(), 100);
+        assert!(awaited(config::ConnectionMode::Pull));
+        assert!(!awaited(config::ConnectionMode::Push));
+    }
+}
@@ -0,0 +1,541 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Migration of hosts set up with the agent updater of agents before Checkmk 2.1. Its config and
+//! its state are Python literals, which name the site and the certificates trusted for it.
+
+use super::registration;
+use crate::{certs, cli, config, constants, output, site_spec};
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// What the agent updater knows about the site, from its config or its state
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+struct UpdaterSettings {
+    #[serde(default)]
+    server: Option<String>,
+    #[serde(default)]
+    site: Option<String>,
+    #[serde(default)]
+    host_name: Option<String>,
+    #[serde(default)]
+    user: Option<String>,
+    /// Configured in the agent bakery
+    #[serde(default)]
+    certificates: Option<Vec<String>>,
+    /// Trusted with 'cmk-update-agent --trust-cert'
+    #[serde(default)]
+    local_certificates: Option<Vec<String>>,
+}
+
+/// The registration parameters read from the agent updater, as written with --dry-run
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct Prefilled {
+    server: Option<String>,
+    site: Option<String>,
+    host_name: Option<String>,
+    user: Option<String>,
+    trusted_certificates: usize,
+}
+
+impl UpdaterSettings {
+    /// The assignments of the config file, eg. "site = 'mysite'"
+    fn from_config(config: &str) -> AnyhowResult<Self> {
+        Ok(serde_json::from_value(Value::Object(
+            PythonLiteral::new(config).assignments()?,
+        ))?)
+    }
+
+    /// The dict of the state file
+    fn from_state(state: &str) -> AnyhowResult<Self> {
+        let mut literal = PythonLiteral::new(state);
+        let value = literal.value()?;
+        literal.end()?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    fn read(path: &Path, parse: impl Fn(&str) -> AnyhowResult<Self>) -> AnyhowResult<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        parse(&content)
+            .map(Some)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// The state is written when registering with the agent updater, so its values are the ones
+    /// which worked. The config fills in the rest.
+    fn load(config_path: &Path, state_path: &Path) -> AnyhowResult<Self> {
+        match (
+            Self::read(config_path, Self::from_config)?,
+            Self::read(state_path, Self::from_state)?,
+        ) {
+            (None, None) => bail!(
+                "Neither {} nor {} exist, the agent updater is not set up",
+                config_path.display(),
+                state_path.display()
+            ),
+            (Some(config), None) => Ok(config),
+            (None, Some(state)) => Ok(state),
+            (Some(config), Some(state)) => Ok(state.or(config)),
+        }
+    }
+
+    fn or(self, other: Self) -> Self {
+        Self {
+            server: self.server.or(other.server),
+            site: self.site.or(other.site),
+            host_name: self.host_name.or(other.host_name),
+            user: self.user.or(other.user),
+            certificates: self.certificates.or(other.certificates),
+            local_certificates: self.local_certificates.or(other.local_certificates),
+        }
+    }
+
+    fn trusted_certificates(&self) -> Vec<&str> {
+        self.local_certificates
+            .iter()
+            .chain(self.certificates.iter())
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
+
+    fn prefilled(&self) -> Prefilled {
+        Prefilled {
+            server: self.server.clone(),
+            site: self.site.clone(),
+            host_name: self.host_name.clone(),
+            user: self.user.clone(),
+            trusted_certificates: self.trusted_certificates().len(),
+        }
+    }
+
+    fn register_opts(&self, opts: cli::MigrateUpdaterOpts) -> AnyhowResult<cli::RegisterOpts> {
+        let (Some(server), Some(site)) = (&self.server, &self.site) else {
+            bail!("The agent updater is not set up for a site, its server and site are unknown");
+        };
+        let Some(user) = opts.user.or_else(|| self.user.clone()) else {
+            bail!("The agent updater knows no user, please use --user");
+        };
+        Ok(cli::RegisterOpts {
+            connection_opts: cli::RegistrationConnectionOpts {
+                server_spec: site_spec::ServerSpec::from_str(server)
+                    .with_context(|| format!("Invalid server \"{server}\""))?,
+                site: site.clone(),
+                user,
+                password: opts.password,
+                trust_server_cert: opts.trust_server_cert,
+                prompt_format: cli::PromptFormat::Text,
+                tags: vec![],
+                expires_in: None,
+                client_opts: opts.client_opts,
+                reg_client_opts: opts.reg_client_opts,
+            },
+            hostname: opts.hostname.or_else(|| self.host_name.clone()),
+            hostname_from: None,
+            host_creation_opts: cli::HostCreationOpts {
+                create_host: false,
+                folder: None,
+                host_labels: vec![],
+                api_url: None,
+                api_user: None,
+                api_password: None,
+            },
+            keep_credentials: false,
+        })
+    }
+}
+
+/// The certificate trusted by the agent updater which issued the certificate of the agent
+/// receiver, if any. This is the case if the site CA also issued the certificate of the web server.
+fn issuing_certificate<'a>(trusted: &[&'a str], server_cert: &str) -> Option<&'a str> {
+    trusted
+        .iter()
+        .find(|root_cert| certs::is_issued_by_pem(server_cert, root_cert).unwrap_or(false))
+        .copied()
+}
+
+#[cfg(unix)]
+fn default_paths() -> (PathBuf, PathBuf) {
+    (
+        Path::new(constants::UNIX_UPDATER_CONFIG_DIR).join(constants::UPDATER_CONFIG_FILE),
+        Path::new(constants::UNIX_UPDATER_STATE_DIR).join(constants::UPDATER_STATE_FILE),
+    )
+}
+
+#[cfg(windows)]
+fn default_paths() -> (PathBuf, PathBuf) {
+    let program_data_path = std::env::var(constants::ENV_PROGRAM_DATA)
+        .unwrap_or_else(|_| String::from("c:\\ProgramData"));
+    let dir = PathBuf::from(program_data_path + constants::WIN_AGENT_HOME_DIR)
+        .join(constants::WIN_UPDATER_DIR);
+    (
+        dir.join(constants::UPDATER_CONFIG_FILE),
+        dir.join(constants::UPDATER_STATE_FILE),
+    )
+}
+
+pub fn migrate_updater(
+    runtime_config: config::RuntimeConfig,
+    opts: cli::MigrateUpdaterOpts,
+    registry: &mut config::Registry,
+    format: cli::OutputFormat,
+) -> AnyhowResult<()> {
+    let (default_config_path, default_state_path) = default_paths();
+    let settings = UpdaterSettings::load(
+        opts.updater_config
+            .as_deref()
+            .unwrap_or(&default_config_path),
+        opts.updater_state.as_deref().unwrap_or(&default_state_path),
+    )?;
+    if opts.dry_run {
+        let prefilled = settings.prefilled();
+        let unknown = || String::from("unknown");
+        return output::print(
+            format,
+            &prefilled,
+            &format!(
+                "Server: {}\nSite: {}\nHost name: {}\nUser: {}\nTrusted certificates: {}",
+                prefilled.server.clone().unwrap_or_else(unknown),
+                prefilled.site.clone().unwrap_or_else(unknown),
+                prefilled.host_name.clone().unwrap_or_else(unknown),
+                prefilled.user.clone().unwrap_or_else(unknown),
+                prefilled.trusted_certificates
+            ),
+        );
+    }
+
+    let mut register_config =
+        config::RegisterExistingConfig::new(runtime_config, settings.register_opts(opts)?)?;
+    let trusted = settings.trusted_certificates();
+    if !trusted.is_empty() {
+        let connection_config = &register_config.connection_config;
+        let server_cert = certs::fetch_server_cert_pem(
+            &connection_config.site_id.server,
+            &connection_config.receiver_port,
+            connection_config.client_config.ip_preference,
+            &connection_config.client_config.timeouts,
+        )?;
+        match issuing_certificate(&trusted, &server_cert) {
+            Some(root_cert) => {
+                info!("The agent receiver is trusted via a certificate of the agent updater");
+                register_config.connection_config.root_certificate = Some(String::from(root_cert));
+            }
+            None => info!(
+                "None of the certificates of the agent updater issued the certificate of the \
+                 agent receiver"
+            ),
+        }
+    }
+    registration::register_existing(&register_config, registry, format)
+}
+
+/// Parser for the Python literals written by the agent updater and the agent bakery: dicts,
+/// lists, tuples, strings, numbers, booleans and None
+struct PythonLiteral<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> PythonLiteral<'a> {
+    fn new(text: &'a str) -> Self {
+        Self { text, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        let line = self.text[..self.pos].matches('\n').count() + 1;
+        anyhow!("{message} in line {line}")
+    }
+
+    /// Whitespace and comments
+    fn skip_blank(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                '#' => self.pos += self.rest().find('\n').unwrap_or(self.rest().len()),
+                c if c.is_whitespace() => self.pos += c.len_utf8(),
+                _ => break,
+            }
+        }
+    }
+
+    fn end(&mut self) -> AnyhowResult<()> {
+        self.skip_blank();
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.error("Unexpected content")),
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> AnyhowResult<()> {
+        self.skip_blank();
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("Expected '{expected}'")));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Names, keywords and numbers
+    fn word(&mut self) -> &'a str {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_alphanumeric() || matches!(c, '_' | '.' | '+' | '-')))
+            .unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    /// "name = value" per line, as in the config file
+    fn assignments(mut self) -> AnyhowResult<Map<String, Value>> {
+        let mut assignments = Map::new();
+        loop {
+            self.skip_blank();
+            if self.peek().is_none() {
+                return Ok(assignments);
+            }
+            let name = self.word();
+            if name.is_empty() {
+                return Err(self.error("Expected an assignment"));
+            }
+            self.expect('=')?;
+            assignments.insert(String::from(name), self.value()?);
+        }
+    }
+
+    fn value(&mut self) -> AnyhowResult<Value> {
+        self.skip_blank();
+        match self.peek() {
+            None => Err(self.error("Unexpected end")),
+            Some('{') => self.dict(),
+            Some('[') => self.sequence(']'),
+            Some('(') => self.sequence(')'),
+            Some('\'' | '"') => self.string(false),
+            Some(_) => {
+                let word = self.word();
+                if matches!(self.peek(), Some('\'' | '"'))
+                    && !word.is_empty()
+                    && word.chars().all(|c| "bBuUrR".contains(c))
+                {
+                    return self.string(word.contains(['r', 'R']));
+                }
+                match word {
+                    "None" => Ok(Value::Null),
+                    "True" => Ok(Value::Bool(true)),
+                    "False" => Ok(Value::Bool(false)),
+                    number => number
+                        .parse::<i64>()
+                        .map(Value::from)
+                        .or_else(|_| number.parse::<f64>().map(Value::from))
+                        .map_err(|_| self.error(&format!("Unsupported value '{number}'"))),
+                }
+            }
+        }
+    }
+
+    /// After a value, either a separator or the end of the collection
+    fn separator(&mut self, closing: char) -> AnyhowResult<()> {
+        self.skip_blank();
+        match self.peek() {
+            Some(',') => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(c) if c == closing => Ok(()),
+            _ => Err(self.error(&format!("Expected ',' or '{closing}'"))),
+        }
+    }
+
+    /// Lists and tuples
+    fn sequence(&mut self, closing: char) -> AnyhowResult<Value> {
+        self.pos += 1;
+        let mut values = vec![];
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(closing) {
+                self.pos += 1;
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.separator(closing)?;
+        }
+    }
+
+    fn dict(&mut self) -> AnyhowResult<Value> {
+        self.pos += 1;
+        let mut entries = Map::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some('}') {
+                self.pos += 1;
+                return Ok(Value::Object(entries));
+            }
+            let key = match self.value()? {
+                Value::String(key) => key,
+                key => key.to_string(),
+            };
+            self.expect(':')?;
+            entries.insert(key, self.value()?);
+            self.separator('}')?;
+        }
+    }
+
+    fn string(&mut self, raw: bool) -> AnyhowResult<Value> {
+        let quote = self.peek().unwrap_or('\'');
+        let triple = quote.to_string().repeat(3);
+        let delimiter = match self.rest().starts_with(&triple) {
+            true => triple,
+            false => quote.to_string(),
+        };
+        self.pos += delimiter.len();
+        let start = self.pos;
+        let mut string = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((offset, c)) = chars.next() {
+            if self.text[start + offset..].starts_with(&delimiter) {
+                self.pos = start + offset + delimiter.len();
+                return Ok(Value::String(string));
+            }
+            if c != '\\' {
+                string.push(c);
+                continue;
+            }
+            let Some((_, escaped)) = chars.next() else {
+                break;
+            };
+            if raw {
+                string.push(c);
+                string.push(escaped);
+                continue;
+            }
+            let mut hex = |digits| {
+                let code: String = chars.by_ref().take(digits).map(|(_, c)| c).collect();
+                u32::from_str_radix(&code, 16).ok().and_then(char::from_u32)
+            };
+            match escaped {
+                'n' => string.push('\n'),
+                'r' => string.push('\r'),
+                't' => string.push('\t'),
+                '0' => string.push('\0'),
+                '\\' | '\'' | '"' => string.push(escaped),
+                // Line continuation
+                '\n' => {}
+                'x' | 'u' | 'U' => {
+                    let digits = match escaped {
+                        'x' => 2,
+                        'u' => 4,
+                        _ => 8,
+                    };
+                    match hex(digits) {
+                        Some(decoded) => string.push(decoded),
+                        None => return Err(self.error("Invalid escape sequence")),
+                    }
+                }
+                other => {
+                    string.push(c);
+                    string.push(other);
+                }
+            }
+        }
+        Err(self.error("Unterminated string"))
+    }
+}
+
+#[cfg(test)]
+mod test_migrate_updater {
+    use super::*;
+
+    const STATE: &str = "{'protocol': 'http', 'installed_aghash': 'a71dfa65aacb1b52', \
+        'last_error': None, 'site': 'stable', 'last_check': 1563801836.6, 'host_name': 'windows', \
+        'server': '10.3.2.41', 'last_update': 1563801842.459, 'host_secret': 'zlsgotaddy', \
+        'user': 'cmkadmin', 'local_certificates': [\"-----BEGIN CERTIFICATE-----\\nMIIC\\n\"]}";
+
+    const CONFIG: &str = "# Created by Check_MK Agent Bakery.\n\
+        server = 'checkmk.example.com'\n\
+        site = r'mysite'\n\
+        protocol = 'https'\n\
+        interval = 86400\n\
+        proxy = {'proxy_protocol': 'socks5', 'port': 1234, 'enabled': True}\n\
+        certificates = [\n    '''-----BEGIN CERTIFICATE-----\n\\x41BC''',\n]\n";
+
+    #[test]
+    fn test_from_state() {
+        assert_eq!(
+            UpdaterSettings::from_state(STATE).unwrap(),
+            UpdaterSettings {
+                server: Some(String::from("10.3.2.41")),
+                site: Some(String::from("stable")),
+                host_name: Some(String::from("windows")),
+                user: Some(String::from("cmkadmin")),
+                certificates: None,
+                local_certificates: Some(vec![String::from("-----BEGIN CERTIFICATE-----\nMIIC\n")]),
+            }
+        );
+        assert!(UpdaterSettings::from_state("{'site': 'stable'").is_err());
+        assert!(UpdaterSettings::from_state("{'site': 'stable'} x").is_err());
+    }
+
+    #[test]
+    fn test_from_config() {
+        assert_eq!(
+            UpdaterSettings::from_config(CONFIG).unwrap(),
+            UpdaterSettings {
+                server: Some(String::from("checkmk.example.com")),
+                site: Some(String::from("mysite")),
+                certificates: Some(vec![String::from("-----BEGIN CERTIFICATE-----\nABC")]),
+                ..UpdaterSettings::default()
+            }
+        );
+        assert!(UpdaterSettings::from_config("site = 'unterminated").is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join(constants::UPDATER_CONFIG_FILE);
+        let state_path = dir.path().join(constants::UPDATER_STATE_FILE);
+        assert!(UpdaterSettings::load(&config_path, &state_path).is_err());
+        fs::write(&config_path, CONFIG).unwrap();
+        fs::write(&state_path, "{'site': 'stable', 'host_name': 'windows'}").unwrap();
+        let settings = UpdaterSettings::load(&config_path, &state_path).unwrap();
+        assert_eq!(
+            settings.prefilled(),
+            Prefilled {
+                server: Some(String::from("checkmk.example.com")),
+                site: Some(String::from("stable")),
+                host_name: Some(String::from("windows")),
+                user: None,
+                trusted_certificates: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_issuing_certificate() {
+        let (root_cert, root_key) = certs::make_ca("site", 1).unwrap();
+        let (other_root_cert, _) = certs::make_ca("other", 1).unwrap();
+        let (csr, _) = certs::make_csr("receiver").unwrap();
+        let server_cert = certs::sign_csr(&root_cert, &root_key, &csr, 1).unwrap();
+        assert_eq!(
+            issuing_certificate(&[&other_root_cert, "garbage", &root_cert], &server_cert),
+            Some(root_cert.as_str())
+        );
+        assert_eq!(issuing_certificate(&[&other_root_cert], &server_cert), None);
+    }
+}
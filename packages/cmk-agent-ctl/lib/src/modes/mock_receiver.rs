@@ -0,0 +1,710 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Stand-in for the agent receiver of a Checkmk site, st. registration flows can be tested without
+//! a site. Only meant for testing, hence behind the feature "mock-receiver".
+
+use crate::{certs, cli, constants};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use http::StatusCode;
+use log::{debug, info, warn};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use serde_with::DisplayFromStr;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// Not a UUID, st. the controller accepts it as server certificate
+const SERVER_CN: &str = "cmk-agent-ctl-mock-receiver";
+const CERT_VALIDITY_DAYS: u32 = 365;
+const DISCOVERY_PATH: &str =
+    "check_mk/api/1.0/domain-types/internal/actions/discover-receiver/invoke";
+
+#[serde_with::serde_as]
+#[derive(Deserialize)]
+struct RegisterExistingBody {
+    #[serde_as(as = "DisplayFromStr")]
+    uuid: uuid::Uuid,
+    csr: String,
+    host_name: String,
+}
+
+#[serde_with::serde_as]
+#[derive(Deserialize)]
+struct RegisterNewBody {
+    #[serde_as(as = "DisplayFromStr")]
+    uuid: uuid::Uuid,
+    csr: String,
+    agent_labels: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct RenewCertificateBody {
+    csr: String,
+}
+
+#[derive(Deserialize)]
+struct RenameHostBody {
+    host_name: String,
+}
+
+#[derive(Deserialize)]
+struct LabelUpdateBody {
+    agent_labels: HashMap<String, String>,
+}
+
+enum Host {
+    /// Registered via register_new, waiting for the controller to ask for the outcome
+    Pending {
+        host_name: String,
+        csr: String,
+    },
+    Registered {
+        host_name: String,
+    },
+}
+
+struct Request {
+    method: String,
+    path: String,
+    authorized: bool,
+    body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Response {
+    status: StatusCode,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: StatusCode, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    fn error(status: StatusCode, detail: &str) -> Self {
+        Self::json(status, json!({ "detail": detail }))
+    }
+
+    fn no_content() -> Self {
+        Self {
+            status: StatusCode::NO_CONTENT,
+            content_type: "text/plain",
+            body: String::new(),
+        }
+    }
+
+    fn to_http(&self) -> String {
+        let status_line = format!(
+            "HTTP/1.1 {}\r\nDate: {}\r\nConnection: close\r\n",
+            self.status,
+            httpdate::fmt_http_date(SystemTime::now())
+        );
+        if self.status == StatusCode::NO_CONTENT {
+            return status_line + "\r\n";
+        }
+        format!(
+            "{status_line}Content-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+    }
+}
+
+struct MockReceiver {
+    site: String,
+    port: u16,
+    behaviour: cli::MockBehaviour,
+    delay: Duration,
+    failure_rate: f64,
+    connection_mode: &'static str,
+    /// Self-signed, it is also handed out as root certificate and issues the agent certificates
+    cert: String,
+    key: String,
+    hosts: Mutex<HashMap<uuid::Uuid, Host>>,
+}
+
+impl MockReceiver {
+    fn new(opts: &cli::MockReceiverOpts) -> AnyhowResult<Self> {
+        let (cert, key) = certs::make_self_signed_cert(SERVER_CN, CERT_VALIDITY_DAYS)
+            .context("Failed to create server certificate")?;
+        Ok(Self {
+            site: opts.site.clone(),
+            port: opts.port,
+            behaviour: opts.behaviour,
+            delay: Duration::from_secs(opts.delay),
+            failure_rate: opts.failure_rate,
+            connection_mode: match opts.connection_mode {
+                cli::MockConnectionMode::Push => "push-agent",
+                cli::MockConnectionMode::Pull => "pull-agent",
+            },
+            cert,
+            key,
+            hosts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn tls_config(&self) -> AnyhowResult<Arc<ServerConfig>> {
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![certs::rustls_certificate(&self.cert)?],
+                certs::rustls_private_key(&self.key)?,
+            )?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+
+    fn hosts(&self) -> std::sync::MutexGuard<'_, HashMap<uuid::Uuid, Host>> {
+        match self.hosts.lock() {
+            Ok(hosts) => hosts,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn is_declining(&self) -> bool {
+        self.behaviour == cli::MockBehaviour::Decline
+    }
+
+    fn fails_at_random(&self) -> bool {
+        self.behaviour == cli::MockBehaviour::Flaky
+            && rand::thread_rng().gen_bool(self.failure_rate)
+    }
+
+    fn answer(&self, request: &Request) -> Response {
+        if self.fails_at_random() {
+            return Response::error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Mock agent receiver failed at random",
+            );
+        }
+        let Some(path) = request
+            .path
+            .strip_prefix('/')
+            .and_then(|path| path.strip_prefix(self.site.as_str()))
+            .and_then(|path| path.strip_prefix('/'))
+        else {
+            return Response::error(StatusCode::NOT_FOUND, "Unknown site");
+        };
+        if request.method == "GET" && path == DISCOVERY_PATH {
+            return Response {
+                status: StatusCode::OK,
+                content_type: "text/plain",
+                body: self.port.to_string(),
+            };
+        }
+        // Parameters like the wait of long polls are ignored, the mock decides right away
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        let segments: Vec<&str> = match path.strip_prefix("agent-receiver/") {
+            Some(endpoint) => endpoint.split('/').collect(),
+            None => vec![],
+        };
+        let result = match (request.method.as_str(), segments.as_slice()) {
+            ("POST", ["register_existing"]) => self.register_existing(request),
+            ("POST", ["register_new"]) => self.register_new(request),
+            ("POST", ["register_new_ongoing", uuid]) => self.register_new_ongoing(request, uuid),
+            ("GET", ["registration_status_v2", uuid]) => self.registration_status(uuid),
+            ("POST", ["renew_certificate", uuid]) => self.renew_certificate(request, uuid),
+            ("POST", ["agent_data", uuid]) => self.agent_data(uuid),
+            ("POST", ["labels", uuid]) => self.labels(request, uuid),
+            ("POST", ["rename_host", uuid]) => self.rename_host(request, uuid),
+            _ => Ok(Response::error(StatusCode::NOT_FOUND, "Not Found")),
+        };
+        result.unwrap_or_else(|err| Response::error(StatusCode::BAD_REQUEST, &format!("{err:#}")))
+    }
+
+    fn register_existing(&self, request: &Request) -> AnyhowResult<Response> {
+        if !request.authorized {
+            return Ok(unauthorized());
+        }
+        let body: RegisterExistingBody = serde_json::from_slice(&request.body)?;
+        if self.is_declining() {
+            return Ok(Response::error(
+                StatusCode::FORBIDDEN,
+                "Registration declined by mock agent receiver",
+            ));
+        }
+        let agent_cert = self.sign(&body.csr)?;
+        info!("Registered {} as {}", body.host_name, body.uuid);
+        self.hosts().insert(
+            body.uuid,
+            Host::Registered {
+                host_name: body.host_name,
+            },
+        );
+        Ok(Response::json(
+            StatusCode::OK,
+            json!({
+                "root_cert": self.cert,
+                "agent_cert": agent_cert,
+                "connection_mode": self.connection_mode,
+            }),
+        ))
+    }
+
+    fn register_new(&self, request: &Request) -> AnyhowResult<Response> {
+        if !request.authorized {
+            return Ok(unauthorized());
+        }
+        let body: RegisterNewBody = serde_json::from_slice(&request.body)?;
+        let host_name = body
+            .agent_labels
+            .get("cmk/hostname-simple")
+            .cloned()
+            .unwrap_or_else(|| body.uuid.to_string());
+        info!("Creating host {} for {}", host_name, body.uuid);
+        self.hosts().insert(
+            body.uuid,
+            Host::Pending {
+                host_name,
+                csr: body.csr,
+            },
+        );
+        Ok(Response::json(
+            StatusCode::OK,
+            json!({ "root_cert": self.cert }),
+        ))
+    }
+
+    fn register_new_ongoing(&self, request: &Request, uuid: &str) -> AnyhowResult<Response> {
+        if !request.authorized {
+            return Ok(unauthorized());
+        }
+        let uuid = uuid::Uuid::parse_str(uuid)?;
+        let mut hosts = self.hosts();
+        let Some(Host::Pending { host_name, csr }) = hosts.get(&uuid) else {
+            return Ok(Response::error(
+                StatusCode::NOT_FOUND,
+                "No registration in progress",
+            ));
+        };
+        if self.is_declining() {
+            hosts.remove(&uuid);
+            return Ok(Response::json(
+                StatusCode::OK,
+                json!({
+                    "status": "Declined",
+                    "reason": "Registration declined by mock agent receiver",
+                }),
+            ));
+        }
+        let agent_cert = self.sign(csr)?;
+        info!("Registered {} as {}", host_name, uuid);
+        let host_name = host_name.clone();
+        hosts.insert(uuid, Host::Registered { host_name });
+        Ok(Response::json(
+            StatusCode::OK,
+            json!({
+                "status": "Success",
+                "agent_cert": agent_cert,
+                "connection_mode": self.connection_mode,
+            }),
+        ))
+    }
+
+    fn registration_status(&self, uuid: &str) -> AnyhowResult<Response> {
+        let uuid = uuid::Uuid::parse_str(uuid)?;
+        Ok(Response::json(
+            StatusCode::OK,
+            match self.hosts().get(&uuid) {
+                Some(Host::Registered { host_name }) => json!({
+                    "status": "Registered",
+                    "hostname": host_name,
+                    "connection_mode": self.connection_mode,
+                }),
+                _ => json!({ "status": "NotRegistered" }),
+            },
+        ))
+    }
+
+    fn renew_certificate(&self, request: &Request, uuid: &str) -> AnyhowResult<Response> {
+        if !self.is_registered(uuid)? {
+            return Ok(not_registered());
+        }
+        let body: RenewCertificateBody = serde_json::from_slice(&request.body)?;
+        Ok(Response::json(
+            StatusCode::OK,
+            json!({ "agent_cert": self.sign(&body.csr)? }),
+        ))
+    }
+
+    fn agent_data(&self, uuid: &str) -> AnyhowResult<Response> {
+        if !self.is_registered(uuid)? {
+            return Ok(not_registered());
+        }
+        Ok(Response::no_content())
+    }
+
+    fn rename_host(&self, request: &Request, uuid: &str) -> AnyhowResult<Response> {
+        if !request.authorized {
+            return Ok(unauthorized());
+        }
+        if !self.is_registered(uuid)? {
+            return Ok(not_registered());
+        }
+        let body: RenameHostBody = serde_json::from_slice(&request.body)?;
+        info!("Renamed host of {} to {}", uuid, body.host_name);
+        self.hosts().insert(
+            uuid::Uuid::parse_str(uuid)?,
+            Host::Registered {
+                host_name: body.host_name,
+            },
+        );
+        Ok(Response::no_content())
+    }
+
+    fn labels(&self, request: &Request, uuid: &str) -> AnyhowResult<Response> {
+        if !self.is_registered(uuid)? {
+            return Ok(not_registered());
+        }
+        let body: LabelUpdateBody = serde_json::from_slice(&request.body)?;
+        info!("Updated labels of {}: {:?}", uuid, body.agent_labels);
+        Ok(Response::no_content())
+    }
+
+    fn is_registered(&self, uuid: &str) -> AnyhowResult<bool> {
+        Ok(matches!(
+            self.hosts().get(&uuid::Uuid::parse_str(uuid)?),
+            Some(Host::Registered { .. })
+        ))
+    }
+
+    fn sign(&self, csr: &str) -> AnyhowResult<String> {
+        certs::sign_csr(&self.cert, &self.key, csr, CERT_VALIDITY_DAYS)
+    }
+}
+
+fn unauthorized() -> Response {
+    Response::error(StatusCode::UNAUTHORIZED, "Not authenticated")
+}
+
+fn not_registered() -> Response {
+    Response::error(StatusCode::NOT_FOUND, "Host is not registered")
+}
+
+/// None if the client closed the connection without sending a request, which the controller does
+/// when fetching the server certificate
+async fn read_request(stream: impl tokio::io::AsyncRead + Unpin) -> AnyhowResult<Option<Request>> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).await? == 0 {
+        return Ok(None);
+    }
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        bail!("Malformed request line {:?}", request_line)
+    };
+    let (mut content_length, mut authorized) = (0, false);
+    let mut header = String::new();
+    while reader.read_line(&mut header).await? > 0 && !header.trim().is_empty() {
+        if let Some((name, value)) = header.split_once(':') {
+            match name.trim().to_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse()?,
+                "authorization" => authorized = true,
+                "transfer-encoding" => bail!("Only requests with Content-Length are supported"),
+                _ => {}
+            }
+        }
+        header.clear();
+    }
+    if content_length > constants::MOCK_RECEIVER_MAX_BODY_SIZE {
+        bail!("Request body of {} bytes is too large", content_length);
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(Request {
+        method: String::from(method),
+        path: String::from(path),
+        authorized,
+        body,
+    }))
+}
+
+async fn handle_connection(
+    receiver: Arc<MockReceiver>,
+    acceptor: TlsAcceptor,
+    stream: TcpStream,
+) -> AnyhowResult<()> {
+    let timeout = Duration::from_secs(constants::CONNECTION_TIMEOUT);
+    let mut stream = tokio::time::timeout(timeout, acceptor.accept(stream))
+        .await
+        .context("Timed out during TLS handshake")??;
+    let Some(request) = tokio::time::timeout(timeout, read_request(&mut stream))
+        .await
+        .context("Timed out reading request")??
+    else {
+        return Ok(());
+    };
+    if receiver.behaviour == cli::MockBehaviour::Slow {
+        tokio::time::sleep(receiver.delay).await;
+    }
+    let response = receiver.answer(&request);
+    info!("{} {} -> {}", request.method, request.path, response.status);
+    stream.write_all(response.to_http().as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn serve(listener: std::net::TcpListener, receiver: MockReceiver) -> AnyhowResult<()> {
+    let listener = TcpListener::from_std(listener)?;
+    let acceptor = TlsAcceptor::from(receiver.tls_config()?);
+    let receiver = Arc::new(receiver);
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Error accepting connection. ({})", err);
+                continue;
+            }
+        };
+        let (receiver, acceptor) = (Arc::clone(&receiver), acceptor.clone());
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(receiver, acceptor, stream).await {
+                debug!("{}: Error handling request. ({:#})", remote, err)
+            }
+        });
+    }
+}
+
+pub fn mock_receiver(opts: cli::MockReceiverOpts) -> AnyhowResult<()> {
+    let receiver = MockReceiver::new(&opts)?;
+    let listener = std::net::TcpListener::bind((opts.address.as_str(), opts.port))
+        .context(format!("Failed to bind to {}:{}", opts.address, opts.port))?;
+    listener.set_nonblocking(true)?;
+    println!(
+        "Mock agent receiver of site '{}' listening on {}:{}, register with\n  \
+         cmk-agent-ctl register --server {}:{} --site {} --trust-cert --user <any> --password <any> ...",
+        opts.site, opts.address, opts.port, opts.address, opts.port, opts.site
+    );
+    serve(listener, receiver)
+}
+
+#[cfg(test)]
+mod test_mock_receiver {
+    use super::*;
+    use clap::Parser;
+
+    const UUID: &str = "5bb7a9cd-8b5f-4b5b-a2a1-0e3d6e8f3cb0";
+
+    fn receiver(args: &[&str]) -> MockReceiver {
+        #[derive(Parser)]
+        struct Args {
+            #[clap(flatten)]
+            opts: cli::MockReceiverOpts,
+        }
+        let args = Args::parse_from([&["mock-receiver"][..], args].concat());
+        MockReceiver::new(&args.opts).unwrap()
+    }
+
+    fn request(method: &str, path: &str, body: serde_json::Value) -> Request {
+        Request {
+            method: String::from(method),
+            path: format!("/mock/{path}"),
+            authorized: true,
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn json_of(response: &Response) -> serde_json::Value {
+        serde_json::from_str(&response.body).unwrap()
+    }
+
+    fn register_existing(receiver: &MockReceiver) -> Response {
+        receiver.answer(&request(
+            "POST",
+            "agent-receiver/register_existing",
+            json!({
+                "uuid": UUID,
+                "csr": certs::make_csr(UUID).unwrap().0,
+                "host_name": "web01",
+            }),
+        ))
+    }
+
+    fn status(receiver: &MockReceiver) -> serde_json::Value {
+        json_of(&receiver.answer(&request(
+            "GET",
+            &format!("agent-receiver/registration_status_v2/{UUID}"),
+            json!(null),
+        )))
+    }
+
+    #[test]
+    fn test_register_existing() {
+        let receiver = receiver(&["--connection-mode", "pull"]);
+        assert_eq!(status(&receiver), json!({ "status": "NotRegistered" }));
+        let response = register_existing(&receiver);
+        assert_eq!(response.status, StatusCode::OK);
+        let body = json_of(&response);
+        assert_eq!(body["root_cert"], receiver.cert);
+        assert_eq!(body["connection_mode"], "pull-agent");
+        let agent_cert = certs::parse_pem(body["agent_cert"].as_str().unwrap()).unwrap();
+        assert!(agent_cert
+            .parse_x509()
+            .unwrap()
+            .subject()
+            .to_string()
+            .contains(UUID));
+        assert_eq!(
+            status(&receiver),
+            json!({
+                "status": "Registered",
+                "hostname": "web01",
+                "connection_mode": "pull-agent",
+            })
+        );
+    }
+
+    #[test]
+    fn test_rename_host() {
+        let receiver = receiver(&[]);
+        let rename = request(
+            "POST",
+            &format!("agent-receiver/rename_host/{UUID}"),
+            json!({ "host_name": "web03" }),
+        );
+        assert_eq!(receiver.answer(&rename).status, StatusCode::NOT_FOUND);
+        register_existing(&receiver);
+        let mut unauthorized = request(
+            "POST",
+            &format!("agent-receiver/rename_host/{UUID}"),
+            json!({ "host_name": "web03" }),
+        );
+        unauthorized.authorized = false;
+        assert_eq!(
+            receiver.answer(&unauthorized).status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(receiver.answer(&rename).status, StatusCode::NO_CONTENT);
+        assert_eq!(status(&receiver)["hostname"], "web03");
+    }
+
+    #[test]
+    fn test_labels() {
+        let receiver = receiver(&[]);
+        let update = request(
+            "POST",
+            &format!("agent-receiver/labels/{UUID}"),
+            json!({ "agent_labels": { "cmk/hw-vendor": "ACME" } }),
+        );
+        assert_eq!(receiver.answer(&update).status, StatusCode::NOT_FOUND);
+        register_existing(&receiver);
+        assert_eq!(receiver.answer(&update).status, StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn test_register_new() {
+        let receiver = receiver(&[]);
+        let response = receiver.answer(&request(
+            "POST",
+            "agent-receiver/register_new",
+            json!({
+                "uuid": UUID,
+                "csr": certs::make_csr(UUID).unwrap().0,
+                "agent_labels": { "cmk/hostname-simple": "web02" },
+            }),
+        ));
+        assert_eq!(json_of(&response)["root_cert"], receiver.cert);
+        assert_eq!(status(&receiver), json!({ "status": "NotRegistered" }));
+        let ongoing = json_of(&receiver.answer(&request(
+            "POST",
+            &format!("agent-receiver/register_new_ongoing/{UUID}?wait=60"),
+            json!(null),
+        )));
+        assert_eq!(ongoing["status"], "Success");
+        assert_eq!(ongoing["connection_mode"], "push-agent");
+        assert_eq!(status(&receiver)["hostname"], "web02");
+    }
+
+    #[test]
+    fn test_decline() {
+        let receiver = receiver(&["--behaviour", "decline"]);
+        assert_eq!(register_existing(&receiver).status, StatusCode::FORBIDDEN);
+        receiver.answer(&request(
+            "POST",
+            "agent-receiver/register_new",
+            json!({
+                "uuid": UUID,
+                "csr": certs::make_csr(UUID).unwrap().0,
+                "agent_labels": {},
+            }),
+        ));
+        assert_eq!(
+            json_of(&receiver.answer(&request(
+                "POST",
+                &format!("agent-receiver/register_new_ongoing/{UUID}"),
+                json!(null),
+            )))["status"],
+            "Declined"
+        );
+        assert_eq!(status(&receiver), json!({ "status": "NotRegistered" }));
+    }
+
+    #[test]
+    fn test_flaky() {
+        let failing = receiver(&["--behaviour", "flaky", "--failure-rate", "1"]);
+        assert_eq!(
+            register_existing(&failing).status,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        let succeeding = receiver(&["--behaviour", "flaky", "--failure-rate", "0"]);
+        assert_eq!(register_existing(&succeeding).status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_unauthorized_and_unknown() {
+        let receiver = receiver(&[]);
+        let mut unauthorized = request("POST", "agent-receiver/register_existing", json!({}));
+        unauthorized.authorized = false;
+        assert_eq!(
+            receiver.answer(&unauthorized).status,
+            StatusCode::UNAUTHORIZED
+        );
+        let mut other_site = request("GET", DISCOVERY_PATH, json!(null));
+        other_site.path = format!("/other/{DISCOVERY_PATH}");
+        assert_eq!(receiver.answer(&other_site).status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            receiver
+                .answer(&request("GET", DISCOVERY_PATH, json!(null)))
+                .body,
+            "8000"
+        );
+        assert_eq!(
+            receiver
+                .answer(&request(
+                    "POST",
+                    &format!("agent-receiver/agent_data/{UUID}"),
+                    json!(null)
+                ))
+                .status,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let raw = b"POST /mock/agent-receiver/register_new HTTP/1.1\r\n\
+            authorization: Basic dXNlcjpwYXNz\r\nContent-Length: 4\r\n\r\n{}\r\n";
+        let request = read_request(&raw[..]).await.unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/mock/agent-receiver/register_new");
+        assert!(request.authorized);
+        assert_eq!(request.body, b"{}\r\n");
+        assert!(read_request(&b""[..]).await.unwrap().is_none());
+    }
+}
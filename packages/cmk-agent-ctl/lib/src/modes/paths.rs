@@ -0,0 +1,126 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Locations the controller reads and writes, as resolved from the defaults and the config file.
+//! Useful for writing mandatory access control policies (SELinux, AppArmor).
+
+#[cfg(windows)]
+use crate::constants;
+use crate::setup;
+use anyhow::Result as AnyhowResult;
+use serde::Serialize;
+use std::path::PathBuf;
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Locations {
+    home_dir: PathBuf,
+    config_file: PathBuf,
+    state_dir: PathBuf,
+    registry: PathBuf,
+    push_spool: PathBuf,
+    control_socket: PathBuf,
+    log_file: Option<PathBuf>,
+    agent_socket: String,
+}
+
+impl Locations {
+    fn new(paths: &setup::PathResolver, log_file: Option<PathBuf>, agent_socket: String) -> Self {
+        Self {
+            home_dir: paths.home_dir.clone(),
+            config_file: paths.config_path.clone(),
+            state_dir: paths.state_dir.clone(),
+            registry: paths.registry_path.clone(),
+            push_spool: paths.push_spool_path.clone(),
+            #[cfg(unix)]
+            control_socket: paths.control_socket_path.clone(),
+            #[cfg(windows)]
+            control_socket: PathBuf::from(constants::WIN_CONTROL_PIPE),
+            log_file,
+            agent_socket,
+        }
+    }
+
+    fn to_human_readable(&self) -> String {
+        [
+            ("Home directory", self.home_dir.display().to_string()),
+            ("Config file", self.config_file.display().to_string()),
+            ("State directory", self.state_dir.display().to_string()),
+            ("Registry", self.registry.display().to_string()),
+            ("Push spool", self.push_spool.display().to_string()),
+            ("Control socket", self.control_socket.display().to_string()),
+            (
+                "Log file",
+                self.log_file
+                    .as_ref()
+                    .map_or(String::from("(none)"), |path| path.display().to_string()),
+            ),
+            ("Agent socket", self.agent_socket.clone()),
+        ]
+        .iter()
+        .map(|(name, location)| format!("{:<16} {location}", format!("{name}:")))
+        .collect::<Vec<_>>()
+        .join("\n")
+    }
+}
+
+pub fn paths(paths: &setup::PathResolver, json: bool) -> AnyhowResult<()> {
+    let locations = Locations::new(
+        paths,
+        setup::log_file(&paths.config_path),
+        setup::agent_channel().to_string(),
+    );
+    println!(
+        "{}",
+        match json {
+            true => serde_json::to_string(&locations)?,
+            false => locations.to_human_readable(),
+        }
+    );
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod test_paths {
+    use super::*;
+    use crate::config;
+
+    fn locations() -> Locations {
+        Locations::new(
+            &setup::PathResolver::new(std::path::Path::new("/var/lib/cmk-agent")).with_config(
+                &config::PathsConfig {
+                    state_dir: Some(PathBuf::from("/var/lib/cmk-agent-ctl")),
+                    push_spool_dir: None,
+                    control_socket: Some(PathBuf::from("/run/cmk-agent-ctl/cmk-agent-ctl.sock")),
+                },
+            ),
+            None,
+            String::from("/run/check-mk-agent.socket"),
+        )
+    }
+
+    #[test]
+    fn test_human_readable() {
+        assert_eq!(
+            locations().to_human_readable(),
+            "Home directory:  /var/lib/cmk-agent\n\
+             Config file:     /var/lib/cmk-agent/cmk-agent-ctl.toml\n\
+             State directory: /var/lib/cmk-agent-ctl\n\
+             Registry:        /var/lib/cmk-agent-ctl/registered_connections.json\n\
+             Push spool:      /var/lib/cmk-agent-ctl/push_spool\n\
+             Control socket:  /run/cmk-agent-ctl/cmk-agent-ctl.sock\n\
+             Log file:        (none)\n\
+             Agent socket:    /run/check-mk-agent.socket"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let json = serde_json::to_value(locations()).unwrap();
+        assert_eq!(
+            json["registry"],
+            "/var/lib/cmk-agent-ctl/registered_connections.json"
+        );
+        assert_eq!(json["log_file"], serde_json::Value::Null);
+    }
+}
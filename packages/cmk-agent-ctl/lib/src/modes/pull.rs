@@ -0,0 +1,1266 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use core::future::Future;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use crate::{
+    config,
+    connection_stats::ConnectionStats,
+    data_source::{self, DataSource},
+    fips, legacy_encryption,
+    misc::anyhow_error_to_human_readable,
+    monitoring_data, openssl_tls,
+    payload_memory::{BufferedPayload, PayloadMemory},
+    post_processing::Pipeline,
+    quic,
+    status_section::StatusSection,
+    tls_server, transport, types, usage_stats,
+};
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use openssl::ssl::NameType;
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener as TcpListenerStd};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, Semaphore};
+use tokio::time::{timeout, Duration};
+use tokio_rustls::rustls::server::Acceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::LazyConfigAcceptor;
+
+pub const TLS_ID: &[u8] = b"16";
+pub const HEADER_VERSION: &[u8] = b"\x00\x00";
+/// Offered via ALPN by receivers which understand version 1 of the pull protocol
+pub const ALPN_PULL_V1: &[u8] = b"cmk-pull/1";
+const ONE_MINUTE: u64 = 60;
+const PULL_ACTIVITY_TIMEOUT: u64 = 330; // Avoid exactly 5 minutes, as this is a common check interval
+
+/// Framing of the agent output sent via TLS, as agreed with the receiver when handshaking. A
+/// receiver not offering any version via ALPN gets the legacy framing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PullProtocol {
+    /// Header version (0, u16) and compression (u8), followed by the compressed agent output
+    Legacy,
+    /// Protocol version (1, u16), compression (u8) and length of the compressed agent output
+    /// (u64), all big endian, followed by the compressed agent output. The length leaves room for
+    /// sending more than one chunk in the future.
+    V1,
+}
+
+impl PullProtocol {
+    const V1_HEADER_LENGTH: usize = 11;
+
+    pub fn negotiate<'a>(offered: Option<impl Iterator<Item = &'a [u8]>>) -> Self {
+        match offered.map(|mut protocols| protocols.any(|protocol| protocol == ALPN_PULL_V1)) {
+            Some(true) => Self::V1,
+            _ => Self::Legacy,
+        }
+    }
+
+    /// The TLS setup agreeing on this protocol with the receiver
+    fn server_config(self, server_config: &Arc<ServerConfig>) -> Arc<ServerConfig> {
+        match self {
+            Self::Legacy => Arc::clone(server_config),
+            Self::V1 => {
+                let mut server_config = (**server_config).clone();
+                server_config.alpn_protocols = vec![ALPN_PULL_V1.to_vec()];
+                Arc::new(server_config)
+            }
+        }
+    }
+
+    fn legacy_header_length() -> usize {
+        HEADER_VERSION.len() + monitoring_data::compression_header_info().pull.len()
+    }
+
+    /// Split the agent output, encoded with the legacy header, into the header of this protocol
+    /// and the compressed agent output
+    fn frame(self, encoded: &[u8]) -> (Vec<u8>, &[u8]) {
+        match self {
+            Self::Legacy => (vec![], encoded),
+            Self::V1 => {
+                let (legacy_header, compressed) = encoded.split_at(Self::legacy_header_length());
+                let mut header = Vec::with_capacity(Self::V1_HEADER_LENGTH);
+                header.extend_from_slice(&1_u16.to_be_bytes());
+                header.extend_from_slice(&legacy_header[HEADER_VERSION.len()..]);
+                header.extend_from_slice(&(compressed.len() as u64).to_be_bytes());
+                (header, compressed)
+            }
+        }
+    }
+
+    /// The compressed agent output, as received by a site
+    pub fn unframe(self, received: &[u8]) -> AnyhowResult<&[u8]> {
+        let compression = monitoring_data::compression_header_info().pull;
+        match self {
+            Self::Legacy => {
+                let Some(compressed) = received.strip_prefix(HEADER_VERSION) else {
+                    bail!("Received data does not start with the expected protocol header")
+                };
+                compressed
+                    .strip_prefix(compression.as_slice())
+                    .context("Received data is not compressed as expected")
+            }
+            Self::V1 => {
+                if received.len() < Self::V1_HEADER_LENGTH {
+                    bail!("Received data is too short for the protocol header");
+                }
+                let (header, compressed) = received.split_at(Self::V1_HEADER_LENGTH);
+                if header[..2] != 1_u16.to_be_bytes() {
+                    bail!("Received data does not start with the expected protocol header")
+                }
+                if header[2..3] != compression[..] {
+                    bail!("Received data is not compressed as expected")
+                }
+                let mut length = [0; 8];
+                length.copy_from_slice(&header[3..]);
+                if u64::from_be_bytes(length) != compressed.len() as u64 {
+                    bail!(
+                        "Received {} bytes of data, the protocol header announced {}",
+                        compressed.len(),
+                        u64::from_be_bytes(length)
+                    )
+                }
+                Ok(compressed)
+            }
+        }
+    }
+}
+
+struct ListeningConfig {
+    pub addr_v4: Ipv4Addr,
+    pub addr_v6: Ipv6Addr,
+    pub port: u16,
+}
+
+impl ListeningConfig {
+    fn any(port: u16) -> ListeningConfig {
+        ListeningConfig {
+            addr_v4: Ipv4Addr::UNSPECIFIED,
+            addr_v6: Ipv6Addr::UNSPECIFIED,
+            port,
+        }
+    }
+}
+
+/// TLS of the pull server, via OpenSSL instead of rustls in FIPS mode
+enum ServerTls {
+    Rustls(tls_server::PullTls),
+    OpenSsl(Arc<openssl_tls::PullAcceptor>),
+}
+
+impl ServerTls {
+    fn new<'a>(
+        connections: impl Iterator<Item = &'a config::TrustedConnection>,
+    ) -> AnyhowResult<Self> {
+        Ok(match fips::enabled() {
+            true => Self::OpenSsl(Arc::new(openssl_tls::PullAcceptor::new(
+                connections,
+                ALPN_PULL_V1,
+            )?)),
+            false => Self::Rustls(tls_server::pull_tls(connections)?),
+        })
+    }
+}
+
+trait PullState {
+    fn refresh(&mut self) -> AnyhowResult<()>;
+    fn tls(&self) -> Arc<ServerTls>;
+    fn connection_stats(&self) -> ConnectionStats;
+    fn allow_legacy_pull(&self) -> bool;
+    fn is_active(&self) -> bool;
+    fn ip_allowlist(&self) -> &[String];
+    fn listening_config(&self) -> ListeningConfig;
+    fn connection_timeout(&self) -> u64;
+}
+struct PullStateImpl {
+    allow_legacy_pull: bool,
+    tls: Arc<ServerTls>,
+    connection_stats: ConnectionStats,
+    config: config::PullConfig,
+}
+
+impl PullStateImpl {
+    fn new(config: config::PullConfig, connection_stats: ConnectionStats) -> AnyhowResult<Self> {
+        Ok(Self {
+            allow_legacy_pull: config.allow_legacy_pull(),
+            tls: Arc::new(
+                ServerTls::new(config.get_pull_connections())
+                    .context("Could not initialize TLS.")?,
+            ),
+            connection_stats,
+            config,
+        })
+    }
+}
+
+impl PullState for PullStateImpl {
+    fn refresh(&mut self) -> AnyhowResult<()> {
+        if self.config.refresh()? {
+            self.tls = Arc::new(
+                ServerTls::new(self.config.get_pull_connections())
+                    .context("Could not initialize TLS.")?,
+            );
+        };
+        self.allow_legacy_pull = self.config.allow_legacy_pull();
+        Ok(())
+    }
+
+    fn tls(&self) -> Arc<ServerTls> {
+        Arc::clone(&self.tls)
+    }
+
+    fn connection_stats(&self) -> ConnectionStats {
+        self.connection_stats.clone()
+    }
+
+    fn allow_legacy_pull(&self) -> bool {
+        self.allow_legacy_pull
+    }
+
+    fn is_active(&self) -> bool {
+        self.allow_legacy_pull || self.config.has_connections()
+    }
+
+    fn ip_allowlist(&self) -> &[String] {
+        &self.config.allowed_ip
+    }
+
+    fn listening_config(&self) -> ListeningConfig {
+        ListeningConfig::any(self.config.port)
+    }
+
+    fn connection_timeout(&self) -> u64 {
+        self.config.connection_timeout
+    }
+}
+
+#[async_trait]
+pub trait AgentOutputCollector: std::clone::Clone + Sync + Send + 'static {
+    /// For legacy pull mode, encrypted if a passphrase is configured
+    async fn plain_output(&self, remote_ip: std::net::IpAddr) -> AnyhowResult<BufferedPayload>;
+    /// From the data source of the requested connection, see data_source
+    async fn encoded_output(
+        &self,
+        remote_ip: std::net::IpAddr,
+        data_source: Option<&DataSource>,
+    ) -> AnyhowResult<BufferedPayload>;
+}
+
+#[derive(Clone)]
+pub struct AgentOutputCollectorImpl {
+    agent_channel: types::AgentChannel,
+    fallback_command: Option<config::FallbackCommandConfig>,
+    legacy_pull_passphrase: Option<String>,
+    payload_memory: PayloadMemory,
+    post_processing: Arc<Pipeline>,
+}
+
+impl AgentOutputCollectorImpl {
+    pub fn new(
+        agent_channel: &types::AgentChannel,
+        payload_memory: PayloadMemory,
+        post_processing: Arc<Pipeline>,
+    ) -> Self {
+        AgentOutputCollectorImpl {
+            agent_channel: agent_channel.clone(),
+            fallback_command: None,
+            legacy_pull_passphrase: None,
+            payload_memory,
+            post_processing,
+        }
+    }
+
+    /// Run if the agent channel is unreachable, see data_source
+    pub fn with_fallback_command(
+        self,
+        fallback_command: Option<config::FallbackCommandConfig>,
+    ) -> Self {
+        Self {
+            fallback_command,
+            ..self
+        }
+    }
+
+    /// Encrypt the agent output served in legacy pull mode, see legacy_encryption
+    pub fn with_legacy_pull_passphrase(self, legacy_pull_passphrase: Option<String>) -> Self {
+        Self {
+            legacy_pull_passphrase,
+            ..self
+        }
+    }
+
+    async fn collect(
+        &self,
+        remote_ip: std::net::IpAddr,
+        data_source: Option<&DataSource>,
+    ) -> AnyhowResult<Vec<u8>> {
+        let mon_data = data_source::async_collect(
+            data_source,
+            &self.agent_channel,
+            remote_ip,
+            self.fallback_command.as_ref(),
+        )
+        .await?;
+        if self.post_processing.is_empty() {
+            return Ok(mon_data);
+        }
+        // External commands may take a while
+        let post_processing = Arc::clone(&self.post_processing);
+        tokio::task::spawn_blocking(move || post_processing.process(mon_data)).await?
+    }
+
+    /// Agent outputs which do not fit into memory any more are dropped, rejecting the request
+    fn buffer(&self, mon_data: Vec<u8>) -> AnyhowResult<BufferedPayload> {
+        self.payload_memory.buffer(mon_data).map_err(|_| {
+            anyhow!(
+                "Rejecting pull request - agent outputs buffered for pull requests exceed the memory limit of {} bytes",
+                self.payload_memory.limit().unwrap_or_default()
+            )
+        })
+    }
+
+    fn encode(&self, raw_agent_output: &[u8]) -> AnyhowResult<Vec<u8>> {
+        let mut encoded_data = HEADER_VERSION.to_vec();
+        encoded_data.append(&mut monitoring_data::compression_header_info().pull);
+        encoded_data.append(
+            &mut monitoring_data::compress(raw_agent_output)
+                .context("Error compressing monitoring data")?,
+        );
+        Ok(encoded_data)
+    }
+}
+
+#[async_trait]
+impl AgentOutputCollector for AgentOutputCollectorImpl {
+    async fn plain_output(&self, remote_ip: std::net::IpAddr) -> AnyhowResult<BufferedPayload> {
+        let mon_data = self.buffer(self.collect(remote_ip, None).await?)?;
+        match &self.legacy_pull_passphrase {
+            Some(passphrase) => mon_data.try_map(|mon_data| {
+                legacy_encryption::encrypt(passphrase, mon_data)
+                    .context("Error encrypting monitoring data")
+            }),
+            None => Ok(mon_data),
+        }
+    }
+
+    async fn encoded_output(
+        &self,
+        remote_ip: std::net::IpAddr,
+        data_source: Option<&DataSource>,
+    ) -> AnyhowResult<BufferedPayload> {
+        let mon_data = self
+            .collect(remote_ip, data_source)
+            .await
+            .context("Error collecting monitoring data.")?;
+        self.buffer(mon_data)?
+            .try_map(|mon_data| self.encode(mon_data))
+    }
+}
+struct MaxConnectionsGuard {
+    max_connections: usize,
+    active_connections: HashMap<IpAddr, Arc<Semaphore>>,
+}
+
+impl MaxConnectionsGuard {
+    pub fn new(max_connections: usize) -> Self {
+        MaxConnectionsGuard {
+            max_connections,
+            active_connections: HashMap::new(),
+        }
+    }
+
+    pub fn try_make_task_for_addr(
+        &mut self,
+        addr: SocketAddr,
+        fut: impl Future<Output = AnyhowResult<()>>,
+    ) -> AnyhowResult<impl Future<Output = AnyhowResult<()>>> {
+        let ip_addr = addr.ip();
+        let sem = self
+            .active_connections
+            .entry(ip_addr)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_connections)));
+        if let Ok(permit) = Arc::clone(sem).try_acquire_owned() {
+            let task_num = self.max_connections - sem.available_permits();
+            Ok(async move {
+                let res = fut.await;
+                drop(permit);
+                debug!(
+                    "processed task {} from ip {:?} result {:?}",
+                    task_num, ip_addr, res
+                );
+                res
+            })
+        } else {
+            debug!("Too many active connections at ip_addr {}", ip_addr);
+            bail!("Too many active connections")
+        }
+    }
+}
+
+pub fn pull(
+    pull_config: config::PullConfig,
+    connection_stats: ConnectionStats,
+) -> AnyhowResult<()> {
+    pull_runtime_wrapper(pull_config, connection_stats)
+}
+
+/// `ready` is signaled as soon as the listener is up, or if there is nothing to listen for.
+/// With a pre-bound listener, eg. on a privileged port, it is used instead of binding a new one
+/// whenever pull is active. It stays bound while pull is inactive.
+pub async fn async_pull(
+    pull_config: config::PullConfig,
+    connection_stats: ConnectionStats,
+    status_section: Option<StatusSection>,
+    ready: Option<oneshot::Sender<()>>,
+    listener: Option<TcpListenerStd>,
+) -> AnyhowResult<()> {
+    let guard = MaxConnectionsGuard::new(pull_config.max_connections);
+    let agent_output_collector = AgentOutputCollectorImpl::new(
+        &pull_config.agent_channel,
+        PayloadMemory::new(pull_config.max_payload_memory),
+        Arc::new(
+            Pipeline::new(&pull_config.section_filter, &pull_config.post_processors)?
+                .with_payload_accounting(&pull_config.payload_size, connection_stats.payload())
+                .with_status_section(status_section),
+        ),
+    )
+    .with_fallback_command(pull_config.fallback_command.clone())
+    .with_legacy_pull_passphrase(pull_config.legacy_pull_passphrase.clone());
+    // Connections registered with QUIC are pulled via QUIC regardless of the setting
+    let quic = pull_config.quic
+        || pull_config
+            .get_pull_connections()
+            .any(|connection| connection.transport == transport::TransportKind::Quic);
+    if quic && fips::enabled() {
+        warn!("Not serving pull requests via QUIC, QUIC is not available in FIPS mode.");
+    } else if quic {
+        let quic_pulls = quic::serve_pulls(
+            pull_config.registry().clone(),
+            pull_config.quic,
+            pull_config.port,
+            pull_config.allowed_ip.clone(),
+            pull_config.connection_timeout,
+            agent_output_collector.clone(),
+            connection_stats.clone(),
+        );
+        tokio::spawn(async move {
+            // Pull requests via TCP are served regardless
+            if let Err(err) = quic_pulls.await {
+                error!(
+                    "Error serving pull requests via QUIC, QUIC is unavailable. ({})",
+                    err
+                );
+            }
+        });
+    }
+    let pull_state = PullStateImpl::new(pull_config, connection_stats)?;
+    _pull(pull_state, guard, agent_output_collector, ready, listener).await
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn pull_runtime_wrapper(
+    pull_config: config::PullConfig,
+    connection_stats: ConnectionStats,
+) -> AnyhowResult<()> {
+    async_pull(pull_config, connection_stats, None, None, None).await
+}
+
+fn signal_ready(ready: &mut Option<oneshot::Sender<()>>) {
+    if let Some(ready) = ready.take() {
+        // Nobody waiting is fine
+        let _ = ready.send(());
+    }
+}
+
+async fn _pull(
+    mut pull_state: impl PullState,
+    mut guard: MaxConnectionsGuard,
+    agent_output_collector: impl AgentOutputCollector,
+    mut ready: Option<oneshot::Sender<()>>,
+    listener: Option<TcpListenerStd>,
+) -> AnyhowResult<()> {
+    loop {
+        if !pull_state.is_active() {
+            signal_ready(&mut ready);
+            tokio::time::sleep(Duration::from_secs(ONE_MINUTE)).await;
+            // Allow a crash due to a failing registry reload. It's not likely to recover
+            // here without action taken, and it's vital for all connections.
+            pull_state.refresh()?;
+            continue;
+        }
+        info!("Start listening for incoming pull requests");
+        _pull_loop(
+            &mut pull_state,
+            &mut guard,
+            agent_output_collector.clone(),
+            &mut ready,
+            listener.as_ref(),
+        )
+        .await?;
+    }
+}
+
+#[cfg(windows)]
+/// In windows REUSE is forbidden due to security reasons
+fn set_socket_for_exclusive_use(socket: &Socket, on: bool) -> std::io::Result<()> {
+    use std::os::windows::prelude::AsRawSocket;
+    use winapi::{ctypes, um::winsock2};
+    let on = u32::from(on);
+    let ret = unsafe {
+        let sock = socket.as_raw_socket() as winsock2::SOCKET;
+        let ptr = &on as *const ctypes::c_ulong as *const ctypes::c_void as *const ctypes::c_char;
+        winsock2::setsockopt(
+            sock,
+            winsock2::SOL_SOCKET,
+            winsock2::SO_EXCLUSIVEADDRUSE,
+            ptr,
+            4,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        code => Err(std::io::Error::from_raw_os_error(code)),
+    }
+}
+
+pub enum SocketMode {
+    Reuse,
+    Exclusive,
+}
+
+fn configure_socket(socket: Socket, mode: SocketMode) -> AnyhowResult<Socket> {
+    socket.set_nonblocking(true)?;
+    // https://stackoverflow.com/questions/3229860/what-is-the-meaning-of-so-reuseaddr-setsockopt-option-linux
+    // Allow re-using the address, even if there are still connections. After sending the agent
+    // data, we end up with a waiting connection which will be dropped at some point, but not
+    // immediately (this is OK, standard TCP protocol). However, this will block the re-creation
+    // of the socket if the agent controller is restarted (agent update or manual restart).
+    match mode {
+        #[cfg(windows)]
+        SocketMode::Exclusive => set_socket_for_exclusive_use(&socket, true)?,
+        #[cfg(unix)]
+        SocketMode::Exclusive => panic!("Not supported in Linux"),
+        SocketMode::Reuse => socket.set_reuse_address(true)?,
+    }
+
+    Ok(socket)
+}
+
+#[cfg(windows)]
+const DEFAULT_SOCKET_MODE: SocketMode = SocketMode::Exclusive;
+#[cfg(unix)]
+const DEFAULT_SOCKET_MODE: SocketMode = SocketMode::Reuse;
+
+fn tcp_listener_v4(address: Ipv4Addr, port: u16) -> AnyhowResult<TcpListenerStd> {
+    let socket = configure_socket(
+        Socket::new(Domain::IPV4, Type::STREAM, None)?,
+        DEFAULT_SOCKET_MODE,
+    )?;
+    socket.bind(&SockAddr::from(SocketAddr::new(IpAddr::V4(address), port)))?;
+    socket.listen(4096)?;
+    Ok(socket.into())
+}
+
+fn tcp_listener_v6(address: Ipv6Addr, port: u16) -> AnyhowResult<TcpListenerStd> {
+    let socket = configure_socket(
+        Socket::new(Domain::IPV6, Type::STREAM, None)?,
+        DEFAULT_SOCKET_MODE,
+    )?;
+    socket.set_only_v6(false)?;
+    socket.bind(&SockAddr::from(SocketAddr::new(IpAddr::V6(address), port)))?;
+    socket.listen(4096)?;
+    Ok(socket.into())
+}
+
+fn tcp_listener(listening_config: ListeningConfig) -> AnyhowResult<TcpListenerStd> {
+    let err_v6 = match tcp_listener_v6(listening_config.addr_v6, listening_config.port) {
+        Ok(listener) => {
+            info!(
+                "Listening on {} for incoming pull connections (IPv6 & IPv4 if activated)",
+                listener.local_addr()?
+            );
+            return Ok(listener);
+        }
+        Err(err_v6) => err_v6,
+    };
+    info!("Failed to open IPv6 socket for pull connections, attempting with IPv4");
+    let err_v4 = match tcp_listener_v4(listening_config.addr_v4, listening_config.port) {
+        Ok(listener) => {
+            info!(
+                "Listening on {} for incoming pull connections (IPv4)",
+                listener.local_addr()?
+            );
+            return Ok(listener);
+        }
+        Err(err_v4) => err_v4,
+    };
+    bail!(
+        "Failed to listen on TCP socket for incoming pull connections.\n\nError with IPV6:\n{}\n\nError with IPV4:\n{}",
+        anyhow_error_to_human_readable(&err_v6),
+        anyhow_error_to_human_readable(&err_v4),
+    );
+}
+
+/// Listen on the given port on all addresses, IPv6 and IPv4 if possible
+pub fn tcp_listener_any(port: u16) -> AnyhowResult<TcpListenerStd> {
+    tcp_listener(ListeningConfig::any(port))
+}
+
+/// Bind the pull port up front if only root may do so, st. the daemon can drop its privileges
+#[cfg(unix)]
+pub fn privileged_listener(
+    pull_config: &config::PullConfig,
+) -> AnyhowResult<Option<TcpListenerStd>> {
+    if pull_config.port >= crate::constants::PRIVILEGED_PORT_LIMIT {
+        return Ok(None);
+    }
+    tcp_listener(ListeningConfig::any(pull_config.port)).map(Some)
+}
+
+async fn _pull_loop(
+    pull_state: &mut impl PullState,
+    guard: &mut MaxConnectionsGuard,
+    agent_output_collector: impl AgentOutputCollector,
+    ready: &mut Option<oneshot::Sender<()>>,
+    listener: Option<&TcpListenerStd>,
+) -> AnyhowResult<()> {
+    let listener = TcpListener::from_std(match listener {
+        Some(listener) => listener.try_clone()?,
+        None => tcp_listener(pull_state.listening_config())?,
+    })?;
+    signal_ready(ready);
+
+    loop {
+        let Ok(connection_attempt) = timeout(
+            Duration::from_secs(PULL_ACTIVITY_TIMEOUT),
+            listener.accept(),
+        )
+        .await
+        else {
+            // No connection within timeout. Refresh config and check if we're still active.
+            pull_state.refresh()?;
+            if !pull_state.is_active() {
+                info!(
+                    "No pull connection registered, stop listening on {}.",
+                    listener.local_addr()?
+                );
+                return Ok(());
+            }
+            // If still active, don't return as this would close the socket - Just continue listening.
+            continue;
+        };
+
+        let (stream, remote) = match connection_attempt {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                warn!("Failed accepting pull connection. ({})", error);
+                continue;
+            }
+        };
+
+        if !is_addr_allowed(&remote, pull_state.ip_allowlist()) {
+            warn!(
+                "{}: Rejecting pull request - connection from IP is not allowed.",
+                remote
+            );
+            continue;
+        }
+
+        // Act on most recent registration data
+        pull_state.refresh()?;
+
+        // Check if pull was deactivated meanwhile before actually handling the request.
+        if !pull_state.is_active() {
+            info!("No pull connection registered, closing current connection and stop listening.");
+            return Ok(());
+        }
+
+        info!("{}: Handling pull request.", remote);
+
+        let request_handler_fut = handle_request(
+            stream,
+            agent_output_collector.clone(),
+            remote.ip(),
+            pull_state.allow_legacy_pull(),
+            pull_state.tls(),
+            pull_state.connection_stats(),
+            pull_state.connection_timeout(),
+        );
+
+        match guard.try_make_task_for_addr(remote, request_handler_fut) {
+            Ok(connection_fut) => {
+                tokio::spawn(async move {
+                    if let Err(err) = connection_fut.await {
+                        warn!("{}: Request failed. ({})", remote, err)
+                    };
+                });
+            }
+            Err(error) => {
+                warn!("{}: Request failed. ({})", remote, error);
+            }
+        }
+        debug!("{}: Handling pull request DONE (Task detached).", remote);
+    }
+}
+
+pub fn is_addr_allowed(addr: &SocketAddr, allowed_ip: &[String]) -> bool {
+    if allowed_ip.is_empty() {
+        return true;
+    }
+    let can_addr = to_canonical(addr.ip());
+    for ip in allowed_ip {
+        // Our list may contain both network, ip addresses and bad data(!)
+        // Examples: network - 192.168.1.14/24, address - 127.0.0.1
+        if let Ok(allowed_net) = ip.parse::<ipnet::IpNet>() {
+            if allowed_net.contains(&can_addr) {
+                return true;
+            }
+        }
+        if let Ok(allowed_addr) = ip.parse::<IpAddr>() {
+            if allowed_addr == can_addr {
+                return true;
+            }
+        }
+        // NOTE: no reporting about bad data here.
+        // We prefer to ignore error here: despite the possibility
+        // to have invalid settings we should check and report this once
+    }
+    false
+}
+
+fn to_canonical(ip_addr: IpAddr) -> IpAddr {
+    //IpAddr::to_canonical is unstable API :-(
+    match ip_addr {
+        IpAddr::V4(_v4) => ip_addr,
+        IpAddr::V6(v6) => {
+            let v4_map_subnet =
+                ipnet::Ipv6Net::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0), 96).unwrap();
+            if v4_map_subnet.contains(&v6) {
+                IpAddr::V4(v6.to_ipv4().unwrap())
+            } else {
+                ip_addr
+            }
+        }
+    }
+}
+
+async fn handle_request(
+    mut stream: TcpStream,
+    agent_output_collector: impl AgentOutputCollector,
+    remote_ip: IpAddr,
+    is_legacy_pull: bool,
+    tls: Arc<ServerTls>,
+    connection_stats: ConnectionStats,
+    connection_timeout: u64,
+) -> AnyhowResult<()> {
+    if is_legacy_pull {
+        debug!("handle_request: starts in legacy mode from {:?}", remote_ip);
+        return handle_legacy_pull_request(
+            stream,
+            agent_output_collector.plain_output(remote_ip),
+            connection_timeout,
+        )
+        .await;
+    }
+    debug!("handle_request: starts from {:?}", remote_ip);
+    let tls = match tls.as_ref() {
+        ServerTls::Rustls(tls) => tls,
+        ServerTls::OpenSsl(acceptor) => {
+            return handle_openssl_request(
+                stream,
+                agent_output_collector,
+                remote_ip,
+                Arc::clone(acceptor),
+                connection_stats,
+                connection_timeout,
+            )
+            .await
+        }
+    };
+
+    let start_handshake = with_timeout(
+        async move {
+            stream.write_all(TLS_ID).await?;
+            stream.flush().await?;
+            // Read the client hello first, st. failed handshakes can be attributed to the
+            // connection requested via SNI, and its data source is known.
+            LazyConfigAcceptor::new(Acceptor::default(), stream).await
+        },
+        connection_timeout,
+    )
+    .await?;
+    let requested_uuid = requested_connection(start_handshake.client_hello().server_name());
+    let protocol = PullProtocol::negotiate(start_handshake.client_hello().alpn());
+    let server_config = Arc::clone(&tls.server_config);
+    let stats = connection_stats.clone();
+    let handshake = async move {
+        let tls_stream = with_timeout(
+            start_handshake.into_stream(protocol.server_config(&server_config)),
+            connection_timeout,
+        )
+        .await;
+        if let Err(err) = &tls_stream {
+            record_tls_failure(
+                err,
+                "TLS handshake failed",
+                remote_ip,
+                requested_uuid,
+                &stats,
+            );
+        }
+        tls_stream.map(|tls_stream| (tls_stream, protocol))
+    };
+
+    // Only sent if the requested connection is the authorized one, see below
+    let encoded_mondata = agent_output_collector.encoded_output(
+        remote_ip,
+        requested_uuid
+            .as_ref()
+            .and_then(|uuid| tls.data_source(uuid)),
+    );
+
+    let (mon_data, tls_stream) = tokio::join!(encoded_mondata, handshake);
+    let mon_data = mon_data?;
+    let (mut tls_stream, protocol) = tls_stream?;
+    let (_, server_connection) = tls_stream.get_ref();
+    let uuid = match tls.authorizer.authorize(
+        server_connection.server_name(),
+        server_connection.peer_certificates(),
+    ) {
+        Ok(uuid) => {
+            info!(
+                "{}: Authorized pull request for connection {}.",
+                remote_ip, uuid
+            );
+            uuid
+        }
+        Err(err) => {
+            record_tls_failure(
+                &err,
+                "Rejecting pull request",
+                remote_ip,
+                requested_connection(server_connection.server_name()),
+                &connection_stats,
+            );
+            return Err(err);
+        }
+    };
+    debug!(
+        "handle_request: ready to be send {:?} using {:?} protocol",
+        remote_ip, protocol
+    );
+    let bytes = mon_data.len();
+    with_timeout(
+        async move {
+            let (header, payload) = protocol.frame(&mon_data);
+            tls_stream.write_all(&header).await?;
+            tls_stream.write_all(payload).await?;
+            debug!("handle_request: had been send {:?}", remote_ip);
+            tls_stream.flush().await?;
+            tls_stream.shutdown().await
+        },
+        connection_timeout,
+    )
+    .await?;
+    connection_stats.record_pull(&uuid, remote_ip, bytes);
+    Ok(())
+}
+
+/// The counterpart of the handshake via rustls in FIPS mode, see openssl_tls. The agent output
+/// is collected after the handshake, when the requested connection is known.
+async fn handle_openssl_request(
+    mut stream: TcpStream,
+    agent_output_collector: impl AgentOutputCollector,
+    remote_ip: IpAddr,
+    acceptor: Arc<openssl_tls::PullAcceptor>,
+    connection_stats: ConnectionStats,
+    connection_timeout: u64,
+) -> AnyhowResult<()> {
+    with_timeout(
+        async {
+            stream.write_all(TLS_ID).await?;
+            stream.flush().await
+        },
+        connection_timeout,
+    )
+    .await?;
+    let stream = openssl_tls::into_blocking(stream, connection_timeout)?;
+    let handshake_acceptor = Arc::clone(&acceptor);
+    let mut tls_stream =
+        match tokio::task::spawn_blocking(move || handshake_acceptor.accept(stream)).await? {
+            Ok(tls_stream) => tls_stream,
+            Err(failure) => {
+                record_tls_failure(
+                    &failure.error,
+                    "TLS handshake failed",
+                    remote_ip,
+                    requested_connection(failure.server_name.as_deref()),
+                    &connection_stats,
+                );
+                return Err(failure.error);
+            }
+        };
+    let uuid = match acceptor.authorize(tls_stream.ssl()) {
+        Ok(uuid) => {
+            info!(
+                "{}: Authorized pull request for connection {}.",
+                remote_ip, uuid
+            );
+            uuid
+        }
+        Err(err) => {
+            record_tls_failure(
+                &err,
+                "Rejecting pull request",
+                remote_ip,
+                requested_connection(tls_stream.ssl().servername(NameType::HOST_NAME)),
+                &connection_stats,
+            );
+            return Err(err);
+        }
+    };
+    let protocol = PullProtocol::negotiate(
+        tls_stream
+            .ssl()
+            .selected_alpn_protocol()
+            .map(|protocol| [protocol].into_iter()),
+    );
+    let mon_data = agent_output_collector
+        .encoded_output(remote_ip, acceptor.data_source(&uuid))
+        .await?;
+    let bytes = mon_data.len();
+    tokio::task::spawn_blocking(move || -> AnyhowResult<()> {
+        let (header, payload) = protocol.frame(&mon_data);
+        std::io::Write::write_all(&mut tls_stream, &header)?;
+        std::io::Write::write_all(&mut tls_stream, payload)?;
+        std::io::Write::flush(&mut tls_stream)?;
+        tls_stream.shutdown()?;
+        Ok(())
+    })
+    .await??;
+    connection_stats.record_pull(&uuid, remote_ip, bytes);
+    Ok(())
+}
+
+/// Log why a pull request failed and count it for the connection requested via SNI
+fn record_tls_failure(
+    err: &anyhow::Error,
+    failure: &str,
+    remote_ip: IpAddr,
+    requested_uuid: Option<uuid::Uuid>,
+    connection_stats: &ConnectionStats,
+) {
+    usage_stats::record_transport_error(err);
+    let reason = anyhow_error_to_human_readable(err).replace('\n', ": ");
+    warn!("{}: {} - {}", remote_ip, failure, reason);
+    if let Some(uuid) = requested_uuid {
+        connection_stats.record_tls_failure(&uuid, remote_ip, &reason);
+    }
+}
+
+fn requested_connection(server_name: Option<&str>) -> Option<uuid::Uuid> {
+    server_name.and_then(|name| uuid::Uuid::parse_str(name).ok())
+}
+
+async fn handle_legacy_pull_request(
+    mut stream: TcpStream,
+    plain_mondata: impl Future<Output = AnyhowResult<BufferedPayload>>,
+    connection_timeout: u64,
+) -> AnyhowResult<()> {
+    let mon_data = plain_mondata
+        .await
+        .context("Error collecting monitoring data.")?;
+
+    with_timeout(
+        async move {
+            stream.write_all(&mon_data).await?;
+            stream.flush().await
+        },
+        connection_timeout,
+    )
+    .await
+}
+
+pub async fn with_timeout<T, E: 'static + Error + Send + Sync>(
+    fut: impl Future<Output = Result<T, E>>,
+    seconds: u64,
+) -> AnyhowResult<T> {
+    Ok(timeout(Duration::from_secs(seconds), fut)
+        .await
+        .map_err(|e| anyhow!(e))??)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::types::AgentChannel;
+
+    #[cfg(windows)]
+    mod win {
+        use socket2::{Domain, SockAddr, Socket, Type};
+        use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+        use crate::modes::pull::{configure_socket, SocketMode};
+
+        fn make_socket_std() -> Socket {
+            let socket_a = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+            configure_socket(socket_a, SocketMode::Reuse).unwrap()
+        }
+
+        fn make_socket_exclusive() -> Socket {
+            let socket_exclusive = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+            configure_socket(socket_exclusive, SocketMode::Exclusive).unwrap()
+        }
+
+        #[test]
+        fn test_socket_reuse_exclusive() {
+            let socket_a = make_socket_std();
+            let socket_b = make_socket_std();
+            let socket_exclusive = make_socket_exclusive();
+            let addr = SockAddr::from(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 19900));
+            let a = socket_a.bind(&addr);
+            let b = socket_b.bind(&addr);
+            let exclusive = socket_exclusive.bind(&addr);
+
+            assert!(a.is_ok());
+            assert!(b.is_ok());
+            assert!(exclusive.is_err());
+        }
+        #[test]
+        fn test_socket_exclusive_reuse() {
+            let socket_std = make_socket_std();
+            let socket_exclusive = make_socket_exclusive();
+            let addr = SockAddr::from(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 19900));
+
+            let exclusive = socket_exclusive.bind(&addr);
+            let a = socket_std.bind(&addr);
+
+            assert!(a.is_err());
+            assert!(exclusive.is_ok());
+        }
+        #[test]
+        fn test_socket_exclusive_exclusive() {
+            {
+                let socket_exclusive = make_socket_exclusive();
+                let addr =
+                    SockAddr::from(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 19900));
+                let exclusive = socket_exclusive.bind(&addr);
+                assert!(exclusive.is_ok());
+            }
+            let socket_exclusive = make_socket_exclusive();
+            let addr = SockAddr::from(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 19900));
+            let exclusive = socket_exclusive.bind(&addr);
+            assert!(exclusive.is_ok());
+        }
+    }
+
+    #[test]
+    fn test_encode_data_for_transport() {
+        let mut expected_result = b"\x00\x00\x01".to_vec();
+        expected_result.append(&mut monitoring_data::compress(b"abc").unwrap());
+        let agout = AgentOutputCollectorImpl::new(
+            &AgentChannel::from("dummy"),
+            PayloadMemory::new(None),
+            Arc::new(Pipeline::default()),
+        );
+        assert_eq!(agout.encode(b"abc").unwrap(), expected_result);
+    }
+
+    #[test]
+    fn test_pull_protocol_negotiate() {
+        let offered =
+            |protocols: &[&'static [u8]]| PullProtocol::negotiate(Some(protocols.iter().copied()));
+        assert_eq!(
+            PullProtocol::negotiate(None::<std::iter::Empty<&[u8]>>),
+            PullProtocol::Legacy
+        );
+        assert_eq!(offered(&[b"http/1.1"]), PullProtocol::Legacy);
+        assert_eq!(offered(&[b"cmk-pull/2", ALPN_PULL_V1]), PullProtocol::V1);
+    }
+
+    #[test]
+    fn test_pull_protocol_frame() {
+        let mut encoded = b"\x00\x00\x01".to_vec();
+        encoded.extend_from_slice(b"abc");
+        assert_eq!(
+            PullProtocol::Legacy.frame(&encoded),
+            (vec![], encoded.as_slice())
+        );
+        let (header, compressed) = PullProtocol::V1.frame(&encoded);
+        assert_eq!(header, b"\x00\x01\x01\x00\x00\x00\x00\x00\x00\x00\x03");
+        assert_eq!(compressed, b"abc");
+
+        for protocol in [PullProtocol::Legacy, PullProtocol::V1] {
+            let (mut received, compressed) = protocol.frame(&encoded);
+            received.extend_from_slice(compressed);
+            assert_eq!(protocol.unframe(&received).unwrap(), b"abc");
+        }
+    }
+
+    #[test]
+    fn test_pull_protocol_unframe_error() {
+        for received in [
+            &b"\x00\x01\x01"[..],
+            b"\x00\x02\x01\x00\x00\x00\x00\x00\x00\x00\x03abc",
+            b"\x00\x01\x02\x00\x00\x00\x00\x00\x00\x00\x03abc",
+            b"\x00\x01\x01\x00\x00\x00\x00\x00\x00\x00\x04abc",
+        ] {
+            assert!(PullProtocol::V1.unframe(received).is_err());
+        }
+        assert!(PullProtocol::Legacy.unframe(b"\x00\x01\x01abc").is_err());
+        assert!(PullProtocol::Legacy.unframe(b"\x00\x00\x00abc").is_err());
+    }
+
+    #[test]
+    fn test_buffer_limited() {
+        let agout = AgentOutputCollectorImpl::new(
+            &AgentChannel::from("dummy"),
+            PayloadMemory::new(Some(5)),
+            Arc::new(Pipeline::default()),
+        );
+        let buffered = agout.buffer(b"abc".to_vec()).unwrap();
+        assert!(agout.buffer(b"def".to_vec()).is_err());
+        drop(buffered);
+        assert_eq!(&*agout.buffer(b"def".to_vec()).unwrap(), b"def");
+    }
+    fn listening_config(port: u16) -> ListeningConfig {
+        ListeningConfig::any(port)
+    }
+
+    // we rely on our CI system using IPv6
+    #[test]
+    fn test_tcp_listener() {
+        let port = 45147;
+        assert_eq!(
+            tcp_listener(listening_config(port))
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .to_string(),
+            format!("[::]:{port}")
+        );
+    }
+
+    // we rely on our CI system using IPv6
+    #[test]
+    fn test_tcp_listener_v6() {
+        let lc = listening_config(45148);
+        assert_eq!(
+            tcp_listener_v6(lc.addr_v6, lc.port)
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .to_string(),
+            format!("[::]:{}", lc.port)
+        );
+    }
+
+    #[test]
+    fn test_tcp_listener_ipv4() {
+        let lc = listening_config(45149);
+        assert_eq!(
+            tcp_listener_v4(lc.addr_v4, lc.port)
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .to_string(),
+            format!("0.0.0.0:{}", lc.port)
+        );
+    }
+
+    mod allowed_ip {
+        use super::*;
+        fn args_good() -> Vec<String> {
+            vec![
+                "192.168.1.14/24".to_string(), // net
+                "::1".to_string(),
+                "127.0.0.1".to_string(),
+                "fd00::/17".to_string(), // net
+                "fd05::3".to_string(),
+            ]
+        }
+
+        fn args_bad() -> Vec<String> {
+            vec![
+                "192168114/24".to_string(), // invalid
+                "::1".to_string(),
+                "127.0.0.1".to_string(),
+                "fd00::/17".to_string(),
+            ]
+        }
+
+        fn to_sock_addr(addr: &str) -> SocketAddr {
+            format!("{addr}:80").parse::<SocketAddr>().unwrap()
+        }
+
+        fn to_ip_addr(addr: &str) -> IpAddr {
+            IpAddr::from_str(addr).unwrap()
+        }
+
+        #[test]
+        fn test_to_canonical() {
+            assert_eq!(
+                to_canonical(to_ip_addr("::ffff:1.2.3.4")),
+                to_ip_addr("1.2.3.4")
+            );
+            assert_eq!(
+                to_canonical(to_ip_addr("::fffe:1.2.3.4")),
+                to_ip_addr("::fffe:1.2.3.4")
+            );
+            assert_eq!(to_canonical(to_ip_addr("::1")), to_ip_addr("::1"));
+            assert_eq!(to_canonical(to_ip_addr("fd05::3")), to_ip_addr("fd05::3"));
+            assert_eq!(to_canonical(to_ip_addr("1.2.3.4")), to_ip_addr("1.2.3.4"));
+        }
+        #[test]
+        fn test_empty_list() {
+            let args = &vec![];
+            assert!(is_addr_allowed(&to_sock_addr("127.0.0.2"), args));
+            assert!(is_addr_allowed(&to_sock_addr("127.0.0.1"), args));
+        }
+        #[test]
+        fn test_good_list_ipaddr() {
+            let args = &args_good();
+            assert!(is_addr_allowed(&to_sock_addr("127.0.0.1"), args));
+            assert!(!is_addr_allowed(&to_sock_addr("127.0.0.2"), args));
+            assert!(is_addr_allowed(&to_sock_addr("[::ffff:127.0.0.1]"), args));
+            assert!(!is_addr_allowed(&to_sock_addr("[::ffff:127.0.0.2]"), args));
+            assert!(is_addr_allowed(&to_sock_addr("[::1]"), args));
+            assert!(!is_addr_allowed(&to_sock_addr("[::2]"), args));
+            assert!(is_addr_allowed(&to_sock_addr("[fd05::3]"), args));
+            assert!(!is_addr_allowed(&to_sock_addr("[fd05::9]"), args));
+        }
+        #[test]
+        fn test_bad_list_ipaddr() {
+            let args = &args_bad();
+            assert!(is_addr_allowed(&to_sock_addr("127.0.0.1"), args));
+            assert!(!is_addr_allowed(&to_sock_addr("127.0.0.2"), args));
+            assert!(is_addr_allowed(&to_sock_addr("[::ffff:127.0.0.1]"), args));
+            assert!(!is_addr_allowed(&to_sock_addr("[::ffff:127.0.0.2]"), args));
+        }
+        #[test]
+        fn test_valid_list_net() {
+            let args = &args_good();
+            assert!(is_addr_allowed(&to_sock_addr("192.168.1.13"), args));
+            assert!(!is_addr_allowed(&to_sock_addr("172.168.1.13"), args));
+            assert!(is_addr_allowed(
+                &to_sock_addr("[::ffff:192.168.1.13]"),
+                args
+            ));
+            assert!(!is_addr_allowed(
+                &to_sock_addr("[::ffff:172.168.1.13]"),
+                args
+            ));
+            assert!(is_addr_allowed(&to_sock_addr("[fd00::1]"), args));
+            assert!(!is_addr_allowed(&to_sock_addr("[fd01::1]"), args));
+        }
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::pull::{PullProtocol, ALPN_PULL_V1, TLS_ID};
+use crate::{certs, config, fips, openssl_tls, site_spec};
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
+use log::info;
+use openssl::pkey::PKey;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode, SslVersion};
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::X509;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Act as a Checkmk site and pull the monitoring data from the local pull listener, using the
+/// credentials of the given connection.
+pub fn pull_once(
+    registry: &config::Registry,
+    pull_config: &config::PullConfig,
+    ident: &str,
+    address: &str,
+) -> AnyhowResult<()> {
+    let connection = find_pull_connection(registry, ident)?;
+    let (encoded_mon_data, protocol) = fetch(
+        connection,
+        address,
+        pull_config.port,
+        pull_config.connection_timeout,
+    )?;
+    info!(
+        "Received {} bytes via connection {} using {:?} protocol",
+        encoded_mon_data.len(),
+        connection.uuid,
+        protocol
+    );
+    std::io::stdout()
+        .write_all(&decode(&encoded_mon_data, protocol)?)
+        .context("Error writing monitoring data to stdout.")?;
+    Ok(())
+}
+
+fn find_pull_connection<'reg>(
+    registry: &'reg config::Registry,
+    ident: &str,
+) -> AnyhowResult<&'reg config::TrustedConnection> {
+    if let Ok(site_id) = site_spec::SiteID::from_str(ident) {
+        return registry
+            .get_standard_pull_connections()
+            .find(|(id, _)| **id == site_id)
+            .map(|(_, conn)| &conn.trust)
+            .ok_or_else(|| anyhow!("No pull connection with site ID '{}'", site_id));
+    }
+
+    let Ok(uuid) = uuid::Uuid::from_str(ident) else {
+        bail!(
+            "Provided connection identifier '{}' is neither valid as site ID nor as UUID",
+            ident
+        );
+    };
+    registry
+        .get_pull_connections()
+        .find(|conn| conn.uuid == uuid)
+        .ok_or_else(|| anyhow!("No pull connection with UUID '{}'", uuid))
+}
+
+fn tls_client_config(connection: &config::TrustedConnection) -> AnyhowResult<rustls::ClientConfig> {
+    let mut config = fips::tls_defaults(rustls::ClientConfig::builder())?
+        .with_root_certificates(certs::root_cert_store(
+            [connection.root_cert.as_str()].into_iter(),
+        )?)
+        .with_client_auth_cert(
+            vec![certs::rustls_certificate(&connection.certificate)?],
+            certs::rustls_private_key(&connection.private_key)?,
+        )?;
+    config.alpn_protocols = vec![ALPN_PULL_V1.to_vec()];
+    Ok(config)
+}
+
+/// The counterpart of tls_client_config in FIPS mode, see openssl_tls
+fn openssl_connector(connection: &config::TrustedConnection) -> AnyhowResult<SslConnector> {
+    let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+    builder.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+    let mut store = X509StoreBuilder::new()?;
+    store.add_cert(X509::from_pem(connection.root_cert.as_bytes())?)?;
+    builder.set_verify_cert_store(store.build())?;
+    builder.set_verify(SslVerifyMode::PEER);
+    let certificate = X509::from_pem(connection.certificate.as_bytes())?;
+    builder.set_certificate(&certificate)?;
+    let private_key = PKey::private_key_from_pem(connection.private_key.as_bytes())?;
+    builder.set_private_key(&private_key)?;
+    builder.set_alpn_protos(&openssl_tls::alpn_wire(ALPN_PULL_V1))?;
+    Ok(builder.build())
+}
+
+fn fetch_via_openssl(
+    connection: &config::TrustedConnection,
+    tcp_stream: TcpStream,
+) -> AnyhowResult<(Vec<u8>, PullProtocol)> {
+    let mut configuration = openssl_connector(connection)?.configure()?;
+    // The certificate names the connection, which is requested via SNI
+    configuration.set_verify_hostname(false);
+    let mut tls_stream = configuration
+        .connect(&connection.uuid.to_string(), tcp_stream)
+        .context("TLS handshake failed")?;
+    // The agent output is collected after the handshake and may take a while
+    tls_stream.get_ref().set_read_timeout(None)?;
+    let protocol = PullProtocol::negotiate(
+        tls_stream
+            .ssl()
+            .selected_alpn_protocol()
+            .map(|protocol| [protocol].into_iter()),
+    );
+
+    let mut encoded_mon_data = vec![];
+    tls_stream
+        .read_to_end(&mut encoded_mon_data)
+        .context("Failed to receive monitoring data")?;
+    Ok((encoded_mon_data, protocol))
+}
+
+fn fetch(
+    connection: &config::TrustedConnection,
+    address: &str,
+    port: u16,
+    connection_timeout: u64,
+) -> AnyhowResult<(Vec<u8>, PullProtocol)> {
+    let mut tcp_stream = TcpStream::connect((address, port))
+        .context(format!("Failed to connect to {address}:{port}"))?;
+    tcp_stream.set_read_timeout(Some(Duration::from_secs(connection_timeout)))?;
+
+    let mut id_buf: [u8; 2] = [0; 2];
+    tcp_stream
+        .read_exact(&mut id_buf)
+        .context("Failed to read the TLS announcement")?;
+    if id_buf != TLS_ID {
+        bail!(
+            "Expected TLS announcement {:?}, got {:?} - is legacy pull mode active?",
+            String::from_utf8_lossy(TLS_ID),
+            String::from_utf8_lossy(&id_buf)
+        );
+    }
+    if fips::enabled() {
+        return fetch_via_openssl(connection, tcp_stream);
+    }
+
+    let mut client_connection = rustls::ClientConnection::new(
+        Arc::new(tls_client_config(connection)?),
+        rustls::ServerName::try_from(connection.uuid.to_string().as_str())?,
+    )?;
+    while client_connection.is_handshaking() {
+        client_connection
+            .complete_io(&mut tcp_stream)
+            .context("TLS handshake failed")?;
+    }
+    // The agent output is collected concurrently to the handshake and may take a while
+    tcp_stream.set_read_timeout(None)?;
+    let protocol = PullProtocol::negotiate(
+        client_connection
+            .alpn_protocol()
+            .map(|protocol| [protocol].into_iter()),
+    );
+
+    let mut encoded_mon_data = vec![];
+    rustls::Stream::new(&mut client_connection, &mut tcp_stream)
+        .read_to_end(&mut encoded_mon_data)
+        .context("Failed to receive monitoring data")?;
+    Ok((encoded_mon_data, protocol))
+}
+
+fn decode(encoded_mon_data: &[u8], protocol: PullProtocol) -> AnyhowResult<Vec<u8>> {
+    let compressed = protocol.unframe(encoded_mon_data)?;
+    let mut mon_data = vec![];
+    flate2::read::ZlibDecoder::new(compressed)
+        .read_to_end(&mut mon_data)
+        .context("Failed to decompress monitoring data")?;
+    Ok(mon_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::test_helpers::TestRegistry;
+
+    const UUID_PULL: &str = "b3501e4d-2820-433c-8e9c-38c69ac20faa";
+    const UUID_PULL_IMP: &str = "00c21714-5086-46d7-848e-5be72c715cfd";
+    const UUID_PUSH: &str = "0096abd7-83c9-42f8-8b3a-3ffba7ba959d";
+
+    fn registry() -> TestRegistry {
+        TestRegistry::new()
+            .add_connection(
+                &config::ConnectionMode::Pull,
+                "server/pull-site",
+                config::TrustedConnectionWithRemote::from(UUID_PULL),
+            )
+            .add_connection(
+                &config::ConnectionMode::Push,
+                "server/push-site",
+                config::TrustedConnectionWithRemote::from(UUID_PUSH),
+            )
+            .add_imported_connection(UUID_PULL_IMP)
+    }
+
+    #[test]
+    fn test_find_pull_connection() {
+        let registry = registry();
+        for (ident, uuid) in [
+            ("server/pull-site", UUID_PULL),
+            (UUID_PULL, UUID_PULL),
+            (UUID_PULL_IMP, UUID_PULL_IMP),
+        ] {
+            assert_eq!(
+                find_pull_connection(&registry.registry, ident)
+                    .unwrap()
+                    .uuid
+                    .to_string(),
+                uuid
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_pull_connection_error() {
+        let registry = registry();
+        for ident in ["server/push-site", UUID_PUSH, "server/other-site", "abc"] {
+            assert!(find_pull_connection(&registry.registry, ident).is_err());
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut encoded = b"\x00\x00\x01".to_vec();
+        encoded.append(&mut crate::monitoring_data::compress(b"<<<check_mk>>>").unwrap());
+        assert_eq!(
+            decode(&encoded, PullProtocol::Legacy).unwrap(),
+            b"<<<check_mk>>>"
+        );
+        let mut framed = b"\x00\x01\x01".to_vec();
+        framed.extend_from_slice(&(encoded.len() as u64 - 3).to_be_bytes());
+        framed.extend_from_slice(&encoded[3..]);
+        assert_eq!(
+            decode(&framed, PullProtocol::V1).unwrap(),
+            b"<<<check_mk>>>"
+        );
+    }
+
+    #[test]
+    fn test_decode_error() {
+        assert!(decode(b"\x00\x01\x01abc", PullProtocol::Legacy).is_err());
+        assert!(decode(b"\x00\x00\x00abc", PullProtocol::Legacy).is_err());
+        assert!(decode(b"\x00\x00\x01abc", PullProtocol::Legacy).is_err());
+        assert!(decode(b"\x00\x00\x01abc", PullProtocol::V1).is_err());
+    }
+}
@@ -0,0 +1,1858 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::renew_certificate;
+use crate::{
+    agent_receiver_api::{self, AgentData, AgentDataChunk, AgentDataDelta, ChunkedUpload},
+    change_detection::ChangeDetection,
+    config,
+    connection_stats::{ConnectionStats, PushAttempt},
+    constants,
+    data_source::{self, DataSource},
+    delta, ipc,
+    lifecycle::Lifecycle,
+    misc, monitoring_data,
+    post_processing::Pipeline,
+    push_failover::PushFailover,
+    push_spool::PushSpool,
+    scheduler_state::SchedulerState,
+    site_spec,
+    status_section::StatusSection,
+    transport::{Transport, Transports},
+    types::AgentChannel,
+    usage_stats,
+};
+use anyhow::{Context, Result as AnyhowResult};
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, oneshot, Mutex as AsyncMutex, Semaphore};
+
+pub async fn push(
+    mut registry: config::Registry,
+    client_config: config::ClientConfig,
+    push_config: config::PushConfig,
+    agent_channel: AgentChannel,
+    connection_stats: ConnectionStats,
+    push_spool: PushSpool,
+    control: PushControl,
+) -> AnyhowResult<()> {
+    tokio::select! {
+        () = misc::sleep_randomly() => {}
+        () = control.lifecycle.stopping() => return Ok(()),
+    }
+    let push_now = Arc::clone(&control.push_now);
+    let mut push_now = push_now.lock().await;
+    let mut schedule = PushSchedule::default();
+    let status_section = push_config.status_section.then(|| {
+        StatusSection::new(
+            registry.clone(),
+            connection_stats.clone(),
+            push_spool.clone(),
+        )
+    });
+    let state = Arc::new(
+        PushState::new(&push_config, &client_config, connection_stats, push_spool)?
+            .with_scheduler(control.scheduler.clone())
+            .with_status_section(status_section),
+    );
+    loop {
+        if !wait_while_paused(&control.lifecycle, &mut push_now).await {
+            return Ok(());
+        }
+        registry.refresh()?;
+        if let Err(error) = state.push_spool.retain(
+            &registry
+                .get_push_connections()
+                .map(|(_, connection)| connection.trust.uuid)
+                .collect::<Vec<_>>(),
+        ) {
+            warn!("Error cleaning up push spool. ({})", error);
+        }
+        let begin = Instant::now();
+        let due_connections = schedule.due_connections(&registry, &push_config, begin);
+        control
+            .scheduler
+            .record_push_schedule(schedule.unix_timestamps(begin));
+        if !due_connections.is_empty() {
+            if let Err(error) =
+                push_to_connections(owned(due_connections), &agent_channel, &state, false).await
+            {
+                warn!("Error running push cycle. ({})", error);
+            };
+        }
+        let timeout = schedule
+            .next_push(begin)
+            .unwrap_or(Duration::from_secs(push_config.push_interval))
+            .saturating_sub(begin.elapsed());
+        let request = tokio::select! {
+            request = next_push_now(&mut push_now, timeout) => request,
+            () = control.lifecycle.interrupted() => None,
+        };
+        if let Some(request) = request {
+            registry.refresh()?;
+            let response = handle_push_now(
+                &registry,
+                request.connection.as_deref(),
+                &agent_channel,
+                &state,
+            )
+            .await;
+            if request.reply.send(response).is_err() {
+                warn!("Could not reply to push-now request");
+            }
+        }
+    }
+}
+
+/// How the daemon steers the push task besides its schedule. A push task restarted by the
+/// supervisor takes over the push-now requests of its predecessor.
+#[derive(Clone)]
+pub struct PushControl {
+    pub push_now: Arc<AsyncMutex<mpsc::Receiver<PushNowRequest>>>,
+    pub lifecycle: Lifecycle,
+    /// Where the schedule is reported for the status
+    pub scheduler: SchedulerState,
+}
+
+/// The next push-now request arriving within the given time
+async fn next_push_now(
+    push_now: &mut mpsc::Receiver<PushNowRequest>,
+    timeout: Duration,
+) -> Option<PushNowRequest> {
+    match tokio::time::timeout(timeout, push_now.recv()).await {
+        Ok(Some(request)) => Some(request),
+        Err(_) => None,
+        // The IPC task is not running
+        Ok(None) => {
+            tokio::time::sleep(timeout).await;
+            None
+        }
+    }
+}
+
+/// Wait while the service manager has paused pushing, push-now requests are rejected meanwhile.
+/// Returns false once the daemon is stopping.
+async fn wait_while_paused(
+    lifecycle: &Lifecycle,
+    push_now: &mut mpsc::Receiver<PushNowRequest>,
+) -> bool {
+    loop {
+        tokio::select! {
+            proceed = lifecycle.proceed() => return proceed,
+            Some(request) = push_now.recv() => {
+                let response = ipc::Response::Error {
+                    message: String::from("Pushing is paused"),
+                };
+                if request.reply.send(response).is_err() {
+                    warn!("Could not reply to push-now request");
+                }
+            }
+        }
+    }
+}
+
+/// Request for an immediate push, handed from the IPC task to the push task
+pub struct PushNowRequest {
+    connection: Option<String>,
+    reply: oneshot::Sender<ipc::Response>,
+}
+
+/// Forward a push-now request to the push task and wait for the outcome
+pub async fn request_push_now(
+    connection: Option<String>,
+    push_now: &mpsc::Sender<PushNowRequest>,
+) -> ipc::Response {
+    let (reply, response) = oneshot::channel();
+    if push_now
+        .send(PushNowRequest { connection, reply })
+        .await
+        .is_err()
+    {
+        return ipc::Response::Error {
+            message: String::from("Push task is not running"),
+        };
+    }
+    response.await.unwrap_or_else(|_| ipc::Response::Error {
+        message: String::from("Push task did not reply"),
+    })
+}
+
+async fn handle_push_now(
+    registry: &config::Registry,
+    connection: Option<&str>,
+    agent_channel: &AgentChannel,
+    state: &Arc<PushState>,
+) -> ipc::Response {
+    let site_id = match connection
+        .map(|ident| renew_certificate::site_id_from_ident(registry, ident))
+        .transpose()
+    {
+        Ok(site_id) => site_id,
+        Err(error) => {
+            return ipc::Response::Error {
+                message: error.to_string(),
+            }
+        }
+    };
+    let now = misc::unix_now();
+    let connections: Vec<_> = registry
+        .get_active_push_connections(now)
+        .filter(|(id, _)| match &site_id {
+            Some(site_id) => site_id == *id,
+            None => true,
+        })
+        .collect();
+    if connections.is_empty() {
+        let expired = site_id.as_ref().is_some_and(|site_id| {
+            registry
+                .get_push_connections()
+                .any(|(id, connection)| id == site_id && connection.is_expired(now))
+        });
+        return ipc::Response::Error {
+            message: match site_id {
+                Some(site_id) if expired => format!("Push connection {site_id} has expired"),
+                Some(site_id) => format!("{site_id} is not a push connection"),
+                None => String::from("No active push connections registered"),
+            },
+        };
+    }
+    info!("Pushing to {} connection(s) on request", connections.len());
+    match push_to_connections(owned(connections), agent_channel, state, true).await {
+        Ok(results) => ipc::Response::PushNow { results },
+        Err(error) => ipc::Response::Error {
+            message: misc::anyhow_error_to_human_readable(&error),
+        },
+    }
+}
+
+/// The pushes run in tasks of their own, which cannot borrow from the registry
+fn owned<'reg>(
+    connections: impl IntoIterator<
+        Item = (
+            &'reg site_spec::SiteID,
+            &'reg config::TrustedConnectionWithRemote,
+        ),
+    >,
+) -> Vec<(site_spec::SiteID, config::TrustedConnectionWithRemote)> {
+    connections
+        .into_iter()
+        .map(|(site_id, connection)| (site_id.clone(), connection.clone()))
+        .collect()
+}
+
+/// Keeps track of when each push connection is due next, st. every connection is pushed to
+/// according to its own push interval.
+#[derive(Default)]
+struct PushSchedule {
+    next_push: HashMap<uuid::Uuid, Instant>,
+}
+
+impl PushSchedule {
+    fn due_connections<'reg>(
+        &mut self,
+        registry: &'reg config::Registry,
+        push_config: &config::PushConfig,
+        now: Instant,
+    ) -> Vec<(
+        &'reg site_spec::SiteID,
+        &'reg config::TrustedConnectionWithRemote,
+    )> {
+        let connections: Vec<_> = registry
+            .get_active_push_connections(misc::unix_now())
+            .collect();
+        self.next_push.retain(|uuid, _| {
+            connections
+                .iter()
+                .any(|(_, connection)| &connection.trust.uuid == uuid)
+        });
+        let since_epoch = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut rng = rand::thread_rng();
+        connections
+            .into_iter()
+            .filter(|(_, connection)| {
+                let uuid = connection.trust.uuid;
+                let is_due = match self.next_push.get(&uuid) {
+                    Some(next) => *next <= now,
+                    // New connections are pushed to right away, unless pushes are aligned
+                    None => !push_config.align_push,
+                };
+                if is_due || !self.next_push.contains_key(&uuid) {
+                    self.next_push.insert(
+                        uuid,
+                        now + push_config.next_push_delay(connection, since_epoch, &mut rng),
+                    );
+                }
+                is_due
+            })
+            .collect()
+    }
+
+    /// Time from `now` until the next connection is due
+    fn next_push(&self, now: Instant) -> Option<Duration> {
+        self.next_push
+            .values()
+            .min()
+            .map(|next| next.saturating_duration_since(now))
+    }
+
+    /// Unix timestamps at which the connections are due next
+    fn unix_timestamps(&self, now: Instant) -> HashMap<uuid::Uuid, u64> {
+        let unix_now = misc::unix_now();
+        self.next_push
+            .iter()
+            .map(|(uuid, next)| {
+                (
+                    *uuid,
+                    unix_now + next.saturating_duration_since(now).as_secs(),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Agent output of a single push cycle, compressed lazily and at most once per algorithm
+struct PushPayload {
+    collected_at: u64,
+    mon_data: Vec<u8>,
+    digest: Option<u64>,
+    /// Push even if the agent output did not change
+    forced: bool,
+    zlib: OnceLock<Vec<u8>>,
+    zstd: OnceLock<Vec<u8>>,
+    signature: OnceLock<Arc<delta::Signature>>,
+}
+
+impl PushPayload {
+    fn new(collected_at: u64, mon_data: Vec<u8>, change_detection: &ChangeDetection) -> Self {
+        Self {
+            collected_at,
+            digest: change_detection.digest(&mon_data),
+            forced: false,
+            mon_data,
+            zlib: OnceLock::new(),
+            zstd: OnceLock::new(),
+            signature: OnceLock::new(),
+        }
+    }
+
+    fn signature(&self) -> Arc<delta::Signature> {
+        Arc::clone(self.signature.get_or_init(|| {
+            Arc::new(delta::Signature::new(
+                &self.mon_data,
+                constants::DELTA_BLOCK_SIZE,
+            ))
+        }))
+    }
+
+    fn compressed(&self, compression: monitoring_data::PushCompression) -> AnyhowResult<&[u8]> {
+        let cell = match compression {
+            monitoring_data::PushCompression::Zlib => &self.zlib,
+            monitoring_data::PushCompression::Zstd => &self.zstd,
+        };
+        if let Some(compressed) = cell.get() {
+            return Ok(compressed);
+        }
+        let compressed = compression.compress(&self.mon_data).context(format!(
+            "Error compressing agent output with {compression:?}"
+        ))?;
+        Ok(cell.get_or_init(|| compressed))
+    }
+}
+
+/// Keeps track of the receivers which rejected the preferred compression. These are sent zlib
+/// compressed data instead, which every receiver understands.
+struct CompressionNegotiation {
+    preferred: monitoring_data::PushCompression,
+    rejected: Mutex<HashSet<uuid::Uuid>>,
+}
+
+impl CompressionNegotiation {
+    fn new(preferred: monitoring_data::PushCompression) -> Self {
+        Self {
+            preferred,
+            rejected: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn rejected(&self) -> std::sync::MutexGuard<'_, HashSet<uuid::Uuid>> {
+        match self.rejected.lock() {
+            Ok(rejected) => rejected,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn compression_for(&self, uuid: &uuid::Uuid) -> monitoring_data::PushCompression {
+        if self.rejected().contains(uuid) {
+            monitoring_data::PushCompression::Zlib
+        } else {
+            self.preferred
+        }
+    }
+
+    fn reject(&self, uuid: &uuid::Uuid) {
+        self.rejected().insert(*uuid);
+    }
+}
+
+/// Keeps the signature of the agent output each receiver got last, st. the next push only needs
+/// to upload the changed blocks. Receivers which do not know delta uploads get full pushes.
+struct DeltaNegotiation {
+    enabled: bool,
+    bases: Mutex<HashMap<uuid::Uuid, Arc<delta::Signature>>>,
+    unsupported: Mutex<HashSet<uuid::Uuid>>,
+}
+
+impl DeltaNegotiation {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            bases: Mutex::new(HashMap::new()),
+            unsupported: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn bases(&self) -> std::sync::MutexGuard<'_, HashMap<uuid::Uuid, Arc<delta::Signature>>> {
+        match self.bases.lock() {
+            Ok(bases) => bases,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn unsupported(&self) -> std::sync::MutexGuard<'_, HashSet<uuid::Uuid>> {
+        match self.unsupported.lock() {
+            Ok(unsupported) => unsupported,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn base_for(&self, uuid: &uuid::Uuid) -> Option<Arc<delta::Signature>> {
+        self.bases().get(uuid).cloned()
+    }
+
+    /// Remember the agent output the receiver holds now
+    fn record_push(&self, uuid: &uuid::Uuid, payload: &PushPayload) {
+        if self.enabled && !self.unsupported().contains(uuid) {
+            self.bases().insert(*uuid, payload.signature());
+        }
+    }
+
+    /// The receiver holds an agent output unknown to us, e.g. a replayed one
+    fn forget(&self, uuid: &uuid::Uuid) {
+        self.bases().remove(uuid);
+    }
+
+    fn reject(&self, uuid: &uuid::Uuid) {
+        self.unsupported().insert(*uuid);
+        self.forget(uuid);
+    }
+}
+
+/// Agent outputs larger than the chunk size are uploaded in chunks to receivers supporting it, st.
+/// an interrupted upload can be resumed instead of started over
+struct ChunkedNegotiation {
+    chunk_size: Option<usize>,
+    unsupported: Mutex<HashSet<uuid::Uuid>>,
+}
+
+impl ChunkedNegotiation {
+    fn new(chunk_size: Option<usize>) -> Self {
+        Self {
+            chunk_size,
+            unsupported: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn unsupported(&self) -> std::sync::MutexGuard<'_, HashSet<uuid::Uuid>> {
+        match self.unsupported.lock() {
+            Ok(unsupported) => unsupported,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn chunk_size_for(&self, uuid: &uuid::Uuid, length: usize) -> Option<usize> {
+        self.chunk_size
+            .filter(|chunk_size| length > *chunk_size && !self.unsupported().contains(uuid))
+    }
+
+    fn reject(&self, uuid: &uuid::Uuid) {
+        self.unsupported().insert(*uuid);
+    }
+}
+
+/// Everything the push cycles of a process share
+struct PushState {
+    api: Arc<Transports>,
+    connection_stats: ConnectionStats,
+    push_spool: PushSpool,
+    compression: CompressionNegotiation,
+    delta: DeltaNegotiation,
+    chunked: ChunkedNegotiation,
+    /// Sign chunked uploads as a whole, the API signs the other pushes
+    sign_push: bool,
+    change_detection: ChangeDetection,
+    failover: PushFailover,
+    post_processing: Pipeline,
+    fallback_command: Option<config::FallbackCommandConfig>,
+    /// Bounds the number of pushes running at the same time
+    push_slots: Arc<Semaphore>,
+    scheduler: SchedulerState,
+}
+
+impl PushState {
+    fn new(
+        push_config: &config::PushConfig,
+        client_config: &config::ClientConfig,
+        connection_stats: ConnectionStats,
+        push_spool: PushSpool,
+    ) -> AnyhowResult<Self> {
+        let post_processing =
+            Pipeline::new(&push_config.section_filter, &push_config.post_processors)?
+                .with_payload_accounting(&push_config.payload_size, connection_stats.payload());
+        Ok(Self {
+            api: Arc::new(Transports::new(
+                || {
+                    agent_receiver_api::Api::new(client_config)
+                        .with_clock_skew_observer({
+                            let connection_stats = connection_stats.clone();
+                            move |uuid, skew| connection_stats.record_clock_skew(uuid, skew)
+                        })
+                        .with_payload_signing(push_config.sign_push)
+                },
+                push_config.quic,
+            )),
+            connection_stats,
+            push_spool,
+            compression: CompressionNegotiation::new(push_config.push_compression),
+            delta: DeltaNegotiation::new(push_config.delta_push),
+            chunked: ChunkedNegotiation::new(push_config.push_chunk_size),
+            sign_push: push_config.sign_push,
+            change_detection: ChangeDetection::new(push_config),
+            failover: PushFailover::new(push_config),
+            post_processing,
+            fallback_command: push_config.fallback_command.clone(),
+            push_slots: Arc::new(Semaphore::new(push_config.max_outbound_requests)),
+            scheduler: SchedulerState::default(),
+        })
+    }
+
+    fn with_scheduler(self, scheduler: SchedulerState) -> Self {
+        Self { scheduler, ..self }
+    }
+
+    fn with_status_section(self, status_section: Option<StatusSection>) -> Self {
+        Self {
+            post_processing: self.post_processing.with_status_section(status_section),
+            ..self
+        }
+    }
+}
+
+enum PushOutcome {
+    Pushed(usize),
+    Unchanged,
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn handle_push_cycle(
+    registry: &config::Registry,
+    client_config: &config::ClientConfig,
+    push_config: &config::PushConfig,
+    agent_channel: &AgentChannel,
+    connection_stats: ConnectionStats,
+    push_spool: PushSpool,
+) -> AnyhowResult<()> {
+    if registry.is_push_empty() {
+        return Ok(());
+    }
+    push_to_connections(
+        owned(registry.get_active_push_connections(misc::unix_now())),
+        agent_channel,
+        &Arc::new(PushState::new(
+            push_config,
+            client_config,
+            connection_stats,
+            push_spool,
+        )?),
+        false,
+    )
+    .await?;
+    Ok(())
+}
+
+/// The agent output of every data source is collected once, for all connections it is pushed to.
+/// Failing to collect from a data source only affects its connections, unless it fails for all.
+async fn push_to_connections(
+    connections: Vec<(site_spec::SiteID, config::TrustedConnectionWithRemote)>,
+    agent_channel: &AgentChannel,
+    state: &Arc<PushState>,
+    forced: bool,
+) -> AnyhowResult<Vec<ipc::PushResult>> {
+    debug!("Handling registered push connections.");
+
+    let mut by_data_source: HashMap<Option<DataSource>, Vec<_>> = HashMap::new();
+    for (site_id, connection) in connections {
+        by_data_source
+            .entry(connection.trust.data_source.clone())
+            .or_default()
+            .push((site_id, connection));
+    }
+    let mut results = vec![];
+    let mut failures = vec![];
+    let sources = by_data_source.len();
+    for (data_source, connections) in by_data_source {
+        let payload =
+            match collect_payload(data_source, agent_channel, state, forced).await {
+                Ok(payload) => payload,
+                Err(error) => {
+                    results.extend(connections.iter().map(|(site_id, connection)| {
+                        ipc::PushResult {
+                            site_id: site_id.to_string(),
+                            uuid: connection.trust.uuid.to_string(),
+                            error: Some(format!("{error:#}")),
+                        }
+                    }));
+                    failures.push(error);
+                    continue;
+                }
+            };
+        results.extend(
+            push_concurrently(
+                Arc::clone(&state.api),
+                connections,
+                Arc::new(payload),
+                Arc::clone(state),
+            )
+            .await,
+        );
+    }
+    if failures.len() == sources {
+        if let Some(error) = failures.pop() {
+            return Err(error);
+        }
+    }
+    for error in failures {
+        warn!("{:#}", error);
+    }
+    Ok(results)
+}
+
+async fn collect_payload(
+    data_source: Option<DataSource>,
+    agent_channel: &AgentChannel,
+    state: &Arc<PushState>,
+    forced: bool,
+) -> AnyhowResult<PushPayload> {
+    let collected_at = misc::unix_now();
+    let agent_channel = agent_channel.clone();
+    let processing_state = Arc::clone(state);
+    let mon_data = tokio::task::spawn_blocking(move || {
+        processing_state.post_processing.process(
+            data_source::collect(
+                data_source.as_ref(),
+                &agent_channel,
+                processing_state.fallback_command.as_ref(),
+            )
+            .context(match &data_source {
+                Some(data_source) => {
+                    format!("Error collecting agent output from {data_source}")
+                }
+                None => String::from("Error collecting agent output"),
+            })?,
+        )
+    })
+    .await??;
+    let mut payload = PushPayload::new(collected_at, mon_data, &state.change_detection);
+    payload.forced = forced;
+    Ok(payload)
+}
+
+/// Push to every connection in a task of its own, st. a slow or unreachable site does not delay
+/// the others. Failures only affect the respective connection. The receiver API is blocking, so
+/// the pushes run on the blocking thread pool, bounded by the push slots of the state.
+async fn push_concurrently(
+    api: Arc<impl Transport + 'static>,
+    connections: Vec<(site_spec::SiteID, config::TrustedConnectionWithRemote)>,
+    payload: Arc<PushPayload>,
+    state: Arc<PushState>,
+) -> Vec<ipc::PushResult> {
+    let handles: Vec<_> = connections
+        .into_iter()
+        .map(|(site_id, connection)| {
+            let result = ipc::PushResult {
+                site_id: site_id.to_string(),
+                uuid: connection.trust.uuid.to_string(),
+                error: None,
+            };
+            let (api, payload, state) =
+                (Arc::clone(&api), Arc::clone(&payload), Arc::clone(&state));
+            let handle = tokio::spawn(async move {
+                let Ok(permit) = Arc::clone(&state.push_slots).acquire_owned().await else {
+                    return Some(String::from("Push slots are closed"));
+                };
+                let push = tokio::task::spawn_blocking(move || {
+                    let _permit = permit;
+                    push_and_record(api.as_ref(), &site_id, &connection, &payload, &state)
+                });
+                // A push which does not finish in time keeps its slot until it does, st. hanging
+                // pushes cannot pile up
+                match tokio::time::timeout(Duration::from_secs(constants::PUSH_TASK_TIMEOUT), push)
+                    .await
+                {
+                    Ok(Ok(error)) => error,
+                    Ok(Err(_)) => Some(String::from("Push task panicked")),
+                    Err(_) => Some(format!(
+                        "Push did not finish within {}s",
+                        constants::PUSH_TASK_TIMEOUT
+                    )),
+                }
+            });
+            (result, handle)
+        })
+        .collect();
+    let mut results = vec![];
+    for (mut result, handle) in handles {
+        result.error = handle
+            .await
+            .unwrap_or_else(|_| Some(String::from("Push task panicked")));
+        results.push(result);
+    }
+    results
+}
+
+/// Push to a single connection and record the outcome. Returns the error, if any.
+fn push_and_record(
+    api: &impl Transport,
+    site_id: &site_spec::SiteID,
+    connection: &config::TrustedConnectionWithRemote,
+    payload: &PushPayload,
+    state: &PushState,
+) -> Option<String> {
+    let uuid = &connection.trust.uuid;
+    let timestamp = misc::unix_now();
+    let start = Instant::now();
+    let result = push_to_connection(api, site_id, connection, payload, state);
+    let duration = start.elapsed();
+    state.scheduler.record_push_outcome(uuid, result.is_ok());
+    match result {
+        Ok(PushOutcome::Pushed(bytes)) => state
+            .connection_stats
+            .record_push(uuid, PushAttempt::pushed(timestamp, duration, bytes)),
+        Ok(PushOutcome::Unchanged) => state
+            .connection_stats
+            .record_push(uuid, PushAttempt::unchanged(timestamp, duration)),
+        Err(error) => {
+            usage_stats::record_transport_error(&error);
+            let http_status =
+                agent_receiver_api::response_status(&error).map(|status| status.as_u16());
+            let error = misc::anyhow_error_to_human_readable(&error);
+            warn!(
+                "{}: Error pushing agent output. ({})",
+                site_id,
+                error.replace('\n', ": ")
+            );
+            state.connection_stats.record_push(
+                uuid,
+                PushAttempt::failed(timestamp, duration, http_status, error.clone()),
+            );
+            if let Err(error) = payload
+                .compressed(monitoring_data::PushCompression::Zlib)
+                .and_then(|compressed_mon_data| {
+                    state
+                        .push_spool
+                        .spool(uuid, payload.collected_at, compressed_mon_data)
+                })
+            {
+                warn!("{}: Error spooling agent output. ({})", site_id, error);
+            }
+            return Some(error);
+        }
+    }
+    None
+}
+
+/// Push the agent output to a single connection. If the output did not change since the last
+/// push, only check that the receiver is reachable.
+fn push_to_connection(
+    api: &impl Transport,
+    site_id: &site_spec::SiteID,
+    connection: &config::TrustedConnectionWithRemote,
+    payload: &PushPayload,
+    state: &PushState,
+) -> AnyhowResult<PushOutcome> {
+    let site_url = state
+        .failover
+        .select(
+            site_id,
+            connection,
+            |url| api.registration_status_v2(url, &connection.trust).is_ok(),
+            Instant::now(),
+        )
+        .context("Failed to construct URL for pushing data")?;
+    // Spooled agent outputs go first, st. the receiver ends up with the most recent one
+    replay_spooled(api, &site_url, connection, state)?;
+
+    let uuid = &connection.trust.uuid;
+    let now = Instant::now();
+    if !payload.forced
+        && state
+            .change_detection
+            .is_unchanged(uuid, payload.digest, now)
+    {
+        debug!("{}: Agent output unchanged, sending heartbeat", site_id);
+        api.registration_status_v2(&site_url, &connection.trust)
+            .context("Heartbeat failed")?;
+        return Ok(PushOutcome::Unchanged);
+    }
+
+    if let Some(bytes) = push_delta(api, &site_url, connection, payload, state)? {
+        state
+            .change_detection
+            .record_push(uuid, payload.digest, now);
+        state.delta.record_push(uuid, payload);
+        return Ok(PushOutcome::Pushed(bytes));
+    }
+
+    info!("{}: Pushing agent output", site_id);
+    let push_compressed = |compression: monitoring_data::PushCompression| {
+        let compressed_mon_data = payload.compressed(compression)?;
+        push_data(
+            api,
+            &site_url,
+            connection,
+            compression.header(),
+            compressed_mon_data,
+            payload.collected_at,
+            state,
+        )
+        .map(|_| compressed_mon_data.len())
+    };
+    let bytes = match push_compressed(state.compression.compression_for(uuid)) {
+        Err(error) if error.is::<agent_receiver_api::UnsupportedCompression>() => {
+            info!(
+                "{}: Receiver does not support {:?} compression, falling back to zlib",
+                site_id,
+                state.compression.compression_for(uuid)
+            );
+            state.compression.reject(uuid);
+            push_compressed(monitoring_data::PushCompression::Zlib)
+        }
+        result => result,
+    }?;
+    state
+        .change_detection
+        .record_push(uuid, payload.digest, now);
+    state.delta.record_push(uuid, payload);
+    Ok(PushOutcome::Pushed(bytes))
+}
+
+/// Push only the changes since the last push, if the receiver holds the previous agent output.
+/// Returns the number of bytes uploaded, nothing if the full agent output has to be pushed.
+fn push_delta(
+    api: &impl AgentDataDelta,
+    site_url: &reqwest::Url,
+    connection: &config::TrustedConnectionWithRemote,
+    payload: &PushPayload,
+    state: &PushState,
+) -> AnyhowResult<Option<usize>> {
+    let uuid = &connection.trust.uuid;
+    let Some(base) = state.delta.base_for(uuid) else {
+        return Ok(None);
+    };
+    let encoded = delta::encode(&base, &payload.mon_data);
+    if encoded.len() >= payload.mon_data.len() {
+        return Ok(None);
+    }
+    let compression = state.compression.compression_for(uuid);
+    let compressed_delta = compression
+        .compress(&encoded)
+        .context(format!("Error compressing delta with {compression:?}"))?;
+    info!(
+        "{}: Pushing delta of agent output ({} of {} bytes)",
+        site_url,
+        encoded.len(),
+        payload.mon_data.len()
+    );
+    match api.agent_data_delta(
+        site_url,
+        &connection.trust,
+        compression.header(),
+        &compressed_delta,
+        payload.collected_at,
+    ) {
+        Ok(()) => Ok(Some(compressed_delta.len())),
+        // The full push takes care of falling back to zlib
+        Err(error) if error.is::<agent_receiver_api::UnsupportedCompression>() => Ok(None),
+        Err(error) => match agent_receiver_api::response_status(&error) {
+            Some(reqwest::StatusCode::NOT_FOUND) => {
+                info!(
+                    "{}: Receiver does not support delta uploads, pushing full agent output",
+                    site_url
+                );
+                state.delta.reject(uuid);
+                Ok(None)
+            }
+            Some(reqwest::StatusCode::CONFLICT) => {
+                debug!(
+                    "{}: Receiver does not hold the previous agent output, pushing full agent output",
+                    site_url
+                );
+                state.delta.forget(uuid);
+                Ok(None)
+            }
+            _ => Err(error),
+        },
+    }
+}
+
+/// Push a compressed agent output, in chunks if it is large and the receiver supports it
+fn push_data(
+    api: &(impl AgentData + AgentDataChunk),
+    site_url: &reqwest::Url,
+    connection: &config::TrustedConnectionWithRemote,
+    compression_algorithm: &str,
+    compressed_mon_data: &[u8],
+    collected_at: u64,
+    state: &PushState,
+) -> AnyhowResult<()> {
+    let uuid = &connection.trust.uuid;
+    if let Some(chunk_size) = state
+        .chunked
+        .chunk_size_for(uuid, compressed_mon_data.len())
+    {
+        let upload = ChunkedUpload {
+            id: upload_id(compressed_mon_data, collected_at),
+            compression_algorithm,
+            total_length: compressed_mon_data.len() as u64,
+            collected_at,
+            signature: state
+                .sign_push
+                .then(|| {
+                    agent_receiver_api::payload_signature(
+                        &connection.trust,
+                        compression_algorithm,
+                        collected_at,
+                        compressed_mon_data,
+                    )
+                })
+                .transpose()?,
+        };
+        match push_chunked(
+            api,
+            site_url,
+            &connection.trust,
+            &upload,
+            compressed_mon_data,
+            chunk_size,
+        ) {
+            Err(error)
+                if agent_receiver_api::response_status(&error)
+                    == Some(reqwest::StatusCode::NOT_FOUND) =>
+            {
+                info!(
+                    "{}: Receiver does not support chunked uploads, pushing agent output at once",
+                    site_url
+                );
+                state.chunked.reject(uuid);
+            }
+            result => return result,
+        }
+    }
+    api.agent_data(
+        site_url,
+        &connection.trust,
+        compression_algorithm,
+        compressed_mon_data,
+        collected_at,
+    )
+}
+
+/// Identical for every attempt to upload the same data, eg. a spooled agent output
+fn upload_id(data: &[u8], collected_at: u64) -> String {
+    let digest = openssl::sha::sha256(data);
+    format!(
+        "{collected_at}-{}",
+        digest[..8]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>()
+    )
+}
+
+/// Upload the chunks from the offset the receiver acknowledged last. Chunks interrupted by
+/// transport errors or not accepted by the receiver are sent again a few times.
+fn push_chunked(
+    api: &impl AgentDataChunk,
+    site_url: &reqwest::Url,
+    connection: &config::TrustedConnection,
+    upload: &ChunkedUpload,
+    data: &[u8],
+    chunk_size: usize,
+) -> AnyhowResult<()> {
+    let mut offset = 0;
+    let mut retries = 0;
+    while offset < data.len() {
+        let chunk = &data[offset..data.len().min(offset + chunk_size)];
+        let acknowledged =
+            match api.agent_data_chunk(site_url, connection, upload, offset as u64, chunk) {
+                Ok(acknowledged) => usize::try_from(acknowledged)?,
+                Err(error)
+                    if agent_receiver_api::response_status(&error).is_none()
+                        && retries < constants::PUSH_CHUNK_RETRIES =>
+                {
+                    retries += 1;
+                    debug!(
+                        "{}: Chunk at offset {} of upload {} failed, retrying. ({})",
+                        site_url, offset, upload.id, error
+                    );
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+        if acknowledged > data.len() {
+            anyhow::bail!(
+                "Agent receiver acknowledged {} bytes of an upload of {} bytes",
+                acknowledged,
+                data.len()
+            );
+        }
+        if acknowledged <= offset {
+            if retries >= constants::PUSH_CHUNK_RETRIES {
+                anyhow::bail!(
+                    "Agent receiver did not accept the chunk at offset {} of the upload",
+                    offset
+                );
+            }
+            retries += 1;
+        } else {
+            retries = 0;
+        }
+        offset = acknowledged;
+    }
+    Ok(())
+}
+
+/// Push the spooled agent outputs of a connection, oldest first. Stops at the first failure, the
+/// remaining outputs stay in the spool.
+fn replay_spooled(
+    api: &(impl AgentData + AgentDataChunk),
+    site_url: &reqwest::Url,
+    connection: &config::TrustedConnectionWithRemote,
+    state: &PushState,
+) -> AnyhowResult<()> {
+    for spooled in state.push_spool.entries(&connection.trust.uuid)? {
+        let compressed_mon_data = match spooled.read() {
+            Ok(data) => data,
+            Err(error) => {
+                warn!(
+                    "{}: Discarding unreadable spooled agent output. ({})",
+                    site_url, error
+                );
+                spooled.remove()?;
+                continue;
+            }
+        };
+        let timestamp = misc::unix_now();
+        let start = Instant::now();
+        push_data(
+            api,
+            site_url,
+            connection,
+            &monitoring_data::compression_header_info().push,
+            &compressed_mon_data,
+            spooled.collected_at,
+            state,
+        )
+        .context("Failed to replay spooled agent output")?;
+        info!(
+            "{}: Replayed agent output collected at {}",
+            site_url, spooled.collected_at
+        );
+        state.connection_stats.record_push(
+            &connection.trust.uuid,
+            PushAttempt::pushed(timestamp, start.elapsed(), compressed_mon_data.len()),
+        );
+        state.delta.forget(&connection.trust.uuid);
+        spooled.remove()?;
+    }
+    Ok(())
+}
+
+/// Replay the spooled agent outputs of the given connections right away, eg. after a long outage
+#[tokio::main(flavor = "current_thread")]
+pub async fn replay_spools(
+    connections: Vec<(site_spec::SiteID, config::TrustedConnectionWithRemote)>,
+    client_config: &config::ClientConfig,
+    push_config: &config::PushConfig,
+    connection_stats: ConnectionStats,
+    push_spool: PushSpool,
+) -> AnyhowResult<Vec<ipc::PushResult>> {
+    let state = PushState::new(push_config, client_config, connection_stats, push_spool)?;
+    // The receiver API is blocking
+    Ok(tokio::task::spawn_blocking(move || {
+        connections
+            .into_iter()
+            .map(|(site_id, connection)| ipc::PushResult {
+                site_id: site_id.to_string(),
+                uuid: connection.trust.uuid.to_string(),
+                error: site_spec::make_site_url(&site_id, &connection.receiver_port)
+                    .and_then(|site_url| {
+                        replay_spooled(state.api.as_ref(), &site_url, &connection, &state)
+                    })
+                    .err()
+                    .map(|error| misc::anyhow_error_to_human_readable(&error)),
+            })
+            .collect()
+    })
+    .await?)
+}
+
+/// Override the global push interval for a single connection, or reset it if no interval is given.
+pub fn set_push_interval(
+    registry: &mut config::Registry,
+    ident: &str,
+    push_interval: Option<u64>,
+) -> AnyhowResult<()> {
+    let (connection, site_id) = renew_certificate::find_site_for_ident(registry, ident)?;
+    connection.push_interval = push_interval;
+    match push_interval {
+        Some(seconds) => println!("Push interval for '{site_id}' set to {seconds}s"),
+        None => println!("Push interval for '{site_id}' reset to global push interval"),
+    }
+    registry.save()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_receiver_api::RegistrationStatusV2;
+    use crate::{happy_eyeballs, proxy};
+    use config::test_helpers::TestRegistry;
+    use std::str::FromStr;
+
+    const UUID_FAST: &str = "0096abd7-83c9-42f8-8b3a-3ffba7ba959d";
+    const UUID_SLOW: &str = "b3501e4d-2820-433c-8e9c-38c69ac20faa";
+
+    fn registry() -> TestRegistry {
+        let mut fast_connection = config::TrustedConnectionWithRemote::from(UUID_FAST);
+        fast_connection.push_interval = Some(30);
+        TestRegistry::new()
+            .add_connection(
+                &config::ConnectionMode::Push,
+                "server/fast-site",
+                fast_connection,
+            )
+            .add_connection(
+                &config::ConnectionMode::Push,
+                "server/slow-site",
+                config::TrustedConnectionWithRemote::from(UUID_SLOW),
+            )
+            .add_connection(
+                &config::ConnectionMode::Pull,
+                "server/pull-site",
+                config::TrustedConnectionWithRemote::from("b3501e4d-2820-433c-8e9c-38c69ac20fab"),
+            )
+    }
+
+    fn due_sites(
+        schedule: &mut PushSchedule,
+        registry: &config::Registry,
+        now: Instant,
+    ) -> Vec<String> {
+        let mut sites: Vec<String> = schedule
+            .due_connections(
+                registry,
+                &config::PushConfig {
+                    push_interval: 300,
+                    push_jitter: 0,
+                    align_push: false,
+                    push_spool_size: 0,
+                    push_spool_max_size: None,
+                    max_outbound_requests: 8,
+                    push_compression: monitoring_data::PushCompression::Zlib,
+                    conditional_push: false,
+                    conditional_push_ignored_sections: vec![],
+                    conditional_push_max_age: 600,
+                    delta_push: false,
+                    push_chunk_size: None,
+                    sign_push: false,
+                    quic: false,
+                    push_failover: HashMap::new(),
+                    section_filter: config::SectionFilterConfig::default(),
+                    post_processors: vec![],
+                    fallback_command: None,
+                    payload_size: config::PayloadSizeConfig::default(),
+                    realtime: config::RealtimeConfig::default(),
+                    reregistration: config::ReregistrationConfig::default(),
+                    hardware_labels: false,
+                    status_section: false,
+                },
+                now,
+            )
+            .into_iter()
+            .map(|(site_id, _)| site_id.to_string())
+            .collect();
+        sites.sort();
+        sites
+    }
+
+    #[test]
+    fn test_push_schedule() {
+        let registry = registry();
+        let mut schedule = PushSchedule::default();
+        let start = Instant::now();
+
+        assert_eq!(
+            due_sites(&mut schedule, &registry.registry, start),
+            vec!["server/fast-site", "server/slow-site"]
+        );
+        assert_eq!(schedule.next_push(start), Some(Duration::from_secs(30)));
+        let unix_now = misc::unix_now();
+        let unix_timestamps = schedule.unix_timestamps(start);
+        assert!(unix_timestamps[&uuid::Uuid::parse_str(UUID_FAST).unwrap()] >= unix_now + 30);
+        assert!(due_sites(
+            &mut schedule,
+            &registry.registry,
+            start + Duration::from_secs(10)
+        )
+        .is_empty());
+        assert_eq!(
+            due_sites(
+                &mut schedule,
+                &registry.registry,
+                start + Duration::from_secs(30)
+            ),
+            vec!["server/fast-site"]
+        );
+        assert_eq!(
+            due_sites(
+                &mut schedule,
+                &registry.registry,
+                start + Duration::from_secs(300)
+            ),
+            vec!["server/fast-site", "server/slow-site"]
+        );
+    }
+
+    #[test]
+    fn test_push_schedule_aligned() {
+        let registry = registry();
+        let mut schedule = PushSchedule::default();
+        let start = Instant::now();
+        assert!(schedule
+            .due_connections(
+                &registry.registry,
+                &config::PushConfig {
+                    push_interval: 300,
+                    push_jitter: 0,
+                    align_push: true,
+                    push_spool_size: 0,
+                    push_spool_max_size: None,
+                    max_outbound_requests: 8,
+                    push_compression: monitoring_data::PushCompression::Zlib,
+                    conditional_push: false,
+                    conditional_push_ignored_sections: vec![],
+                    conditional_push_max_age: 600,
+                    delta_push: false,
+                    push_chunk_size: None,
+                    sign_push: false,
+                    quic: false,
+                    push_failover: HashMap::new(),
+                    section_filter: config::SectionFilterConfig::default(),
+                    post_processors: vec![],
+                    fallback_command: None,
+                    payload_size: config::PayloadSizeConfig::default(),
+                    realtime: config::RealtimeConfig::default(),
+                    reregistration: config::ReregistrationConfig::default(),
+                    hardware_labels: false,
+                    status_section: false,
+                },
+                start,
+            )
+            .is_empty());
+        assert!(schedule.next_push(start).unwrap() <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_push_schedule_forgets_deleted_connections() {
+        let mut registry = registry();
+        let mut schedule = PushSchedule::default();
+        let start = Instant::now();
+        due_sites(&mut schedule, &registry.registry, start);
+        registry
+            .registry
+            .delete_standard_connection(&site_spec::SiteID::from_str("server/fast-site").unwrap())
+            .unwrap();
+        due_sites(&mut schedule, &registry.registry, start);
+        assert_eq!(schedule.next_push(start), Some(Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn test_push_schedule_skips_expired_connections() {
+        let mut registry = registry();
+        for (site_id, connection) in registry.registry.get_standard_connections_as_mut() {
+            if site_id.to_string() == "server/fast-site" {
+                connection.expires = Some(misc::unix_now());
+            }
+        }
+        let mut schedule = PushSchedule::default();
+        assert_eq!(
+            due_sites(&mut schedule, &registry.registry, Instant::now()),
+            vec!["server/slow-site"]
+        );
+    }
+
+    #[test]
+    fn test_set_push_interval() {
+        let mut registry = registry();
+        set_push_interval(&mut registry.registry, "server/slow-site", Some(120)).unwrap();
+        set_push_interval(&mut registry.registry, UUID_FAST, None).unwrap();
+        let slow_site = site_spec::SiteID::from_str("server/slow-site").unwrap();
+        let fast_site = site_spec::SiteID::from_str("server/fast-site").unwrap();
+        assert_eq!(
+            registry.registry.get(&slow_site).unwrap().push_interval,
+            Some(120)
+        );
+        assert_eq!(
+            registry.registry.get(&fast_site).unwrap().push_interval,
+            None
+        );
+        assert!(
+            set_push_interval(&mut registry.registry, "server/unknown-site", Some(120)).is_err()
+        );
+    }
+
+    fn push_config(
+        push_compression: monitoring_data::PushCompression,
+        conditional_push: bool,
+    ) -> config::PushConfig {
+        config::PushConfig {
+            push_interval: 60,
+            push_jitter: 0,
+            align_push: false,
+            push_spool_size: 10,
+            push_spool_max_size: None,
+            max_outbound_requests: 8,
+            push_compression,
+            conditional_push,
+            conditional_push_ignored_sections: vec![],
+            conditional_push_max_age: 600,
+            delta_push: false,
+            push_chunk_size: None,
+            sign_push: false,
+            quic: false,
+            push_failover: HashMap::new(),
+            section_filter: config::SectionFilterConfig::default(),
+            post_processors: vec![],
+            fallback_command: None,
+            payload_size: config::PayloadSizeConfig::default(),
+            realtime: config::RealtimeConfig::default(),
+            reregistration: config::ReregistrationConfig::default(),
+            hardware_labels: false,
+            status_section: false,
+        }
+    }
+
+    fn push_state(dir: &std::path::Path, push_config: &config::PushConfig) -> PushState {
+        PushState::new(
+            push_config,
+            &config::ClientConfig {
+                proxy_mode: proxy::ProxyMode::Direct,
+                validate_api_cert: false,
+                source_address: None,
+                ip_preference: happy_eyeballs::IpPreference::Ipv6,
+                request_headers: Default::default(),
+                timeouts: Default::default(),
+            },
+            ConnectionStats::new(dir.join("connection_stats.json")),
+            PushSpool::new(
+                dir.join("push_spool"),
+                push_config.push_spool_size,
+                push_config.push_spool_max_size,
+            ),
+        )
+        .unwrap()
+    }
+
+    #[derive(Default)]
+    struct MockApi {
+        fail_after: Option<usize>,
+        failing: Option<uuid::Uuid>,
+        rejects_zstd: bool,
+        /// Answer to delta uploads other than 204
+        delta_status: Option<reqwest::StatusCode>,
+        pushed: Mutex<Vec<(String, u64)>>,
+        deltas: Mutex<usize>,
+        /// Answer to chunked uploads other than 200
+        chunk_status: Option<reqwest::StatusCode>,
+        uploads: Mutex<HashMap<String, Vec<u8>>>,
+        /// Number of chunks whose acknowledgement gets lost
+        chunk_drops: Mutex<usize>,
+        heartbeats: Mutex<usize>,
+        delay: Duration,
+        /// Currently running and maximum number of concurrent uploads
+        concurrency: Mutex<(usize, usize)>,
+    }
+
+    impl MockApi {
+        fn pushed(&self) -> Vec<(String, u64)> {
+            self.pushed.lock().unwrap().clone()
+        }
+    }
+
+    impl AgentData for MockApi {
+        fn agent_data(
+            &self,
+            _base_url: &reqwest::Url,
+            connection: &config::TrustedConnection,
+            compression_algorithm: &str,
+            _monitoring_data: &[u8],
+            collected_at: u64,
+        ) -> AnyhowResult<()> {
+            {
+                let mut concurrency = self.concurrency.lock().unwrap();
+                concurrency.0 += 1;
+                concurrency.1 = concurrency.1.max(concurrency.0);
+            }
+            std::thread::sleep(self.delay);
+            self.concurrency.lock().unwrap().0 -= 1;
+            let mut pushed = self.pushed.lock().unwrap();
+            if Some(connection.uuid) == self.failing
+                || self
+                    .fail_after
+                    .is_some_and(|fail_after| pushed.len() >= fail_after)
+            {
+                anyhow::bail!("receiver unreachable")
+            }
+            pushed.push((String::from(compression_algorithm), collected_at));
+            if compression_algorithm == "zstd" && self.rejects_zstd {
+                return Err(agent_receiver_api::UnsupportedCompression(String::from(
+                    "Unsupported compression algorithm: zstd",
+                ))
+                .into());
+            }
+            Ok(())
+        }
+    }
+
+    impl AgentDataDelta for MockApi {
+        fn agent_data_delta(
+            &self,
+            _base_url: &reqwest::Url,
+            _connection: &config::TrustedConnection,
+            _compression_algorithm: &str,
+            _delta: &[u8],
+            _collected_at: u64,
+        ) -> AnyhowResult<()> {
+            if let Some(status) = self.delta_status {
+                return Err(agent_receiver_api::ResponseError::new(status, None).into());
+            }
+            *self.deltas.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    impl AgentDataChunk for MockApi {
+        fn agent_data_chunk(
+            &self,
+            _base_url: &reqwest::Url,
+            _connection: &config::TrustedConnection,
+            upload: &ChunkedUpload,
+            offset: u64,
+            chunk: &[u8],
+        ) -> AnyhowResult<u64> {
+            if let Some(status) = self.chunk_status {
+                return Err(agent_receiver_api::ResponseError::new(status, None).into());
+            }
+            let mut uploads = self.uploads.lock().unwrap();
+            let received = uploads.entry(upload.id.clone()).or_default();
+            if offset == received.len() as u64 {
+                received.extend_from_slice(chunk);
+            }
+            let mut chunk_drops = self.chunk_drops.lock().unwrap();
+            if *chunk_drops > 0 {
+                *chunk_drops -= 1;
+                anyhow::bail!("connection reset")
+            }
+            Ok(received.len() as u64)
+        }
+    }
+
+    impl RegistrationStatusV2 for MockApi {
+        fn registration_status_v2(
+            &self,
+            _base_url: &reqwest::Url,
+            _connection: &config::TrustedConnection,
+        ) -> AnyhowResult<agent_receiver_api::RegistrationStatusV2Response> {
+            *self.heartbeats.lock().unwrap() += 1;
+            Ok(agent_receiver_api::RegistrationStatusV2Response::NotRegistered)
+        }
+    }
+
+    fn spooled(state: &PushState, uuid: &uuid::Uuid) -> Vec<u64> {
+        state
+            .push_spool
+            .entries(uuid)
+            .unwrap()
+            .iter()
+            .map(|e| e.collected_at)
+            .collect()
+    }
+
+    #[test]
+    fn test_replay_spooled() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = push_state(
+            dir.path(),
+            &push_config(monitoring_data::PushCompression::Zlib, false),
+        );
+        let connection = config::TrustedConnectionWithRemote::from(UUID_FAST);
+        for collected_at in [1300, 1000, 1600] {
+            state
+                .push_spool
+                .spool(&connection.trust.uuid, collected_at, b"data")
+                .unwrap();
+        }
+        let site_url = reqwest::Url::parse("https://server:8000/site").unwrap();
+
+        let api = MockApi {
+            fail_after: Some(2),
+            ..MockApi::default()
+        };
+        assert!(replay_spooled(&api, &site_url, &connection, &state).is_err());
+        assert_eq!(
+            api.pushed(),
+            vec![(String::from("zlib"), 1000), (String::from("zlib"), 1300)]
+        );
+        assert_eq!(spooled(&state, &connection.trust.uuid), vec![1600]);
+
+        let api = MockApi::default();
+        replay_spooled(&api, &site_url, &connection, &state).unwrap();
+        assert_eq!(api.pushed(), vec![(String::from("zlib"), 1600)]);
+        assert!(spooled(&state, &connection.trust.uuid).is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_push_concurrently_isolates_failures() {
+        let registry = registry();
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(push_state(
+            dir.path(),
+            &push_config(monitoring_data::PushCompression::Zlib, false),
+        ));
+        let failing = uuid::Uuid::from_str(UUID_SLOW).unwrap();
+        let mut results = push_concurrently(
+            Arc::new(MockApi {
+                failing: Some(failing),
+                ..MockApi::default()
+            }),
+            owned(registry.registry.get_push_connections()),
+            Arc::new(PushPayload::new(
+                1000,
+                b"data".to_vec(),
+                &state.change_detection,
+            )),
+            Arc::clone(&state),
+        )
+        .await;
+        results.sort_by(|a, b| a.site_id.cmp(&b.site_id));
+        assert_eq!(
+            results,
+            vec![
+                ipc::PushResult {
+                    site_id: String::from("server/fast-site"),
+                    uuid: String::from(UUID_FAST),
+                    error: None,
+                },
+                ipc::PushResult {
+                    site_id: String::from("server/slow-site"),
+                    uuid: String::from(UUID_SLOW),
+                    error: Some(String::from("receiver unreachable")),
+                },
+            ]
+        );
+
+        let counters = ConnectionStats::load(dir.path().join("connection_stats.json")).unwrap();
+        let fast = uuid::Uuid::from_str(UUID_FAST).unwrap();
+        assert_eq!(counters.get(&fast).unwrap().successful_pushes, 1);
+        assert_eq!(counters.get(&failing).unwrap().failed_pushes, 1);
+        let attempt = &counters.get(&failing).unwrap().push_history[0];
+        assert_eq!(
+            attempt.outcome,
+            crate::connection_stats::PushAttemptOutcome::Failed
+        );
+        assert_eq!(attempt.error, Some(String::from("receiver unreachable")));
+        assert!(spooled(&state, &fast).is_empty());
+        assert_eq!(spooled(&state, &failing), vec![1000]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_push_concurrently_bounded() {
+        let registry = registry();
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(push_state(
+            dir.path(),
+            &config::PushConfig {
+                max_outbound_requests: 1,
+                ..push_config(monitoring_data::PushCompression::Zlib, false)
+            },
+        ));
+        let api = Arc::new(MockApi {
+            delay: Duration::from_millis(50),
+            ..MockApi::default()
+        });
+        let results = push_concurrently(
+            Arc::clone(&api),
+            owned(registry.registry.get_push_connections()),
+            Arc::new(PushPayload::new(
+                1000,
+                b"data".to_vec(),
+                &state.change_detection,
+            )),
+            state,
+        )
+        .await;
+        assert!(results.iter().all(|result| result.error.is_none()));
+        assert_eq!(api.pushed().len(), 2);
+        assert_eq!(api.concurrency.lock().unwrap().1, 1);
+    }
+
+    #[test]
+    fn test_push_compression_fallback() {
+        let registry = registry();
+        let dir = tempfile::tempdir().unwrap();
+        let state = push_state(
+            dir.path(),
+            &push_config(monitoring_data::PushCompression::Zstd, false),
+        );
+        let (site_id, connection) = registry.registry.get_push_connections().next().unwrap();
+        let payload = PushPayload::new(1000, b"data".to_vec(), &state.change_detection);
+        let push = |rejects_zstd: bool| {
+            let api = MockApi {
+                rejects_zstd,
+                ..MockApi::default()
+            };
+            let outcome = push_to_connection(&api, site_id, connection, &payload, &state).unwrap();
+            assert!(matches!(outcome, PushOutcome::Pushed(_)));
+            api.pushed()
+                .into_iter()
+                .map(|(compression, _)| compression)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(push(false), vec![String::from("zstd")]);
+        assert_eq!(push(true), vec![String::from("zstd"), String::from("zlib")]);
+        // The rejection is remembered for subsequent pushes
+        assert_eq!(push(true), vec![String::from("zlib")]);
+    }
+
+    #[test]
+    fn test_conditional_push() {
+        let registry = registry();
+        let dir = tempfile::tempdir().unwrap();
+        let state = push_state(
+            dir.path(),
+            &push_config(monitoring_data::PushCompression::Zlib, true),
+        );
+        let (site_id, connection) = registry.registry.get_push_connections().next().unwrap();
+        let api = MockApi::default();
+        let push = |mon_data: &[u8], forced: bool| {
+            let mut payload = PushPayload::new(1000, mon_data.to_vec(), &state.change_detection);
+            payload.forced = forced;
+            push_to_connection(&api, site_id, connection, &payload, &state).unwrap()
+        };
+
+        assert!(matches!(
+            push(b"<<<a>>>\n1\n", false),
+            PushOutcome::Pushed(_)
+        ));
+        assert!(matches!(
+            push(b"<<<a>>>\n1\n", false),
+            PushOutcome::Unchanged
+        ));
+        assert!(matches!(
+            push(b"<<<a>>>\n1\n", true),
+            PushOutcome::Pushed(_)
+        ));
+        assert!(matches!(
+            push(b"<<<a>>>\n2\n", false),
+            PushOutcome::Pushed(_)
+        ));
+        assert_eq!(api.pushed().len(), 3);
+        assert_eq!(*api.heartbeats.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_delta_push() {
+        let registry = registry();
+        let dir = tempfile::tempdir().unwrap();
+        let mut push_config = push_config(monitoring_data::PushCompression::Zlib, false);
+        push_config.delta_push = true;
+        let state = push_state(dir.path(), &push_config);
+        let (site_id, connection) = registry.registry.get_push_connections().next().unwrap();
+        let push = |api: &MockApi, uptime: u64| {
+            let mut mon_data = format!("<<<uptime>>>\n{uptime}\n<<<df>>>\n");
+            for i in 0..100 {
+                mon_data.push_str(&format!("/dev/sda{i} ext4 1000 {i} 42% /mnt/{i}\n"));
+            }
+            let payload = PushPayload::new(uptime, mon_data.into_bytes(), &state.change_detection);
+            push_to_connection(api, site_id, connection, &payload, &state).unwrap();
+            (api.pushed().len(), *api.deltas.lock().unwrap())
+        };
+
+        let api = MockApi::default();
+        // Nothing to refer to on the first push
+        assert_eq!(push(&api, 1), (1, 0));
+        assert_eq!(push(&api, 2), (1, 1));
+        assert_eq!(push(&api, 3), (1, 2));
+
+        let lost_base = MockApi {
+            delta_status: Some(reqwest::StatusCode::CONFLICT),
+            ..MockApi::default()
+        };
+        assert_eq!(push(&lost_base, 4), (1, 0));
+        assert_eq!(push(&api, 5), (1, 3));
+
+        let unsupported = MockApi {
+            delta_status: Some(reqwest::StatusCode::NOT_FOUND),
+            ..MockApi::default()
+        };
+        assert_eq!(push(&unsupported, 6), (1, 0));
+        // Not tried again
+        assert_eq!(push(&api, 7), (2, 3));
+        assert_eq!(push(&api, 8), (3, 3));
+    }
+
+    #[test]
+    fn test_chunked_push() {
+        let registry = registry();
+        let dir = tempfile::tempdir().unwrap();
+        let mut push_config = push_config(monitoring_data::PushCompression::Zlib, false);
+        push_config.push_chunk_size = Some(constants::MIN_PUSH_CHUNK_SIZE);
+        let state = push_state(dir.path(), &push_config);
+        let (site_id, connection) = registry.registry.get_push_connections().next().unwrap();
+        // Hardly compressible, st. the upload takes several chunks
+        let mut state_xorshift: u64 = 88172645463325252;
+        let mon_data: Vec<u8> = (0..4 * constants::MIN_PUSH_CHUNK_SIZE)
+            .map(|_| {
+                state_xorshift ^= state_xorshift << 13;
+                state_xorshift ^= state_xorshift >> 7;
+                state_xorshift ^= state_xorshift << 17;
+                state_xorshift as u8
+            })
+            .collect();
+        let payload = PushPayload::new(1000, mon_data, &state.change_detection);
+        let compressed = payload
+            .compressed(monitoring_data::PushCompression::Zlib)
+            .unwrap()
+            .to_vec();
+        assert!(compressed.len() > 2 * constants::MIN_PUSH_CHUNK_SIZE);
+
+        let api = MockApi {
+            chunk_drops: Mutex::new(2),
+            ..MockApi::default()
+        };
+        push_to_connection(&api, site_id, connection, &payload, &state).unwrap();
+        assert!(api.pushed().is_empty());
+        assert_eq!(
+            api.uploads.lock().unwrap().values().collect::<Vec<_>>(),
+            vec![&compressed]
+        );
+
+        let unsupported = MockApi {
+            chunk_status: Some(reqwest::StatusCode::NOT_FOUND),
+            ..MockApi::default()
+        };
+        push_to_connection(&unsupported, site_id, connection, &payload, &state).unwrap();
+        assert_eq!(unsupported.pushed(), vec![(String::from("zlib"), 1000)]);
+        // Not tried again
+        push_to_connection(&api, site_id, connection, &payload, &state).unwrap();
+        assert_eq!(api.pushed(), vec![(String::from("zlib"), 1000)]);
+    }
+
+    #[test]
+    fn test_push_chunked_gives_up() {
+        let site_url = reqwest::Url::parse("https://server:8000/site").unwrap();
+        let connection = config::TrustedConnectionWithRemote::from(UUID_FAST);
+        let upload = ChunkedUpload {
+            id: upload_id(b"data", 1000),
+            compression_algorithm: "zlib",
+            total_length: 4,
+            collected_at: 1000,
+            signature: None,
+        };
+        let api = MockApi {
+            chunk_drops: Mutex::new(constants::PUSH_CHUNK_RETRIES + 1),
+            ..MockApi::default()
+        };
+        assert!(push_chunked(&api, &site_url, &connection.trust, &upload, b"data", 1).is_err());
+        // The receiver keeps what it got for the next attempt
+        assert_eq!(
+            api.uploads.lock().unwrap().get(&upload.id),
+            Some(&b"d".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_push_now() {
+        let (push_now, mut requests) = mpsc::channel::<PushNowRequest>(1);
+        tokio::spawn(async move {
+            while let Some(request) = requests.recv().await {
+                request
+                    .reply
+                    .send(ipc::Response::PushNow {
+                        results: vec![ipc::PushResult {
+                            site_id: request.connection.unwrap_or_default(),
+                            uuid: String::from(UUID_FAST),
+                            error: None,
+                        }],
+                    })
+                    .unwrap();
+            }
+        });
+        assert_eq!(
+            request_push_now(Some(String::from("server/fast-site")), &push_now).await,
+            ipc::Response::PushNow {
+                results: vec![ipc::PushResult {
+                    site_id: String::from("server/fast-site"),
+                    uuid: String::from(UUID_FAST),
+                    error: None,
+                }]
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push_now_while_paused() {
+        let (push_now, mut requests) = mpsc::channel::<PushNowRequest>(1);
+        let lifecycle = Lifecycle::default();
+        lifecycle.started();
+        lifecycle.pause();
+        let waiting = tokio::spawn({
+            let lifecycle = lifecycle.clone();
+            async move { wait_while_paused(&lifecycle, &mut requests).await }
+        });
+        assert_eq!(
+            request_push_now(None, &push_now).await,
+            ipc::Response::Error {
+                message: String::from("Pushing is paused")
+            }
+        );
+        lifecycle.stop();
+        assert!(!waiting.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_request_push_now_push_not_running() {
+        let (push_now, _) = mpsc::channel::<PushNowRequest>(1);
+        assert!(matches!(
+            request_push_now(None, &push_now).await,
+            ipc::Response::Error { .. }
+        ));
+    }
+}
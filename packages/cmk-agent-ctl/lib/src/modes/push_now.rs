@@ -0,0 +1,58 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::ipc;
+use anyhow::{bail, Result as AnyhowResult};
+use std::path::Path;
+
+pub fn push_now(path_control_socket: &Path, connection: Option<String>) -> AnyhowResult<()> {
+    match ipc::request(path_control_socket, &ipc::Request::PushNow { connection })? {
+        ipc::Response::PushNow { results } => {
+            for result in &results {
+                println!("{}", render_result(result));
+            }
+            let failed = results.iter().filter(|r| r.error.is_some()).count();
+            if failed > 0 {
+                bail!(
+                    "Push failed for {} of {} connection(s)",
+                    failed,
+                    results.len()
+                );
+            }
+            Ok(())
+        }
+        ipc::Response::Error { message } => bail!(message),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
+
+fn render_result(result: &ipc::PushResult) -> String {
+    match &result.error {
+        None => format!("{} ({}): pushed", result.site_id, result.uuid),
+        Some(error) => format!("{} ({}): failed ({})", result.site_id, result.uuid, error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_result() {
+        let mut result = ipc::PushResult {
+            site_id: String::from("server/site"),
+            uuid: String::from("0096abd7-83c9-42f8-8b3a-3ffba7ba959d"),
+            error: None,
+        };
+        assert_eq!(
+            render_result(&result),
+            "server/site (0096abd7-83c9-42f8-8b3a-3ffba7ba959d): pushed"
+        );
+        result.error = Some(String::from("Connection refused"));
+        assert_eq!(
+            render_result(&result),
+            "server/site (0096abd7-83c9-42f8-8b3a-3ffba7ba959d): failed (Connection refused)"
+        );
+    }
+}
@@ -0,0 +1,2212 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::{
+    agent_receiver_api::{self, RegistrationStatusV2},
+    certs, cli, config, constants, error_code, happy_eyeballs, messages, misc, output,
+    registration_throttle::RegistrationThrottle,
+    reregistration::{ReregistrationCredentials, StoredCredentials},
+    rest_api, site_spec, types, usage_stats, vault,
+};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use config::JSONLoaderMissingSafe;
+use log::{error, info, warn};
+use messages::Message;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::time::{Duration, Instant};
+
+trait TrustEstablishing {
+    fn prompt_server_certificate(&self, server: &str, port: &u16) -> AnyhowResult<()>;
+    fn prompt_password(&self, user: &str) -> AnyhowResult<String>;
+}
+
+/// Details of a server certificate, as presented to whoever decides on trusting it
+#[derive(serde::Serialize)]
+struct CertificateDetails {
+    pem: String,
+    issued_by: Vec<String>,
+    issued_to: Vec<String>,
+    valid_from: String,
+    valid_to: String,
+}
+
+impl CertificateDetails {
+    fn fetch(
+        server: &str,
+        port: &u16,
+        ip_preference: happy_eyeballs::IpPreference,
+        timeouts: &config::NetworkTimeouts,
+    ) -> AnyhowResult<Self> {
+        let pem_str = certs::fetch_server_cert_pem(server, port, ip_preference, timeouts)?;
+        let pem = certs::parse_pem(&pem_str)?;
+        let x509 = pem.parse_x509()?;
+        let validity = x509.validity();
+        Ok(Self {
+            issued_by: certs::common_names(x509.issuer())?
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            issued_to: certs::common_names(x509.subject())?
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            valid_from: validity.not_before.to_rfc2822(),
+            valid_to: validity.not_after.to_rfc2822(),
+            pem: pem_str,
+        })
+    }
+
+    fn display(&self) {
+        eprintln!("{}:\n{}", Message::PemCertificate, self.pem);
+        eprintln!("{}:\n\t{}", Message::IssuedBy, self.issued_by.join(", "));
+        eprintln!("{}:\n\t{}", Message::IssuedTo, self.issued_to.join(", "));
+        eprintln!(
+            "{}:\n\t{:<4} {}\n\t{:<4} {}",
+            Message::Validity,
+            Message::ValidFrom.to_string(),
+            self.valid_from,
+            Message::ValidTo.to_string(),
+            self.valid_to,
+        );
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TrustAnswer {
+    trust: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct PasswordAnswer {
+    password: String,
+}
+
+/// Write the question as one line of JSON and read the answer as one line of JSON
+fn ask_json<A: DeserializeOwned>(
+    question: &serde_json::Value,
+    output: &mut impl Write,
+    input: &mut impl BufRead,
+) -> AnyhowResult<A> {
+    writeln!(output, "{question}")?;
+    output.flush()?;
+    let mut answer = String::new();
+    if input
+        .read_line(&mut answer)
+        .context("Failed to read answer from standard input")?
+        == 0
+    {
+        bail!("No answer on standard input");
+    }
+    serde_json::from_str(&answer).context(format!("Invalid answer {:?}", answer.trim()))
+}
+
+fn ask_json_trust(
+    server: &str,
+    port: &u16,
+    certificate: &CertificateDetails,
+    output: &mut impl Write,
+    input: &mut impl BufRead,
+) -> AnyhowResult<()> {
+    let answer: TrustAnswer = ask_json(
+        &serde_json::json!({
+            "question": "trust_server_certificate",
+            "server": server,
+            "port": port,
+            "certificate": certificate,
+        }),
+        output,
+        input,
+    )?;
+    if !answer.trust {
+        bail!("Cannot continue without trusting {server}, port {port}")
+    }
+    Ok(())
+}
+
+fn ask_json_password(
+    user: &str,
+    output: &mut impl Write,
+    input: &mut impl BufRead,
+) -> AnyhowResult<String> {
+    let answer: PasswordAnswer = ask_json(
+        &serde_json::json!({
+            "question": "password",
+            "user": user,
+        }),
+        output,
+        input,
+    )?;
+    Ok(answer.password)
+}
+
+struct InteractiveTrust {
+    prompt_format: cli::PromptFormat,
+    ip_preference: happy_eyeballs::IpPreference,
+    timeouts: config::NetworkTimeouts,
+}
+
+impl InteractiveTrust {
+    fn new(config: &config::RegistrationConnectionConfig) -> Self {
+        Self {
+            prompt_format: config.prompt_format,
+            ip_preference: config.client_config.ip_preference,
+            timeouts: config.client_config.timeouts,
+        }
+    }
+}
+
+impl TrustEstablishing for InteractiveTrust {
+    fn prompt_server_certificate(&self, server: &str, port: &u16) -> AnyhowResult<()> {
+        if self.prompt_format == cli::PromptFormat::Json {
+            return ask_json_trust(
+                server,
+                port,
+                &CertificateDetails::fetch(server, port, self.ip_preference, &self.timeouts)?,
+                &mut std::io::stdout(),
+                &mut std::io::stdin().lock(),
+            );
+        }
+        eprintln!(
+            "{}",
+            Message::RegisteringAt {
+                server,
+                port: *port
+            }
+        );
+        CertificateDetails::fetch(server, port, self.ip_preference, &self.timeouts)?.display();
+        eprintln!();
+        eprintln!("{}", Message::TrustQuestion);
+        eprint!("> ");
+        loop {
+            let mut answer = String::new();
+            std::io::stdin()
+                .read_line(&mut answer)
+                .context(Message::ReadAnswerFailed.to_string())?;
+            match messages::parse_yes_no(&answer) {
+                Some(true) => return Ok(()),
+                Some(false) => bail!(Message::NotTrusted {
+                    server,
+                    port: *port
+                }
+                .to_string()),
+                None => {
+                    eprintln!("{}", Message::AnswerYesOrNo);
+                    eprint!("> ");
+                }
+            }
+        }
+    }
+
+    fn prompt_password(&self, user: &str) -> AnyhowResult<String> {
+        if self.prompt_format == cli::PromptFormat::Json {
+            return ask_json_password(user, &mut std::io::stdout(), &mut std::io::stdin().lock());
+        }
+        eprintln!();
+        eprint!("{}\n> ", Message::PasswordPrompt { user });
+        rpassword::read_password().context(Message::PasswordFailed.to_string())
+    }
+}
+
+/// For programs driving the registration: nothing can be prompted, so the server certificate has
+/// to be trusted via the configuration and the password has to be given.
+struct UnattendedTrust {}
+
+impl TrustEstablishing for UnattendedTrust {
+    fn prompt_server_certificate(&self, server: &str, port: &u16) -> AnyhowResult<()> {
+        bail!(
+            "Cannot interactively establish trust with {server}, port {port}. Provide the root \
+             certificate of the site or trust the server certificate blindly."
+        )
+    }
+
+    fn prompt_password(&self, user: &str) -> AnyhowResult<String> {
+        bail!("Cannot interactively ask for the password of '{user}', it has to be provided")
+    }
+}
+
+fn registration_server_cert<'a>(
+    config: &'a config::RegistrationConnectionConfig,
+    trust_establisher: &impl TrustEstablishing,
+) -> AnyhowResult<Option<&'a str>> {
+    match &config.root_certificate {
+        Some(cert) => {
+            if config.trust_server_cert {
+                eprintln!(
+                    "Blind trust of server certificate enabled but a root certificate was \
+                     given in the configuration and will be used to verify the server certificate."
+                );
+            }
+            Ok(Some(cert))
+        }
+        None => {
+            if !config.trust_server_cert {
+                trust_establisher
+                    .prompt_server_certificate(&config.site_id.server, &config.receiver_port)?;
+            }
+            Ok(None)
+        }
+    }
+}
+
+struct RegistrationInput<'a> {
+    root_cert: Option<&'a str>,
+    credentials: types::Credentials,
+    uuid: uuid::Uuid,
+    private_key: String,
+    csr: String,
+}
+
+fn prepare_registration<'a>(
+    config: &'a config::RegistrationConnectionConfig,
+    trust_establisher: &impl TrustEstablishing,
+) -> AnyhowResult<RegistrationInput<'a>> {
+    let uuid = uuid::Uuid::new_v4();
+    let (csr, private_key) = certs::make_csr(&uuid.to_string()).context("Error creating CSR.")?;
+    let root_cert = registration_server_cert(config, trust_establisher)?;
+    let credentials = types::Credentials {
+        username: config.username.clone(),
+        password: if let Some(password) = &config.password {
+            String::from(password)
+        } else {
+            trust_establisher.prompt_password(&config.username)?
+        },
+    };
+    Ok(RegistrationInput {
+        root_cert,
+        credentials,
+        uuid,
+        private_key,
+        csr,
+    })
+}
+
+struct RegistrationResult {
+    root_cert: String,
+    agent_cert: String,
+    connection_mode: config::ConnectionMode,
+}
+
+impl std::convert::From<agent_receiver_api::RegisterExistingResponse> for RegistrationResult {
+    fn from(register_existing_response: agent_receiver_api::RegisterExistingResponse) -> Self {
+        Self {
+            root_cert: register_existing_response.root_cert,
+            agent_cert: register_existing_response.agent_cert,
+            connection_mode: register_existing_response.connection_mode,
+        }
+    }
+}
+
+trait RegistrationEndpointCall {
+    fn call(
+        &self,
+        site_url: &reqwest::Url,
+        registration_input: &RegistrationInput,
+        agent_rec_api: &impl agent_receiver_api::Registration,
+    ) -> AnyhowResult<RegistrationResult>;
+}
+
+struct RegistrationCallExisting<'a> {
+    host_name: &'a str,
+}
+
+impl RegistrationEndpointCall for RegistrationCallExisting<'_> {
+    fn call(
+        &self,
+        site_url: &reqwest::Url,
+        registration_input: &RegistrationInput,
+        agent_rec_api: &impl agent_receiver_api::Registration,
+    ) -> AnyhowResult<RegistrationResult> {
+        Ok(RegistrationResult::from(
+            agent_rec_api
+                .register_existing(
+                    site_url,
+                    &registration_input.root_cert,
+                    &registration_input.credentials,
+                    &registration_input.uuid,
+                    &registration_input.csr,
+                    self.host_name,
+                )
+                .context(format!("Error registering existing host at {}", site_url))?,
+        ))
+    }
+}
+
+struct RegistrationCallNew<'a> {
+    agent_labels: &'a types::AgentLabels,
+    approval: &'a config::ApprovalConfig,
+}
+
+impl RegistrationEndpointCall for RegistrationCallNew<'_> {
+    fn call(
+        &self,
+        site_url: &reqwest::Url,
+        registration_input: &RegistrationInput,
+        agent_rec_api: &impl agent_receiver_api::Registration,
+    ) -> AnyhowResult<RegistrationResult> {
+        let reg_new_response = agent_rec_api
+            .register_new(
+                site_url,
+                &registration_input.root_cert,
+                &registration_input.credentials,
+                &registration_input.uuid,
+                &registration_input.csr,
+                &agent_receiver_api::NewHost {
+                    agent_labels: self.agent_labels,
+                    approval_callback_url: self.approval.callback_url.as_ref(),
+                },
+            )
+            .context(format!("Error registering new host at {}", site_url))?;
+
+        let success = await_approval(
+            self.approval,
+            Duration::from_secs(constants::APPROVAL_POLL_INTERVAL),
+            &registration_input.uuid,
+            |wait| {
+                agent_rec_api
+                    .register_new_ongoing(
+                        site_url,
+                        &reg_new_response.root_cert,
+                        &registration_input.credentials,
+                        &registration_input.uuid,
+                        wait,
+                    )
+                    .context(format!(
+                        "Error querying registration progress at {}",
+                        site_url
+                    ))
+            },
+        )?;
+        Ok(RegistrationResult {
+            root_cert: reg_new_response.root_cert,
+            agent_cert: success.agent_cert,
+            connection_mode: success.connection_mode,
+        })
+    }
+}
+
+/// Decision on a new host, as passed to the approval hook
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ApprovalDecision {
+    Approved,
+    Declined,
+    Timeout,
+}
+
+impl ApprovalDecision {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Approved => "approved",
+            Self::Declined => "declined",
+            Self::Timeout => "timeout",
+        }
+    }
+}
+
+/// A failing hook does not change the outcome of the registration
+fn run_approval_hook(
+    approval: &config::ApprovalConfig,
+    decision: ApprovalDecision,
+    uuid: &uuid::Uuid,
+    reason: &str,
+) {
+    let Some(hook) = &approval.hook else {
+        return;
+    };
+    match std::process::Command::new(hook)
+        .env("CMK_APPROVAL_DECISION", decision.as_str())
+        .env("CMK_APPROVAL_UUID", uuid.to_string())
+        .env("CMK_APPROVAL_REASON", reason)
+        .status()
+    {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("Approval hook {} failed ({})", hook.display(), status),
+        Err(err) => warn!("Failed to run approval hook {}: {}", hook.display(), err),
+    }
+}
+
+/// Wait for the site to approve or decline a new host. The receiver is asked to answer only once
+/// it decided (long polling), receivers which answer right away are asked again after the poll
+/// interval.
+fn await_approval(
+    approval: &config::ApprovalConfig,
+    poll_interval: Duration,
+    uuid: &uuid::Uuid,
+    mut poll: impl FnMut(u64) -> AnyhowResult<agent_receiver_api::RegisterNewOngoingResponse>,
+) -> AnyhowResult<agent_receiver_api::RegisterNewOngoingResponseSuccess> {
+    let started = Instant::now();
+    let remaining = || {
+        approval
+            .timeout
+            .map(|timeout| Duration::from_secs(timeout).saturating_sub(started.elapsed()))
+    };
+    loop {
+        let polled = Instant::now();
+        // Long polls end within the approval timeout
+        let wait = remaining().map_or(constants::APPROVAL_LONG_POLL_WAIT, |remaining| {
+            remaining.as_secs().min(constants::APPROVAL_LONG_POLL_WAIT)
+        });
+        match poll(wait)? {
+            agent_receiver_api::RegisterNewOngoingResponse::InProgress => {}
+            agent_receiver_api::RegisterNewOngoingResponse::Declined(declined_resp) => {
+                run_approval_hook(
+                    approval,
+                    ApprovalDecision::Declined,
+                    uuid,
+                    &declined_resp.reason,
+                );
+                return Err(error_code::CodedError::new(
+                    error_code::ErrorCode::RegistrationDeclined,
+                    format!(
+                        "Registration declined by Checkmk instance: {}",
+                        declined_resp.reason
+                    ),
+                )
+                .into());
+            }
+            agent_receiver_api::RegisterNewOngoingResponse::Success(success_resp) => {
+                run_approval_hook(approval, ApprovalDecision::Approved, uuid, "");
+                return Ok(success_resp);
+            }
+        }
+        let remaining = remaining();
+        if remaining.is_some_and(|remaining| remaining.is_zero()) {
+            run_approval_hook(approval, ApprovalDecision::Timeout, uuid, "");
+            return Err(error_code::CodedError::new(
+                error_code::ErrorCode::ApprovalTimeout,
+                format!(
+                    "Registration was not approved on Checkmk instance within {} s, it may still \
+                     be approved later",
+                    approval.timeout.unwrap_or_default()
+                ),
+            )
+            .into());
+        }
+        let pause = poll_interval.saturating_sub(polled.elapsed());
+        let pause = remaining.map_or(pause, |remaining| pause.min(remaining));
+        if !pause.is_zero() {
+            println!(
+                "Waiting for registration to complete on Checkmk instance, sleeping {} s",
+                pause.as_secs()
+            );
+            std::thread::sleep(pause);
+        }
+    }
+}
+
+/// Create or update the host via the REST API of the site, if configured. Returns the
+/// configuration for registering, including the password if it was prompted for already.
+fn set_up_host(
+    config: &config::RegisterExistingConfig,
+    host_management: &impl rest_api::HostManagement,
+    trust_establisher: &impl TrustEstablishing,
+) -> AnyhowResult<config::RegistrationConnectionConfig> {
+    let mut connection_config = config.connection_config.clone();
+    let Some(host_creation) = &config.host_creation else {
+        return Ok(connection_config);
+    };
+    let credentials = match &host_creation.username {
+        Some(username) => types::Credentials {
+            username: username.clone(),
+            password: match &host_creation.password {
+                Some(password) => password.clone(),
+                None => trust_establisher.prompt_password(username)?,
+            },
+        },
+        None => {
+            let password = match &connection_config.password {
+                Some(password) => password.clone(),
+                None => trust_establisher.prompt_password(&connection_config.username)?,
+            };
+            connection_config.password = Some(password.clone());
+            types::Credentials {
+                username: connection_config.username.clone(),
+                password,
+            }
+        }
+    };
+    let change = rest_api::set_up_host(
+        host_management,
+        &host_creation.api_url,
+        &credentials,
+        &config.host_name,
+        host_creation.folder.as_deref(),
+        &host_creation.labels,
+    )
+    .context(format!(
+        "Error setting up host {} via the REST API at {}",
+        config.host_name, host_creation.api_url
+    ))?;
+    eprintln!(
+        "{}",
+        match change {
+            rest_api::HostChange::Created => format!("Created host {}.", config.host_name),
+            rest_api::HostChange::Updated => format!("Updated host {}.", config.host_name),
+            rest_api::HostChange::Unchanged => format!("Host {} is up to date.", config.host_name),
+        }
+    );
+    Ok(connection_config)
+}
+
+/// Client of the agent receiver, speaking the protocol given for the connection
+fn receiver_api(
+    connection_config: &config::RegistrationConnectionConfig,
+) -> agent_receiver_api::Api {
+    agent_receiver_api::Api::new(&connection_config.client_config)
+        .with_receiver_protocol(connection_config.receiver_protocol)
+}
+
+/// Result of a registration, as written with --output
+#[derive(serde::Serialize, Debug)]
+pub struct Registered {
+    pub site_id: String,
+    pub uuid: String,
+    pub connection_mode: config::ConnectionMode,
+    /// Unix timestamp at which a temporary registration expires
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+fn direct_registration(
+    config: &config::RegistrationConnectionConfig,
+    registry: &mut config::Registry,
+    agent_rec_api: &impl agent_receiver_api::Registration,
+    trust_establisher: &impl TrustEstablishing,
+    endpoint_call: &impl RegistrationEndpointCall,
+) -> AnyhowResult<Registered> {
+    let throttle_path = registry.registration_throttle_path();
+    // A broken throttle file must not prevent registrations
+    let mut throttle = RegistrationThrottle::load_missing_safe(&throttle_path).unwrap_or_default();
+    throttle.check(&config.site_id, misc::unix_now())?;
+    let registration_input = prepare_registration(config, trust_establisher)?;
+
+    let registration_result = endpoint_call.call(
+        &site_spec::make_site_url(&config.site_id, &config.receiver_port)?,
+        &registration_input,
+        agent_rec_api,
+    );
+    if throttle.record(
+        &config.site_id,
+        registration_result.as_ref().err(),
+        misc::unix_now(),
+    ) {
+        if let Err(error) = throttle.save(&throttle_path) {
+            warn!(
+                "Failed to save rejected registrations to {:?}: {:#}",
+                throttle_path, error
+            );
+        }
+    }
+    let registration_result = registration_result?;
+
+    // Keep a push interval, source address, data source and tags configured for a previous
+    // registration with this site
+    let (push_interval, previous_source_address, data_source, mut tags) = registry
+        .get_connection_as_mut(&config.site_id)
+        .map_or((None, None, None, BTreeMap::new()), |connection| {
+            (
+                connection.push_interval,
+                connection.trust.source_address,
+                connection.trust.data_source.clone(),
+                connection.tags.clone(),
+            )
+        });
+    let source_address = config.source_address.or(previous_source_address);
+    tags.extend(config.tags.clone());
+    let expires = config
+        .expires_in
+        .map(|expires_in| misc::unix_now().saturating_add(expires_in));
+    let registered = Registered {
+        site_id: config.site_id.to_string(),
+        uuid: registration_input.uuid.to_string(),
+        connection_mode: registration_result.connection_mode.clone(),
+        expires,
+    };
+    registry.register_connection(
+        &registration_result.connection_mode,
+        &config.site_id,
+        config::TrustedConnectionWithRemote {
+            trust: config::TrustedConnection {
+                uuid: registration_input.uuid,
+                private_key: registration_input.private_key,
+                certificate: registration_result.agent_cert,
+                root_cert: registration_result.root_cert,
+                source_address,
+                receiver_protocol: config.receiver_protocol,
+                transport: config.transport,
+                data_source,
+            },
+            receiver_port: config.receiver_port,
+            push_interval,
+            hostname: None,
+            tags,
+            expires,
+        },
+    );
+
+    registry.save()?;
+    usage_stats::record_registration();
+
+    Ok(registered)
+}
+
+/// Register an existing host on behalf of another host, without saving the connection
+fn proxy_registration(
+    config: &config::RegisterExistingConfig,
+    agent_rec_api: &impl agent_receiver_api::Registration,
+    host_management: &impl rest_api::HostManagement,
+    trust_establisher: &impl TrustEstablishing,
+) -> AnyhowResult<(config::ConnectionMode, config::TrustedConnection)> {
+    let connection_config = set_up_host(config, host_management, trust_establisher)?;
+    let registration_input = prepare_registration(&connection_config, trust_establisher)?;
+
+    let registration_result = RegistrationCallExisting {
+        host_name: &config.host_name,
+    }
+    .call(
+        &site_spec::make_site_url(&connection_config.site_id, &connection_config.receiver_port)?,
+        &registration_input,
+        agent_rec_api,
+    )?;
+    usage_stats::record_registration();
+
+    Ok((
+        registration_result.connection_mode,
+        config::TrustedConnection {
+            uuid: registration_input.uuid,
+            private_key: registration_input.private_key,
+            certificate: registration_result.agent_cert,
+            root_cert: registration_result.root_cert,
+            source_address: None,
+            receiver_protocol: connection_config.receiver_protocol,
+            transport: connection_config.transport,
+            data_source: None,
+        },
+    ))
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ProxyPullData {
+    pub agent_controller_version: String,
+    pub connection: config::TrustedConnection,
+}
+
+impl config::JSONLoader for ProxyPullData {}
+
+pub fn register_existing(
+    config: &config::RegisterExistingConfig,
+    registry: &mut config::Registry,
+    output_format: cli::OutputFormat,
+) -> AnyhowResult<()> {
+    let registered = register_existing_interactively(config, registry)?;
+    output::print(output_format, &registered, "Registration complete.")
+}
+
+/// Register this host, asking for whatever is missing
+pub fn register_existing_interactively(
+    config: &config::RegisterExistingConfig,
+    registry: &mut config::Registry,
+) -> AnyhowResult<Registered> {
+    let trust_establisher = InteractiveTrust::new(&config.connection_config);
+    let mut connection_config = set_up_host(
+        config,
+        &rest_api::Api::new(&config.connection_config.client_config),
+        &trust_establisher,
+    )?;
+    if config.keep_credentials && connection_config.password.is_none() {
+        // Asked for up front, st. it can be stored after the registration
+        connection_config.password =
+            Some(trust_establisher.prompt_password(&connection_config.username)?);
+    }
+    let registered = direct_registration(
+        &connection_config,
+        registry,
+        &receiver_api(&config.connection_config),
+        &trust_establisher,
+        &RegistrationCallExisting {
+            host_name: &config.host_name,
+        },
+    )?;
+    if config.keep_credentials {
+        keep_credentials(registry, &connection_config, &config.host_name)?;
+    }
+    Ok(registered)
+}
+
+fn keep_credentials(
+    registry: &config::Registry,
+    connection_config: &config::RegistrationConnectionConfig,
+    host_name: &str,
+) -> AnyhowResult<()> {
+    let path = registry.reregistration_credentials_path();
+    let mut stored = ReregistrationCredentials::load_missing_safe(&path)?;
+    stored.insert(
+        &connection_config.site_id,
+        StoredCredentials {
+            username: connection_config.username.clone(),
+            password: connection_config.password.clone().unwrap_or_default(),
+            host_name: String::from(host_name),
+        },
+    );
+    stored
+        .save(&path)
+        .context(format!("Failed to store the credentials in {path:?}"))
+}
+
+/// Register a connection again with stored credentials, trusting the root certificate of the
+/// previous registration. Tags and push interval are kept, so is the remaining time of a
+/// temporary registration.
+pub fn reregister(
+    site_id: &site_spec::SiteID,
+    credentials: &StoredCredentials,
+    client_config: &config::ClientConfig,
+    registry: &mut config::Registry,
+) -> AnyhowResult<Registered> {
+    let connection = registry
+        .get_connection_as_mut(site_id)
+        .context(format!("Couldn't find connection with site ID {site_id}"))?;
+    let connection_config = config::RegistrationConnectionConfig {
+        site_id: site_id.clone(),
+        receiver_port: connection.receiver_port,
+        username: credentials.username.clone(),
+        password: Some(credentials.password.clone()),
+        root_certificate: Some(connection.trust.root_cert.clone()),
+        trust_server_cert: false,
+        prompt_format: cli::PromptFormat::default(),
+        client_config: config::ClientConfig {
+            source_address: connection
+                .trust
+                .source_address
+                .or(client_config.source_address),
+            ..client_config.clone()
+        },
+        source_address: connection.trust.source_address,
+        receiver_protocol: connection.trust.receiver_protocol,
+        transport: connection.trust.transport,
+        tags: BTreeMap::new(),
+        expires_in: connection
+            .expires
+            .map(|expires| expires.saturating_sub(misc::unix_now()).max(1)),
+    };
+    direct_registration(
+        &connection_config,
+        registry,
+        &receiver_api(&connection_config),
+        &UnattendedTrust {},
+        &RegistrationCallExisting {
+            host_name: &credentials.host_name,
+        },
+    )
+}
+
+pub fn register_new(
+    config: &config::RegisterNewConfig,
+    registry: &mut config::Registry,
+    output_format: cli::OutputFormat,
+) -> AnyhowResult<()> {
+    let registered = direct_registration(
+        &config.connection_config,
+        registry,
+        &receiver_api(&config.connection_config),
+        &InteractiveTrust::new(&config.connection_config),
+        &RegistrationCallNew {
+            agent_labels: &config.agent_labels,
+            approval: &config.approval,
+        },
+    )?;
+    output::print(
+        output_format,
+        &registered,
+        "Registration complete. It may take few minutes until the newly created host and its services are visible in the site.",
+    )
+}
+
+pub fn register_existing_unattended(
+    config: &config::RegisterExistingConfig,
+    registry: &mut config::Registry,
+) -> AnyhowResult<()> {
+    direct_registration(
+        &set_up_host(
+            config,
+            &rest_api::Api::new(&config.connection_config.client_config),
+            &UnattendedTrust {},
+        )?,
+        registry,
+        &receiver_api(&config.connection_config),
+        &UnattendedTrust {},
+        &RegistrationCallExisting {
+            host_name: &config.host_name,
+        },
+    )?;
+    Ok(())
+}
+
+pub fn register_new_unattended(
+    config: &config::RegisterNewConfig,
+    registry: &mut config::Registry,
+) -> AnyhowResult<()> {
+    direct_registration(
+        &config.connection_config,
+        registry,
+        &receiver_api(&config.connection_config),
+        &UnattendedTrust {},
+        &RegistrationCallNew {
+            agent_labels: &config.agent_labels,
+            approval: &config.approval,
+        },
+    )?;
+    Ok(())
+}
+
+pub fn register_pre_configured(
+    pre_configured_connections: &config::PreConfiguredConnections,
+    client_config: &config::ClientConfig,
+    registry: &mut config::Registry,
+) -> AnyhowResult<()> {
+    _register_pre_configured(
+        pre_configured_connections,
+        client_config,
+        registry,
+        &RegistrationPreConfiguredImpl {},
+    )
+}
+
+fn _register_pre_configured(
+    pre_configured_connections: &config::PreConfiguredConnections,
+    client_config: &config::ClientConfig,
+    registry: &mut config::Registry,
+    registration_pre_configured: &impl RegistrationPreConfigured,
+) -> AnyhowResult<()> {
+    for (site_id, pre_configured_connection) in pre_configured_connections.connections.iter() {
+        if let Err(error) = process_pre_configured_connection(
+            site_id,
+            pre_configured_connection,
+            &pre_configured_connections.agent_labels,
+            client_config,
+            registry,
+            registration_pre_configured,
+        ) {
+            error!(
+                "Error while processing connection {}: {}",
+                site_id,
+                misc::anyhow_error_to_human_readable(&error)
+            )
+        }
+    }
+
+    if !pre_configured_connections.keep_existing_connections {
+        delete_vanished_connections(
+            registry,
+            registry
+                .get_registered_site_ids()
+                .filter(|site_id| !pre_configured_connections.connections.contains_key(site_id))
+                .cloned()
+                .collect::<Vec<site_spec::SiteID>>()
+                .iter(),
+        )
+    }
+
+    registry
+        .save()
+        .context("Failed to save registered connections")
+}
+
+trait RegistrationPreConfigured {
+    fn register(
+        &self,
+        config: &config::RegisterNewConfig,
+        registry: &mut config::Registry,
+    ) -> AnyhowResult<()>;
+
+    fn registration_status_v2(
+        &self,
+        site_id: &site_spec::SiteID,
+        connection: &config::TrustedConnectionWithRemote,
+        client_config: &config::ClientConfig,
+    ) -> AnyhowResult<agent_receiver_api::RegistrationStatusV2Response>;
+}
+
+struct RegistrationPreConfiguredImpl;
+
+impl RegistrationPreConfigured for RegistrationPreConfiguredImpl {
+    fn register(
+        &self,
+        config: &config::RegisterNewConfig,
+        registry: &mut config::Registry,
+    ) -> AnyhowResult<()> {
+        register_new(config, registry, cli::OutputFormat::Human)
+    }
+
+    fn registration_status_v2(
+        &self,
+        site_id: &site_spec::SiteID,
+        connection: &config::TrustedConnectionWithRemote,
+        client_config: &config::ClientConfig,
+    ) -> AnyhowResult<agent_receiver_api::RegistrationStatusV2Response> {
+        agent_receiver_api::Api::new(client_config).registration_status_v2(
+            &site_spec::make_site_url(site_id, &connection.receiver_port)?,
+            &connection.trust,
+        )
+    }
+}
+
+fn process_pre_configured_connection(
+    site_id: &site_spec::SiteID,
+    pre_configured: &config::PreConfiguredConnection,
+    agent_labels: &types::AgentLabels,
+    client_config: &config::ClientConfig,
+    registry: &mut config::Registry,
+    registration_pre_configured: &impl RegistrationPreConfigured,
+) -> AnyhowResult<()> {
+    let receiver_port = match pre_configured.port {
+        Some(receiver_port) => receiver_port,
+        None => site_spec::discover_receiver_port(site_id, client_config)?,
+    };
+
+    if let Some(registered_connection) = registry.get_connection_as_mut(site_id) {
+        registered_connection.receiver_port = receiver_port;
+        info!(
+            "Updated agent receiver port for existing connection {}",
+            site_id
+        );
+        if matches!(
+            registration_pre_configured.registration_status_v2(
+                site_id,
+                registered_connection,
+                client_config
+            )?,
+            agent_receiver_api::RegistrationStatusV2Response::Registered(..)
+        ) {
+            return Ok(());
+        }
+    }
+
+    let registration_config = config::RegisterNewConfig::new(
+        config::RegistrationConnectionConfig {
+            site_id: site_id.clone(),
+            receiver_port,
+            username: pre_configured.credentials.username.clone(),
+            password: Some(pre_configured.credentials.password.clone()),
+            root_certificate: Some(pre_configured.root_cert.clone()),
+            trust_server_cert: false,
+            prompt_format: cli::PromptFormat::Text,
+            client_config: client_config.clone(),
+            source_address: None,
+            receiver_protocol: cli::ReceiverProtocol::Rest,
+            transport: Default::default(),
+            tags: BTreeMap::new(),
+            expires_in: None,
+        },
+        agent_labels.clone(),
+    )?;
+
+    registration_pre_configured.register(&registration_config, registry)?;
+    info!("Registered new connection {}", site_id);
+
+    Ok(())
+}
+
+fn delete_vanished_connections<'a>(
+    registry: &mut config::Registry,
+    site_ids_to_delete: impl Iterator<Item = &'a site_spec::SiteID>,
+) {
+    for site_id in site_ids_to_delete {
+        if let Err(error) = registry.delete_standard_connection(site_id) {
+            error!(
+                "Error deleting vanished connection {}: {}",
+                site_id,
+                misc::anyhow_error_to_human_readable(&error)
+            )
+        }
+    }
+
+    registry.clear_imported();
+}
+
+/// Register on behalf of another host. The connection is written to standard output, or stored
+/// in the vault of the given tenant.
+pub fn proxy_register(
+    config: &config::RegisterExistingConfig,
+    tenant: Option<&vault::Tenant>,
+) -> AnyhowResult<()> {
+    if let Some(tenant) = tenant {
+        // Before registering, the connection would be lost otherwise
+        tenant.prepare(&config.host_name)?;
+    }
+    let (connection_mode, connection) = proxy_registration(
+        config,
+        &receiver_api(&config.connection_config),
+        &rest_api::Api::new(&config.connection_config.client_config),
+        &InteractiveTrust::new(&config.connection_config),
+    )?;
+
+    if connection_mode == config::ConnectionMode::Push {
+        eprintln!(
+            "WARNING: The host you just registered is configured to be a push host. The imported \
+             connection will only work if the monitored host can connect to the monitoring server."
+        )
+    }
+
+    let uuid = connection.uuid;
+    let data = serde_json::to_string(&ProxyPullData {
+        agent_controller_version: String::from(constants::VERSION),
+        connection,
+    })?;
+    match tenant {
+        Some(tenant) => {
+            tenant.store(
+                &config.host_name,
+                &config.connection_config.site_id,
+                &uuid,
+                &data,
+            )?;
+            println!(
+                "Stored the connection of {} in the vault.",
+                config.host_name
+            );
+        }
+        None => println!("{data}"),
+    }
+    Ok(())
+}
+
+/// Register an existing host for an agent behind a relay. The site pulls the relay via the
+/// returned connection, so only pull hosts can be relayed.
+pub fn relay_register(
+    config: &config::RegisterExistingConfig,
+) -> AnyhowResult<config::TrustedConnection> {
+    let (connection_mode, connection) = proxy_registration(
+        config,
+        &receiver_api(&config.connection_config),
+        &rest_api::Api::new(&config.connection_config.client_config),
+        &InteractiveTrust::new(&config.connection_config),
+    )?;
+    if connection_mode == config::ConnectionMode::Push {
+        bail!(
+            "The host {} is configured to be a push host, only pull hosts can be relayed.",
+            config.host_name
+        )
+    }
+    Ok(connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy;
+    use config::test_helpers::TestRegistry;
+    use std::str::FromStr;
+
+    const SERVER: &str = "server";
+    const PORT: u16 = 8000;
+    const SITE: &str = "site";
+    const HOST_NAME: &str = "host";
+    const USERNAME: &str = "user";
+
+    fn site_id() -> site_spec::SiteID {
+        site_spec::SiteID {
+            server: String::from(SERVER),
+            site: String::from(SITE),
+        }
+    }
+
+    fn expected_url() -> reqwest::Url {
+        site_spec::make_site_url(&site_id(), &PORT).unwrap()
+    }
+
+    enum RegistrationMethod {
+        Existing,
+        New,
+    }
+
+    struct MockApi {
+        expect_root_cert: bool,
+        expected_registration_method: Option<RegistrationMethod>,
+    }
+
+    impl agent_receiver_api::Registration for MockApi {
+        fn register_existing(
+            &self,
+            base_url: &reqwest::Url,
+            root_cert: &Option<&str>,
+            _credentials: &types::Credentials,
+            _uuid: &uuid::Uuid,
+            _csr: &str,
+            host_name: &str,
+        ) -> AnyhowResult<agent_receiver_api::RegisterExistingResponse> {
+            assert!(matches!(
+                self.expected_registration_method.as_ref().unwrap(),
+                RegistrationMethod::Existing
+            ));
+            assert!(base_url == &expected_url());
+            assert!(root_cert.is_some() == self.expect_root_cert);
+            assert!(host_name == HOST_NAME);
+            Ok(agent_receiver_api::RegisterExistingResponse {
+                root_cert: String::from("root_cert"),
+                agent_cert: String::from("agent_cert"),
+                connection_mode: config::ConnectionMode::Pull,
+            })
+        }
+
+        fn register_new(
+            &self,
+            base_url: &reqwest::Url,
+            root_cert: &Option<&str>,
+            _credentials: &types::Credentials,
+            _uuid: &uuid::Uuid,
+            _csr: &str,
+            new_host: &agent_receiver_api::NewHost,
+        ) -> AnyhowResult<agent_receiver_api::RegisterNewResponse> {
+            assert!(matches!(
+                self.expected_registration_method.as_ref().unwrap(),
+                RegistrationMethod::New
+            ));
+            assert!(base_url == &expected_url());
+            assert!(root_cert.is_some() == self.expect_root_cert);
+            assert!(new_host.agent_labels == &agent_labels());
+            Ok(agent_receiver_api::RegisterNewResponse {
+                root_cert: String::from("root_cert"),
+            })
+        }
+
+        fn register_new_ongoing(
+            &self,
+            base_url: &reqwest::Url,
+            _root_cert: &str,
+            _credentials: &types::Credentials,
+            _uuid: &uuid::Uuid,
+            _wait: u64,
+        ) -> AnyhowResult<agent_receiver_api::RegisterNewOngoingResponse> {
+            assert!(matches!(
+                self.expected_registration_method.as_ref().unwrap(),
+                RegistrationMethod::New
+            ));
+            assert!(base_url == &expected_url());
+            Ok(agent_receiver_api::RegisterNewOngoingResponse::Success(
+                agent_receiver_api::RegisterNewOngoingResponseSuccess {
+                    agent_cert: String::from("agent_cert"),
+                    connection_mode: config::ConnectionMode::Push,
+                },
+            ))
+        }
+    }
+
+    struct MockInteractiveTrust {
+        expect_server_cert_prompt: bool,
+        expect_password_prompt: bool,
+    }
+
+    impl TrustEstablishing for MockInteractiveTrust {
+        fn prompt_server_certificate(&self, server: &str, port: &u16) -> AnyhowResult<()> {
+            assert!(self.expect_server_cert_prompt);
+            assert!(server == SERVER);
+            assert!(port == &PORT);
+            Ok(())
+        }
+
+        fn prompt_password(&self, user: &str) -> AnyhowResult<String> {
+            assert!(self.expect_password_prompt);
+            assert_eq!(user, USERNAME);
+            Ok(String::from("password"))
+        }
+    }
+
+    /// Records the user of each request, hosts never exist
+    #[derive(Default)]
+    struct MockHostManagement {
+        users: std::cell::RefCell<Vec<String>>,
+    }
+
+    impl rest_api::HostManagement for MockHostManagement {
+        fn get_host(
+            &self,
+            _base_url: &reqwest::Url,
+            credentials: &types::Credentials,
+            host_name: &str,
+        ) -> AnyhowResult<Option<rest_api::HostObject>> {
+            assert_eq!(host_name, HOST_NAME);
+            self.users.borrow_mut().push(credentials.username.clone());
+            Ok(None)
+        }
+
+        fn create_host(
+            &self,
+            _base_url: &reqwest::Url,
+            credentials: &types::Credentials,
+            host_name: &str,
+            _folder: &str,
+            _labels: &types::AgentLabels,
+        ) -> AnyhowResult<()> {
+            assert_eq!(host_name, HOST_NAME);
+            self.users.borrow_mut().push(credentials.username.clone());
+            Ok(())
+        }
+
+        fn update_labels(
+            &self,
+            _base_url: &reqwest::Url,
+            _credentials: &types::Credentials,
+            _host_name: &str,
+            _etag: &str,
+            _labels: &types::AgentLabels,
+        ) -> AnyhowResult<String> {
+            bail!("Labels are not updated in this test")
+        }
+
+        fn move_host(
+            &self,
+            _base_url: &reqwest::Url,
+            _credentials: &types::Credentials,
+            _host_name: &str,
+            _etag: &str,
+            _folder: &str,
+        ) -> AnyhowResult<()> {
+            bail!("Hosts are not moved in this test")
+        }
+    }
+
+    fn agent_labels() -> types::AgentLabels {
+        let mut al = std::collections::HashMap::new();
+        al.insert(String::from("a"), String::from("b"));
+        al
+    }
+
+    fn registration_connection_config(
+        root_certificate: Option<String>,
+        password: Option<String>,
+        trust_server_cert: bool,
+    ) -> config::RegistrationConnectionConfig {
+        config::RegistrationConnectionConfig {
+            site_id: site_id(),
+            receiver_port: PORT,
+            username: String::from(USERNAME),
+            password,
+            root_certificate,
+            trust_server_cert,
+            prompt_format: cli::PromptFormat::Text,
+            client_config: config::ClientConfig {
+                proxy_mode: proxy::ProxyMode::Direct,
+                validate_api_cert: false,
+                source_address: None,
+                ip_preference: happy_eyeballs::IpPreference::Ipv6,
+                request_headers: Default::default(),
+                timeouts: Default::default(),
+            },
+            source_address: None,
+            receiver_protocol: cli::ReceiverProtocol::Rest,
+            transport: Default::default(),
+            tags: BTreeMap::from([(String::from("env"), String::from("prod"))]),
+            expires_in: None,
+        }
+    }
+
+    mod test_prepare_registration {
+        use super::*;
+
+        #[test]
+        fn test_interactive_trust() {
+            assert!(prepare_registration(
+                &registration_connection_config(None, None, false),
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: true,
+                    expect_password_prompt: true,
+                },
+            )
+            .is_ok());
+        }
+
+        #[test]
+        fn test_blind_trust() {
+            assert!(prepare_registration(
+                &registration_connection_config(None, Some(String::from("password")), true),
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: false,
+                },
+            )
+            .is_ok());
+        }
+
+        #[test]
+        fn test_root_cert_from_config() {
+            assert!(prepare_registration(
+                &registration_connection_config(
+                    Some(String::from("root_certificate")),
+                    Some(String::from("password")),
+                    false
+                ),
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: false,
+                },
+            )
+            .is_ok());
+        }
+
+        #[test]
+        fn test_root_cert_from_config_and_blind_trust() {
+            assert!(prepare_registration(
+                &registration_connection_config(Some(String::from("root_certificate")), None, true),
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: true,
+                },
+            )
+            .is_ok());
+        }
+
+        #[test]
+        fn test_unattended_trust() {
+            assert!(prepare_registration(
+                &registration_connection_config(None, Some(String::from("password")), true),
+                &UnattendedTrust {},
+            )
+            .is_ok());
+            assert!(prepare_registration(
+                &registration_connection_config(None, Some(String::from("password")), false),
+                &UnattendedTrust {},
+            )
+            .is_err());
+            assert!(prepare_registration(
+                &registration_connection_config(
+                    Some(String::from("root_certificate")),
+                    None,
+                    false
+                ),
+                &UnattendedTrust {},
+            )
+            .is_err());
+        }
+    }
+
+    mod test_json_prompts {
+        use super::*;
+
+        fn certificate() -> CertificateDetails {
+            CertificateDetails {
+                pem: String::from("pem"),
+                issued_by: vec![String::from("Site 'heute' local CA")],
+                issued_to: vec![String::from("heute")],
+                valid_from: String::from("Mon, 1 Jan 2024 00:00:00 +0000"),
+                valid_to: String::from("Wed, 1 Jan 2025 00:00:00 +0000"),
+            }
+        }
+
+        fn ask_trust(answer: &str) -> (AnyhowResult<()>, serde_json::Value) {
+            let mut output = vec![];
+            let result = ask_json_trust(
+                SERVER,
+                &PORT,
+                &certificate(),
+                &mut output,
+                &mut answer.as_bytes(),
+            );
+            (result, serde_json::from_slice(&output).unwrap())
+        }
+
+        #[test]
+        fn test_trust() {
+            let (result, question) = ask_trust("{\"trust\": true}\n");
+            assert!(result.is_ok());
+            assert_eq!(question["question"], "trust_server_certificate");
+            assert_eq!(question["server"], SERVER);
+            assert_eq!(question["port"], PORT);
+            assert_eq!(question["certificate"]["issued_to"][0], "heute");
+        }
+
+        #[test]
+        fn test_distrust() {
+            assert!(ask_trust("{\"trust\": false}\n").0.is_err());
+            assert!(ask_trust("y\n").0.is_err());
+            assert!(ask_trust("").0.is_err());
+        }
+
+        #[test]
+        fn test_password() {
+            let mut output = vec![];
+            assert_eq!(
+                ask_json_password(
+                    USERNAME,
+                    &mut output,
+                    &mut "{\"password\": \"secret\"}\n".as_bytes()
+                )
+                .unwrap(),
+                "secret"
+            );
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                format!("{{\"question\":\"password\",\"user\":\"{USERNAME}\"}}\n")
+            );
+        }
+    }
+
+    mod test_register_manual {
+        use super::*;
+
+        #[test]
+        fn test_existing() {
+            let mut r = TestRegistry::new();
+            let registry = &mut r.registry;
+            assert!(!registry.path().exists());
+            assert!(direct_registration(
+                &registration_connection_config(None, None, false),
+                registry,
+                &MockApi {
+                    expect_root_cert: false,
+                    expected_registration_method: Some(RegistrationMethod::Existing),
+                },
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: true,
+                    expect_password_prompt: true,
+                },
+                &RegistrationCallExisting {
+                    host_name: HOST_NAME
+                },
+            )
+            .is_ok());
+            assert!(!registry.is_empty());
+            assert!(registry.path().exists());
+        }
+
+        #[test]
+        fn test_temporary_registration() {
+            let mut r = TestRegistry::new();
+            let config = config::RegistrationConnectionConfig {
+                expires_in: Some(3600),
+                ..registration_connection_config(None, None, false)
+            };
+            let before = misc::unix_now();
+            let registered = direct_registration(
+                &config,
+                &mut r.registry,
+                &MockApi {
+                    expect_root_cert: false,
+                    expected_registration_method: Some(RegistrationMethod::Existing),
+                },
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: true,
+                    expect_password_prompt: true,
+                },
+                &RegistrationCallExisting {
+                    host_name: HOST_NAME,
+                },
+            )
+            .unwrap();
+            let expires = r.registry.get(&site_id()).unwrap().expires.unwrap();
+            assert!(expires >= before + 3600 && expires <= misc::unix_now() + 3600);
+            assert_eq!(registered.expires, Some(expires));
+        }
+
+        #[test]
+        fn test_tags_are_added_to_previous_ones() {
+            let mut previous = config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4());
+            previous.tags = BTreeMap::from([
+                (String::from("env"), String::from("test")),
+                (String::from("owner"), String::from("team-x")),
+            ]);
+            let mut r = TestRegistry::new().add_connection(
+                &config::ConnectionMode::Push,
+                &site_id().to_string(),
+                previous,
+            );
+            direct_registration(
+                &registration_connection_config(None, None, false),
+                &mut r.registry,
+                &MockApi {
+                    expect_root_cert: false,
+                    expected_registration_method: Some(RegistrationMethod::Existing),
+                },
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: true,
+                    expect_password_prompt: true,
+                },
+                &RegistrationCallExisting {
+                    host_name: HOST_NAME,
+                },
+            )
+            .unwrap();
+            assert_eq!(
+                r.registry.get(&site_id()).unwrap().tags,
+                BTreeMap::from([
+                    (String::from("env"), String::from("prod")),
+                    (String::from("owner"), String::from("team-x")),
+                ])
+            );
+        }
+
+        /// Rejects the credentials of every registration
+        #[derive(Default)]
+        struct RejectingApi {
+            calls: std::cell::Cell<usize>,
+        }
+
+        impl agent_receiver_api::Registration for RejectingApi {
+            fn register_existing(
+                &self,
+                _base_url: &reqwest::Url,
+                _root_cert: &Option<&str>,
+                _credentials: &types::Credentials,
+                _uuid: &uuid::Uuid,
+                _csr: &str,
+                _host_name: &str,
+            ) -> AnyhowResult<agent_receiver_api::RegisterExistingResponse> {
+                self.calls.set(self.calls.get() + 1);
+                Err(
+                    agent_receiver_api::ResponseError::new(reqwest::StatusCode::UNAUTHORIZED, None)
+                        .into(),
+                )
+            }
+
+            fn register_new(
+                &self,
+                _base_url: &reqwest::Url,
+                _root_cert: &Option<&str>,
+                _credentials: &types::Credentials,
+                _uuid: &uuid::Uuid,
+                _csr: &str,
+                _new_host: &agent_receiver_api::NewHost,
+            ) -> AnyhowResult<agent_receiver_api::RegisterNewResponse> {
+                bail!("New hosts are not registered in this test")
+            }
+
+            fn register_new_ongoing(
+                &self,
+                _base_url: &reqwest::Url,
+                _root_cert: &str,
+                _credentials: &types::Credentials,
+                _uuid: &uuid::Uuid,
+                _wait: u64,
+            ) -> AnyhowResult<agent_receiver_api::RegisterNewOngoingResponse> {
+                bail!("New hosts are not registered in this test")
+            }
+        }
+
+        #[test]
+        fn test_rejected_credentials_are_throttled() {
+            let mut r = TestRegistry::new();
+            let registry = &mut r.registry;
+            let api = RejectingApi::default();
+            let register = |registry: &mut config::Registry| {
+                direct_registration(
+                    &registration_connection_config(
+                        Some(String::from("root_cert")),
+                        Some(String::from("password")),
+                        false,
+                    ),
+                    registry,
+                    &api,
+                    &UnattendedTrust {},
+                    &RegistrationCallExisting {
+                        host_name: HOST_NAME,
+                    },
+                )
+                .unwrap_err()
+                .to_string()
+            };
+            assert!(register(registry).contains("Error registering existing host"));
+            assert!(register(registry).contains("Error registering existing host"));
+            assert!(register(registry).contains("next attempt is possible in"));
+            assert_eq!(api.calls.get(), 2);
+            assert!(registry.registration_throttle_path().exists());
+        }
+
+        #[test]
+        fn test_new() {
+            let mut r = TestRegistry::new();
+            let registry = &mut r.registry;
+            assert!(!registry.path().exists());
+            assert!(direct_registration(
+                &registration_connection_config(
+                    Some(String::from("root_certificate")),
+                    Some(String::from("password")),
+                    false
+                ),
+                registry,
+                &MockApi {
+                    expect_root_cert: true,
+                    expected_registration_method: Some(RegistrationMethod::New),
+                },
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: false,
+                },
+                &RegistrationCallNew {
+                    agent_labels: &agent_labels(),
+                    approval: &config::ApprovalConfig::default(),
+                },
+            )
+            .is_ok());
+            assert!(!registry.is_empty());
+            assert!(registry.path().exists());
+        }
+
+        #[test]
+        fn test_proxy() {
+            assert!(proxy_registration(
+                &config::RegisterExistingConfig {
+                    connection_config: registration_connection_config(None, None, true),
+                    host_name: String::from(HOST_NAME),
+                    host_creation: None,
+                    keep_credentials: false,
+                },
+                &MockApi {
+                    expect_root_cert: false,
+                    expected_registration_method: Some(RegistrationMethod::Existing),
+                },
+                &MockHostManagement::default(),
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: true,
+                },
+            )
+            .is_ok());
+        }
+    }
+
+    mod test_set_up_host {
+        use super::*;
+
+        fn register_existing_config(
+            host_creation: Option<config::HostCreationConfig>,
+        ) -> config::RegisterExistingConfig {
+            config::RegisterExistingConfig {
+                connection_config: registration_connection_config(None, None, true),
+                host_name: String::from(HOST_NAME),
+                host_creation,
+                keep_credentials: false,
+            }
+        }
+
+        fn host_creation(
+            username: Option<&str>,
+            password: Option<&str>,
+        ) -> config::HostCreationConfig {
+            config::HostCreationConfig {
+                api_url: reqwest::Url::parse("https://server/site/check_mk/api/1.0").unwrap(),
+                folder: None,
+                labels: agent_labels(),
+                username: username.map(String::from),
+                password: password.map(String::from),
+            }
+        }
+
+        #[test]
+        fn test_no_host_creation() {
+            let host_management = MockHostManagement::default();
+            let connection_config = set_up_host(
+                &register_existing_config(None),
+                &host_management,
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: false,
+                },
+            )
+            .unwrap();
+            assert!(connection_config.password.is_none());
+            assert!(host_management.users.into_inner().is_empty());
+        }
+
+        #[test]
+        fn test_registration_credentials() {
+            let host_management = MockHostManagement::default();
+            let connection_config = set_up_host(
+                &register_existing_config(Some(host_creation(None, None))),
+                &host_management,
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: true,
+                },
+            )
+            .unwrap();
+            assert_eq!(host_management.users.into_inner(), vec![USERNAME; 2]);
+            // The password is not prompted for again when registering
+            assert_eq!(connection_config.password.as_deref(), Some("password"));
+            assert!(prepare_registration(
+                &connection_config,
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: false,
+                },
+            )
+            .is_ok());
+        }
+
+        #[test]
+        fn test_api_credentials() {
+            let host_management = MockHostManagement::default();
+            let connection_config = set_up_host(
+                &register_existing_config(Some(host_creation(Some("automation"), Some("secret")))),
+                &host_management,
+                &MockInteractiveTrust {
+                    expect_server_cert_prompt: false,
+                    expect_password_prompt: false,
+                },
+            )
+            .unwrap();
+            assert_eq!(host_management.users.into_inner(), vec!["automation"; 2]);
+            assert!(connection_config.password.is_none());
+        }
+    }
+
+    mod test_register_pre_configured {
+        use super::*;
+
+        fn registry() -> TestRegistry {
+            TestRegistry::new()
+                .add_connection(
+                    &config::ConnectionMode::Pull,
+                    "server/pre-baked-pull-site",
+                    uuid::Uuid::new_v4(),
+                )
+                .add_connection(
+                    &config::ConnectionMode::Pull,
+                    "server/other-pull-site",
+                    uuid::Uuid::new_v4(),
+                )
+                .add_connection(
+                    &config::ConnectionMode::Push,
+                    "server/pre-baked-push-site",
+                    uuid::Uuid::new_v4(),
+                )
+                .add_connection(
+                    &config::ConnectionMode::Push,
+                    "server/other-push-site",
+                    uuid::Uuid::new_v4(),
+                )
+                .add_imported_connection(uuid::Uuid::new_v4())
+        }
+
+        fn pre_configured_connections(
+            keep_existing_connections: bool,
+        ) -> config::PreConfiguredConnections {
+            config::PreConfiguredConnections {
+                connections: std::collections::HashMap::from([
+                    (
+                        site_spec::SiteID::from_str("server/pre-baked-pull-site").unwrap(),
+                        config::PreConfiguredConnection {
+                            port: Some(1001),
+                            credentials: types::Credentials {
+                                username: String::from("user"),
+                                password: String::from("password"),
+                            },
+                            root_cert: String::from("root_cert"),
+                        },
+                    ),
+                    (
+                        site_spec::SiteID::from_str("server/pre-baked-pull-site-2").unwrap(),
+                        config::PreConfiguredConnection {
+                            port: Some(1002),
+                            credentials: types::Credentials {
+                                username: String::from("user"),
+                                password: String::from("password"),
+                            },
+                            root_cert: String::from("root_cert"),
+                        },
+                    ),
+                    (
+                        site_spec::SiteID::from_str("server/pre-baked-push-site").unwrap(),
+                        config::PreConfiguredConnection {
+                            port: Some(1003),
+                            credentials: types::Credentials {
+                                username: String::from("user"),
+                                password: String::from("password"),
+                            },
+                            root_cert: String::from("root_cert"),
+                        },
+                    ),
+                    (
+                        site_spec::SiteID::from_str("server/pre-baked-push-site-2").unwrap(),
+                        config::PreConfiguredConnection {
+                            port: Some(1004),
+                            credentials: types::Credentials {
+                                username: String::from("user"),
+                                password: String::from("password"),
+                            },
+                            root_cert: String::from("root_cert"),
+                        },
+                    ),
+                ]),
+                agent_labels: types::AgentLabels::from([(
+                    String::from("key"),
+                    String::from("value"),
+                )]),
+                keep_existing_connections,
+            }
+        }
+
+        struct MockRegistrationPreConfiguredImpl {
+            is_registered_at_remote: bool,
+        }
+
+        impl RegistrationPreConfigured for MockRegistrationPreConfiguredImpl {
+            fn register(
+                &self,
+                config: &config::RegisterNewConfig,
+                registry: &mut config::Registry,
+            ) -> AnyhowResult<()> {
+                assert!(config.connection_config.password.is_some());
+                assert!(config.connection_config.root_certificate.is_some());
+                assert!(!config.connection_config.trust_server_cert);
+                assert_eq!(config.agent_labels.get("key").unwrap(), "value");
+                registry.register_connection(
+                    std::collections::HashMap::from([
+                        ("server/pre-baked-pull-site", config::ConnectionMode::Pull),
+                        ("server/pre-baked-pull-site-2", config::ConnectionMode::Pull),
+                        ("server/pre-baked-push-site", config::ConnectionMode::Push),
+                        ("server/pre-baked-push-site-2", config::ConnectionMode::Push),
+                    ])
+                    .get(config.connection_config.site_id.to_string().as_str())
+                    .unwrap(),
+                    &config.connection_config.site_id,
+                    config::TrustedConnectionWithRemote {
+                        trust: config::TrustedConnection {
+                            uuid: uuid::Uuid::new_v4(),
+                            private_key: String::from("private_key"),
+                            certificate: String::from("certificate"),
+                            root_cert: String::from("root_cert"),
+                            source_address: None,
+                            receiver_protocol: Default::default(),
+                            transport: Default::default(),
+                            data_source: None,
+                        },
+                        receiver_port: config.connection_config.receiver_port,
+                        push_interval: None,
+                        hostname: None,
+                        tags: BTreeMap::new(),
+                        expires: None,
+                    },
+                );
+                Ok(())
+            }
+
+            fn registration_status_v2(
+                &self,
+                _site_id: &site_spec::SiteID,
+                _connection: &config::TrustedConnectionWithRemote,
+                _client_config: &config::ClientConfig,
+            ) -> AnyhowResult<agent_receiver_api::RegistrationStatusV2Response> {
+                Ok(if self.is_registered_at_remote {
+                    agent_receiver_api::RegistrationStatusV2Response::Registered(
+                        agent_receiver_api::RegistrationStatusV2ResponseRegistered {
+                            hostname: String::from("my-host"),
+                            connection_mode: config::ConnectionMode::Pull,
+                        },
+                    )
+                } else {
+                    agent_receiver_api::RegistrationStatusV2Response::NotRegistered
+                })
+            }
+        }
+
+        fn test_registered_standard_connections<'a>(
+            expected_site_ids: impl Iterator<Item = &'a str>,
+            registered_connections: impl Iterator<
+                Item = (
+                    &'a site_spec::SiteID,
+                    &'a config::TrustedConnectionWithRemote,
+                ),
+            >,
+        ) {
+            let mut exp_site_ids: Vec<&str> = expected_site_ids.collect();
+            exp_site_ids.sort_unstable();
+            let mut reg_site_ids: Vec<String> = registered_connections
+                .map(|site_id_and_conn| site_id_and_conn.0.to_string())
+                .collect();
+            reg_site_ids.sort_unstable();
+            assert_eq!(exp_site_ids, reg_site_ids);
+        }
+
+        fn test_registry_after_registration(
+            keep_existing_connections: bool,
+            registry: &mut config::Registry,
+        ) {
+            // We reload to ensure that the changes were written to disk
+            let mut registry = config::Registry::from_file(registry.path()).unwrap();
+
+            let mut expected_pull_site_ids =
+                vec!["server/pre-baked-pull-site", "server/pre-baked-pull-site-2"];
+            let mut expected_push_site_ids =
+                vec!["server/pre-baked-push-site", "server/pre-baked-push-site-2"];
+            if keep_existing_connections {
+                expected_pull_site_ids.push("server/other-pull-site");
+                expected_push_site_ids.push("server/other-push-site");
+            }
+            test_registered_standard_connections(
+                expected_pull_site_ids.into_iter(),
+                registry.get_standard_pull_connections(),
+            );
+            test_registered_standard_connections(
+                expected_push_site_ids.into_iter(),
+                registry.get_push_connections(),
+            );
+
+            assert_eq!(
+                registry
+                    .get_connection_as_mut(
+                        &site_spec::SiteID::from_str("server/pre-baked-pull-site").unwrap()
+                    )
+                    .unwrap()
+                    .receiver_port,
+                1001
+            );
+            assert_eq!(
+                registry
+                    .get_connection_as_mut(
+                        &site_spec::SiteID::from_str("server/pre-baked-push-site").unwrap()
+                    )
+                    .unwrap()
+                    .receiver_port,
+                1003
+            );
+
+            assert_eq!(
+                registry.get_imported_pull_connections().count(),
+                match keep_existing_connections {
+                    true => 1,
+                    false => 0,
+                },
+            );
+        }
+
+        #[test]
+        fn test_keep_existing_connections() {
+            let mut r = registry();
+            assert!(_register_pre_configured(
+                &pre_configured_connections(true),
+                &config::ClientConfig {
+                    proxy_mode: proxy::ProxyMode::Direct,
+                    validate_api_cert: false,
+                    source_address: None,
+                    ip_preference: happy_eyeballs::IpPreference::Ipv6,
+                    request_headers: Default::default(),
+                    timeouts: Default::default(),
+                },
+                &mut r.registry,
+                &MockRegistrationPreConfiguredImpl {
+                    is_registered_at_remote: true
+                },
+            )
+            .is_ok());
+            test_registry_after_registration(true, &mut r.registry)
+        }
+
+        #[test]
+        fn test_remove_vanished_connections() {
+            let mut r = registry();
+            assert!(_register_pre_configured(
+                &pre_configured_connections(false),
+                &config::ClientConfig {
+                    proxy_mode: proxy::ProxyMode::Direct,
+                    validate_api_cert: false,
+                    source_address: None,
+                    ip_preference: happy_eyeballs::IpPreference::Ipv6,
+                    request_headers: Default::default(),
+                    timeouts: Default::default(),
+                },
+                &mut r.registry,
+                &MockRegistrationPreConfiguredImpl {
+                    is_registered_at_remote: true
+                },
+            )
+            .is_ok());
+            test_registry_after_registration(false, &mut r.registry)
+        }
+
+        #[test]
+        fn test_port_update_only() {
+            let mut r = TestRegistry::new();
+            let registry = &mut r.registry;
+            registry.register_connection(
+                &config::ConnectionMode::Pull,
+                &site_spec::SiteID::from_str("server/pre-baked-pull-site").unwrap(),
+                config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4()),
+            );
+            let pre_configured_connections = config::PreConfiguredConnections {
+                connections: std::collections::HashMap::from([(
+                    site_spec::SiteID::from_str("server/pre-baked-pull-site").unwrap(),
+                    config::PreConfiguredConnection {
+                        port: Some(1001),
+                        credentials: types::Credentials {
+                            username: String::from("user"),
+                            password: String::from("password"),
+                        },
+                        root_cert: String::from("root_cert"),
+                    },
+                )]),
+                agent_labels: types::AgentLabels::from([(
+                    String::from("key"),
+                    String::from("value"),
+                )]),
+                keep_existing_connections: true,
+            };
+            assert!(_register_pre_configured(
+                &pre_configured_connections,
+                &config::ClientConfig {
+                    proxy_mode: proxy::ProxyMode::Direct,
+                    validate_api_cert: false,
+                    source_address: None,
+                    ip_preference: happy_eyeballs::IpPreference::Ipv6,
+                    request_headers: Default::default(),
+                    timeouts: Default::default(),
+                },
+                registry,
+                &MockRegistrationPreConfiguredImpl {
+                    is_registered_at_remote: true
+                },
+            )
+            .is_ok());
+
+            // We reload to ensure that the changes were written to disk
+            let mut registry = config::Registry::from_file(registry.path()).unwrap();
+            assert_eq!(
+                registry
+                    .get_connection_as_mut(
+                        &site_spec::SiteID::from_str("server/pre-baked-pull-site").unwrap()
+                    )
+                    .unwrap()
+                    .receiver_port,
+                1001
+            );
+        }
+
+        #[test]
+        fn test_registered_locally_but_not_at_remote() -> AnyhowResult<()> {
+            let mut r = registry();
+            let registry_before_registration = r.registry.clone();
+            let pre_configured_connections = pre_configured_connections(true);
+            assert!(_register_pre_configured(
+                &pre_configured_connections,
+                &config::ClientConfig {
+                    proxy_mode: proxy::ProxyMode::Direct,
+                    validate_api_cert: false,
+                    source_address: None,
+                    ip_preference: happy_eyeballs::IpPreference::Ipv6,
+                    request_headers: Default::default(),
+                    timeouts: Default::default(),
+                },
+                &mut r.registry,
+                &MockRegistrationPreConfiguredImpl {
+                    is_registered_at_remote: false
+                },
+            )
+            .is_ok());
+
+            // for any connection which was already present before registering the pre-configured
+            // connections, we expect the uuid to change, because the remote says that we are not
+            // registered --> re-registration
+            for site_id in pre_configured_connections.connections.keys() {
+                if let Some(connection_before_registration) =
+                    registry_before_registration.get(site_id)
+                {
+                    assert_ne!(
+                        connection_before_registration.trust.uuid,
+                        r.registry.get(site_id).unwrap().trust.uuid
+                    );
+                }
+            }
+            Ok(())
+        }
+    }
+
+    mod test_await_approval {
+        use super::*;
+
+        const UUID: &str = "99f56bbc-5965-4b34-bc70-1959ad1d32d6";
+
+        fn await_responses(
+            approval: &config::ApprovalConfig,
+            mut responses: Vec<agent_receiver_api::RegisterNewOngoingResponse>,
+        ) -> (
+            AnyhowResult<agent_receiver_api::RegisterNewOngoingResponseSuccess>,
+            Vec<u64>,
+        ) {
+            let mut waits = vec![];
+            responses.reverse();
+            let result = await_approval(
+                approval,
+                Duration::ZERO,
+                &uuid::Uuid::from_str(UUID).unwrap(),
+                |wait| {
+                    waits.push(wait);
+                    Ok(responses.pop().unwrap())
+                },
+            );
+            (result, waits)
+        }
+
+        #[test]
+        fn test_declined() {
+            let (result, waits) = await_responses(
+                &config::ApprovalConfig::default(),
+                vec![
+                    agent_receiver_api::RegisterNewOngoingResponse::InProgress,
+                    agent_receiver_api::RegisterNewOngoingResponse::Declined(
+                        agent_receiver_api::RegisterNewOngoingResponseDeclined {
+                            reason: String::from("Unknown host"),
+                        },
+                    ),
+                ],
+            );
+            let err = result.err().unwrap();
+            assert_eq!(
+                error_code::ErrorCode::of(&err),
+                error_code::ErrorCode::RegistrationDeclined
+            );
+            assert!(err.to_string().contains("Unknown host"));
+            assert_eq!(waits, vec![constants::APPROVAL_LONG_POLL_WAIT; 2]);
+        }
+
+        #[test]
+        fn test_timeout() {
+            let (result, waits) = await_responses(
+                &config::ApprovalConfig {
+                    timeout: Some(0),
+                    ..Default::default()
+                },
+                vec![agent_receiver_api::RegisterNewOngoingResponse::InProgress],
+            );
+            assert_eq!(
+                error_code::ErrorCode::of(&result.err().unwrap()),
+                error_code::ErrorCode::ApprovalTimeout
+            );
+            assert_eq!(waits, vec![0]);
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn test_hook() {
+            use std::os::unix::fs::PermissionsExt;
+            let dir = tempfile::tempdir().unwrap();
+            let hook = dir.path().join("hook");
+            let decision = dir.path().join("decision");
+            std::fs::write(
+                &hook,
+                format!(
+                    "#!/bin/sh\necho \"$CMK_APPROVAL_DECISION $CMK_APPROVAL_UUID\" > {}\n",
+                    decision.display()
+                ),
+            )
+            .unwrap();
+            std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+            let (result, _) = await_responses(
+                &config::ApprovalConfig {
+                    hook: Some(hook),
+                    ..Default::default()
+                },
+                vec![agent_receiver_api::RegisterNewOngoingResponse::Success(
+                    agent_receiver_api::RegisterNewOngoingResponseSuccess {
+                        agent_cert: String::from("agent_cert"),
+                        connection_mode: config::ConnectionMode::Push,
+                    },
+                )],
+            );
+            assert_eq!(result.unwrap().agent_cert, "agent_cert");
+            assert_eq!(
+                std::fs::read_to_string(decision).unwrap(),
+                format!("approved {UUID}\n")
+            );
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::registration::{self, ProxyPullData};
+use crate::relay::{self, Relay, RelayedAgent};
+use crate::{cli, config, constants};
+use anyhow::{Context, Result as AnyhowResult};
+use std::path::Path;
+
+pub fn relay(
+    relay_path: &Path,
+    runtime_config: config::RuntimeConfig,
+    action: cli::RelayAction,
+) -> AnyhowResult<()> {
+    let mut relay = Relay::from_file(relay_path)
+        .with_context(|| format!("Error while loading paired agents from {:?}.", relay_path))?;
+    match action {
+        cli::RelayAction::Pair(pair_opts) => {
+            let connection = relay.pair(&pair_opts.agent_opts.name, &pair_opts.address)?;
+            relay.save()?;
+            println!(
+                "{}",
+                serde_json::to_string(&ProxyPullData {
+                    agent_controller_version: String::from(constants::VERSION),
+                    connection,
+                })?
+            );
+            Ok(())
+        }
+        cli::RelayAction::Register(register_opts) => {
+            let name = register_opts.agent_opts.name;
+            // Before registering with the site
+            relay.agent(&name)?;
+            let config = config::RegisterExistingConfig::new_for_other_host(
+                runtime_config,
+                register_opts.register_opts,
+            )?;
+            let connection = registration::relay_register(&config)?;
+            relay.register(&name, config.connection_config.site_id, connection)?;
+            relay.save()?;
+            println!("Registration complete.");
+            Ok(())
+        }
+        cli::RelayAction::Remove(agent_opts) => {
+            relay.remove(&agent_opts.name)?;
+            relay.save()?;
+            println!("Removed agent '{}'", agent_opts.name);
+            Ok(())
+        }
+        cli::RelayAction::List => {
+            println!("{}", render_agents(relay.agents()));
+            Ok(())
+        }
+        cli::RelayAction::Serve(serve_opts) => serve_runtime_wrapper(
+            relay_path,
+            config::RelayConfig::new(&runtime_config, &serve_opts),
+        ),
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn serve_runtime_wrapper(relay_path: &Path, config: config::RelayConfig) -> AnyhowResult<()> {
+    relay::serve(relay_path, config).await
+}
+
+fn render_agents<'a>(agents: impl Iterator<Item = (&'a String, &'a RelayedAgent)>) -> String {
+    let lines: Vec<String> = agents
+        .map(|(name, agent)| {
+            format!(
+                "{}: {}:{}, paired as {}, {}",
+                name,
+                agent.server,
+                agent.port,
+                agent.pairing_uuid,
+                match &agent.site {
+                    Some(site) => format!(
+                        "registered with {} as {}",
+                        site.site_id, site.connection.uuid
+                    ),
+                    None => String::from("not registered"),
+                }
+            )
+        })
+        .collect();
+    if lines.is_empty() {
+        return String::from("No paired agents");
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::site_spec;
+    use std::str::FromStr;
+
+    fn agent(site: Option<relay::SiteConnection>) -> RelayedAgent {
+        RelayedAgent {
+            server: String::from("10.0.0.1"),
+            port: 6556,
+            pairing_uuid: uuid::Uuid::from_str("8a5cc1e4-5ec7-4b51-9fa2-0f2da6bd0b0b").unwrap(),
+            site,
+        }
+    }
+
+    #[test]
+    fn test_render_agents() {
+        let registered = agent(Some(relay::SiteConnection {
+            site_id: site_spec::SiteID::from_str("server/site").unwrap(),
+            connection: config::TrustedConnection {
+                uuid: uuid::Uuid::from_str("2da53af5-5c06-4195-ab6f-668875710bec").unwrap(),
+                private_key: String::from("private_key"),
+                certificate: String::from("certificate"),
+                root_cert: String::from("root_cert"),
+                source_address: None,
+                receiver_protocol: Default::default(),
+                transport: Default::default(),
+                data_source: None,
+            },
+        }));
+        let unregistered = agent(None);
+        let (name_a, name_b) = (String::from("a"), String::from("b"));
+        assert_eq!(
+            render_agents([(&name_a, &registered), (&name_b, &unregistered)].into_iter()),
+            "a: 10.0.0.1:6556, paired as 8a5cc1e4-5ec7-4b51-9fa2-0f2da6bd0b0b, registered with \
+             server/site as 2da53af5-5c06-4195-ab6f-668875710bec\n\
+             b: 10.0.0.1:6556, paired as 8a5cc1e4-5ec7-4b51-9fa2-0f2da6bd0b0b, not registered"
+        );
+        assert_eq!(render_agents([].into_iter()), "No paired agents");
+    }
+}
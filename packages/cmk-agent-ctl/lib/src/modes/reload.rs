@@ -0,0 +1,18 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::ipc;
+use anyhow::{bail, Result as AnyhowResult};
+use std::path::Path;
+
+pub fn reload(path_control_socket: &Path) -> AnyhowResult<()> {
+    match ipc::request(path_control_socket, &ipc::Request::Reload)? {
+        ipc::Response::Reloaded { log_level } => {
+            println!("Daemon reloaded its config, log level is now '{log_level}'");
+            Ok(())
+        }
+        ipc::Response::Error { message } => bail!(message),
+        response => bail!("Unexpected response from daemon: {:?}", response),
+    }
+}
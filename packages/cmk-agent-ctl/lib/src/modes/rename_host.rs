@@ -0,0 +1,229 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::{renew_certificate, test_connection};
+use crate::agent_receiver_api::{self, HostRename};
+use crate::config::{self, JSONLoaderMissingSafe};
+use crate::reregistration::ReregistrationCredentials;
+use crate::{cli, misc, output, site_spec, types};
+use anyhow::{Context, Result as AnyhowResult};
+use serde::Serialize;
+
+/// Outcome of a renaming, as written with --output
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Renamed {
+    pub site_id: String,
+    pub uuid: String,
+    pub host_name: String,
+}
+
+impl std::fmt::Display for Renamed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Connection to '{}' ({}) now belongs to host '{}'",
+            self.site_id, self.uuid, self.host_name
+        )
+    }
+}
+
+/// Assign the connection to another host at its site, then update the host name kept locally.
+/// The local state is only touched once the site accepted the new name.
+fn rename(
+    registry: &mut config::Registry,
+    ident: &str,
+    host_name: &str,
+    credentials: &types::Credentials,
+    rename_api: &impl HostRename,
+) -> AnyhowResult<Renamed> {
+    let (connection, site_id) = renew_certificate::find_site_for_ident(registry, ident)?;
+    let url = site_spec::make_site_url(&site_id, &connection.receiver_port)?;
+    rename_api
+        .rename_host(
+            &url,
+            &connection.trust.root_cert,
+            credentials,
+            &connection.trust.uuid,
+            host_name,
+        )
+        .context(format!("Error renaming host at {site_id}"))?;
+    let renamed = Renamed {
+        site_id: site_id.to_string(),
+        uuid: connection.trust.uuid.to_string(),
+        host_name: String::from(host_name),
+    };
+    if connection.record_hostname(host_name, misc::unix_now()) {
+        registry.save()?;
+    }
+    update_stored_credentials(registry, &site_id, host_name)?;
+    Ok(renamed)
+}
+
+/// Re-registrations have to use the new name, otherwise they would register the old host again
+fn update_stored_credentials(
+    registry: &config::Registry,
+    site_id: &site_spec::SiteID,
+    host_name: &str,
+) -> AnyhowResult<()> {
+    let path = registry.reregistration_credentials_path();
+    let mut stored = ReregistrationCredentials::load_missing_safe(&path)?;
+    let Some(site_credentials) = stored.get(site_id) else {
+        return Ok(());
+    };
+    let mut site_credentials = site_credentials.clone();
+    site_credentials.host_name = String::from(host_name);
+    stored.insert(site_id, site_credentials);
+    stored
+        .save(&path)
+        .context("Failed to update stored credentials for re-registration")
+}
+
+pub fn rename_host(
+    registry: &mut config::Registry,
+    runtime_config: config::RuntimeConfig,
+    rename_host_opts: cli::RenameHostOpts,
+    output_format: cli::OutputFormat,
+) -> AnyhowResult<()> {
+    let client_config =
+        config::ClientConfig::new(runtime_config, rename_host_opts.client_opts, None);
+    let credentials = types::Credentials {
+        password: match rename_host_opts.password {
+            Some(password) => password,
+            None => test_connection::prompt_password(&rename_host_opts.user)?,
+        },
+        username: rename_host_opts.user,
+    };
+    let renamed = rename(
+        registry,
+        &rename_host_opts.connection_opts.connection,
+        &rename_host_opts.host_name,
+        &credentials,
+        &agent_receiver_api::Api::new(&client_config),
+    )?;
+    output::print(output_format, &renamed, &renamed.to_string())
+}
+
+#[cfg(test)]
+mod test_rename_host {
+    use super::*;
+    use crate::config::JSONLoader;
+    use crate::reregistration::StoredCredentials;
+    use config::test_helpers::TestRegistry;
+    use std::cell::RefCell;
+    use std::str::FromStr;
+
+    const UUID: &str = "0096abd7-83c9-42f8-8b3a-3ffba7ba959d";
+
+    #[derive(Default)]
+    struct MockRenameApi {
+        failing: bool,
+        renamed: RefCell<Vec<(String, String)>>,
+    }
+
+    impl HostRename for MockRenameApi {
+        fn rename_host(
+            &self,
+            base_url: &reqwest::Url,
+            _root_cert: &str,
+            _credentials: &types::Credentials,
+            uuid: &uuid::Uuid,
+            host_name: &str,
+        ) -> AnyhowResult<()> {
+            if self.failing {
+                anyhow::bail!("forbidden")
+            }
+            assert_eq!(base_url.as_str(), "https://server:8000/site");
+            self.renamed
+                .borrow_mut()
+                .push((uuid.to_string(), String::from(host_name)));
+            Ok(())
+        }
+    }
+
+    fn credentials() -> types::Credentials {
+        types::Credentials {
+            username: String::from("automation"),
+            password: String::from("secret"),
+        }
+    }
+
+    fn stored_credentials(host_name: &str) -> StoredCredentials {
+        StoredCredentials {
+            username: String::from("automation"),
+            password: String::from("secret"),
+            host_name: String::from(host_name),
+        }
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut r = TestRegistry::new().add_connection(
+            &config::ConnectionMode::Push,
+            "server/site",
+            config::TrustedConnectionWithRemote::from(UUID),
+        );
+        let site_id = site_spec::SiteID::from_str("server/site").unwrap();
+        let mut stored = ReregistrationCredentials::default();
+        stored.insert(&site_id, stored_credentials("web01"));
+        stored
+            .save(&r.registry.reregistration_credentials_path())
+            .unwrap();
+
+        let failing = MockRenameApi {
+            failing: true,
+            ..MockRenameApi::default()
+        };
+        assert!(rename(
+            &mut r.registry,
+            "server/site",
+            "web02",
+            &credentials(),
+            &failing
+        )
+        .is_err());
+        assert!(r.registry.get(&site_id).unwrap().hostname.is_none());
+
+        let api = MockRenameApi::default();
+        let renamed = rename(&mut r.registry, UUID, "web02", &credentials(), &api).unwrap();
+        assert_eq!(
+            renamed,
+            Renamed {
+                site_id: String::from("server/site"),
+                uuid: String::from(UUID),
+                host_name: String::from("web02"),
+            }
+        );
+        assert_eq!(
+            api.renamed.take(),
+            [(String::from(UUID), String::from("web02"))]
+        );
+        assert_eq!(
+            config::Registry::from_file(r.registry.path())
+                .unwrap()
+                .get(&site_id)
+                .unwrap()
+                .hostname
+                .as_ref()
+                .unwrap()
+                .hostname,
+            "web02"
+        );
+        assert_eq!(
+            ReregistrationCredentials::load(&r.registry.reregistration_credentials_path())
+                .unwrap()
+                .get(&site_id),
+            Some(&stored_credentials("web02"))
+        );
+
+        assert!(rename(
+            &mut r.registry,
+            "server/unknown",
+            "web03",
+            &credentials(),
+            &api
+        )
+        .is_err());
+        assert!(api.renamed.take().is_empty());
+    }
+}
@@ -2,23 +2,24 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
-use crate::{agent_receiver_api, certs, config, constants, misc, site_spec};
-use anyhow::{anyhow, bail, Result as AnyhowResult};
+use crate::{
+    agent_receiver_api, certs, config, constants, error_code, misc,
+    scheduler_state::SchedulerState, site_spec, usage_stats,
+};
+use anyhow::{bail, Result as AnyhowResult};
 use log::{debug, info, warn};
 use std::str::FromStr;
-use std::thread;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use x509_parser;
 
 pub fn renew_certificate(
-    mut registry: config::Registry,
+    registry: &mut config::Registry,
     ident: &str,
     client_config: config::ClientConfig,
 ) -> AnyhowResult<()> {
-    let renew_certificate_api = agent_receiver_api::Api {
-        use_proxy: client_config.use_proxy,
-    };
-    _renew_certificate(&mut registry, ident, &renew_certificate_api)
+    let renew_certificate_api = agent_receiver_api::Api::new(&client_config);
+    _renew_certificate(registry, ident, &renew_certificate_api)
 }
 
 fn _renew_certificate(
@@ -34,7 +35,11 @@ fn _renew_certificate(
     Ok(())
 }
 
-fn find_site_for_ident<'reg>(
+fn unknown_connection(message: String) -> anyhow::Error {
+    error_code::CodedError::new(error_code::ErrorCode::UnknownConnection, message).into()
+}
+
+pub fn find_site_for_ident<'reg>(
     registry: &'reg mut config::Registry,
     ident: &str,
 ) -> AnyhowResult<(
@@ -43,14 +48,17 @@ fn find_site_for_ident<'reg>(
 )> {
     let site_id = site_id_from_ident(registry, ident)?;
     Ok((
-        registry
-            .get_connection_as_mut(&site_id)
-            .ok_or_else(|| anyhow!("Couldn't find connection with site ID {}", site_id))?,
+        registry.get_connection_as_mut(&site_id).ok_or_else(|| {
+            unknown_connection(format!("Couldn't find connection with site ID {site_id}"))
+        })?,
         site_id,
     ))
 }
 
-fn site_id_from_ident(registry: &config::Registry, ident: &str) -> AnyhowResult<site_spec::SiteID> {
+pub fn site_id_from_ident(
+    registry: &config::Registry,
+    ident: &str,
+) -> AnyhowResult<site_spec::SiteID> {
     if let Ok(site_id) = site_spec::SiteID::from_str(ident) {
         return Ok(site_id);
     };
@@ -63,39 +71,50 @@ fn site_id_from_ident(registry: &config::Registry, ident: &str) -> AnyhowResult<
     };
     registry
         .retrieve_standard_connection_by_uuid(&uuid)
-        .ok_or_else(|| anyhow!("Couldn't find connection with UUID '{}'", ident))
+        .ok_or_else(|| unknown_connection(format!("Couldn't find connection with UUID '{ident}'")))
 }
 
-fn renew_connection_cert(
+pub fn renew_connection_cert(
     site_id: &site_spec::SiteID,
     connection: &mut config::TrustedConnectionWithRemote,
     renew_certificate_api: &impl agent_receiver_api::RenewCertificate,
 ) -> AnyhowResult<()> {
     let url = site_spec::make_site_url(site_id, &connection.receiver_port)?;
     let (csr, private_key) = certs::make_csr(&connection.trust.uuid.to_string())?;
-    let new_cert = renew_certificate_api.renew_certificate(&url, &connection.trust, csr)?;
+    let new_cert = renew_certificate_api.renew_certificate(&url, &connection.trust, csr);
+    usage_stats::record_renewal(new_cert.as_ref().err());
+    let new_cert = new_cert?;
     connection.trust.private_key = private_key;
     connection.trust.certificate = new_cert.agent_cert;
 
     Ok(())
 }
 
-pub fn daemon(
+pub async fn daemon(
     mut registry: config::Registry,
     client_config: config::ClientConfig,
+    scheduler: SchedulerState,
 ) -> AnyhowResult<()> {
-    misc::sleep_randomly();
-    let renew_certificate_api = agent_receiver_api::Api {
-        use_proxy: client_config.use_proxy,
-    };
+    misc::sleep_randomly().await;
+    let renew_certificate_api = Arc::new(agent_receiver_api::Api::new(&client_config));
     loop {
         debug!("Checking registered connections for certificate expiry.");
         registry.refresh()?;
         let begin = Instant::now();
-        if let Err(error) = renew_all_certificates(&mut registry, &renew_certificate_api) {
+        let api = Arc::clone(&renew_certificate_api);
+        // The receiver API is blocking, the registry is handed to the blocking task and back
+        let (renewed_registry, result) = tokio::task::spawn_blocking(move || {
+            let result = renew_all_certificates(&mut registry, api.as_ref());
+            (registry, result)
+        })
+        .await?;
+        registry = renewed_registry;
+        if let Err(error) = result {
             warn!("Error running renew-certificate cycle. ({})", error);
         };
-        thread::sleep(Duration::from_secs(60 * 60 * 24).saturating_sub(begin.elapsed()));
+        let delay = Duration::from_secs(60 * 60 * 24).saturating_sub(begin.elapsed());
+        scheduler.record_renewal_check(misc::unix_now() + delay.as_secs());
+        tokio::time::sleep(delay).await;
     }
 }
 
@@ -302,6 +321,10 @@ mod test_renew_certificate {
         config::TrustedConnectionWithRemote {
             trust: new_trusted_connection(cert),
             receiver_port: 8000,
+            push_interval: None,
+            hostname: None,
+            tags: Default::default(),
+            expires: None,
         }
     }
 
@@ -311,6 +334,10 @@ mod test_renew_certificate {
             private_key: String::from("private_key"),
             certificate: cert,
             root_cert: String::from("root_cert"),
+            source_address: None,
+            receiver_protocol: Default::default(),
+            transport: Default::default(),
+            data_source: None,
         }
     }
 
@@ -391,7 +418,7 @@ mod test_renew_certificate {
     fn test_renew_all_certificates_legacy_pull_mode() -> AnyhowResult<()> {
         let mut r = TestRegistry::new();
         let reg = &mut r.registry;
-        reg.activate_legacy_pull()?;
+        reg.activate_legacy_pull(None)?;
         renew_all_certificates(reg, &TestApi {})?;
         assert!(reg.is_legacy_pull_active());
         Ok(())
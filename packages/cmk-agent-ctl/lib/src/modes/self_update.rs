@@ -0,0 +1,164 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::agent_receiver_api::{self, ControllerUpdate, ControllerUpdateResponse};
+use crate::self_update::{self};
+use crate::{cli, config, constants, misc, site_spec};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use log::{info, warn};
+use std::fs;
+
+/// The controller the sites offer, asking one after another until one answers. Nothing if that
+/// one has no newer version than the running one.
+fn query(
+    registry: &config::Registry,
+    update_api: &impl ControllerUpdate,
+    now: u64,
+) -> AnyhowResult<Option<(site_spec::SiteID, ControllerUpdateResponse)>> {
+    let mut asked = false;
+    for (site_id, connection) in registry
+        .get_push_connections()
+        .chain(registry.get_standard_pull_connections())
+        .filter(|(_, connection)| !connection.is_expired(now))
+    {
+        asked = true;
+        match site_spec::make_site_url(site_id, &connection.receiver_port).and_then(|url| {
+            update_api.controller_update(
+                &url,
+                &connection.trust,
+                &self_update::platform(),
+                constants::VERSION,
+            )
+        }) {
+            Ok(Some(update)) if self_update::is_newer(&update.version, constants::VERSION)? => {
+                return Ok(Some((site_id.clone(), update)))
+            }
+            Ok(_) => return Ok(None),
+            Err(err) => warn!(
+                "{}: Error asking for controller update. ({:#})",
+                site_id, err
+            ),
+        }
+    }
+    if !asked {
+        bail!("No registered connections to ask for an update")
+    }
+    bail!("None of the sites answered, see the warnings above")
+}
+
+pub fn self_update(
+    registry: &config::Registry,
+    runtime_config: config::RuntimeConfig,
+    client_opts: cli::ClientOpts,
+) -> AnyhowResult<()> {
+    let self_update_config = config::SelfUpdateConfig::new(&runtime_config)?;
+    let signing_key = fs::read(&self_update_config.signing_key).context(format!(
+        "Failed to read signing key {:?}",
+        self_update_config.signing_key
+    ))?;
+    let client_config = config::ClientConfig::new(runtime_config, client_opts, None);
+    let Some((site_id, update)) = query(
+        registry,
+        &agent_receiver_api::Api::new(&client_config),
+        misc::unix_now(),
+    )?
+    else {
+        println!("The controller is up to date.");
+        return Ok(());
+    };
+
+    info!("{} offers controller {}", site_id, update.version);
+    let binary = openssl::base64::decode_block(&update.binary)
+        .context("Failed to decode controller binary")?;
+    self_update::verify(
+        &openssl::base64::decode_block(&update.manifest)
+            .context("Failed to decode controller manifest")?,
+        &openssl::base64::decode_block(&update.signature)
+            .context("Failed to decode signature of controller manifest")?,
+        &signing_key,
+    )?
+    .check(&update.version, &binary)?;
+    let executable = std::env::current_exe()?;
+    self_update::install(&executable, &binary, |path| {
+        self_update::check_version(path, &update.version)
+    })?;
+    println!(
+        "Updated the controller from {} to {}, as offered by {}. The previous one is kept as {:?}.",
+        constants::VERSION,
+        update.version,
+        site_id,
+        self_update::backup_path(&executable)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_self_update {
+    use super::*;
+    use config::test_helpers::TestRegistry;
+    use std::str::FromStr;
+
+    struct MockUpdateApi {
+        version: Option<&'static str>,
+    }
+
+    impl ControllerUpdate for MockUpdateApi {
+        fn controller_update(
+            &self,
+            base_url: &reqwest::Url,
+            _connection: &config::TrustedConnection,
+            _platform: &str,
+            _version: &str,
+        ) -> AnyhowResult<Option<ControllerUpdateResponse>> {
+            if base_url.as_str().contains("unreachable") {
+                bail!("unreachable")
+            }
+            Ok(self.version.map(|version| ControllerUpdateResponse {
+                version: String::from(version),
+                binary: String::new(),
+                manifest: String::new(),
+                signature: String::new(),
+            }))
+        }
+    }
+
+    #[test]
+    fn test_query() {
+        let api = MockUpdateApi {
+            version: Some("99.0.0"),
+        };
+        assert!(query(&TestRegistry::new().registry, &api, 2000).is_err());
+
+        let r = TestRegistry::new()
+            .add_connection(
+                &config::ConnectionMode::Push,
+                "server/unreachable",
+                config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4()),
+            )
+            .add_connection(
+                &config::ConnectionMode::Pull,
+                "server/site",
+                config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4()),
+            );
+        let (site_id, update) = query(&r.registry, &api, 2000).unwrap().unwrap();
+        assert_eq!(site_id, site_spec::SiteID::from_str("server/site").unwrap());
+        assert_eq!(update.version, "99.0.0");
+
+        assert!(query(&r.registry, &MockUpdateApi { version: None }, 2000)
+            .unwrap()
+            .is_none());
+        let running = MockUpdateApi {
+            version: Some(constants::VERSION),
+        };
+        assert!(query(&r.registry, &running, 2000).unwrap().is_none());
+        let older = MockUpdateApi {
+            version: Some("2.2.0p20"),
+        };
+        assert!(query(&r.registry, &older, 2000).unwrap().is_none());
+        let unknown = MockUpdateApi {
+            version: Some("latest"),
+        };
+        assert!(query(&r.registry, &unknown, 2000).is_err());
+    }
+}
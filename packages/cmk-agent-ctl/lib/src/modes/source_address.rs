@@ -0,0 +1,70 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::renew_certificate;
+use crate::config;
+use anyhow::Result as AnyhowResult;
+use std::net::IpAddr;
+
+/// Bind the outgoing connections to the receiver of a single connection to a local address, or
+/// use the global setting again if no address is given.
+pub fn set_source_address(
+    registry: &mut config::Registry,
+    ident: &str,
+    source_address: Option<IpAddr>,
+) -> AnyhowResult<()> {
+    let (connection, site_id) = renew_certificate::find_site_for_ident(registry, ident)?;
+    connection.trust.source_address = source_address;
+    match source_address {
+        Some(address) => println!("Source address for '{site_id}' set to {address}"),
+        None => println!("Source address for '{site_id}' reset to global source address"),
+    }
+    registry.save()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::site_spec;
+    use config::test_helpers::TestRegistry;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_set_source_address() {
+        let mut registry = TestRegistry::new().add_connection(
+            &config::ConnectionMode::Push,
+            "server/site",
+            config::TrustedConnectionWithRemote::from("0096abd7-83c9-42f8-8b3a-3ffba7ba959d"),
+        );
+        let site_id = site_spec::SiteID::from_str("server/site").unwrap();
+        let address = IpAddr::from([192, 168, 0, 1]);
+        set_source_address(&mut registry.registry, "server/site", Some(address)).unwrap();
+        assert_eq!(
+            config::Registry::from_file(registry.registry.path())
+                .unwrap()
+                .get(&site_id)
+                .unwrap()
+                .trust
+                .source_address,
+            Some(address)
+        );
+        set_source_address(
+            &mut registry.registry,
+            "0096abd7-83c9-42f8-8b3a-3ffba7ba959d",
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            registry
+                .registry
+                .get(&site_id)
+                .unwrap()
+                .trust
+                .source_address,
+            None
+        );
+        assert!(set_source_address(&mut registry.registry, "server/unknown", None).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::{push, renew_certificate};
+use crate::connection_stats::ConnectionStats;
+use crate::push_spool::PushSpool;
+use crate::{cli, config, misc, output, site_spec};
+use anyhow::{bail, Result as AnyhowResult};
+use serde::Serialize;
+use std::time::{Duration, SystemTime};
+
+/// A spooled agent output, as written with --output
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct SpooledEntry {
+    /// Unix timestamp
+    collected_at: u64,
+    size: u64,
+}
+
+/// The spool of a push connection, as written with --output
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct ConnectionSpool {
+    site_id: String,
+    uuid: String,
+    entries: Vec<SpooledEntry>,
+}
+
+impl ConnectionSpool {
+    fn load(
+        push_spool: &PushSpool,
+        site_id: &site_spec::SiteID,
+        connection: &config::TrustedConnectionWithRemote,
+    ) -> AnyhowResult<Self> {
+        Ok(Self {
+            site_id: site_id.to_string(),
+            uuid: connection.trust.uuid.to_string(),
+            entries: push_spool
+                .entries(&connection.trust.uuid)?
+                .iter()
+                .map(|entry| {
+                    Ok(SpooledEntry {
+                        collected_at: entry.collected_at,
+                        size: entry.size()?,
+                    })
+                })
+                .collect::<AnyhowResult<_>>()?,
+        })
+    }
+
+    fn to_human_readable(&self, now: u64) -> String {
+        let total: u64 = self.entries.iter().map(|entry| entry.size).sum();
+        let mut lines = vec![format!(
+            "{} ({}): {} agent output(s), {}",
+            self.site_id,
+            self.uuid,
+            self.entries.len(),
+            misc::human_readable_bytes(total)
+        )];
+        for entry in &self.entries {
+            lines.push(format!(
+                "\t{} ({}, {} ago): {}",
+                entry.collected_at,
+                httpdate::fmt_http_date(
+                    SystemTime::UNIX_EPOCH + Duration::from_secs(entry.collected_at)
+                ),
+                misc::human_readable_duration(now.saturating_sub(entry.collected_at)),
+                misc::human_readable_bytes(entry.size)
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Push connections selected by site ID or UUID, all of them if none is given
+fn selected_connections(
+    registry: &config::Registry,
+    ident: Option<&str>,
+) -> AnyhowResult<Vec<(site_spec::SiteID, config::TrustedConnectionWithRemote)>> {
+    let site_id = ident
+        .map(|ident| renew_certificate::site_id_from_ident(registry, ident))
+        .transpose()?;
+    let connections: Vec<_> = registry
+        .get_push_connections()
+        .filter(|(id, _)| site_id.is_none() || site_id.as_ref() == Some(*id))
+        .map(|(site_id, connection)| (site_id.clone(), connection.clone()))
+        .collect();
+    if let (Some(site_id), true) = (site_id, connections.is_empty()) {
+        bail!("{site_id} is not a push connection");
+    }
+    Ok(connections)
+}
+
+fn list(
+    registry: &config::Registry,
+    push_spool: &PushSpool,
+    ident: Option<&str>,
+    format: cli::OutputFormat,
+) -> AnyhowResult<()> {
+    let spools = selected_connections(registry, ident)?
+        .iter()
+        .map(|(site_id, connection)| ConnectionSpool::load(push_spool, site_id, connection))
+        .collect::<AnyhowResult<Vec<_>>>()?;
+    let now = misc::unix_now();
+    output::print(
+        format,
+        &spools,
+        &match spools.is_empty() {
+            true => String::from("No push connections"),
+            false => spools
+                .iter()
+                .map(|spool| spool.to_human_readable(now))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        },
+    )
+}
+
+pub fn spool(
+    registry: &config::Registry,
+    runtime_config: config::RuntimeConfig,
+    push_config: &config::PushConfig,
+    connection_stats: ConnectionStats,
+    push_spool: PushSpool,
+    action: cli::SpoolAction,
+    format: cli::OutputFormat,
+) -> AnyhowResult<()> {
+    match action {
+        cli::SpoolAction::List(list_opts) => list(
+            registry,
+            &push_spool,
+            list_opts.connection.as_deref(),
+            format,
+        ),
+        cli::SpoolAction::Drop(drop_opts) => {
+            let mut dropped = 0;
+            for (_, connection) in
+                selected_connections(registry, Some(&drop_opts.connection_opts.connection))?
+            {
+                dropped +=
+                    push_spool.drop_entries(&connection.trust.uuid, drop_opts.collected_at)?;
+            }
+            println!("Dropped {dropped} spooled agent output(s)");
+            Ok(())
+        }
+        cli::SpoolAction::Replay(replay_opts) => {
+            let results = push::replay_spools(
+                selected_connections(registry, replay_opts.connection.as_deref())?,
+                &config::ClientConfig::new(runtime_config, replay_opts.client_opts, None),
+                push_config,
+                connection_stats,
+                push_spool,
+            )?;
+            let failed = results
+                .iter()
+                .filter(|result| result.error.is_some())
+                .count();
+            output::print(
+                format,
+                &results,
+                &results
+                    .iter()
+                    .map(|result| match &result.error {
+                        None => format!("{}: replayed", result.site_id),
+                        Some(error) => format!("{}: {}", result.site_id, error),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )?;
+            if failed > 0 {
+                bail!("Replaying failed for {failed} connection(s)");
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_spool {
+    use super::*;
+    use crate::config::test_helpers::TestRegistry;
+
+    const UUID: &str = "99f56bbc-5965-4b34-bc70-1959ad1d32d6";
+
+    #[test]
+    fn test_connection_spool() {
+        let dir = tempfile::tempdir().unwrap();
+        let push_spool = PushSpool::new(dir.path(), 10, None);
+        let connection = config::TrustedConnectionWithRemote::from(UUID);
+        push_spool
+            .spool(&connection.trust.uuid, 1000, b"data")
+            .unwrap();
+        push_spool
+            .spool(&connection.trust.uuid, 1060, b"more data")
+            .unwrap();
+        let spool = ConnectionSpool::load(
+            &push_spool,
+            &site_spec::SiteID {
+                server: String::from("server"),
+                site: String::from("site"),
+            },
+            &connection,
+        )
+        .unwrap();
+        assert_eq!(
+            spool.entries,
+            vec![
+                SpooledEntry {
+                    collected_at: 1000,
+                    size: 4
+                },
+                SpooledEntry {
+                    collected_at: 1060,
+                    size: 9
+                }
+            ]
+        );
+        assert_eq!(
+            spool.to_human_readable(1120),
+            format!(
+                "server/site ({UUID}): 2 agent output(s), 13 B\n\
+                 \t1000 (Thu, 01 Jan 1970 00:16:40 GMT, 2m ago): 4 B\n\
+                 \t1060 (Thu, 01 Jan 1970 00:17:40 GMT, 1m ago): 9 B"
+            )
+        );
+    }
+
+    #[test]
+    fn test_selected_connections() {
+        let registry = TestRegistry::new().fill_registry();
+        assert_eq!(
+            selected_connections(&registry.registry, None)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            selected_connections(&registry.registry, Some("server/push-site"))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(selected_connections(&registry.registry, Some("server/pull-site")).is_err());
+    }
+}
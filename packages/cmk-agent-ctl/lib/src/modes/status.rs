@@ -0,0 +1,2577 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::config::{JSONLoader, JSONLoaderMissingSafe};
+use crate::tls_failure::TlsFailure;
+use crate::{
+    agent_receiver_api, certs, cli, config, connection_stats, constants, error_code, integrity,
+    ipc, messages, misc, output, payload_stats, setup, site_spec, transport,
+};
+use anyhow::Result as AnyhowResult;
+use log::debug;
+use messages::Message;
+use serde::ser::SerializeStruct;
+use serde_with::DisplayFromStr;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+#[derive(serde::Serialize)]
+struct CertInfo {
+    issuer: String,
+    from: String,
+    to: String,
+    #[serde(skip)]
+    not_after: i64,
+}
+
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum CertParsingResult {
+    Success(CertInfo),
+    Error(String),
+}
+
+#[derive(serde::Serialize)]
+struct LocalConnectionStatus {
+    connection_mode: config::ConnectionMode,
+    #[serde(skip_serializing_if = "transport::TransportKind::is_https")]
+    transport: transport::TransportKind,
+    cert_info: CertParsingResult,
+}
+
+enum Remote {
+    StatusResponse(AnyhowResult<agent_receiver_api::RegistrationStatusV2Response>),
+    /// Result of an earlier query, used if querying was not wanted or failed
+    Cached {
+        response: agent_receiver_api::RegistrationStatusV2Response,
+        age: u64,
+        query_error: Option<String>,
+    },
+    Imported,
+    QueryDisabled,
+}
+
+#[derive(serde::Serialize)]
+struct CachedRemote<'a> {
+    #[serde(flatten)]
+    response: &'a agent_receiver_api::RegistrationStatusV2Response,
+    cache_age: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query_error: Option<&'a str>,
+}
+
+impl serde::ser::Serialize for Remote {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        match self {
+            Self::StatusResponse(remote_conn_stat) => match remote_conn_stat {
+                Ok(remote_conn_stat) => remote_conn_stat.serialize(serializer),
+                Err(err) => {
+                    let classification = error_code::ErrorClassification::from(err);
+                    let mut s = serializer.serialize_struct("Error", 4)?;
+                    s.serialize_field("error", &err.to_string())?;
+                    s.serialize_field("error_code", &classification.error_code)?;
+                    s.serialize_field("error_category", &classification.error_category)?;
+                    if let Some(tls_failure) = &classification.tls_failure {
+                        s.serialize_field("tls_failure", tls_failure)?;
+                    }
+                    s.end()
+                }
+            },
+            Self::Cached {
+                response,
+                age,
+                query_error,
+            } => CachedRemote {
+                response,
+                cache_age: *age,
+                query_error: query_error.as_deref(),
+            }
+            .serialize(serializer),
+            Self::Imported => serializer.serialize_str("imported_connection"),
+            Self::QueryDisabled => serializer.serialize_str("remote_query_disabled"),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedRemoteStatus {
+    /// Unix timestamp of the query
+    timestamp: u64,
+    response: agent_receiver_api::RegistrationStatusV2Response,
+}
+
+/// Result of the last successful remote query of each connection, keyed by connection UUID
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct RemoteStatusCache(HashMap<String, CachedRemoteStatus>);
+
+impl JSONLoader for RemoteStatusCache {}
+impl JSONLoaderMissingSafe for RemoteStatusCache {}
+
+impl RemoteStatusCache {
+    fn load(path: &std::path::Path) -> RemoteStatusCache {
+        <Self as JSONLoaderMissingSafe>::load_missing_safe(path).unwrap_or_else(|err| {
+            debug!("Could not load cached remote status: {}", err);
+            RemoteStatusCache::default()
+        })
+    }
+
+    /// Forget about connections which are not registered (anymore)
+    fn retain_registered(&mut self, registry: &config::Registry) {
+        let registered: Vec<String> = registry
+            .get_push_connections()
+            .chain(registry.get_standard_pull_connections())
+            .map(|(_, conn)| conn.trust.uuid.to_string())
+            .collect();
+        self.0.retain(|uuid, _| registered.contains(uuid));
+    }
+
+    fn save(&self, path: &std::path::Path) -> AnyhowResult<()> {
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_string(self)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+}
+
+/// Determines the remote status of connections, either by querying the agent receiver or from
+/// the cache of earlier queries
+struct RemoteQuery<'a, A> {
+    /// None if querying is disabled
+    api: Option<&'a A>,
+    /// Use cached results up to this age (in seconds) instead of querying
+    max_age: Option<u64>,
+    cache: RemoteStatusCache,
+    now: u64,
+    /// Results of the queries run ahead, see prefetch
+    prefetched: HashMap<uuid::Uuid, AnyhowResult<agent_receiver_api::RegistrationStatusV2Response>>,
+}
+
+impl<'a, A: agent_receiver_api::RegistrationStatusV2> RemoteQuery<'a, A> {
+    fn new(api: Option<&'a A>, max_age: Option<u64>, cache: RemoteStatusCache) -> Self {
+        Self {
+            api,
+            max_age,
+            cache,
+            now: misc::unix_now(),
+            prefetched: HashMap::new(),
+        }
+    }
+
+    /// Cached result which is recent enough to be reported instead of querying
+    fn fresh_cached(&self, uuid: &uuid::Uuid) -> Option<Remote> {
+        let max_age = self.max_age?;
+        match self.cached(uuid, None) {
+            Some(Remote::Cached { age, .. }) if age > max_age => None,
+            cached => cached,
+        }
+    }
+
+    fn cached(&self, uuid: &uuid::Uuid, query_error: Option<String>) -> Option<Remote> {
+        self.cache
+            .0
+            .get(&uuid.to_string())
+            .map(|cached| Remote::Cached {
+                response: cached.response.clone(),
+                age: self.now.saturating_sub(cached.timestamp),
+                query_error,
+            })
+    }
+
+    fn remote(
+        &mut self,
+        site_id: &site_spec::SiteID,
+        conn: &config::TrustedConnectionWithRemote,
+    ) -> Remote {
+        let Some(api) = self.api else {
+            return self
+                .cached(&conn.trust.uuid, None)
+                .unwrap_or(Remote::QueryDisabled);
+        };
+        if let Some(cached) = self.fresh_cached(&conn.trust.uuid) {
+            return cached;
+        }
+        let result = self
+            .prefetched
+            .remove(&conn.trust.uuid)
+            .unwrap_or_else(|| ConnectionStatus::query_remote(site_id, conn, api));
+        match result {
+            Ok(response) => {
+                self.cache.0.insert(
+                    conn.trust.uuid.to_string(),
+                    CachedRemoteStatus {
+                        timestamp: self.now,
+                        response: response.clone(),
+                    },
+                );
+                Remote::StatusResponse(Ok(response))
+            }
+            Err(err) => self
+                .cached(&conn.trust.uuid, Some(err.to_string()))
+                .unwrap_or(Remote::StatusResponse(Err(err))),
+        }
+    }
+}
+
+impl<A: agent_receiver_api::RegistrationStatusV2 + Sync> RemoteQuery<'_, A> {
+    /// Query the receivers of the given connections concurrently, at most
+    /// MAX_PARALLEL_STATUS_QUERIES at a time. Every query ends within the total timeout of the
+    /// client, st. unreachable sites do not add up.
+    fn prefetch<'c>(
+        &mut self,
+        connections: impl Iterator<
+            Item = (
+                &'c site_spec::SiteID,
+                &'c config::TrustedConnectionWithRemote,
+            ),
+        >,
+    ) {
+        let Some(api) = self.api else {
+            return;
+        };
+        let pending: Vec<_> = connections
+            .filter(|(_, conn)| self.fresh_cached(&conn.trust.uuid).is_none())
+            .collect();
+        let workers = pending.len().min(constants::MAX_PARALLEL_STATUS_QUERIES);
+        let pending = Mutex::new(pending.into_iter());
+        let next = || {
+            let mut pending = match pending.lock() {
+                Ok(pending) => pending,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            pending.next()
+        };
+        self.prefetched = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut results = vec![];
+                        while let Some((site_id, conn)) = next() {
+                            results.push((
+                                conn.trust.uuid,
+                                ConnectionStatus::query_remote(site_id, conn, api),
+                            ));
+                        }
+                        results
+                    })
+                })
+                .collect();
+            // Connections of a panicked worker are queried again later on
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap_or_default())
+                .collect()
+        });
+        let failed = self
+            .prefetched
+            .values()
+            .filter(|result| result.is_err())
+            .count();
+        if failed > 0 {
+            debug!(
+                "{} of {} remote queries failed",
+                failed,
+                self.prefetched.len()
+            );
+        }
+    }
+}
+
+/// Hostname the receiver reported earlier, kept in the registry
+#[derive(serde::Serialize)]
+struct KnownHostname {
+    #[serde(flatten)]
+    known: config::KnownHostname,
+    /// Time (in seconds) since the receiver last reported the hostname
+    age: u64,
+}
+
+/// Expiry of a temporary registration
+#[derive(serde::Serialize)]
+struct Expiry {
+    /// Unix timestamp at which the connection expires
+    expires: u64,
+    expired: bool,
+    /// Time (in seconds) until or since the expiry
+    #[serde(skip)]
+    distance: u64,
+}
+
+impl Expiry {
+    fn new(expires: u64, now: u64) -> Self {
+        Self {
+            expires,
+            expired: now >= expires,
+            distance: expires.abs_diff(now),
+        }
+    }
+}
+
+#[serde_with::serde_as]
+#[derive(serde::Serialize)]
+struct ConnectionStatus {
+    #[serde(flatten)]
+    site_data: Option<SiteData>,
+    #[serde_as(as = "DisplayFromStr")]
+    uuid: uuid::Uuid,
+    local: LocalConnectionStatus,
+    remote: Remote,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    known_hostname: Option<KnownHostname>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    tags: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiry: Option<Expiry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    counters: Option<connection_stats::ConnectionCounters>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receiver_stats: Option<connection_stats::ReceiverStats>,
+}
+
+#[derive(serde::Serialize)]
+struct SiteData {
+    site_id: site_spec::SiteID,
+    receiver_port: u16,
+}
+
+/// State of the running daemon, as reported by itself
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum Daemon {
+    Running(ipc::DaemonStatus),
+    Unreachable { error: String },
+}
+
+impl Daemon {
+    fn query(path_control_socket: &std::path::Path) -> Daemon {
+        match ipc::request(path_control_socket, &ipc::Request::Status) {
+            Ok(ipc::Response::Status { status }) => Daemon::Running(status),
+            Ok(ipc::Response::Error { message }) => Daemon::Unreachable { error: message },
+            Ok(response) => Daemon::Unreachable {
+                error: format!("Unexpected response from daemon: {response:?}"),
+            },
+            Err(err) => Daemon::Unreachable {
+                error: misc::anyhow_error_to_human_readable(&err),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Daemon {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Daemon::Running(status) => {
+                write!(
+                    f,
+                    "{}",
+                    Message::DaemonRunning {
+                        state: &status.state,
+                        pid: status.pid,
+                        uptime: &misc::human_readable_duration(
+                            misc::unix_now().saturating_sub(status.started)
+                        ),
+                        log_level: &status.log_level,
+                    }
+                )?;
+                for (task, report) in &status.restarts {
+                    if let Some(last) = report.history.back() {
+                        write!(
+                            f,
+                            "\n\t{}",
+                            mark_problematic(&Message::TaskRestarted {
+                                task,
+                                restarts: report.restarts,
+                                age: &misc::human_readable_duration(
+                                    misc::unix_now().saturating_sub(last.timestamp)
+                                ),
+                                reason: &last.reason,
+                            })
+                        )?;
+                    }
+                }
+                Ok(())
+            }
+            Daemon::Unreachable { error } => write!(
+                f,
+                "{}",
+                mark_problematic(&Message::DaemonUnreachable { error })
+            ),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Status {
+    version: String,
+    agent_socket_operational: bool,
+    ip_allowlist: Vec<String>,
+    allow_legacy_pull: bool,
+    /// Size of the most recently collected agent output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<payload_stats::PayloadSample>,
+    /// Only reported if asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    daemon: Option<Daemon>,
+    /// Only reported once the daemon checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    integrity: Option<integrity::IntegrityReport>,
+    /// Entries of the registry which could not be loaded
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    quarantined: Vec<Quarantined>,
+    connections: Vec<ConnectionStatus>,
+}
+
+/// Quarantined entry of the registry, without the entry itself, which contains the private key
+#[derive(serde::Serialize, Clone)]
+struct Quarantined {
+    entry: String,
+    /// Unix timestamp
+    quarantined_at: u64,
+    reason: String,
+}
+
+impl From<&config::QuarantinedConnection> for Quarantined {
+    fn from(quarantined: &config::QuarantinedConnection) -> Self {
+        Self {
+            entry: quarantined.describe(),
+            quarantined_at: quarantined.quarantined_at,
+            reason: quarantined.reason.clone(),
+        }
+    }
+}
+
+/// Overall state of the reported status, determines the exit code of the status mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Ok => 0,
+            Self::Warning => 1,
+            Self::Error => 2,
+        }
+    }
+}
+
+/// Returned by the status mode if the printed status contains problems
+#[derive(Debug)]
+pub struct ProblemsFound(pub Severity);
+
+impl std::fmt::Display for ProblemsFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.0 {
+            Severity::Error => write!(f, "Status contains errors"),
+            _ => write!(f, "Status contains warnings"),
+        }
+    }
+}
+
+impl std::error::Error for ProblemsFound {}
+
+impl CertInfo {
+    fn from(certificate: &str) -> AnyhowResult<CertInfo> {
+        let pem = certs::parse_pem(certificate)?;
+        let x509 = pem.parse_x509()?;
+        Ok(CertInfo {
+            issuer: certs::common_names(x509.issuer())?.join(", "),
+            from: x509.validity().not_before.to_rfc2822(),
+            to: x509.validity().not_after.to_rfc2822(),
+            not_after: x509.validity().not_after.timestamp(),
+        })
+    }
+
+    fn severity(&self, now: i64) -> Severity {
+        let remaining = self.not_after - now;
+        if remaining <= 0 {
+            Severity::Error
+        } else if remaining < constants::CERT_VALIDITY_LOWER_LIMIT as i64 {
+            // Should have been renewed by now
+            Severity::Warning
+        } else {
+            Severity::Ok
+        }
+    }
+}
+
+impl CertParsingResult {
+    fn from(certificate: &str) -> CertParsingResult {
+        match CertInfo::from(certificate) {
+            Ok(cert_info) => CertParsingResult::Success(cert_info),
+            _ => CertParsingResult::Error(String::from("parsing_error")),
+        }
+    }
+}
+
+impl ConnectionStatus {
+    fn query_remote(
+        site_id: &site_spec::SiteID,
+        conn: &config::TrustedConnectionWithRemote,
+        agent_rec_api: &impl agent_receiver_api::RegistrationStatusV2,
+    ) -> AnyhowResult<agent_receiver_api::RegistrationStatusV2Response> {
+        agent_rec_api.registration_status_v2(
+            &site_spec::make_site_url(site_id, &conn.receiver_port)?,
+            &conn.trust,
+        )
+    }
+
+    fn from_standard_conn(
+        site_id: &site_spec::SiteID,
+        conn: &config::TrustedConnectionWithRemote,
+        conn_mode: config::ConnectionMode,
+        remote_query: &mut RemoteQuery<impl agent_receiver_api::RegistrationStatusV2>,
+        counters: &connection_stats::CountersByConnection,
+    ) -> ConnectionStatus {
+        ConnectionStatus {
+            site_data: Some(SiteData {
+                site_id: site_id.clone(),
+                receiver_port: conn.receiver_port,
+            }),
+            uuid: conn.trust.uuid,
+            local: LocalConnectionStatus {
+                connection_mode: conn_mode,
+                transport: conn.trust.transport,
+                cert_info: CertParsingResult::from(&conn.trust.certificate),
+            },
+            remote: remote_query.remote(site_id, conn),
+            known_hostname: conn.hostname.clone().map(|known| KnownHostname {
+                age: remote_query.now.saturating_sub(known.last_seen),
+                known,
+            }),
+            tags: conn.tags.clone(),
+            expiry: conn
+                .expires
+                .map(|expires| Expiry::new(expires, remote_query.now)),
+            counters: counters.get(&conn.trust.uuid).cloned(),
+            receiver_stats: counters
+                .get(&conn.trust.uuid)
+                .and_then(|counters| counters.receiver_stats()),
+        }
+    }
+
+    fn from_imported_conn(
+        conn: &config::TrustedConnection,
+        counters: &connection_stats::CountersByConnection,
+    ) -> ConnectionStatus {
+        ConnectionStatus {
+            site_data: None,
+            uuid: conn.uuid,
+            local: LocalConnectionStatus {
+                connection_mode: config::ConnectionMode::Pull,
+                transport: conn.transport,
+                cert_info: CertParsingResult::from(&conn.certificate),
+            },
+            remote: Remote::Imported,
+            known_hostname: None,
+            tags: BTreeMap::new(),
+            expiry: None,
+            counters: counters.get(&conn.uuid).cloned(),
+            receiver_stats: None,
+        }
+    }
+
+    fn severity(&self, now: i64) -> Severity {
+        let local = match &self.local.cert_info {
+            CertParsingResult::Success(cert_info) => cert_info.severity(now),
+            CertParsingResult::Error(..) => Severity::Error,
+        };
+        let remote = match &self.remote {
+            Remote::StatusResponse(Err(..)) => Severity::Error,
+            Remote::StatusResponse(Ok(response)) => self.response_severity(response),
+            Remote::Cached {
+                query_error: Some(..),
+                ..
+            } => Severity::Error,
+            Remote::Cached { response, .. } => self.response_severity(response),
+            _ => Severity::Ok,
+        };
+        let clock = match self.clock_skew() {
+            Some(skew) if skew.is_excessive() => Severity::Warning,
+            _ => Severity::Ok,
+        };
+        let expiry = match &self.expiry {
+            Some(expiry) if expiry.expired => Severity::Warning,
+            _ => Severity::Ok,
+        };
+        local.max(remote).max(clock).max(expiry)
+    }
+
+    fn clock_skew(&self) -> Option<connection_stats::ClockSkew> {
+        self.counters
+            .as_ref()
+            .and_then(|counters| counters.clock_skew)
+    }
+
+    fn response_severity(
+        &self,
+        response: &agent_receiver_api::RegistrationStatusV2Response,
+    ) -> Severity {
+        match response {
+            agent_receiver_api::RegistrationStatusV2Response::NotRegistered => Severity::Error,
+            agent_receiver_api::RegistrationStatusV2Response::Registered(registered)
+                if registered.connection_mode != self.local.connection_mode =>
+            {
+                Severity::Warning
+            }
+            _ => Severity::Ok,
+        }
+    }
+
+    fn local_lines_readable(&self) -> Vec<String> {
+        let mut lines = vec![];
+        lines.push(format!(
+            "{}: {}",
+            Message::ConnectionMode,
+            self.local.connection_mode
+        ));
+        lines.push(format!(
+            "{}: {}",
+            Message::ReceiverPort,
+            if let Some(site_data) = &self.site_data {
+                site_data.receiver_port.to_string()
+            } else {
+                Message::NoReceiverPort.to_string()
+            }
+        ));
+        if !self.local.transport.is_https() {
+            lines.push(format!("{}: {}", Message::Transport, self.local.transport));
+        }
+        match &self.local.cert_info {
+            CertParsingResult::Success(cert_info) => {
+                lines.push(format!(
+                    "{}: {}",
+                    Message::CertificateIssuer,
+                    cert_info.issuer
+                ));
+                lines.push(format!(
+                    "{}: {} - {}",
+                    Message::CertificateValidity,
+                    cert_info.from,
+                    cert_info.to
+                ));
+            }
+            CertParsingResult::Error(..) => {
+                lines.push(mark_problematic(&Message::CertificateParsingFailed))
+            }
+        }
+        if !self.tags.is_empty() {
+            let tags: Vec<String> = self
+                .tags
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect();
+            lines.push(format!("{}: {}", Message::Tags, tags.join(", ")));
+        }
+        if let Some(expiry) = &self.expiry {
+            let distance = misc::human_readable_duration(expiry.distance);
+            lines.push(if expiry.expired {
+                mark_problematic(&format!(
+                    "{}: {}",
+                    Message::Expiry,
+                    Message::ExpiredAgo { age: &distance }
+                ))
+            } else {
+                format!(
+                    "{}: {}",
+                    Message::Expiry,
+                    Message::ExpiresIn {
+                        remaining: &distance
+                    }
+                )
+            });
+        }
+        lines
+    }
+
+    fn remote_lines_readable(&self) -> Vec<String> {
+        let mut lines = self.remote_status_lines_readable();
+        // The hostname is part of a successful answer, otherwise fall back to the known one
+        if let (Remote::StatusResponse(Err(..)) | Remote::QueryDisabled, Some(known_hostname)) =
+            (&self.remote, &self.known_hostname)
+        {
+            lines.push(format!(
+                "{}: {} ({})",
+                Message::Hostname,
+                known_hostname.known.hostname,
+                Message::LastSeen {
+                    age: &misc::human_readable_duration(known_hostname.age)
+                }
+            ));
+        }
+        lines
+    }
+
+    fn remote_status_lines_readable(&self) -> Vec<String> {
+        match &self.remote {
+            Remote::StatusResponse(registration_status_v2_response) => {
+                match &registration_status_v2_response {
+                    Ok(registration_status_v2_response) => Self::remote_lines_success_readable(
+                        registration_status_v2_response,
+                        &self.local.connection_mode,
+                    ),
+                    Err(err) => {
+                        let mut lines =
+                            vec![mark_problematic(&format!("{}: {err}", Message::Error))];
+                        if let Some(tls_failure) = TlsFailure::of(err) {
+                            lines.push(format!("{}. {}", tls_failure, tls_failure.guidance()));
+                        }
+                        lines
+                    }
+                }
+            }
+            Remote::Cached {
+                response,
+                age,
+                query_error,
+            } => {
+                let mut lines =
+                    Self::remote_lines_success_readable(response, &self.local.connection_mode);
+                lines.push(
+                    Message::CachedResult {
+                        age: &misc::human_readable_duration(*age),
+                    }
+                    .to_string(),
+                );
+                if let Some(query_error) = query_error {
+                    lines.push(mark_problematic(&format!(
+                        "{}: {query_error}",
+                        Message::QueryFailed
+                    )));
+                }
+                lines
+            }
+            Remote::Imported => vec![Message::NoRemoteAddress.to_string()],
+            Remote::QueryDisabled => vec![Message::RemoteQueryDisabled.to_string()],
+        }
+    }
+
+    fn remote_lines_success_readable(
+        registration_status_v2_response: &agent_receiver_api::RegistrationStatusV2Response,
+        local_conn_mode: &config::ConnectionMode,
+    ) -> Vec<String> {
+        match registration_status_v2_response {
+            agent_receiver_api::RegistrationStatusV2Response::NotRegistered => {
+                vec![mark_problematic(&Message::NotRegistered)]
+            }
+            agent_receiver_api::RegistrationStatusV2Response::Registered(
+                registration_status_v2_response_registered,
+            ) => {
+                vec![
+                    format!(
+                        "{}: {}",
+                        Message::ConnectionMode,
+                        if &registration_status_v2_response_registered.connection_mode
+                            == local_conn_mode
+                        {
+                            registration_status_v2_response_registered
+                                .connection_mode
+                                .to_string()
+                        } else {
+                            mark_problematic(
+                                &registration_status_v2_response_registered.connection_mode,
+                            )
+                        },
+                    ),
+                    format!(
+                        "{}: {}",
+                        Message::Hostname,
+                        registration_status_v2_response_registered.hostname
+                    ),
+                ]
+            }
+        }
+    }
+
+    fn receiver_lines_readable(stats: &connection_stats::ReceiverStats) -> Vec<String> {
+        let latency = |percentile: Option<u64>| match percentile {
+            Some(ms) => format!("{ms} ms"),
+            None => Message::NotAvailable.to_string(),
+        };
+        vec![
+            Message::SuccessfulCalls {
+                percent: stats.success_ratio * 100.0,
+                successful: stats.successful_calls,
+                calls: stats.calls,
+            }
+            .to_string(),
+            format!(
+                "{}: p50 {}, p90 {}, p99 {}",
+                Message::Latency,
+                latency(stats.latency_p50_ms),
+                latency(stats.latency_p90_ms),
+                latency(stats.latency_p99_ms)
+            ),
+        ]
+    }
+
+    fn to_human_readable(&self) -> String {
+        format!(
+            "{}\n\tUUID: {}\n\t{}:\n\t\t{}\n\t{}:\n\t\t{}{}",
+            match &self.site_data {
+                Some(site_data) => format!("{}: {}", Message::Connection, &site_data.site_id),
+                None => format!("{}:", Message::ImportedConnection),
+            },
+            self.uuid,
+            Message::Local,
+            self.local_lines_readable().join("\n\t\t"),
+            Message::Remote,
+            self.remote_lines_readable()
+                .into_iter()
+                .chain(self.clock_skew().map(|skew| {
+                    let line = format!("{}: {}", Message::Clock, skew.describe());
+                    match skew.is_excessive() {
+                        true => mark_problematic(&line),
+                        false => line,
+                    }
+                }))
+                .collect::<Vec<String>>()
+                .join("\n\t\t"),
+            match &self.receiver_stats {
+                Some(stats) => format!(
+                    "\n\t{}:\n\t\t{}",
+                    Message::ReceiverCalls { calls: stats.calls },
+                    Self::receiver_lines_readable(stats).join("\n\t\t")
+                ),
+                None => String::new(),
+            }
+        )
+    }
+}
+
+impl std::fmt::Display for ConnectionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.to_human_readable())
+    }
+}
+
+impl Status {
+    fn from(
+        registry: &config::Registry,
+        pull_config: &config::PullConfig,
+        remote_query: &mut RemoteQuery<impl agent_receiver_api::RegistrationStatusV2 + Sync>,
+        counters: &connection_stats::CountersByConnection,
+        payload: Option<&payload_stats::PayloadSample>,
+        connection: Option<&str>,
+        tags: &[(String, String)],
+    ) -> Status {
+        let selected = |site_id: Option<&site_spec::SiteID>, uuid: &uuid::Uuid| match connection {
+            Some(ident) => {
+                uuid.to_string() == ident || site_id.is_some_and(|id| id.to_string() == ident)
+            }
+            None => true,
+        };
+
+        let standard_conns: Vec<_> = registry
+            .get_push_connections()
+            .map(|(site_id, conn)| (site_id, conn, config::ConnectionMode::Push))
+            .chain(
+                registry
+                    .get_standard_pull_connections()
+                    .map(|(site_id, conn)| (site_id, conn, config::ConnectionMode::Pull)),
+            )
+            .filter(|(site_id, conn, _)| {
+                selected(Some(*site_id), &conn.trust.uuid) && conn.has_tags(tags)
+            })
+            .collect();
+        remote_query.prefetch(
+            standard_conns
+                .iter()
+                .map(|(site_id, conn, _)| (*site_id, *conn)),
+        );
+        let mut conn_stats: Vec<_> = standard_conns
+            .into_iter()
+            .map(|(site_id, conn, conn_mode)| {
+                ConnectionStatus::from_standard_conn(
+                    site_id,
+                    conn,
+                    conn_mode,
+                    remote_query,
+                    counters,
+                )
+            })
+            .collect();
+        for imp_pull_conn in registry.get_imported_pull_connections() {
+            // Imported connections have no tags
+            if !selected(None, &imp_pull_conn.uuid) || !tags.is_empty() {
+                continue;
+            }
+            conn_stats.push(ConnectionStatus::from_imported_conn(
+                imp_pull_conn,
+                counters,
+            ));
+        }
+
+        Status {
+            version: String::from(constants::VERSION),
+            agent_socket_operational: pull_config.agent_channel.operational(),
+            ip_allowlist: pull_config.allowed_ip.to_vec(),
+            allow_legacy_pull: registry.is_legacy_pull_active(),
+            // Recorded sizes are outdated once the levels are removed
+            payload: payload
+                .filter(|_| pull_config.payload_size.is_enabled())
+                .cloned(),
+            daemon: None,
+            integrity: None,
+            quarantined: vec![],
+            connections: conn_stats,
+        }
+    }
+
+    fn severity(&self, now: i64) -> Severity {
+        let overall = match self.agent_socket_operational {
+            true => Severity::Ok,
+            false => Severity::Error,
+        };
+        let overall = match self.payload.as_ref().map(|payload| payload.state) {
+            Some(payload_stats::PayloadState::Warn) => overall.max(Severity::Warning),
+            Some(payload_stats::PayloadState::Crit) => Severity::Error,
+            _ => overall,
+        };
+        let overall = match self.daemon {
+            Some(Daemon::Unreachable { .. }) => overall.max(Severity::Warning),
+            _ => overall,
+        };
+        let overall = match &self.integrity {
+            Some(report) if !report.is_ok() => Severity::Error,
+            _ => overall,
+        };
+        let overall = match self.quarantined.is_empty() {
+            true => overall,
+            false => overall.max(Severity::Warning),
+        };
+        self.connections
+            .iter()
+            .map(|conn_stat| conn_stat.severity(now))
+            .fold(overall, Severity::max)
+    }
+
+    fn to_string(&self, format: cli::OutputFormat) -> AnyhowResult<String> {
+        Ok(match output::render(format, self)? {
+            Some(rendered) => rendered,
+            None => format!("{self}"),
+        })
+    }
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {}\n{}: {}\n{}: {}{}{}{}{}{}{}",
+            Message::Version,
+            self.version,
+            Message::AgentSocket,
+            match self.agent_socket_operational {
+                true => Message::Operational.to_string(),
+                false => mark_problematic(&Message::Inoperational),
+            },
+            Message::IpAllowlist,
+            match self.ip_allowlist.is_empty() {
+                true => Message::AnyAddress.to_string(),
+                false => self.ip_allowlist.join(" "),
+            },
+            match self.allow_legacy_pull {
+                true => format!("\n{}", Message::LegacyModeEnabled),
+                false => String::new(),
+            },
+            match &self.payload {
+                // The summary marks the exceeded levels
+                Some(payload) => format!("\n{}: {}", Message::AgentOutput, payload.summary),
+                None => String::new(),
+            },
+            match &self.daemon {
+                Some(daemon) => format!("\n{}: {}", Message::Daemon, daemon),
+                None => String::new(),
+            },
+            match &self.integrity {
+                Some(report) => format!(
+                    "\n{}: {}",
+                    Message::IntegrityCheck,
+                    integrity_summary(report)
+                ),
+                None => String::new(),
+            },
+            self.quarantined
+                .iter()
+                .map(|quarantined| format!(
+                    "\n{}: {}",
+                    Message::Quarantined,
+                    mark_problematic(&format!("{}: {}", quarantined.entry, quarantined.reason))
+                ))
+                .collect::<String>(),
+            if self.connections.is_empty() {
+                format!("\n{}", Message::NoConnections)
+            } else {
+                format!(
+                    "\n\n\n{}",
+                    self.connections
+                        .iter()
+                        .map(|conn_stat| format!("{conn_stat}"))
+                        .collect::<Vec<String>>()
+                        .join("\n\n\n"),
+                )
+            }
+        )
+    }
+}
+
+fn mark_problematic(to_mark: &(impl std::fmt::Display + ?Sized)) -> String {
+    format!("{to_mark} (!!)")
+}
+
+fn integrity_summary(report: &integrity::IntegrityReport) -> String {
+    let age = misc::human_readable_duration(misc::unix_now().saturating_sub(report.timestamp));
+    match report.is_ok() {
+        true => Message::IntegrityOk {
+            connections: report.checked_connections,
+            age: &age,
+        }
+        .to_string(),
+        false => mark_problematic(&Message::IntegrityFailed {
+            age: &age,
+            failures: &report.failures.join("; "),
+        }),
+    }
+}
+
+fn _status(
+    registry: &config::Registry,
+    pull_config: &config::PullConfig,
+    options: &StatusOptions,
+    remote_query: &mut RemoteQuery<impl agent_receiver_api::RegistrationStatusV2 + Sync>,
+    recorded: &Recorded,
+    daemon: Option<Daemon>,
+) -> AnyhowResult<(String, Severity)> {
+    let mut status = Status::from(
+        registry,
+        pull_config,
+        remote_query,
+        &recorded.counters,
+        recorded.payload.as_ref(),
+        options.connection,
+        options.tags,
+    );
+    status.integrity = recorded.integrity.clone();
+    status.quarantined = recorded.quarantined.clone();
+    status.daemon = daemon;
+    if let Some(connection) = options.connection {
+        if status.connections.is_empty() {
+            return Err(error_code::CodedError::new(
+                error_code::ErrorCode::UnknownConnection,
+                Message::NoConnectionMatching { connection }.to_string(),
+            )
+            .into());
+        }
+    }
+    Ok((
+        status.to_string(options.output)?,
+        status.severity(misc::unix_now() as i64),
+    ))
+}
+
+/// What the status mode reports and how
+pub struct StatusOptions<'a> {
+    pub output: cli::OutputFormat,
+    pub query_remote: bool,
+    /// Only report this connection (site ID or UUID)
+    pub connection: Option<&'a str>,
+    /// Only report connections with all of these tags
+    pub tags: &'a [(String, String)],
+    /// Also report the state of the running daemon
+    pub live: bool,
+    /// Keep refreshing the status at this interval (in seconds)
+    pub watch: Option<u64>,
+    /// Use cached remote status up to this age (in seconds) instead of querying the remote
+    pub max_age: Option<u64>,
+    /// Give up on a remote query after this many seconds instead of the total network timeout
+    pub query_timeout: Option<u64>,
+}
+
+/// What the daemon recorded for the status
+#[derive(Default)]
+struct Recorded {
+    counters: connection_stats::CountersByConnection,
+    payload: Option<payload_stats::PayloadSample>,
+    integrity: Option<integrity::IntegrityReport>,
+    quarantined: Vec<Quarantined>,
+}
+
+impl Recorded {
+    fn load(paths: &setup::PathResolver) -> Self {
+        Self {
+            counters: load_counters(&paths.connection_stats_path),
+            payload: load_payload(&paths.payload_stats_path),
+            integrity: load_integrity(&paths.integrity_check_path),
+            quarantined: load_quarantined(&config::Registry::quarantine_path(&paths.registry_path)),
+        }
+    }
+}
+
+fn load_counters(
+    connection_stats_path: &std::path::Path,
+) -> connection_stats::CountersByConnection {
+    connection_stats::ConnectionStats::load(connection_stats_path).unwrap_or_else(|err| {
+        debug!("Could not load connection counters: {}", err);
+        connection_stats::CountersByConnection::default()
+    })
+}
+
+fn load_payload(payload_stats_path: &std::path::Path) -> Option<payload_stats::PayloadSample> {
+    match payload_stats::PayloadStats::load(payload_stats_path) {
+        Ok(history) => history.latest().cloned(),
+        Err(err) => {
+            debug!("Could not load agent output sizes: {}", err);
+            None
+        }
+    }
+}
+
+fn load_integrity(integrity_check_path: &std::path::Path) -> Option<integrity::IntegrityReport> {
+    match integrity::IntegrityReport::load(integrity_check_path) {
+        Ok(report) => Some(report),
+        Err(err) => {
+            debug!("Could not load integrity check: {}", err);
+            None
+        }
+    }
+}
+
+fn load_quarantined(quarantine_path: &std::path::Path) -> Vec<Quarantined> {
+    match config::Quarantine::load_missing_safe(quarantine_path) {
+        Ok(quarantine) => quarantine.0.iter().map(Quarantined::from).collect(),
+        Err(err) => {
+            debug!("Could not load quarantined registry entries: {}", err);
+            vec![]
+        }
+    }
+}
+
+/// Mark the lines which differ from the previous output of the watch mode
+fn highlight_changes(previous: Option<&str>, current: &str) -> String {
+    let mut previous_lines = previous.map(|previous| previous.lines());
+    current
+        .lines()
+        .map(|line| {
+            match previous_lines.as_mut().map(|lines| lines.next()) {
+                Some(Some(previous_line)) if previous_line == line => String::from(line),
+                // Changed or added line
+                Some(_) => format!("\x1b[7m{line}\x1b[0m"),
+                // First output, nothing to compare with
+                None => String::from(line),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn save_remote_status_cache(
+    remote_query: &mut RemoteQuery<impl agent_receiver_api::RegistrationStatusV2>,
+    registry: &config::Registry,
+    path: &std::path::Path,
+) {
+    if remote_query.api.is_none() {
+        // Nothing was queried, so nothing changed
+        return;
+    }
+    remote_query.cache.retain_registered(registry);
+    if let Err(err) = remote_query.cache.save(path) {
+        debug!("Could not save cached remote status to {:?}: {}", path, err);
+    }
+}
+
+/// Keep the hostnames reported by the receivers in the registry, to display them when the
+/// receivers cannot be queried
+fn record_hostnames(
+    remote_query: &RemoteQuery<impl agent_receiver_api::RegistrationStatusV2>,
+    registry: &mut config::Registry,
+) {
+    if remote_query.api.is_none() {
+        return;
+    }
+    let mut changed = false;
+    for (_, connection) in registry.get_standard_connections_as_mut() {
+        if let Some(CachedRemoteStatus {
+            timestamp,
+            response: agent_receiver_api::RegistrationStatusV2Response::Registered(registered),
+        }) = remote_query.cache.0.get(&connection.trust.uuid.to_string())
+        {
+            changed |= connection.record_hostname(&registered.hostname, *timestamp);
+        }
+    }
+    if changed {
+        if let Err(err) = registry.save() {
+            debug!("Could not save reported hostnames to registry: {}", err);
+        }
+    }
+}
+
+fn watch(
+    registry: &mut config::Registry,
+    pull_config: &config::PullConfig,
+    remote_query: &mut RemoteQuery<impl agent_receiver_api::RegistrationStatusV2 + Sync>,
+    options: &StatusOptions,
+    interval: u64,
+    paths: &setup::PathResolver,
+) -> AnyhowResult<()> {
+    let mut previous: Option<String> = None;
+    loop {
+        registry.refresh()?;
+        remote_query.now = misc::unix_now();
+        let (output, _) = _status(
+            registry,
+            pull_config,
+            options,
+            remote_query,
+            &Recorded::load(paths),
+            options
+                .live
+                .then(|| Daemon::query(&paths.control_socket_path)),
+        )?;
+        save_remote_status_cache(remote_query, registry, &paths.remote_status_cache_path);
+        record_hostnames(remote_query, registry);
+        if options.output == cli::OutputFormat::Json {
+            // One line of JSON per refresh
+            println!("{output}");
+        } else if options.output == cli::OutputFormat::Yaml {
+            // One YAML document per refresh
+            println!("---\n{output}");
+        } else {
+            // Clear the screen before redrawing
+            print!("\x1b[2J\x1b[H");
+            println!(
+                "Refreshing every {}s, press Ctrl+C to quit\n\n{}",
+                interval,
+                highlight_changes(previous.as_deref(), &output)
+            );
+            previous = Some(output);
+        }
+        std::thread::sleep(std::time::Duration::from_secs(interval));
+    }
+}
+
+/// Each connection is queried via its transport, QUIC only if registered with it
+fn remote_query_api(
+    client_config: &config::ClientConfig,
+    options: &StatusOptions,
+) -> transport::Transports {
+    let mut client_config = client_config.clone();
+    if let Some(query_timeout) = options.query_timeout {
+        client_config.timeouts.total = query_timeout;
+    }
+    transport::Transports::new(|| agent_receiver_api::Api::new(&client_config), false)
+}
+
+/// The status report without printing it, eg. for programs driving the controller
+pub fn report(
+    registry: &mut config::Registry,
+    pull_config: &config::PullConfig,
+    client_config: &config::ClientConfig,
+    options: &StatusOptions,
+    paths: &setup::PathResolver,
+) -> AnyhowResult<(String, Severity)> {
+    let agent_rec_api = remote_query_api(client_config, options);
+    let mut remote_query = RemoteQuery::new(
+        options.query_remote.then_some(&agent_rec_api),
+        options.max_age,
+        RemoteStatusCache::load(&paths.remote_status_cache_path),
+    );
+    let report = _status(
+        registry,
+        pull_config,
+        options,
+        &mut remote_query,
+        &Recorded::load(paths),
+        options
+            .live
+            .then(|| Daemon::query(&paths.control_socket_path)),
+    )?;
+    save_remote_status_cache(&mut remote_query, registry, &paths.remote_status_cache_path);
+    record_hostnames(&remote_query, registry);
+    Ok(report)
+}
+
+pub fn status(
+    mut registry: config::Registry,
+    pull_config: &config::PullConfig,
+    client_config: config::ClientConfig,
+    options: &StatusOptions,
+    paths: &setup::PathResolver,
+) -> AnyhowResult<()> {
+    debug!("Mode status started");
+    if let Some(interval) = options.watch {
+        let agent_rec_api = remote_query_api(&client_config, options);
+        return watch(
+            &mut registry,
+            pull_config,
+            &mut RemoteQuery::new(
+                options.query_remote.then_some(&agent_rec_api),
+                options.max_age,
+                RemoteStatusCache::load(&paths.remote_status_cache_path),
+            ),
+            options,
+            interval,
+            paths,
+        );
+    }
+    let (output, severity) = report(&mut registry, pull_config, &client_config, options, paths)?;
+    println!("{output}");
+    debug!("Mode status finished");
+    match severity {
+        Severity::Ok => Ok(()),
+        severity => Err(ProblemsFound(severity).into()),
+    }
+}
+
+#[cfg(test)]
+mod test_status {
+    use super::*;
+    use crate::cli;
+    use anyhow::anyhow;
+    use std::str::FromStr;
+
+    fn cert_info() -> CertInfo {
+        CertInfo {
+            issuer: String::from("Site 'site' local CA"),
+            from: String::from("Thu, 16 Dec 2021 08:18:41 +0000"),
+            to: String::from("Tue, 18 Apr 3020 08:18:41 +0000"),
+            not_after: 33144106721,
+        }
+    }
+
+    fn local_connection_status() -> LocalConnectionStatus {
+        LocalConnectionStatus {
+            connection_mode: config::ConnectionMode::Pull,
+            transport: transport::TransportKind::Https,
+            cert_info: CertParsingResult::Success(cert_info()),
+        }
+    }
+
+    #[test]
+    fn test_connection_status_remote_disabled() {
+        assert_eq!(
+            format!(
+                "{}",
+                ConnectionStatus {
+                    site_data: Some(SiteData {
+                        site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
+                        receiver_port: 8000,
+                    }),
+                    uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+                    local: LocalConnectionStatus {
+                        connection_mode: config::ConnectionMode::Pull,
+                        transport: transport::TransportKind::Https,
+                        cert_info: CertParsingResult::Success(cert_info())
+                    },
+                    remote: Remote::QueryDisabled,
+                    known_hostname: None,
+                    tags: BTreeMap::new(),
+                    expiry: None,
+                    counters: None,
+                    receiver_stats: None,
+                }
+            ),
+            String::from(
+                "Connection: localhost/site\n\
+                 \tUUID: 99f56bbc-5965-4b34-bc70-1959ad1d32d6\n\
+                 \tLocal:\n\
+                 \t\tConnection mode: pull-agent\n\
+                 \t\tConnecting to receiver port: 8000\n\
+                 \t\tCertificate issuer: Site 'site' local CA\n\
+                 \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \tRemote:\n\
+                 \t\tRemote query disabled"
+            )
+        );
+    }
+
+    #[test]
+    fn test_connection_status_known_hostname() {
+        let connection_status = ConnectionStatus {
+            site_data: Some(SiteData {
+                site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
+                receiver_port: 8000,
+            }),
+            uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+            local: local_connection_status(),
+            remote: Remote::StatusResponse(Err(anyhow!("Connection refused"))),
+            known_hostname: Some(KnownHostname {
+                known: config::KnownHostname {
+                    hostname: String::from("my-host"),
+                    last_seen: 1000,
+                },
+                age: 7500,
+            }),
+            tags: BTreeMap::new(),
+            expiry: None,
+            counters: None,
+            receiver_stats: None,
+        };
+        assert_eq!(
+            connection_status.remote_lines_readable(),
+            vec![
+                String::from("Error: Connection refused (!!)"),
+                String::from("Hostname: my-host (last seen 2h 5m ago)")
+            ]
+        );
+        let json = serde_json::to_value(&connection_status).unwrap();
+        assert_eq!(json["known_hostname"]["hostname"], "my-host");
+        assert_eq!(json["known_hostname"]["last_seen"], 1000);
+        assert_eq!(json["known_hostname"]["age"], 7500);
+    }
+
+    #[test]
+    fn test_connection_status_transport() {
+        let mut connection_status = ConnectionStatus {
+            site_data: None,
+            uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+            local: local_connection_status(),
+            remote: Remote::QueryDisabled,
+            known_hostname: None,
+            tags: BTreeMap::new(),
+            expiry: None,
+            counters: None,
+            receiver_stats: None,
+        };
+        let json = serde_json::to_value(&connection_status).unwrap();
+        assert!(json["local"].get("transport").is_none());
+        connection_status.local.transport = transport::TransportKind::WebSocket;
+        assert!(connection_status
+            .local_lines_readable()
+            .contains(&String::from("Transport: websocket")));
+        let json = serde_json::to_value(&connection_status).unwrap();
+        assert_eq!(json["local"]["transport"], "websocket");
+    }
+
+    #[test]
+    fn test_connection_status_expiry() {
+        let connection_status = |expires| ConnectionStatus {
+            site_data: Some(SiteData {
+                site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
+                receiver_port: 8000,
+            }),
+            uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+            local: local_connection_status(),
+            remote: Remote::QueryDisabled,
+            known_hostname: None,
+            tags: BTreeMap::new(),
+            expiry: Some(Expiry::new(expires, 10000)),
+            counters: None,
+            receiver_stats: None,
+        };
+        let pending = connection_status(10000 + 3600);
+        assert!(pending
+            .local_lines_readable()
+            .contains(&String::from("Expiry: in 1h 0m")));
+        assert_eq!(pending.severity(10000), Severity::Ok);
+        let json = serde_json::to_value(&pending).unwrap();
+        assert_eq!(json["expiry"]["expires"], 13600);
+        assert_eq!(json["expiry"]["expired"], false);
+
+        let expired = connection_status(10000 - 120);
+        assert!(expired.local_lines_readable().contains(&String::from(
+            "Expiry: expired 2m ago, no longer used, remove with 'cmk-agent-ctl delete-all --expired' (!!)"
+        )));
+        assert_eq!(expired.severity(10000), Severity::Warning);
+    }
+
+    #[test]
+    fn test_connection_status_fmt_normal() {
+        assert_eq!(
+            format!(
+                "{}",
+                ConnectionStatus {
+                    site_data: Some(SiteData {
+                        site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
+                        receiver_port: 8000,
+                    }),
+                    uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+                    local: local_connection_status(),
+                    remote: Remote::StatusResponse(Ok(
+                        agent_receiver_api::RegistrationStatusV2Response::Registered(
+                            agent_receiver_api::RegistrationStatusV2ResponseRegistered {
+                            connection_mode: config::ConnectionMode::Pull,
+                            hostname: String::from("my-host"),
+                            }
+                        )
+                    )),
+                    known_hostname: None,
+                    tags: BTreeMap::new(),
+                    expiry: None,
+                    counters: None,
+                    receiver_stats: None,
+                }
+            ),
+            String::from(
+                "Connection: localhost/site\n\
+                 \tUUID: 99f56bbc-5965-4b34-bc70-1959ad1d32d6\n\
+                 \tLocal:\n\
+                 \t\tConnection mode: pull-agent\n\
+                 \t\tConnecting to receiver port: 8000\n\
+                 \t\tCertificate issuer: Site 'site' local CA\n\
+                 \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \tRemote:\n\
+                 \t\tConnection mode: pull-agent\n\
+                 \t\tHostname: my-host"
+            )
+        );
+    }
+
+    #[test]
+    fn test_connection_status_fmt_imported() {
+        assert_eq!(
+            format!(
+                "{}",
+                ConnectionStatus {
+                    site_data: None,
+                    uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+                    local: local_connection_status(),
+                    remote: Remote::Imported,
+                    known_hostname: None,
+                    tags: BTreeMap::new(),
+                    expiry: None,
+                    counters: None,
+                    receiver_stats: None,
+                }
+            ),
+            String::from(
+                "Imported connection:\n\
+                 \tUUID: 99f56bbc-5965-4b34-bc70-1959ad1d32d6\n\
+                 \tLocal:\n\
+                 \t\tConnection mode: pull-agent\n\
+                 \t\tConnecting to receiver port: None (imported connection)\n\
+                 \t\tCertificate issuer: Site 'site' local CA\n\
+                 \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \tRemote:\n\
+                 \t\tNo remote address (imported connection)"
+            )
+        );
+    }
+
+    #[test]
+    fn test_connection_status_fmt_error() {
+        assert_eq!(
+            format!(
+                "{}",
+                ConnectionStatus {
+                    site_data: Some(SiteData {
+                        site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
+                        receiver_port: 8000,
+                    }),
+                    uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+                    local: local_connection_status(),
+                    remote: Remote::StatusResponse(Err(anyhow!("You shall not pass"))),
+                    known_hostname: None,
+                    tags: BTreeMap::new(),
+                    expiry: None,
+                    counters: None,
+                    receiver_stats: None,
+                }
+            ),
+            String::from(
+                "Connection: localhost/site\n\
+                 \tUUID: 99f56bbc-5965-4b34-bc70-1959ad1d32d6\n\
+                 \tLocal:\n\
+                 \t\tConnection mode: pull-agent\n\
+                 \t\tConnecting to receiver port: 8000\n\
+                 \t\tCertificate issuer: Site 'site' local CA\n\
+                 \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \tRemote:\n\
+                 \t\tError: You shall not pass (!!)"
+            )
+        );
+    }
+
+    #[test]
+    fn test_connection_status_fmt_mismatch_conn_mode() {
+        assert_eq!(
+            format!(
+                "{}",
+                ConnectionStatus {
+                    site_data: Some(SiteData {
+                        site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
+                        receiver_port: 8000,
+                    }),
+                    uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+                    local: local_connection_status(),
+                    remote: Remote::StatusResponse(Ok(
+                        agent_receiver_api::RegistrationStatusV2Response::Registered(
+                            agent_receiver_api::RegistrationStatusV2ResponseRegistered {
+                                connection_mode: config::ConnectionMode::Push,
+                                hostname: String::from("my-host"),
+                            }
+                        )
+                    )),
+                    known_hostname: None,
+                    tags: BTreeMap::new(),
+                    expiry: None,
+                    counters: None,
+                    receiver_stats: None,
+                }
+            ),
+            String::from(
+                "Connection: localhost/site\n\
+                 \tUUID: 99f56bbc-5965-4b34-bc70-1959ad1d32d6\n\
+                 \tLocal:\n\
+                 \t\tConnection mode: pull-agent\n\
+                 \t\tConnecting to receiver port: 8000\n\
+                 \t\tCertificate issuer: Site 'site' local CA\n\
+                 \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \tRemote:\n\
+                 \t\tConnection mode: push-agent (!!)\n\
+                 \t\tHostname: my-host"
+            )
+        );
+    }
+
+    #[test]
+    fn test_connection_status_fmt_receiver_stats() {
+        assert_eq!(
+            format!(
+                "{}",
+                ConnectionStatus {
+                    site_data: Some(SiteData {
+                        site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
+                        receiver_port: 8000,
+                    }),
+                    uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+                    local: local_connection_status(),
+                    remote: Remote::QueryDisabled,
+                    known_hostname: None,
+                    tags: BTreeMap::new(),
+                    expiry: None,
+                    counters: None,
+                    receiver_stats: Some(connection_stats::ReceiverStats {
+                        calls: 40,
+                        successful_calls: 39,
+                        success_ratio: 0.975,
+                        latency_p50_ms: Some(120),
+                        latency_p90_ms: Some(480),
+                        latency_p99_ms: None,
+                    }),
+                }
+            ),
+            String::from(
+                "Connection: localhost/site\n\
+                 \tUUID: 99f56bbc-5965-4b34-bc70-1959ad1d32d6\n\
+                 \tLocal:\n\
+                 \t\tConnection mode: pull-agent\n\
+                 \t\tConnecting to receiver port: 8000\n\
+                 \t\tCertificate issuer: Site 'site' local CA\n\
+                 \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \tRemote:\n\
+                 \t\tRemote query disabled\n\
+                 \tReceiver calls (last 40):\n\
+                 \t\tSuccessful calls: 97.5% (39 of 40)\n\
+                 \t\tLatency: p50 120 ms, p90 480 ms, p99 n/a"
+            )
+        );
+    }
+
+    #[test]
+    fn test_connection_status_fmt_not_registered() {
+        assert_eq!(
+            format!(
+                "{}",
+                ConnectionStatus {
+                    site_data: Some(SiteData {
+                        site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
+                        receiver_port: 8000,
+                    }),
+                    uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+                    local: local_connection_status(),
+                    remote: Remote::StatusResponse(Ok(
+                        agent_receiver_api::RegistrationStatusV2Response::NotRegistered
+                    )),
+                    known_hostname: None,
+                    tags: BTreeMap::new(),
+                    expiry: None,
+                    counters: None,
+                    receiver_stats: None,
+                }
+            ),
+            String::from(
+                "Connection: localhost/site\n\
+                 \tUUID: 99f56bbc-5965-4b34-bc70-1959ad1d32d6\n\
+                 \tLocal:\n\
+                 \t\tConnection mode: pull-agent\n\
+                 \t\tConnecting to receiver port: 8000\n\
+                 \t\tCertificate issuer: Site 'site' local CA\n\
+                 \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+                 \tRemote:\n\
+                 \t\tNot registered (!!)"
+            )
+        );
+    }
+
+    fn pull_config(registry: &config::Registry) -> config::PullConfig {
+        config::PullConfig::new(
+            config::RuntimeConfig::default(),
+            cli::PullOpts {
+                port: None,
+                #[cfg(windows)]
+                agent_channel: None,
+            },
+            registry.clone(),
+        )
+        .unwrap()
+    }
+
+    fn options(json: bool, connection: Option<&str>) -> StatusOptions<'_> {
+        StatusOptions {
+            output: if json {
+                cli::OutputFormat::Json
+            } else {
+                cli::OutputFormat::Human
+            },
+            query_remote: true,
+            connection,
+            tags: &[],
+            live: false,
+            watch: None,
+            max_age: None,
+            query_timeout: None,
+        }
+    }
+
+    fn build_status() -> Status {
+        Status {
+            version: String::from("1.0.0"),
+            agent_socket_operational: true,
+            ip_allowlist: vec![String::from("192.168.1.13"), String::from("[::1]")],
+            allow_legacy_pull: false,
+            payload: None,
+            daemon: None,
+            integrity: None,
+            quarantined: vec![],
+            connections: vec![
+                ConnectionStatus {
+                    site_data: Some(SiteData {
+                        site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
+                        receiver_port: 8000,
+                    }),
+                    uuid: uuid::Uuid::from_str("50611369-7a42-4c0b-927e-9a14330401fe").unwrap(),
+                    local: local_connection_status(),
+                    remote: Remote::StatusResponse(Ok(
+                        agent_receiver_api::RegistrationStatusV2Response::Registered(
+                            agent_receiver_api::RegistrationStatusV2ResponseRegistered {
+                                connection_mode: config::ConnectionMode::Pull,
+                                hostname: String::from("my-host"),
+                            },
+                        ),
+                    )),
+                    known_hostname: None,
+                    tags: BTreeMap::new(),
+                    expiry: None,
+                    counters: None,
+                    receiver_stats: None,
+                },
+                ConnectionStatus {
+                    site_data: Some(SiteData {
+                        site_id: site_spec::SiteID::from_str("somewhere/site2").unwrap(),
+                        receiver_port: 8000,
+                    }),
+                    uuid: uuid::Uuid::from_str("3c87778b-8bb8-434d-bcc6-6d05f2668c80").unwrap(),
+                    local: LocalConnectionStatus {
+                        connection_mode: config::ConnectionMode::Push,
+                        transport: transport::TransportKind::Https,
+                        cert_info: CertParsingResult::Success(CertInfo {
+                            issuer: String::from("Site 'site2' local CA"),
+                            from: String::from("Thu, 16 Dec 2021 08:18:41 +0000"),
+                            to: String::from("Tue, 18 Apr 3020 08:18:41 +0000"),
+                            not_after: 33144106721,
+                        }),
+                    },
+                    remote: Remote::StatusResponse(Ok(
+                        agent_receiver_api::RegistrationStatusV2Response::Registered(
+                            agent_receiver_api::RegistrationStatusV2ResponseRegistered {
+                                connection_mode: config::ConnectionMode::Push,
+                                hostname: String::from("my-host2"),
+                            },
+                        ),
+                    )),
+                    known_hostname: None,
+                    tags: BTreeMap::new(),
+                    expiry: None,
+                    counters: None,
+                    receiver_stats: None,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_status_str_human_readable() {
+        assert_eq!(
+            build_status().to_string(cli::OutputFormat::Human).unwrap(),
+            "Version: 1.0.0\n\
+             Agent socket: operational\n\
+             IP allowlist: 192.168.1.13 [::1]\n\n\n\
+             Connection: localhost/site\n\
+             \tUUID: 50611369-7a42-4c0b-927e-9a14330401fe\n\
+             \tLocal:\n\
+             \t\tConnection mode: pull-agent\n\
+             \t\tConnecting to receiver port: 8000\n\
+             \t\tCertificate issuer: Site 'site' local CA\n\
+             \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+             \tRemote:\n\
+             \t\tConnection mode: pull-agent\n\
+             \t\tHostname: my-host\n\n\n\
+             Connection: somewhere/site2\n\
+             \tUUID: 3c87778b-8bb8-434d-bcc6-6d05f2668c80\n\
+             \tLocal:\n\
+             \t\tConnection mode: push-agent\n\
+             \t\tConnecting to receiver port: 8000\n\
+             \t\tCertificate issuer: Site 'site2' local CA\n\
+             \t\tCertificate validity: Thu, 16 Dec 2021 08:18:41 +0000 - Tue, 18 Apr 3020 08:18:41 +0000\n\
+             \tRemote:\n\
+             \t\tConnection mode: push-agent\n\
+             \t\tHostname: my-host2"
+        );
+    }
+
+    #[test]
+    fn test_status_str_json() {
+        assert_eq!(
+            build_status().to_string(cli::OutputFormat::Json).unwrap(),
+            serde_json::to_string(&build_status()).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_status_str_yaml() {
+        assert_eq!(
+            serde_yaml::from_str::<serde_json::Value>(
+                &build_status().to_string(cli::OutputFormat::Yaml).unwrap()
+            )
+            .unwrap(),
+            serde_json::to_value(build_status()).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_status_json_counters() {
+        let mut status = build_status();
+        status.connections[0].counters = Some(connection_stats::ConnectionCounters {
+            successful_pulls: 3,
+            bytes_served: 1024,
+            last_peer_address: Some(String::from("192.168.1.13")),
+            push_history: std::collections::VecDeque::from([
+                connection_stats::PushAttempt::pushed(
+                    1000,
+                    std::time::Duration::from_millis(120),
+                    512,
+                ),
+            ]),
+            ..Default::default()
+        });
+        status.connections[0].receiver_stats =
+            connection_stats::ReceiverStats::from_calls(&[connection_stats::ReceiverCall {
+                timestamp: 1000,
+                duration_ms: 120,
+                success: true,
+            }]);
+        let json: serde_json::Value =
+            serde_json::from_str(&status.to_string(cli::OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["connections"][0]["counters"]["successful_pulls"], 3);
+        assert_eq!(json["connections"][0]["counters"]["bytes_served"], 1024);
+        assert_eq!(
+            json["connections"][0]["counters"]["last_peer_address"],
+            "192.168.1.13"
+        );
+        let attempt = &json["connections"][0]["counters"]["push_history"][0];
+        assert_eq!(attempt["timestamp"], 1000);
+        assert_eq!(attempt["duration_ms"], 120);
+        assert_eq!(attempt["outcome"], "pushed");
+        assert_eq!(attempt["payload_bytes"], 512);
+        assert_eq!(attempt["http_status"], 204);
+        assert!(json["connections"][1].get("counters").is_none());
+        let receiver_stats = &json["connections"][0]["receiver_stats"];
+        assert_eq!(receiver_stats["calls"], 1);
+        assert_eq!(receiver_stats["success_ratio"], 1.0);
+        assert_eq!(receiver_stats["latency_p50_ms"], 120);
+        assert!(json["connections"][1].get("receiver_stats").is_none());
+    }
+
+    #[test]
+    fn test_status_str_empty() {
+        assert_eq!(
+            Status {
+                version: String::from("2.3r18"),
+                agent_socket_operational: false,
+                ip_allowlist: vec![],
+                allow_legacy_pull: true,
+                payload: None,
+                daemon: None,
+                integrity: None,
+                quarantined: vec![],
+                connections: vec![],
+            }
+            .to_string(cli::OutputFormat::Human)
+            .unwrap(),
+            "Version: 2.3r18\n\
+             Agent socket: inoperational (!!)\n\
+             IP allowlist: any\n\
+             Legacy mode: enabled\n\
+             No connections"
+        );
+    }
+
+    struct MockApi {}
+
+    impl agent_receiver_api::RegistrationStatusV2 for MockApi {
+        fn registration_status_v2(
+            &self,
+            _base_url: &reqwest::Url,
+            _connection: &config::TrustedConnection,
+        ) -> AnyhowResult<agent_receiver_api::RegistrationStatusV2Response> {
+            Ok(
+                agent_receiver_api::RegistrationStatusV2Response::Registered(
+                    agent_receiver_api::RegistrationStatusV2ResponseRegistered {
+                        hostname: String::from("host"),
+                        connection_mode: config::ConnectionMode::Pull,
+                    },
+                ),
+            )
+        }
+    }
+
+    #[test]
+    fn test_status_end_to_end() {
+        let r = config::test_helpers::TestRegistry::new().add_connection(
+            &config::ConnectionMode::Push,
+            "server/push-site",
+            "99f56bbc-5965-4b34-bc70-1959ad1d32d6",
+        );
+
+        let (output, severity) = _status(
+            &r.registry,
+            &pull_config(&r.registry),
+            &options(false, None),
+            &mut RemoteQuery::new(Some(&MockApi {}), None, RemoteStatusCache::default()),
+            &Recorded::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            format!(
+                "Version: {}\n\
+                 Agent socket: {}\n\
+                 IP allowlist: any\n\n\n\
+                 Connection: server/push-site\n\
+                 \tUUID: 99f56bbc-5965-4b34-bc70-1959ad1d32d6\n\
+                 \tLocal:\n\
+                 \t\tConnection mode: push-agent\n\
+                 \t\tConnecting to receiver port: 8000\n\
+                 \t\tCertificate parsing failed (!!)\n\
+                 \tRemote:\n\
+                 \t\tConnection mode: pull-agent (!!)\n\
+                 \t\tHostname: host",
+                constants::VERSION,
+                if cfg!(unix) {
+                    "inoperational (!!)"
+                } else {
+                    "operational"
+                }
+            )
+        );
+        assert_eq!(severity, Severity::Error);
+    }
+
+    #[test]
+    fn test_record_hostnames() {
+        let mut r = config::test_helpers::TestRegistry::new().add_connection(
+            &config::ConnectionMode::Push,
+            "server/push-site",
+            "99f56bbc-5965-4b34-bc70-1959ad1d32d6",
+        );
+        let mut remote_query =
+            RemoteQuery::new(Some(&MockApi {}), None, RemoteStatusCache::default());
+        _status(
+            &r.registry,
+            &pull_config(&r.registry),
+            &options(false, None),
+            &mut remote_query,
+            &Recorded::default(),
+            None,
+        )
+        .unwrap();
+        record_hostnames(&remote_query, &mut r.registry);
+        let known = config::KnownHostname {
+            hostname: String::from("host"),
+            last_seen: remote_query.now,
+        };
+        let (_, connection) = r.registry.get_push_connections().next().unwrap();
+        assert_eq!(connection.hostname.as_ref(), Some(&known));
+        r.registry.clear();
+        r.registry.refresh().unwrap();
+        let (_, connection) = r.registry.get_push_connections().next().unwrap();
+        assert_eq!(connection.hostname, Some(known));
+    }
+
+    #[test]
+    fn test_status_connection_filter() {
+        let r = config::test_helpers::TestRegistry::new()
+            .add_connection(
+                &config::ConnectionMode::Push,
+                "server/push-site",
+                "99f56bbc-5965-4b34-bc70-1959ad1d32d6",
+            )
+            .add_connection(
+                &config::ConnectionMode::Pull,
+                "server/pull-site",
+                "50611369-7a42-4c0b-927e-9a14330401fe",
+            );
+        let filtered = |connection| {
+            _status(
+                &r.registry,
+                &pull_config(&r.registry),
+                &options(true, Some(connection)),
+                &mut RemoteQuery::new(None::<&MockApi>, None, RemoteStatusCache::default()),
+                &Recorded::default(),
+                None,
+            )
+            .map(|(output, _)| {
+                let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+                json["connections"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|conn| String::from(conn["uuid"].as_str().unwrap()))
+                    .collect::<Vec<String>>()
+            })
+        };
+
+        assert_eq!(
+            filtered("server/pull-site").unwrap(),
+            vec!["50611369-7a42-4c0b-927e-9a14330401fe"]
+        );
+        assert_eq!(
+            filtered("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+            vec!["99f56bbc-5965-4b34-bc70-1959ad1d32d6"]
+        );
+        assert!(filtered("server/other-site").is_err());
+    }
+
+    #[test]
+    fn test_status_tag_filter() {
+        let mut tagged =
+            config::TrustedConnectionWithRemote::from("50611369-7a42-4c0b-927e-9a14330401fe");
+        tagged.tags = BTreeMap::from([
+            (String::from("env"), String::from("prod")),
+            (String::from("owner"), String::from("team-x")),
+        ]);
+        let r = config::test_helpers::TestRegistry::new()
+            .add_connection(
+                &config::ConnectionMode::Push,
+                "server/push-site",
+                "99f56bbc-5965-4b34-bc70-1959ad1d32d6",
+            )
+            .add_connection(&config::ConnectionMode::Pull, "server/pull-site", tagged)
+            .add_imported_connection("00c21714-5086-46d7-848e-5be72c715cfd");
+        let status = |tags: &[(String, String)], output| {
+            _status(
+                &r.registry,
+                &pull_config(&r.registry),
+                &StatusOptions {
+                    tags,
+                    query_remote: false,
+                    output,
+                    ..options(false, None)
+                },
+                &mut RemoteQuery::new(None::<&MockApi>, None, RemoteStatusCache::default()),
+                &Recorded::default(),
+                None,
+            )
+            .unwrap()
+            .0
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&status(
+            &[(String::from("env"), String::from("prod"))],
+            cli::OutputFormat::Json,
+        ))
+        .unwrap();
+        let connections = json["connections"].as_array().unwrap();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0]["tags"]["owner"], "team-x");
+        assert!(status(
+            &[(String::from("env"), String::from("test"))],
+            cli::OutputFormat::Json
+        )
+        .contains(r#""connections":[]"#));
+        let human = status(&[], cli::OutputFormat::Human);
+        assert!(human.contains("Tags: env=prod, owner=team-x"));
+        assert_eq!(human.matches("Tags:").count(), 1);
+    }
+
+    struct FailingApi {}
+
+    impl agent_receiver_api::RegistrationStatusV2 for FailingApi {
+        fn registration_status_v2(
+            &self,
+            _base_url: &reqwest::Url,
+            _connection: &config::TrustedConnection,
+        ) -> AnyhowResult<agent_receiver_api::RegistrationStatusV2Response> {
+            Err(anyhow!("Connection refused"))
+        }
+    }
+
+    fn cache_with_entry(uuid: &str, timestamp: u64) -> RemoteStatusCache {
+        RemoteStatusCache(HashMap::from([(
+            String::from(uuid),
+            CachedRemoteStatus {
+                timestamp,
+                response: agent_receiver_api::RegistrationStatusV2Response::NotRegistered,
+            },
+        )]))
+    }
+
+    #[test]
+    fn test_remote_query_cache() {
+        let uuid = "99f56bbc-5965-4b34-bc70-1959ad1d32d6";
+        let site_id = site_spec::SiteID::from_str("server/site").unwrap();
+        let conn = config::TrustedConnectionWithRemote::from(uuid);
+        let is_cached = |remote: &Remote, expected_age: u64, failed: bool| {
+            matches!(remote, Remote::Cached { age, query_error, .. }
+                if *age == expected_age && query_error.is_some() == failed)
+        };
+
+        // Querying updates the cache
+        let mut remote_query = RemoteQuery::new(Some(&MockApi {}), None, cache_with_entry(uuid, 0));
+        remote_query.now = 100;
+        assert!(matches!(
+            remote_query.remote(&site_id, &conn),
+            Remote::StatusResponse(Ok(..))
+        ));
+        assert_eq!(remote_query.cache.0[uuid].timestamp, 100);
+
+        // Young enough cached results are used without querying
+        let mut remote_query =
+            RemoteQuery::new(Some(&FailingApi {}), Some(60), cache_with_entry(uuid, 50));
+        remote_query.now = 100;
+        assert!(is_cached(&remote_query.remote(&site_id, &conn), 50, false));
+        remote_query.max_age = Some(30);
+        assert!(is_cached(&remote_query.remote(&site_id, &conn), 50, true));
+
+        // Without cached results, errors are reported as before
+        remote_query.cache = RemoteStatusCache::default();
+        assert!(matches!(
+            remote_query.remote(&site_id, &conn),
+            Remote::StatusResponse(Err(..))
+        ));
+
+        // Querying disabled
+        let mut remote_query = RemoteQuery::new(None::<&MockApi>, None, cache_with_entry(uuid, 50));
+        remote_query.now = 100;
+        assert!(is_cached(&remote_query.remote(&site_id, &conn), 50, false));
+        remote_query.cache = RemoteStatusCache::default();
+        assert!(matches!(
+            remote_query.remote(&site_id, &conn),
+            Remote::QueryDisabled
+        ));
+    }
+
+    struct CountingApi(std::sync::atomic::AtomicUsize);
+
+    impl agent_receiver_api::RegistrationStatusV2 for CountingApi {
+        fn registration_status_v2(
+            &self,
+            base_url: &reqwest::Url,
+            connection: &config::TrustedConnection,
+        ) -> AnyhowResult<agent_receiver_api::RegistrationStatusV2Response> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            match base_url.host_str() {
+                Some("dead") => Err(anyhow!("Connection refused")),
+                _ => MockApi {}.registration_status_v2(base_url, connection),
+            }
+        }
+    }
+
+    #[test]
+    fn test_remote_query_prefetch() {
+        let uuids = [
+            "99f56bbc-5965-4b34-bc70-1959ad1d32d6",
+            "00c21714-5086-46d7-848e-5be72c715cfd",
+            "0096abd7-83c9-42f8-8b3a-3ffba7ba959d",
+        ];
+        let connections: Vec<_> = ["server/site1", "dead/site2", "server/site3"]
+            .iter()
+            .zip(uuids)
+            .map(|(site_id, uuid)| {
+                (
+                    site_spec::SiteID::from_str(site_id).unwrap(),
+                    config::TrustedConnectionWithRemote::from(uuid),
+                )
+            })
+            .collect();
+        let api = CountingApi(std::sync::atomic::AtomicUsize::new(0));
+        // The third one is cached recently enough
+        let mut remote_query =
+            RemoteQuery::new(Some(&api), Some(60), cache_with_entry(uuids[2], 50));
+        remote_query.now = 100;
+        remote_query.prefetch(connections.iter().map(|(site_id, conn)| (site_id, conn)));
+        assert_eq!(api.0.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(remote_query.prefetched.len(), 2);
+
+        let remotes: Vec<_> = connections
+            .iter()
+            .map(|(site_id, conn)| remote_query.remote(site_id, conn))
+            .collect();
+        assert!(matches!(remotes[0], Remote::StatusResponse(Ok(..))));
+        assert!(matches!(remotes[1], Remote::StatusResponse(Err(..))));
+        assert!(matches!(remotes[2], Remote::Cached { age: 50, .. }));
+        // Nothing is queried twice
+        assert_eq!(api.0.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert!(remote_query.prefetched.is_empty());
+    }
+
+    #[test]
+    fn test_cached_remote_output() {
+        let connection_status = ConnectionStatus {
+            site_data: Some(SiteData {
+                site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
+                receiver_port: 8000,
+            }),
+            uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+            local: local_connection_status(),
+            remote: Remote::Cached {
+                response: agent_receiver_api::RegistrationStatusV2Response::Registered(
+                    agent_receiver_api::RegistrationStatusV2ResponseRegistered {
+                        hostname: String::from("my-host"),
+                        connection_mode: config::ConnectionMode::Pull,
+                    },
+                ),
+                age: 125,
+                query_error: Some(String::from("Connection refused")),
+            },
+            known_hostname: None,
+            tags: BTreeMap::new(),
+            expiry: None,
+            counters: None,
+            receiver_stats: None,
+        };
+        assert_eq!(
+            connection_status.remote_lines_readable(),
+            vec![
+                "Connection mode: pull-agent",
+                "Hostname: my-host",
+                "Cached result from 2m ago",
+                "Query failed: Connection refused (!!)",
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&connection_status.remote).unwrap(),
+            "{\"status\":\"Registered\",\"hostname\":\"my-host\",\"connection_mode\":\"pull-agent\",\"cache_age\":125,\"query_error\":\"Connection refused\"}"
+        );
+        assert_eq!(connection_status.severity(0), Severity::Error);
+    }
+
+    #[test]
+    fn test_remote_error_output() {
+        let remote = Remote::StatusResponse(Err(anyhow::Error::from(
+            agent_receiver_api::ResponseError::new(reqwest::StatusCode::UNAUTHORIZED, None),
+        )
+        .context("Failed to query remote status")));
+        assert_eq!(
+            serde_json::to_value(&remote).unwrap(),
+            serde_json::json!({
+                "error": "Failed to query remote status",
+                "error_code": 3001,
+                "error_category": "auth",
+            })
+        );
+    }
+
+    #[test]
+    fn test_remote_status_cache_io() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("remote_status_cache.json");
+        assert!(RemoteStatusCache::load(&path).0.is_empty());
+
+        let r = config::test_helpers::TestRegistry::new().add_connection(
+            &config::ConnectionMode::Push,
+            "server/push-site",
+            "99f56bbc-5965-4b34-bc70-1959ad1d32d6",
+        );
+        let mut cache = cache_with_entry("99f56bbc-5965-4b34-bc70-1959ad1d32d6", 10);
+        cache
+            .0
+            .extend(cache_with_entry("50611369-7a42-4c0b-927e-9a14330401fe", 20).0);
+        cache.retain_registered(&r.registry);
+        cache.save(&path).unwrap();
+        let loaded = RemoteStatusCache::load(&path);
+        assert_eq!(
+            loaded.0.keys().collect::<Vec<_>>(),
+            vec!["99f56bbc-5965-4b34-bc70-1959ad1d32d6"]
+        );
+        assert_eq!(
+            loaded.0["99f56bbc-5965-4b34-bc70-1959ad1d32d6"].timestamp,
+            10
+        );
+    }
+
+    #[test]
+    fn test_cert_info_severity() {
+        let cert_info = cert_info();
+        assert_eq!(cert_info.severity(0), Severity::Ok);
+        assert_eq!(
+            cert_info.severity(cert_info.not_after - 86400),
+            Severity::Warning
+        );
+        assert_eq!(cert_info.severity(cert_info.not_after), Severity::Error);
+    }
+
+    #[test]
+    fn test_connection_status_severity() {
+        let connection_status = |remote| ConnectionStatus {
+            site_data: Some(SiteData {
+                site_id: site_spec::SiteID::from_str("localhost/site").unwrap(),
+                receiver_port: 8000,
+            }),
+            uuid: uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap(),
+            local: local_connection_status(),
+            remote,
+            known_hostname: None,
+            tags: BTreeMap::new(),
+            expiry: None,
+            counters: None,
+            receiver_stats: None,
+        };
+        let registered = |connection_mode| {
+            Remote::StatusResponse(Ok(
+                agent_receiver_api::RegistrationStatusV2Response::Registered(
+                    agent_receiver_api::RegistrationStatusV2ResponseRegistered {
+                        connection_mode,
+                        hostname: String::from("my-host"),
+                    },
+                ),
+            ))
+        };
+
+        assert_eq!(
+            connection_status(registered(config::ConnectionMode::Pull)).severity(0),
+            Severity::Ok
+        );
+        assert_eq!(
+            connection_status(Remote::QueryDisabled).severity(0),
+            Severity::Ok
+        );
+        assert_eq!(
+            connection_status(registered(config::ConnectionMode::Push)).severity(0),
+            Severity::Warning
+        );
+        assert_eq!(
+            connection_status(Remote::StatusResponse(Err(anyhow!("Connection refused"))))
+                .severity(0),
+            Severity::Error
+        );
+        assert_eq!(
+            connection_status(Remote::StatusResponse(Ok(
+                agent_receiver_api::RegistrationStatusV2Response::NotRegistered
+            )))
+            .severity(0),
+            Severity::Error
+        );
+    }
+
+    #[test]
+    fn test_status_severity() {
+        let mut status = build_status();
+        assert_eq!(status.severity(0), Severity::Ok);
+        status.connections[1].local.connection_mode = config::ConnectionMode::Pull;
+        assert_eq!(status.severity(0), Severity::Warning);
+        status.agent_socket_operational = false;
+        assert_eq!(status.severity(0), Severity::Error);
+        assert_eq!(
+            [Severity::Ok, Severity::Warning, Severity::Error].map(|severity| severity.exit_code()),
+            [0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_status_payload() {
+        let mut status = build_status();
+        status.payload = Some(payload_stats::PayloadSample {
+            timestamp: 0,
+            total_bytes: 80 * 1024 * 1024,
+            sections: std::collections::BTreeMap::new(),
+            state: payload_stats::PayloadState::Warn,
+            summary: String::from("Total: 80.0 MiB (warn/crit at 50.0 MiB/100.0 MiB)(!)"),
+        });
+        assert_eq!(status.severity(0), Severity::Warning);
+        assert!(status
+            .to_string(cli::OutputFormat::Human)
+            .unwrap()
+            .contains("\nAgent output: Total: 80.0 MiB (warn/crit at 50.0 MiB/100.0 MiB)(!)\n"));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(
+                &status.to_string(cli::OutputFormat::Json).unwrap()
+            )
+            .unwrap()["payload"]["state"],
+            "warn"
+        );
+    }
+
+    #[test]
+    fn test_status_daemon() {
+        let mut status = build_status();
+        status.daemon = Some(Daemon::Running(ipc::DaemonStatus {
+            pid: 4711,
+            version: String::from("1.0.0"),
+            started: misc::unix_now() - 7500,
+            state: String::from("running"),
+            log_level: String::from("info"),
+            scheduler: crate::scheduler_state::SchedulerReport {
+                push: BTreeMap::from([(
+                    String::from("99f56bbc-5965-4b34-bc70-1959ad1d32d6"),
+                    crate::scheduler_state::ConnectionSchedule {
+                        next_push: 1120,
+                        consecutive_failures: 3,
+                        spool_depth: 2,
+                    },
+                )]),
+                next_renewal_check: Some(5000),
+            },
+            restarts: BTreeMap::from([(
+                String::from("pull"),
+                crate::supervisor::TaskReport {
+                    restarts: 2,
+                    history: std::collections::VecDeque::from([crate::supervisor::TaskRestart {
+                        timestamp: misc::unix_now() - 120,
+                        reason: String::from("bind failed"),
+                    }]),
+                },
+            )]),
+        }));
+        assert_eq!(status.severity(0), Severity::Ok);
+        assert!(status
+            .to_string(cli::OutputFormat::Human)
+            .unwrap()
+            .contains(
+                "\nDaemon: running as PID 4711 for 2h 5m, log level 'info'\n\
+                 \tpull task restarted 2 time(s), last 2m ago: bind failed (!!)\n"
+            ));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(
+                &status.to_string(cli::OutputFormat::Json).unwrap()
+            )
+            .unwrap()["daemon"]["pid"],
+            4711
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(
+                &status.to_string(cli::OutputFormat::Json).unwrap()
+            )
+            .unwrap()["daemon"]["scheduler"]["push"]["99f56bbc-5965-4b34-bc70-1959ad1d32d6"]
+                ["consecutive_failures"],
+            3
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        status.daemon = Some(Daemon::query(&dir.path().join("test.sock")));
+        assert_eq!(status.severity(0), Severity::Warning);
+        assert!(status
+            .to_string(cli::OutputFormat::Human)
+            .unwrap()
+            .contains("\nDaemon: not reachable (Failed to connect to "));
+    }
+
+    #[test]
+    fn test_status_integrity() {
+        let mut status = build_status();
+        status.integrity = Some(integrity::IntegrityReport {
+            timestamp: misc::unix_now() - 300,
+            checked_connections: 2,
+            failures: vec![],
+        });
+        assert_eq!(status.severity(0), Severity::Ok);
+        assert!(status
+            .to_string(cli::OutputFormat::Human)
+            .unwrap()
+            .contains("\nIntegrity check: ok, 2 connection(s) checked 5m ago\n"));
+
+        status.integrity = Some(integrity::IntegrityReport {
+            timestamp: misc::unix_now() - 300,
+            checked_connections: 2,
+            failures: vec![
+                String::from("Connection server/push-site: Invalid private key"),
+                String::from("Connection server/pull-site: Invalid certificate"),
+            ],
+        });
+        assert_eq!(status.severity(0), Severity::Error);
+        assert!(status
+            .to_string(cli::OutputFormat::Human)
+            .unwrap()
+            .contains(
+            "\nIntegrity check: failed 5m ago: Connection server/push-site: Invalid private key; \
+             Connection server/pull-site: Invalid certificate (!!)\n"
+        ));
+        let json: serde_json::Value =
+            serde_json::from_str(&status.to_string(cli::OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["integrity"]["failures"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_status_quarantined() {
+        let mut status = build_status();
+        status.quarantined = vec![Quarantined {
+            entry: String::from("server/broken-site (push)"),
+            quarantined_at: 1000,
+            reason: String::from("Invalid certificate"),
+        }];
+        assert_eq!(status.severity(0), Severity::Warning);
+        assert!(status
+            .to_string(cli::OutputFormat::Human)
+            .unwrap()
+            .contains(
+            "\nQuarantined registry entry: server/broken-site (push): Invalid certificate (!!)\n"
+        ));
+        let json: serde_json::Value =
+            serde_json::from_str(&status.to_string(cli::OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["quarantined"][0]["reason"], "Invalid certificate");
+    }
+
+    #[test]
+    fn test_status_clock_skew() {
+        let mut status = build_status();
+        status.connections[0].counters = Some(connection_stats::ConnectionCounters {
+            clock_skew: Some(connection_stats::ClockSkew {
+                timestamp: 0,
+                skew_secs: 600,
+            }),
+            ..Default::default()
+        });
+        assert_eq!(status.severity(0), Severity::Warning);
+        assert!(status
+            .to_string(cli::OutputFormat::Human)
+            .unwrap()
+            .contains("\n\t\tClock: 10m ahead (!!)"));
+    }
+
+    #[test]
+    fn test_highlight_changes() {
+        let previous = "Version: 1.0.0\nAgent socket: operational\nHostname: a";
+        let current = "Version: 1.0.0\nAgent socket: inoperational (!!)\nHostname: a\nNew line";
+        assert_eq!(highlight_changes(None, current), current);
+        assert_eq!(
+            highlight_changes(Some(previous), current),
+            "Version: 1.0.0\n\
+             \x1b[7mAgent socket: inoperational (!!)\x1b[0m\n\
+             Hostname: a\n\
+             \x1b[7mNew line\x1b[0m"
+        );
+    }
+}
@@ -0,0 +1,440 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Collects what support needs to look into a problem in a compressed tar archive: logs, the
+//! config with secrets redacted, the registered connections without keys and certificates, and
+//! the usage and connection statistics.
+
+use crate::config::{self, LogFileConfig};
+use crate::setup::PathResolver;
+use crate::{certs, constants, misc};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use flate2::write::GzEncoder;
+use log::warn;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+const REDACTED: &str = "<redacted>";
+const TAR_BLOCK_SIZE: usize = 512;
+
+#[derive(Serialize)]
+struct BundleInfo {
+    version: &'static str,
+    os: String,
+    /// Unix timestamp
+    created_at: u64,
+    /// Parts which could not be collected
+    problems: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ConnectionMetadata {
+    mode: config::ConnectionMode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    site_id: Option<String>,
+    uuid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receiver_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    push_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hostname: Option<config::KnownHostname>,
+    certificate: CertificateMetadata,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum CertificateMetadata {
+    Parsed {
+        issuer: String,
+        not_before: String,
+        not_after: String,
+    },
+    Unparsable {
+        error: String,
+    },
+}
+
+impl CertificateMetadata {
+    fn from(certificate: &str) -> Self {
+        let parse = || -> AnyhowResult<Self> {
+            let pem = certs::parse_pem(certificate)?;
+            let x509 = pem.parse_x509()?;
+            Ok(Self::Parsed {
+                issuer: certs::common_names(x509.issuer())?.join(", "),
+                not_before: x509.validity().not_before.to_rfc2822(),
+                not_after: x509.validity().not_after.to_rfc2822(),
+            })
+        };
+        parse().unwrap_or_else(|err| Self::Unparsable {
+            error: err.to_string(),
+        })
+    }
+}
+
+impl ConnectionMetadata {
+    fn new(
+        mode: config::ConnectionMode,
+        site_id: Option<String>,
+        trust: &config::TrustedConnection,
+    ) -> Self {
+        Self {
+            mode,
+            site_id,
+            uuid: trust.uuid.to_string(),
+            receiver_port: None,
+            push_interval: None,
+            hostname: None,
+            certificate: CertificateMetadata::from(&trust.certificate),
+        }
+    }
+
+    fn with_remote(self, connection: &config::TrustedConnectionWithRemote) -> Self {
+        Self {
+            receiver_port: Some(connection.receiver_port),
+            push_interval: connection.push_interval,
+            hostname: connection.hostname.clone(),
+            ..self
+        }
+    }
+}
+
+/// The registered connections, without private keys and certificates
+fn registry_metadata(registry: &config::Registry) -> Vec<ConnectionMetadata> {
+    let standard = |mode: config::ConnectionMode| {
+        move |(site_id, connection): (&_, &config::TrustedConnectionWithRemote)| {
+            ConnectionMetadata::new(mode.clone(), Some(format!("{site_id}")), &connection.trust)
+                .with_remote(connection)
+        }
+    };
+    registry
+        .get_push_connections()
+        .map(standard(config::ConnectionMode::Push))
+        .chain(
+            registry
+                .get_standard_pull_connections()
+                .map(standard(config::ConnectionMode::Pull)),
+        )
+        .chain(
+            registry
+                .get_imported_pull_connections()
+                .map(|trust| ConnectionMetadata::new(config::ConnectionMode::Pull, None, trust)),
+        )
+        .collect()
+}
+
+fn redact(value: &mut serde_json::Value) {
+    // The user names to anonymize are as sensitive as the agent output they are removed from
+    misc::redact_secrets(value, &["users"], REDACTED)
+}
+
+/// The config file as JSON with secrets redacted. Files which cannot be parsed are left out, as
+/// the secrets in them cannot be told apart.
+fn sanitized_config(path: &Path) -> AnyhowResult<Option<String>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(path)?;
+    let mut value: serde_json::Value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&content)?,
+        _ => serde_json::from_str(&content)?,
+    };
+    redact(&mut value);
+    Ok(Some(serde_json::to_string_pretty(&value)?))
+}
+
+#[cfg(unix)]
+fn log_file_path(log_file_config: &LogFileConfig) -> Option<PathBuf> {
+    log_file_config.path.clone()
+}
+
+#[cfg(windows)]
+fn log_file_path(_log_file_config: &LogFileConfig) -> Option<PathBuf> {
+    std::env::var(constants::ENV_AGENT_LOG_DIR)
+        .ok()
+        .map(|dir| PathBuf::from(dir).join("cmk-agent-ctl.log"))
+}
+
+/// The log file and its rotated predecessors, eg. cmk-agent-ctl_r00001.log.gz
+fn log_files(log_file: &Path) -> AnyhowResult<Vec<PathBuf>> {
+    let (Some(dir), Some(file_name), Some(stem)) = (
+        log_file.parent(),
+        log_file.file_name(),
+        log_file.file_stem().and_then(|stem| stem.to_str()),
+    ) else {
+        bail!("Invalid log file {:?}", log_file);
+    };
+    // Not just any file starting with the stem, the log file may be next to the config file
+    let rotated_prefix = format!("{stem}_r");
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file()
+            && (path.file_name() == Some(file_name)
+                || path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with(&rotated_prefix)))
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Minimal writer of tar archives in the ustar format, which is all a support bundle needs
+struct TarWriter<W: Write> {
+    writer: W,
+    mtime: u64,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn new(writer: W, mtime: u64) -> Self {
+        Self { writer, mtime }
+    }
+
+    fn header(&self, name: &str, size: u64) -> io::Result<[u8; TAR_BLOCK_SIZE]> {
+        if name.len() > 99 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("File name too long for tar archive: {name}"),
+            ));
+        }
+        let mut header = [0; TAR_BLOCK_SIZE];
+        let mut field = |offset: usize, value: &[u8]| {
+            header[offset..offset + value.len()].copy_from_slice(value)
+        };
+        field(0, name.as_bytes());
+        field(100, b"0000600\0");
+        field(108, b"0000000\0");
+        field(116, b"0000000\0");
+        field(124, format!("{size:011o}\0").as_bytes());
+        field(136, format!("{:011o}\0", self.mtime).as_bytes());
+        // The checksum is computed with its own field set to spaces
+        field(148, b"        ");
+        field(156, b"0");
+        field(257, b"ustar\x0000");
+        let checksum: u32 = header.iter().map(|byte| u32::from(*byte)).sum();
+        header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+        Ok(header)
+    }
+
+    fn append(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.writer
+            .write_all(&self.header(name, data.len() as u64)?)?;
+        self.writer.write_all(data)?;
+        let padding = (TAR_BLOCK_SIZE - data.len() % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+        self.writer.write_all(&vec![0; padding])
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.writer.write_all(&[0; 2 * TAR_BLOCK_SIZE])?;
+        Ok(self.writer)
+    }
+}
+
+fn write_bundle(
+    writer: impl Write,
+    paths: &PathResolver,
+    registry: &config::Registry,
+    log_file: Option<&Path>,
+) -> AnyhowResult<()> {
+    let now = misc::unix_now();
+    let mut tar = TarWriter::new(GzEncoder::new(writer, flate2::Compression::default()), now);
+    let mut problems = vec![];
+
+    for (name, path) in [
+        ("config/cmk-agent-ctl.json", &paths.config_path),
+        (
+            "config/pre_configured_connections.json",
+            &paths.pre_configured_connections_path,
+        ),
+    ] {
+        match sanitized_config(path) {
+            Ok(Some(config)) => tar.append(name, config.as_bytes())?,
+            Ok(None) => {}
+            Err(err) => problems.push(format!("Left out {path:?}: {err}")),
+        }
+    }
+    tar.append(
+        "registry.json",
+        serde_json::to_string_pretty(&registry_metadata(registry))?.as_bytes(),
+    )?;
+    for (name, path) in [
+        ("stats/usage_stats.json", &paths.usage_stats_path),
+        ("stats/connection_stats.json", &paths.connection_stats_path),
+    ] {
+        if path.exists() {
+            tar.append(name, &fs::read(path)?)?;
+        }
+    }
+    match log_file.map(log_files) {
+        Some(Ok(files)) => {
+            for file in files {
+                let name = file.file_name().unwrap_or_default().to_string_lossy();
+                match fs::read(&file) {
+                    Ok(content) => tar.append(&format!("logs/{name}"), &content)?,
+                    Err(err) => problems.push(format!("Left out {file:?}: {err}")),
+                }
+            }
+        }
+        Some(Err(err)) => problems.push(format!("Could not collect the log files: {err}")),
+        None => problems.push(String::from(
+            "No log file configured, the controller logs to the system log or standard error",
+        )),
+    }
+    for problem in &problems {
+        warn!("{}", problem);
+    }
+    tar.append(
+        "info.json",
+        serde_json::to_string_pretty(&BundleInfo {
+            version: constants::VERSION,
+            os: os_info::get().to_string(),
+            created_at: now,
+            problems,
+        })?
+        .as_bytes(),
+    )?;
+    tar.finish()?.finish()?.flush()?;
+    Ok(())
+}
+
+pub fn support_bundle(
+    paths: &PathResolver,
+    registry: &config::Registry,
+    log_file_config: &LogFileConfig,
+    output: Option<&Path>,
+) -> AnyhowResult<()> {
+    let log_file = log_file_path(log_file_config);
+    match output {
+        Some(output) => write_bundle(
+            fs::File::create(output)
+                .with_context(|| format!("Failed to create support bundle {output:?}"))?,
+            paths,
+            registry,
+            log_file.as_deref(),
+        ),
+        None => {
+            if io::stdout().is_terminal() {
+                bail!("Refusing to write the support bundle to a terminal, use --output or redirect standard output");
+            }
+            write_bundle(io::stdout().lock(), paths, registry, log_file.as_deref())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_support_bundle {
+    use super::*;
+    use crate::configuration::config::test_helpers::TestRegistry;
+    use std::io::Read;
+
+    #[test]
+    fn test_redact() {
+        let mut value = serde_json::json!({
+            "no_proxy": true,
+            "connections": {
+                "server:8000/site": {
+                    "credentials": {"username": "automation", "password": "secret"},
+                },
+            },
+            "post_processors": [{"anonymize_users": {"users": ["alice"]}}],
+            "api_token": "abc",
+        });
+        redact(&mut value);
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "no_proxy": true,
+                "connections": {
+                    "server:8000/site": {
+                        "credentials": {"username": "automation", "password": "<redacted>"},
+                    },
+                },
+                "post_processors": [{"anonymize_users": {"users": "<redacted>"}}],
+                "api_token": "<redacted>",
+            })
+        );
+    }
+
+    #[test]
+    fn test_sanitized_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cmk-agent-ctl.toml");
+        assert!(sanitized_config(&path).unwrap().is_none());
+        fs::write(&path, "push_interval = 30\nrelay_secret = \"abc\"\n").unwrap();
+        let config: serde_json::Value =
+            serde_json::from_str(&sanitized_config(&path).unwrap().unwrap()).unwrap();
+        assert_eq!(
+            config,
+            serde_json::json!({"push_interval": 30, "relay_secret": "<redacted>"})
+        );
+        fs::write(&path, "relay_secret = ").unwrap();
+        assert!(sanitized_config(&path).is_err());
+    }
+
+    #[test]
+    fn test_tar_header() {
+        let tar = TarWriter::new(vec![], 0);
+        let header = tar.header("info.json", 1000).unwrap();
+        assert_eq!(&header[..9], b"info.json");
+        assert_eq!(&header[124..136], b"00000001750\0");
+        let checksum: u32 = header[..148]
+            .iter()
+            .chain(b"        ")
+            .chain(&header[156..])
+            .map(|byte| u32::from(*byte))
+            .sum();
+        assert_eq!(&header[148..156], format!("{checksum:06o}\0 ").as_bytes());
+        assert!(tar.header(&"x".repeat(100), 0).is_err());
+    }
+
+    #[test]
+    fn test_write_bundle() {
+        let registry = TestRegistry::new().fill_registry();
+        let dir = tempfile::tempdir().unwrap();
+        let paths = PathResolver::new(dir.path());
+        fs::write(&paths.config_path, "push_interval = 30\n").unwrap();
+        fs::write(&paths.usage_stats_path, "{\"days\": []}").unwrap();
+        let log_file = dir.path().join("cmk-agent-ctl.log");
+        fs::write(&log_file, "INFO something\n").unwrap();
+        fs::write(dir.path().join("cmk-agent-ctl_r00001.log"), "INFO before\n").unwrap();
+
+        let mut bundle = vec![];
+        write_bundle(&mut bundle, &paths, &registry.registry, Some(&log_file)).unwrap();
+        let mut tar = vec![];
+        flate2::read::GzDecoder::new(bundle.as_slice())
+            .read_to_end(&mut tar)
+            .unwrap();
+        let mut names = vec![];
+        let mut offset = 0;
+        while tar[offset] != 0 {
+            let header = &tar[offset..offset + 512];
+            let name_end = header.iter().position(|byte| *byte == 0).unwrap();
+            names.push(String::from_utf8(header[..name_end].to_vec()).unwrap());
+            let size =
+                usize::from_str_radix(std::str::from_utf8(&header[124..135]).unwrap(), 8).unwrap();
+            offset += 512 + size + (512 - size % 512) % 512;
+        }
+        assert_eq!(
+            names,
+            [
+                "config/cmk-agent-ctl.json",
+                "registry.json",
+                "stats/usage_stats.json",
+                "logs/cmk-agent-ctl.log",
+                "logs/cmk-agent-ctl_r00001.log",
+                "info.json",
+            ]
+        );
+        let tar = String::from_utf8_lossy(&tar);
+        assert!(tar.contains("\"site_id\": \"server/push-site\""));
+        assert!(tar.contains("\"mode\": \"pull-agent\""));
+        assert!(!tar.contains("private_key"));
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::renew_certificate;
+use crate::{cli, config, output};
+use anyhow::Result as AnyhowResult;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Tags of a connection, as written with --output
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Tagged {
+    pub site_id: String,
+    pub tags: BTreeMap<String, String>,
+}
+
+impl std::fmt::Display for Tagged {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.tags.is_empty() {
+            return write!(f, "No tags for '{}'", self.site_id);
+        }
+        write!(f, "Tags for '{}':", self.site_id)?;
+        for (key, value) in &self.tags {
+            write!(f, "\n{key}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Set and remove tags of a connection. The registry is only saved if anything changed.
+pub fn tag(
+    registry: &mut config::Registry,
+    ident: &str,
+    set: Vec<(String, String)>,
+    remove: &[String],
+) -> AnyhowResult<Tagged> {
+    let (connection, site_id) = renew_certificate::find_site_for_ident(registry, ident)?;
+    let before = connection.tags.clone();
+    for key in remove {
+        connection.tags.remove(key);
+    }
+    connection.tags.extend(set);
+    let tagged = Tagged {
+        site_id: site_id.to_string(),
+        tags: connection.tags.clone(),
+    };
+    if tagged.tags != before {
+        registry.save()?;
+    }
+    Ok(tagged)
+}
+
+pub fn set_tags(
+    registry: &mut config::Registry,
+    tag_opts: cli::TagOpts,
+    output_format: cli::OutputFormat,
+) -> AnyhowResult<()> {
+    let tagged = tag(
+        registry,
+        &tag_opts.connection_opts.connection,
+        tag_opts.set,
+        &tag_opts.remove,
+    )?;
+    output::print(output_format, &tagged, &tagged.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::site_spec;
+    use config::test_helpers::TestRegistry;
+    use std::str::FromStr;
+
+    fn tag_pair(key: &str, value: &str) -> (String, String) {
+        (String::from(key), String::from(value))
+    }
+
+    #[test]
+    fn test_tag() {
+        let mut registry = TestRegistry::new().add_connection(
+            &config::ConnectionMode::Push,
+            "server/site",
+            config::TrustedConnectionWithRemote::from("0096abd7-83c9-42f8-8b3a-3ffba7ba959d"),
+        );
+        let site_id = site_spec::SiteID::from_str("server/site").unwrap();
+        let tagged = tag(
+            &mut registry.registry,
+            "server/site",
+            vec![tag_pair("env", "prod"), tag_pair("owner", "team-x")],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            tagged,
+            Tagged {
+                site_id: String::from("server/site"),
+                tags: BTreeMap::from([tag_pair("env", "prod"), tag_pair("owner", "team-x")]),
+            }
+        );
+        assert_eq!(
+            tagged.to_string(),
+            "Tags for 'server/site':\nenv=prod\nowner=team-x"
+        );
+        assert_eq!(
+            config::Registry::from_file(registry.registry.path())
+                .unwrap()
+                .get(&site_id)
+                .unwrap()
+                .tags,
+            tagged.tags
+        );
+
+        let tagged = tag(
+            &mut registry.registry,
+            "0096abd7-83c9-42f8-8b3a-3ffba7ba959d",
+            vec![tag_pair("env", "test")],
+            &[String::from("owner"), String::from("unknown")],
+        )
+        .unwrap();
+        assert_eq!(tagged.tags, BTreeMap::from([tag_pair("env", "test")]));
+
+        let tagged = tag(
+            &mut registry.registry,
+            "server/site",
+            vec![],
+            &[String::from("env")],
+        )
+        .unwrap();
+        assert_eq!(tagged.to_string(), "No tags for 'server/site'");
+        assert!(tag(&mut registry.registry, "server/unknown", vec![], &[]).is_err());
+    }
+}
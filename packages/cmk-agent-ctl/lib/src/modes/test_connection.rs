@@ -0,0 +1,433 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::doctor::Finding;
+use super::status::{ProblemsFound, Severity};
+use crate::agent_receiver_api::CredentialsCheck;
+use crate::{certs, config, constants, happy_eyeballs, misc, site_spec, types};
+use anyhow::{Context, Result as AnyhowResult};
+use http::StatusCode;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// Outcome of a step: the value the following steps depend on, or the finding explaining why
+/// they cannot be performed
+type StepResult<T> = Result<(T, Finding), Finding>;
+
+fn format_elapsed(elapsed: Duration) -> String {
+    format!("{} ms", elapsed.as_millis())
+}
+
+/// Runs and times a step and reports its finding
+fn timed<T>(report: &mut impl FnMut(Finding), step: impl FnOnce() -> StepResult<T>) -> Option<T> {
+    let start = Instant::now();
+    let result = step();
+    let elapsed = format_elapsed(start.elapsed());
+    let (value, mut finding) = match result {
+        Ok((value, finding)) => (Some(value), finding),
+        Err(finding) => (None, finding),
+    };
+    finding.subject = format!("{} ({})", finding.subject, elapsed);
+    report(finding);
+    value
+}
+
+/// In the order the addresses are tried, see happy_eyeballs
+fn resolve(server: &str, ip_preference: happy_eyeballs::IpPreference) -> StepResult<Vec<IpAddr>> {
+    let subject = "DNS resolution";
+    let addresses: Vec<IpAddr> = match (server, 0).to_socket_addrs() {
+        Ok(addresses) => happy_eyeballs::sort(addresses, ip_preference)
+            .iter()
+            .map(|address| address.ip())
+            .collect(),
+        Err(err) => {
+            return Err(Finding::problem(
+                Severity::Error,
+                subject,
+                format!("Cannot resolve {server}: {err}"),
+                "Check the DNS resolution of the Checkmk server",
+            ))
+        }
+    };
+    let message = format!(
+        "{} resolved to {}",
+        server,
+        addresses
+            .iter()
+            .map(|address| address.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+    Ok((addresses, Finding::ok(subject, message)))
+}
+
+fn discover_port(
+    site_id: &site_spec::SiteID,
+    client_config: &config::ClientConfig,
+) -> StepResult<u16> {
+    let subject = "Port discovery";
+    match site_spec::discover_receiver_port(site_id, client_config) {
+        Ok(port) => Ok((
+            port,
+            Finding::ok(
+                subject,
+                format!("Agent receiver listens on port {port} according to the REST API"),
+            ),
+        )),
+        Err(err) => Err(Finding::problem(
+            Severity::Error,
+            subject,
+            misc::anyhow_error_to_human_readable(&err),
+            "Pass the port of the agent receiver as \"<server>:<port>/<site>\", or check that \
+             the site is running and its web server is reachable",
+        )),
+    }
+}
+
+fn connect(addresses: &[IpAddr], port: u16) -> StepResult<TcpStream> {
+    let subject = "TCP connection";
+    let addresses: Vec<SocketAddr> = addresses
+        .iter()
+        .map(|address| SocketAddr::new(*address, port))
+        .collect();
+    match happy_eyeballs::connect_timeout(
+        &addresses,
+        Duration::from_secs(constants::DOCTOR_CONNECT_TIMEOUT),
+    )
+    .and_then(|stream| Ok((stream.peer_addr()?, stream)))
+    {
+        Ok((address, stream)) => Ok((
+            stream,
+            Finding::ok(subject, format!("Connected to {address}")),
+        )),
+        Err(err) => Err(Finding::problem(
+            Severity::Error,
+            subject,
+            format!("Cannot connect to port {port}: {err}"),
+            "Check the firewalls between this host and the Checkmk server. This check does not \
+             apply if the server is only reachable via a proxy.",
+        )),
+    }
+}
+
+fn describe_certificate(certificate: &str) -> AnyhowResult<String> {
+    let pem = certs::parse_pem(certificate)?;
+    let x509 = pem.parse_x509()?;
+    Ok(format!(
+        "{}, issued by {}, valid until {}",
+        certs::common_names(x509.subject())?.join(", "),
+        certs::common_names(x509.issuer())?.join(", "),
+        x509.validity().not_after.to_rfc2822()
+    ))
+}
+
+fn describe_chain(chain: &[String]) -> String {
+    chain
+        .iter()
+        .enumerate()
+        .map(|(index, certificate)| {
+            format!(
+                "\n         {}: {}",
+                index,
+                describe_certificate(certificate)
+                    .unwrap_or_else(|err| format!("Cannot parse certificate: {err}"))
+            )
+        })
+        .collect()
+}
+
+fn handshake(tcp_stream: TcpStream, server: &str) -> StepResult<()> {
+    let subject = "TLS handshake";
+    let timeout = Some(Duration::from_secs(constants::DOCTOR_CONNECT_TIMEOUT));
+    match tcp_stream
+        .set_read_timeout(timeout)
+        .and_then(|_| tcp_stream.set_write_timeout(timeout))
+        .context("Failed to set socket timeouts")
+        .and_then(|_| certs::probe_tls(tcp_stream, server))
+    {
+        Ok((version, chain)) => Ok((
+            (),
+            Finding::ok(
+                subject,
+                format!(
+                    "Negotiated {}, the server presented this certificate chain:{}",
+                    version,
+                    describe_chain(&chain)
+                ),
+            ),
+        )),
+        Err(err) => Err(Finding::problem(
+            Severity::Error,
+            subject,
+            misc::anyhow_error_to_human_readable(&err),
+            "Make sure that the port belongs to the agent receiver of the site and that no \
+             TLS-intercepting device is in between",
+        )),
+    }
+}
+
+fn authentication_finding(status: StatusCode) -> Finding {
+    let subject = "Authentication";
+    match status {
+        StatusCode::UNAUTHORIZED => Finding::problem(
+            Severity::Error,
+            subject,
+            "The credentials were rejected",
+            "Check the name and the password of the API user",
+        ),
+        StatusCode::FORBIDDEN => Finding::problem(
+            Severity::Error,
+            subject,
+            "The API user lacks the permissions for registering hosts",
+            "Use a user with the permissions for registering hosts, e.g. an administrator",
+        ),
+        status if status.is_server_error() => Finding::problem(
+            Severity::Error,
+            subject,
+            format!("The agent receiver failed with {status}"),
+            "Check the log of the agent receiver on the Checkmk server",
+        ),
+        // Asking for a registration that does not exist results in a client error
+        status => Finding::ok(
+            subject,
+            format!("Credentials accepted, request answered with {status}"),
+        ),
+    }
+}
+
+fn authenticate(
+    api: &impl CredentialsCheck,
+    site_id: &site_spec::SiteID,
+    port: u16,
+    credentials: &types::Credentials,
+) -> StepResult<()> {
+    match site_spec::make_site_url(site_id, &port)
+        .and_then(|url| api.check_credentials(&url, credentials))
+    {
+        Ok(status) => {
+            let finding = authentication_finding(status);
+            match finding.severity {
+                Severity::Ok => Ok(((), finding)),
+                _ => Err(finding),
+            }
+        }
+        Err(err) => Err(Finding::problem(
+            Severity::Error,
+            "Authentication",
+            format!(
+                "Querying the agent receiver failed: {}",
+                misc::anyhow_error_to_human_readable(&err)
+            ),
+            "Check the proxy settings, the request is sent via the proxy if proxy detection is \
+             enabled",
+        )),
+    }
+}
+
+pub fn prompt_password(user: &str) -> AnyhowResult<String> {
+    eprint!("Please enter password for '{user}'\n> ");
+    rpassword::read_password().context("Failed to obtain API password")
+}
+
+fn run_steps(
+    coordinates: &site_spec::Coordinates,
+    client_config: &config::ClientConfig,
+    credentials: Option<&types::Credentials>,
+    api: &impl CredentialsCheck,
+    report: &mut impl FnMut(Finding),
+) -> Option<()> {
+    let site_id = &coordinates.site_id;
+    let addresses = timed(report, || {
+        resolve(&site_id.server, client_config.ip_preference)
+    })?;
+    let port = match coordinates.port {
+        Some(port) => port,
+        None => timed(report, || discover_port(site_id, client_config))?,
+    };
+    let tcp_stream = timed(report, || connect(&addresses, port))?;
+    timed(report, || handshake(tcp_stream, &site_id.server))?;
+    match credentials {
+        Some(credentials) => timed(report, || authenticate(api, site_id, port, credentials)),
+        None => {
+            report(Finding {
+                severity: Severity::Ok,
+                subject: String::from("Authentication"),
+                message: String::from("Skipped"),
+                hint: Some(String::from(
+                    "Pass --user to check the credentials used for registering",
+                )),
+            });
+            Some(())
+        }
+    }
+}
+
+pub fn test_connection(
+    coordinates: &site_spec::Coordinates,
+    client_config: &config::ClientConfig,
+    user: Option<String>,
+    password: Option<String>,
+    api: &impl CredentialsCheck,
+) -> AnyhowResult<()> {
+    let credentials = match user {
+        Some(username) => Some(types::Credentials {
+            password: match password {
+                Some(password) => password,
+                None => prompt_password(&username)?,
+            },
+            username,
+        }),
+        None => None,
+    };
+    let mut report = |finding: Finding| println!("{finding}");
+    match run_steps(
+        coordinates,
+        client_config,
+        credentials.as_ref(),
+        api,
+        &mut report,
+    ) {
+        Some(()) => {
+            println!("\nConnection to {} is working", coordinates.site_id);
+            Ok(())
+        }
+        None => {
+            println!("\nConnection to {} failed", coordinates.site_id);
+            Err(ProblemsFound(Severity::Error).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy;
+    use std::str::FromStr;
+
+    struct MockApi {
+        status: StatusCode,
+    }
+
+    impl CredentialsCheck for MockApi {
+        fn check_credentials(
+            &self,
+            base_url: &reqwest::Url,
+            credentials: &types::Credentials,
+        ) -> AnyhowResult<StatusCode> {
+            assert_eq!(base_url.as_str(), "https://localhost:8000/site");
+            assert_eq!(credentials.username, "automation");
+            Ok(self.status)
+        }
+    }
+
+    fn credentials() -> types::Credentials {
+        types::Credentials {
+            username: String::from("automation"),
+            password: String::from("secret"),
+        }
+    }
+
+    #[test]
+    fn test_timed() {
+        let mut findings = vec![];
+        let value = timed(&mut |finding| findings.push(finding), || {
+            Ok((42, Finding::ok("Step", "Done")))
+        });
+        assert_eq!(value, Some(42));
+        assert!(findings[0].subject.starts_with("Step ("));
+        assert!(findings[0].subject.ends_with(" ms)"));
+
+        let value: Option<()> = timed(&mut |finding| findings.push(finding), || {
+            Err(Finding::problem(
+                Severity::Error,
+                "Step",
+                "Failed",
+                "Fix it",
+            ))
+        });
+        assert!(value.is_none());
+        assert_eq!(findings[1].severity, Severity::Error);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let (addresses, finding) =
+            resolve("localhost", happy_eyeballs::IpPreference::Ipv4).unwrap();
+        assert!(addresses.iter().all(|address| address.is_loopback()));
+        assert!(addresses[0].is_ipv4());
+        assert!(finding.message.starts_with("localhost resolved to "));
+        assert!(resolve("does-not-exist.invalid", happy_eyeballs::IpPreference::Ipv6).is_err());
+    }
+
+    #[test]
+    fn test_connect_and_handshake() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let localhost = [IpAddr::from([127, 0, 0, 1])];
+        let (tcp_stream, finding) = connect(&localhost, port).unwrap();
+        assert_eq!(finding.message, format!("Connected to 127.0.0.1:{port}"));
+
+        // The listener does not speak TLS
+        drop(listener);
+        let failed = handshake(tcp_stream, "localhost").unwrap_err();
+        assert_eq!(failed.severity, Severity::Error);
+        assert!(connect(&localhost, port).is_err());
+    }
+
+    #[test]
+    fn test_describe_chain() {
+        assert_eq!(
+            describe_chain(&[
+                String::from(constants::TEST_CERT_OK),
+                String::from("no certificate")
+            ]),
+            "\n         0: heute, issued by Site 'heute' local CA, valid until \
+             Sat, 14 Oct 3020 10:14:25 +0000\
+             \n         1: Cannot parse certificate: Input data does not contain a PEM block"
+        );
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let site_id = site_spec::SiteID::from_str("localhost/site").unwrap();
+        let check = |status| {
+            authenticate(&MockApi { status }, &site_id, 8000, &credentials())
+                .map(|(_, finding)| finding)
+        };
+        assert_eq!(
+            check(StatusCode::NOT_FOUND).unwrap().message,
+            "Credentials accepted, request answered with 404 Not Found"
+        );
+        assert_eq!(
+            check(StatusCode::UNAUTHORIZED).unwrap_err().message,
+            "The credentials were rejected"
+        );
+        assert!(check(StatusCode::FORBIDDEN).is_err());
+        assert!(check(StatusCode::BAD_GATEWAY).is_err());
+    }
+
+    #[test]
+    fn test_run_steps_stops_at_first_failure() {
+        let mut findings = vec![];
+        let result = run_steps(
+            &site_spec::Coordinates::from_str("does-not-exist.invalid:8000/site").unwrap(),
+            &config::ClientConfig {
+                proxy_mode: proxy::ProxyMode::Direct,
+                validate_api_cert: false,
+                source_address: None,
+                ip_preference: happy_eyeballs::IpPreference::Ipv6,
+                request_headers: Default::default(),
+                timeouts: Default::default(),
+            },
+            Some(&credentials()),
+            &MockApi {
+                status: StatusCode::NOT_FOUND,
+            },
+            &mut |finding| findings.push(finding),
+        );
+        assert!(result.is_none());
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].subject.starts_with("DNS resolution"));
+    }
+}
@@ -0,0 +1,58 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::cli;
+use crate::vault::{StoredConnection, Vault};
+use anyhow::Result as AnyhowResult;
+use std::path::Path;
+
+pub fn vault(
+    vault_path: &Path,
+    vault_key_path: &Path,
+    action: cli::VaultAction,
+) -> AnyhowResult<()> {
+    let vault = Vault::new(vault_path, vault_key_path);
+    match action {
+        cli::VaultAction::List(list_opts) => {
+            match list_opts.tenant {
+                Some(tenant) => println!(
+                    "{}",
+                    render_connections(&vault.tenant(&tenant)?.connections()?)
+                ),
+                None => println!("{}", vault.tenants()?.join("\n")),
+            }
+            Ok(())
+        }
+        cli::VaultAction::Export(host_opts) => {
+            println!(
+                "{}",
+                vault.tenant(&host_opts.tenant)?.load(&host_opts.hostname)?
+            );
+            Ok(())
+        }
+        cli::VaultAction::Remove(host_opts) => {
+            vault
+                .tenant(&host_opts.tenant)?
+                .remove(&host_opts.hostname)?;
+            println!(
+                "Removed connection of {} for tenant '{}'",
+                host_opts.hostname, host_opts.tenant
+            );
+            Ok(())
+        }
+    }
+}
+
+fn render_connections(connections: &[StoredConnection]) -> String {
+    connections
+        .iter()
+        .map(|connection| {
+            format!(
+                "{}: registered with {} as {}",
+                connection.host_name, connection.site_id, connection.uuid
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
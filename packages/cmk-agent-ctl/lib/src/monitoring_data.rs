@@ -0,0 +1,109 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use serde::Deserialize;
+use std::io::{Result as IoResult, Write};
+
+#[cfg(any(windows, test))]
+mod agent_frame;
+#[cfg(unix)]
+mod linux;
+#[cfg(unix)]
+pub use linux::{async_collect, collect};
+#[cfg(windows)]
+mod win;
+#[cfg(windows)]
+pub use win::{async_collect, collect};
+
+/// Name of the section started by the given line of agent output, e.g. "df" for "<<<df:sep(9)>>>".
+/// Piggyback headers ("<<<<host>>>>") do not start a section.
+pub fn section_header(line: &[u8]) -> Option<&[u8]> {
+    if line.starts_with(b"<<<<") {
+        return None;
+    }
+    let header = line.strip_prefix(b"<<<")?.strip_suffix(b">>>")?;
+    Some(header.split(|byte| *byte == b':').next().unwrap_or(header))
+}
+
+pub fn compress(data: &[u8]) -> IoResult<Vec<u8>> {
+    let mut zlib_enc = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    zlib_enc.write_all(data)?;
+    zlib_enc.finish()
+}
+
+pub fn compress_zstd(data: &[u8]) -> IoResult<Vec<u8>> {
+    zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+}
+
+/// Compression algorithm of pushed agent data, announced to the receiver in the compression header
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PushCompression {
+    #[default]
+    Zlib,
+    Zstd,
+}
+
+impl PushCompression {
+    pub fn header(&self) -> &'static str {
+        match self {
+            Self::Zlib => "zlib",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> IoResult<Vec<u8>> {
+        match self {
+            Self::Zlib => compress(data),
+            Self::Zstd => compress_zstd(data),
+        }
+    }
+}
+
+pub struct CompressionHeaderInfo {
+    pub push: String,
+    pub pull: Vec<u8>,
+}
+
+pub fn compression_header_info() -> CompressionHeaderInfo {
+    CompressionHeaderInfo {
+        push: String::from("zlib"),
+        pull: b"\x01".to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_section_header() {
+        assert_eq!(section_header(b"<<<df:sep(9)>>>"), Some(&b"df"[..]));
+        assert_eq!(section_header(b"<<<uptime>>>"), Some(&b"uptime"[..]));
+        assert_eq!(section_header(b"<<<<piggy>>>>"), None);
+        assert_eq!(section_header(b"/ 42%"), None);
+    }
+
+    #[test]
+    fn test_compress() {
+        let input_str = "abc";
+        let compressed_data = compress(input_str.as_bytes()).unwrap();
+        let mut zlib_dec = flate2::read::ZlibDecoder::new(&compressed_data[..]);
+        let mut decompressed_str = String::new();
+        zlib_dec.read_to_string(&mut decompressed_str).unwrap();
+        assert_eq!(input_str, decompressed_str);
+    }
+
+    #[test]
+    fn test_compress_zstd() {
+        let compressed_data = PushCompression::Zstd.compress(b"abc").unwrap();
+        assert_eq!(zstd::decode_all(&compressed_data[..]).unwrap(), b"abc");
+        assert_eq!(PushCompression::Zstd.header(), "zstd");
+        assert_eq!(
+            PushCompression::Zlib.header(),
+            compression_header_info().push
+        );
+    }
+}
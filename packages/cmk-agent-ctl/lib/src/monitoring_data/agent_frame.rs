@@ -0,0 +1,158 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Framing of the messages exchanged with the Windows agent service over its named pipe. Every
+//! message is one frame: magic, protocol version and kind (u16 each), the payload length (u32),
+//! all little endian, followed by the payload.
+//!
+//! * the controller sends a request, its payload is the IP address of the peer as text
+//! * the agent answers with the agent output, or with an error, its payload is the reason as text
+//!
+//! Attention: must be in sync with windows agent code
+
+use std::io::{Error, ErrorKind, Result as IoResult};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const MAGIC: u16 = 0x4b43; // "CK"
+pub const PROTOCOL_VERSION: u16 = 1;
+const HEADER_LENGTH: usize = 10;
+/// Upper bound of the payload, st. a corrupt header does not make us allocate gigabytes
+const MAX_PAYLOAD_LENGTH: u32 = 512 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Request = 1,
+    Output = 2,
+    Error = 3,
+}
+
+impl TryFrom<u16> for Kind {
+    type Error = Error;
+
+    fn try_from(kind: u16) -> IoResult<Self> {
+        match kind {
+            1 => Ok(Self::Request),
+            2 => Ok(Self::Output),
+            3 => Ok(Self::Error),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown frame kind {kind}"),
+            )),
+        }
+    }
+}
+
+pub async fn write(
+    writer: &mut (impl AsyncWrite + Unpin),
+    kind: Kind,
+    payload: &[u8],
+) -> IoResult<()> {
+    let length = u32::try_from(payload.len())
+        .ok()
+        .filter(|length| *length <= MAX_PAYLOAD_LENGTH)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Payload too large"))?;
+    let mut frame = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    frame.extend_from_slice(&MAGIC.to_le_bytes());
+    frame.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    frame.extend_from_slice(&(kind as u16).to_le_bytes());
+    frame.extend_from_slice(&length.to_le_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+pub async fn read(reader: &mut (impl AsyncRead + Unpin)) -> IoResult<(Kind, Vec<u8>)> {
+    let mut header = [0; HEADER_LENGTH];
+    reader.read_exact(&mut header).await?;
+    let field = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+    if field(0) != MAGIC {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Not a frame of the agent channel protocol",
+        ));
+    }
+    if field(2) != PROTOCOL_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Agent speaks protocol version {}, expected {}",
+                field(2),
+                PROTOCOL_VERSION
+            ),
+        ));
+    }
+    let kind = Kind::try_from(field(4))?;
+    let length = u32::from_le_bytes([header[6], header[7], header[8], header[9]]);
+    if length > MAX_PAYLOAD_LENGTH {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Frame of {length} bytes exceeds the limit"),
+        ));
+    }
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok((kind, payload))
+}
+
+#[cfg(test)]
+mod test_agent_frame {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let mut buffer = vec![];
+        write(&mut buffer, Kind::Request, b"192.168.0.1")
+            .await
+            .unwrap();
+        write(&mut buffer, Kind::Output, b"").await.unwrap();
+        assert_eq!(
+            &buffer[..HEADER_LENGTH],
+            &[0x43, 0x4b, 1, 0, 1, 0, 11, 0, 0, 0]
+        );
+        let mut reader = buffer.as_slice();
+        assert_eq!(
+            read(&mut reader).await.unwrap(),
+            (Kind::Request, b"192.168.0.1".to_vec())
+        );
+        assert_eq!(read(&mut reader).await.unwrap(), (Kind::Output, vec![]));
+        assert_eq!(
+            read(&mut reader).await.unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_invalid() {
+        for (frame, message) in [
+            (
+                vec![0x00, 0x4b, 1, 0, 2, 0, 0, 0, 0, 0],
+                "Not a frame of the agent channel protocol",
+            ),
+            (
+                vec![0x43, 0x4b, 2, 0, 2, 0, 0, 0, 0, 0],
+                "Agent speaks protocol version 2, expected 1",
+            ),
+            (
+                vec![0x43, 0x4b, 1, 0, 9, 0, 0, 0, 0, 0],
+                "Unknown frame kind 9",
+            ),
+            (
+                vec![0x43, 0x4b, 1, 0, 2, 0, 0xff, 0xff, 0xff, 0xff],
+                "Frame of 4294967295 bytes exceeds the limit",
+            ),
+        ] {
+            let err = read(&mut frame.as_slice()).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), message);
+        }
+        // Truncated payload
+        assert_eq!(
+            read(&mut [0x43, 0x4b, 1, 0, 2, 0, 4, 0, 0, 0, b'o', b'u'].as_slice())
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use crate::types::AgentChannel;
+use std::io::{Error, ErrorKind, Read, Result as IoResult, Write};
+
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream as AsyncUnixStream;
+
+/// Only root and the user running the controller are trusted to provide the agent output
+fn trusted(uid: u32) -> bool {
+    uid == 0 || uid == nix::unistd::geteuid().as_raw()
+}
+
+fn untrusted(message: String) -> Error {
+    Error::new(ErrorKind::PermissionDenied, message)
+}
+
+/// Refuse sockets another user could have put in place, ie. sockets not owned by a trusted user
+/// or in a directory other users may write to (unless sticky, like /tmp).
+fn verify_socket_path(path: &Path) -> IoResult<()> {
+    let path = path.canonicalize()?;
+    let metadata = path.metadata()?;
+    if !metadata.file_type().is_socket() {
+        return Err(untrusted(format!("{path:?} is not a socket")));
+    }
+    if !trusted(metadata.uid()) {
+        return Err(untrusted(format!(
+            "{:?} is owned by user id {}",
+            path,
+            metadata.uid()
+        )));
+    }
+    if let Some(dir) = path.parent() {
+        let metadata = dir.metadata()?;
+        let writable_by_others = metadata.mode() & 0o022 != 0 && metadata.mode() & 0o1000 == 0;
+        if !trusted(metadata.uid()) || writable_by_others {
+            return Err(untrusted(format!("{dir:?} may be modified by other users")));
+        }
+    }
+    Ok(())
+}
+
+/// The peer credentials are obtained via SO_PEERCRED under Linux and getpeereid under macOS and BSD
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(fd: RawFd) -> IoResult<u32> {
+    Ok(nix::sys::socket::getsockopt(fd, nix::sys::socket::sockopt::PeerCredentials)?.uid())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(fd: RawFd) -> IoResult<u32> {
+    Ok(nix::unistd::getpeereid(fd)?.0.as_raw())
+}
+
+/// Refuse to talk to an agent endpoint served by an untrusted user, even if the socket path looked
+/// fine, eg. because it was replaced in between.
+fn verify_peer(stream: &impl AsRawFd) -> IoResult<()> {
+    let uid = peer_uid(stream.as_raw_fd())?;
+    match trusted(uid) {
+        true => Ok(()),
+        false => Err(untrusted(format!(
+            "Agent socket is served by user id {uid}"
+        ))),
+    }
+}
+
+pub async fn async_collect(
+    agent_channel: &AgentChannel,
+    remote_ip: std::net::IpAddr,
+) -> IoResult<Vec<u8>> {
+    verify_socket_path(agent_channel.as_ref())?;
+    let mut agent_stream = AsyncUnixStream::connect(agent_channel).await?;
+    verify_peer(&agent_stream)?;
+    agent_stream
+        .write_all(format!("{remote_ip}\n").as_bytes())
+        .await?;
+    let mut data: Vec<u8> = vec![];
+    agent_stream.read_to_end(&mut data).await?;
+    Ok(data)
+}
+
+pub fn collect(agent_channel: &AgentChannel) -> IoResult<Vec<u8>> {
+    verify_socket_path(agent_channel.as_ref())?;
+    let mut agent_stream = UnixStream::connect(agent_channel)?;
+    verify_peer(&agent_stream)?;
+    agent_stream.write_all("\n".as_bytes())?; // No remote IP, signalize agent to continue and collect
+    let mut data: Vec<u8> = vec![];
+    agent_stream.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test_linux {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn serve_once(listener: std::os::unix::net::UnixListener) {
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut line = [0; 1];
+            stream.read_exact(&mut line).unwrap();
+            stream.write_all(b"<<<check_mk>>>").unwrap();
+        });
+    }
+
+    #[test]
+    fn test_collect() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.socket");
+        serve_once(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert_eq!(
+            collect(&AgentChannel::from(path)).unwrap(),
+            b"<<<check_mk>>>"
+        );
+    }
+
+    #[test]
+    fn test_verify_socket_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.socket");
+        let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        assert!(verify_socket_path(&path).is_ok());
+
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+        assert_eq!(
+            verify_socket_path(&path).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o1777)).unwrap();
+        assert!(verify_socket_path(&path).is_ok());
+
+        let file = dir.path().join("agent.txt");
+        fs::write(&file, "<<<check_mk>>>").unwrap();
+        assert_eq!(
+            verify_socket_path(&file).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            verify_socket_path(&dir.path().join("missing.socket"))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_verify_peer() {
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        assert!(verify_peer(&ours).is_ok());
+    }
+}
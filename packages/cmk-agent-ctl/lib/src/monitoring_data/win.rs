@@ -2,7 +2,9 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
+use super::agent_frame::{self, Kind};
 use crate::{
+    constants,
     mailslot_transport::{self, MailSlotBackend},
     types::AgentChannel,
 };
@@ -13,7 +15,11 @@ use async_std::prelude::*;
 use std::net::IpAddr;
 
 use std::io::{Error, ErrorKind, Result as IoResult};
-use std::time::Duration;
+use std::os::windows::io::AsRawHandle;
+use std::time::{Duration, Instant};
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient};
+use winapi::shared::winerror::{ERROR_PIPE_BUSY, ERROR_SUCCESS};
+use winapi::um::{accctrl, aclapi, securitybaseapi, winbase, winnt};
 
 /// Absolute max time to wait the agent
 const MAX_ANSWER_WAIT_TIME: Duration = Duration::from_secs(180);
@@ -22,9 +28,11 @@ const MAX_ANSWER_WAIT_TIME: Duration = Duration::from_secs(180);
 enum ChannelType {
     Ip,
     Mailslot,
+    Pipe,
 }
 
 impl AgentChannel {
+    const CHANNEL_PIPE_PREFIX: &'static str = "np";
     const CHANNEL_MAILSLOT_PREFIX: &'static str = "ms";
     const CHANNEL_IP_PREFIX: &'static str = "ip";
     const CHANNEL_PREFIX_SEPARATOR: char = '/';
@@ -38,7 +46,7 @@ impl AgentChannel {
 
     /// Parse windows agent channel as a pattern"type/address"
     /// where
-    ///     type is either "ms" or "ip"
+    ///     type is either "np", "ms" or "ip"
     ///     address is arbitrary string
     fn parse(&self) -> IoResult<(ChannelType, String)> {
         let split = self.split();
@@ -54,6 +62,7 @@ impl AgentChannel {
         }
         let addr = split[1].to_string();
         match split[0] {
+            Self::CHANNEL_PIPE_PREFIX => Ok((ChannelType::Pipe, addr)),
             Self::CHANNEL_MAILSLOT_PREFIX => Ok((ChannelType::Mailslot, addr)),
             Self::CHANNEL_IP_PREFIX => Ok((ChannelType::Ip, addr)),
             _ => Err(Error::new(
@@ -135,9 +144,89 @@ async fn async_collect_from_mailslot(agent_mailslot: &str, remote_ip: IpAddr) ->
     Ok(value)
 }
 
+/// Opens the named pipe of the agent service, waiting for a free instance if all are busy.
+///
+/// The identification level only allows the agent to check who we are, not to act as us.
+async fn open_pipe(path: &str) -> IoResult<NamedPipeClient> {
+    let deadline = Instant::now() + Duration::from_secs(constants::WIN_AGENT_PIPE_BUSY_TIMEOUT);
+    loop {
+        match ClientOptions::new()
+            .security_qos_flags(winbase::SECURITY_IDENTIFICATION)
+            .open(path)
+        {
+            Err(err)
+                if err.raw_os_error() == Some(ERROR_PIPE_BUSY as i32)
+                    && Instant::now() < deadline =>
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Authenticates the agent service: only LocalSystem and the administrators may own the pipe. A
+/// pipe created by a local user under the same name first is rejected.
+fn verify_pipe_owner(pipe: &NamedPipeClient, path: &str) -> IoResult<()> {
+    let mut owner: winnt::PSID = std::ptr::null_mut();
+    let mut descriptor: winnt::PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+    // The owner points into the descriptor, which we free after checking the owner
+    let status = unsafe {
+        aclapi::GetSecurityInfo(
+            pipe.as_raw_handle() as _,
+            accctrl::SE_KERNEL_OBJECT,
+            winnt::OWNER_SECURITY_INFORMATION,
+            &mut owner,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut descriptor,
+        )
+    };
+    if status != ERROR_SUCCESS {
+        return Err(Error::from_raw_os_error(status as i32));
+    }
+    let trusted = unsafe {
+        securitybaseapi::IsWellKnownSid(owner, winnt::WinLocalSystemSid) != 0
+            || securitybaseapi::IsWellKnownSid(owner, winnt::WinBuiltinAdministratorsSid) != 0
+    };
+    unsafe { winbase::LocalFree(descriptor) };
+    match trusted {
+        true => Ok(()),
+        false => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} is not owned by the agent service", path),
+        )),
+    }
+}
+
+/// Sends a request framed as described in agent_frame to the named pipe of the agent service and
+/// awaits the agent output.
+async fn async_collect_from_pipe(pipe_name: &str, remote_ip: IpAddr) -> IoResult<Vec<u8>> {
+    let path = format!("\\\\.\\pipe\\{}", pipe_name);
+    debug!("connect to {}", path);
+    let mut pipe = open_pipe(&path).await?;
+    verify_pipe_owner(&pipe, &path)?;
+    agent_frame::write(&mut pipe, Kind::Request, remote_ip.to_string().as_bytes()).await?;
+    let (kind, payload) = tokio::time::timeout(MAX_ANSWER_WAIT_TIME, agent_frame::read(&mut pipe))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "Agent did not answer in time"))??;
+    match kind {
+        Kind::Output => Ok(payload),
+        Kind::Error => Err(Error::new(
+            ErrorKind::Other,
+            format!("Agent failed: {}", String::from_utf8_lossy(&payload)),
+        )),
+        Kind::Request => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Agent answered with a request",
+        )),
+    }
+}
+
 /// Sends the command to the agent channel and awaits
 ///
-/// This is a simple wrapper for Ip and Mailslot channel
+/// This is a simple wrapper for Ip, Mailslot and Pipe channel
 pub async fn async_collect(
     agent_channel: &AgentChannel,
     remote_ip: std::net::IpAddr,
@@ -146,6 +235,7 @@ pub async fn async_collect(
     match ch_type {
         ChannelType::Ip => async_collect_from_ip(&ch_addr, remote_ip).await,
         ChannelType::Mailslot => async_collect_from_mailslot(&ch_addr, remote_ip).await,
+        ChannelType::Pipe => async_collect_from_pipe(&ch_addr, remote_ip).await,
     }
 }
 
@@ -161,11 +251,17 @@ async fn collect_from_mailslot(mailslot: &str) -> IoResult<Vec<u8>> {
     async_collect_from_mailslot(mailslot, IpAddr::from([127, 0, 0, 1])).await
 }
 
+#[tokio::main(flavor = "current_thread")]
+async fn collect_from_pipe(pipe_name: &str) -> IoResult<Vec<u8>> {
+    async_collect_from_pipe(pipe_name, IpAddr::from([127, 0, 0, 1])).await
+}
+
 pub fn collect(agent_channel: &AgentChannel) -> IoResult<Vec<u8>> {
     let (ch_type, ch_addr) = agent_channel.parse()?;
     let collector = match ch_type {
         ChannelType::Ip => collect_from_ip,
         ChannelType::Mailslot => collect_from_mailslot,
+        ChannelType::Pipe => collect_from_pipe,
     };
     collector(&ch_addr)
 }
@@ -192,6 +288,7 @@ mod tests {
             match *self {
                 ChannelType::Ip => "Ip",
                 ChannelType::Mailslot => "Mailslot",
+                ChannelType::Pipe => "Pipe",
             }
         }
     }
@@ -219,6 +316,7 @@ mod tests {
     #[test]
     fn test_address_channel_parse_valid() {
         for (channel, channel_type) in [
+            ("np/buzz_inc", ChannelType::Pipe),
             ("ms/buzz_inc", ChannelType::Mailslot),
             ("ip/buzz_inc", ChannelType::Ip),
             ("buzz_inc", ChannelType::Ip), // legacy
@@ -260,6 +358,16 @@ mod tests {
         );
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_collect_missing_pipe() {
+        assert_eq!(
+            async_collect(&AgentChannel::from("np/cmk_agent_ctl_missing"), addr())
+                .await
+                .map_err(|e| e.kind()),
+            Err(ErrorKind::NotFound)
+        );
+    }
+
     /// TODO(sk): estimate to move to integration
     #[tokio::test(flavor = "multi_thread")]
     async fn test_async_collect_missing_mailslot() {
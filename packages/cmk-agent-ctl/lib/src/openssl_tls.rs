@@ -0,0 +1,287 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! TLS via OpenSSL instead of rustls, for FIPS mode (see fips.rs). Covers the calls of the agent
+//! receiver API, via native-tls, serving pull requests via TCP and pull-once. OpenSSL works on
+//! blocking streams here, the pull server hands the handshake and the sending over to the
+//! blocking threads of the runtime.
+//!
+//! The certificates are verified against the root certificate of the connection, like with
+//! rustls. Client certificates with a UUID as CN are rejected, except for the own ones. For
+//! server certificates, native-tls offers no hook to check the CN, the chain is verified only.
+
+use crate::data_source::DataSource;
+use crate::{certs, config};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use openssl::pkey::PKey;
+use openssl::ssl::{
+    AlpnError, HandshakeError, NameType, SniError, Ssl, SslAlert, SslContext, SslContextBuilder,
+    SslMethod, SslRef, SslStream, SslVerifyMode, SslVersion,
+};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509StoreContext, X509};
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Protocol list in the wire format of ALPN, ie. prefixed by its length
+pub fn alpn_wire(protocol: &[u8]) -> Vec<u8> {
+    let mut wire = vec![protocol.len() as u8];
+    wire.extend_from_slice(protocol);
+    wire
+}
+
+/// For reqwest, the counterpart of certs::tls_config
+pub fn connector(
+    handshake_credentials: certs::HandshakeCredentials,
+) -> AnyhowResult<native_tls::TlsConnector> {
+    let mut builder = native_tls::TlsConnector::builder();
+    builder
+        .add_root_certificate(native_tls::Certificate::from_pem(
+            handshake_credentials.server_root_cert.as_bytes(),
+        )?)
+        .disable_built_in_roots(true)
+        // The server certificate names the site, not the host it runs on
+        .danger_accept_invalid_hostnames(true)
+        .min_protocol_version(Some(native_tls::Protocol::Tlsv12));
+    if let Some(identity) = handshake_credentials.client_identity {
+        let mut cert_chain = vec![];
+        for certificate in identity.cert_chain {
+            cert_chain.extend(X509::from_der(&certificate.0)?.to_pem()?);
+        }
+        builder.identity(native_tls::Identity::from_pkcs8(
+            &cert_chain,
+            &PKey::private_key_from_der(&identity.key_der.0)?.private_key_to_pem_pkcs8()?,
+        )?);
+    }
+    Ok(builder.build()?)
+}
+
+/// A failed handshake, along with the connection the client requested via SNI
+pub struct HandshakeFailure {
+    pub server_name: Option<String>,
+    pub error: anyhow::Error,
+}
+
+/// Serving pull requests to the registered pull connections, the counterpart of
+/// tls_server::PullTls. The certificate is chosen by the connection requested via SNI.
+pub struct PullAcceptor {
+    context: SslContext,
+    roots: HashMap<uuid::Uuid, X509>,
+    /// Accepted as client certificates despite their UUID CN, see tls_server::local_certificates
+    local_certificates: Vec<Vec<u8>>,
+    data_sources: HashMap<uuid::Uuid, DataSource>,
+}
+
+impl PullAcceptor {
+    /// The given protocol is agreed on via ALPN, if the client offers it
+    pub fn new<'a>(
+        connections: impl Iterator<Item = &'a config::TrustedConnection>,
+        alpn_protocol: &[u8],
+    ) -> AnyhowResult<Self> {
+        let connections: Vec<&config::TrustedConnection> = connections.collect();
+        let mut roots = HashMap::new();
+        let mut contexts = HashMap::new();
+        let mut local_certificates = vec![];
+        for connection in &connections {
+            let root = X509::from_pem(connection.root_cert.as_bytes())
+                .context(format!("Invalid root certificate of {}", connection.uuid))?;
+            let certificate = X509::from_pem(connection.certificate.as_bytes())
+                .context(format!("Invalid certificate of {}", connection.uuid))?;
+            let mut builder = context_builder(&[&root], alpn_protocol)?;
+            builder.set_certificate(&certificate)?;
+            let private_key = PKey::private_key_from_pem(connection.private_key.as_bytes())?;
+            builder.set_private_key(&private_key)?;
+            contexts.insert(connection.uuid.to_string(), builder.build());
+            local_certificates.push(certificate.to_der()?);
+            roots.insert(connection.uuid, root);
+        }
+
+        let mut builder = context_builder(&roots.values().collect::<Vec<_>>(), alpn_protocol)?;
+        builder.set_servername_callback(move |ssl, alert| {
+            let Some(context) = ssl
+                .servername(NameType::HOST_NAME)
+                .and_then(|name| contexts.get(name))
+            else {
+                *alert = SslAlert::UNRECOGNIZED_NAME;
+                return Err(SniError::ALERT_FATAL);
+            };
+            ssl.set_ssl_context(context)
+                .map_err(|_| SniError::ALERT_FATAL)
+        });
+        Ok(Self {
+            context: builder.build(),
+            roots,
+            local_certificates,
+            data_sources: connections
+                .iter()
+                .filter_map(|conn| Some((conn.uuid, conn.data_source.clone()?)))
+                .collect(),
+        })
+    }
+
+    /// The data source of the given connection, None for the agent socket
+    pub fn data_source(&self, uuid: &uuid::Uuid) -> Option<&DataSource> {
+        self.data_sources.get(uuid)
+    }
+
+    /// Handshake on a blocking stream
+    pub fn accept(&self, stream: TcpStream) -> Result<SslStream<TcpStream>, HandshakeFailure> {
+        let ssl = Ssl::new(&self.context).map_err(|err| HandshakeFailure {
+            server_name: None,
+            error: anyhow::Error::from(err),
+        })?;
+        ssl.accept(stream).map_err(|err| {
+            let server_name = match &err {
+                HandshakeError::Failure(stream) | HandshakeError::WouldBlock(stream) => stream
+                    .ssl()
+                    .servername(NameType::HOST_NAME)
+                    .map(String::from),
+                HandshakeError::SetupFailure(_) => None,
+            };
+            HandshakeFailure {
+                server_name,
+                error: anyhow::Error::from(err),
+            }
+        })
+    }
+
+    /// Check that the client certificate was issued by the root of the connection which was
+    /// requested via SNI, see tls_server::PullAuthorizer. Returns the UUID of this connection.
+    pub fn authorize(&self, ssl: &SslRef) -> AnyhowResult<uuid::Uuid> {
+        let server_name = ssl
+            .servername(NameType::HOST_NAME)
+            .context("Client did not request a connection (no SNI)")?;
+        let uuid = uuid::Uuid::parse_str(server_name).context(format!(
+            "Requested connection is not a valid UUID: {server_name}"
+        ))?;
+        let Some(root) = self.roots.get(&uuid) else {
+            bail!("Requested connection {} is not registered", uuid)
+        };
+        let Some(certificate) = ssl.peer_certificate() else {
+            bail!(
+                "Client did not present a certificate for connection {}",
+                uuid
+            )
+        };
+        let der = certificate.to_der()?;
+        let cn_checker = certs::CNCheckerUUID::try_from(&rustls::Certificate(der.clone()))?;
+        if cn_checker.cn_is_uuid() && !self.local_certificates.contains(&der) {
+            bail!(
+                "CN in client certificate is a valid UUID: {}",
+                cn_checker.cn()
+            )
+        }
+
+        let mut store = X509StoreBuilder::new()?;
+        store.add_cert(root.clone())?;
+        let store = store.build();
+        let mut intermediates = Stack::new()?;
+        // On the server side, the chain does not contain the client certificate itself
+        for intermediate in ssl.peer_cert_chain().into_iter().flatten() {
+            intermediates.push(intermediate.to_owned())?;
+        }
+        let mut context = X509StoreContext::new()?;
+        let verified = context.init(&store, &certificate, &intermediates, |context| {
+            Ok(context.verify_cert()?.then_some(()).ok_or(context.error()))
+        })?;
+        if let Err(err) = verified {
+            bail!("Client certificate was not issued by the root of connection {uuid}: {err}")
+        }
+        Ok(uuid)
+    }
+}
+
+/// Requires a client certificate issued by one of the given roots
+fn context_builder(roots: &[&X509], alpn_protocol: &[u8]) -> AnyhowResult<SslContextBuilder> {
+    let mut builder = SslContext::builder(SslMethod::tls_server())?;
+    builder.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+    builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    let mut store = X509StoreBuilder::new()?;
+    for root in roots {
+        store.add_cert((*root).clone())?;
+    }
+    builder.set_verify_cert_store(store.build())?;
+    let server_protocols = alpn_wire(alpn_protocol);
+    builder.set_alpn_select_callback(move |_, client_protocols| {
+        openssl::ssl::select_next_proto(&server_protocols, client_protocols).ok_or(AlpnError::NOACK)
+    });
+    Ok(builder)
+}
+
+/// The pull server hands the stream over to the blocking threads once the TLS announcement is
+/// sent
+pub fn into_blocking(stream: tokio::net::TcpStream, timeout: u64) -> AnyhowResult<TcpStream> {
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(timeout)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(timeout)))?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod test_pull_acceptor {
+    use super::*;
+    use openssl::ssl::SslConnector;
+    use std::net::TcpListener;
+
+    const UUID: &str = "99f56bbc-5965-4b34-bc70-1959ad1d32d6";
+
+    /// Pull via a connection whose root issued both certificates, as the site does
+    fn pull(client_cn: &str) -> AnyhowResult<uuid::Uuid> {
+        let (root_cert, root_key) = certs::make_ca("site-ca", 1)?;
+        let (certificate, private_key) = certs::make_signed_cert(&root_cert, &root_key, UUID, 1)?;
+        let (client_cert, client_key) =
+            certs::make_signed_cert(&root_cert, &root_key, client_cn, 1)?;
+        let connection = config::TrustedConnection {
+            uuid: uuid::Uuid::parse_str(UUID)?,
+            private_key,
+            certificate,
+            root_cert: root_cert.clone(),
+            source_address: None,
+            receiver_protocol: Default::default(),
+            transport: Default::default(),
+            data_source: None,
+        };
+        let acceptor = PullAcceptor::new([connection].iter(), b"pull")?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+
+        let client = std::thread::spawn(move || -> AnyhowResult<()> {
+            let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+            let mut store = X509StoreBuilder::new()?;
+            store.add_cert(X509::from_pem(root_cert.as_bytes())?)?;
+            builder.set_verify_cert_store(store.build())?;
+            let client_cert = X509::from_pem(client_cert.as_bytes())?;
+            builder.set_certificate(&client_cert)?;
+            let client_key = PKey::private_key_from_pem(client_key.as_bytes())?;
+            builder.set_private_key(&client_key)?;
+            let mut configuration = builder.build().configure()?;
+            configuration.set_verify_hostname(false);
+            configuration.connect(UUID, TcpStream::connect(address)?)?;
+            Ok(())
+        });
+        let (stream, _) = listener.accept()?;
+        let uuid = match acceptor.accept(stream) {
+            Ok(tls_stream) => acceptor.authorize(tls_stream.ssl()),
+            Err(failure) => Err(failure.error),
+        };
+        client.join().unwrap()?;
+        uuid
+    }
+
+    #[test]
+    fn test_authorize_ok() {
+        assert_eq!(pull("site").unwrap().to_string(), UUID);
+    }
+
+    #[test]
+    fn test_authorize_cn_is_uuid() {
+        assert!(pull("cf771eeb-b666-4673-95c9-683960fb2939")
+            .unwrap_err()
+            .to_string()
+            .starts_with("CN in client certificate is a valid UUID"));
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Machine-readable results of the CLI modes, selected with --output. Fields may be added to the
+//! schemas, but are neither renamed nor removed, st. scripts can rely on them.
+
+use crate::cli::OutputFormat;
+use anyhow::{Context, Result as AnyhowResult};
+use serde::Serialize;
+
+/// The result in the selected machine-readable format. Nothing for human-readable output, which
+/// the modes write themselves.
+pub fn render<T: Serialize>(format: OutputFormat, result: &T) -> AnyhowResult<Option<String>> {
+    match format {
+        OutputFormat::Human => Ok(None),
+        OutputFormat::Json => serde_json::to_string(result)
+            .map(Some)
+            .context("Failed to serialize result to JSON"),
+        OutputFormat::Yaml => serde_yaml::to_string(result)
+            .map(|yaml| Some(String::from(yaml.trim_end())))
+            .context("Failed to serialize result to YAML"),
+    }
+}
+
+/// Print the result in the selected machine-readable format, or the human-readable message
+pub fn print<T: Serialize>(format: OutputFormat, result: &T, human: &str) -> AnyhowResult<()> {
+    match render(format, result)? {
+        Some(rendered) => println!("{rendered}"),
+        None if human.is_empty() => {}
+        None => println!("{human}"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_output {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Deleted {
+        uuid: &'static str,
+        site_id: Option<&'static str>,
+    }
+
+    #[test]
+    fn test_render() {
+        let result = Deleted {
+            uuid: "0096abd7-83c9-42f8-8b3a-3ffba7ba959d",
+            site_id: None,
+        };
+        assert_eq!(render(OutputFormat::Human, &result).unwrap(), None);
+        assert_eq!(
+            render(OutputFormat::Json, &result).unwrap().unwrap(),
+            r#"{"uuid":"0096abd7-83c9-42f8-8b3a-3ffba7ba959d","site_id":null}"#
+        );
+        assert_eq!(
+            render(OutputFormat::Yaml, &result).unwrap().unwrap(),
+            "uuid: 0096abd7-83c9-42f8-8b3a-3ffba7ba959d\nsite_id: null"
+        );
+    }
+}
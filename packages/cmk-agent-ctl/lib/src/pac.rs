@@ -0,0 +1,683 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Evaluation of proxy auto-config (PAC) scripts. Instead of embedding a JavaScript engine, the
+//! subset of JavaScript found in PAC scripts is supported: FindProxyForURL made up of if/else
+//! statements, local variables and returns, using the PAC helper functions and string operations.
+
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs, UdpSocket};
+
+/// Name resolution of the PAC helper functions, st. scripts can be evaluated without DNS
+pub trait Resolver {
+    fn resolve(&self, host: &str) -> Option<IpAddr>;
+    fn my_ip_address(&self) -> IpAddr;
+}
+
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str) -> Option<IpAddr> {
+        let addresses: Vec<IpAddr> = (host, 0)
+            .to_socket_addrs()
+            .ok()?
+            .map(|address| address.ip())
+            .collect();
+        // PAC scripts compare against IPv4 networks
+        addresses
+            .iter()
+            .find(|address| address.is_ipv4())
+            .or(addresses.first())
+            .copied()
+    }
+
+    fn my_ip_address(&self) -> IpAddr {
+        // Connecting a UDP socket sends nothing, but selects the address of the outgoing interface
+        UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| {
+                socket.connect("192.0.2.1:80")?;
+                socket.local_addr()
+            })
+            .map(|address| address.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Punct(&'static str),
+}
+
+/// Longest first, st. eg. "===" is not taken for "=="
+const PUNCTUATORS: [&str; 19] = [
+    "===", "!==", "==", "!=", "&&", "||", "<=", ">=", "(", ")", "{", "}", ",", ";", "!", "=", "+",
+    "<", ">",
+];
+
+fn tokenize(source: &str) -> AnyhowResult<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut rest = source;
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return Ok(tokens);
+        }
+        if let Some(comment) = rest.strip_prefix("//") {
+            rest = comment.split_once('\n').map_or("", |(_, rest)| rest);
+            continue;
+        }
+        if let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment.split_once("*/").context("Unterminated comment")?.1;
+            continue;
+        }
+        let first = rest.chars().next().unwrap_or_default();
+        if first == '"' || first == '\'' {
+            let (string, length) = string_literal(rest)?;
+            tokens.push(Token::Str(string));
+            rest = &rest[length..];
+        } else if first.is_ascii_digit() {
+            let length = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Num(rest[..length].parse()?));
+            rest = &rest[length..];
+        } else if first.is_alphabetic() || first == '_' || first == '$' {
+            let length = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(String::from(&rest[..length])));
+            rest = &rest[length..];
+        } else if first == '.' {
+            // Only method calls, numbers start with a digit
+            tokens.push(Token::Punct("."));
+            rest = &rest[1..];
+        } else {
+            let punctuator = PUNCTUATORS
+                .iter()
+                .find(|punctuator| rest.starts_with(*punctuator))
+                .with_context(|| format!("Unexpected character '{first}'"))?;
+            tokens.push(Token::Punct(punctuator));
+            rest = &rest[punctuator.len()..];
+        }
+    }
+}
+
+/// The string starting with the quote at the beginning of the source and its length in the source
+fn string_literal(source: &str) -> AnyhowResult<(String, usize)> {
+    let mut chars = source.char_indices();
+    let (_, quote) = chars.next().context("Missing quote")?;
+    let mut string = String::new();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => {
+                let (_, escaped) = chars.next().context("Unterminated string")?;
+                string.push(match escaped {
+                    'n' => '\n',
+                    't' => '\t',
+                    other => other,
+                });
+            }
+            c if c == quote => return Ok((string, index + 1)),
+            c => string.push(c),
+        }
+    }
+    bail!("Unterminated string")
+}
+
+#[derive(Debug)]
+enum Statement {
+    If(Expr, Box<Statement>, Option<Box<Statement>>),
+    Block(Vec<Statement>),
+    Return(Expr),
+    Assign(String, Expr),
+    Empty,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(Value),
+    Var(String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    Method(Box<Expr>, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Num(f64),
+    Bool(bool),
+    Null,
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Self::Str(string) => !string.is_empty(),
+            Self::Num(number) => *number != 0.0,
+            Self::Bool(boolean) => *boolean,
+            Self::Null => false,
+        }
+    }
+
+    fn string(&self) -> String {
+        match self {
+            Self::Str(string) => string.clone(),
+            Self::Num(number) => number.to_string(),
+            Self::Bool(boolean) => boolean.to_string(),
+            Self::Null => String::from("null"),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> AnyhowResult<Token> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .context("Unexpected end of script")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, punctuator: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Punct(candidate)) if *candidate == punctuator) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Ident(ident)) if ident == keyword) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, punctuator: &str) -> AnyhowResult<()> {
+        if !self.eat(punctuator) {
+            bail!("Expected '{}', found {:?}", punctuator, self.peek());
+        }
+        Ok(())
+    }
+
+    fn ident(&mut self) -> AnyhowResult<String> {
+        match self.next()? {
+            Token::Ident(ident) => Ok(ident),
+            token => bail!("Expected a name, found {:?}", token),
+        }
+    }
+
+    fn statement(&mut self) -> AnyhowResult<Statement> {
+        if self.eat(";") {
+            return Ok(Statement::Empty);
+        }
+        if self.eat("{") {
+            let mut statements = vec![];
+            while !self.eat("}") {
+                statements.push(self.statement()?);
+            }
+            return Ok(Statement::Block(statements));
+        }
+        if self.eat_keyword("if") {
+            self.expect("(")?;
+            let condition = self.expr()?;
+            self.expect(")")?;
+            let then = self.statement()?;
+            let otherwise = if self.eat_keyword("else") {
+                Some(Box::new(self.statement()?))
+            } else {
+                None
+            };
+            return Ok(Statement::If(condition, Box::new(then), otherwise));
+        }
+        if self.eat_keyword("return") {
+            let value = self.expr()?;
+            self.eat(";");
+            return Ok(Statement::Return(value));
+        }
+        self.eat_keyword("var");
+        let name = self.ident()?;
+        self.expect("=")?;
+        let value = self.expr()?;
+        self.eat(";");
+        Ok(Statement::Assign(name, value))
+    }
+
+    fn expr(&mut self) -> AnyhowResult<Expr> {
+        let mut left = self.and()?;
+        while self.eat("||") {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> AnyhowResult<Expr> {
+        let mut left = self.comparison()?;
+        while self.eat("&&") {
+            left = Expr::And(Box::new(left), Box::new(self.comparison()?));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> AnyhowResult<Expr> {
+        let mut left = self.sum()?;
+        while let Some(operator) = ["===", "!==", "==", "!=", "<=", ">=", "<", ">"]
+            .into_iter()
+            .find(|operator| self.eat(operator))
+        {
+            left = Expr::Binary(operator, Box::new(left), Box::new(self.sum()?));
+        }
+        Ok(left)
+    }
+
+    fn sum(&mut self) -> AnyhowResult<Expr> {
+        let mut left = self.unary()?;
+        while self.eat("+") {
+            left = Expr::Binary("+", Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> AnyhowResult<Expr> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        let mut expr = self.primary()?;
+        while self.eat(".") {
+            let method = self.ident()?;
+            self.expect("(")?;
+            self.expect(")")?;
+            expr = Expr::Method(Box::new(expr), method);
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> AnyhowResult<Expr> {
+        Ok(match self.next()? {
+            Token::Str(string) => Expr::Literal(Value::Str(string)),
+            Token::Num(number) => Expr::Literal(Value::Num(number)),
+            Token::Punct("(") => {
+                let expr = self.expr()?;
+                self.expect(")")?;
+                expr
+            }
+            Token::Ident(ident) => match ident.as_str() {
+                "true" => Expr::Literal(Value::Bool(true)),
+                "false" => Expr::Literal(Value::Bool(false)),
+                "null" | "undefined" => Expr::Literal(Value::Null),
+                _ if self.eat("(") => {
+                    let mut args = vec![];
+                    while !self.eat(")") {
+                        if !args.is_empty() {
+                            self.expect(",")?;
+                        }
+                        args.push(self.expr()?);
+                    }
+                    Expr::Call(ident, args)
+                }
+                _ => Expr::Var(ident),
+            },
+            token => bail!("Unexpected {:?}", token),
+        })
+    }
+}
+
+/// A parsed PAC script
+#[derive(Debug)]
+pub struct Script {
+    url_param: String,
+    host_param: String,
+    body: Vec<Statement>,
+}
+
+impl Script {
+    pub fn parse(source: &str) -> AnyhowResult<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+        if !(parser.eat_keyword("function") && parser.eat_keyword("FindProxyForURL")) {
+            bail!("Expected the function FindProxyForURL, other definitions are not supported");
+        }
+        parser.expect("(")?;
+        let url_param = parser.ident()?;
+        parser.expect(",")?;
+        let host_param = parser.ident()?;
+        parser.expect(")")?;
+        let Statement::Block(body) = parser.statement()? else {
+            bail!("Expected the body of FindProxyForURL");
+        };
+        while parser.eat(";") {}
+        if let Some(token) = parser.peek() {
+            bail!("Unexpected {:?} after FindProxyForURL", token);
+        }
+        Ok(Self {
+            url_param,
+            host_param,
+            body,
+        })
+    }
+
+    /// Result of FindProxyForURL, eg. "PROXY proxy:3128; DIRECT"
+    pub fn find_proxy(
+        &self,
+        url: &str,
+        host: &str,
+        resolver: &impl Resolver,
+    ) -> AnyhowResult<String> {
+        let mut evaluation = Evaluation {
+            variables: HashMap::from([
+                (self.url_param.clone(), Value::Str(String::from(url))),
+                (self.host_param.clone(), Value::Str(String::from(host))),
+            ]),
+            resolver,
+        };
+        match evaluation.block(&self.body)? {
+            Some(Value::Str(result)) => Ok(result),
+            Some(value) => bail!("FindProxyForURL returned {:?} instead of a string", value),
+            None => bail!("FindProxyForURL returned nothing"),
+        }
+    }
+}
+
+struct Evaluation<'a, R: Resolver> {
+    variables: HashMap<String, Value>,
+    resolver: &'a R,
+}
+
+impl<R: Resolver> Evaluation<'_, R> {
+    /// The returned value, if any
+    fn block(&mut self, statements: &[Statement]) -> AnyhowResult<Option<Value>> {
+        for statement in statements {
+            if let Some(value) = self.statement(statement)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    fn statement(&mut self, statement: &Statement) -> AnyhowResult<Option<Value>> {
+        match statement {
+            Statement::If(condition, then, otherwise) => {
+                if self.expr(condition)?.truthy() {
+                    self.statement(then)
+                } else if let Some(otherwise) = otherwise {
+                    self.statement(otherwise)
+                } else {
+                    Ok(None)
+                }
+            }
+            Statement::Block(statements) => self.block(statements),
+            Statement::Return(expr) => Ok(Some(self.expr(expr)?)),
+            Statement::Assign(name, expr) => {
+                let value = self.expr(expr)?;
+                self.variables.insert(name.clone(), value);
+                Ok(None)
+            }
+            Statement::Empty => Ok(None),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> AnyhowResult<Value> {
+        Ok(match expr {
+            Expr::Literal(value) => value.clone(),
+            Expr::Var(name) => self
+                .variables
+                .get(name)
+                .cloned()
+                .with_context(|| format!("Unknown variable {name}"))?,
+            Expr::Not(expr) => Value::Bool(!self.expr(expr)?.truthy()),
+            Expr::And(left, right) => match self.expr(left)? {
+                left if !left.truthy() => left,
+                _ => self.expr(right)?,
+            },
+            Expr::Or(left, right) => match self.expr(left)? {
+                left if left.truthy() => left,
+                _ => self.expr(right)?,
+            },
+            Expr::Binary(operator, left, right) => {
+                let (left, right) = (self.expr(left)?, self.expr(right)?);
+                binary(operator, left, right)?
+            }
+            Expr::Method(expr, method) => {
+                let string = self.expr(expr)?.string();
+                Value::Str(match method.as_str() {
+                    "toLowerCase" => string.to_lowercase(),
+                    "toUpperCase" => string.to_uppercase(),
+                    _ => bail!("Unsupported method {method}"),
+                })
+            }
+            Expr::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.expr(arg).map(|value| value.string()))
+                    .collect::<AnyhowResult<Vec<String>>>()?;
+                self.call(function, &args)?
+            }
+        })
+    }
+
+    fn call(&self, function: &str, args: &[String]) -> AnyhowResult<Value> {
+        let arg = |index: usize| {
+            args.get(index)
+                .map(String::as_str)
+                .ok_or_else(|| anyhow!("Too few arguments for {function}"))
+        };
+        Ok(match function {
+            "isPlainHostName" => Value::Bool(!arg(0)?.contains('.')),
+            "dnsDomainIs" => Value::Bool(arg(0)?.ends_with(arg(1)?)),
+            "localHostOrDomainIs" => {
+                let (host, fqdn) = (arg(0)?, arg(1)?);
+                Value::Bool(
+                    host == fqdn || (!host.contains('.') && fqdn.starts_with(&format!("{host}."))),
+                )
+            }
+            "dnsDomainLevels" => Value::Num(arg(0)?.matches('.').count() as f64),
+            "shExpMatch" => Value::Bool(sh_exp_match(arg(0)?, arg(1)?)?),
+            "isResolvable" => Value::Bool(self.resolve(arg(0)?).is_some()),
+            "dnsResolve" => self
+                .resolve(arg(0)?)
+                .map_or(Value::Null, |address| Value::Str(address.to_string())),
+            "myIpAddress" => Value::Str(self.resolver.my_ip_address().to_string()),
+            "isInNet" => Value::Bool(
+                match (
+                    self.resolve(arg(0)?),
+                    arg(1)?.parse::<Ipv4Addr>(),
+                    arg(2)?.parse::<Ipv4Addr>(),
+                ) {
+                    (Some(IpAddr::V4(address)), Ok(pattern), Ok(mask)) => {
+                        u32::from(address) & u32::from(mask) == u32::from(pattern) & u32::from(mask)
+                    }
+                    _ => false,
+                },
+            ),
+            "alert" => Value::Null,
+            _ => bail!("Unsupported function {function}"),
+        })
+    }
+
+    fn resolve(&self, host: &str) -> Option<IpAddr> {
+        host.parse().ok().or_else(|| self.resolver.resolve(host))
+    }
+}
+
+fn binary(operator: &str, left: Value, right: Value) -> AnyhowResult<Value> {
+    Ok(match (operator, left, right) {
+        ("+", Value::Num(left), Value::Num(right)) => Value::Num(left + right),
+        ("+", left, right) => Value::Str(left.string() + &right.string()),
+        ("==" | "===", left, right) => Value::Bool(left == right),
+        ("!=" | "!==", left, right) => Value::Bool(left != right),
+        (operator, Value::Num(left), Value::Num(right)) => Value::Bool(match operator {
+            "<" => left < right,
+            ">" => left > right,
+            "<=" => left <= right,
+            _ => left >= right,
+        }),
+        (operator, left, right) => bail!("Cannot compare {:?} {} {:?}", left, operator, right),
+    })
+}
+
+/// Shell expression as used by shExpMatch, ie. with the wildcards * and ?
+fn sh_exp_match(string: &str, pattern: &str) -> AnyhowResult<bool> {
+    let regex: String = pattern
+        .chars()
+        .map(|c| match c {
+            '*' => String::from(".*"),
+            '?' => String::from("."),
+            c => regex::escape(&c.to_string()),
+        })
+        .collect();
+    Ok(regex::Regex::new(&format!("^{regex}$"))?.is_match(string))
+}
+
+/// URL of the first supported proxy in the result of FindProxyForURL, None to connect directly.
+/// SOCKS proxies are not supported and skipped.
+pub fn proxy_url(result: &str) -> Option<String> {
+    result.split(';').find_map(|entry| {
+        let mut words = entry.split_whitespace();
+        match (words.next()?.to_uppercase().as_str(), words.next()) {
+            ("DIRECT", _) => Some(None),
+            ("PROXY" | "HTTP", Some(address)) => Some(Some(format!("http://{address}"))),
+            ("HTTPS", Some(address)) => Some(Some(format!("https://{address}"))),
+            _ => None,
+        }
+    })?
+}
+
+#[cfg(test)]
+mod test_pac {
+    use super::*;
+
+    struct TestResolver;
+
+    impl Resolver for TestResolver {
+        fn resolve(&self, host: &str) -> Option<IpAddr> {
+            match host {
+                "intranet.corp.example.com" => Some(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))),
+                "www.example.com" => Some(IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))),
+                _ => None,
+            }
+        }
+
+        fn my_ip_address(&self) -> IpAddr {
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5))
+        }
+    }
+
+    const SCRIPT: &str = r#"
+        // Sites in the datacenter are reached directly
+        function FindProxyForURL(url, host) {
+            var lower = host.toLowerCase();
+            if (isPlainHostName(lower) || dnsDomainIs(lower, ".corp.example.com"))
+                return "DIRECT";
+            /* Branch offices */
+            if (isInNet(myIpAddress(), "10.0.0.0", "255.255.0.0") && !shExpMatch(url, "https://*.example.org*")) {
+                return "PROXY proxy-" + "branch.example.com:3128; DIRECT";
+            } else if (isInNet(dnsResolve(host), "93.184.0.0", "255.255.0.0")) {
+                return 'SOCKS socks.example.com:1080; HTTPS secure-proxy.example.com:443';
+            }
+            return "PROXY proxy.example.com:8080";
+        };
+    "#;
+
+    fn find_proxy(script: &Script, url: &str) -> Option<String> {
+        let host = reqwest::Url::parse(url).unwrap();
+        proxy_url(
+            &script
+                .find_proxy(url, host.host_str().unwrap(), &TestResolver)
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_find_proxy() {
+        let script = Script::parse(SCRIPT).unwrap();
+        assert_eq!(find_proxy(&script, "https://checkmk:8000/site"), None);
+        assert_eq!(
+            find_proxy(&script, "https://Monitoring.corp.example.com:8000/site"),
+            None
+        );
+        assert_eq!(
+            find_proxy(&script, "https://checkmk.example.net:8000/site"),
+            Some(String::from("http://proxy-branch.example.com:3128"))
+        );
+        assert_eq!(
+            find_proxy(&script, "https://checkmk.example.org:8000/site"),
+            Some(String::from("http://proxy.example.com:8080"))
+        );
+    }
+
+    #[test]
+    fn test_find_proxy_resolving() {
+        let script = Script::parse(
+            "function FindProxyForURL(u, h) {
+                if (!isResolvable(h)) return 'DIRECT';
+                if (isInNet(h, '93.184.0.0', '255.255.0.0') && dnsDomainLevels(h) > 1)
+                    return 'SOCKS socks.example.com:1080; HTTPS secure-proxy.example.com:443';
+                return 'PROXY proxy.example.com:8080';
+            }",
+        )
+        .unwrap();
+        assert_eq!(find_proxy(&script, "https://unknown.example.com/"), None);
+        assert_eq!(
+            find_proxy(&script, "https://www.example.com/"),
+            Some(String::from("https://secure-proxy.example.com:443"))
+        );
+        assert_eq!(
+            find_proxy(&script, "https://intranet.corp.example.com/"),
+            Some(String::from("http://proxy.example.com:8080"))
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        for source in [
+            "var proxy = 'DIRECT';",
+            "function FindProxyForURL(url, host) { return 'DIRECT' ",
+            "function FindProxyForURL(url, host) { for (;;) {} }",
+            "function FindProxyForURL(url, host) { return \"DIRECT; }",
+            "function FindProxyForURL(url, host) { return 'DIRECT'; } function helper() {}",
+        ] {
+            assert!(Script::parse(source).is_err(), "{source}");
+        }
+        let script = Script::parse(
+            "function FindProxyForURL(url, host) {
+                if (weekdayRange('MON', 'FRI')) return 'PROXY proxy:3128';
+            }",
+        )
+        .unwrap();
+        assert!(script.find_proxy("https://a/", "a", &TestResolver).is_err());
+    }
+
+    #[test]
+    fn test_proxy_url() {
+        assert_eq!(proxy_url("DIRECT"), None);
+        assert_eq!(proxy_url(""), None);
+        assert_eq!(
+            proxy_url("SOCKS5 socks:1080;  proxy  proxy:3128"),
+            Some(String::from("http://proxy:3128"))
+        );
+        assert_eq!(proxy_url("SOCKS socks:1080"), None);
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Accounting of the memory taken by agent outputs which are buffered until they are sent, st.
+//! many simultaneous requests cannot exhaust the memory of small devices.
+
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct PayloadMemory {
+    limit: Option<usize>,
+    used: Arc<AtomicUsize>,
+}
+
+impl PayloadMemory {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            used: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Account for the given payload until it is dropped. Fails if the limit would be exceeded.
+    pub fn buffer(&self, data: Vec<u8>) -> Result<BufferedPayload, Vec<u8>> {
+        let size = data.len();
+        let reserved = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                let total = used.checked_add(size)?;
+                match self.limit {
+                    Some(limit) if total > limit => None,
+                    _ => Some(total),
+                }
+            });
+        match reserved {
+            Ok(_) => Ok(BufferedPayload {
+                data,
+                size,
+                used: Arc::clone(&self.used),
+            }),
+            Err(_) => Err(data),
+        }
+    }
+}
+
+/// An agent output which is accounted for in its PayloadMemory
+pub struct BufferedPayload {
+    data: Vec<u8>,
+    size: usize,
+    used: Arc<AtomicUsize>,
+}
+
+impl BufferedPayload {
+    /// Replace the data by a version derived from it, eg. a compressed one. The size of the
+    /// original data stays accounted for.
+    pub fn try_map<E>(
+        mut self,
+        f: impl FnOnce(&[u8]) -> Result<Vec<u8>, E>,
+    ) -> Result<BufferedPayload, E> {
+        self.data = f(&self.data)?;
+        Ok(self)
+    }
+}
+
+impl Deref for BufferedPayload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Drop for BufferedPayload {
+    fn drop(&mut self) {
+        self.used.fetch_sub(self.size, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test_payload_memory {
+    use super::*;
+
+    fn used(memory: &PayloadMemory) -> usize {
+        memory.used.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn test_buffer_within_limit() {
+        let memory = PayloadMemory::new(Some(10));
+        let first = memory.buffer(vec![0; 6]).unwrap();
+        assert_eq!(used(&memory), 6);
+        assert_eq!(memory.buffer(vec![1; 5]).err(), Some(vec![1; 5]));
+        let second = memory.buffer(vec![2; 4]).unwrap();
+        assert_eq!(&*second, &[2; 4]);
+        drop(first);
+        assert_eq!(used(&memory), 4);
+        drop(second);
+        assert_eq!(used(&memory), 0);
+    }
+
+    #[test]
+    fn test_buffer_unlimited() {
+        let memory = PayloadMemory::new(None);
+        let payload = memory.buffer(vec![0; 1000]).unwrap();
+        assert_eq!(used(&memory), 1000);
+        drop(payload);
+        assert_eq!(used(&memory), 0);
+    }
+
+    #[test]
+    fn test_try_map_keeps_reservation() {
+        let memory = PayloadMemory::new(Some(10));
+        let payload = memory
+            .buffer(b"abcdef".to_vec())
+            .unwrap()
+            .try_map(|data| Ok::<_, ()>(data[..2].to_vec()))
+            .unwrap();
+        assert_eq!(&*payload, b"ab");
+        assert_eq!(used(&memory), 6);
+        drop(payload);
+        assert_eq!(used(&memory), 0);
+    }
+}
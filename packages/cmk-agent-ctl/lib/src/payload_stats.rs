@@ -0,0 +1,378 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Accounting of the size of the collected agent output per section, st. an exploding section is
+//! noticed on the host, before the agent output gets too large to be transported.
+
+use crate::config::{self, JSONLoader, JSONLoaderMissingSafe};
+use crate::monitoring_data::section_header;
+use crate::{constants, misc};
+use anyhow::Result as AnyhowResult;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Name of the service reported in the local section
+const SERVICE_NAME: &str = "Agent output size";
+
+/// Number of sections exceeding their levels which are named in the summary
+const REPORTED_SECTIONS: usize = 3;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadState {
+    Ok,
+    Warn,
+    Crit,
+}
+
+impl PayloadState {
+    fn of(bytes: u64, levels: Option<(u64, u64)>) -> Self {
+        match levels {
+            Some((_, crit)) if bytes >= crit => Self::Crit,
+            Some((warn, _)) if bytes >= warn => Self::Warn,
+            _ => Self::Ok,
+        }
+    }
+
+    /// State of a local check
+    fn code(self) -> u8 {
+        match self {
+            Self::Ok => 0,
+            Self::Warn => 1,
+            Self::Crit => 2,
+        }
+    }
+
+    fn marker(self) -> &'static str {
+        match self {
+            Self::Ok => "",
+            Self::Warn => "(!)",
+            Self::Crit => "(!!)",
+        }
+    }
+}
+
+/// Size of a single collected agent output
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PayloadSample {
+    /// Unix timestamp of the collection
+    pub timestamp: u64,
+    pub total_bytes: u64,
+    /// Sizes of the sections, including their headers. Piggybacked data counts per host, keyed
+    /// by its piggyback header.
+    pub sections: BTreeMap<String, u64>,
+    pub state: PayloadState,
+    pub summary: String,
+}
+
+impl PayloadSample {
+    fn measure(timestamp: u64, mon_data: &[u8]) -> Self {
+        let mut sections = BTreeMap::new();
+        let mut current: Option<String> = None;
+        let mut piggyback = false;
+        for line in mon_data.split_inclusive(|byte| *byte == b'\n') {
+            let content = line.strip_suffix(b"\n").unwrap_or(line);
+            let content = content.strip_suffix(b"\r").unwrap_or(content);
+            if content.starts_with(b"<<<<") {
+                piggyback = content != b"<<<<>>>>";
+                current = piggyback.then(|| String::from_utf8_lossy(content).into_owned());
+            } else if let Some(name) = section_header(content).filter(|_| !piggyback) {
+                current = Some(String::from_utf8_lossy(name).into_owned());
+            }
+            if let Some(section) = &current {
+                *sections.entry(section.clone()).or_default() += line.len() as u64;
+            }
+        }
+        Self {
+            timestamp,
+            total_bytes: mon_data.len() as u64,
+            sections,
+            state: PayloadState::Ok,
+            summary: String::new(),
+        }
+    }
+
+    /// Evaluate the sizes against the levels, compared to the oldest sample of the history
+    fn evaluate(&mut self, config: &config::PayloadSizeConfig, oldest: Option<&PayloadSample>) {
+        let with_levels = |bytes: u64, levels: Option<(u64, u64)>, state: PayloadState| match levels
+        {
+            Some((warn, crit)) if state != PayloadState::Ok => format!(
+                "{} (warn/crit at {}/{}){}",
+                misc::human_readable_bytes(bytes),
+                misc::human_readable_bytes(warn),
+                misc::human_readable_bytes(crit),
+                state.marker()
+            ),
+            _ => misc::human_readable_bytes(bytes),
+        };
+        let total_state = PayloadState::of(self.total_bytes, config.total_levels);
+        let mut summary = vec![format!(
+            "Total: {}",
+            with_levels(self.total_bytes, config.total_levels, total_state)
+        )];
+        if let Some(oldest) = oldest.filter(|oldest| oldest.total_bytes < self.total_bytes) {
+            summary.push(format!(
+                "grew from {} within {}",
+                misc::human_readable_bytes(oldest.total_bytes),
+                misc::human_readable_duration(self.timestamp.saturating_sub(oldest.timestamp))
+            ));
+        }
+        let mut exceeding: Vec<(&String, u64, PayloadState)> = self
+            .sections
+            .iter()
+            .map(|(name, bytes)| {
+                (
+                    name,
+                    *bytes,
+                    PayloadState::of(*bytes, config.section_levels),
+                )
+            })
+            .filter(|(_, _, state)| *state != PayloadState::Ok)
+            .collect();
+        exceeding.sort_by_key(|(_, bytes, _)| Reverse(*bytes));
+        self.state = exceeding
+            .iter()
+            .map(|(_, _, state)| *state)
+            .fold(total_state, PayloadState::max);
+        for (name, bytes, state) in exceeding.iter().take(REPORTED_SECTIONS) {
+            summary.push(format!(
+                "section {}: {}",
+                name,
+                with_levels(*bytes, config.section_levels, *state)
+            ));
+        }
+        if exceeding.len() > REPORTED_SECTIONS {
+            summary.push(format!(
+                "{} more sections exceed their levels",
+                exceeding.len() - REPORTED_SECTIONS
+            ));
+        }
+        if exceeding.is_empty() {
+            if let Some((name, bytes)) = self.sections.iter().max_by_key(|(_, bytes)| **bytes) {
+                summary.push(format!(
+                    "largest section {}: {}",
+                    name,
+                    misc::human_readable_bytes(*bytes)
+                ));
+            }
+        }
+        self.summary = summary.join(", ");
+    }
+
+    /// Local section reporting the sample as a service of the host
+    fn local_section(&self, config: &config::PayloadSizeConfig) -> Vec<u8> {
+        let levels = match config.total_levels {
+            Some((warn, crit)) => format!(";{warn};{crit}"),
+            None => String::new(),
+        };
+        format!(
+            "<<<local:sep(0)>>>\n{} \"{}\" total_bytes={}{} {}\n",
+            self.state.code(),
+            SERVICE_NAME,
+            self.total_bytes,
+            levels,
+            self.summary
+        )
+        .into_bytes()
+    }
+}
+
+/// The most recent samples, oldest first
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct PayloadHistory(VecDeque<PayloadSample>);
+
+impl JSONLoader for PayloadHistory {}
+impl JSONLoaderMissingSafe for PayloadHistory {}
+
+impl PayloadHistory {
+    pub fn latest(&self) -> Option<&PayloadSample> {
+        self.0.back()
+    }
+}
+
+/// Collects the sizes of the agent outputs in the daemon. Unless created without a path, they are
+/// persisted, st. they can be reported by the status mode, which runs in a separate process.
+#[derive(Clone, Default)]
+pub struct PayloadStats {
+    path: Option<PathBuf>,
+    history: Arc<Mutex<PayloadHistory>>,
+}
+
+impl PayloadStats {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let history = PayloadHistory::load_missing_safe(path.as_ref()).unwrap_or_else(|err| {
+            warn!(
+                "Could not load agent output sizes from {:?}, starting from scratch. ({})",
+                path.as_ref(),
+                err
+            );
+            PayloadHistory::default()
+        });
+        Self {
+            path: Some(PathBuf::from(path.as_ref())),
+            history: Arc::new(Mutex::new(history)),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> AnyhowResult<PayloadHistory> {
+        PayloadHistory::load_missing_safe(path.as_ref())
+    }
+
+    /// Measure the collected agent output and record its size
+    pub fn record(&self, config: &config::PayloadSizeConfig, mon_data: &[u8]) -> PayloadSample {
+        let mut sample = PayloadSample::measure(misc::unix_now(), mon_data);
+        let mut history = self.lock();
+        sample.evaluate(config, history.0.front());
+        history.0.push_back(sample.clone());
+        while history.0.len() > constants::PAYLOAD_HISTORY_SIZE {
+            history.0.pop_front();
+        }
+        if let Some(path) = &self.path {
+            if let Err(err) = Self::save(path, &history) {
+                warn!(
+                    "Failed to write agent output sizes to {:?}. ({})",
+                    path, err
+                );
+            }
+        }
+        sample
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PayloadHistory> {
+        match self.history.lock() {
+            Ok(history) => history,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn save(path: &Path, history: &PayloadHistory) -> io::Result<()> {
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string(history)?)?;
+        fs::rename(&tmp_path, path)?;
+        #[cfg(unix)]
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        Ok(())
+    }
+}
+
+/// Records the size of every collected agent output and reports it in a local section
+pub struct PayloadAccounting {
+    config: config::PayloadSizeConfig,
+    stats: PayloadStats,
+}
+
+impl PayloadAccounting {
+    pub fn new(config: &config::PayloadSizeConfig, stats: PayloadStats) -> Self {
+        Self {
+            config: config.clone(),
+            stats,
+        }
+    }
+
+    pub fn account(&self, mon_data: &[u8]) -> Vec<u8> {
+        self.stats
+            .record(&self.config, mon_data)
+            .local_section(&self.config)
+    }
+}
+
+#[cfg(test)]
+mod test_payload_stats {
+    use super::*;
+
+    const OUTPUT: &[u8] = b"<<<check_mk>>>\nVersion: 2.3.0\n\
+        <<<ps>>>\n1 init\n2 sshd\n\
+        <<<<piggy>>>>\n<<<ps>>>\n3 java\n<<<<>>>>\n\
+        <<<uptime>>>\n1234\n";
+
+    fn config(
+        total_levels: Option<(u64, u64)>,
+        section_levels: Option<(u64, u64)>,
+    ) -> config::PayloadSizeConfig {
+        config::PayloadSizeConfig {
+            total_levels,
+            section_levels,
+        }
+    }
+
+    #[test]
+    fn test_measure() {
+        let sample = PayloadSample::measure(0, OUTPUT);
+        assert_eq!(sample.total_bytes, OUTPUT.len() as u64);
+        assert_eq!(
+            sample.sections,
+            BTreeMap::from([
+                (String::from("check_mk"), 30),
+                (String::from("ps"), 23),
+                (String::from("<<<<piggy>>>>"), 30),
+                (String::from("uptime"), 18),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut sample = PayloadSample::measure(120, OUTPUT);
+        sample.evaluate(&config(Some((1000, 2000)), None), None);
+        assert_eq!(sample.state, PayloadState::Ok);
+        assert_eq!(
+            sample.summary,
+            "Total: 110 B, largest section check_mk: 30 B"
+        );
+
+        let oldest = PayloadSample::measure(0, b"<<<check_mk>>>\n");
+        sample.evaluate(&config(Some((100, 200)), Some((20, 25))), Some(&oldest));
+        assert_eq!(sample.state, PayloadState::Crit);
+        assert_eq!(
+            sample.summary,
+            "Total: 110 B (warn/crit at 100 B/200 B)(!), grew from 15 B within 2m, \
+             section <<<<piggy>>>>: 30 B (warn/crit at 20 B/25 B)(!!), \
+             section check_mk: 30 B (warn/crit at 20 B/25 B)(!!), \
+             section ps: 23 B (warn/crit at 20 B/25 B)(!)"
+        );
+    }
+
+    #[test]
+    fn test_local_section() {
+        let config = config(Some((100, 200)), None);
+        assert_eq!(
+            String::from_utf8(
+                PayloadAccounting::new(&config, PayloadStats::default()).account(OUTPUT)
+            )
+            .unwrap(),
+            "<<<local:sep(0)>>>\n1 \"Agent output size\" total_bytes=110;100;200 \
+             Total: 110 B (warn/crit at 100 B/200 B)(!), largest section check_mk: 30 B\n"
+        );
+    }
+
+    #[test]
+    fn test_record_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("payload_stats.json");
+        let config = config(None, Some((20, 25)));
+        PayloadStats::new(&path).record(&config, OUTPUT);
+        // Continues with the history of the previous process
+        let stats = PayloadStats::new(&path);
+        stats.record(&config, b"<<<check_mk>>>\n");
+        assert_eq!(PayloadStats::load(&path).unwrap().0.len(), 2);
+        for _ in 0..constants::PAYLOAD_HISTORY_SIZE {
+            stats.record(&config, b"<<<check_mk>>>\n");
+        }
+        let history = PayloadStats::load(&path).unwrap();
+        assert_eq!(history.0.len(), constants::PAYLOAD_HISTORY_SIZE);
+        assert_eq!(history.latest().unwrap().state, PayloadState::Ok);
+        assert!(PayloadStats::load(dir.path().join("missing.json"))
+            .unwrap()
+            .latest()
+            .is_none());
+    }
+}
@@ -0,0 +1,440 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Pipeline of configurable steps applied to the agent output before it is dumped, pulled or
+//! pushed. If a step fails, the agent output is not delivered at all, st. data which was supposed
+//! to be removed cannot leak.
+
+use crate::monitoring_data::section_header;
+use crate::payload_stats::{PayloadAccounting, PayloadStats};
+use crate::status_section::StatusSection;
+use crate::{config, constants, section_filter, types};
+use anyhow::{anyhow, bail, Context, Result as AnyhowResult};
+use regex::bytes::Regex;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait PostProcessor: Send + Sync {
+    fn process(&self, mon_data: Vec<u8>) -> AnyhowResult<Vec<u8>>;
+}
+
+struct SectionFilter(config::SectionFilterConfig);
+
+impl PostProcessor for SectionFilter {
+    fn process(&self, mon_data: Vec<u8>) -> AnyhowResult<Vec<u8>> {
+        Ok(section_filter::filter(mon_data, &self.0))
+    }
+}
+
+struct AnonymizeUsers {
+    users: Regex,
+}
+
+impl AnonymizeUsers {
+    fn new(users: &[String]) -> AnyhowResult<Self> {
+        if users.is_empty() {
+            bail!("No users given");
+        }
+        let alternatives: Vec<String> = users.iter().map(|user| regex::escape(user)).collect();
+        Ok(Self {
+            users: Regex::new(&format!(r"\b(?:{})\b", alternatives.join("|")))?,
+        })
+    }
+
+    fn pseudonym(user: &[u8]) -> Vec<u8> {
+        let digest = openssl::sha::sha256(user);
+        let hex: String = digest[..4]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        format!("user-{hex}").into_bytes()
+    }
+}
+
+impl PostProcessor for AnonymizeUsers {
+    fn process(&self, mon_data: Vec<u8>) -> AnyhowResult<Vec<u8>> {
+        Ok(self
+            .users
+            .replace_all(&mon_data, |captures: &regex::bytes::Captures| {
+                Self::pseudonym(&captures[0])
+            })
+            .into_owned())
+    }
+}
+
+struct DropLines {
+    pattern: Regex,
+    sections: Vec<String>,
+}
+
+impl PostProcessor for DropLines {
+    fn process(&self, mon_data: Vec<u8>) -> AnyhowResult<Vec<u8>> {
+        let mut processed = Vec::with_capacity(mon_data.len());
+        let mut in_scope = self.sections.is_empty();
+        for line in mon_data.split_inclusive(|byte| *byte == b'\n') {
+            let content = line.strip_suffix(b"\n").unwrap_or(line);
+            let content = content.strip_suffix(b"\r").unwrap_or(content);
+            if let Some(section) = section_header(content) {
+                in_scope = self.sections.is_empty()
+                    || self
+                        .sections
+                        .iter()
+                        .any(|pattern| section_filter::matches(pattern, section));
+            } else if in_scope && self.pattern.is_match(content) {
+                continue;
+            }
+            processed.extend_from_slice(line);
+        }
+        Ok(processed)
+    }
+}
+
+/// Placed first, st. it cannot end up in the piggyback data of another host
+struct LabelSection {
+    section: Vec<u8>,
+}
+
+impl LabelSection {
+    fn new(labels: &types::AgentLabels) -> AnyhowResult<Self> {
+        // Sorted, st. the agent output does not change from run to run
+        let sorted: BTreeMap<&String, &String> = labels.iter().collect();
+        Ok(Self {
+            section: format!("<<<labels:sep(0)>>>\n{}\n", serde_json::to_string(&sorted)?)
+                .into_bytes(),
+        })
+    }
+}
+
+impl PostProcessor for LabelSection {
+    fn process(&self, mon_data: Vec<u8>) -> AnyhowResult<Vec<u8>> {
+        let mut processed = self.section.clone();
+        processed.extend(mon_data);
+        Ok(processed)
+    }
+}
+
+/// Gets the agent output on stdin and writes the processed one to stdout
+struct ExternalCommand {
+    command: Vec<String>,
+    timeout: Duration,
+}
+
+impl PostProcessor for ExternalCommand {
+    fn process(&self, mon_data: Vec<u8>) -> AnyhowResult<Vec<u8>> {
+        run_command(&self.command, mon_data, self.timeout, None)
+    }
+}
+
+/// Run the given program with the given arguments, feeding it the input on stdin. Its output on
+/// stdout, if it succeeds within the timeout and does not exceed the maximum size (in bytes).
+pub fn run_command(
+    command: &[String],
+    input: Vec<u8>,
+    timeout: Duration,
+    max_output: Option<usize>,
+) -> AnyhowResult<Vec<u8>> {
+    let (program, args) = command.split_first().context("No command given")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(format!("Failed to start {program}"))?;
+    let mut stdin = child.stdin.take().context("No stdin")?;
+    // The command may not read all of its input, this shows in its output
+    thread::spawn(move || stdin.write_all(&input));
+    // One byte more than allowed, to tell too large outputs
+    let stdout = read_in_background(
+        child.stdout.take().context("No stdout")?,
+        max_output.map_or(u64::MAX, |max_output| max_output as u64 + 1),
+    );
+    let stderr = read_in_background(child.stderr.take().context("No stderr")?, u64::MAX);
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            // Failing to kill leaves nothing else to do
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!(
+                "{program} did not finish within {} seconds",
+                timeout.as_secs()
+            );
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    if !status.success() {
+        let stderr = stderr.join().unwrap_or_default();
+        bail!(
+            "{program} failed ({status}): {}",
+            String::from_utf8_lossy(&stderr).trim()
+        );
+    }
+    let output = stdout
+        .join()
+        .map_err(|_| anyhow!("Failed to read the output of {program}"))?;
+    if let Some(max_output) = max_output.filter(|max_output| output.len() > *max_output) {
+        bail!("{program} printed more than {max_output} bytes");
+    }
+    Ok(output)
+}
+
+/// Output beyond the limit is read, st. the command does not block, but dropped
+fn read_in_background(
+    mut pipe: impl Read + Send + 'static,
+    limit: u64,
+) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = vec![];
+        // A broken pipe just ends the output
+        let _ = (&mut pipe).take(limit).read_to_end(&mut output);
+        let _ = std::io::copy(&mut pipe, &mut std::io::sink());
+        output
+    })
+}
+
+fn post_processor(config: &config::PostProcessorConfig) -> AnyhowResult<Box<dyn PostProcessor>> {
+    Ok(match config {
+        config::PostProcessorConfig::AnonymizeUsers { users } => {
+            Box::new(AnonymizeUsers::new(users)?)
+        }
+        config::PostProcessorConfig::DropLines { pattern, sections } => Box::new(DropLines {
+            pattern: Regex::new(pattern).context(format!("Invalid pattern '{pattern}'"))?,
+            sections: sections.clone(),
+        }),
+        config::PostProcessorConfig::LabelSection { labels } => {
+            Box::new(LabelSection::new(labels)?)
+        }
+        config::PostProcessorConfig::Command { command, timeout } => Box::new(ExternalCommand {
+            command: command.clone(),
+            timeout: Duration::from_secs(
+                timeout.unwrap_or(constants::POST_PROCESSING_COMMAND_TIMEOUT),
+            ),
+        }),
+    })
+}
+
+#[derive(Default)]
+pub struct Pipeline {
+    steps: Vec<Box<dyn PostProcessor>>,
+    /// Measures the collected agent output, its local section is added after the last step
+    payload_accounting: Option<PayloadAccounting>,
+    /// Added after the local section of the payload accounting
+    status_section: Option<StatusSection>,
+}
+
+impl Pipeline {
+    pub fn new(
+        section_filter: &config::SectionFilterConfig,
+        post_processors: &[config::PostProcessorConfig],
+    ) -> AnyhowResult<Self> {
+        let mut steps: Vec<Box<dyn PostProcessor>> = vec![];
+        if !section_filter.is_empty() {
+            steps.push(Box::new(SectionFilter(section_filter.clone())));
+        }
+        for (index, config) in post_processors.iter().enumerate() {
+            steps.push(
+                post_processor(config).context(format!("Invalid post-processor #{}", index + 1))?,
+            );
+        }
+        Ok(Self {
+            steps,
+            payload_accounting: None,
+            status_section: None,
+        })
+    }
+
+    /// Account for the size of the collected agent outputs, if any levels are configured
+    pub fn with_payload_accounting(
+        self,
+        config: &config::PayloadSizeConfig,
+        stats: PayloadStats,
+    ) -> Self {
+        Self {
+            payload_accounting: config
+                .is_enabled()
+                .then(|| PayloadAccounting::new(config, stats)),
+            ..self
+        }
+    }
+
+    /// Report the state of the controller, see status_section
+    pub fn with_status_section(self, status_section: Option<StatusSection>) -> Self {
+        Self {
+            status_section,
+            ..self
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty() && self.payload_accounting.is_none() && self.status_section.is_none()
+    }
+
+    pub fn process(&self, mut mon_data: Vec<u8>) -> AnyhowResult<Vec<u8>> {
+        let local_section = self
+            .payload_accounting
+            .as_ref()
+            .map(|accounting| accounting.account(&mon_data));
+        for (index, step) in self.steps.iter().enumerate() {
+            mon_data = step
+                .process(mon_data)
+                .context(format!("Post-processing step #{} failed", index + 1))?;
+        }
+        if let Some(local_section) = local_section {
+            mon_data.extend(local_section);
+        }
+        if let Some(status_section) = &self.status_section {
+            mon_data.extend(status_section.render());
+        }
+        Ok(mon_data)
+    }
+}
+
+#[cfg(test)]
+mod test_post_processing {
+    use super::*;
+
+    const OUTPUT: &[u8] = b"<<<check_mk>>>\nVersion: 2.3.0\n\
+        <<<ps>>>\nalice 1 bash\nroot 2 sshd\nbob 3 vim password=x\n\
+        <<<df>>>\n/ password=y\n";
+
+    fn process(post_processors: &[config::PostProcessorConfig]) -> AnyhowResult<String> {
+        Ok(String::from_utf8(
+            Pipeline::new(&config::SectionFilterConfig::default(), post_processors)?
+                .process(OUTPUT.to_vec())?,
+        )?)
+    }
+
+    #[test]
+    fn test_empty() {
+        let pipeline = Pipeline::new(&config::SectionFilterConfig::default(), &[]).unwrap();
+        assert!(pipeline.is_empty());
+        assert_eq!(pipeline.process(OUTPUT.to_vec()).unwrap(), OUTPUT);
+    }
+
+    #[test]
+    fn test_anonymize_users() {
+        let processed = process(&[config::PostProcessorConfig::AnonymizeUsers {
+            users: vec![String::from("alice"), String::from("bob")],
+        }])
+        .unwrap();
+        let alice = String::from_utf8(AnonymizeUsers::pseudonym(b"alice")).unwrap();
+        assert!(processed.contains(&format!("\n{alice} 1 bash\n")));
+        assert!(processed.contains("\nroot 2 sshd\n"));
+        assert!(!processed.contains("alice") && !processed.contains("bob"));
+        // Stable across runs
+        assert_eq!(alice, "user-2bd806c9");
+    }
+
+    #[test]
+    fn test_drop_lines() {
+        let drop_lines = |sections: Vec<String>| config::PostProcessorConfig::DropLines {
+            pattern: String::from("password="),
+            sections,
+        };
+        assert_eq!(
+            process(&[drop_lines(vec![])]).unwrap(),
+            "<<<check_mk>>>\nVersion: 2.3.0\n<<<ps>>>\nalice 1 bash\nroot 2 sshd\n<<<df>>>\n"
+        );
+        assert!(process(&[drop_lines(vec![String::from("d*")])])
+            .unwrap()
+            .contains("bob 3 vim password=x"));
+    }
+
+    #[test]
+    fn test_label_section() {
+        assert!(process(&[config::PostProcessorConfig::LabelSection {
+            labels: labels(&[("env", "prod"), ("app", "web")]),
+        }])
+        .unwrap()
+        .starts_with("<<<labels:sep(0)>>>\n{\"app\":\"web\",\"env\":\"prod\"}\n<<<check_mk>>>\n"));
+    }
+
+    fn labels(labels: &[(&str, &str)]) -> types::AgentLabels {
+        labels
+            .iter()
+            .map(|(key, value)| (String::from(*key), String::from(*value)))
+            .collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command() {
+        let command = |command: &[&str]| config::PostProcessorConfig::Command {
+            command: command.iter().map(|arg| String::from(*arg)).collect(),
+            timeout: Some(1),
+        };
+        assert_eq!(
+            process(&[command(&["grep", "-v", "root"])]).unwrap(),
+            String::from_utf8(OUTPUT.to_vec())
+                .unwrap()
+                .replace("root 2 sshd\n", "")
+        );
+        assert!(process(&[command(&["false"])]).is_err());
+        assert!(process(&[command(&["sleep", "5"])]).is_err());
+        assert!(process(&[command(&["/does/not/exist"])]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_command_max_output() {
+        let command = [String::from("cat")];
+        let run = |max_output| {
+            run_command(
+                &command,
+                OUTPUT.to_vec(),
+                Duration::from_secs(1),
+                max_output,
+            )
+        };
+        assert_eq!(run(Some(OUTPUT.len())).unwrap(), OUTPUT);
+        assert!(run(Some(OUTPUT.len() - 1)).is_err());
+    }
+
+    #[test]
+    fn test_payload_accounting() {
+        let pipeline = Pipeline::default().with_payload_accounting(
+            &config::PayloadSizeConfig::default(),
+            PayloadStats::default(),
+        );
+        assert!(pipeline.is_empty());
+        let pipeline = Pipeline::new(
+            &config::SectionFilterConfig {
+                excluded_sections: vec![String::from("ps")],
+                ..config::SectionFilterConfig::default()
+            },
+            &[],
+        )
+        .unwrap()
+        .with_payload_accounting(
+            &config::PayloadSizeConfig {
+                total_levels: None,
+                section_levels: Some((40, 50)),
+            },
+            PayloadStats::default(),
+        );
+        // The collected output is measured, not the processed one
+        assert_eq!(
+            String::from_utf8(pipeline.process(OUTPUT.to_vec()).unwrap()).unwrap(),
+            "<<<check_mk>>>\nVersion: 2.3.0\n<<<df>>>\n/ password=y\n\
+             <<<local:sep(0)>>>\n2 \"Agent output size\" total_bytes=107 \
+             Total: 107 B, section ps: 55 B (warn/crit at 40 B/50 B)(!!)\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(process(&[config::PostProcessorConfig::DropLines {
+            pattern: String::from("("),
+            sections: vec![],
+        }])
+        .is_err());
+    }
+}
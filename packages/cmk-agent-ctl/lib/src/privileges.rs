@@ -0,0 +1,186 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! The user and group the controller runs as. All modes switch to them right away, except for
+//! the daemon when started as root: it binds privileged ports and reads protected files first,
+//! then hands the files it writes over to the user and drops its root privileges for good.
+
+use super::config::PrivilegesConfig;
+use super::constants;
+use super::container;
+use anyhow::{bail, Context, Result as AnyhowResult};
+use nix::unistd::{self, Gid, Group, Uid, User};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+pub struct Identity {
+    pub user: User,
+    pub group: Group,
+}
+
+impl Identity {
+    pub fn resolve(config: &PrivilegesConfig) -> AnyhowResult<Identity> {
+        let user = User::from_name(&config.user)?.context(format!(
+            "Could not find dedicated Checkmk agent user {}",
+            config.user
+        ))?;
+        let group = match &config.group {
+            Some(name) => {
+                Group::from_name(name)?.context(format!("Could not find group {name}"))?
+            }
+            None => Group::from_gid(user.gid)?.context(format!(
+                "Could not find group id {} corresponding to user {}",
+                user.gid, user.name
+            ))?,
+        };
+        Ok(Identity { user, group })
+    }
+
+    fn is_current(&self) -> bool {
+        self.user.uid == unistd::getuid() && self.group.gid == unistd::getgid()
+    }
+
+    /// Switch to this user and group. When switching away from root, there is no way back.
+    pub fn assume(&self) -> AnyhowResult<()> {
+        // If we already are the right user, return early. Otherwise, eg. setting the
+        // supplementary group ids will fail due to insufficient permissions.
+        if self.is_current() {
+            return Ok(());
+        }
+
+        set_supplementary_group(self.group.gid).context(format!(
+            "Failed to set supplementary group id {} corresponding to group {}",
+            self.group.gid, self.group.name,
+        ))?;
+        unistd::setgid(self.group.gid).context(format!(
+            "Failed to set group id {} corresponding to group {}",
+            self.group.gid, self.group.name,
+        ))?;
+        unistd::setuid(self.user.uid).context(format!(
+            "Failed to set user id {} corresponding to user {}",
+            self.user.uid, self.user.name,
+        ))?;
+
+        if !self.user.uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+            bail!(
+                "Root privileges could be regained after switching to user {}",
+                self.user.name
+            );
+        }
+        Ok(())
+    }
+
+    /// Make this user and group own the given file, st. it can still be written after dropping
+    /// root privileges. Returns whether the ownership was changed, missing files are skipped.
+    pub fn take_ownership(&self, path: &Path) -> AnyhowResult<bool> {
+        let metadata = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err).context(format!("Failed to inspect {}", path.display())),
+        };
+        if !needs_ownership_change(&metadata, self) {
+            return Ok(false);
+        }
+        unistd::fchownat(
+            None,
+            path,
+            Some(self.user.uid),
+            Some(self.group.gid),
+            unistd::FchownatFlags::NoFollowSymlink,
+        )
+        .context(format!(
+            "Failed to change owner of {} to {}:{}",
+            path.display(),
+            self.user.name,
+            self.group.name
+        ))?;
+        Ok(true)
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn set_supplementary_group(gid: Gid) -> nix::Result<()> {
+    unistd::setgroups(&[gid])
+}
+
+// nix does not offer setgroups under macOS, although the system call is available
+#[cfg(target_os = "macos")]
+fn set_supplementary_group(gid: Gid) -> nix::Result<()> {
+    let gids = [gid.as_raw()];
+    nix::errno::Errno::result(unsafe { nix::libc::setgroups(1, gids.as_ptr()) }).map(drop)
+}
+
+fn needs_ownership_change(metadata: &impl MetadataExt, identity: &Identity) -> bool {
+    metadata.uid() != identity.user.uid.as_raw() || metadata.gid() != identity.group.gid.as_raw()
+}
+
+/// Whether switching the user is still due. It is skipped when running with a debug home dir or
+/// in a container.
+pub fn pending() -> bool {
+    unistd::geteuid().is_root()
+        && std::env::var(constants::ENV_HOME_DIR).is_err()
+        && !container::enabled()
+}
+
+#[cfg(test)]
+mod test_privileges {
+    use super::*;
+
+    fn root_config(group: Option<&str>) -> PrivilegesConfig {
+        PrivilegesConfig {
+            user: String::from("root"),
+            group: group.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let identity = Identity::resolve(&root_config(None)).unwrap();
+        assert!(identity.user.uid.is_root());
+        assert_eq!(identity.group.gid, identity.user.gid);
+    }
+
+    #[test]
+    fn test_resolve_unknown() {
+        assert!(Identity::resolve(&PrivilegesConfig {
+            user: String::from("cmk-agent-ctl-no-such-user"),
+            group: None,
+        })
+        .is_err());
+        assert!(Identity::resolve(&root_config(Some("cmk-agent-ctl-no-such-group"))).is_err());
+    }
+
+    #[test]
+    fn test_take_ownership() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("registered_connections.json");
+        std::fs::write(&path, "{}").unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        // Taking over own files works without privileges
+        let identity = Identity {
+            user: User::from_uid(Uid::from_raw(metadata.uid()))
+                .unwrap()
+                .unwrap(),
+            group: Group::from_gid(Gid::from_raw(metadata.gid()))
+                .unwrap()
+                .unwrap(),
+        };
+        assert!(!needs_ownership_change(&metadata, &identity));
+        assert!(!identity.take_ownership(&path).unwrap());
+        assert!(!identity
+            .take_ownership(&dir.path().join("missing"))
+            .unwrap());
+
+        let mut other_user = identity.user.clone();
+        other_user.uid = Uid::from_raw(metadata.uid() + 1);
+        assert!(needs_ownership_change(
+            &metadata,
+            &Identity {
+                user: other_user,
+                group: identity.group.clone(),
+            }
+        ));
+    }
+}
@@ -0,0 +1,414 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! How outgoing HTTPS connections reach the sites. In split-horizon networks, a single proxy for
+//! all sites does not fit, the proxy is rather chosen per target URL as configured on the system.
+
+use crate::constants;
+#[cfg(unix)]
+use crate::pac;
+#[cfg(unix)]
+use anyhow::Context;
+use anyhow::Result as AnyhowResult;
+#[cfg(unix)]
+use log::debug;
+use log::warn;
+use reqwest::blocking::ClientBuilder;
+#[cfg(unix)]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ProxyMode {
+    /// Ignore configured proxies
+    #[default]
+    Direct,
+    /// Proxy from the environment (HTTPS_PROXY, NO_PROXY, ...), under Windows also the static
+    /// proxy of the Internet options
+    Environment,
+    /// Proxy per target URL as given by a proxy auto-config (PAC) script, see `discover`.
+    /// Without PAC script, same as Environment.
+    Auto,
+}
+
+/// Discovered source of the proxy for automatic proxy discovery
+#[derive(Clone)]
+pub enum Discovery {
+    #[cfg(unix)]
+    Pac {
+        location: String,
+        script: Arc<pac::Script>,
+    },
+    #[cfg(windows)]
+    WinHttp(winhttp::AutoConfig),
+    Environment,
+}
+
+impl std::fmt::Display for Discovery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            #[cfg(unix)]
+            Self::Pac { location, .. } => write!(f, "proxy auto-config from {location}"),
+            #[cfg(windows)]
+            Self::WinHttp(winhttp::AutoConfig { url: Some(url) }) => {
+                write!(
+                    f,
+                    "proxy auto-config from {url}, as set in the Internet options"
+                )
+            }
+            #[cfg(windows)]
+            Self::WinHttp(winhttp::AutoConfig { url: None }) => write!(
+                f,
+                "automatically detected proxy, as set in the Internet options"
+            ),
+            Self::Environment => {
+                write!(f, "no proxy auto-config found, proxy from the environment")
+            }
+        }
+    }
+}
+
+impl Discovery {
+    fn proxy_for(&self, url: &reqwest::Url) -> Option<reqwest::Url> {
+        let proxy = match self {
+            #[cfg(unix)]
+            Self::Pac { script, .. } => script
+                .find_proxy(url.as_str(), url.host_str()?, &pac::SystemResolver)
+                .map(|result| pac::proxy_url(&result)),
+            #[cfg(windows)]
+            Self::WinHttp(auto_config) => winhttp::proxy_for_url(url.as_str(), auto_config),
+            Self::Environment => return None,
+        };
+        match proxy
+            .and_then(|proxy| Ok(proxy.map(|proxy| reqwest::Url::parse(&proxy)).transpose()?))
+        {
+            Ok(proxy) => proxy,
+            Err(err) => {
+                warn!(
+                    "Failed to determine the proxy for {}, connecting directly. ({:#})",
+                    url, err
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Discovered on first use, discovered again after PROXY_DISCOVERY_CACHE_TIME
+static DISCOVERY: Mutex<Option<(Instant, Discovery)>> = Mutex::new(None);
+
+/// Apply the proxy mode to a client
+pub fn configure(builder: ClientBuilder, proxy_mode: ProxyMode) -> ClientBuilder {
+    match proxy_mode {
+        ProxyMode::Direct => builder.no_proxy(),
+        // The default of reqwest
+        ProxyMode::Environment => builder,
+        ProxyMode::Auto => match discovery() {
+            Discovery::Environment => builder,
+            discovery => builder.proxy(reqwest::Proxy::custom(move |url| discovery.proxy_for(url))),
+        },
+    }
+}
+
+fn discovery() -> Discovery {
+    let mut cached = DISCOVERY
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some((discovered, discovery)) = cached.as_ref() {
+        if discovered.elapsed() < Duration::from_secs(constants::PROXY_DISCOVERY_CACHE_TIME) {
+            return discovery.clone();
+        }
+    }
+    let discovery = discover().unwrap_or_else(|err| {
+        warn!(
+            "Proxy discovery failed, using the proxy from the environment. ({:#})",
+            err
+        );
+        Discovery::Environment
+    });
+    *cached = Some((Instant::now(), discovery.clone()));
+    discovery
+}
+
+/// The PAC script from the location in CMK_AGENT_CTL_PAC_URL, otherwise the one found via WPAD
+/// in the domain of this host
+#[cfg(unix)]
+pub fn discover() -> AnyhowResult<Discovery> {
+    if let Some(location) = std::env::var(constants::ENV_PAC_URL)
+        .ok()
+        .filter(|location| !location.is_empty())
+    {
+        return pac_discovery(&location, fetch(&location)?);
+    }
+    let host_name = gethostname::gethostname().to_string_lossy().to_string();
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    for location in wpad_urls(local_domain(&host_name, &resolv_conf).as_deref()) {
+        match fetch(&location) {
+            Ok(source) => return pac_discovery(&location, source),
+            Err(err) => debug!("No proxy auto-config at {}. ({:#})", location, err),
+        }
+    }
+    Ok(Discovery::Environment)
+}
+
+#[cfg(unix)]
+fn pac_discovery(location: &str, source: String) -> AnyhowResult<Discovery> {
+    Ok(Discovery::Pac {
+        location: String::from(location),
+        script: Arc::new(
+            pac::Script::parse(&source)
+                .with_context(|| format!("Unsupported proxy auto-config at {location}"))?,
+        ),
+    })
+}
+
+/// As configured in the Internet options, the static proxy is taken from there by reqwest itself
+#[cfg(windows)]
+pub fn discover() -> AnyhowResult<Discovery> {
+    Ok(match winhttp::ie_auto_config()? {
+        Some(auto_config) => Discovery::WinHttp(auto_config),
+        None => Discovery::Environment,
+    })
+}
+
+#[cfg(unix)]
+fn fetch(location: &str) -> AnyhowResult<String> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return Ok(ClientBuilder::new()
+            .no_proxy()
+            .timeout(Duration::from_secs(constants::PROXY_DISCOVERY_TIMEOUT))
+            .build()?
+            .get(location)
+            .send()?
+            .error_for_status()?
+            .text()?);
+    }
+    let path = location.strip_prefix("file://").unwrap_or(location);
+    std::fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))
+}
+
+/// Domain of the host name, if fully qualified, otherwise from the resolver configuration
+#[cfg(unix)]
+fn local_domain(host_name: &str, resolv_conf: &str) -> Option<String> {
+    if let Some((_, domain)) = host_name.split_once('.') {
+        return Some(String::from(domain));
+    }
+    resolv_conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match words.next()? {
+            "domain" | "search" => words.next().map(String::from),
+            _ => None,
+        }
+    })
+}
+
+/// Candidates from the most specific domain on, up to the second-level domain. Hosts below a
+/// top-level domain, eg. wpad.com, are not under the control of the organization.
+#[cfg(unix)]
+fn wpad_urls(domain: Option<&str>) -> Vec<String> {
+    let Some(domain) = domain else {
+        return vec![];
+    };
+    let labels: Vec<&str> = domain.trim_end_matches('.').split('.').collect();
+    (0..labels.len().saturating_sub(1))
+        .map(|start| format!("http://wpad.{}/wpad.dat", labels[start..].join(".")))
+        .collect()
+}
+
+/// Proxy URL from a WinHTTP proxy list, eg. "http=proxy:80;https=secure-proxy:443"
+#[cfg(any(windows, test))]
+fn winhttp_proxy_url(proxy_list: &str) -> Option<String> {
+    let entries: Vec<&str> = proxy_list
+        .split([';', ' '])
+        .filter(|entry| !entry.is_empty())
+        .collect();
+    let address = entries
+        .iter()
+        .find_map(|entry| entry.strip_prefix("https="))
+        .or_else(|| entries.iter().find(|entry| !entry.contains('=')).copied())?;
+    Some(format!("http://{address}"))
+}
+
+#[cfg(windows)]
+mod winhttp {
+    use anyhow::{Context, Result as AnyhowResult};
+    use std::ptr;
+    use winapi::um::winbase::GlobalFree;
+    use winapi::um::winhttp::{
+        WinHttpCloseHandle, WinHttpGetIEProxyConfigForCurrentUser, WinHttpGetProxyForUrl,
+        WinHttpOpen, WINHTTP_ACCESS_TYPE_NAMED_PROXY, WINHTTP_ACCESS_TYPE_NO_PROXY,
+        WINHTTP_AUTOPROXY_AUTO_DETECT, WINHTTP_AUTOPROXY_CONFIG_URL, WINHTTP_AUTOPROXY_OPTIONS,
+        WINHTTP_AUTO_DETECT_TYPE_DHCP, WINHTTP_AUTO_DETECT_TYPE_DNS_A,
+        WINHTTP_CURRENT_USER_IE_PROXY_CONFIG, WINHTTP_PROXY_INFO,
+    };
+    use winapi::um::winnt::LPWSTR;
+
+    /// Automatic configuration of the Internet options, with the URL of the PAC script, if given,
+    /// otherwise detected via WPAD
+    #[derive(Clone)]
+    pub struct AutoConfig {
+        pub url: Option<String>,
+    }
+
+    fn to_wide(string: &str) -> Vec<u16> {
+        string.encode_utf16().chain(std::iter::once(0)).collect()
+    }
+
+    /// Frees the string allocated by WinHTTP
+    unsafe fn take_string(string: LPWSTR) -> Option<String> {
+        if string.is_null() {
+            return None;
+        }
+        let length = (0..).take_while(|&i| *string.add(i) != 0).count();
+        let taken = String::from_utf16_lossy(std::slice::from_raw_parts(string, length));
+        GlobalFree(string as _);
+        Some(taken)
+    }
+
+    pub fn ie_auto_config() -> AnyhowResult<Option<AutoConfig>> {
+        let mut config: WINHTTP_CURRENT_USER_IE_PROXY_CONFIG = unsafe { std::mem::zeroed() };
+        if unsafe { WinHttpGetIEProxyConfigForCurrentUser(&mut config) } == 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to read the proxy settings of the Internet options");
+        }
+        let url = unsafe { take_string(config.lpszAutoConfigUrl) };
+        unsafe {
+            take_string(config.lpszProxy);
+            take_string(config.lpszProxyBypass);
+        }
+        Ok((config.fAutoDetect != 0 || url.is_some()).then_some(AutoConfig { url }))
+    }
+
+    pub fn proxy_for_url(url: &str, auto_config: &AutoConfig) -> AnyhowResult<Option<String>> {
+        let agent = to_wide("cmk-agent-ctl");
+        let session = unsafe {
+            WinHttpOpen(
+                agent.as_ptr(),
+                WINHTTP_ACCESS_TYPE_NO_PROXY,
+                ptr::null(),
+                ptr::null(),
+                0,
+            )
+        };
+        if session.is_null() {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to open a WinHTTP session");
+        }
+        let auto_config_url = auto_config.url.as_deref().map(to_wide);
+        let mut options = WINHTTP_AUTOPROXY_OPTIONS {
+            dwFlags: match auto_config_url {
+                Some(_) => WINHTTP_AUTOPROXY_CONFIG_URL,
+                None => WINHTTP_AUTOPROXY_AUTO_DETECT,
+            },
+            dwAutoDetectFlags: match auto_config_url {
+                Some(_) => 0,
+                None => WINHTTP_AUTO_DETECT_TYPE_DHCP | WINHTTP_AUTO_DETECT_TYPE_DNS_A,
+            },
+            lpszAutoConfigUrl: auto_config_url
+                .as_ref()
+                .map_or(ptr::null(), |url| url.as_ptr()),
+            lpvReserved: ptr::null_mut(),
+            dwReserved: 0,
+            fAutoLogonIfChallenged: 1,
+        };
+        let mut info: WINHTTP_PROXY_INFO = unsafe { std::mem::zeroed() };
+        let url = to_wide(url);
+        let found =
+            unsafe { WinHttpGetProxyForUrl(session, url.as_ptr(), &mut options, &mut info) };
+        let error = std::io::Error::last_os_error();
+        unsafe { WinHttpCloseHandle(session) };
+        if found == 0 {
+            return Err(error).context("WinHTTP failed to determine the proxy");
+        }
+        let proxy_list = unsafe { take_string(info.lpszProxy) };
+        unsafe { take_string(info.lpszProxyBypass) };
+        Ok(match info.dwAccessType {
+            WINHTTP_ACCESS_TYPE_NAMED_PROXY => {
+                proxy_list.and_then(|list| super::winhttp_proxy_url(&list))
+            }
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_proxy {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wpad_urls() {
+        assert_eq!(
+            wpad_urls(
+                local_domain("host.branch.corp.example.com", "search other.example.com").as_deref()
+            ),
+            vec![
+                "http://wpad.branch.corp.example.com/wpad.dat",
+                "http://wpad.corp.example.com/wpad.dat",
+                "http://wpad.example.com/wpad.dat",
+            ]
+        );
+        assert_eq!(
+            wpad_urls(
+                local_domain(
+                    "host",
+                    "# comment\nnameserver 10.0.0.1\nsearch example.com corp\n"
+                )
+                .as_deref()
+            ),
+            vec!["http://wpad.example.com/wpad.dat"]
+        );
+        assert!(wpad_urls(local_domain("host", "search corp").as_deref()).is_empty());
+        assert!(wpad_urls(local_domain("host", "").as_deref()).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pac_discovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("proxy.pac");
+        std::fs::write(
+            &path,
+            "function FindProxyForURL(url, host) {
+                if (dnsDomainIs(host, '.corp.example.com')) return 'DIRECT';
+                return 'PROXY proxy.example.com:3128';
+            }",
+        )
+        .unwrap();
+        let location = format!("file://{}", path.display());
+        let discovery = pac_discovery(&location, fetch(&location).unwrap()).unwrap();
+        assert_eq!(
+            discovery.to_string(),
+            format!("proxy auto-config from {location}")
+        );
+        assert_eq!(
+            discovery.proxy_for(
+                &reqwest::Url::parse("https://checkmk.corp.example.com:8000/site").unwrap()
+            ),
+            None
+        );
+        assert_eq!(
+            discovery
+                .proxy_for(&reqwest::Url::parse("https://checkmk.example.org:8000/site").unwrap())
+                .unwrap()
+                .as_str(),
+            "http://proxy.example.com:3128/"
+        );
+        assert!(pac_discovery(&location, String::from("var a = 1;")).is_err());
+    }
+
+    #[test]
+    fn test_winhttp_proxy_url() {
+        assert_eq!(
+            winhttp_proxy_url("proxy:8080; other:8080"),
+            Some(String::from("http://proxy:8080"))
+        );
+        assert_eq!(
+            winhttp_proxy_url("http=proxy:80;https=secure-proxy:443"),
+            Some(String::from("http://secure-proxy:443"))
+        );
+        assert_eq!(winhttp_proxy_url("ftp=ftp-proxy:21"), None);
+    }
+}
@@ -0,0 +1,510 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Pull for hosts the site cannot connect to, eg. behind NAT or firewalls. For every registered
+//! pull connection, the controller opens a WebSocket to the agent receiver, authenticated with the
+//! certificate of the connection like pushes. The site then requests the agent output through
+//! this tunnel:
+//!
+//! * the site sends the text message `{"request_id": <id>}`
+//! * the controller answers with a binary message, the request ID (8 bytes, big endian) followed by
+//!   the data a pull request via the pull port gets, ie. protocol version, compression and agent
+//!   output. If the agent output cannot be collected, it answers with the text message
+//!   `{"request_id": <id>, "error": "<reason>"}`.
+//!
+//! In reverse connection mode, tunnels are kept open for push connections as well, and the site may
+//! trigger a push right away with `{"request_id": <id>, "command": "push"}`. The controller answers
+//! with `{"request_id": <id>}` once the push succeeded, or with an error as above.
+//!
+//! Requests are served concurrently, so the responses may arrive in a different order.
+
+use crate::connection_stats::ConnectionStats;
+use crate::modes::pull::{AgentOutputCollector, AgentOutputCollectorImpl};
+use crate::modes::push;
+use crate::websocket::{self, Message, WebSocket};
+use crate::{certs, config, constants, happy_eyeballs, ipc, misc, site_spec, transport};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::ServerName;
+use tokio_rustls::TlsConnector;
+
+/// The agent receiver a tunnel is opened to
+#[derive(Clone)]
+struct Target {
+    site_id: site_spec::SiteID,
+    receiver_port: u16,
+    trust: config::TrustedConnection,
+}
+
+impl Target {
+    /// Connections registered with the transport WebSocket are always included
+    fn targets(
+        registry: &config::Registry,
+        all_pull: bool,
+        reverse_connection: bool,
+    ) -> HashMap<uuid::Uuid, Target> {
+        let now = misc::unix_now();
+        let websocket = |connection: &config::TrustedConnectionWithRemote| {
+            connection.trust.transport == transport::TransportKind::WebSocket
+        };
+        let pull_connections = registry
+            .get_standard_pull_connections()
+            .filter(|(_, connection)| all_pull || websocket(connection));
+        let push_connections = registry
+            .get_push_connections()
+            .filter(|(_, connection)| reverse_connection || websocket(connection));
+        pull_connections
+            .chain(push_connections)
+            .filter(|(_, connection)| !connection.is_expired(now))
+            .map(|(site_id, connection)| {
+                (
+                    connection.trust.uuid,
+                    Target {
+                        site_id: site_id.clone(),
+                        receiver_port: connection.receiver_port,
+                        trust: connection.trust.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Connections compare by UUID only, but a renewed certificate requires a new tunnel as well
+    fn same_as(&self, other: &Target) -> bool {
+        self.site_id == other.site_id
+            && self.receiver_port == other.receiver_port
+            && self.trust.certificate == other.trust.certificate
+            && self.trust.root_cert == other.trust.root_cert
+    }
+}
+
+/// What serving the requests of all tunnels requires
+#[derive(Clone)]
+struct Handler<C> {
+    collector: C,
+    connection_stats: ConnectionStats,
+    push_now: mpsc::Sender<push::PushNowRequest>,
+}
+
+struct Tunnel {
+    target: Target,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Keep a tunnel open for every pull connection (every connection in reverse connection mode),
+/// following changes of the registry
+pub async fn serve(
+    mut registry: config::Registry,
+    pull_tunnel: bool,
+    reverse_connection: bool,
+    ip_preference: happy_eyeballs::IpPreference,
+    collector: AgentOutputCollectorImpl,
+    connection_stats: ConnectionStats,
+    push_now: mpsc::Sender<push::PushNowRequest>,
+) -> AnyhowResult<()> {
+    let handler = Handler {
+        collector,
+        connection_stats,
+        push_now,
+    };
+    let mut tunnels: HashMap<uuid::Uuid, Tunnel> = HashMap::new();
+    loop {
+        registry.refresh()?;
+        let targets = Target::targets(&registry, pull_tunnel, reverse_connection);
+        tunnels.retain(|uuid, tunnel| {
+            let keep = targets
+                .get(uuid)
+                .is_some_and(|target| target.same_as(&tunnel.target));
+            if !keep {
+                info!("{}: Closing pull tunnel", tunnel.target.site_id);
+            }
+            keep
+        });
+        for (uuid, target) in targets {
+            tunnels.entry(uuid).or_insert_with(|| {
+                info!("{}: Opening pull tunnel", target.site_id);
+                Tunnel {
+                    target: target.clone(),
+                    task: tokio::spawn(keep_open(target, handler.clone(), ip_preference)),
+                }
+            });
+        }
+        tokio::time::sleep(Duration::from_secs(constants::PULL_TUNNEL_REFRESH_INTERVAL)).await;
+    }
+}
+
+async fn keep_open(
+    target: Target,
+    handler: Handler<impl AgentOutputCollector>,
+    ip_preference: happy_eyeballs::IpPreference,
+) {
+    let mut retry = constants::PULL_TUNNEL_RETRY_MIN;
+    loop {
+        let opened = Instant::now();
+        if let Err(err) = tunnel(&target, &handler, ip_preference).await {
+            warn!(
+                "{}: Pull tunnel failed, reopening it in {} seconds. ({:#})",
+                target.site_id, retry, err
+            );
+        }
+        // Only back off if the tunnel fails repeatedly
+        if opened.elapsed() > Duration::from_secs(constants::PULL_TUNNEL_RETRY_MAX) {
+            retry = constants::PULL_TUNNEL_RETRY_MIN;
+        }
+        tokio::time::sleep(Duration::from_secs(retry)).await;
+        retry = (retry * 2).min(constants::PULL_TUNNEL_RETRY_MAX);
+    }
+}
+
+async fn tunnel(
+    target: &Target,
+    handler: &Handler<impl AgentOutputCollector>,
+    ip_preference: happy_eyeballs::IpPreference,
+) -> AnyhowResult<()> {
+    let server = &target.site_id.server;
+    let tcp_stream = tokio::time::timeout(
+        Duration::from_secs(constants::CONNECTION_TIMEOUT),
+        happy_eyeballs::connect(server, target.receiver_port, ip_preference),
+    )
+    .await
+    .context(format!(
+        "Timeout connecting to {}:{}",
+        server, target.receiver_port
+    ))??;
+    let peer = tcp_stream.peer_addr()?.ip();
+    // The server certificate is verified by its CN, the name only serves for SNI
+    let server_name =
+        ServerName::try_from(server.as_str()).context(format!("Invalid server name {}", server))?;
+    let tls_stream = TlsConnector::from(Arc::new(certs::websocket_tls_config(
+        target.trust.tls_handshake_credentials()?,
+    )?))
+    .connect(server_name, tcp_stream)
+    .await
+    .context("TLS handshake with agent receiver failed")?;
+    let mut websocket = websocket::connect(
+        tls_stream,
+        &format!("{}:{}", server, target.receiver_port),
+        &format!(
+            "/{}/agent-receiver/pull_tunnel/{}",
+            target.site_id.site, target.trust.uuid
+        ),
+        constants::PULL_TUNNEL_MAX_MESSAGE_SIZE,
+    )
+    .await?;
+    info!("{}: Pull tunnel is open", target.site_id);
+    serve_requests(&mut websocket, target, handler, peer).await
+}
+
+async fn serve_requests<S: AsyncRead + AsyncWrite + Send + 'static>(
+    websocket: &mut WebSocket<S>,
+    target: &Target,
+    handler: &Handler<impl AgentOutputCollector>,
+    peer: IpAddr,
+) -> AnyhowResult<()> {
+    let ping_interval = Duration::from_secs(constants::PULL_TUNNEL_PING_INTERVAL);
+    let mut awaiting_pong = false;
+    loop {
+        let Some(message) = websocket.receive(ping_interval).await? else {
+            if awaiting_pong {
+                bail!(
+                    "No answer from agent receiver for {} seconds",
+                    2 * constants::PULL_TUNNEL_PING_INTERVAL
+                );
+            }
+            websocket.ping().await?;
+            awaiting_pong = true;
+            continue;
+        };
+        awaiting_pong = false;
+        match message {
+            Message::Text(request) => {
+                let sender = websocket.sender();
+                let target = target.clone();
+                let handler = handler.clone();
+                // A slow agent must not hold up the other requests
+                tokio::spawn(async move {
+                    if let Err(err) =
+                        serve_request(&request, &sender, &target, &handler, peer).await
+                    {
+                        warn!(
+                            "{}: Failed to answer request through tunnel. ({:#})",
+                            target.site_id, err
+                        );
+                    }
+                });
+            }
+            Message::Binary(_) => bail!("Unexpected binary message from agent receiver"),
+            Message::Pong => {}
+            Message::Close => {
+                info!("{}: Pull tunnel closed by agent receiver", target.site_id);
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn serve_request<S: AsyncWrite>(
+    request: &str,
+    sender: &websocket::Sender<S>,
+    target: &Target,
+    handler: &Handler<impl AgentOutputCollector>,
+    peer: IpAddr,
+) -> AnyhowResult<()> {
+    let (response, bytes) = respond(request, target, handler, peer).await?;
+    sender.send(response).await?;
+    if let Some(bytes) = bytes {
+        info!(
+            "{}: Served pull request through tunnel for connection {}.",
+            target.site_id, target.trust.uuid
+        );
+        handler
+            .connection_stats
+            .record_pull(&target.trust.uuid, peer, bytes);
+    }
+    Ok(())
+}
+
+#[derive(serde::Deserialize, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Command {
+    #[default]
+    Pull,
+    Push,
+}
+
+#[derive(serde::Deserialize)]
+struct Request {
+    request_id: u64,
+    #[serde(default)]
+    command: Command,
+}
+
+#[derive(serde::Serialize)]
+struct Reply {
+    request_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// The answer to a request and the number of bytes pulled, if any
+async fn respond(
+    request: &str,
+    target: &Target,
+    handler: &Handler<impl AgentOutputCollector>,
+    peer: IpAddr,
+) -> AnyhowResult<(Message, Option<usize>)> {
+    let request: Request =
+        serde_json::from_str(request).context("Invalid request from agent receiver")?;
+    debug!(
+        "{:?} request {} through tunnel",
+        request.command, request.request_id
+    );
+    let reply = |error: Option<String>| -> AnyhowResult<Message> {
+        Ok(Message::Text(serde_json::to_string(&Reply {
+            request_id: request.request_id,
+            error,
+        })?))
+    };
+    let result = match request.command {
+        Command::Pull => handler
+            .collector
+            .encoded_output(peer, target.trust.data_source.as_ref())
+            .await
+            .map(|mon_data| {
+                let mut response = request.request_id.to_be_bytes().to_vec();
+                response.extend_from_slice(&mon_data);
+                (Message::Binary(response), Some(mon_data.len()))
+            }),
+        Command::Push => trigger_push(target, &handler.push_now)
+            .await
+            .and_then(|()| Ok((reply(None)?, None))),
+    };
+    result.or_else(|err| {
+        warn!(
+            "{}: Failed to serve {:?} request through tunnel. ({:#})",
+            target.site_id, request.command, err
+        );
+        Ok((reply(Some(format!("{err:#}")))?, None))
+    })
+}
+
+/// Push to the connection of the tunnel right away, like push-now
+async fn trigger_push(
+    target: &Target,
+    push_now: &mpsc::Sender<push::PushNowRequest>,
+) -> AnyhowResult<()> {
+    match push::request_push_now(Some(target.trust.uuid.to_string()), push_now).await {
+        ipc::Response::PushNow { results } => match results.into_iter().find_map(|r| r.error) {
+            Some(error) => bail!(error),
+            None => Ok(()),
+        },
+        ipc::Response::Error { message } => bail!(message),
+        response => bail!("Unexpected response from push task: {:?}", response),
+    }
+}
+
+#[cfg(test)]
+mod test_pull_tunnel {
+    use super::*;
+    use crate::data_source::DataSource;
+    use crate::payload_memory::{BufferedPayload, PayloadMemory};
+    use anyhow::anyhow;
+    use async_trait::async_trait;
+    use config::test_helpers::TestRegistry;
+    use std::str::FromStr;
+
+    #[derive(Clone)]
+    struct TestCollector {
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl AgentOutputCollector for TestCollector {
+        async fn plain_output(&self, _remote_ip: IpAddr) -> AnyhowResult<BufferedPayload> {
+            Err(anyhow!("Pull tunnels only collect encoded output"))
+        }
+
+        async fn encoded_output(
+            &self,
+            _remote_ip: IpAddr,
+            _data_source: Option<&DataSource>,
+        ) -> AnyhowResult<BufferedPayload> {
+            if self.fail {
+                return Err(anyhow!("Agent socket is gone"));
+            }
+            Ok(PayloadMemory::new(None).buffer(b"output".to_vec()).unwrap())
+        }
+    }
+
+    fn peer() -> IpAddr {
+        IpAddr::from_str("192.168.0.1").unwrap()
+    }
+
+    fn target() -> Target {
+        let uuid = uuid::Uuid::new_v4();
+        let registry = TestRegistry::new().add_connection(
+            &config::ConnectionMode::Push,
+            "server/push-site",
+            uuid,
+        );
+        Target::targets(&registry.registry, true, true)
+            .remove(&uuid)
+            .unwrap()
+    }
+
+    /// Responding records nothing, and the push task is not running
+    fn handler(fail: bool) -> Handler<TestCollector> {
+        Handler {
+            collector: TestCollector { fail },
+            connection_stats: ConnectionStats::new("connection_stats.json"),
+            push_now: mpsc::channel(1).0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_respond() {
+        let mut expected = 17u64.to_be_bytes().to_vec();
+        expected.extend(b"output");
+        for request in [
+            r#"{"request_id": 17}"#,
+            r#"{"request_id": 17, "command": "pull"}"#,
+        ] {
+            assert_eq!(
+                respond(request, &target(), &handler(false), peer())
+                    .await
+                    .unwrap(),
+                (Message::Binary(expected.clone()), Some(6))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_respond_failure() {
+        assert_eq!(
+            respond(r#"{"request_id": 17}"#, &target(), &handler(true), peer())
+                .await
+                .unwrap(),
+            (
+                Message::Text(String::from(
+                    r#"{"request_id":17,"error":"Agent socket is gone"}"#
+                )),
+                None
+            )
+        );
+        for request in ["pull", r#"{"request_id": 17, "command": "collect"}"#] {
+            assert!(respond(request, &target(), &handler(false), peer())
+                .await
+                .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_respond_push() {
+        assert_eq!(
+            respond(
+                r#"{"request_id": 18, "command": "push"}"#,
+                &target(),
+                &handler(false),
+                peer()
+            )
+            .await
+            .unwrap(),
+            (
+                Message::Text(String::from(
+                    r#"{"request_id":18,"error":"Push task is not running"}"#
+                )),
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn test_targets() {
+        let uuid = uuid::Uuid::new_v4();
+        let registry = TestRegistry::new()
+            .add_connection(&config::ConnectionMode::Pull, "server/pull-site", uuid)
+            .add_connection(
+                &config::ConnectionMode::Push,
+                "server/push-site",
+                uuid::Uuid::new_v4(),
+            )
+            .add_imported_connection(uuid::Uuid::new_v4());
+        assert_eq!(Target::targets(&registry.registry, true, true).len(), 2);
+        assert!(Target::targets(&registry.registry, false, false).is_empty());
+        let targets = Target::targets(&registry.registry, true, false);
+        assert_eq!(targets.len(), 1);
+        let target = &targets[&uuid];
+        assert_eq!(target.site_id.to_string(), "server/pull-site");
+        assert!(target.same_as(target));
+        let mut renewed = target.clone();
+        renewed.trust.certificate = String::from("renewed");
+        assert!(!target.same_as(&renewed));
+    }
+
+    #[test]
+    fn test_targets_websocket() {
+        let uuid = uuid::Uuid::new_v4();
+        let mut connection = config::TrustedConnectionWithRemote::from(uuid);
+        connection.trust.transport = transport::TransportKind::WebSocket;
+        let mut registry = TestRegistry::new();
+        registry.registry.register_connection(
+            &config::ConnectionMode::Push,
+            &site_spec::SiteID::from_str("server/push-site").unwrap(),
+            connection,
+        );
+        assert!(Target::targets(&registry.registry, false, false).contains_key(&uuid));
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Failover of pushes to further endpoints of a site, eg. the standby receivers of an HA setup.
+//! Before each push, the endpoint in use is probed. If it does not answer, the next one which
+//! does takes over. The registered endpoint is preferred: while another one is in use, it is
+//! probed again every few minutes, and pushes return to it as soon as it answers.
+
+use crate::{config, constants, site_spec};
+use anyhow::{Context, Result as AnyhowResult};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Endpoint in use for a connection other than the registered one
+struct Failover {
+    index: usize,
+    /// When the registered endpoint was found unavailable last
+    primary_probed: Instant,
+}
+
+pub struct PushFailover {
+    endpoints: HashMap<String, Vec<String>>,
+    recovery_interval: Duration,
+    failovers: Mutex<HashMap<uuid::Uuid, Failover>>,
+}
+
+impl PushFailover {
+    pub fn new(push_config: &config::PushConfig) -> Self {
+        Self {
+            endpoints: push_config.push_failover.clone(),
+            recovery_interval: Duration::from_secs(constants::PUSH_FAILOVER_RECOVERY_INTERVAL),
+            failovers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn failovers(&self) -> MutexGuard<'_, HashMap<uuid::Uuid, Failover>> {
+        match self.failovers.lock() {
+            Ok(failovers) => failovers,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// The registered endpoint of a connection, followed by the ones configured for failover
+    fn endpoint_urls(
+        &self,
+        site_id: &site_spec::SiteID,
+        receiver_port: u16,
+    ) -> AnyhowResult<Vec<reqwest::Url>> {
+        let mut urls = vec![site_spec::make_site_url(site_id, &receiver_port)?];
+        for endpoint in self
+            .endpoints
+            .get(&site_id.to_string())
+            .into_iter()
+            .flatten()
+        {
+            let (server, port) = parse_endpoint(endpoint, receiver_port);
+            urls.push(
+                site_spec::make_site_url(
+                    &site_spec::SiteID {
+                        server: String::from(server),
+                        site: site_id.site.clone(),
+                    },
+                    &port,
+                )
+                .context(format!(
+                    "Invalid failover endpoint '{endpoint}' for {site_id}"
+                ))?,
+            );
+        }
+        Ok(urls)
+    }
+
+    /// URL to push to, the one of the endpoint in use as long as it is available. Nothing is
+    /// probed for connections without failover endpoints.
+    pub fn select(
+        &self,
+        site_id: &site_spec::SiteID,
+        connection: &config::TrustedConnectionWithRemote,
+        probe: impl Fn(&reqwest::Url) -> bool,
+        now: Instant,
+    ) -> AnyhowResult<reqwest::Url> {
+        let mut urls = self.endpoint_urls(site_id, connection.receiver_port)?;
+        if urls.len() == 1 {
+            return Ok(urls.remove(0));
+        }
+        let uuid = &connection.trust.uuid;
+        let (mut index, mut primary_probed) = self
+            .failovers()
+            .get(uuid)
+            .filter(|failover| failover.index < urls.len())
+            .map_or((0, now), |failover| {
+                (failover.index, failover.primary_probed)
+            });
+
+        // The probes go to the receivers, the lock is not held meanwhile
+        let available =
+            if index != 0 && now.duration_since(primary_probed) >= self.recovery_interval {
+                primary_probed = now;
+                if probe(&urls[0]) {
+                    info!(
+                        "{}: Registered endpoint {} is available again, pushing to it",
+                        site_id, urls[0]
+                    );
+                    index = 0;
+                    true
+                } else {
+                    probe(&urls[index])
+                }
+            } else {
+                probe(&urls[index])
+            };
+        if !available {
+            if let Some(next) = (1..urls.len())
+                .map(|offset| (index + offset) % urls.len())
+                .find(|candidate| probe(&urls[*candidate]))
+            {
+                warn!(
+                    "{}: Endpoint {} is unavailable, failing over to {}",
+                    site_id, urls[index], urls[next]
+                );
+                if index == 0 {
+                    primary_probed = now;
+                }
+                index = next;
+            }
+        }
+
+        if index == 0 {
+            self.failovers().remove(uuid);
+        } else {
+            self.failovers().insert(
+                *uuid,
+                Failover {
+                    index,
+                    primary_probed,
+                },
+            );
+        }
+        Ok(urls.swap_remove(index))
+    }
+}
+
+/// Host and port of an endpoint given as "host" or "host:port", IPv6 addresses in brackets
+fn parse_endpoint(endpoint: &str, default_port: u16) -> (&str, u16) {
+    match endpoint.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => match port.parse() {
+            Ok(port) => (host, port),
+            Err(_) => (endpoint, default_port),
+        },
+        _ => (endpoint, default_port),
+    }
+}
+
+#[cfg(test)]
+mod test_push_failover {
+    use super::*;
+    use std::cell::RefCell;
+    use std::str::FromStr;
+
+    fn push_failover(endpoints: &[&str]) -> PushFailover {
+        PushFailover {
+            endpoints: HashMap::from([(
+                String::from("primary/site"),
+                endpoints.iter().map(|e| String::from(*e)).collect(),
+            )]),
+            recovery_interval: Duration::from_secs(300),
+            failovers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn connection() -> config::TrustedConnectionWithRemote {
+        config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4())
+    }
+
+    /// Probes which succeed for the given hosts, recording all probed hosts
+    struct Receivers {
+        available: RefCell<Vec<&'static str>>,
+        probed: RefCell<Vec<String>>,
+    }
+
+    impl Receivers {
+        fn new(available: &[&'static str]) -> Self {
+            Self {
+                available: RefCell::new(available.to_vec()),
+                probed: RefCell::new(vec![]),
+            }
+        }
+
+        fn probe(&self, url: &reqwest::Url) -> bool {
+            let host = url.host_str().unwrap().to_string();
+            self.probed.borrow_mut().push(host.clone());
+            self.available.borrow().contains(&host.as_str())
+        }
+
+        fn probed(&self) -> Vec<String> {
+            self.probed.take()
+        }
+    }
+
+    fn host(url: AnyhowResult<reqwest::Url>) -> String {
+        url.unwrap().host_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(parse_endpoint("standby", 8000), ("standby", 8000));
+        assert_eq!(parse_endpoint("standby:8001", 8000), ("standby", 8001));
+        assert_eq!(parse_endpoint("[::1]", 8000), ("[::1]", 8000));
+        assert_eq!(parse_endpoint("[::1]:8001", 8000), ("[::1]", 8001));
+    }
+
+    #[test]
+    fn test_without_failover_endpoints() {
+        let failover = push_failover(&[]);
+        let receivers = Receivers::new(&[]);
+        let site_id = site_spec::SiteID::from_str("other/site").unwrap();
+        let url = failover.select(
+            &site_id,
+            &connection(),
+            |url| receivers.probe(url),
+            Instant::now(),
+        );
+        assert_eq!(url.unwrap().as_str(), "https://other:8000/site");
+        assert!(receivers.probed().is_empty());
+    }
+
+    #[test]
+    fn test_failover_and_recovery() {
+        let failover = push_failover(&["standby-1", "standby-2:8001"]);
+        let receivers = Receivers::new(&["primary", "standby-1", "standby-2"]);
+        let site_id = site_spec::SiteID::from_str("primary/site").unwrap();
+        let connection = connection();
+        let start = Instant::now();
+        let select = |seconds| {
+            failover.select(
+                &site_id,
+                &connection,
+                |url| receivers.probe(url),
+                start + Duration::from_secs(seconds),
+            )
+        };
+
+        assert_eq!(host(select(0)), "primary");
+        assert_eq!(receivers.probed(), ["primary"]);
+
+        receivers.available.replace(vec!["standby-2"]);
+        let url = select(60).unwrap();
+        assert_eq!(url.as_str(), "https://standby-2:8001/site");
+        assert_eq!(receivers.probed(), ["primary", "standby-1", "standby-2"]);
+
+        // Sticky: the endpoint in use is kept, even if an earlier one is available again
+        receivers.available.replace(vec!["standby-1", "standby-2"]);
+        assert_eq!(host(select(120)), "standby-2");
+        assert_eq!(receivers.probed(), ["standby-2"]);
+
+        // The registered endpoint is probed again after the recovery interval
+        assert_eq!(host(select(360)), "standby-2");
+        assert_eq!(receivers.probed(), ["primary", "standby-2"]);
+        receivers.available.replace(vec!["primary", "standby-2"]);
+        assert_eq!(host(select(420)), "standby-2");
+        assert_eq!(receivers.probed(), ["standby-2"]);
+        assert_eq!(host(select(660)), "primary");
+        assert_eq!(receivers.probed(), ["primary"]);
+    }
+
+    #[test]
+    fn test_nothing_available() {
+        let failover = push_failover(&["standby"]);
+        let receivers = Receivers::new(&[]);
+        let site_id = site_spec::SiteID::from_str("primary/site").unwrap();
+        let url = failover.select(
+            &site_id,
+            &connection(),
+            |url| receivers.probe(url),
+            Instant::now(),
+        );
+        assert_eq!(host(url), "primary");
+        assert_eq!(receivers.probed(), ["primary", "standby"]);
+    }
+}
@@ -0,0 +1,300 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use anyhow::{Context, Result as AnyhowResult};
+use log::{info, warn};
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+const SPOOL_FILE_EXTENSION: &str = "zlib";
+
+/// A compressed agent output which could not be pushed and is waiting to be replayed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SpooledPayload {
+    /// Unix timestamp at which the agent output was collected
+    pub collected_at: u64,
+    path: PathBuf,
+}
+
+impl SpooledPayload {
+    pub fn read(&self) -> AnyhowResult<Vec<u8>> {
+        fs::read(&self.path).context(format!("Failed to read spooled payload {:?}", self.path))
+    }
+
+    pub fn size(&self) -> AnyhowResult<u64> {
+        Ok(fs::metadata(&self.path)
+            .context(format!("Failed to inspect spooled payload {:?}", self.path))?
+            .len())
+    }
+
+    pub fn remove(&self) -> AnyhowResult<()> {
+        fs::remove_file(&self.path)
+            .context(format!("Failed to remove spooled payload {:?}", self.path))
+    }
+}
+
+/// On-disk queue of compressed agent outputs per push connection. Payloads are kept in one
+/// directory per connection UUID, named after their collection timestamp. Each queue holds at most
+/// `max_entries` payloads and all queues together at most `max_size` bytes, older payloads are
+/// dropped first.
+#[derive(Clone)]
+pub struct PushSpool {
+    dir: PathBuf,
+    max_entries: usize,
+    max_size: Option<u64>,
+}
+
+impl PushSpool {
+    pub fn new(dir: impl AsRef<Path>, max_entries: usize, max_size: Option<u64>) -> Self {
+        Self {
+            dir: PathBuf::from(dir.as_ref()),
+            max_entries,
+            max_size,
+        }
+    }
+
+    fn connection_dir(&self, uuid: &uuid::Uuid) -> PathBuf {
+        self.dir.join(uuid.to_string())
+    }
+
+    pub fn spool(
+        &self,
+        uuid: &uuid::Uuid,
+        collected_at: u64,
+        compressed_mon_data: &[u8],
+    ) -> AnyhowResult<()> {
+        if self.max_entries == 0 {
+            return Ok(());
+        }
+        let connection_dir = self.connection_dir(uuid);
+        fs::create_dir_all(&connection_dir).context(format!(
+            "Failed to create spool directory {:?}",
+            connection_dir
+        ))?;
+        #[cfg(unix)]
+        fs::set_permissions(&self.dir, fs::Permissions::from_mode(0o700))?;
+        let path = connection_dir.join(format!("{collected_at}.{SPOOL_FILE_EXTENSION}"));
+        let tmp_path = path.with_extension(format!("{SPOOL_FILE_EXTENSION}.tmp"));
+        fs::write(&tmp_path, compressed_mon_data)
+            .context(format!("Failed to write spooled payload {:?}", tmp_path))?;
+        fs::rename(&tmp_path, &path)?;
+
+        let entries = self.entries(uuid)?;
+        let excess = entries.len().saturating_sub(self.max_entries);
+        for entry in &entries[..excess] {
+            warn!(
+                "{}: Push spool is full, dropping agent output collected at {}",
+                uuid, entry.collected_at
+            );
+            entry.remove()?;
+        }
+        self.enforce_max_size()
+    }
+
+    /// Drop the oldest payloads of all connections until the spool fits into its maximum size
+    fn enforce_max_size(&self) -> AnyhowResult<()> {
+        let Some(max_size) = self.max_size else {
+            return Ok(());
+        };
+        let mut entries = vec![];
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let Some(uuid) = dir_entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| uuid::Uuid::parse_str(name).ok())
+            else {
+                continue;
+            };
+            for entry in self.entries(&uuid)? {
+                let size = entry.size()?;
+                entries.push((uuid, entry, size));
+            }
+        }
+        let mut total_size: u64 = entries.iter().map(|(_, _, size)| size).sum();
+        entries.sort_by_key(|(_, entry, _)| entry.collected_at);
+        for (uuid, entry, size) in entries {
+            if total_size <= max_size {
+                break;
+            }
+            warn!(
+                "{}: Push spool exceeds {} bytes, dropping agent output collected at {}",
+                uuid, max_size, entry.collected_at
+            );
+            entry.remove()?;
+            total_size -= size;
+        }
+        Ok(())
+    }
+
+    /// Spooled payloads of the given connection, oldest first
+    pub fn entries(&self, uuid: &uuid::Uuid) -> AnyhowResult<Vec<SpooledPayload>> {
+        let connection_dir = self.connection_dir(uuid);
+        if !connection_dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut entries = vec![];
+        for dir_entry in fs::read_dir(&connection_dir).context(format!(
+            "Failed to read spool directory {:?}",
+            connection_dir
+        ))? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SPOOL_FILE_EXTENSION) {
+                continue;
+            }
+            let Some(collected_at) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            entries.push(SpooledPayload { collected_at, path });
+        }
+        entries.sort_by_key(|entry| entry.collected_at);
+        Ok(entries)
+    }
+
+    /// Remove the spooled payloads of the given connection, or only the one collected at the given
+    /// time. Returns the number of removed payloads.
+    pub fn drop_entries(
+        &self,
+        uuid: &uuid::Uuid,
+        collected_at: Option<u64>,
+    ) -> AnyhowResult<usize> {
+        let mut dropped = 0;
+        for entry in self.entries(uuid)? {
+            if collected_at.is_some_and(|collected_at| collected_at != entry.collected_at) {
+                continue;
+            }
+            entry.remove()?;
+            dropped += 1;
+        }
+        Ok(dropped)
+    }
+
+    /// Drop the queues of connections which are not registered (anymore)
+    pub fn retain(&self, uuids: &[uuid::Uuid]) -> AnyhowResult<()> {
+        if !self.dir.exists() {
+            return Ok(());
+        }
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            let is_known = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| uuid::Uuid::parse_str(name).ok())
+                .is_some_and(|uuid| uuids.contains(&uuid));
+            if !is_known && path.is_dir() {
+                info!("Discarding push spool {:?} of unknown connection", path);
+                fs::remove_dir_all(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_push_spool {
+    use super::*;
+    use std::str::FromStr;
+
+    fn uuid() -> uuid::Uuid {
+        uuid::Uuid::from_str("99f56bbc-5965-4b34-bc70-1959ad1d32d6").unwrap()
+    }
+
+    #[test]
+    fn test_spool_and_replay_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = PushSpool::new(dir.path().join("push_spool"), 10, None);
+        assert!(spool.entries(&uuid()).unwrap().is_empty());
+        for (collected_at, data) in [(1200, b"second"), (900, b"first_"), (1500, b"third_")] {
+            spool.spool(&uuid(), collected_at, data).unwrap();
+        }
+
+        let entries = spool.entries(&uuid()).unwrap();
+        assert_eq!(
+            entries.iter().map(|e| e.collected_at).collect::<Vec<_>>(),
+            vec![900, 1200, 1500]
+        );
+        assert_eq!(entries[0].read().unwrap(), b"first_");
+        entries[0].remove().unwrap();
+        assert_eq!(spool.entries(&uuid()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_spool_is_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = PushSpool::new(dir.path(), 3, None);
+        for collected_at in 0..5 {
+            spool.spool(&uuid(), collected_at, b"data").unwrap();
+        }
+        assert_eq!(
+            spool
+                .entries(&uuid())
+                .unwrap()
+                .iter()
+                .map(|e| e.collected_at)
+                .collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn test_spool_max_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = PushSpool::new(dir.path(), 10, Some(10));
+        let other = uuid::Uuid::from_str("0096abd7-83c9-42f8-8b3a-3ffba7ba959d").unwrap();
+        spool.spool(&uuid(), 1000, b"data").unwrap();
+        spool.spool(&other, 1100, b"data").unwrap();
+        assert_eq!(spool.entries(&uuid()).unwrap().len(), 1);
+        // Exceeding the size drops the oldest payload, regardless of its connection
+        spool.spool(&other, 1200, b"data").unwrap();
+        assert!(spool.entries(&uuid()).unwrap().is_empty());
+        assert_eq!(
+            spool
+                .entries(&other)
+                .unwrap()
+                .iter()
+                .map(|e| e.collected_at)
+                .collect::<Vec<_>>(),
+            vec![1100, 1200]
+        );
+    }
+
+    #[test]
+    fn test_spool_disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = PushSpool::new(dir.path().join("push_spool"), 0, None);
+        spool.spool(&uuid(), 1000, b"data").unwrap();
+        assert!(!dir.path().join("push_spool").exists());
+    }
+
+    #[test]
+    fn test_drop_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = PushSpool::new(dir.path(), 10, None);
+        for collected_at in [1000, 1100, 1200] {
+            spool.spool(&uuid(), collected_at, b"data").unwrap();
+        }
+        assert_eq!(spool.drop_entries(&uuid(), Some(1100)).unwrap(), 1);
+        assert_eq!(spool.drop_entries(&uuid(), Some(1100)).unwrap(), 0);
+        assert_eq!(spool.entries(&uuid()).unwrap()[0].size().unwrap(), 4);
+        assert_eq!(spool.drop_entries(&uuid(), None).unwrap(), 2);
+        assert!(spool.entries(&uuid()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_retain() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = PushSpool::new(dir.path(), 3, None);
+        let other = uuid::Uuid::from_str("0096abd7-83c9-42f8-8b3a-3ffba7ba959d").unwrap();
+        spool.spool(&uuid(), 1000, b"data").unwrap();
+        spool.spool(&other, 1000, b"data").unwrap();
+        spool.retain(&[uuid()]).unwrap();
+        assert_eq!(spool.entries(&uuid()).unwrap().len(), 1);
+        assert!(spool.entries(&other).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,502 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Experimental QUIC transport for links where the handshakes of TCP and TLS dominate the latency,
+//! eg. lossy mobile or satellite links. The payloads are the ones of the TCP transports, but they
+//! are not framed as HTTP/3 requests:
+//!
+//! * Pull: the site connects to the pull port via UDP, offering the application protocol
+//!   `cmk-agent-pull`. Like for pulls via TLS, it requests the connection via SNI (the UUID) and
+//!   authenticates with its client certificate. The controller sends the data of a pull via TLS
+//!   (protocol version, compression and agent output) on a unidirectional stream.
+//! * Push: the controller connects to the receiver port via UDP, offering the application protocol
+//!   `cmk-agent-push` and authenticating with the certificate of the connection. On a bidirectional
+//!   stream, it sends the header line `{"uuid": .., "compression": .., "collected_at": ..}`
+//!   followed by the compressed agent output. With signed pushes, the header line also has the
+//!   `"signature"` of the agent output, like the header of pushes via HTTPS. The receiver answers with
+//!   `{"status": <HTTP status>, "detail": ..}`.
+//!
+//! Whether a receiver supports QUIC is found out per connection: if the QUIC handshake fails, the
+//! controller pushes via HTTPS and tries QUIC again for this connection after an hour.
+
+use crate::agent_receiver_api::{
+    self, AgentData, AgentDataChunk, AgentDataDelta, ChunkedUpload, RegistrationStatusV2,
+    RegistrationStatusV2Response,
+};
+use crate::connection_stats::ConnectionStats;
+use crate::misc::anyhow_error_to_human_readable;
+use crate::modes::pull::{self, AgentOutputCollector};
+use crate::{certs, config, constants, happy_eyeballs, misc, tls_server, transport};
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+const ALPN_PULL: &[u8] = b"cmk-agent-pull";
+const ALPN_PUSH: &[u8] = b"cmk-agent-push";
+const REFRESH_INTERVAL: u64 = 60;
+const MAX_PUSH_RESPONSE_SIZE: usize = 65536;
+
+fn pull_server_config(tls: &tls_server::PullTls) -> quinn::ServerConfig {
+    let mut crypto = (*tls.server_config).clone();
+    crypto.alpn_protocols = vec![ALPN_PULL.to_vec()];
+    quinn::ServerConfig::with_crypto(Arc::new(crypto))
+}
+
+fn pull_endpoint(tls: &tls_server::PullTls, port: u16) -> AnyhowResult<quinn::Endpoint> {
+    let err_v6 = match quinn::Endpoint::server(
+        pull_server_config(tls),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port),
+    ) {
+        Ok(endpoint) => return Ok(endpoint),
+        Err(err_v6) => err_v6,
+    };
+    quinn::Endpoint::server(
+        pull_server_config(tls),
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+    )
+    .context(format!(
+        "Failed to listen on UDP socket for incoming pull connections via QUIC (IPv6: {err_v6})"
+    ))
+}
+
+/// The connections pulled via QUIC, all of them with the setting "quic", otherwise the ones
+/// registered with the transport QUIC
+fn quic_pull_connections(
+    registry: &config::Registry,
+    now: u64,
+    all_connections: bool,
+) -> impl Iterator<Item = &config::TrustedConnection> {
+    registry
+        .get_active_pull_connections(now)
+        .filter(move |connection| {
+            all_connections || connection.transport == transport::TransportKind::Quic
+        })
+}
+
+/// Serve pull requests via QUIC on the pull port
+pub async fn serve_pulls(
+    mut registry: config::Registry,
+    all_connections: bool,
+    port: u16,
+    allowed_ip: Vec<String>,
+    connection_timeout: u64,
+    agent_output_collector: impl AgentOutputCollector,
+    connection_stats: ConnectionStats,
+) -> AnyhowResult<()> {
+    let mut expired = registry.count_expired(misc::unix_now());
+    let mut tls = Arc::new(
+        tls_server::pull_tls(quic_pull_connections(
+            &registry,
+            misc::unix_now(),
+            all_connections,
+        ))
+        .context("Could not initialize TLS.")?,
+    );
+    let endpoint = pull_endpoint(&tls, port)?;
+    info!(
+        "Listening on {} for incoming pull connections via QUIC",
+        endpoint.local_addr()?
+    );
+    loop {
+        let accepted = timeout(Duration::from_secs(REFRESH_INTERVAL), endpoint.accept()).await;
+        let changed = registry.refresh()?;
+        let now = misc::unix_now();
+        // Expired connections are no longer served, even if the registry did not change
+        let now_expired = registry.count_expired(now);
+        if changed || now_expired != expired {
+            expired = now_expired;
+            tls = Arc::new(
+                tls_server::pull_tls(quic_pull_connections(&registry, now, all_connections))
+                    .context("Could not initialize TLS.")?,
+            );
+            endpoint.set_server_config(Some(pull_server_config(&tls)));
+        }
+        let Ok(connecting) = accepted else {
+            continue;
+        };
+        let connecting = connecting.context("QUIC endpoint was closed")?;
+        let remote = connecting.remote_address();
+        if !pull::is_addr_allowed(&remote, &allowed_ip) {
+            warn!(
+                "{}: Rejecting pull request via QUIC - connection from IP is not allowed.",
+                remote
+            );
+            continue;
+        }
+        info!("{}: Handling pull request via QUIC.", remote);
+        let request = handle_pull(
+            connecting,
+            agent_output_collector.clone(),
+            Arc::clone(&tls),
+            connection_stats.clone(),
+            connection_timeout,
+        );
+        tokio::spawn(async move {
+            if let Err(err) = request.await {
+                warn!("{}: Request via QUIC failed. ({})", remote, err)
+            }
+        });
+    }
+}
+
+async fn handle_pull(
+    connecting: quinn::Connecting,
+    agent_output_collector: impl AgentOutputCollector,
+    tls: Arc<tls_server::PullTls>,
+    connection_stats: ConnectionStats,
+    connection_timeout: u64,
+) -> AnyhowResult<()> {
+    let remote_ip = connecting.remote_address().ip();
+    let connection = timeout(Duration::from_secs(connection_timeout), connecting)
+        .await
+        .context("QUIC handshake timed out")?
+        .context("QUIC handshake failed")?;
+    let server_name = connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|data| data.server_name);
+    let peer_certificates = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok());
+    let uuid = match tls.authorizer.authorize(
+        server_name.as_deref(),
+        peer_certificates.as_deref().map(Vec::as_slice),
+    ) {
+        Ok(uuid) => {
+            info!(
+                "{}: Authorized pull request via QUIC for connection {}.",
+                remote_ip, uuid
+            );
+            uuid
+        }
+        Err(err) => {
+            let reason = anyhow_error_to_human_readable(&err).replace('\n', ": ");
+            warn!(
+                "{}: Rejecting pull request via QUIC - {}",
+                remote_ip, reason
+            );
+            if let Some(uuid) = server_name.and_then(|name| uuid::Uuid::parse_str(&name).ok()) {
+                connection_stats.record_tls_failure(&uuid, remote_ip, &reason);
+            }
+            connection.close(1u32.into(), b"unauthorized");
+            return Err(err);
+        }
+    };
+    // The requested connection is only known after the handshake, unlike with TLS over TCP
+    let mon_data = agent_output_collector
+        .encoded_output(remote_ip, tls.data_source(&uuid))
+        .await?;
+    let bytes = mon_data.len();
+    timeout(Duration::from_secs(connection_timeout), async {
+        let mut stream = connection.open_uni().await?;
+        stream.write_all(&mon_data).await?;
+        stream.finish().await?;
+        AnyhowResult::<()>::Ok(())
+    })
+    .await
+    .context("Sending agent output via QUIC timed out")??;
+    connection_stats.record_pull(&uuid, remote_ip, bytes);
+    Ok(())
+}
+
+/// Connections whose receivers did not accept QUIC, and when this was noticed
+#[derive(Default)]
+struct TransportNegotiation {
+    rejected: Mutex<HashMap<uuid::Uuid, Instant>>,
+}
+
+impl TransportNegotiation {
+    fn rejected(&self) -> std::sync::MutexGuard<'_, HashMap<uuid::Uuid, Instant>> {
+        self.rejected
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn use_quic(&self, uuid: &uuid::Uuid, now: Instant) -> bool {
+        match self.rejected().get(uuid) {
+            Some(rejected) => {
+                now.duration_since(*rejected) >= Duration::from_secs(constants::QUIC_RETRY_INTERVAL)
+            }
+            None => true,
+        }
+    }
+
+    fn reject(&self, uuid: &uuid::Uuid, now: Instant) {
+        self.rejected().insert(*uuid, now);
+    }
+}
+
+/// The receiver API used for pushing. With QUIC enabled, agent data goes via QUIC to the receivers
+/// which support it, everything else via HTTPS.
+pub struct PushApi {
+    https: agent_receiver_api::Api,
+    quic: Option<TransportNegotiation>,
+}
+
+impl PushApi {
+    pub fn new(https: agent_receiver_api::Api, quic: bool) -> Self {
+        Self {
+            https,
+            quic: quic.then(TransportNegotiation::default),
+        }
+    }
+}
+
+impl AgentData for PushApi {
+    fn agent_data(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        compression_algorithm: &str,
+        monitoring_data: &[u8],
+        collected_at: u64,
+    ) -> AnyhowResult<()> {
+        if let Some(negotiation) = &self.quic {
+            if negotiation.use_quic(&connection.uuid, Instant::now()) {
+                // Pushes run on the blocking threads of the runtime
+                match tokio::runtime::Handle::current().block_on(push(
+                    base_url,
+                    connection,
+                    self.https.source_address(connection),
+                    self.https.ip_preference(),
+                    &PushHeader {
+                        uuid: connection.uuid.to_string(),
+                        compression: compression_algorithm,
+                        collected_at,
+                        signature: self.https.signature(
+                            connection,
+                            compression_algorithm,
+                            collected_at,
+                            monitoring_data,
+                        )?,
+                    },
+                    monitoring_data,
+                )) {
+                    Ok(response) => return response.into_result(),
+                    Err(err) => {
+                        info!(
+                            "{}: Pushing via QUIC failed, using HTTPS for the next {} seconds. ({:#})",
+                            base_url,
+                            constants::QUIC_RETRY_INTERVAL,
+                            err
+                        );
+                        negotiation.reject(&connection.uuid, Instant::now());
+                    }
+                }
+            }
+        }
+        self.https.agent_data(
+            base_url,
+            connection,
+            compression_algorithm,
+            monitoring_data,
+            collected_at,
+        )
+    }
+}
+
+/// Deltas are small anyway, they always go via HTTPS
+impl AgentDataDelta for PushApi {
+    fn agent_data_delta(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        compression_algorithm: &str,
+        delta: &[u8],
+        collected_at: u64,
+    ) -> AnyhowResult<()> {
+        self.https.agent_data_delta(
+            base_url,
+            connection,
+            compression_algorithm,
+            delta,
+            collected_at,
+        )
+    }
+}
+
+impl AgentDataChunk for PushApi {
+    fn agent_data_chunk(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        upload: &ChunkedUpload,
+        offset: u64,
+        chunk: &[u8],
+    ) -> AnyhowResult<u64> {
+        self.https
+            .agent_data_chunk(base_url, connection, upload, offset, chunk)
+    }
+}
+
+impl RegistrationStatusV2 for PushApi {
+    fn registration_status_v2(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+    ) -> AnyhowResult<RegistrationStatusV2Response> {
+        self.https.registration_status_v2(base_url, connection)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct PushHeader<'a> {
+    uuid: String,
+    compression: &'a str,
+    collected_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct PushResponse {
+    status: u16,
+    #[serde(default)]
+    detail: String,
+}
+
+impl PushResponse {
+    /// Like the answers to pushes via HTTPS
+    fn into_result(self) -> AnyhowResult<()> {
+        let status = reqwest::StatusCode::from_u16(self.status).context(format!(
+            "Invalid status {} from agent receiver",
+            self.status
+        ))?;
+        if status == reqwest::StatusCode::NO_CONTENT {
+            return Ok(());
+        }
+        if status == reqwest::StatusCode::BAD_REQUEST
+            && self.detail.contains("Unsupported compression algorithm")
+        {
+            return Err(agent_receiver_api::UnsupportedCompression(self.detail).into());
+        }
+        Err(agent_receiver_api::ResponseError::new(status, Some(self.detail)).into())
+    }
+}
+
+/// Push via QUIC. Errors mean the push did not reach the receiver, its answer is returned otherwise.
+async fn push(
+    base_url: &reqwest::Url,
+    connection: &config::TrustedConnection,
+    source_address: Option<IpAddr>,
+    ip_preference: happy_eyeballs::IpPreference,
+    header: &PushHeader<'_>,
+    monitoring_data: &[u8],
+) -> AnyhowResult<PushResponse> {
+    let host = base_url.host_str().context("Site URL has no host")?;
+    let port = base_url
+        .port_or_known_default()
+        .context("Site URL has no port")?;
+    // QUIC attempts are not raced, a failed one falls back to HTTPS anyway
+    let address = happy_eyeballs::resolve_async(host, port, ip_preference)
+        .await?
+        .into_iter()
+        .find(|address| {
+            source_address
+                .map(|source_address| source_address.is_ipv6() == address.is_ipv6())
+                .unwrap_or(true)
+        })
+        .ok_or_else(|| anyhow!("Could not resolve {}", host))?;
+    let endpoint = quinn::Endpoint::client(match (source_address, address) {
+        (Some(source_address), _) => SocketAddr::new(source_address, 0),
+        (None, SocketAddr::V4(_)) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        (None, SocketAddr::V6(_)) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    })?;
+    let client_config = quinn::ClientConfig::new(Arc::new(certs::quic_tls_config(
+        connection.tls_handshake_credentials()?,
+        ALPN_PUSH,
+    )?));
+    let quic_connection = timeout(
+        Duration::from_secs(constants::QUIC_CONNECT_TIMEOUT),
+        endpoint.connect_with(client_config, address, host)?,
+    )
+    .await
+    .context("QUIC handshake timed out")??;
+    debug!("{}: Pushing via QUIC", base_url);
+    let response = timeout(Duration::from_secs(constants::PUSH_TIMEOUT), async {
+        let (mut send, mut receive) = quic_connection.open_bi().await?;
+        let mut request = serde_json::to_vec(header)?;
+        request.push(b'\n');
+        send.write_all(&request).await?;
+        send.write_all(monitoring_data).await?;
+        send.finish().await?;
+        AnyhowResult::<Vec<u8>>::Ok(receive.read_to_end(MAX_PUSH_RESPONSE_SIZE).await?)
+    })
+    .await
+    .context("Push via QUIC timed out")??;
+    quic_connection.close(0u32.into(), b"");
+    serde_json::from_slice(&response).context("Invalid answer from agent receiver via QUIC")
+}
+
+#[cfg(test)]
+mod test_quic {
+    use super::*;
+
+    #[test]
+    fn test_transport_negotiation() {
+        let negotiation = TransportNegotiation::default();
+        let uuid = uuid::Uuid::new_v4();
+        let now = Instant::now();
+        assert!(negotiation.use_quic(&uuid, now));
+        negotiation.reject(&uuid, now);
+        assert!(!negotiation.use_quic(&uuid, now));
+        assert!(negotiation.use_quic(&uuid::Uuid::new_v4(), now));
+        assert!(negotiation.use_quic(
+            &uuid,
+            now + Duration::from_secs(constants::QUIC_RETRY_INTERVAL)
+        ));
+    }
+
+    fn response(status: u16, detail: &str) -> PushResponse {
+        PushResponse {
+            status,
+            detail: String::from(detail),
+        }
+    }
+
+    #[test]
+    fn test_push_response() {
+        assert!(response(204, "").into_result().is_ok());
+        let err = response(400, "Unsupported compression algorithm: zstd")
+            .into_result()
+            .unwrap_err();
+        assert!(err.is::<agent_receiver_api::UnsupportedCompression>());
+        let err = response(403, "Unknown connection")
+            .into_result()
+            .unwrap_err();
+        assert_eq!(
+            agent_receiver_api::response_status(&err),
+            Some(reqwest::StatusCode::FORBIDDEN)
+        );
+        assert!(response(1000, "").into_result().is_err());
+    }
+
+    #[test]
+    fn test_push_header() {
+        assert_eq!(
+            serde_json::to_string(&PushHeader {
+                uuid: String::from("99f56bbc-5965-4b34-bc70-1959ad1d32d6"),
+                compression: "zlib",
+                collected_at: 1700000000,
+                signature: None,
+            })
+            .unwrap(),
+            r#"{"uuid":"99f56bbc-5965-4b34-bc70-1959ad1d32d6","compression":"zlib","collected_at":1700000000}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&PushHeader {
+                uuid: String::from("99f56bbc-5965-4b34-bc70-1959ad1d32d6"),
+                compression: "zlib",
+                collected_at: 1700000000,
+                signature: Some(String::from("c2lnbmF0dXJl")),
+            })
+            .unwrap(),
+            r#"{"uuid":"99f56bbc-5965-4b34-bc70-1959ad1d32d6","compression":"zlib","collected_at":1700000000,"signature":"c2lnbmF0dXJl"}"#
+        );
+    }
+}
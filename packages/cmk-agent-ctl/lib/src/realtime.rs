@@ -0,0 +1,240 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Secondary channel pushing a few lightweight sections at sub-minute intervals, st. the site can
+//! draw near-real-time graphs without the whole agent running more often. The sections are read
+//! by the controller itself, the agent is not involved.
+
+use crate::agent_receiver_api::{self, RealtimeData};
+use crate::lifecycle::Lifecycle;
+use crate::{config, constants, misc, monitoring_data, site_spec};
+use anyhow::{Context, Result as AnyhowResult};
+use http::StatusCode;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RealtimeSection {
+    /// Load and number of CPUs
+    Cpu,
+    /// Memory usage
+    Mem,
+    /// CPU utilization and paging counters
+    Kernel,
+}
+
+impl RealtimeSection {
+    #[cfg(target_os = "linux")]
+    fn collect(self) -> AnyhowResult<String> {
+        let read =
+            |path: &str| std::fs::read_to_string(path).context(format!("Failed to read {path}"));
+        Ok(match self {
+            Self::Cpu => format!(
+                "<<<cpu>>>\n{}\nnum_cpus {}\n",
+                read("/proc/loadavg")?.trim_end(),
+                std::thread::available_parallelism().map_or(1, |cpus| cpus.get())
+            ),
+            Self::Mem => format!("<<<mem>>>\n{}", read("/proc/meminfo")?),
+            Self::Kernel => format!(
+                "<<<kernel>>>\n{}\n{}{}",
+                misc::unix_now(),
+                read("/proc/vmstat")?,
+                read("/proc/stat")?
+            ),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn collect(self) -> AnyhowResult<String> {
+        anyhow::bail!("Real-time sections are only supported on Linux")
+    }
+}
+
+fn collect(sections: &[RealtimeSection]) -> AnyhowResult<Vec<u8>> {
+    let mut mon_data = String::new();
+    for section in sections {
+        mon_data.push_str(&section.collect()?);
+    }
+    Ok(mon_data.into_bytes())
+}
+
+/// Push connections whose receiver does not accept real-time data, and since when
+type Unsupported = HashMap<uuid::Uuid, Instant>;
+
+pub async fn push(
+    mut registry: config::Registry,
+    config: config::RealtimeConfig,
+    client_config: config::ClientConfig,
+    lifecycle: Lifecycle,
+) -> AnyhowResult<()> {
+    // Fail early instead of once per interval
+    collect(&config.sections).context("Error collecting real-time sections")?;
+    info!(
+        "Pushing real-time sections {:?} every {} seconds",
+        config.sections,
+        config.interval.as_secs()
+    );
+    let api = Arc::new(agent_receiver_api::Api::new(&client_config));
+    let mut unsupported = Unsupported::new();
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = lifecycle.stopping() => return Ok(()),
+        }
+        if !lifecycle.proceed().await {
+            return Ok(());
+        }
+        registry.refresh()?;
+        let connections: Vec<_> = registry
+            .get_active_push_connections(misc::unix_now())
+            .map(|(site_id, connection)| (site_id.clone(), connection.clone()))
+            .collect();
+        if connections.is_empty() {
+            continue;
+        }
+        let (api, sections) = (Arc::clone(&api), config.sections.clone());
+        unsupported = tokio::task::spawn_blocking(move || {
+            push_cycle(api.as_ref(), &connections, &sections, unsupported)
+        })
+        .await?;
+    }
+}
+
+fn push_cycle(
+    api: &impl RealtimeData,
+    connections: &[(site_spec::SiteID, config::TrustedConnectionWithRemote)],
+    sections: &[RealtimeSection],
+    mut unsupported: Unsupported,
+) -> Unsupported {
+    let now = Instant::now();
+    unsupported.retain(|uuid, since| {
+        now.duration_since(*since) < Duration::from_secs(constants::REALTIME_RETRY_INTERVAL)
+            && connections
+                .iter()
+                .any(|(_, connection)| &connection.trust.uuid == uuid)
+    });
+    let collected_at = misc::unix_now();
+    let compressed =
+        match collect(sections).and_then(|mon_data| Ok(monitoring_data::compress(&mon_data)?)) {
+            Ok(compressed) => compressed,
+            Err(error) => {
+                warn!("Error collecting real-time sections. ({})", error);
+                return unsupported;
+            }
+        };
+    for (site_id, connection) in connections {
+        let uuid = connection.trust.uuid;
+        if unsupported.contains_key(&uuid) {
+            continue;
+        }
+        let result = site_spec::make_site_url(site_id, &connection.receiver_port)
+            .context("Failed to construct URL for pushing real-time data")
+            .and_then(|site_url| {
+                api.realtime_data(&site_url, &connection.trust, &compressed, collected_at)
+            });
+        match result {
+            Ok(()) => {}
+            Err(error)
+                if agent_receiver_api::response_status(&error) == Some(StatusCode::NOT_FOUND) =>
+            {
+                info!(
+                    "{}: Receiver does not accept real-time data, retrying in {} seconds",
+                    site_id,
+                    constants::REALTIME_RETRY_INTERVAL
+                );
+                unsupported.insert(uuid, now);
+            }
+            // Connection problems are reported by the regular push
+            Err(error) => debug!("{}: Error pushing real-time data. ({})", site_id, error),
+        }
+    }
+    unsupported
+}
+
+#[cfg(test)]
+mod test_realtime {
+    use super::*;
+    use agent_receiver_api::ResponseError;
+    use std::cell::RefCell;
+    use std::str::FromStr;
+
+    const UUID_OLD: &str = "0096abd7-83c9-42f8-8b3a-3ffba7ba959d";
+    const UUID_NEW: &str = "b3501e4d-2820-433c-8e9c-38c69ac20faa";
+
+    #[derive(Default)]
+    struct MockApi {
+        pushed: RefCell<Vec<uuid::Uuid>>,
+    }
+
+    impl RealtimeData for MockApi {
+        fn realtime_data(
+            &self,
+            _base_url: &reqwest::Url,
+            connection: &config::TrustedConnection,
+            _monitoring_data: &[u8],
+            _collected_at: u64,
+        ) -> AnyhowResult<()> {
+            self.pushed.borrow_mut().push(connection.uuid);
+            if connection.uuid.to_string() == UUID_OLD {
+                return Err(ResponseError::new(StatusCode::NOT_FOUND, None).into());
+            }
+            Ok(())
+        }
+    }
+
+    fn connections() -> Vec<(site_spec::SiteID, config::TrustedConnectionWithRemote)> {
+        [("server/old-site", UUID_OLD), ("server/new-site", UUID_NEW)]
+            .into_iter()
+            .map(|(site_id, uuid)| {
+                (
+                    site_spec::SiteID::from_str(site_id).unwrap(),
+                    config::TrustedConnectionWithRemote::from(uuid),
+                )
+            })
+            .collect()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_collect() {
+        let mon_data =
+            String::from_utf8(collect(&[RealtimeSection::Cpu, RealtimeSection::Mem]).unwrap())
+                .unwrap();
+        assert!(mon_data.starts_with("<<<cpu>>>\n"));
+        assert!(mon_data.contains("\nnum_cpus "));
+        assert!(mon_data.contains("<<<mem>>>\nMemTotal:"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_push_cycle_skips_unsupported() {
+        let api = MockApi::default();
+        let connections = connections();
+        let unsupported = push_cycle(&api, &connections, &[RealtimeSection::Cpu], HashMap::new());
+        assert_eq!(
+            unsupported.keys().collect::<Vec<_>>(),
+            [&uuid::Uuid::from_str(UUID_OLD).unwrap()]
+        );
+        let unsupported = push_cycle(&api, &connections, &[RealtimeSection::Cpu], unsupported);
+        assert_eq!(unsupported.len(), 1);
+        assert_eq!(
+            api.pushed.into_inner(),
+            [UUID_OLD, UUID_NEW, UUID_NEW].map(|uuid| uuid::Uuid::from_str(uuid).unwrap())
+        );
+        // Forgotten once the connection is gone
+        assert!(push_cycle(
+            &MockApi::default(),
+            &connections[1..],
+            &[RealtimeSection::Cpu],
+            unsupported
+        )
+        .is_empty());
+    }
+}
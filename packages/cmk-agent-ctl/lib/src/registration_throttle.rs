@@ -0,0 +1,187 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Client-side throttling of registrations whose credentials the receiver rejected. Automation
+//! with a wrong password baked in would otherwise retry at full speed and lock the account of
+//! the registration user. After a rejection, one immediate retry is allowed (eg. after a typo),
+//! afterwards the delays between attempts double up to a maximum.
+
+use crate::agent_receiver_api;
+use crate::config::{JSONLoader, JSONLoaderMissingSafe};
+use crate::{constants, site_spec};
+use anyhow::{bail, Result as AnyhowResult};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Consecutive rejected registrations at a site
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Rejections {
+    pub count: u32,
+    /// Unix timestamp of the last rejection
+    pub last: u64,
+}
+
+impl Rejections {
+    /// Seconds to wait after the last rejection before the next attempt
+    fn delay(&self) -> u64 {
+        if self.count < 2 {
+            return 0;
+        }
+        constants::REGISTRATION_BACKOFF_BASE
+            .saturating_mul(1 << (self.count - 2).min(16))
+            .min(constants::REGISTRATION_BACKOFF_MAX)
+    }
+}
+
+/// Rejected registrations per site (server/site)
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct RegistrationThrottle {
+    sites: BTreeMap<String, Rejections>,
+}
+
+impl JSONLoader for RegistrationThrottle {}
+impl JSONLoaderMissingSafe for RegistrationThrottle {}
+
+impl RegistrationThrottle {
+    /// Refuse to contact the site while the delay after the last rejections did not pass yet
+    pub fn check(&self, site_id: &site_spec::SiteID, now: u64) -> AnyhowResult<()> {
+        let Some(rejections) = self.sites.get(&site_id.to_string()) else {
+            return Ok(());
+        };
+        let next_attempt = rejections.last.saturating_add(rejections.delay());
+        if now < next_attempt {
+            bail!(
+                "The credentials were rejected by {} in the last {} attempts. To avoid locking \
+                 the account, the next attempt is possible in {}s. Please check username and \
+                 password.",
+                site_id,
+                rejections.count,
+                next_attempt - now
+            );
+        }
+        Ok(())
+    }
+
+    /// Count a registration which failed due to rejected credentials, a successful one starts
+    /// over. Returns whether anything changed.
+    pub fn record(
+        &mut self,
+        site_id: &site_spec::SiteID,
+        error: Option<&anyhow::Error>,
+        now: u64,
+    ) -> bool {
+        let key = site_id.to_string();
+        match error {
+            None => self.sites.remove(&key).is_some(),
+            Some(error) if is_rejected_credentials(error) => {
+                let rejections = self
+                    .sites
+                    .entry(key)
+                    .or_insert(Rejections { count: 0, last: 0 });
+                rejections.count = rejections.count.saturating_add(1);
+                rejections.last = now;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> AnyhowResult<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        #[cfg(unix)]
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        Ok(())
+    }
+}
+
+fn is_rejected_credentials(error: &anyhow::Error) -> bool {
+    matches!(
+        agent_receiver_api::response_status(error),
+        Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+    )
+}
+
+#[cfg(test)]
+mod test_registration_throttle {
+    use super::*;
+    use std::str::FromStr;
+
+    fn rejected(status: StatusCode) -> anyhow::Error {
+        anyhow::Error::from(agent_receiver_api::ResponseError::new(status, None))
+            .context("Error registering existing host")
+    }
+
+    #[test]
+    fn test_backoff() {
+        let site_id = site_spec::SiteID::from_str("server/site").unwrap();
+        let mut throttle = RegistrationThrottle::default();
+        assert!(throttle.check(&site_id, 1000).is_ok());
+
+        assert!(throttle.record(&site_id, Some(&rejected(StatusCode::UNAUTHORIZED)), 1000));
+        // One immediate retry, eg. after a typo
+        assert!(throttle.check(&site_id, 1000).is_ok());
+
+        assert!(throttle.record(&site_id, Some(&rejected(StatusCode::FORBIDDEN)), 1000));
+        let error = throttle.check(&site_id, 1000).unwrap_err().to_string();
+        assert!(error.contains("rejected by server/site in the last 2 attempts"));
+        assert!(error.contains(&format!("{}s", constants::REGISTRATION_BACKOFF_BASE)));
+        assert!(throttle
+            .check(&site_id, 1000 + constants::REGISTRATION_BACKOFF_BASE)
+            .is_ok());
+
+        throttle.record(&site_id, Some(&rejected(StatusCode::UNAUTHORIZED)), 1000);
+        assert!(throttle
+            .check(&site_id, 1000 + constants::REGISTRATION_BACKOFF_BASE)
+            .is_err());
+        assert!(throttle
+            .check(&site_id, 1000 + 2 * constants::REGISTRATION_BACKOFF_BASE)
+            .is_ok());
+
+        for _ in 0..40 {
+            throttle.record(&site_id, Some(&rejected(StatusCode::UNAUTHORIZED)), 1000);
+        }
+        assert!(throttle
+            .check(&site_id, 1000 + constants::REGISTRATION_BACKOFF_MAX)
+            .is_ok());
+
+        // Other sites are not affected
+        let other_site_id = site_spec::SiteID::from_str("server/other-site").unwrap();
+        assert!(throttle.check(&other_site_id, 1000).is_ok());
+    }
+
+    #[test]
+    fn test_record() {
+        let site_id = site_spec::SiteID::from_str("server/site").unwrap();
+        let mut throttle = RegistrationThrottle::default();
+        assert!(!throttle.record(&site_id, Some(&rejected(StatusCode::BAD_GATEWAY)), 1000));
+        assert!(!throttle.record(&site_id, Some(&anyhow::anyhow!("unreachable")), 1000));
+        assert!(!throttle.record(&site_id, None, 1000));
+        assert_eq!(throttle, RegistrationThrottle::default());
+
+        throttle.record(&site_id, Some(&rejected(StatusCode::UNAUTHORIZED)), 1000);
+        throttle.record(&site_id, Some(&rejected(StatusCode::UNAUTHORIZED)), 1000);
+        assert!(throttle.record(&site_id, None, 1010));
+        assert!(throttle.check(&site_id, 1010).is_ok());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(constants::REGISTRATION_THROTTLE_FILE);
+        assert_eq!(
+            RegistrationThrottle::load_missing_safe(&path).unwrap(),
+            RegistrationThrottle::default()
+        );
+        let site_id = site_spec::SiteID::from_str("server/site").unwrap();
+        let mut throttle = RegistrationThrottle::default();
+        throttle.record(&site_id, Some(&rejected(StatusCode::UNAUTHORIZED)), 1000);
+        throttle.save(&path).unwrap();
+        assert_eq!(RegistrationThrottle::load(&path).unwrap(), throttle);
+    }
+}
@@ -0,0 +1,515 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Relay for agents in networks the site cannot reach, eg. behind a DMZ. The relay accepts the
+//! pull requests of the site and forwards them to the agents it is paired with, st. only the
+//! relay has to be reachable from the site.
+//!
+//! Both sides are separate trust domains. Towards the site, the relay serves the connections
+//! registered for the hosts of the paired agents, just like the pull mode. Towards the agents, it
+//! acts as a site on its own: pairing issues a connection signed by the CA of the relay, which the
+//! agent imports, and the relay pulls with a client certificate of this CA.
+
+use crate::config::{JSONLoader, JSONLoaderMissingSafe};
+use crate::modes::pull::{self, with_timeout, TLS_ID};
+use crate::{certs, config, constants, fips, site_spec, tls_server};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::{ClientConfig, ServerName};
+use tokio_rustls::{client::TlsStream, TlsAcceptor, TlsConnector};
+
+const CA_NAME: &str = "Checkmk agent relay CA";
+/// Must not be a UUID, agents reject such client certificates
+const CLIENT_NAME: &str = "Checkmk agent relay";
+
+/// CA of the relay and the client identity it pulls the agents with
+#[derive(Serialize, Deserialize, Clone)]
+struct Identity {
+    root_cert: String,
+    root_key: String,
+    certificate: String,
+    private_key: String,
+}
+
+impl Identity {
+    fn new() -> AnyhowResult<Self> {
+        let (root_cert, root_key) = certs::make_ca(CA_NAME, constants::RELAY_CERT_VALIDITY_DAYS)?;
+        let (certificate, private_key) = certs::make_signed_cert(
+            &root_cert,
+            &root_key,
+            CLIENT_NAME,
+            constants::RELAY_CERT_VALIDITY_DAYS,
+        )?;
+        Ok(Self {
+            root_cert,
+            root_key,
+            certificate,
+            private_key,
+        })
+    }
+
+    fn client_config(&self) -> AnyhowResult<ClientConfig> {
+        Ok(fips::tls_defaults(ClientConfig::builder())?
+            .with_root_certificates(certs::root_cert_store(
+                [self.root_cert.as_str()].into_iter(),
+            )?)
+            .with_client_auth_cert(
+                vec![certs::rustls_certificate(&self.certificate)?],
+                certs::rustls_private_key(&self.private_key)?,
+            )?)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SiteConnection {
+    pub site_id: site_spec::SiteID,
+    pub connection: config::TrustedConnection,
+}
+
+#[serde_with::serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RelayedAgent {
+    pub server: String,
+    /// Pull port of the agent
+    pub port: u16,
+    /// Connection the agent imported when pairing, requested via SNI when pulling it
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub pairing_uuid: uuid::Uuid,
+    /// Connection registered with the site for the host of the agent
+    #[serde(default)]
+    pub site: Option<SiteConnection>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct RelayData {
+    #[serde(default)]
+    identity: Option<Identity>,
+    #[serde(default)]
+    agents: BTreeMap<String, RelayedAgent>,
+}
+
+impl JSONLoader for RelayData {}
+impl JSONLoaderMissingSafe for RelayData {}
+
+/// The paired agents of a relay, stored next to the connection registry
+pub struct Relay {
+    data: RelayData,
+    path: PathBuf,
+}
+
+impl Relay {
+    pub fn from_file(path: &Path) -> AnyhowResult<Self> {
+        Ok(Self {
+            data: RelayData::load_missing_safe(path)?,
+            path: PathBuf::from(path),
+        })
+    }
+
+    pub fn save(&self) -> AnyhowResult<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(&self.data)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        // Contains the key of the CA the agents trust
+        #[cfg(unix)]
+        fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600))?;
+        Ok(())
+    }
+
+    /// Pair with the agent at the given address. Returns the connection to import on its host.
+    /// A connection registered with the site for a previous pairing is kept.
+    pub fn pair(
+        &mut self,
+        name: &str,
+        address: &site_spec::ServerSpec,
+    ) -> AnyhowResult<config::TrustedConnection> {
+        let identity = match &self.data.identity {
+            Some(identity) => identity,
+            None => self
+                .data
+                .identity
+                .insert(Identity::new().context("Failed to create the CA of the relay")?),
+        };
+        let pairing_uuid = uuid::Uuid::new_v4();
+        let (certificate, private_key) = certs::make_signed_cert(
+            &identity.root_cert,
+            &identity.root_key,
+            &pairing_uuid.to_string(),
+            constants::RELAY_CERT_VALIDITY_DAYS,
+        )
+        .context("Failed to issue a certificate for the agent")?;
+        let connection = config::TrustedConnection {
+            uuid: pairing_uuid,
+            private_key,
+            certificate,
+            root_cert: identity.root_cert.clone(),
+            source_address: None,
+            receiver_protocol: Default::default(),
+            transport: Default::default(),
+            data_source: None,
+        };
+        let site = self
+            .data
+            .agents
+            .remove(name)
+            .and_then(|previous| previous.site);
+        self.data.agents.insert(
+            String::from(name),
+            RelayedAgent {
+                server: address.server.clone(),
+                port: address.port.unwrap_or(constants::DEFAULT_PULL_PORT),
+                pairing_uuid,
+                site,
+            },
+        );
+        Ok(connection)
+    }
+
+    pub fn agent(&self, name: &str) -> AnyhowResult<&RelayedAgent> {
+        self.data
+            .agents
+            .get(name)
+            .with_context(|| format!("No agent paired as '{name}'"))
+    }
+
+    pub fn register(
+        &mut self,
+        name: &str,
+        site_id: site_spec::SiteID,
+        connection: config::TrustedConnection,
+    ) -> AnyhowResult<()> {
+        let Some(agent) = self.data.agents.get_mut(name) else {
+            bail!("No agent paired as '{}'", name)
+        };
+        agent.site = Some(SiteConnection {
+            site_id,
+            connection,
+        });
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> AnyhowResult<RelayedAgent> {
+        self.data
+            .agents
+            .remove(name)
+            .with_context(|| format!("No agent paired as '{name}'"))
+    }
+
+    pub fn agents(&self) -> impl Iterator<Item = (&String, &RelayedAgent)> {
+        self.data.agents.iter()
+    }
+}
+
+/// Where to forward the pull requests of the site to, by the UUID of the site connection
+struct Routes {
+    site_tls: tls_server::PullTls,
+    agent_tls: Option<Arc<ClientConfig>>,
+    agents: HashMap<uuid::Uuid, RelayedAgent>,
+}
+
+impl Routes {
+    fn new(relay: &Relay) -> AnyhowResult<Self> {
+        let agents: HashMap<uuid::Uuid, RelayedAgent> = relay
+            .agents()
+            .filter_map(|(_, agent)| {
+                agent
+                    .site
+                    .as_ref()
+                    .map(|site| (site.connection.uuid, agent.clone()))
+            })
+            .collect();
+        Ok(Self {
+            site_tls: tls_server::pull_tls(
+                agents
+                    .values()
+                    .filter_map(|agent| agent.site.as_ref().map(|site| &site.connection)),
+            )
+            .context("Could not initialize TLS for the sites.")?,
+            agent_tls: relay
+                .data
+                .identity
+                .as_ref()
+                .map(|identity| identity.client_config().map(Arc::new))
+                .transpose()
+                .context("Could not initialize TLS for the agents.")?,
+            agents,
+        })
+    }
+}
+
+/// Routes of the relay file, reloaded whenever it was modified
+struct RelayState {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    routes: Arc<Routes>,
+}
+
+impl RelayState {
+    fn new(path: &Path) -> AnyhowResult<Self> {
+        Ok(Self {
+            path: PathBuf::from(path),
+            modified: Self::modified(path),
+            routes: Arc::new(Routes::new(&Relay::from_file(path)?)?),
+        })
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|meta| meta.modified()).ok()
+    }
+
+    fn refresh(&mut self) -> AnyhowResult<()> {
+        let modified = Self::modified(&self.path);
+        if modified != self.modified {
+            self.routes = Arc::new(Routes::new(&Relay::from_file(&self.path)?)?);
+            self.modified = modified;
+        }
+        Ok(())
+    }
+}
+
+pub async fn serve(relay_path: &Path, config: config::RelayConfig) -> AnyhowResult<()> {
+    let mut state = RelayState::new(relay_path)?;
+    let listener = TcpListener::from_std(pull::tcp_listener_any(config.port)?)?;
+    info!("Relaying pull requests from port {}", config.port);
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                warn!("Failed accepting pull connection. ({})", error);
+                continue;
+            }
+        };
+        if !pull::is_addr_allowed(&remote, &config.allowed_ip) {
+            warn!(
+                "{}: Rejecting pull request - connection from IP is not allowed.",
+                remote
+            );
+            continue;
+        }
+        // Pick up agents paired or registered meanwhile
+        if let Err(err) = state.refresh() {
+            warn!(
+                "Failed to reload {:?}, relaying with the previous agents. ({})",
+                relay_path, err
+            );
+        }
+        let routes = Arc::clone(&state.routes);
+        let connection_timeout = config.connection_timeout;
+        tokio::spawn(async move {
+            if let Err(err) = relay_request(stream, routes, connection_timeout).await {
+                warn!("{}: Relaying pull request failed. ({})", remote, err)
+            }
+        });
+    }
+}
+
+async fn relay_request(
+    mut stream: TcpStream,
+    routes: Arc<Routes>,
+    connection_timeout: u64,
+) -> AnyhowResult<()> {
+    let remote = stream.peer_addr()?;
+    let acceptor = TlsAcceptor::from(Arc::clone(&routes.site_tls.server_config));
+    let mut site_stream = with_timeout(
+        async move {
+            stream.write_all(TLS_ID).await?;
+            stream.flush().await?;
+            acceptor.accept(stream).await
+        },
+        connection_timeout,
+    )
+    .await?;
+    let (_, server_connection) = site_stream.get_ref();
+    let uuid = routes.site_tls.authorizer.authorize(
+        server_connection.server_name(),
+        server_connection.peer_certificates(),
+    )?;
+    let (Some(agent), Some(agent_tls)) = (routes.agents.get(&uuid), &routes.agent_tls) else {
+        bail!("No agent is paired for connection {}", uuid)
+    };
+    info!(
+        "{}: Relaying pull request for connection {} to {}:{}.",
+        remote, uuid, agent.server, agent.port
+    );
+
+    let mut agent_stream = with_timeout(
+        connect_agent(agent, Arc::clone(agent_tls)),
+        connection_timeout,
+    )
+    .await?;
+    // The agent output is collected while connecting and may take a while
+    let bytes = tokio::io::copy(&mut agent_stream, &mut site_stream)
+        .await
+        .context(format!(
+            "Failed to forward monitoring data from {}:{}",
+            agent.server, agent.port
+        ))?;
+    with_timeout(site_stream.shutdown(), connection_timeout).await?;
+    info!(
+        "{}: Relayed {} bytes via connection {}.",
+        remote, bytes, uuid
+    );
+    Ok(())
+}
+
+async fn connect_agent(
+    agent: &RelayedAgent,
+    tls: Arc<ClientConfig>,
+) -> std::io::Result<TlsStream<TcpStream>> {
+    let mut stream = TcpStream::connect((agent.server.as_str(), agent.port)).await?;
+    let mut id_buf: [u8; 2] = [0; 2];
+    stream.read_exact(&mut id_buf).await?;
+    if id_buf != TLS_ID {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Expected TLS announcement {:?} from agent, got {:?} - is legacy pull mode active?",
+                String::from_utf8_lossy(TLS_ID),
+                String::from_utf8_lossy(&id_buf)
+            ),
+        ));
+    }
+    let server_name = ServerName::try_from(agent.pairing_uuid.to_string().as_str())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    TlsConnector::from(tls).connect(server_name, stream).await
+}
+
+#[cfg(test)]
+mod test_relay {
+    use super::*;
+    use std::str::FromStr;
+
+    fn address() -> site_spec::ServerSpec {
+        site_spec::ServerSpec::from_str("inner.example.com").unwrap()
+    }
+
+    fn site_id() -> site_spec::SiteID {
+        site_spec::SiteID::from_str("server/site").unwrap()
+    }
+
+    fn relay(dir: &tempfile::TempDir) -> Relay {
+        Relay::from_file(&dir.path().join(constants::RELAY_FILE)).unwrap()
+    }
+
+    #[test]
+    fn test_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut relay = relay(&dir);
+        let connection = relay.pair("inner", &address()).unwrap();
+        let agent = relay.agent("inner").unwrap();
+        assert_eq!(agent.server, "inner.example.com");
+        assert_eq!(agent.port, constants::DEFAULT_PULL_PORT);
+        assert_eq!(agent.pairing_uuid, connection.uuid);
+        assert!(agent.site.is_none());
+
+        let identity = relay.data.identity.as_ref().unwrap();
+        assert_eq!(connection.root_cert, identity.root_cert);
+        // The agent serves the connection, and only trusts the relay to pull via it
+        let agent_tls = tls_server::pull_tls([&connection].into_iter()).unwrap();
+        assert!(agent_tls
+            .authorizer
+            .authorize(
+                Some(&connection.uuid.to_string()),
+                Some(&[certs::rustls_certificate(&identity.certificate).unwrap()]),
+            )
+            .is_ok());
+        assert!(identity.client_config().is_ok());
+    }
+
+    #[test]
+    fn test_pair_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut relay = relay(&dir);
+        let site_connection = relay.pair("site", &address()).unwrap();
+        let first = relay.pair("inner", &address()).unwrap();
+        relay
+            .register("inner", site_id(), site_connection.clone())
+            .unwrap();
+        let root_cert = relay.data.identity.as_ref().unwrap().root_cert.clone();
+
+        let second = relay
+            .pair(
+                "inner",
+                &site_spec::ServerSpec::from_str("10.0.0.1:6666").unwrap(),
+            )
+            .unwrap();
+        assert_ne!(first.uuid, second.uuid);
+        assert_eq!(second.root_cert, root_cert);
+        let agent = relay.agent("inner").unwrap();
+        assert_eq!(agent.port, 6666);
+        assert_eq!(agent.pairing_uuid, second.uuid);
+        assert_eq!(
+            agent.site.as_ref().unwrap().connection.uuid,
+            site_connection.uuid
+        );
+    }
+
+    #[test]
+    fn test_register_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut relay = relay(&dir);
+        let connection = relay.pair("inner", &address()).unwrap();
+        assert!(relay
+            .register("unknown", site_id(), connection.clone())
+            .is_err());
+        relay.register("inner", site_id(), connection).unwrap();
+        assert!(relay.remove("unknown").is_err());
+        assert!(relay.remove("inner").unwrap().site.is_some());
+        assert!(relay.agent("inner").is_err());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut relay = relay(&dir);
+        let connection = relay.pair("inner", &address()).unwrap();
+        relay.register("inner", site_id(), connection).unwrap();
+        relay.save().unwrap();
+        let loaded = Relay::from_file(&relay.path).unwrap();
+        assert_eq!(
+            loaded.agent("inner").unwrap(),
+            relay.agent("inner").unwrap()
+        );
+        assert_eq!(
+            loaded.data.identity.unwrap().root_key,
+            relay.data.identity.unwrap().root_key
+        );
+    }
+
+    #[test]
+    fn test_routes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut relay = relay(&dir);
+        assert!(Routes::new(&relay).unwrap().agent_tls.is_none());
+
+        // Any valid connection will do as the one registered with the site
+        let site_connection = Relay::from_file(&dir.path().join("site.json"))
+            .unwrap()
+            .pair("site", &address())
+            .unwrap();
+        relay.pair("inner", &address()).unwrap();
+        relay.pair("unregistered", &address()).unwrap();
+        relay
+            .register("inner", site_id(), site_connection.clone())
+            .unwrap();
+
+        let routes = Routes::new(&relay).unwrap();
+        assert!(routes.agent_tls.is_some());
+        assert_eq!(routes.agents.len(), 1);
+        assert_eq!(
+            routes.agents[&site_connection.uuid].pairing_uuid,
+            relay.agent("inner").unwrap().pairing_uuid
+        );
+    }
+}
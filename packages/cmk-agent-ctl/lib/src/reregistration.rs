@@ -0,0 +1,270 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Automatic re-registration of connections which their site rejects, eg. because the host was
+//! recreated in the site or its certificate was revoked. Otherwise, the host silently goes dark
+//! until someone notices and registers it again. Enabled with "auto_reregister" in the config,
+//! using the credentials stored by register --keep-credentials.
+
+use crate::agent_receiver_api::{self, RegistrationStatusV2, RegistrationStatusV2Response};
+use crate::config::{self, JSONLoader, JSONLoaderMissingSafe};
+use crate::error_code::ErrorCode;
+use crate::modes::registration;
+use crate::{constants, misc, site_spec, transport};
+use anyhow::Result as AnyhowResult;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// What is needed to register this host at a site again
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StoredCredentials {
+    pub username: String,
+    pub password: String,
+    pub host_name: String,
+}
+
+/// Stored credentials per site (server/site)
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct ReregistrationCredentials {
+    sites: BTreeMap<String, StoredCredentials>,
+}
+
+impl JSONLoader for ReregistrationCredentials {}
+impl JSONLoaderMissingSafe for ReregistrationCredentials {}
+
+impl ReregistrationCredentials {
+    pub fn get(&self, site_id: &site_spec::SiteID) -> Option<&StoredCredentials> {
+        self.sites.get(&site_id.to_string())
+    }
+
+    pub fn insert(&mut self, site_id: &site_spec::SiteID, credentials: StoredCredentials) {
+        self.sites.insert(site_id.to_string(), credentials);
+    }
+
+    pub fn save(&self, path: &Path) -> AnyhowResult<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        #[cfg(unix)]
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        Ok(())
+    }
+}
+
+/// Whether the answer to a status request means that the site does not accept the connection
+/// anymore. Network failures and the like are no reason to register again.
+fn is_rejected(status: &AnyhowResult<RegistrationStatusV2Response>) -> bool {
+    match status {
+        Ok(RegistrationStatusV2Response::NotRegistered) => true,
+        Ok(RegistrationStatusV2Response::Registered(_)) => false,
+        Err(err) => matches!(
+            ErrorCode::of(err),
+            ErrorCode::Unauthorized | ErrorCode::Forbidden | ErrorCode::TlsHandshake
+        ),
+    }
+}
+
+/// Sites rejecting their active connection, among the ones with stored credentials
+fn rejecting_sites(
+    registry: &config::Registry,
+    credentials: &ReregistrationCredentials,
+    status_api: &impl RegistrationStatusV2,
+    now: u64,
+) -> Vec<site_spec::SiteID> {
+    registry
+        .get_push_connections()
+        .chain(registry.get_standard_pull_connections())
+        .filter(|(site_id, connection)| {
+            credentials.get(site_id).is_some() && !connection.is_expired(now)
+        })
+        .filter_map(|(site_id, connection)| {
+            let status = site_spec::make_site_url(site_id, &connection.receiver_port)
+                .and_then(|url| status_api.registration_status_v2(&url, &connection.trust));
+            match &status {
+                Err(err) if is_rejected(&status) => {
+                    warn!("{} rejects the connection. ({:#})", site_id, err)
+                }
+                Ok(_) if is_rejected(&status) => {
+                    warn!("{} does not know the connection anymore.", site_id)
+                }
+                Err(err) => debug!(
+                    "Status of the connection to {} unknown. ({:#})",
+                    site_id, err
+                ),
+                Ok(_) => {}
+            }
+            is_rejected(&status).then(|| site_id.clone())
+        })
+        .collect()
+}
+
+fn reregister_rejected(
+    registry: &mut config::Registry,
+    client_config: &config::ClientConfig,
+) -> AnyhowResult<()> {
+    let credentials =
+        ReregistrationCredentials::load_missing_safe(&registry.reregistration_credentials_path())?;
+    // Via the transport of each connection, st. eg. mock connections are not registered again
+    let status_api =
+        transport::Transports::new(|| agent_receiver_api::Api::new(client_config), false);
+    for site_id in rejecting_sites(registry, &credentials, &status_api, misc::unix_now()) {
+        let Some(site_credentials) = credentials.get(&site_id) else {
+            continue;
+        };
+        match registration::reregister(&site_id, site_credentials, client_config, registry) {
+            Ok(registered) => info!(
+                "Registered again at {}, new UUID is {}.",
+                site_id, registered.uuid
+            ),
+            // The registration throttle prevents locking the account with outdated credentials
+            Err(err) => warn!("Failed to register again at {}. ({:#})", site_id, err),
+        }
+    }
+    Ok(())
+}
+
+pub async fn daemon(
+    mut registry: config::Registry,
+    client_config: config::ClientConfig,
+) -> AnyhowResult<()> {
+    misc::sleep_randomly().await;
+    loop {
+        debug!("Checking registered connections for rejections by their sites.");
+        registry.refresh()?;
+        let begin = Instant::now();
+        let client_config = client_config.clone();
+        // The receiver API is blocking, the registry is handed to the blocking task and back
+        let (checked_registry, result) = tokio::task::spawn_blocking(move || {
+            let result = reregister_rejected(&mut registry, &client_config);
+            (registry, result)
+        })
+        .await?;
+        registry = checked_registry;
+        if let Err(error) = result {
+            warn!("Error running re-registration cycle. ({:#})", error);
+        }
+        tokio::time::sleep(
+            Duration::from_secs(constants::REREGISTRATION_CHECK_INTERVAL)
+                .saturating_sub(begin.elapsed()),
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod test_reregistration {
+    use super::*;
+    use crate::agent_receiver_api::RegistrationStatusV2ResponseRegistered;
+    use config::test_helpers::TestRegistry;
+    use http::StatusCode;
+    use std::str::FromStr;
+
+    fn credentials() -> StoredCredentials {
+        StoredCredentials {
+            username: String::from("automation"),
+            password: String::from("secret"),
+            host_name: String::from("host"),
+        }
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(constants::REREGISTRATION_CREDENTIALS_FILE);
+        assert_eq!(
+            ReregistrationCredentials::load_missing_safe(&path).unwrap(),
+            ReregistrationCredentials::default()
+        );
+        let site_id = site_spec::SiteID::from_str("server/site").unwrap();
+        let mut stored = ReregistrationCredentials::default();
+        stored.insert(&site_id, credentials());
+        stored.save(&path).unwrap();
+        let loaded = ReregistrationCredentials::load(&path).unwrap();
+        assert_eq!(loaded.get(&site_id), Some(&credentials()));
+        #[cfg(unix)]
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+    }
+
+    #[test]
+    fn test_is_rejected() {
+        let response_error = |status| {
+            Err(anyhow::Error::from(agent_receiver_api::ResponseError::new(
+                status, None,
+            )))
+        };
+        assert!(is_rejected(&Ok(
+            RegistrationStatusV2Response::NotRegistered
+        )));
+        assert!(!is_rejected(&Ok(RegistrationStatusV2Response::Registered(
+            RegistrationStatusV2ResponseRegistered {
+                hostname: String::from("host"),
+                connection_mode: config::ConnectionMode::Push,
+            }
+        ))));
+        assert!(is_rejected(&response_error(StatusCode::UNAUTHORIZED)));
+        assert!(is_rejected(&response_error(StatusCode::FORBIDDEN)));
+        assert!(!is_rejected(&response_error(StatusCode::BAD_GATEWAY)));
+        assert!(!is_rejected(&Err(anyhow::anyhow!("unreachable"))));
+    }
+
+    struct MockStatusApi {}
+
+    impl RegistrationStatusV2 for MockStatusApi {
+        fn registration_status_v2(
+            &self,
+            base_url: &reqwest::Url,
+            _connection: &config::TrustedConnection,
+        ) -> AnyhowResult<RegistrationStatusV2Response> {
+            if base_url.as_str().contains("rejecting") {
+                return Ok(RegistrationStatusV2Response::NotRegistered);
+            }
+            Err(anyhow::anyhow!("unreachable"))
+        }
+    }
+
+    #[test]
+    fn test_rejecting_sites() {
+        let mut expired = config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4());
+        expired.expires = Some(1000);
+        let r = TestRegistry::new()
+            .add_connection(
+                &config::ConnectionMode::Push,
+                "server/rejecting",
+                config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4()),
+            )
+            .add_connection(
+                &config::ConnectionMode::Pull,
+                "server/rejecting-expired",
+                expired,
+            )
+            .add_connection(
+                &config::ConnectionMode::Pull,
+                "server/rejecting-without-credentials",
+                config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4()),
+            )
+            .add_connection(
+                &config::ConnectionMode::Pull,
+                "server/unreachable",
+                config::TrustedConnectionWithRemote::from(uuid::Uuid::new_v4()),
+            );
+        let mut stored = ReregistrationCredentials::default();
+        for site in ["rejecting", "rejecting-expired", "unreachable"] {
+            stored.insert(
+                &site_spec::SiteID::from_str(&format!("server/{site}")).unwrap(),
+                credentials(),
+            );
+        }
+        assert_eq!(
+            rejecting_sites(&r.registry, &stored, &MockStatusApi {}, 2000),
+            vec![site_spec::SiteID::from_str("server/rejecting").unwrap()]
+        );
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Cache for the responses of endpoints the daemon queries over and over again, eg. the
+//! registration status, which is asked for with every heartbeat and failover probe. Responses
+//! carrying an ETag or a Last-Modified header are kept per URL, and later requests are made
+//! conditional. If the receiver answers 304 (Not Modified), the kept body is used instead of
+//! transferring it again.
+
+use http::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use http::StatusCode;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+struct CachedResponse {
+    /// If-None-Match and If-Modified-Since, as derived from the response
+    validators: HeaderMap,
+    body: String,
+}
+
+#[derive(Default)]
+pub struct ResponseCache {
+    responses: Mutex<HashMap<reqwest::Url, CachedResponse>>,
+}
+
+impl ResponseCache {
+    fn responses(&self) -> MutexGuard<'_, HashMap<reqwest::Url, CachedResponse>> {
+        match self.responses.lock() {
+            Ok(responses) => responses,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Headers making the request conditional, empty if there is no response to revalidate
+    pub fn validators(&self, url: &reqwest::Url) -> HeaderMap {
+        self.responses()
+            .get(url)
+            .map(|cached| cached.validators.clone())
+            .unwrap_or_default()
+    }
+
+    /// The body to use for the answer of the receiver. Successful responses which can be
+    /// revalidated are kept, for 304 (Not Modified), the kept body is returned. Nothing for
+    /// other answers, which also drop the kept response.
+    pub fn resolve(
+        &self,
+        url: &reqwest::Url,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: String,
+    ) -> Option<String> {
+        let mut responses = self.responses();
+        match status {
+            StatusCode::NOT_MODIFIED => responses.get(url).map(|cached| cached.body.clone()),
+            StatusCode::OK => {
+                let mut validators = HeaderMap::new();
+                if let Some(etag) = headers.get(ETAG) {
+                    validators.insert(IF_NONE_MATCH, etag.clone());
+                }
+                if let Some(last_modified) = headers.get(LAST_MODIFIED) {
+                    validators.insert(IF_MODIFIED_SINCE, last_modified.clone());
+                }
+                if validators.is_empty() {
+                    responses.remove(url);
+                } else {
+                    responses.insert(
+                        url.clone(),
+                        CachedResponse {
+                            validators,
+                            body: body.clone(),
+                        },
+                    );
+                }
+                Some(body)
+            }
+            _ => {
+                responses.remove(url);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_response_cache {
+    use super::*;
+    use http::HeaderValue;
+
+    fn url() -> reqwest::Url {
+        reqwest::Url::parse("https://server:8000/site/agent-receiver/registration_status_v2/1")
+            .unwrap()
+    }
+
+    fn headers(headers: &[(http::header::HeaderName, &'static str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn test_revalidation() {
+        let cache = ResponseCache::default();
+        assert!(cache.validators(&url()).is_empty());
+        assert_eq!(
+            cache.resolve(
+                &url(),
+                StatusCode::OK,
+                &headers(&[
+                    (ETAG, "\"v1\""),
+                    (LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT")
+                ]),
+                String::from("body v1")
+            ),
+            Some(String::from("body v1"))
+        );
+        assert_eq!(
+            cache.validators(&url()),
+            headers(&[
+                (IF_NONE_MATCH, "\"v1\""),
+                (IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")
+            ])
+        );
+        assert_eq!(
+            cache.resolve(
+                &url(),
+                StatusCode::NOT_MODIFIED,
+                &HeaderMap::new(),
+                String::new()
+            ),
+            Some(String::from("body v1"))
+        );
+
+        cache.resolve(
+            &url(),
+            StatusCode::OK,
+            &headers(&[(ETAG, "\"v2\"")]),
+            String::from("body v2"),
+        );
+        assert_eq!(
+            cache.validators(&url()),
+            headers(&[(IF_NONE_MATCH, "\"v2\"")])
+        );
+        assert_eq!(
+            cache.resolve(
+                &url(),
+                StatusCode::NOT_MODIFIED,
+                &HeaderMap::new(),
+                String::new()
+            ),
+            Some(String::from("body v2"))
+        );
+    }
+
+    #[test]
+    fn test_not_kept() {
+        let cache = ResponseCache::default();
+        // Without validators, nothing can be revalidated
+        cache.resolve(
+            &url(),
+            StatusCode::OK,
+            &HeaderMap::new(),
+            String::from("body"),
+        );
+        assert!(cache.validators(&url()).is_empty());
+        assert_eq!(
+            cache.resolve(
+                &url(),
+                StatusCode::NOT_MODIFIED,
+                &HeaderMap::new(),
+                String::new()
+            ),
+            None
+        );
+
+        // Errors drop the kept response
+        cache.resolve(
+            &url(),
+            StatusCode::OK,
+            &headers(&[(ETAG, "\"v1\"")]),
+            String::from("body"),
+        );
+        assert_eq!(
+            cache.resolve(
+                &url(),
+                StatusCode::NOT_FOUND,
+                &HeaderMap::new(),
+                String::from("not found")
+            ),
+            None
+        );
+        assert!(cache.validators(&url()).is_empty());
+    }
+}
@@ -0,0 +1,488 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Client of the REST API of a Checkmk site, used to set up the host object before registering.
+
+use crate::agent_receiver_api::ResponseError;
+use crate::{config, proxy, types};
+use anyhow::{Context, Result as AnyhowResult};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq)]
+pub struct HostObject {
+    pub etag: String,
+    pub folder: String,
+    pub labels: types::AgentLabels,
+}
+
+#[derive(Deserialize)]
+struct HostResponse {
+    extensions: HostExtensions,
+}
+
+#[derive(Deserialize)]
+struct HostExtensions {
+    folder: String,
+    #[serde(default)]
+    attributes: HostAttributes,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct HostAttributes {
+    #[serde(default)]
+    labels: types::AgentLabels,
+}
+
+#[derive(Serialize)]
+struct CreateHostBody<'a> {
+    host_name: &'a str,
+    folder: &'a str,
+    attributes: HostAttributes,
+}
+
+#[derive(Serialize)]
+struct UpdateHostBody {
+    update_attributes: HostAttributes,
+}
+
+#[derive(Serialize)]
+struct MoveHostBody<'a> {
+    target_folder: &'a str,
+}
+
+pub trait HostManagement {
+    fn get_host(
+        &self,
+        base_url: &reqwest::Url,
+        credentials: &types::Credentials,
+        host_name: &str,
+    ) -> AnyhowResult<Option<HostObject>>;
+
+    fn create_host(
+        &self,
+        base_url: &reqwest::Url,
+        credentials: &types::Credentials,
+        host_name: &str,
+        folder: &str,
+        labels: &types::AgentLabels,
+    ) -> AnyhowResult<()>;
+
+    /// Replace the labels of the host, returns the new ETag of the host
+    fn update_labels(
+        &self,
+        base_url: &reqwest::Url,
+        credentials: &types::Credentials,
+        host_name: &str,
+        etag: &str,
+        labels: &types::AgentLabels,
+    ) -> AnyhowResult<String>;
+
+    fn move_host(
+        &self,
+        base_url: &reqwest::Url,
+        credentials: &types::Credentials,
+        host_name: &str,
+        etag: &str,
+        folder: &str,
+    ) -> AnyhowResult<()>;
+}
+
+pub struct Api {
+    client_config: config::ClientConfig,
+}
+
+impl Api {
+    pub fn new(client_config: &config::ClientConfig) -> Self {
+        Self {
+            client_config: client_config.clone(),
+        }
+    }
+
+    /// Same as for discovering the port of the agent receiver, the REST API is served by the web
+    /// server of the site, whose certificate is only validated if configured.
+    fn client(&self) -> reqwest::Result<reqwest::blocking::Client> {
+        proxy::configure(
+            reqwest::blocking::ClientBuilder::new()
+                .danger_accept_invalid_certs(!self.client_config.validate_api_cert)
+                .local_address(self.client_config.source_address),
+            self.client_config.proxy_mode,
+        )
+        .build()
+    }
+
+    fn endpoint_url(base_url: &reqwest::Url, segments: &[&str]) -> AnyhowResult<reqwest::Url> {
+        let mut url = base_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("REST API URL {} cannot be extended", base_url))?
+            .pop_if_empty()
+            .extend(segments);
+        Ok(url)
+    }
+
+    fn host_url(base_url: &reqwest::Url, host_name: &str) -> AnyhowResult<reqwest::Url> {
+        Self::endpoint_url(base_url, &["objects", "host_config", host_name])
+    }
+
+    fn authorization(credentials: &types::Credentials) -> String {
+        format!("Bearer {} {}", credentials.username, credentials.password)
+    }
+
+    fn check_response(
+        response: reqwest::blocking::Response,
+    ) -> AnyhowResult<reqwest::blocking::Response> {
+        let status = response.status();
+        if status != StatusCode::OK {
+            return Err(ResponseError::new(status, response.text().ok()).into());
+        }
+        Ok(response)
+    }
+
+    fn etag(response: &reqwest::blocking::Response) -> AnyhowResult<String> {
+        Ok(response
+            .headers()
+            .get(http::header::ETAG)
+            .context("Response of the REST API lacks the ETag of the host")?
+            .to_str()
+            .context("ETag of the host is not valid")?
+            .to_owned())
+    }
+}
+
+impl HostManagement for Api {
+    fn get_host(
+        &self,
+        base_url: &reqwest::Url,
+        credentials: &types::Credentials,
+        host_name: &str,
+    ) -> AnyhowResult<Option<HostObject>> {
+        let url = Self::host_url(base_url, host_name)?;
+        let response = self
+            .client()?
+            .get(url.clone())
+            .header(
+                http::header::AUTHORIZATION,
+                Self::authorization(credentials),
+            )
+            .send()
+            .context(format!("Failed to query host {host_name} from {url}"))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = Self::check_response(response)?;
+        let etag = Self::etag(&response)?;
+        let body = response.text().context("Failed to obtain response body")?;
+        let host: HostResponse = serde_json::from_str(&body)
+            .context(format!("Error parsing this response body: {body}"))?;
+        Ok(Some(HostObject {
+            etag,
+            folder: host.extensions.folder,
+            labels: host.extensions.attributes.labels,
+        }))
+    }
+
+    fn create_host(
+        &self,
+        base_url: &reqwest::Url,
+        credentials: &types::Credentials,
+        host_name: &str,
+        folder: &str,
+        labels: &types::AgentLabels,
+    ) -> AnyhowResult<()> {
+        let url = Self::endpoint_url(
+            base_url,
+            &["domain-types", "host_config", "collections", "all"],
+        )?;
+        Self::check_response(
+            self.client()?
+                .post(url.clone())
+                .header(
+                    http::header::AUTHORIZATION,
+                    Self::authorization(credentials),
+                )
+                .json(&CreateHostBody {
+                    host_name,
+                    folder,
+                    attributes: HostAttributes {
+                        labels: labels.clone(),
+                    },
+                })
+                .send()
+                .context(format!("Failed to create host {host_name} via {url}"))?,
+        )?;
+        Ok(())
+    }
+
+    fn update_labels(
+        &self,
+        base_url: &reqwest::Url,
+        credentials: &types::Credentials,
+        host_name: &str,
+        etag: &str,
+        labels: &types::AgentLabels,
+    ) -> AnyhowResult<String> {
+        let url = Self::host_url(base_url, host_name)?;
+        let response = Self::check_response(
+            self.client()?
+                .put(url.clone())
+                .header(
+                    http::header::AUTHORIZATION,
+                    Self::authorization(credentials),
+                )
+                .header(http::header::IF_MATCH, etag)
+                .json(&UpdateHostBody {
+                    update_attributes: HostAttributes {
+                        labels: labels.clone(),
+                    },
+                })
+                .send()
+                .context(format!("Failed to update host {host_name} via {url}"))?,
+        )?;
+        Self::etag(&response)
+    }
+
+    fn move_host(
+        &self,
+        base_url: &reqwest::Url,
+        credentials: &types::Credentials,
+        host_name: &str,
+        etag: &str,
+        folder: &str,
+    ) -> AnyhowResult<()> {
+        let url = Self::endpoint_url(
+            base_url,
+            &[
+                "objects",
+                "host_config",
+                host_name,
+                "actions",
+                "move",
+                "invoke",
+            ],
+        )?;
+        Self::check_response(
+            self.client()?
+                .post(url.clone())
+                .header(
+                    http::header::AUTHORIZATION,
+                    Self::authorization(credentials),
+                )
+                .header(http::header::IF_MATCH, etag)
+                .json(&MoveHostBody {
+                    target_folder: folder,
+                })
+                .send()
+                .context(format!("Failed to move host {host_name} via {url}"))?,
+        )?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HostChange {
+    Created,
+    Updated,
+    Unchanged,
+}
+
+/// The REST API accepts folders both as "/linux/web" and as "~linux~web"
+fn same_folder(left: &str, right: &str) -> bool {
+    let normalize = |folder: &str| folder.replace('~', "/").trim_matches('/').to_owned();
+    normalize(left) == normalize(right)
+}
+
+/// Create the host in the given folder (the main folder if unset), or, if it exists, move it to
+/// the folder and add the labels to its labels.
+pub fn set_up_host(
+    api: &impl HostManagement,
+    base_url: &reqwest::Url,
+    credentials: &types::Credentials,
+    host_name: &str,
+    folder: Option<&str>,
+    labels: &types::AgentLabels,
+) -> AnyhowResult<HostChange> {
+    let Some(host) = api.get_host(base_url, credentials, host_name)? else {
+        api.create_host(
+            base_url,
+            credentials,
+            host_name,
+            folder.unwrap_or("/"),
+            labels,
+        )?;
+        return Ok(HostChange::Created);
+    };
+
+    let mut change = HostChange::Unchanged;
+    let mut etag = host.etag;
+    let mut merged_labels = host.labels.clone();
+    merged_labels.extend(labels.clone());
+    if merged_labels != host.labels {
+        etag = api.update_labels(base_url, credentials, host_name, &etag, &merged_labels)?;
+        change = HostChange::Updated;
+    }
+    if let Some(folder) = folder.filter(|folder| !same_folder(folder, &host.folder)) {
+        api.move_host(base_url, credentials, host_name, &etag, folder)?;
+        change = HostChange::Updated;
+    }
+    Ok(change)
+}
+
+#[cfg(test)]
+mod test_set_up_host {
+    use super::*;
+    use std::cell::RefCell;
+
+    const HOST_NAME: &str = "host";
+
+    #[derive(Default)]
+    struct MockApi {
+        host: Option<HostObject>,
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl HostManagement for MockApi {
+        fn get_host(
+            &self,
+            _base_url: &reqwest::Url,
+            credentials: &types::Credentials,
+            host_name: &str,
+        ) -> AnyhowResult<Option<HostObject>> {
+            assert_eq!(credentials.username, "automation");
+            assert_eq!(host_name, HOST_NAME);
+            Ok(self.host.as_ref().map(|host| HostObject {
+                etag: host.etag.clone(),
+                folder: host.folder.clone(),
+                labels: host.labels.clone(),
+            }))
+        }
+
+        fn create_host(
+            &self,
+            _base_url: &reqwest::Url,
+            _credentials: &types::Credentials,
+            _host_name: &str,
+            folder: &str,
+            labels: &types::AgentLabels,
+        ) -> AnyhowResult<()> {
+            self.calls
+                .borrow_mut()
+                .push(format!("create {} {}", folder, labels.len()));
+            Ok(())
+        }
+
+        fn update_labels(
+            &self,
+            _base_url: &reqwest::Url,
+            _credentials: &types::Credentials,
+            _host_name: &str,
+            etag: &str,
+            labels: &types::AgentLabels,
+        ) -> AnyhowResult<String> {
+            self.calls
+                .borrow_mut()
+                .push(format!("update {} {}", etag, labels.len()));
+            Ok(String::from("etag-2"))
+        }
+
+        fn move_host(
+            &self,
+            _base_url: &reqwest::Url,
+            _credentials: &types::Credentials,
+            _host_name: &str,
+            etag: &str,
+            folder: &str,
+        ) -> AnyhowResult<()> {
+            self.calls
+                .borrow_mut()
+                .push(format!("move {} {}", etag, folder));
+            Ok(())
+        }
+    }
+
+    fn existing_host() -> MockApi {
+        MockApi {
+            host: Some(HostObject {
+                etag: String::from("etag-1"),
+                folder: String::from("/linux"),
+                labels: types::AgentLabels::from([(String::from("a"), String::from("1"))]),
+            }),
+            ..MockApi::default()
+        }
+    }
+
+    fn set_up(api: &MockApi, folder: Option<&str>, labels: &[(&str, &str)]) -> HostChange {
+        set_up_host(
+            api,
+            &reqwest::Url::parse("https://server/site/check_mk/api/1.0").unwrap(),
+            &types::Credentials {
+                username: String::from("automation"),
+                password: String::from("secret"),
+            },
+            HOST_NAME,
+            folder,
+            &labels
+                .iter()
+                .map(|(k, v)| (String::from(*k), String::from(*v)))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_create() {
+        let api = MockApi::default();
+        assert_eq!(set_up(&api, None, &[("a", "1")]), HostChange::Created);
+        assert_eq!(api.calls.into_inner(), vec!["create / 1"]);
+        let api = MockApi::default();
+        assert_eq!(set_up(&api, Some("/linux/web"), &[]), HostChange::Created);
+        assert_eq!(api.calls.into_inner(), vec!["create /linux/web 0"]);
+    }
+
+    #[test]
+    fn test_unchanged() {
+        let api = existing_host();
+        assert_eq!(
+            set_up(&api, Some("~linux"), &[("a", "1")]),
+            HostChange::Unchanged
+        );
+        assert_eq!(set_up(&api, None, &[]), HostChange::Unchanged);
+        assert!(api.calls.into_inner().is_empty());
+    }
+
+    #[test]
+    fn test_update() {
+        let api = existing_host();
+        assert_eq!(
+            set_up(&api, Some("/windows"), &[("b", "2")]),
+            HostChange::Updated
+        );
+        assert_eq!(
+            api.calls.into_inner(),
+            vec!["update etag-1 2", "move etag-2 /windows"]
+        );
+    }
+
+    #[test]
+    fn test_same_folder() {
+        assert!(same_folder("/", ""));
+        assert!(same_folder("/linux/web", "~linux~web"));
+        assert!(same_folder("/linux/", "linux"));
+        assert!(!same_folder("/linux", "/linux/web"));
+    }
+
+    #[test]
+    fn test_endpoint_url() {
+        for base_url in [
+            "https://server/site/check_mk/api/1.0",
+            "https://server/site/check_mk/api/1.0/",
+        ] {
+            assert_eq!(
+                Api::host_url(&reqwest::Url::parse(base_url).unwrap(), "my host")
+                    .unwrap()
+                    .as_str(),
+                "https://server/site/check_mk/api/1.0/objects/host_config/my%20host"
+            );
+        }
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Internal state of the schedulers of the daemon. The push and renewal tasks record what they
+//! are about to do, the status request of the daemon reports it, st. eg. a connection which did
+//! not push for a while can be explained without attaching a debugger.
+
+use crate::push_spool::PushSpool;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Push scheduling of a single connection
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ConnectionSchedule {
+    /// Unix timestamp at which the next push is due
+    pub next_push: u64,
+    /// Failed pushes in a row. Pushes do not back off, the next one is due after the push
+    /// interval regardless.
+    pub consecutive_failures: u32,
+    /// Agent outputs waiting in the push spool to be replayed
+    pub spool_depth: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct SchedulerReport {
+    /// Keyed by connection UUID
+    pub push: BTreeMap<String, ConnectionSchedule>,
+    /// Unix timestamp of the next check of the certificates for renewal
+    pub next_renewal_check: Option<u64>,
+}
+
+#[derive(Default)]
+struct Recorded {
+    next_push: HashMap<uuid::Uuid, u64>,
+    consecutive_failures: HashMap<uuid::Uuid, u32>,
+    next_renewal_check: Option<u64>,
+}
+
+/// Shared between the scheduling tasks and the IPC task
+#[derive(Clone, Default)]
+pub struct SchedulerState(Arc<Mutex<Recorded>>);
+
+impl SchedulerState {
+    fn recorded(&self) -> MutexGuard<'_, Recorded> {
+        match self.0.lock() {
+            Ok(recorded) => recorded,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Replaces the schedule, connections missing from it are no longer pushed to
+    pub fn record_push_schedule(&self, next_push: HashMap<uuid::Uuid, u64>) {
+        let mut recorded = self.recorded();
+        recorded
+            .consecutive_failures
+            .retain(|uuid, _| next_push.contains_key(uuid));
+        recorded.next_push = next_push;
+    }
+
+    pub fn record_push_outcome(&self, uuid: &uuid::Uuid, success: bool) {
+        let mut recorded = self.recorded();
+        if success {
+            recorded.consecutive_failures.remove(uuid);
+        } else {
+            *recorded.consecutive_failures.entry(*uuid).or_default() += 1;
+        }
+    }
+
+    pub fn record_renewal_check(&self, next_check: u64) {
+        self.recorded().next_renewal_check = Some(next_check);
+    }
+
+    /// The spool depths are determined at the time of the report
+    pub fn report(&self, push_spool: &PushSpool) -> SchedulerReport {
+        let recorded = self.recorded();
+        SchedulerReport {
+            push: recorded
+                .next_push
+                .iter()
+                .map(|(uuid, next_push)| {
+                    (
+                        uuid.to_string(),
+                        ConnectionSchedule {
+                            next_push: *next_push,
+                            consecutive_failures: recorded
+                                .consecutive_failures
+                                .get(uuid)
+                                .copied()
+                                .unwrap_or_default(),
+                            spool_depth: push_spool
+                                .entries(uuid)
+                                .map_or(0, |entries| entries.len()),
+                        },
+                    )
+                })
+                .collect(),
+            next_renewal_check: recorded.next_renewal_check,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_scheduler_state {
+    use super::*;
+
+    const UUID: &str = "99f56bbc-5965-4b34-bc70-1959ad1d32d6";
+
+    #[test]
+    fn test_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let push_spool = PushSpool::new(dir.path(), 10, None);
+        let uuid = uuid::Uuid::parse_str(UUID).unwrap();
+        push_spool.spool(&uuid, 1000, b"data").unwrap();
+        push_spool.spool(&uuid, 1060, b"data").unwrap();
+
+        let state = SchedulerState::default();
+        assert_eq!(state.report(&push_spool), SchedulerReport::default());
+
+        state.record_push_schedule(HashMap::from([(uuid, 1120)]));
+        state.record_push_outcome(&uuid, false);
+        state.record_push_outcome(&uuid, false);
+        state.record_renewal_check(5000);
+        assert_eq!(
+            state.report(&push_spool),
+            SchedulerReport {
+                push: BTreeMap::from([(
+                    String::from(UUID),
+                    ConnectionSchedule {
+                        next_push: 1120,
+                        consecutive_failures: 2,
+                        spool_depth: 2,
+                    }
+                )]),
+                next_renewal_check: Some(5000),
+            }
+        );
+
+        state.record_push_outcome(&uuid, true);
+        assert_eq!(state.report(&push_spool).push[UUID].consecutive_failures, 0);
+
+        // Deleted connections are forgotten
+        state.record_push_outcome(&uuid, false);
+        state.record_push_schedule(HashMap::new());
+        state.record_push_schedule(HashMap::from([(uuid, 1180)]));
+        assert_eq!(state.report(&push_spool).push[UUID].consecutive_failures, 0);
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Notifications to systemd about the state of the daemon, see sd_notify(3). Without
+//! NOTIFY_SOCKET in the environment, i.e. when not running as systemd service of type notify,
+//! nothing is sent.
+
+use super::constants::sd_notify::{ENV_NOTIFY_SOCKET, ENV_WATCHDOG_PID, ENV_WATCHDOG_USEC};
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// Send the given state, eg. "READY=1", to the service manager. Returns whether it was sent.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match std::env::var_os(ENV_NOTIFY_SOCKET) {
+        Some(socket) => notify_to(&socket.to_string_lossy(), state).map(|_| true),
+        None => Ok(false),
+    }
+}
+
+fn notify_to(socket: &str, state: &str) -> io::Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        // Socket in the abstract namespace
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &address)?;
+        }
+        _ => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+/// Interval in which systemd expects keep-alives, if the watchdog is enabled for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_from(
+        std::env::var(ENV_WATCHDOG_USEC).ok().as_deref(),
+        std::env::var(ENV_WATCHDOG_PID).ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+fn watchdog_interval_from(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    // Without WATCHDOG_PID, the watchdog is meant for us
+    if pid.is_some_and(|pid| pid.parse::<u32>().ok() != Some(own_pid)) {
+        return None;
+    }
+    match usec?.parse::<u64>() {
+        Ok(usec) if usec > 0 => Some(Duration::from_micros(usec)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test_sd_notify {
+    use super::*;
+
+    #[test]
+    fn test_notify_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        notify_to(path.to_str().unwrap(), "READY=1\nSTATUS=Up").unwrap();
+        let mut buffer = [0; 64];
+        let size = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"READY=1\nSTATUS=Up");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_notify_to_abstract() {
+        use std::os::linux::net::SocketAddrExt;
+        let name = format!("cmk-agent-ctl-test-{}", std::process::id());
+        let receiver = UnixDatagram::bind_addr(
+            &std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap(),
+        )
+        .unwrap();
+        notify_to(&format!("@{name}"), "WATCHDOG=1").unwrap();
+        let mut buffer = [0; 64];
+        let size = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"WATCHDOG=1");
+    }
+
+    #[test]
+    fn test_notify_to_missing_socket() {
+        assert!(notify_to("/nonexistent/notify.sock", "READY=1").is_err());
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(
+            watchdog_interval_from(Some("30000000"), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_interval_from(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_interval_from(Some("30000000"), Some("43"), 42),
+            None
+        );
+        assert_eq!(watchdog_interval_from(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval_from(Some("soon"), None, 42), None);
+        assert_eq!(watchdog_interval_from(None, None, 42), None);
+    }
+}
@@ -0,0 +1,191 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Stripping and truncating sections of the agent output before it is dumped, pulled or pushed,
+//! st. sensitive or enormous sections never leave the host.
+
+use crate::config;
+use crate::monitoring_data::section_header;
+
+/// Needed by the site to process the agent output at all
+const ALWAYS_KEPT_SECTION: &[u8] = b"check_mk";
+
+/// Section names match exactly, or by prefix if the pattern ends with "*"
+pub fn matches(pattern: &str, section: &[u8]) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => section.starts_with(prefix.as_bytes()),
+        None => pattern.as_bytes() == section,
+    }
+}
+
+fn is_kept(section: &[u8], config: &config::SectionFilterConfig) -> bool {
+    if section == ALWAYS_KEPT_SECTION {
+        return true;
+    }
+    (config.included_sections.is_empty()
+        || config
+            .included_sections
+            .iter()
+            .any(|pattern| matches(pattern, section)))
+        && !config
+            .excluded_sections
+            .iter()
+            .any(|pattern| matches(pattern, section))
+}
+
+/// An exact entry for the section takes precedence over patterns and the general limit
+fn max_size(section: &[u8], config: &config::SectionFilterConfig) -> Option<usize> {
+    let section_name = String::from_utf8_lossy(section);
+    config
+        .max_section_sizes
+        .get(section_name.as_ref())
+        .or_else(|| {
+            config
+                .max_section_sizes
+                .iter()
+                .filter(|(pattern, _)| pattern.ends_with('*') && matches(pattern, section))
+                .map(|(_, max_size)| max_size)
+                .min()
+        })
+        .copied()
+        .or(config.max_section_size)
+}
+
+fn truncation_marker(max_size: usize) -> Vec<u8> {
+    format!("[truncated by cmk-agent-ctl, section exceeds {max_size} bytes]\n").into_bytes()
+}
+
+struct Section {
+    kept: bool,
+    max_size: Option<usize>,
+    size: usize,
+    truncated: bool,
+}
+
+impl Section {
+    /// Lines before the first section header and piggyback headers
+    fn unrestricted() -> Self {
+        Self {
+            kept: true,
+            max_size: None,
+            size: 0,
+            truncated: false,
+        }
+    }
+}
+
+/// The size of a section is that of its lines without the header. Truncated sections keep the
+/// complete lines fitting in, followed by a marker line.
+pub fn filter(mon_data: Vec<u8>, config: &config::SectionFilterConfig) -> Vec<u8> {
+    if config.is_empty() {
+        return mon_data;
+    }
+    let mut filtered = Vec::with_capacity(mon_data.len());
+    let mut section = Section::unrestricted();
+    for line in mon_data.split_inclusive(|byte| *byte == b'\n') {
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        if let Some(name) = section_header(content) {
+            section = Section {
+                kept: is_kept(name, config),
+                max_size: max_size(name, config),
+                size: 0,
+                truncated: false,
+            };
+        } else if content.starts_with(b"<<<<") {
+            section = Section::unrestricted();
+        } else if section.kept && !section.truncated {
+            if let Some(max_size) = section.max_size {
+                if section.size + line.len() > max_size {
+                    section.truncated = true;
+                    filtered.extend(truncation_marker(max_size));
+                    continue;
+                }
+            }
+            section.size += line.len();
+        }
+        if section.kept && !section.truncated {
+            filtered.extend_from_slice(line);
+        }
+    }
+    filtered
+}
+
+#[cfg(test)]
+mod test_section_filter {
+    use super::*;
+    use std::collections::HashMap;
+
+    const OUTPUT: &[u8] = b"<<<check_mk>>>\nVersion: 2.3.0\n\
+        <<<mk_inventory>>>\nsecret\n\
+        <<<ps:sep(0)>>>\n1 init\n2 sshd\n3 bash\n\
+        <<<<piggy>>>>\n<<<ps>>>\n4 java\n<<<<>>>>\n\
+        <<<uptime>>>\n1234\n";
+
+    fn filter_str(config: &config::SectionFilterConfig) -> String {
+        String::from_utf8(filter(OUTPUT.to_vec(), config)).unwrap()
+    }
+
+    #[test]
+    fn test_no_filter() {
+        assert_eq!(
+            filter(OUTPUT.to_vec(), &config::SectionFilterConfig::default()),
+            OUTPUT
+        );
+    }
+
+    #[test]
+    fn test_exclude() {
+        assert_eq!(
+            filter_str(&config::SectionFilterConfig {
+                excluded_sections: vec![String::from("mk_*"), String::from("ps")],
+                ..config::SectionFilterConfig::default()
+            }),
+            "<<<check_mk>>>\nVersion: 2.3.0\n<<<<piggy>>>>\n<<<<>>>>\n<<<uptime>>>\n1234\n"
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        assert_eq!(
+            filter_str(&config::SectionFilterConfig {
+                included_sections: vec![String::from("uptime")],
+                ..config::SectionFilterConfig::default()
+            }),
+            "<<<check_mk>>>\nVersion: 2.3.0\n<<<<piggy>>>>\n<<<<>>>>\n<<<uptime>>>\n1234\n"
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(
+            filter_str(&config::SectionFilterConfig {
+                max_section_size: Some(100),
+                max_section_sizes: HashMap::from([(String::from("ps"), 15)]),
+                ..config::SectionFilterConfig::default()
+            }),
+            "<<<check_mk>>>\nVersion: 2.3.0\n\
+             <<<mk_inventory>>>\nsecret\n\
+             <<<ps:sep(0)>>>\n1 init\n2 sshd\n\
+             [truncated by cmk-agent-ctl, section exceeds 15 bytes]\n\
+             <<<<piggy>>>>\n<<<ps>>>\n4 java\n<<<<>>>>\n\
+             <<<uptime>>>\n1234\n"
+        );
+    }
+
+    #[test]
+    fn test_max_size() {
+        let config = config::SectionFilterConfig {
+            max_section_size: Some(100),
+            max_section_sizes: HashMap::from([
+                (String::from("mk_*"), 10),
+                (String::from("mk_inventory"), 20),
+            ]),
+            ..config::SectionFilterConfig::default()
+        };
+        assert_eq!(max_size(b"mk_inventory", &config), Some(20));
+        assert_eq!(max_size(b"mk_docker", &config), Some(10));
+        assert_eq!(max_size(b"df", &config), Some(100));
+    }
+}
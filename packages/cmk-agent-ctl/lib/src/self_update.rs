@@ -0,0 +1,316 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Replacing the controller binary with a newer one the site offers, for hosts without a
+//! package manager. The site signs a manifest of the version, platform and hash of the binary
+//! with the pinned key, the binary has to match it. It is checked before and after taking the
+//! place of the running one, a failing check restores the previous binary, which is kept next
+//! to it.
+
+use anyhow::{bail, Context, Result as AnyhowResult};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Verifier;
+use serde::Deserialize;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+/// Platform the site has to provide the binary for, eg. "linux-x86_64"
+pub fn platform() -> String {
+    format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH)
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Stage {
+    Innovation,
+    Beta,
+    Stable,
+    Patch,
+}
+
+/// Checkmk version, eg. 2.3.0i1, 2.3.0b1, 2.3.0 or 2.3.0p12, ordered in this sequence
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct Version {
+    release: [u64; 3],
+    stage: Stage,
+    number: u64,
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> AnyhowResult<Self> {
+        let invalid = || anyhow::anyhow!("Invalid controller version '{}'", s);
+        let (release, suffix) = s.split_at(s.find(char::is_alphabetic).unwrap_or(s.len()));
+        let release: Vec<u64> = release
+            .split('.')
+            .map(|part| part.parse::<u64>().map_err(|_| invalid()))
+            .collect::<AnyhowResult<_>>()?;
+        let (stage, number) = match suffix.split_at(suffix.len().min(1)) {
+            ("", "") => (Stage::Stable, "0"),
+            ("i", number) => (Stage::Innovation, number),
+            ("b", number) => (Stage::Beta, number),
+            ("p", number) => (Stage::Patch, number),
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            release: release.try_into().map_err(|_| invalid())?,
+            stage,
+            number: number.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Whether the offered version is strictly newer than the running one. Going back to an
+/// older version is not an update, it could reintroduce fixed vulnerabilities.
+pub fn is_newer(offered: &str, running: &str) -> AnyhowResult<bool> {
+    Ok(Version::from_str(offered)? > Version::from_str(running)?)
+}
+
+/// What the site signs, st. a signed binary cannot be offered as another version or for
+/// another platform
+#[derive(Deserialize, Debug)]
+pub struct Manifest {
+    pub version: String,
+    pub platform: String,
+    /// Hex encoded SHA-256 hash of the binary
+    pub sha256: String,
+}
+
+impl Manifest {
+    /// Check that the manifest is for the binary, offered as the given version for this platform
+    pub fn check(&self, version: &str, binary: &[u8]) -> AnyhowResult<()> {
+        if self.version != version {
+            bail!(
+                "Controller is offered as version {}, but signed as {}",
+                version,
+                self.version
+            )
+        }
+        if self.platform != platform() {
+            bail!(
+                "Controller binary is for {}, not for {}",
+                self.platform,
+                platform()
+            )
+        }
+        if !self.sha256.eq_ignore_ascii_case(&sha256(binary)) {
+            bail!("Controller binary does not match the hash in the signed manifest")
+        }
+        Ok(())
+    }
+}
+
+fn sha256(data: &[u8]) -> String {
+    openssl::sha::sha256(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The manifest, if it is signed with the pinned key
+pub fn verify(manifest: &[u8], signature: &[u8], public_key_pem: &[u8]) -> AnyhowResult<Manifest> {
+    let public_key =
+        PKey::public_key_from_pem(public_key_pem).context("Invalid signing key for self-update")?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key)?;
+    verifier.update(manifest)?;
+    if !verifier.verify(signature).unwrap_or(false) {
+        bail!("Signature of the controller manifest is invalid")
+    }
+    serde_json::from_slice(manifest).context("Failed to parse controller manifest")
+}
+
+/// Check that the binary runs and reports the expected version
+pub fn check_version(binary: &Path, version: &str) -> AnyhowResult<()> {
+    let output = Command::new(binary)
+        .arg("--version")
+        .output()
+        .context(format!("Failed to run {:?}", binary))?;
+    let reported = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() || !reported.split_whitespace().any(|word| word == version) {
+        bail!(
+            "{:?} does not report version {} but '{}'",
+            binary,
+            version,
+            reported.trim()
+        )
+    }
+    Ok(())
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.file_name().unwrap_or_default());
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Where the previous binary is kept
+pub fn backup_path(executable: &Path) -> PathBuf {
+    sibling(executable, ".old")
+}
+
+/// Let the binary take the place of the executable. The new binary is written next to it and
+/// moved into place, st. the executable is replaced at once. If the check fails afterwards, the
+/// previous binary is restored.
+pub fn install(
+    executable: &Path,
+    binary: &[u8],
+    check: impl Fn(&Path) -> AnyhowResult<()>,
+) -> AnyhowResult<()> {
+    let staged = sibling(executable, ".new");
+    let backup = backup_path(executable);
+    fs::write(&staged, binary).context(format!("Failed to write {:?}", staged))?;
+    let staged_check = fs::set_permissions(&staged, fs::metadata(executable)?.permissions())
+        .map_err(anyhow::Error::from)
+        .and_then(|_| check(&staged));
+    if let Err(err) = staged_check {
+        fs::remove_file(&staged)?;
+        return Err(err.context("New controller binary is not usable"));
+    }
+
+    if backup.exists() {
+        fs::remove_file(&backup)?;
+    }
+    swap(executable, &staged, &backup)?;
+    if let Err(err) = check(executable) {
+        fs::rename(&backup, executable).context(format!(
+            "Failed to restore previous binary from {:?}",
+            backup
+        ))?;
+        return Err(err.context("Installed controller binary is not usable, restored previous one"));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn swap(executable: &Path, staged: &Path, backup: &Path) -> AnyhowResult<()> {
+    fs::hard_link(executable, backup)
+        .or_else(|_| fs::copy(executable, backup).map(|_| ()))
+        .context(format!("Failed to keep previous binary as {:?}", backup))?;
+    fs::rename(staged, executable).context(format!("Failed to replace {:?}", executable))
+}
+
+/// The running executable cannot be overwritten, but renamed
+#[cfg(windows)]
+fn swap(executable: &Path, staged: &Path, backup: &Path) -> AnyhowResult<()> {
+    fs::rename(executable, backup)
+        .context(format!("Failed to keep previous binary as {:?}", backup))?;
+    if let Err(err) = fs::rename(staged, executable) {
+        fs::rename(backup, executable)?;
+        return Err(anyhow::Error::from(err).context(format!("Failed to replace {:?}", executable)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_self_update {
+    use super::*;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+
+    fn manifest(version: &str, platform: &str, binary: &[u8]) -> Vec<u8> {
+        serde_json::json!({"version": version, "platform": platform, "sha256": sha256(binary)})
+            .to_string()
+            .into_bytes()
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("2.3.0", "2.3.0b1").unwrap());
+        assert!(is_newer("2.3.0b1", "2.3.0i2").unwrap());
+        assert!(is_newer("2.3.0p1", "2.3.0").unwrap());
+        assert!(is_newer("2.3.0p10", "2.3.0p9").unwrap());
+        assert!(is_newer("2.4.0i1", "2.3.0p30").unwrap());
+        assert!(is_newer("10.0.0", "9.0.0").unwrap());
+        assert!(!is_newer("2.3.0b1", "2.3.0b1").unwrap());
+        assert!(!is_newer("2.2.0p20", "2.3.0b1").unwrap());
+        assert!(!is_newer("2.3.0b1", "2.3.0").unwrap());
+        assert!(is_newer("2.3", "2.3.0").is_err());
+        assert!(is_newer("2.3.0x1", "2.3.0").is_err());
+        assert!(is_newer("2.3.0p", "2.3.0").is_err());
+        assert!(is_newer("2.3.0-2024.01.01", "2.3.0").is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let public_key_pem = key.public_key_to_pem().unwrap();
+        let manifest = manifest("2.3.0p1", &platform(), b"binary");
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer.update(&manifest).unwrap();
+        let signature = signer.sign_to_vec().unwrap();
+
+        let verified = verify(&manifest, &signature, &public_key_pem).unwrap();
+        assert_eq!(verified.version, "2.3.0p1");
+        assert!(verify(b"tampered", &signature, &public_key_pem).is_err());
+        let other_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        assert!(verify(
+            &manifest,
+            &signature,
+            &other_key.public_key_to_pem().unwrap()
+        )
+        .is_err());
+        assert!(verify(&manifest, &signature, b"no key").is_err());
+    }
+
+    #[test]
+    fn test_manifest_check() {
+        let parse = |manifest: Vec<u8>| serde_json::from_slice::<Manifest>(&manifest).unwrap();
+        let signed = parse(manifest("2.3.0p1", &platform(), b"binary"));
+        assert!(signed.check("2.3.0p1", b"binary").is_ok());
+        assert!(signed.check("2.3.0p2", b"binary").is_err());
+        assert!(signed.check("2.3.0p1", b"tampered").is_err());
+        assert!(parse(manifest("2.3.0p1", "plan9-mips", b"binary"))
+            .check("2.3.0p1", b"binary")
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_version() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("cmk-agent-ctl");
+        fs::write(&binary, "#!/bin/sh\necho cmk-agent-ctl 2.3.0p1\n").unwrap();
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(check_version(&binary, "2.3.0p1").is_ok());
+        assert!(check_version(&binary, "2.3.0").is_err());
+        assert!(check_version(&dir.path().join("missing"), "2.3.0p1").is_err());
+    }
+
+    #[test]
+    fn test_install() {
+        let dir = tempfile::tempdir().unwrap();
+        let executable = dir.path().join("cmk-agent-ctl");
+        fs::write(&executable, "old").unwrap();
+        let content = |path: &Path| fs::read_to_string(path).unwrap();
+
+        // Checked before taking the place of the executable
+        let refuse_new = |path: &Path| match content(path).as_str() {
+            "new" => bail!("broken"),
+            _ => Ok(()),
+        };
+        assert!(install(&executable, b"new", refuse_new).is_err());
+        assert_eq!(content(&executable), "old");
+        assert!(!sibling(&executable, ".new").exists());
+
+        // Checked again in place, eg. for binaries which depend on their location
+        let refuse_in_place = |path: &Path| match path == executable {
+            true => bail!("broken"),
+            false => Ok(()),
+        };
+        assert!(install(&executable, b"new", refuse_in_place).is_err());
+        assert_eq!(content(&executable), "old");
+
+        install(&executable, b"new", |_| Ok(())).unwrap();
+        assert_eq!(content(&executable), "new");
+        assert_eq!(content(&backup_path(&executable)), "old");
+        install(&executable, b"newer", |_| Ok(())).unwrap();
+        assert_eq!(content(&executable), "newer");
+        assert_eq!(content(&backup_path(&executable)), "new");
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Running the daemon as Windows service. The service control manager may pause the push
+//! scheduling and stop the daemon, the daemon reports its progress while starting and stopping.
+//! For troubleshooting, the same lifecycle runs in the foreground with --service-debug.
+
+use crate::cli::ServiceOpts;
+use crate::constants::windows_service as constants;
+use crate::lifecycle::{Lifecycle, State, Status};
+use anyhow::{Context, Result as AnyhowResult};
+use log::{error, info, warn};
+use std::ffi::OsString;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
+use windows_service::{define_windows_service, service_dispatcher};
+
+type Daemon = Box<dyn FnOnce(Lifecycle) -> AnyhowResult<()> + Send>;
+
+// The service control manager calls service_main without any context, so the daemon is handed
+// over via this slot.
+static DAEMON: Mutex<Option<Daemon>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+pub fn run(
+    service_opts: &ServiceOpts,
+    daemon: impl FnOnce(Lifecycle) -> AnyhowResult<()> + Send + 'static,
+) -> AnyhowResult<()> {
+    if service_opts.service_debug {
+        return run_debug(daemon);
+    }
+    if !service_opts.service {
+        return daemon(Lifecycle::default());
+    }
+    *DAEMON.lock().unwrap() = Some(Box::new(daemon));
+    service_dispatcher::start(constants::NAME, ffi_service_main).context(
+        "Failed to connect to the service control manager. \
+         To run the service in the foreground, use --service-debug.",
+    )
+}
+
+/// Run the daemon in the foreground as if it was the service: Ctrl+C stops it, Ctrl+Break
+/// pauses and continues it, the status reported to the service control manager is printed.
+fn run_debug(daemon: impl FnOnce(Lifecycle) -> AnyhowResult<()>) -> AnyhowResult<()> {
+    eprintln!("Running service in the foreground. Ctrl+C stops, Ctrl+Break pauses/continues.");
+    let result = daemon(Lifecycle::with_observer(|status| {
+        eprintln!("Service status: {}", status)
+    }));
+    eprintln!(
+        "Service status: Stopped (exit code {})",
+        exit_code(&result).map_or(0, |_| constants::FAILURE_EXIT_CODE)
+    );
+    result
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let Some(daemon) = DAEMON.lock().unwrap().take() else {
+        return;
+    };
+    if let Err(err) = run_service(daemon) {
+        error!("Error running Windows service. ({:?})", err);
+    }
+}
+
+fn run_service(daemon: Daemon) -> AnyhowResult<()> {
+    // The control handler needs the lifecycle, the lifecycle reports to the handle which is only
+    // available after registering the control handler.
+    let status_handle: Arc<Mutex<Option<ServiceStatusHandle>>> = Default::default();
+    let lifecycle = Lifecycle::with_observer({
+        let status_handle = Arc::clone(&status_handle);
+        move |status| {
+            if let Some(status_handle) = *status_handle.lock().unwrap() {
+                report(&status_handle, service_status(status));
+            }
+        }
+    });
+    let handle = service_control_handler::register(constants::NAME, {
+        let lifecycle = lifecycle.clone();
+        move |control| handle_control(&lifecycle, control)
+    })
+    .context("Failed to register handler for service control requests")?;
+    *status_handle.lock().unwrap() = Some(handle);
+    report(&handle, service_status(lifecycle.status()));
+
+    let result = daemon(lifecycle);
+    if let Err(err) = &result {
+        error!(
+            "Service failed, reporting exit code {}. To let the service control manager restart \
+             it, enable recovery actions for this case: sc.exe failureflag {} 1 ({:?})",
+            constants::FAILURE_EXIT_CODE,
+            constants::NAME,
+            err
+        );
+    }
+    report(
+        &handle,
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: ServiceState::Stopped,
+            controls_accepted: ServiceControlAccept::empty(),
+            exit_code: exit_code(&result).unwrap_or(ServiceExitCode::Win32(0)),
+            checkpoint: 0,
+            wait_hint: Duration::ZERO,
+            process_id: None,
+        },
+    );
+    Ok(())
+}
+
+fn handle_control(lifecycle: &Lifecycle, control: ServiceControl) -> ServiceControlHandlerResult {
+    match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            info!("Service control manager requested to stop");
+            lifecycle.stop();
+        }
+        ServiceControl::Pause => {
+            info!("Service control manager requested to pause");
+            lifecycle.pause();
+        }
+        ServiceControl::Continue => {
+            info!("Service control manager requested to continue");
+            lifecycle.resume();
+        }
+        ServiceControl::Interrogate => {}
+        _ => return ServiceControlHandlerResult::NotImplemented,
+    }
+    ServiceControlHandlerResult::NoError
+}
+
+fn exit_code(result: &AnyhowResult<()>) -> Option<ServiceExitCode> {
+    result
+        .as_ref()
+        .err()
+        .map(|_| ServiceExitCode::ServiceSpecific(constants::FAILURE_EXIT_CODE))
+}
+
+fn service_status(status: Status) -> ServiceStatus {
+    let (current_state, pending) = match status.state {
+        State::Starting => (ServiceState::StartPending, true),
+        State::Running => (ServiceState::Running, false),
+        State::Paused => (ServiceState::Paused, false),
+        State::Stopping => (ServiceState::StopPending, true),
+    };
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state,
+        controls_accepted: match status.state {
+            State::Running | State::Paused => {
+                ServiceControlAccept::STOP
+                    | ServiceControlAccept::SHUTDOWN
+                    | ServiceControlAccept::PAUSE_CONTINUE
+            }
+            State::Starting | State::Stopping => ServiceControlAccept::empty(),
+        },
+        exit_code: ServiceExitCode::Win32(0),
+        // Only pending operations report progress
+        checkpoint: if pending { status.checkpoint } else { 0 },
+        wait_hint: if pending {
+            Duration::from_secs(constants::WAIT_HINT)
+        } else {
+            Duration::ZERO
+        },
+        process_id: None,
+    }
+}
+
+fn report(status_handle: &ServiceStatusHandle, status: ServiceStatus) {
+    if let Err(err) = status_handle.set_service_status(status) {
+        warn!(
+            "Failed to report status to service control manager. ({})",
+            err
+        );
+    }
+}
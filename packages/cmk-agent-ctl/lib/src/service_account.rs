@@ -0,0 +1,395 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Running the Windows service under an account other than LocalSystem, typically a group Managed
+//! Service Account (gMSA). Install the account on the host (Install-ADServiceAccount) and assign
+//! it to the service (sc.exe config CheckmkAgentCtl obj= DOMAIN\name$). The account requires:
+//! - the right to log on as a service, which the service control manager grants when assigning
+//!   the account,
+//! - modify access to the home directory (%ProgramData%\checkmk\agent), which holds the
+//!   configuration and the connection registry,
+//! - no access to any certificate store for the connections, their certificates and keys are
+//!   kept in the registry. TLS with the REST API verifies against the Windows root store, a gMSA
+//!   has no user store of its own, so custom CAs have to be imported into LocalMachine\Root.
+//!
+//! The registry file only grants access to LocalSystem, the administrators, the account running
+//! the controller and the service account. The same goes for the control pipe of the daemon,
+//! st. elevated administrators can still query and control a daemon running as gMSA.
+//! 'cmk-agent-ctl doctor --verify-service-account' checks these rights.
+
+use crate::constants::windows_service as constants;
+use anyhow::{Context, Result as AnyhowResult};
+use log::debug;
+use std::ffi::{OsStr, OsString};
+use std::io::{Error, ErrorKind, Result as IoResult};
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+use winapi::shared::minwindef::{DWORD, FALSE};
+use winapi::shared::sddl;
+use winapi::shared::winerror::{ERROR_INSUFFICIENT_BUFFER, ERROR_SUCCESS};
+use winapi::um::{
+    accctrl, aclapi, handleapi, minwinbase, processthreadsapi, securitybaseapi, winbase, winnt,
+};
+use windows_service::service::ServiceAccess;
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+/// Names the service control manager reports for LocalSystem
+const LOCAL_SYSTEM: [&str; 3] = ["LocalSystem", ".\\LocalSystem", "NT AUTHORITY\\SYSTEM"];
+
+/// Access of an account to a file or directory, as far as the controller is concerned
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Access {
+    Denied,
+    Read,
+    Modify,
+}
+
+impl Access {
+    fn from_mask(mask: DWORD) -> Self {
+        let modify = winnt::FILE_GENERIC_READ | winnt::FILE_GENERIC_WRITE | winnt::DELETE;
+        if mask & modify == modify {
+            Access::Modify
+        } else if mask & winnt::FILE_GENERIC_READ == winnt::FILE_GENERIC_READ {
+            Access::Read
+        } else {
+            Access::Denied
+        }
+    }
+}
+
+impl std::fmt::Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Access::Denied => "no access",
+                Access::Read => "read access",
+                Access::Modify => "modify access",
+            }
+        )
+    }
+}
+
+fn wide(s: impl AsRef<OsStr>) -> Vec<u16> {
+    s.as_ref().encode_wide().chain(Some(0)).collect()
+}
+
+/// Discretionary ACL granting the given rights to LocalSystem, the administrators and the given
+/// accounts only, not inheriting any entries from the parent
+fn restricted_sddl<'a>(rights: &str, sids: impl Iterator<Item = &'a str>) -> String {
+    let mut sddl = format!("D:P(A;;{rights};;;SY)(A;;{rights};;;BA)");
+    for sid in sids {
+        sddl.push_str(&format!("(A;;{rights};;;{sid})"));
+    }
+    sddl
+}
+
+/// Self-relative security descriptor, freed when dropped
+struct SecurityDescriptor(winnt::PSECURITY_DESCRIPTOR);
+
+impl SecurityDescriptor {
+    fn from_sddl(sddl: &str) -> IoResult<Self> {
+        let mut descriptor: winnt::PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+        let ok = unsafe {
+            sddl::ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                wide(sddl).as_ptr(),
+                sddl::SDDL_REVISION_1 as DWORD,
+                &mut descriptor,
+                std::ptr::null_mut(),
+            )
+        };
+        match ok {
+            0 => Err(Error::last_os_error()),
+            _ => Ok(Self(descriptor)),
+        }
+    }
+
+    fn dacl(&self) -> IoResult<winnt::PACL> {
+        let mut present = FALSE;
+        let mut defaulted = FALSE;
+        let mut dacl: winnt::PACL = std::ptr::null_mut();
+        let ok = unsafe {
+            securitybaseapi::GetSecurityDescriptorDacl(
+                self.0,
+                &mut present,
+                &mut dacl,
+                &mut defaulted,
+            )
+        };
+        match ok {
+            0 => Err(Error::last_os_error()),
+            _ => Ok(dacl),
+        }
+    }
+}
+
+impl Drop for SecurityDescriptor {
+    fn drop(&mut self) {
+        unsafe { winbase::LocalFree(self.0) };
+    }
+}
+
+fn sid_to_string(sid: winnt::PSID) -> IoResult<String> {
+    let mut string: *mut u16 = std::ptr::null_mut();
+    if unsafe { sddl::ConvertSidToStringSidW(sid, &mut string) } == 0 {
+        return Err(Error::last_os_error());
+    }
+    let len = (0..)
+        .take_while(|&i| unsafe { *string.add(i) } != 0)
+        .count();
+    let result = OsString::from_wide(unsafe { std::slice::from_raw_parts(string, len) });
+    unsafe { winbase::LocalFree(string as _) };
+    Ok(result.to_string_lossy().into_owned())
+}
+
+/// The SID of the account running the controller
+pub fn current_sid() -> IoResult<String> {
+    let mut token: winnt::HANDLE = std::ptr::null_mut();
+    let ok = unsafe {
+        processthreadsapi::OpenProcessToken(
+            processthreadsapi::GetCurrentProcess(),
+            winnt::TOKEN_QUERY,
+            &mut token,
+        )
+    };
+    if ok == 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut size: DWORD = 0;
+    unsafe {
+        securitybaseapi::GetTokenInformation(
+            token,
+            winnt::TokenUser,
+            std::ptr::null_mut(),
+            0,
+            &mut size,
+        )
+    };
+    // u64 st. the buffer is aligned for TOKEN_USER
+    let mut buffer = vec![0u64; (size as usize + 7) / 8];
+    let ok = unsafe {
+        securitybaseapi::GetTokenInformation(
+            token,
+            winnt::TokenUser,
+            buffer.as_mut_ptr() as _,
+            size,
+            &mut size,
+        )
+    };
+    let error = Error::last_os_error();
+    unsafe { handleapi::CloseHandle(token) };
+    if ok == 0 {
+        return Err(error);
+    }
+    let user = buffer.as_ptr() as *const winnt::TOKEN_USER;
+    sid_to_string(unsafe { (*user).User.Sid })
+}
+
+/// The SID of the given account, eg. DOMAIN\name$ for a gMSA
+pub fn sid_of(account: &str) -> IoResult<String> {
+    let name = wide(account);
+    let mut sid_size: DWORD = 0;
+    let mut domain_size: DWORD = 0;
+    let mut sid_use: winnt::SID_NAME_USE = 0;
+    unsafe {
+        winbase::LookupAccountNameW(
+            std::ptr::null(),
+            name.as_ptr(),
+            std::ptr::null_mut(),
+            &mut sid_size,
+            std::ptr::null_mut(),
+            &mut domain_size,
+            &mut sid_use,
+        )
+    };
+    let error = Error::last_os_error();
+    if error.raw_os_error() != Some(ERROR_INSUFFICIENT_BUFFER as i32) {
+        return Err(error);
+    }
+    // u64 st. the buffer is aligned for SID
+    let mut sid = vec![0u64; (sid_size as usize + 7) / 8];
+    let mut domain = vec![0u16; domain_size as usize];
+    let ok = unsafe {
+        winbase::LookupAccountNameW(
+            std::ptr::null(),
+            name.as_ptr(),
+            sid.as_mut_ptr() as _,
+            &mut sid_size,
+            domain.as_mut_ptr(),
+            &mut domain_size,
+            &mut sid_use,
+        )
+    };
+    match ok {
+        0 => Err(Error::last_os_error()),
+        _ => sid_to_string(sid.as_mut_ptr() as _),
+    }
+}
+
+/// The account the service is configured to run as, None for LocalSystem
+pub fn service_account() -> AnyhowResult<Option<String>> {
+    let config = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .context("Failed to connect to the service control manager")?
+        .open_service(constants::NAME, ServiceAccess::QUERY_CONFIG)
+        .context(format!("Failed to open service {}", constants::NAME))?
+        .query_config()
+        .context(format!(
+            "Failed to query configuration of service {}",
+            constants::NAME
+        ))?;
+    Ok(config
+        .account_name
+        .map(|name| name.to_string_lossy().into_owned())
+        .filter(|name| {
+            !LOCAL_SYSTEM
+                .iter()
+                .any(|local_system| name.eq_ignore_ascii_case(local_system))
+        }))
+}
+
+/// SIDs of the accounts which may access the files and the pipe of the controller, besides
+/// LocalSystem and the administrators: the one running the controller and the service account.
+fn trusted_sids() -> Vec<String> {
+    let mut sids = vec![];
+    match current_sid() {
+        Ok(sid) => sids.push(sid),
+        Err(err) => debug!("Cannot determine the current account. ({})", err),
+    }
+    match service_account().map(|account| account.map(|account| sid_of(&account))) {
+        Ok(Some(Ok(sid))) if !sids.contains(&sid) => sids.push(sid),
+        Ok(Some(Err(err))) => debug!("Cannot resolve the service account. ({})", err),
+        Err(err) => debug!("Cannot determine the service account. ({:?})", err),
+        _ => {}
+    }
+    sids
+}
+
+/// Restrict the access to the given file, the counterpart of mode 0600 under Unix
+pub fn restrict_access(path: &Path) -> IoResult<()> {
+    let sids = trusted_sids();
+    let descriptor =
+        SecurityDescriptor::from_sddl(&restricted_sddl("FA", sids.iter().map(String::as_str)))?;
+    let mut path = wide(path);
+    let status = unsafe {
+        aclapi::SetNamedSecurityInfoW(
+            path.as_mut_ptr(),
+            accctrl::SE_FILE_OBJECT,
+            winnt::DACL_SECURITY_INFORMATION | winnt::PROTECTED_DACL_SECURITY_INFORMATION,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            descriptor.dacl()?,
+            std::ptr::null_mut(),
+        )
+    };
+    match status {
+        ERROR_SUCCESS => Ok(()),
+        status => Err(Error::from_raw_os_error(status as i32)),
+    }
+}
+
+/// Effective access of the given account to the given file or directory, including the access
+/// granted via the groups it is a member of
+pub fn effective_access(path: &Path, sid: &str) -> IoResult<Access> {
+    let mut dacl: winnt::PACL = std::ptr::null_mut();
+    let mut descriptor: winnt::PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+    let status = unsafe {
+        aclapi::GetNamedSecurityInfoW(
+            wide(path).as_ptr(),
+            accctrl::SE_FILE_OBJECT,
+            winnt::DACL_SECURITY_INFORMATION,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut dacl,
+            std::ptr::null_mut(),
+            &mut descriptor,
+        )
+    };
+    if status != ERROR_SUCCESS {
+        return Err(Error::from_raw_os_error(status as i32));
+    }
+    // The DACL points into the descriptor, which is freed with it
+    let descriptor = SecurityDescriptor(descriptor);
+    let mut psid: winnt::PSID = std::ptr::null_mut();
+    if unsafe { sddl::ConvertStringSidToSidW(wide(sid).as_ptr(), &mut psid) } == 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut trustee: accctrl::TRUSTEE_W = unsafe { std::mem::zeroed() };
+    unsafe { aclapi::BuildTrusteeWithSidW(&mut trustee, psid) };
+    let mut mask: winnt::ACCESS_MASK = 0;
+    let status = unsafe { aclapi::GetEffectiveRightsFromAclW(dacl, &mut trustee, &mut mask) };
+    unsafe { winbase::LocalFree(psid) };
+    drop(descriptor);
+    match status {
+        ERROR_SUCCESS => Ok(Access::from_mask(mask)),
+        status => Err(Error::from_raw_os_error(status as i32)),
+    }
+}
+
+/// Security attributes for the control pipe of the daemon
+pub struct PipeSecurity {
+    // Referenced by the attributes
+    _descriptor: SecurityDescriptor,
+    attributes: minwinbase::SECURITY_ATTRIBUTES,
+}
+
+impl PipeSecurity {
+    pub fn control_pipe() -> IoResult<Self> {
+        let sids = trusted_sids();
+        if sids.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Cannot determine the account running the daemon",
+            ));
+        }
+        let descriptor =
+            SecurityDescriptor::from_sddl(&restricted_sddl("GA", sids.iter().map(String::as_str)))?;
+        let attributes = minwinbase::SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<minwinbase::SECURITY_ATTRIBUTES>() as DWORD,
+            lpSecurityDescriptor: descriptor.0,
+            bInheritHandle: FALSE,
+        };
+        Ok(Self {
+            _descriptor: descriptor,
+            attributes,
+        })
+    }
+
+    pub fn as_raw(&mut self) -> *mut std::ffi::c_void {
+        &mut self.attributes as *mut minwinbase::SECURITY_ATTRIBUTES as _
+    }
+}
+
+#[cfg(test)]
+mod test_service_account {
+    use super::*;
+
+    #[test]
+    fn test_restricted_sddl() {
+        assert_eq!(
+            restricted_sddl("FA", ["S-1-5-21-1-2-3-1104"].into_iter()),
+            "D:P(A;;FA;;;SY)(A;;FA;;;BA)(A;;FA;;;S-1-5-21-1-2-3-1104)"
+        );
+        assert_eq!(
+            restricted_sddl("GA", std::iter::empty()),
+            "D:P(A;;GA;;;SY)(A;;GA;;;BA)"
+        );
+    }
+
+    #[test]
+    fn test_access_from_mask() {
+        assert_eq!(Access::from_mask(winnt::FILE_ALL_ACCESS), Access::Modify);
+        assert_eq!(Access::from_mask(winnt::FILE_GENERIC_READ), Access::Read);
+        assert_eq!(
+            Access::from_mask(winnt::FILE_GENERIC_READ | winnt::FILE_GENERIC_WRITE),
+            Access::Read
+        );
+        assert_eq!(Access::from_mask(0), Access::Denied);
+    }
+
+    #[test]
+    fn test_current_sid() {
+        assert!(current_sid().unwrap().starts_with("S-1-"));
+    }
+}
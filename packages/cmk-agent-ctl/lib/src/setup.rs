@@ -0,0 +1,716 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+#[cfg(windows)]
+use super::misc;
+#[cfg(unix)]
+use super::privileges;
+use super::{
+    cli, cli_spec, config, constants, container, error_code, fips, messages, system_log, types,
+};
+use anyhow::{Context, Result as AnyhowResult};
+use clap::Parser;
+use flexi_logger::FileSpec;
+#[cfg(windows)]
+use log::info;
+use log::{debug, error, warn};
+#[cfg(unix)]
+use nix::unistd;
+use std::env;
+use std::env::ArgsOs;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+static LOGGER: OnceLock<flexi_logger::LoggerHandle> = OnceLock::new();
+/// Log level as configured at startup, restored when a temporary level is reset
+static CONFIGURED_LOG_SPEC: Mutex<String> = Mutex::new(String::new());
+/// Log level currently applied, as reported by the status of the daemon
+static LOG_SPEC_IN_EFFECT: Mutex<String> = Mutex::new(String::new());
+/// Set if the command line or RUST_LOG set the log level, which reloading the config keeps then
+static LOG_LEVEL_OVERRIDDEN: AtomicBool = AtomicBool::new(false);
+static LOG_AS_JSON: AtomicBool = AtomicBool::new(false);
+/// Log target of the error the controller terminates with, st. its code ends up in the JSON log
+const FATAL_ERROR_TARGET: &str = "cmk_agent_ctl::fatal";
+static FATAL_ERROR_CODE: OnceLock<error_code::ErrorCode> = OnceLock::new();
+
+// TODO(sk): estimate to move in constants
+#[cfg(windows)]
+fn to_version(major: &u64, minor: &u64) -> u64 {
+    major * 100 + minor
+}
+
+fn is_os_supported() -> bool {
+    #[cfg(windows)]
+    {
+        let info = os_info::get();
+        match info.version() {
+            os_info::Version::Semantic(major, minor, _patch) => {
+                to_version(major, minor)
+                    >= to_version(
+                        &constants::MIN_WIN_VERSION_MAJOR,
+                        &constants::MIN_WIN_VERSION_MINOR,
+                    )
+            }
+            _ => false,
+        }
+    }
+
+    #[cfg(unix)]
+    true
+}
+
+pub struct PathResolver {
+    pub home_dir: PathBuf,
+    /// Directory of the registry and the other files written at runtime, the home dir by default
+    pub state_dir: PathBuf,
+    pub config_path: PathBuf,
+    pub pre_configured_connections_path: PathBuf,
+    pub image_connection_path: PathBuf,
+    pub registry_path: PathBuf,
+    pub connection_stats_path: PathBuf,
+    pub payload_stats_path: PathBuf,
+    pub usage_stats_path: PathBuf,
+    pub remote_status_cache_path: PathBuf,
+    pub integrity_check_path: PathBuf,
+    pub push_spool_path: PathBuf,
+    pub control_socket_path: PathBuf,
+    pub relay_path: PathBuf,
+    pub vault_path: PathBuf,
+    pub vault_key_path: PathBuf,
+}
+
+#[cfg(unix)]
+impl PathResolver {
+    pub fn new(home_dir: &Path) -> PathResolver {
+        PathResolver {
+            home_dir: PathBuf::from(home_dir),
+            state_dir: PathBuf::from(home_dir),
+            config_path: home_dir.join(constants::CONFIG_FILE),
+            pre_configured_connections_path: home_dir
+                .join(constants::PRE_CONFIGURED_CONNECTIONS_FILE),
+            image_connection_path: home_dir.join(constants::IMAGE_CONNECTION_FILE),
+            registry_path: home_dir.join(Path::new(constants::REGISTRY_FILE)),
+            connection_stats_path: home_dir.join(Path::new(constants::CONNECTION_STATS_FILE)),
+            payload_stats_path: home_dir.join(Path::new(constants::PAYLOAD_STATS_FILE)),
+            usage_stats_path: home_dir.join(Path::new(constants::USAGE_STATS_FILE)),
+            remote_status_cache_path: home_dir.join(Path::new(constants::REMOTE_STATUS_CACHE_FILE)),
+            integrity_check_path: home_dir.join(Path::new(constants::INTEGRITY_CHECK_FILE)),
+            push_spool_path: home_dir.join(Path::new(constants::PUSH_SPOOL_DIR)),
+            control_socket_path: home_dir.join(Path::new(constants::CONTROL_SOCKET_FILE)),
+            relay_path: home_dir.join(Path::new(constants::RELAY_FILE)),
+            vault_path: home_dir.join(Path::new(constants::VAULT_DIR)),
+            vault_key_path: home_dir.join(Path::new(constants::VAULT_KEY_FILE)),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl PathResolver {
+    pub fn new(home_dir: &Path) -> PathResolver {
+        PathResolver {
+            home_dir: PathBuf::from(home_dir),
+            state_dir: PathBuf::from(home_dir),
+            config_path: home_dir.join(Path::new(constants::CONFIG_FILE)),
+            pre_configured_connections_path: home_dir
+                .join(Path::new(constants::PRE_CONFIGURED_CONNECTIONS_FILE)),
+            image_connection_path: home_dir.join(Path::new(constants::IMAGE_CONNECTION_FILE)),
+            registry_path: home_dir.join(Path::new(constants::REGISTRY_FILE)),
+            connection_stats_path: home_dir.join(Path::new(constants::CONNECTION_STATS_FILE)),
+            payload_stats_path: home_dir.join(Path::new(constants::PAYLOAD_STATS_FILE)),
+            usage_stats_path: home_dir.join(Path::new(constants::USAGE_STATS_FILE)),
+            remote_status_cache_path: home_dir.join(Path::new(constants::REMOTE_STATUS_CACHE_FILE)),
+            integrity_check_path: home_dir.join(Path::new(constants::INTEGRITY_CHECK_FILE)),
+            push_spool_path: home_dir.join(Path::new(constants::PUSH_SPOOL_DIR)),
+            control_socket_path: home_dir.join(Path::new(constants::CONTROL_SOCKET_FILE)),
+            relay_path: home_dir.join(Path::new(constants::RELAY_FILE)),
+            vault_path: home_dir.join(Path::new(constants::VAULT_DIR)),
+            vault_key_path: home_dir.join(Path::new(constants::VAULT_KEY_FILE)),
+        }
+    }
+}
+impl PathResolver {
+    /// Move the files written at runtime to the configured locations. The config file and the
+    /// connections given by the installer or the image stay in the home dir.
+    pub fn with_config(self, paths_config: &config::PathsConfig) -> PathResolver {
+        let state_dir = paths_config
+            .state_dir
+            .clone()
+            .unwrap_or_else(|| self.state_dir.clone());
+        PathResolver {
+            registry_path: state_dir.join(constants::REGISTRY_FILE),
+            connection_stats_path: state_dir.join(constants::CONNECTION_STATS_FILE),
+            payload_stats_path: state_dir.join(constants::PAYLOAD_STATS_FILE),
+            usage_stats_path: state_dir.join(constants::USAGE_STATS_FILE),
+            remote_status_cache_path: state_dir.join(constants::REMOTE_STATUS_CACHE_FILE),
+            integrity_check_path: state_dir.join(constants::INTEGRITY_CHECK_FILE),
+            push_spool_path: paths_config
+                .push_spool_dir
+                .clone()
+                .unwrap_or_else(|| state_dir.join(constants::PUSH_SPOOL_DIR)),
+            control_socket_path: paths_config
+                .control_socket
+                .clone()
+                .unwrap_or_else(|| state_dir.join(constants::CONTROL_SOCKET_FILE)),
+            relay_path: state_dir.join(constants::RELAY_FILE),
+            vault_path: state_dir.join(constants::VAULT_DIR),
+            state_dir,
+            ..self
+        }
+    }
+}
+
+/// Log file as configured, None if logging to stderr or the system log only
+#[cfg(unix)]
+pub fn log_file(config_path: &Path) -> Option<PathBuf> {
+    config::LogFileConfig::load(config_path).path
+}
+
+/// Log file as configured, None if logging to the agent service or the system log only
+#[cfg(windows)]
+pub fn log_file(_config_path: &Path) -> Option<PathBuf> {
+    (env::var(constants::ENV_LOG_TO_FILE).unwrap_or_default() == "1")
+        .then(|| make_log_file_spec().as_pathbuf(None))
+}
+
+trait ExistsOr {
+    fn exists_or(self, other_path: PathBuf) -> PathBuf;
+}
+
+impl ExistsOr for PathBuf {
+    fn exists_or(self, other_path: PathBuf) -> PathBuf {
+        if self.exists() {
+            self
+        } else {
+            other_path
+        }
+    }
+}
+
+pub fn connection_timeout() -> u64 {
+    match env::var(constants::ENV_CONNECTION_TIMEOUT) {
+        Err(_) => constants::CONNECTION_TIMEOUT,
+        Ok(timeout) => {
+            let max = timeout.parse().unwrap();
+            debug!("Using debug value for CONNECTION_TIMEOUT: {}", max);
+            max
+        }
+    }
+}
+
+pub fn max_connections() -> usize {
+    match env::var(constants::ENV_MAX_CONNECTIONS) {
+        Err(_) => constants::MAX_CONNECTIONS,
+        Ok(max) => {
+            let max = max.parse().unwrap();
+            debug!("Using debug value for MAX_CONNECTIONS: {}", max);
+            max
+        }
+    }
+}
+
+#[cfg(unix)]
+pub fn agent_channel() -> types::AgentChannel {
+    if let Some(agent_channel) = container::agent_channel() {
+        return agent_channel;
+    }
+    match env::var(constants::ENV_HOME_DIR) {
+        Err(_) => constants::UNIX_AGENT_SOCKET.into(),
+        Ok(home_dir) => {
+            let sock = PathBuf::from(home_dir).join(
+                PathBuf::from(constants::UNIX_AGENT_SOCKET)
+                    .strip_prefix("/")
+                    .unwrap(),
+            );
+            debug!("Using debug UNIX socket: {:?}", &sock);
+            sock.into()
+        }
+    }
+}
+
+#[cfg(windows)]
+pub fn agent_channel() -> types::AgentChannel {
+    types::AgentChannel::from(format!("np/{}", constants::WIN_AGENT_PIPE).as_ref())
+}
+
+#[cfg(unix)]
+const TEXT_FORMAT: flexi_logger::FormatFunction = flexi_logger::default_format;
+#[cfg(windows)]
+const TEXT_FORMAT: flexi_logger::FormatFunction = flexi_logger::detailed_format;
+
+/// One JSON object per record, st. logs can be shipped to log management systems as they are
+fn json_format(
+    w: &mut dyn Write,
+    _now: &mut flexi_logger::DeferredNow,
+    record: &log::Record,
+) -> io::Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs_f64())
+        .unwrap_or_default();
+    let mut entry = serde_json::json!({
+        "timestamp": timestamp,
+        "level": record.level().as_str(),
+        "module": record.module_path().unwrap_or("<unnamed>"),
+        "message": record.args().to_string(),
+    });
+    if let (FATAL_ERROR_TARGET, Some(code)) = (record.target(), FATAL_ERROR_CODE.get()) {
+        entry["error_code"] = serde_json::json!(code);
+        entry["error_category"] = serde_json::json!(code.category());
+    }
+    write!(w, "{}", entry)
+}
+
+// The format can only be chosen once the config file has been read, which is after logging
+// has been initialized.
+fn log_format(
+    w: &mut dyn Write,
+    now: &mut flexi_logger::DeferredNow,
+    record: &log::Record,
+) -> io::Result<()> {
+    if LOG_AS_JSON.load(Ordering::Relaxed) {
+        json_format(w, now, record)
+    } else {
+        TEXT_FORMAT(w, now, record)
+    }
+}
+
+fn register_logger(
+    logger: Result<flexi_logger::LoggerHandle, flexi_logger::FlexiLoggerError>,
+    level: &str,
+) -> Result<(), flexi_logger::FlexiLoggerError> {
+    let spec = env::var("RUST_LOG").unwrap_or_else(|_| String::from(level));
+    *lock_log_spec(&LOG_SPEC_IN_EFFECT) = spec.clone();
+    *lock_log_spec(&CONFIGURED_LOG_SPEC) = spec;
+    // init is only called once, so this never fails
+    let _ = LOGGER.set(logger?);
+    Ok(())
+}
+
+fn lock_log_spec(spec: &'static Mutex<String>) -> std::sync::MutexGuard<'static, String> {
+    match spec.lock() {
+        Ok(spec) => spec,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn set_log_specification(spec_str: &str) -> AnyhowResult<()> {
+    let spec = flexi_logger::LogSpecification::parse(spec_str)
+        .context(format!("Invalid log level '{spec_str}'"))?;
+    LOGGER
+        .get()
+        .context("Logging is not initialized")?
+        .set_new_spec(spec);
+    *lock_log_spec(&LOG_SPEC_IN_EFFECT) = String::from(spec_str);
+    Ok(())
+}
+
+/// Log the error the controller terminates with, prefixed by its code. Returns the exit code
+/// belonging to its category.
+pub fn log_fatal_error(error: &anyhow::Error) -> i32 {
+    let code = error_code::ErrorCode::of(error);
+    // Only the first fatal error is logged anyway
+    let _ = FATAL_ERROR_CODE.set(code);
+    error!(target: FATAL_ERROR_TARGET, "{}: {:?}", code, error);
+    code.category().exit_code()
+}
+
+/// Apply the logging settings from the config file
+pub fn apply_logging_config(logging_config: &config::LoggingConfig) -> AnyhowResult<()> {
+    LOG_AS_JSON.store(
+        logging_config.format == cli::LogFormat::Json,
+        Ordering::Relaxed,
+    );
+    LOG_LEVEL_OVERRIDDEN.store(logging_config.level_overridden, Ordering::Relaxed);
+    if let Some(level) = &logging_config.level {
+        set_log_specification(level).context("Invalid setting log_level in config file")?;
+        *lock_log_spec(&CONFIGURED_LOG_SPEC) = level.clone();
+    }
+    Ok(())
+}
+
+/// Change the log level until the next restart, or restore the configured one.
+/// Returns the log level in effect.
+pub fn change_log_level(spec: Option<&str>) -> AnyhowResult<String> {
+    let spec = match spec {
+        Some(spec) => String::from(spec),
+        None => lock_log_spec(&CONFIGURED_LOG_SPEC).clone(),
+    };
+    set_log_specification(&spec)?;
+    Ok(spec)
+}
+
+/// Apply the log level of the reloaded config file, which also ends a temporary level. A level set
+/// by the command line or RUST_LOG is kept. Returns the log level in effect.
+pub fn reload_log_level(level: Option<&str>) -> AnyhowResult<String> {
+    if let Some(level) = level.filter(|_| !LOG_LEVEL_OVERRIDDEN.load(Ordering::Relaxed)) {
+        set_log_specification(level).context("Invalid setting log_level in config file")?;
+        *lock_log_spec(&CONFIGURED_LOG_SPEC) = String::from(level);
+    }
+    change_log_level(None)
+}
+
+pub fn log_level() -> String {
+    lock_log_spec(&LOG_SPEC_IN_EFFECT).clone()
+}
+
+/// A system log which is not available is reported once logging is up, the daemon keeps
+/// logging to its regular destination
+fn system_log_writer(
+    backend: Option<config::LogBackend>,
+) -> (
+    Option<Box<dyn flexi_logger::writers::LogWriter>>,
+    Option<anyhow::Error>,
+) {
+    match backend.map(system_log::make_writer) {
+        Some(Ok(writer)) => (Some(writer), None),
+        Some(Err(err)) => (None, Some(err)),
+        None => (None, None),
+    }
+}
+
+fn report_system_log_error(error: Option<anyhow::Error>) {
+    if let Some(error) = error {
+        warn!("{:#}", error);
+    }
+}
+
+/// Without log file, log to stdout instead of stderr, eg. in a container
+#[cfg(unix)]
+fn init_logging(
+    level: &str,
+    log_file_config: Option<&config::LogFileConfig>,
+    stdout: bool,
+) -> Result<(), flexi_logger::FlexiLoggerError> {
+    let logger = flexi_logger::Logger::try_with_env_or_str(level)?.format(log_format);
+    let (writer, system_log_error) =
+        system_log_writer(log_file_config.and_then(|config| config.backend));
+    let logger = match (
+        log_file_config.and_then(|config| Some((config, config.path.as_ref()?))),
+        writer,
+    ) {
+        (Some((log_file_config, path)), writer) => {
+            let file_spec = FileSpec::try_from(path)?.suppress_timestamp();
+            match writer {
+                Some(writer) => logger.log_to_file_and_writer(file_spec, writer),
+                // Still report fatal errors to the service manager
+                None => logger
+                    .log_to_file(file_spec)
+                    .duplicate_to_stderr(flexi_logger::Duplicate::Error),
+            }
+            .append()
+            .rotate(
+                log_file_config.criterion(),
+                constants::log::FILE_NAMING,
+                log_file_config.cleanup(),
+            )
+        }
+        (None, Some(writer)) => logger.log_to_writer(writer),
+        (None, None) if stdout => logger.log_to_stdout(),
+        (None, None) => logger.log_to_stderr(),
+    };
+    register_logger(logger.start(), level)?;
+    report_system_log_error(system_log_error);
+    Ok(())
+}
+
+#[cfg(windows)]
+fn make_log_file_spec() -> FileSpec {
+    FileSpec::default()
+        .directory(env::var(constants::ENV_AGENT_LOG_DIR).unwrap_or_else(|_| ".".to_owned()))
+        .suppress_timestamp()
+        .basename("cmk-agent-ctl")
+}
+
+#[cfg(windows)]
+fn init_logging(
+    level: &str,
+    duplicate_level: flexi_logger::Duplicate,
+    log_file_config: &config::LogFileConfig,
+) -> Result<(), flexi_logger::FlexiLoggerError> {
+    let mut logger = flexi_logger::Logger::try_with_env_or_str(level)?;
+    let mut system_log_error = None;
+
+    logger = match duplicate_level {
+        flexi_logger::Duplicate::None => {
+            let (writer, error) = system_log_writer(log_file_config.backend);
+            system_log_error = error;
+            logger.log_to_writer(writer.unwrap_or_else(|| {
+                crate::log_ext::make_mailslot_logger(level)
+                    as Box<dyn flexi_logger::writers::LogWriter>
+            }))
+        }
+        _ => logger.log_to_stderr(),
+    };
+    if env::var(constants::ENV_LOG_TO_FILE).unwrap_or_default() == "1" {
+        logger = logger.log_to_file(make_log_file_spec());
+    }
+    register_logger(
+        logger
+            .append()
+            .format(log_format)
+            .rotate(
+                log_file_config.criterion(),
+                constants::log::FILE_NAMING,
+                log_file_config.cleanup(),
+            )
+            .start(),
+        level,
+    )?;
+    report_system_log_error(system_log_error);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn determine_paths(username: &str) -> AnyhowResult<PathResolver> {
+    let user = unistd::User::from_name(username)?.context(format!(
+        "Could not find dedicated Checkmk agent user {username}"
+    ))?;
+    Ok(PathResolver::new(&agent_home_dir(&user)))
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn agent_home_dir(user: &unistd::User) -> PathBuf {
+    user.dir.clone()
+}
+
+/// Under macOS, the agent user is a service account without a home dir of its own
+#[cfg(target_os = "macos")]
+fn agent_home_dir(_user: &unistd::User) -> PathBuf {
+    PathBuf::from(constants::MACOS_AGENT_HOME_DIR)
+}
+
+#[cfg(unix)]
+fn failed_to_run_as(username: &str) -> String {
+    format!(
+        "Failed to run as user '{username}'. Please execute with sufficient permissions (maybe try 'sudo')."
+    )
+}
+
+/// Switch to the configured user, which reads the config file as the user starting the
+/// controller. The daemon postpones the switch if started as root.
+#[cfg(unix)]
+fn run_as_configured_user(mode: &cli::Mode) -> AnyhowResult<PathResolver> {
+    let paths = determine_paths(constants::CMK_AGENT_USER)
+        .context(failed_to_run_as(constants::CMK_AGENT_USER))?;
+    if matches!(mode, cli::Mode::Completions(_)) {
+        // Completing runs with the permissions of the shell
+        return Ok(paths);
+    }
+    let privileges_config = config::PrivilegesConfig::load(&paths.config_path);
+    let identity = privileges::Identity::resolve(&privileges_config)
+        .context(failed_to_run_as(&privileges_config.user))?;
+    if !(matches!(mode, cli::Mode::Daemon(_)) && privileges::pending()) {
+        identity
+            .assume()
+            .context(failed_to_run_as(&privileges_config.user))?;
+    }
+    Ok(paths)
+}
+
+#[cfg(windows)]
+fn determine_paths() -> AnyhowResult<PathResolver> {
+    // Alternative home dir can be passed for testing/debug reasons
+    if let Ok(debug_home_dir) = std::env::var(constants::ENV_HOME_DIR) {
+        info!("Using debug HOME_DIR: {}", debug_home_dir);
+        return Ok(PathResolver::new(&PathBuf::from(debug_home_dir)));
+    }
+
+    // Normal/prod home dir
+    let program_data_path = std::env::var(constants::ENV_PROGRAM_DATA)
+        .unwrap_or_else(|_| String::from("c:\\ProgramData"));
+    let home = PathBuf::from(program_data_path + constants::WIN_AGENT_HOME_DIR);
+    Ok(PathResolver::new(&home))
+}
+
+#[cfg(unix)]
+fn setup(cli: &cli::Cli) -> AnyhowResult<PathResolver> {
+    LOG_AS_JSON.store(
+        cli.log_format.unwrap_or(if cli.container {
+            cli::LogFormat::Json
+        } else {
+            cli::LogFormat::Text
+        }) == cli::LogFormat::Json,
+        Ordering::Relaxed,
+    );
+    if cli.container {
+        container::enable();
+    }
+    // Switch the user before initializing logging, st. the log file belongs to the agent user.
+    // A daemon started as root opens the log file before dropping its privileges.
+    let debug_home_dir = env::var(constants::ENV_HOME_DIR);
+    let paths = match &debug_home_dir {
+        // Containers run as the user given by the image or the pod
+        _ if cli.container => container::setup_paths(),
+        // Alternative home dir can be passed for testing/debug reasons
+        Ok(debug_home_dir) => Ok(PathResolver::new(Path::new(debug_home_dir))),
+        // Normal/prod home dir
+        Err(_) => run_as_configured_user(&cli.mode),
+    };
+    let log_file_config = match (&cli.mode, &paths) {
+        // Container logs are collected from stdout
+        (cli::Mode::Daemon(_), Ok(_)) if cli.container => None,
+        (cli::Mode::Daemon(_), Ok(paths)) => Some(config::LogFileConfig::load(&paths.config_path)),
+        _ => None,
+    };
+    let stdout = cli.container && matches!(cli.mode, cli::Mode::Daemon(_));
+    if let Err(err) = init_logging(&cli.logging_level(), log_file_config.as_ref(), stdout) {
+        io::stderr()
+            .write_all(format!("Failed to initialize logging: {err:?}").as_bytes())
+            .unwrap_or(());
+    }
+    if let (Ok(debug_home_dir), false) = (debug_home_dir, cli.container) {
+        debug!(
+            "Skipping to change user and using debug HOME_DIR: {}",
+            debug_home_dir
+        );
+    }
+    paths
+}
+
+#[cfg(windows)]
+fn setup(cli: &cli::Cli) -> AnyhowResult<PathResolver> {
+    let paths = determine_paths()?;
+    let duplicate_level = match &cli.mode {
+        cli::Mode::Daemon(daemon_opts) if !daemon_opts.service_opts.service_debug => {
+            flexi_logger::Duplicate::None
+        }
+        _ => flexi_logger::Duplicate::All,
+    };
+    LOG_AS_JSON.store(
+        cli.log_format == Some(cli::LogFormat::Json),
+        Ordering::Relaxed,
+    );
+    if let Err(err) = init_logging(
+        &cli.logging_level(),
+        duplicate_level,
+        &config::LogFileConfig::load(&paths.config_path),
+    ) {
+        io::stderr()
+            .write_all(format!("Failed to initialize logging: {:?}", err).as_bytes())
+            .unwrap_or(());
+    }
+    if cli.container {
+        anyhow::bail!("Running in a container is only supported under Unix");
+    }
+    Ok(paths)
+}
+
+pub fn init(args: ArgsOs) -> AnyhowResult<(cli::Cli, PathResolver)> {
+    if !is_os_supported() {
+        eprintln!("This OS is unsupported");
+        std::process::exit(1);
+    }
+    let args: Vec<OsString> = args.collect();
+    // Like --help, but not known to clap, which insists on a subcommand
+    if args.get(1).is_some_and(|arg| arg == "--help-json") {
+        println!("{}", cli_spec::help_json());
+        std::process::exit(0);
+    }
+    // Parse args as first action to directly exit from --help or malformatted arguments
+    let cli = cli::Cli::parse_from(args);
+    messages::select_language(cli.language);
+    // The service may run under an account without administrative rights, eg. a gMSA. The
+    // service control manager already restricts who may start it.
+    #[cfg(windows)]
+    if !matches!(&cli.mode, cli::Mode::Daemon(daemon_opts) if daemon_opts.service_opts.service) {
+        misc::validate_elevation()?;
+    }
+
+    let paths = setup(&cli)?;
+    if config::fips_mode(&paths.config_path)? {
+        fips::enable()?;
+    }
+    let paths_config = config::PathsConfig::load(&paths.config_path);
+    Ok((cli, paths.with_config(&paths_config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_format() {
+        let mut buffer = vec![];
+        json_format(
+            &mut buffer,
+            &mut flexi_logger::DeferredNow::new(),
+            &log::Record::builder()
+                .args(format_args!("Pushed \"data\""))
+                .level(log::Level::Info)
+                .module_path(Some("cmk_agent_ctl::modes::push"))
+                .build(),
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["module"], "cmk_agent_ctl::modes::push");
+        assert_eq!(json["message"], "Pushed \"data\"");
+        assert!(json["timestamp"].as_f64().unwrap() > 0.0);
+    }
+
+    #[test]
+    fn test_paths() {
+        let home_dir = std::path::Path::new("/a/b/c");
+        assert_eq!(PathResolver::new(home_dir).home_dir, home_dir);
+    }
+
+    #[test]
+    fn test_paths_with_config() {
+        let home_dir = Path::new("/var/lib/cmk-agent");
+        let paths = PathResolver::new(home_dir).with_config(&config::PathsConfig::default());
+        assert_eq!(paths.state_dir, home_dir);
+        assert_eq!(paths.registry_path, home_dir.join(constants::REGISTRY_FILE));
+
+        let state_dir = Path::new("/var/lib/cmk-agent-ctl");
+        let paths = PathResolver::new(home_dir).with_config(&config::PathsConfig {
+            state_dir: Some(PathBuf::from(state_dir)),
+            push_spool_dir: Some(PathBuf::from("/var/spool/cmk-agent-ctl")),
+            control_socket: None,
+        });
+        assert_eq!(paths.config_path, home_dir.join(constants::CONFIG_FILE));
+        assert_eq!(
+            paths.registry_path,
+            state_dir.join(constants::REGISTRY_FILE)
+        );
+        assert_eq!(paths.push_spool_path, Path::new("/var/spool/cmk-agent-ctl"));
+        assert_eq!(
+            paths.control_socket_path,
+            state_dir.join(constants::CONTROL_SOCKET_FILE)
+        );
+        assert_eq!(paths.vault_path, state_dir.join(constants::VAULT_DIR));
+        assert_eq!(
+            paths.vault_key_path,
+            home_dir.join(constants::VAULT_KEY_FILE)
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_paths() {
+        let p = determine_paths().unwrap();
+        let home = String::from("C:\\ProgramData") + constants::WIN_AGENT_HOME_DIR;
+        assert_eq!(p.home_dir, std::path::PathBuf::from(&home));
+        assert_eq!(
+            p.pre_configured_connections_path,
+            std::path::PathBuf::from(&home).join("pre_configured_connections.json")
+        );
+        assert_eq!(
+            p.registry_path,
+            std::path::PathBuf::from(&home).join("registered_connections.json")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_make_log_file_spec() {
+        assert_eq!(
+            make_log_file_spec().as_pathbuf(None).to_str().unwrap(),
+            ".\\cmk-agent-ctl.log".to_owned()
+        )
+    }
+}
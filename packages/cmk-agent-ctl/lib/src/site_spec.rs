@@ -4,6 +4,7 @@
 
 use super::config::ClientConfig;
 use super::misc::anyhow_error_to_human_readable;
+use super::proxy;
 use anyhow::{bail, Context, Error as AnyhowError, Result as AnyhowResult};
 use log::{debug, info};
 use std::fmt::Display;
@@ -77,6 +78,30 @@ impl Display for SiteID {
     }
 }
 
+/// Site together with the port of its agent receiver, if known, in the format
+/// "<server>/<site>" or "<server>:<port>/<site>"
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Coordinates {
+    pub site_id: SiteID,
+    pub port: Option<u16>,
+}
+
+impl FromStr for Coordinates {
+    type Err = AnyhowError;
+
+    fn from_str(s: &str) -> AnyhowResult<Coordinates> {
+        let site_id = SiteID::from_str(s)?;
+        let server_spec = ServerSpec::from_str(&site_id.server)?;
+        Ok(Coordinates {
+            site_id: SiteID {
+                server: server_spec.server,
+                site: site_id.site,
+            },
+            port: server_spec.port,
+        })
+    }
+}
+
 pub fn make_site_url(site_id: &SiteID, port: &u16) -> AnyhowResult<reqwest::Url> {
     reqwest::Url::parse(&format!(
         "https://{}:{}/{}",
@@ -130,12 +155,13 @@ impl<'a> AgentRecvPortDiscoverer<'a> {
     }
 
     fn build_client(&self) -> reqwest::Result<reqwest::blocking::Client> {
-        let mut client_builder = reqwest::blocking::ClientBuilder::new()
-            .danger_accept_invalid_certs(!self.client_config.validate_api_cert);
-        if !self.client_config.use_proxy {
-            client_builder = client_builder.no_proxy();
-        }
-        client_builder.build()
+        proxy::configure(
+            reqwest::blocking::ClientBuilder::new()
+                .danger_accept_invalid_certs(!self.client_config.validate_api_cert)
+                .local_address(self.client_config.source_address),
+            self.client_config.proxy_mode,
+        )
+        .build()
     }
 
     pub fn discover(&self) -> AnyhowResult<u16> {
@@ -203,6 +229,40 @@ mod test_server_spec {
     }
 }
 
+#[cfg(test)]
+mod test_coordinates {
+    use super::*;
+
+    #[test]
+    fn test_from_str_ok() {
+        assert_eq!(
+            Coordinates::from_str("checkmk.server.com/awesome-site").unwrap(),
+            Coordinates {
+                site_id: SiteID::from_str("checkmk.server.com/awesome-site").unwrap(),
+                port: None,
+            }
+        );
+        assert_eq!(
+            Coordinates::from_str("checkmk.server.com:8000/awesome-site").unwrap(),
+            Coordinates {
+                site_id: SiteID::from_str("checkmk.server.com/awesome-site").unwrap(),
+                port: Some(8000),
+            }
+        );
+    }
+
+    #[test]
+    fn test_from_str_error() {
+        for erroneous_coordinates in [
+            "checkmk.server.com:8000",
+            "checkmk.server.com:port/awesome-site",
+            "checkmk.server.com:8000/awesome-site/too-much",
+        ] {
+            assert!(Coordinates::from_str(erroneous_coordinates).is_err())
+        }
+    }
+}
+
 #[cfg(test)]
 mod test_site_id {
     use super::*;
@@ -245,6 +305,7 @@ mod test_site_id {
 #[cfg(test)]
 mod test_agent_recv_port_discoverer {
     use super::*;
+    use crate::happy_eyeballs;
 
     #[test]
     fn test_url() {
@@ -255,8 +316,12 @@ mod test_agent_recv_port_discoverer {
                     site: String::from("some-site"),
                 },
                 client_config: &ClientConfig {
-                    use_proxy: false,
+                    proxy_mode: proxy::ProxyMode::Direct,
                     validate_api_cert: false,
+                    source_address: None,
+                    ip_preference: happy_eyeballs::IpPreference::Ipv6,
+                    request_headers: Default::default(),
+                    timeouts: Default::default(),
                 },
             }
             .url("http")
@@ -0,0 +1,176 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Section on the state of the controller itself, appended to the agent output it serves and
+//! pushes, st. the site can monitor the controller without a separate channel
+
+use crate::config;
+use crate::connection_stats::{
+    ConnectionCounters, ConnectionStats, CountersByConnection, PushAttemptOutcome,
+};
+use crate::constants;
+use crate::metrics;
+use crate::push_spool::PushSpool;
+use log::warn;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+const HEADER: &str = "<<<cmk_agent_ctl_status:sep(0)>>>";
+
+#[derive(Serialize)]
+struct ConnectionStatus<'a> {
+    // Empty for imported connections
+    site: &'a str,
+    uuid: String,
+    mode: &'static str,
+    /// Unix timestamp, None if the certificate cannot be parsed
+    certificate_expiry: Option<i64>,
+    /// Only for push connections
+    spool_depth: Option<usize>,
+    last_push: Option<PushAttemptOutcome>,
+    successful_pulls: u64,
+    successful_pushes: u64,
+    failed_pushes: u64,
+    tls_failures: u64,
+    last_error: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Status<'a> {
+    version: &'static str,
+    connections: Vec<ConnectionStatus<'a>>,
+}
+
+/// Render the section for all registered connections
+fn render(
+    registry: &config::Registry,
+    counters: &CountersByConnection,
+    spool_depth: impl Fn(&uuid::Uuid) -> usize,
+) -> Vec<u8> {
+    let targets = metrics::targets(registry);
+    let default_counters = ConnectionCounters::default();
+    let status = Status {
+        version: constants::VERSION,
+        connections: targets
+            .iter()
+            .map(|target| {
+                let uuid = &target.connection.uuid;
+                let counters = counters.get(uuid).unwrap_or(&default_counters);
+                ConnectionStatus {
+                    site: &target.site,
+                    uuid: uuid.to_string(),
+                    mode: target.mode,
+                    certificate_expiry: metrics::certificate_not_after(
+                        &target.connection.certificate,
+                    )
+                    .ok(),
+                    spool_depth: target.is_push().then(|| spool_depth(uuid)),
+                    last_push: counters.push_history.back().map(|attempt| attempt.outcome),
+                    successful_pulls: counters.successful_pulls,
+                    successful_pushes: counters.successful_pushes,
+                    failed_pushes: counters.failed_pushes,
+                    tls_failures: counters.tls_failures,
+                    last_error: counters.last_error.as_deref(),
+                }
+            })
+            .collect(),
+    };
+    // Serializing these types cannot fail
+    let json = serde_json::to_string(&status).unwrap_or_default();
+    format!("{HEADER}\n{json}\n").into_bytes()
+}
+
+/// Data sources of the section, see metrics::Metrics for the same data via HTTP. Shared by the
+/// pipelines of the daemon.
+#[derive(Clone)]
+pub struct StatusSection {
+    registry: Arc<Mutex<config::Registry>>,
+    connection_stats: ConnectionStats,
+    push_spool: PushSpool,
+}
+
+impl StatusSection {
+    pub fn new(
+        registry: config::Registry,
+        connection_stats: ConnectionStats,
+        push_spool: PushSpool,
+    ) -> Self {
+        Self {
+            registry: Arc::new(Mutex::new(registry)),
+            connection_stats,
+            push_spool,
+        }
+    }
+
+    pub fn render(&self) -> Vec<u8> {
+        let mut registry = match self.registry.lock() {
+            Ok(registry) => registry,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(err) = registry.refresh() {
+            warn!("Failed to refresh registry for status section. ({})", err);
+        }
+        render(&registry, &self.connection_stats.snapshot(), |uuid| {
+            self.push_spool
+                .entries(uuid)
+                .map(|entries| entries.len())
+                .unwrap_or_default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_status_section {
+    use super::*;
+    use crate::config::test_helpers::TestRegistry;
+    use crate::connection_stats::PushAttempt;
+    use crate::site_spec;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    const PUSH_UUID: &str = "99f56bbc-5965-4b34-bc70-1959ad1d32d6";
+    const IMPORTED_UUID: &str = "50611369-7a42-4c0b-927e-9a14330401fe";
+
+    #[test]
+    fn test_render() {
+        let mut r = TestRegistry::new()
+            .add_connection(&config::ConnectionMode::Push, "server/site", PUSH_UUID)
+            .add_imported_connection(IMPORTED_UUID);
+        r.registry
+            .get_connection_as_mut(&site_spec::SiteID::from_str("server/site").unwrap())
+            .unwrap()
+            .trust
+            .certificate = String::from(constants::TEST_CERT_OK);
+        let dir = tempfile::tempdir().unwrap();
+        let stats = ConnectionStats::new(dir.path().join("connection_stats.json"));
+        stats.record_push(
+            &uuid::Uuid::from_str(PUSH_UUID).unwrap(),
+            PushAttempt::failed(0, Duration::ZERO, Some(503), String::from("down")),
+        );
+
+        let output = String::from_utf8(render(&r.registry, &stats.snapshot(), |_| 3)).unwrap();
+        let (header, json) = output.split_once('\n').unwrap();
+        assert_eq!(header, HEADER);
+        let status: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(status["version"], constants::VERSION);
+        let connections = status["connections"].as_array().unwrap();
+        assert_eq!(connections.len(), 2);
+        let push = &connections[0];
+        assert_eq!(push["site"], "server/site");
+        assert_eq!(push["uuid"], PUSH_UUID);
+        assert_eq!(push["mode"], "push");
+        assert_eq!(
+            push["certificate_expiry"],
+            metrics::certificate_not_after(constants::TEST_CERT_OK).unwrap()
+        );
+        assert_eq!(push["spool_depth"], 3);
+        assert_eq!(push["last_push"], "failed");
+        assert_eq!(push["failed_pushes"], 1);
+        assert_eq!(push["last_error"], "down");
+        let imported = &connections[1];
+        assert_eq!(imported["mode"], "pull");
+        assert_eq!(imported["spool_depth"], serde_json::Value::Null);
+        assert_eq!(imported["certificate_expiry"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Restarts the long-running tasks of the daemon once they fail or panic, st. eg. a panicking
+//! pull listener does not take the push scheduler down with it. Restarts back off exponentially.
+//! A task failing too often within the restart window stops the daemon, which leaves it to the
+//! service manager. The restarts are reported by the status request of the daemon.
+
+use crate::{constants, misc};
+use anyhow::{anyhow, Result as AnyhowResult};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// A single restart of a task, as kept in its restart history
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct TaskRestart {
+    /// Unix timestamp of the failure
+    pub timestamp: u64,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct TaskReport {
+    /// Restarts since the daemon started
+    pub restarts: u64,
+    /// The most recent restarts, oldest first
+    pub history: VecDeque<TaskRestart>,
+}
+
+/// Restarts of a task within the restart window, which the delay of the next one grows with
+struct RestartPolicy {
+    backoff_base: Duration,
+    recent: VecDeque<Instant>,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            backoff_base: Duration::from_secs(constants::TASK_RESTART_BACKOFF_BASE),
+            recent: VecDeque::new(),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Delay until the next restart, None once the task failed too often
+    fn next_delay(&mut self, now: Instant) -> Option<Duration> {
+        let window = Duration::from_secs(constants::TASK_RESTART_WINDOW);
+        while self
+            .recent
+            .front()
+            .is_some_and(|restart| now.duration_since(*restart) >= window)
+        {
+            self.recent.pop_front();
+        }
+        if self.recent.len() >= constants::TASK_RESTART_LIMIT {
+            return None;
+        }
+        let delay = self
+            .backoff_base
+            .saturating_mul(2u32.saturating_pow(self.recent.len() as u32))
+            .min(Duration::from_secs(constants::TASK_RESTART_BACKOFF_MAX));
+        self.recent.push_back(now);
+        Some(delay)
+    }
+}
+
+/// Aborts the running task once its supervisor is aborted, eg. while the daemon is stopping
+struct AbortOnDrop(JoinHandle<AnyhowResult<()>>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Shared between the supervised tasks and the IPC task
+#[derive(Clone, Default)]
+pub struct Supervisor(Arc<Mutex<BTreeMap<String, TaskReport>>>);
+
+impl Supervisor {
+    fn reports(&self) -> MutexGuard<'_, BTreeMap<String, TaskReport>> {
+        match self.0.lock() {
+            Ok(reports) => reports,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn record_restart(&self, task: &str, reason: String) {
+        let mut reports = self.reports();
+        let report = reports.entry(String::from(task)).or_default();
+        report.restarts += 1;
+        report.history.push_back(TaskRestart {
+            timestamp: misc::unix_now(),
+            reason,
+        });
+        while report.history.len() > constants::TASK_RESTART_HISTORY_SIZE {
+            report.history.pop_front();
+        }
+    }
+
+    /// Restarted tasks only, keyed by task
+    pub fn report(&self) -> BTreeMap<String, TaskReport> {
+        self.reports().clone()
+    }
+
+    /// Run the task started by `start`, starting it again whenever it fails or panics. Finishes
+    /// once the task finishes successfully, or with the error of the task once it failed too
+    /// often.
+    pub fn spawn<F, Fut>(&self, task: &'static str, start: F) -> JoinHandle<AnyhowResult<()>>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = AnyhowResult<()>> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        tokio::spawn(async move {
+            supervisor
+                .supervise(task, start, RestartPolicy::default())
+                .await
+        })
+    }
+
+    async fn supervise<F, Fut>(
+        &self,
+        task: &'static str,
+        mut start: F,
+        mut policy: RestartPolicy,
+    ) -> AnyhowResult<()>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = AnyhowResult<()>> + Send + 'static,
+    {
+        loop {
+            let mut running = AbortOnDrop(tokio::spawn(start()));
+            let err = match (&mut running.0).await {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(err)) => err,
+                Err(err) => anyhow!("The {} task crashed. ({})", task, err),
+            };
+            let Some(delay) = policy.next_delay(Instant::now()) else {
+                return Err(err.context(format!(
+                    "The {} task failed more than {} times within {} seconds",
+                    task,
+                    constants::TASK_RESTART_LIMIT,
+                    constants::TASK_RESTART_WINDOW
+                )));
+            };
+            error!(
+                "The {} task failed, restarting it in {} second(s). ({:#})",
+                task,
+                delay.as_secs(),
+                err
+            );
+            self.record_restart(task, format!("{err:#}"));
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_supervisor {
+    use super::*;
+    use anyhow::bail;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_next_delay() {
+        let mut policy = RestartPolicy::default();
+        let start = Instant::now();
+        let delays: Vec<Option<Duration>> = (0..=constants::TASK_RESTART_LIMIT)
+            .map(|_| policy.next_delay(start))
+            .collect();
+        assert_eq!(
+            delays,
+            [
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(2)),
+                Some(Duration::from_secs(4)),
+                Some(Duration::from_secs(8)),
+                Some(Duration::from_secs(16)),
+                None
+            ]
+        );
+        // Restarts outside the window are forgotten
+        let later = start + Duration::from_secs(constants::TASK_RESTART_WINDOW);
+        assert_eq!(policy.next_delay(later), Some(Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn test_supervise() {
+        let supervisor = Supervisor::default();
+        let starts = Arc::new(AtomicUsize::new(0));
+        let result = supervisor
+            .supervise(
+                "test",
+                || {
+                    let starts = Arc::clone(&starts);
+                    async move {
+                        match starts.fetch_add(1, Ordering::Relaxed) {
+                            0 => panic!("crash"),
+                            1 => bail!("failure"),
+                            _ => Ok(()),
+                        }
+                    }
+                },
+                RestartPolicy {
+                    backoff_base: Duration::ZERO,
+                    recent: VecDeque::new(),
+                },
+            )
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(starts.load(Ordering::Relaxed), 3);
+        let report = supervisor.report();
+        assert_eq!(report["test"].restarts, 2);
+        assert!(report["test"].history[0]
+            .reason
+            .starts_with("The test task crashed."));
+        assert_eq!(report["test"].history[1].reason, "failure");
+    }
+
+    #[tokio::test]
+    async fn test_supervise_gives_up() {
+        let result = Supervisor::default()
+            .supervise(
+                "test",
+                || async { Err::<(), _>(anyhow!("failure")) },
+                RestartPolicy {
+                    backoff_base: Duration::ZERO,
+                    recent: VecDeque::new(),
+                },
+            )
+            .await;
+        assert_eq!(
+            format!("{:#}", result.unwrap_err()),
+            format!(
+                "The test task failed more than {} times within {} seconds: failure",
+                constants::TASK_RESTART_LIMIT,
+                constants::TASK_RESTART_WINDOW
+            )
+        );
+    }
+}
@@ -0,0 +1,373 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Log writers for the logging facilities of the operating system: journald and syslog under
+//! Unix, the Event Log under Windows.
+
+use super::config::LogBackend;
+use super::constants::system_log::IDENTIFIER;
+#[cfg(unix)]
+use super::constants::system_log::{JOURNALD_SOCKET, SYSLOG_FACILITY_DAEMON, SYSLOG_SOCKETS};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use flexi_logger::writers::LogWriter;
+use flexi_logger::DeferredNow;
+use log::Record;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+pub fn make_writer(backend: LogBackend) -> AnyhowResult<Box<dyn LogWriter>> {
+    match backend {
+        #[cfg(unix)]
+        LogBackend::Journald => Ok(Box::new(
+            JournaldWriter::new(Path::new(JOURNALD_SOCKET))
+                .context("Failed to connect to journald")?,
+        )),
+        #[cfg(unix)]
+        LogBackend::Syslog => Ok(Box::new(
+            SyslogWriter::new(
+                SYSLOG_SOCKETS
+                    .iter()
+                    .map(Path::new)
+                    .find(|path| path.exists())
+                    .context("Failed to connect to syslog: no syslog socket found")?,
+            )
+            .context("Failed to connect to syslog")?,
+        )),
+        #[cfg(windows)]
+        LogBackend::EventLog => Ok(Box::new(
+            EventLogWriter::new().context("Failed to register Event Log source")?,
+        )),
+        #[allow(unreachable_patterns)]
+        _ => bail!(
+            "Log backend {:?} is not available on this platform",
+            backend
+        ),
+    }
+}
+
+fn module<'a>(record: &Record<'a>) -> &'a str {
+    record.module_path().unwrap_or("<unnamed>")
+}
+
+/// The socket is not connected, st. logging continues after the log daemon was restarted
+#[cfg(unix)]
+fn unbound_socket(path: &Path) -> std::io::Result<UnixDatagram> {
+    if !path.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} does not exist", path.display()),
+        ));
+    }
+    UnixDatagram::unbound()
+}
+
+/// Severity as defined by RFC 5424, which is also used by journald
+#[cfg(unix)]
+fn syslog_severity(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => 3,
+        log::Level::Warn => 4,
+        log::Level::Info => 6,
+        log::Level::Debug | log::Level::Trace => 7,
+    }
+}
+
+/// Writes to journald using its native protocol, st. log levels and source locations end up
+/// in dedicated journal fields
+#[cfg(unix)]
+pub struct JournaldWriter {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl JournaldWriter {
+    fn new(path: &Path) -> std::io::Result<JournaldWriter> {
+        Ok(JournaldWriter {
+            socket: unbound_socket(path)?,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+#[cfg(unix)]
+fn append_journal_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    entry.extend_from_slice(key.as_bytes());
+    // Values containing newlines have to be sent length-prefixed
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+#[cfg(unix)]
+fn journal_entry(record: &Record) -> Vec<u8> {
+    let mut entry = vec![];
+    append_journal_field(
+        &mut entry,
+        "PRIORITY",
+        &syslog_severity(record.level()).to_string(),
+    );
+    append_journal_field(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    append_journal_field(&mut entry, "MESSAGE", &record.args().to_string());
+    append_journal_field(&mut entry, "CODE_MODULE", module(record));
+    if let Some(file) = record.file() {
+        append_journal_field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        append_journal_field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+    entry
+}
+
+#[cfg(unix)]
+impl LogWriter for JournaldWriter {
+    fn write(&self, _now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+        self.socket
+            .send_to(&journal_entry(record), &self.path)
+            .map(|_| ())
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn max_log_level(&self) -> log::LevelFilter {
+        log::LevelFilter::Trace
+    }
+}
+
+/// Writes RFC 3164 messages to the local syslog socket with facility daemon
+#[cfg(unix)]
+pub struct SyslogWriter {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl SyslogWriter {
+    fn new(path: &Path) -> std::io::Result<SyslogWriter> {
+        Ok(SyslogWriter {
+            socket: unbound_socket(path)?,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+#[cfg(unix)]
+fn syslog_message(record: &Record, pid: u32) -> String {
+    // The syslog daemon adds the timestamp and the hostname
+    format!(
+        "<{}>{}[{}]: [{}] {}",
+        SYSLOG_FACILITY_DAEMON * 8 + syslog_severity(record.level()),
+        IDENTIFIER,
+        pid,
+        module(record),
+        record.args()
+    )
+}
+
+#[cfg(unix)]
+impl LogWriter for SyslogWriter {
+    fn write(&self, _now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+        self.socket
+            .send_to(
+                syslog_message(record, std::process::id()).as_bytes(),
+                &self.path,
+            )
+            .map(|_| ())
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn max_log_level(&self) -> log::LevelFilter {
+        log::LevelFilter::Trace
+    }
+}
+
+/// Event IDs by log level, st. events can be filtered in the Event Viewer
+#[cfg(windows)]
+fn event_id(level: log::Level) -> u32 {
+    match level {
+        log::Level::Error => 1,
+        log::Level::Warn => 2,
+        log::Level::Info => 3,
+        log::Level::Debug | log::Level::Trace => 4,
+    }
+}
+
+#[cfg(windows)]
+fn event_type(level: log::Level) -> u16 {
+    use winapi::um::winnt;
+    match level {
+        log::Level::Error => winnt::EVENTLOG_ERROR_TYPE,
+        log::Level::Warn => winnt::EVENTLOG_WARNING_TYPE,
+        log::Level::Info | log::Level::Debug | log::Level::Trace => {
+            winnt::EVENTLOG_INFORMATION_TYPE
+        }
+    }
+}
+
+#[cfg(windows)]
+fn to_wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+/// Writes to the Application log with the event source cmk-agent-ctl. Without a message file
+/// registered for this source, the Event Viewer shows the message as insertion string.
+#[cfg(windows)]
+pub struct EventLogWriter {
+    handle: winapi::um::winnt::HANDLE,
+}
+
+// The event log handle may be used from any thread
+#[cfg(windows)]
+unsafe impl Send for EventLogWriter {}
+#[cfg(windows)]
+unsafe impl Sync for EventLogWriter {}
+
+#[cfg(windows)]
+impl EventLogWriter {
+    fn new() -> std::io::Result<EventLogWriter> {
+        let source = to_wide(IDENTIFIER);
+        let handle =
+            unsafe { winapi::um::winbase::RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+        if handle.is_null() {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(EventLogWriter { handle })
+    }
+}
+
+#[cfg(windows)]
+impl Drop for EventLogWriter {
+    fn drop(&mut self) {
+        unsafe { winapi::um::winbase::DeregisterEventSource(self.handle) };
+    }
+}
+
+#[cfg(windows)]
+impl LogWriter for EventLogWriter {
+    fn write(&self, _now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+        let message = to_wide(&format!("[{}] {}", module(record), record.args()));
+        let mut strings = [message.as_ptr()];
+        let reported = unsafe {
+            winapi::um::winbase::ReportEventW(
+                self.handle,
+                event_type(record.level()),
+                0,
+                event_id(record.level()),
+                std::ptr::null_mut(),
+                1,
+                0,
+                strings.as_mut_ptr(),
+                std::ptr::null_mut(),
+            )
+        };
+        if reported == 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn max_log_level(&self) -> log::LevelFilter {
+        log::LevelFilter::Trace
+    }
+}
+
+#[cfg(test)]
+#[cfg(unix)]
+mod test_system_log {
+    use super::*;
+
+    fn record(args: std::fmt::Arguments<'_>, level: log::Level) -> Record<'_> {
+        Record::builder()
+            .args(args)
+            .level(level)
+            .module_path(Some("cmk_agent_ctl::modes::push"))
+            .file(Some("src/modes/push.rs"))
+            .line(Some(42))
+            .build()
+    }
+
+    #[test]
+    fn test_journal_entry() {
+        assert_eq!(
+            journal_entry(&record(format_args!("Pushed data"), log::Level::Warn)),
+            b"PRIORITY=4\n\
+              SYSLOG_IDENTIFIER=cmk-agent-ctl\n\
+              MESSAGE=Pushed data\n\
+              CODE_MODULE=cmk_agent_ctl::modes::push\n\
+              CODE_FILE=src/modes/push.rs\n\
+              CODE_LINE=42\n"
+        );
+    }
+
+    #[test]
+    fn test_journal_field_with_newline() {
+        let mut entry = vec![];
+        append_journal_field(&mut entry, "MESSAGE", "a\nb");
+        assert_eq!(entry, b"MESSAGE\n\x03\x00\x00\x00\x00\x00\x00\x00a\nb\n");
+    }
+
+    #[test]
+    fn test_syslog_message() {
+        assert_eq!(
+            syslog_message(&record(format_args!("Failed"), log::Level::Error), 123),
+            "<27>cmk-agent-ctl[123]: [cmk_agent_ctl::modes::push] Failed"
+        );
+        assert_eq!(
+            syslog_message(&record(format_args!("Pushing"), log::Level::Debug), 123),
+            "<31>cmk-agent-ctl[123]: [cmk_agent_ctl::modes::push] Pushing"
+        );
+    }
+
+    #[test]
+    fn test_writers_send_datagrams() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let mut buffer = [0; 1024];
+
+        JournaldWriter::new(&path)
+            .unwrap()
+            .write(
+                &mut DeferredNow::new(),
+                &record(format_args!("Journal"), log::Level::Info),
+            )
+            .unwrap();
+        let size = receiver.recv(&mut buffer).unwrap();
+        assert!(buffer[..size].starts_with(b"PRIORITY=6\n"));
+
+        SyslogWriter::new(&path)
+            .unwrap()
+            .write(
+                &mut DeferredNow::new(),
+                &record(format_args!("Syslog"), log::Level::Info),
+            )
+            .unwrap();
+        let size = receiver.recv(&mut buffer).unwrap();
+        assert!(std::str::from_utf8(&buffer[..size])
+            .unwrap()
+            .ends_with("] Syslog"));
+    }
+
+    #[test]
+    fn test_unavailable_backend() {
+        assert!(make_writer(LogBackend::EventLog).is_err());
+        assert!(JournaldWriter::new(Path::new("/nonexistent/journal.sock")).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Diagnosis of failed TLS handshakes. rustls (pull, QUIC) and OpenSSL (push, registration) both
+//! report them in terms of the protocol, eg. "received fatal alert: BadCertificate", which leaves
+//! users guessing what to do about it.
+
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsFailure {
+    /// The certificate of the peer has expired
+    ExpiredCertificate,
+    /// The peer rejected the certificate of the controller as expired
+    ExpiredClientCertificate,
+    /// A certificate is not valid yet, which means that one of the clocks is off
+    ClockSkew,
+    /// The certificate of the peer is not issued by a trusted CA
+    UnknownCa,
+    /// The peer does not accept the certificate of the controller
+    ClientCertificateRejected,
+    /// The certificate of the peer was issued for another name
+    WrongServerName,
+    /// The peers have no TLS version in common
+    ProtocolVersion,
+}
+
+/// Fragments of the messages of OpenSSL, as passed on by native-tls and reqwest. These are
+/// neither typed nor stable, but have not changed in years.
+const OPENSSL_MESSAGES: [(&str, TlsFailure); 14] = [
+    (
+        "alert certificate expired",
+        TlsFailure::ExpiredClientCertificate,
+    ),
+    ("certificate has expired", TlsFailure::ExpiredCertificate),
+    ("certificate is not yet valid", TlsFailure::ClockSkew),
+    ("alert unknown ca", TlsFailure::ClientCertificateRejected),
+    (
+        "alert bad certificate",
+        TlsFailure::ClientCertificateRejected,
+    ),
+    (
+        "alert certificate unknown",
+        TlsFailure::ClientCertificateRejected,
+    ),
+    (
+        "unable to get local issuer certificate",
+        TlsFailure::UnknownCa,
+    ),
+    ("self-signed certificate", TlsFailure::UnknownCa),
+    ("self signed certificate", TlsFailure::UnknownCa),
+    ("hostname mismatch", TlsFailure::WrongServerName),
+    ("unrecognized name", TlsFailure::WrongServerName),
+    ("wrong version number", TlsFailure::ProtocolVersion),
+    ("unsupported protocol", TlsFailure::ProtocolVersion),
+    ("alert protocol version", TlsFailure::ProtocolVersion),
+];
+
+impl TlsFailure {
+    /// Diagnose the TLS failure an error was caused by, if any
+    pub fn of(error: &anyhow::Error) -> Option<Self> {
+        error
+            .chain()
+            .find_map(Self::of_rustls)
+            .or_else(|| error.chain().find_map(Self::of_openssl))
+    }
+
+    fn of_rustls(cause: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        // rustls reports through IO errors, which do not expose it as their source
+        let error = cause.downcast_ref::<rustls::Error>().or_else(|| {
+            cause
+                .downcast_ref::<std::io::Error>()?
+                .get_ref()?
+                .downcast_ref::<rustls::Error>()
+        })?;
+        match error {
+            rustls::Error::InvalidCertificate(error) => match error {
+                rustls::CertificateError::Expired => Some(Self::ExpiredCertificate),
+                rustls::CertificateError::NotValidYet => Some(Self::ClockSkew),
+                rustls::CertificateError::UnknownIssuer
+                | rustls::CertificateError::BadSignature => Some(Self::UnknownCa),
+                rustls::CertificateError::NotValidForName => Some(Self::WrongServerName),
+                _ => None,
+            },
+            rustls::Error::AlertReceived(alert) => match alert {
+                rustls::AlertDescription::CertificateExpired => {
+                    Some(Self::ExpiredClientCertificate)
+                }
+                rustls::AlertDescription::UnknownCA
+                | rustls::AlertDescription::BadCertificate
+                | rustls::AlertDescription::CertificateUnknown => {
+                    Some(Self::ClientCertificateRejected)
+                }
+                rustls::AlertDescription::UnrecognisedName => Some(Self::WrongServerName),
+                rustls::AlertDescription::ProtocolVersion => Some(Self::ProtocolVersion),
+                _ => None,
+            },
+            rustls::Error::PeerIncompatible(
+                rustls::PeerIncompatible::ServerDoesNotSupportTls12Or13
+                | rustls::PeerIncompatible::ServerTlsVersionIsDisabledByOurConfig
+                | rustls::PeerIncompatible::SupportedVersionsExtensionRequired
+                | rustls::PeerIncompatible::Tls12NotOffered
+                | rustls::PeerIncompatible::Tls12NotOfferedOrEnabled
+                | rustls::PeerIncompatible::Tls13RequiredForQuic,
+            ) => Some(Self::ProtocolVersion),
+            _ => None,
+        }
+    }
+
+    /// OpenSSL errors are only passed on as messages
+    pub fn of_openssl(cause: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        let message = cause.to_string().to_lowercase();
+        OPENSSL_MESSAGES
+            .iter()
+            .find(|(fragment, _)| message.contains(fragment))
+            .map(|(_, failure)| *failure)
+    }
+
+    pub fn guidance(&self) -> &'static str {
+        match self {
+            Self::ExpiredCertificate => {
+                "Check the system clock. If it is correct, the certificate has to be renewed on \
+                 the other side."
+            }
+            Self::ExpiredClientCertificate => {
+                "Renew the certificate with 'cmk-agent-ctl renew-certificate' or register again."
+            }
+            Self::ClockSkew => {
+                "Synchronize the system clocks of this host and the Checkmk site, eg. via NTP."
+            }
+            Self::UnknownCa => {
+                "Register again to trust the current CA of the site. If a proxy inspects TLS, \
+                 exempt the agent receiver from it."
+            }
+            Self::ClientCertificateRejected => {
+                "Register again. The site does not know this connection, eg. because it was \
+                 restored from a backup or its CA was replaced."
+            }
+            Self::WrongServerName => {
+                "Connect via the name the certificate was issued for, which is usually the name \
+                 the connection was registered with."
+            }
+            Self::ProtocolVersion => {
+                "Make sure that everything between this host and the site supports TLS 1.2 or \
+                 1.3, eg. load balancers terminating TLS."
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for TlsFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::ExpiredCertificate => "The certificate of the other side has expired",
+                Self::ExpiredClientCertificate => "The certificate of this connection has expired",
+                Self::ClockSkew => "The certificate is not valid yet, the clocks disagree",
+                Self::UnknownCa => "The certificate of the other side is issued by an unknown CA",
+                Self::ClientCertificateRejected =>
+                    "The other side does not accept the certificate of this connection",
+                Self::WrongServerName => "The certificate was issued for another name",
+                Self::ProtocolVersion => "There is no TLS version supported by both sides",
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod test_tls_failure {
+    use super::*;
+    use anyhow::anyhow;
+
+    fn of_rustls(error: rustls::Error) -> Option<TlsFailure> {
+        TlsFailure::of(
+            &anyhow::Error::from(std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+                .context("Handshake failed"),
+        )
+    }
+
+    #[test]
+    fn test_rustls() {
+        assert_eq!(
+            of_rustls(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::Expired
+            )),
+            Some(TlsFailure::ExpiredCertificate)
+        );
+        assert_eq!(
+            of_rustls(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::NotValidYet
+            )),
+            Some(TlsFailure::ClockSkew)
+        );
+        assert_eq!(
+            of_rustls(rustls::Error::AlertReceived(
+                rustls::AlertDescription::CertificateExpired
+            )),
+            Some(TlsFailure::ExpiredClientCertificate)
+        );
+        assert_eq!(
+            of_rustls(rustls::Error::AlertReceived(
+                rustls::AlertDescription::UnknownCA
+            )),
+            Some(TlsFailure::ClientCertificateRejected)
+        );
+        assert_eq!(
+            of_rustls(rustls::Error::PeerIncompatible(
+                rustls::PeerIncompatible::ServerDoesNotSupportTls12Or13
+            )),
+            Some(TlsFailure::ProtocolVersion)
+        );
+        assert_eq!(
+            of_rustls(rustls::Error::AlertReceived(
+                rustls::AlertDescription::DecodeError
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn test_openssl() {
+        let of_message = |message: &str| {
+            TlsFailure::of(&anyhow!(message.to_string()).context("error sending request"))
+        };
+        assert_eq!(
+            of_message(
+                "error:0A000086:SSL routines:tls_post_process_server_certificate:certificate \
+                 verify failed:ssl/statem/statem_clnt.c:1889: (certificate has expired)"
+            ),
+            Some(TlsFailure::ExpiredCertificate)
+        );
+        assert_eq!(
+            of_message(
+                "error:0A000086:SSL routines:tls_post_process_server_certificate:certificate \
+                 verify failed:ssl/statem/statem_clnt.c:1889: (unable to get local issuer \
+                 certificate)"
+            ),
+            Some(TlsFailure::UnknownCa)
+        );
+        assert_eq!(
+            of_message(
+                "error:0A000415:SSL routines:ssl3_read_bytes:sslv3 alert certificate \
+                 expired:ssl/record/rec_layer_s3.c:1584:SSL alert number 45"
+            ),
+            Some(TlsFailure::ExpiredClientCertificate)
+        );
+        assert_eq!(
+            of_message(
+                "error:0A00010B:SSL routines:ssl3_get_record:wrong version \
+                 number:ssl/record/ssl3_record.c:354:"
+            ),
+            Some(TlsFailure::ProtocolVersion)
+        );
+        assert_eq!(of_message("Connection refused"), None);
+    }
+}
@@ -0,0 +1,356 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::data_source::DataSource;
+use super::{certs, config, fips};
+use anyhow::{bail, Context, Result as AnyhowResult};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio_rustls::rustls::{
+    server::AllowAnyAuthenticatedClient, server::ClientCertVerified, server::ClientCertVerifier,
+    server::ResolvesServerCertUsingSni, sign::CertifiedKey, sign::RsaSigningKey, Certificate,
+    Error as RusttlsError, RootCertStore, ServerConfig,
+};
+
+#[cfg(windows)]
+use std::io::{Read, Result as IoResult, Write};
+
+/// TLS setup for serving pull requests to the currently registered pull connections.
+pub struct PullTls {
+    pub server_config: Arc<ServerConfig>,
+    pub authorizer: PullAuthorizer,
+    data_sources: HashMap<uuid::Uuid, DataSource>,
+}
+
+impl PullTls {
+    /// The data source of the given connection, None for the agent socket
+    pub fn data_source(&self, uuid: &uuid::Uuid) -> Option<&DataSource> {
+        self.data_sources.get(uuid)
+    }
+}
+
+pub fn pull_tls<'a>(
+    connections: impl Iterator<Item = &'a config::TrustedConnection>,
+) -> AnyhowResult<PullTls> {
+    let connections: Vec<&config::TrustedConnection> = connections.collect();
+    Ok(PullTls {
+        server_config: tls_config(connections.iter().copied())?,
+        data_sources: connections
+            .iter()
+            .filter_map(|conn| Some((conn.uuid, conn.data_source.clone()?)))
+            .collect(),
+        authorizer: PullAuthorizer::from_connections(connections.into_iter())?,
+    })
+}
+
+fn tls_config<'a>(
+    connections: impl Iterator<Item = &'a config::TrustedConnection>,
+) -> AnyhowResult<Arc<ServerConfig>> {
+    let connections: Vec<&config::TrustedConnection> = connections.collect();
+    Ok(Arc::new(
+        fips::tls_defaults(ServerConfig::builder())?
+            .with_client_cert_verifier(CNNoUUIDVerifier::from_roots(
+                certs::root_cert_store(connections.iter().map(|it| it.root_cert.as_str()))?,
+                local_certificates(connections.iter().copied())?,
+            ))
+            .with_cert_resolver(sni_resolver(connections.into_iter())?),
+    ))
+}
+
+/// The certificates of the given connections. These are accepted as client certificates despite
+/// their UUID CN, st. the controller can pull from itself (see the pull-once mode).
+fn local_certificates<'a>(
+    connections: impl Iterator<Item = &'a config::TrustedConnection>,
+) -> AnyhowResult<Vec<Certificate>> {
+    connections
+        .map(|conn| certs::rustls_certificate(&conn.certificate))
+        .collect()
+}
+
+struct CNNoUUIDVerifier {
+    verifier: Arc<dyn ClientCertVerifier>,
+    local_certificates: Vec<Certificate>,
+}
+
+impl CNNoUUIDVerifier {
+    pub fn from_roots(
+        roots: RootCertStore,
+        local_certificates: Vec<Certificate>,
+    ) -> Arc<dyn ClientCertVerifier> {
+        Arc::new(Self {
+            verifier: AllowAnyAuthenticatedClient::new(roots).boxed(),
+            local_certificates,
+        })
+    }
+}
+
+impl ClientCertVerifier for CNNoUUIDVerifier {
+    fn client_auth_root_subjects(
+        &self,
+    ) -> &[tokio_rustls::rustls::internal::msgs::handshake::DistinguishedName] {
+        self.verifier.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: std::time::SystemTime,
+    ) -> Result<ClientCertVerified, RusttlsError> {
+        let cn_checker = certs::CNCheckerUUID::try_from(end_entity)?;
+        if cn_checker.cn_is_uuid() && !self.local_certificates.contains(end_entity) {
+            return Err(RusttlsError::General(format!(
+                "CN in client certificate is a valid UUID: {}",
+                cn_checker.cn()
+            )));
+        }
+        self.verifier
+            .verify_client_cert(end_entity, intermediates, now)
+    }
+}
+
+/// Maps each registered pull connection to a verifier which only trusts the root certificate of
+/// this very connection. The TLS acceptor verifies client certificates against the union of all
+/// registered root certificates, which on its own would allow a site to pull data via a
+/// connection registered with another site.
+pub struct PullAuthorizer {
+    verifiers: HashMap<uuid::Uuid, Arc<dyn ClientCertVerifier>>,
+}
+
+impl PullAuthorizer {
+    pub fn from_connections<'a>(
+        connections: impl Iterator<Item = &'a config::TrustedConnection>,
+    ) -> AnyhowResult<Self> {
+        let mut verifiers = HashMap::new();
+        for conn in connections {
+            verifiers.insert(
+                conn.uuid,
+                CNNoUUIDVerifier::from_roots(
+                    certs::root_cert_store([conn.root_cert.as_str()].into_iter())?,
+                    local_certificates([conn].into_iter())?,
+                ),
+            );
+        }
+        Ok(Self { verifiers })
+    }
+
+    /// Check that the client certificate chain presented during the handshake was issued by the
+    /// root of the connection which was requested via SNI. Returns the UUID of this connection.
+    pub fn authorize(
+        &self,
+        server_name: Option<&str>,
+        peer_certificates: Option<&[Certificate]>,
+    ) -> AnyhowResult<uuid::Uuid> {
+        let server_name = server_name.context("Client did not request a connection (no SNI)")?;
+        let uuid = uuid::Uuid::parse_str(server_name).context(format!(
+            "Requested connection is not a valid UUID: {server_name}"
+        ))?;
+        let Some(verifier) = self.verifiers.get(&uuid) else {
+            bail!("Requested connection {} is not registered", uuid)
+        };
+        let Some((end_entity, intermediates)) = peer_certificates.and_then(|c| c.split_first())
+        else {
+            bail!(
+                "Client did not present a certificate for connection {}",
+                uuid
+            )
+        };
+        verifier
+            .verify_client_cert(end_entity, intermediates, std::time::SystemTime::now())
+            .context(format!(
+                "Client certificate was not issued by the root of connection {uuid}"
+            ))?;
+        Ok(uuid)
+    }
+}
+
+fn sni_resolver<'a>(
+    connections: impl Iterator<Item = &'a config::TrustedConnection>,
+) -> AnyhowResult<Arc<ResolvesServerCertUsingSni>> {
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+
+    for conn in connections {
+        let key = certs::rustls_private_key(&conn.private_key)?;
+        let cert = certs::rustls_certificate(&conn.certificate)?;
+
+        let certified_key = CertifiedKey::new(vec![cert], Arc::new(RsaSigningKey::new(&key)?));
+
+        resolver.add(&conn.uuid.to_string(), certified_key)?;
+    }
+
+    Ok(Arc::new(resolver))
+}
+
+#[cfg(windows)]
+pub struct IoStream {
+    // Windows Agent will not use stdio/stdin as a communication channel
+    // This is just temporary stub to keep API more or less in sync.
+    reader: std::io::Stdin,
+    writer: std::io::Stdout,
+}
+
+#[cfg(windows)]
+impl Read for IoStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let mut handle = self.reader.lock();
+        handle.read(buf)
+    }
+}
+
+#[cfg(windows)]
+impl Write for IoStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let mut handle = self.writer.lock();
+        handle.write(buf)
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        let mut handle = self.writer.lock();
+        handle.flush()
+    }
+}
+
+#[cfg(test)]
+mod test_cn_no_uuid_verifier {
+    use super::super::constants;
+    use super::*;
+
+    fn verifier() -> Arc<dyn ClientCertVerifier> {
+        CNNoUUIDVerifier::from_roots(
+            certs::root_cert_store([constants::TEST_ROOT_CERT].into_iter()).unwrap(),
+            vec![],
+        )
+    }
+
+    #[test]
+    fn test_verify_client_cert_ok() {
+        assert!(verifier()
+            .verify_client_cert(
+                &certs::rustls_certificate(constants::TEST_CERT_OK).unwrap(),
+                &[],
+                std::time::SystemTime::now(),
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_verify_client_cert_cn_is_uuid() {
+        assert_eq!(
+            match verifier()
+                .verify_client_cert(
+                    &certs::rustls_certificate(constants::TEST_CERT_CN_UUID).unwrap(),
+                    &[],
+                    std::time::SystemTime::now(),
+                )
+                .unwrap_err()
+            {
+                rustls::Error::General(s) => s,
+                _ => panic!("Wrong error type"),
+            },
+            "CN in client certificate is a valid UUID: cf771eeb-b666-4673-95c9-683960fb2939"
+        )
+    }
+
+    #[test]
+    fn test_verify_client_cert_cn_is_uuid_local_certificate() {
+        let cert = certs::rustls_certificate(constants::TEST_CERT_CN_UUID).unwrap();
+        assert!(CNNoUUIDVerifier::from_roots(
+            certs::root_cert_store([constants::TEST_ROOT_CERT].into_iter()).unwrap(),
+            vec![cert.clone()],
+        )
+        .verify_client_cert(&cert, &[], std::time::SystemTime::now())
+        .is_ok());
+    }
+
+    #[test]
+    fn test_verify_client_cert_invalid_signature() {
+        assert!(verifier()
+            .verify_client_cert(
+                &certs::rustls_certificate(constants::TEST_CERT_INVALID_SIGNATURE).unwrap(),
+                &[],
+                std::time::SystemTime::now(),
+            )
+            .is_err(),);
+    }
+}
+
+#[cfg(test)]
+mod test_pull_authorizer {
+    use super::super::constants;
+    use super::*;
+
+    const UUID: &str = "99f56bbc-5965-4b34-bc70-1959ad1d32d6";
+
+    fn authorizer() -> PullAuthorizer {
+        PullAuthorizer::from_connections(
+            [config::TrustedConnection {
+                uuid: uuid::Uuid::parse_str(UUID).unwrap(),
+                private_key: String::from("private_key"),
+                certificate: String::from(constants::TEST_CERT_CN_UUID),
+                root_cert: String::from(constants::TEST_ROOT_CERT),
+                source_address: None,
+                receiver_protocol: Default::default(),
+                transport: Default::default(),
+                data_source: None,
+            }]
+            .iter(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_authorize_ok() {
+        assert_eq!(
+            authorizer()
+                .authorize(
+                    Some(UUID),
+                    Some(&[certs::rustls_certificate(constants::TEST_CERT_OK).unwrap()]),
+                )
+                .unwrap()
+                .to_string(),
+            UUID
+        );
+    }
+
+    #[test]
+    fn test_authorize_local_certificate() {
+        assert!(authorizer()
+            .authorize(
+                Some(UUID),
+                Some(&[certs::rustls_certificate(constants::TEST_CERT_CN_UUID).unwrap()]),
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_authorize_unknown_connection() {
+        assert!(authorizer()
+            .authorize(
+                Some("cf771eeb-b666-4673-95c9-683960fb2939"),
+                Some(&[certs::rustls_certificate(constants::TEST_CERT_OK).unwrap()]),
+            )
+            .is_err());
+        assert!(authorizer()
+            .authorize(
+                None,
+                Some(&[certs::rustls_certificate(constants::TEST_CERT_OK).unwrap()]),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_authorize_no_client_certificate() {
+        assert!(authorizer().authorize(Some(UUID), None).is_err());
+        assert!(authorizer().authorize(Some(UUID), Some(&[])).is_err());
+    }
+
+    #[test]
+    fn test_authorize_certificate_from_other_site() {
+        assert!(authorizer()
+            .authorize(
+                Some(UUID),
+                Some(&[certs::rustls_certificate(constants::TEST_CERT_INVALID_SIGNATURE).unwrap()]),
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! The ways of reaching the agent receiver, chosen per registered connection. Push and status
+//! call the receiver through the Transport of the connection, which `Transports` selects. Pulls
+//! are served via the pull port for every transport, additionally via QUIC for QUIC connections
+//! and through a tunnel for WebSocket connections.
+//!
+//! A new transport needs a variant of TransportKind, an implementation of Transport and a place in
+//! `Transports`, the modes stay as they are.
+
+use crate::agent_receiver_api::{
+    self, AgentData, AgentDataChunk, AgentDataDelta, ChunkedUpload, RegistrationStatusV2,
+    RegistrationStatusV2Response, RegistrationStatusV2ResponseRegistered,
+};
+use crate::{config, fips, quic};
+use anyhow::Result as AnyhowResult;
+use clap::ValueEnum;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    /// HTTPS, with HTTP/2 if the receiver offers it
+    #[default]
+    Https,
+    /// HTTP/2 only, for gateways which speak nothing else
+    Http2,
+    /// HTTPS, and pulls through a tunnel instead of via the pull port only, like with the setting
+    /// "pull_tunnel" for all connections
+    #[value(name = "websocket")]
+    WebSocket,
+    /// QUIC for pushes and pulls, HTTPS for everything else and whenever the receiver does not
+    /// accept QUIC
+    Quic,
+    /// Nothing is sent, pushes are dropped. For trying out the controller without a site.
+    Mock,
+}
+
+impl TransportKind {
+    pub fn is_https(&self) -> bool {
+        *self == Self::Https
+    }
+}
+
+impl std::fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.to_possible_value() {
+            Some(value) => write!(f, "{}", value.get_name()),
+            None => write!(f, "{self:?}"),
+        }
+    }
+}
+
+/// The calls of the push and the status to the agent receiver
+pub trait Transport:
+    AgentData + AgentDataDelta + AgentDataChunk + RegistrationStatusV2 + Send + Sync
+{
+}
+
+impl<T> Transport for T where
+    T: AgentData + AgentDataDelta + AgentDataChunk + RegistrationStatusV2 + Send + Sync
+{
+}
+
+/// Accepts everything without sending anything. The connection is reported as registered to this
+/// host, st. it is not registered again.
+pub struct MockTransport;
+
+impl AgentData for MockTransport {
+    fn agent_data(
+        &self,
+        base_url: &reqwest::Url,
+        _connection: &config::TrustedConnection,
+        compression_algorithm: &str,
+        monitoring_data: &[u8],
+        _collected_at: u64,
+    ) -> AnyhowResult<()> {
+        debug!(
+            "{}: Dropping {} bytes of agent output ({})",
+            base_url,
+            monitoring_data.len(),
+            compression_algorithm
+        );
+        Ok(())
+    }
+}
+
+impl AgentDataDelta for MockTransport {
+    fn agent_data_delta(
+        &self,
+        base_url: &reqwest::Url,
+        _connection: &config::TrustedConnection,
+        _compression_algorithm: &str,
+        delta: &[u8],
+        _collected_at: u64,
+    ) -> AnyhowResult<()> {
+        debug!("{}: Dropping delta of {} bytes", base_url, delta.len());
+        Ok(())
+    }
+}
+
+impl AgentDataChunk for MockTransport {
+    fn agent_data_chunk(
+        &self,
+        _base_url: &reqwest::Url,
+        _connection: &config::TrustedConnection,
+        _upload: &ChunkedUpload,
+        offset: u64,
+        chunk: &[u8],
+    ) -> AnyhowResult<u64> {
+        Ok(offset + chunk.len() as u64)
+    }
+}
+
+impl RegistrationStatusV2 for MockTransport {
+    fn registration_status_v2(
+        &self,
+        _base_url: &reqwest::Url,
+        _connection: &config::TrustedConnection,
+    ) -> AnyhowResult<RegistrationStatusV2Response> {
+        Ok(RegistrationStatusV2Response::Registered(
+            RegistrationStatusV2ResponseRegistered {
+                hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+                connection_mode: config::ConnectionMode::Push,
+            },
+        ))
+    }
+}
+
+/// One instance of every transport, each connection is served by the one it was registered with
+pub struct Transports {
+    https: agent_receiver_api::Api,
+    http2: agent_receiver_api::Api,
+    quic: quic::PushApi,
+    mock: MockTransport,
+    /// The setting "quic", which applies to all HTTPS connections
+    quic_by_default: bool,
+}
+
+impl Transports {
+    /// All transports calling the receiver API are configured alike
+    pub fn new(api: impl Fn() -> agent_receiver_api::Api, quic_by_default: bool) -> Self {
+        Self {
+            https: api(),
+            http2: api().with_http2_only(true),
+            // QUIC runs on rustls only, see fips.rs
+            quic: quic::PushApi::new(api(), !fips::enabled()),
+            mock: MockTransport,
+            quic_by_default,
+        }
+    }
+
+    pub fn for_connection(&self, connection: &config::TrustedConnection) -> &dyn Transport {
+        match connection.transport {
+            TransportKind::Https | TransportKind::WebSocket if self.quic_by_default => &self.quic,
+            TransportKind::Https | TransportKind::WebSocket => &self.https,
+            TransportKind::Http2 => &self.http2,
+            TransportKind::Quic => &self.quic,
+            TransportKind::Mock => &self.mock,
+        }
+    }
+}
+
+impl AgentData for Transports {
+    fn agent_data(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        compression_algorithm: &str,
+        monitoring_data: &[u8],
+        collected_at: u64,
+    ) -> AnyhowResult<()> {
+        self.for_connection(connection).agent_data(
+            base_url,
+            connection,
+            compression_algorithm,
+            monitoring_data,
+            collected_at,
+        )
+    }
+}
+
+impl AgentDataDelta for Transports {
+    fn agent_data_delta(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        compression_algorithm: &str,
+        delta: &[u8],
+        collected_at: u64,
+    ) -> AnyhowResult<()> {
+        self.for_connection(connection).agent_data_delta(
+            base_url,
+            connection,
+            compression_algorithm,
+            delta,
+            collected_at,
+        )
+    }
+}
+
+impl AgentDataChunk for Transports {
+    fn agent_data_chunk(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+        upload: &ChunkedUpload,
+        offset: u64,
+        chunk: &[u8],
+    ) -> AnyhowResult<u64> {
+        self.for_connection(connection)
+            .agent_data_chunk(base_url, connection, upload, offset, chunk)
+    }
+}
+
+impl RegistrationStatusV2 for Transports {
+    fn registration_status_v2(
+        &self,
+        base_url: &reqwest::Url,
+        connection: &config::TrustedConnection,
+    ) -> AnyhowResult<RegistrationStatusV2Response> {
+        self.for_connection(connection)
+            .registration_status_v2(base_url, connection)
+    }
+}
+
+#[cfg(test)]
+mod test_transport {
+    use super::*;
+    use crate::config::test_helpers;
+    use crate::{happy_eyeballs, proxy};
+
+    fn connection(transport: TransportKind) -> config::TrustedConnection {
+        config::TrustedConnection {
+            transport,
+            ..test_helpers::trusted_connection()
+        }
+    }
+
+    fn transports(quic_by_default: bool) -> Transports {
+        Transports::new(
+            || {
+                agent_receiver_api::Api::new(&config::ClientConfig {
+                    proxy_mode: proxy::ProxyMode::Direct,
+                    validate_api_cert: false,
+                    source_address: None,
+                    ip_preference: happy_eyeballs::IpPreference::Ipv6,
+                    request_headers: Default::default(),
+                    timeouts: Default::default(),
+                })
+            },
+            quic_by_default,
+        )
+    }
+
+    #[test]
+    fn test_mock_transport() {
+        let transports = transports(false);
+        let connection = connection(TransportKind::Mock);
+        let url = reqwest::Url::parse("https://unreachable.invalid:8000/site").unwrap();
+        assert!(transports
+            .agent_data(&url, &connection, "zlib", b"data", 1000)
+            .is_ok());
+        assert!(matches!(
+            transports
+                .registration_status_v2(&url, &connection)
+                .unwrap(),
+            RegistrationStatusV2Response::Registered(_)
+        ));
+    }
+
+    #[test]
+    fn test_for_connection() {
+        let addr = |transport: &dyn Transport| transport as *const dyn Transport as *const u8;
+        let transports = transports(false);
+        assert_eq!(
+            addr(transports.for_connection(&connection(TransportKind::WebSocket))),
+            addr(&transports.https)
+        );
+        assert_eq!(
+            addr(transports.for_connection(&connection(TransportKind::Quic))),
+            addr(&transports.quic)
+        );
+        let transports = self::transports(true);
+        assert_eq!(
+            addr(transports.for_connection(&connection(TransportKind::Https))),
+            addr(&transports.quic)
+        );
+        assert_eq!(
+            addr(transports.for_connection(&connection(TransportKind::Http2))),
+            addr(&transports.http2)
+        );
+    }
+
+    #[test]
+    fn test_serialization() {
+        assert_eq!(
+            serde_json::to_string(&TransportKind::WebSocket).unwrap(),
+            "\"websocket\""
+        );
+        assert_eq!(TransportKind::WebSocket.to_string(), "websocket");
+        assert_eq!(
+            TransportKind::from_str("http2", false).unwrap(),
+            TransportKind::Http2
+        );
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Anonymous counters of what the controller did, kept per day in a local file. Nothing is sent
+//! anywhere, the file only ends up in support bundles created by the user.
+
+use crate::config::{JSONLoader, JSONLoaderMissingSafe};
+use crate::error_code::{ErrorCategory, ErrorCode};
+use crate::{constants, misc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+/// Serializes the updates of the threads of the daemon
+static LOCK: Mutex<()> = Mutex::new(());
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Counters of a single day
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct DailyUsage {
+    /// Days since the Unix epoch (UTC)
+    pub day: u64,
+    #[serde(default)]
+    pub registrations: u64,
+    #[serde(default)]
+    pub renewal_attempts: u64,
+    #[serde(default)]
+    pub failed_renewals: u64,
+    #[serde(default)]
+    pub transport_errors: BTreeMap<ErrorCategory, u64>,
+}
+
+/// Counters of the most recent days, oldest first
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct UsageStats {
+    pub days: VecDeque<DailyUsage>,
+}
+
+impl JSONLoader for UsageStats {}
+impl JSONLoaderMissingSafe for UsageStats {}
+
+impl UsageStats {
+    fn update(&mut self, day: u64, f: impl FnOnce(&mut DailyUsage)) {
+        if self.days.back().map(|usage| usage.day) != Some(day) {
+            self.days.push_back(DailyUsage {
+                day,
+                ..DailyUsage::default()
+            });
+        }
+        while self.days.len() > constants::USAGE_STATS_DAYS {
+            self.days.pop_front();
+        }
+        if let Some(usage) = self.days.back_mut() {
+            f(usage)
+        }
+    }
+}
+
+/// Record to the given file from now on. Without it, nothing is recorded, eg. when the controller
+/// is used as library.
+pub fn init(path: &Path) {
+    // Set once at startup
+    let _ = PATH.set(PathBuf::from(path));
+}
+
+pub fn record_registration() {
+    record(|usage| usage.registrations += 1)
+}
+
+/// Count an attempt to renew a certificate, which failed with the given error, if any
+pub fn record_renewal(error: Option<&anyhow::Error>) {
+    record(|usage| {
+        usage.renewal_attempts += 1;
+        if error.is_some() {
+            usage.failed_renewals += 1;
+        }
+    });
+    if let Some(error) = error {
+        record_transport_error(error);
+    }
+}
+
+/// Count a failed call to or from a Checkmk site by the category of the error
+pub fn record_transport_error(error: &anyhow::Error) {
+    let category = ErrorCode::of(error).category();
+    record(|usage| *usage.transport_errors.entry(category).or_default() += 1)
+}
+
+fn record(f: impl FnOnce(&mut DailyUsage)) {
+    let Some(path) = PATH.get() else {
+        return;
+    };
+    if let Err(err) = update(path, misc::unix_now() / SECONDS_PER_DAY, f) {
+        warn!("Failed to write usage statistics to {:?}. ({})", path, err);
+    }
+}
+
+fn update(path: &Path, day: u64, f: impl FnOnce(&mut DailyUsage)) -> anyhow::Result<()> {
+    let _guard = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    // Loaded on every update, the daemon and the other modes write to the same file
+    let mut stats = UsageStats::load_missing_safe(path).unwrap_or_else(|err| {
+        warn!(
+            "Could not load usage statistics from {:?}, starting from scratch. ({})",
+            path, err
+        );
+        UsageStats::default()
+    });
+    stats.update(day, f);
+    Ok(save(path, &stats)?)
+}
+
+fn save(path: &Path, stats: &UsageStats) -> io::Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_string_pretty(stats)?)?;
+    fs::rename(&tmp_path, path)?;
+    #[cfg(unix)]
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_usage_stats {
+    use super::*;
+
+    #[test]
+    fn test_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usage_stats.json");
+        update(&path, 100, |usage| usage.registrations += 1).unwrap();
+        update(&path, 100, |usage| usage.renewal_attempts += 1).unwrap();
+        update(&path, 101, |usage| {
+            *usage
+                .transport_errors
+                .entry(ErrorCategory::Network)
+                .or_default() += 2
+        })
+        .unwrap();
+
+        let stats = UsageStats::load(&path).unwrap();
+        assert_eq!(
+            stats.days,
+            VecDeque::from([
+                DailyUsage {
+                    day: 100,
+                    registrations: 1,
+                    renewal_attempts: 1,
+                    ..DailyUsage::default()
+                },
+                DailyUsage {
+                    day: 101,
+                    transport_errors: BTreeMap::from([(ErrorCategory::Network, 2)]),
+                    ..DailyUsage::default()
+                },
+            ])
+        );
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains("\"transport_errors\": {\n        \"network\": 2"));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let mut stats = UsageStats::default();
+        for day in 0..(constants::USAGE_STATS_DAYS as u64 + 10) {
+            stats.update(day, |usage| usage.registrations += 1);
+        }
+        assert_eq!(stats.days.len(), constants::USAGE_STATS_DAYS);
+        assert_eq!(stats.days.front().unwrap().day, 10);
+    }
+}
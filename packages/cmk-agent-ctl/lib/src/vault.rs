@@ -0,0 +1,469 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Encrypted store for the connections proxy-register creates on behalf of other hosts, eg. on
+//! the provisioning servers of service providers registering hosts of several customers. Each
+//! tenant has a directory with its own key, st. the connections of one tenant can only be read
+//! with its key, and access can be granted per tenant via the permissions of its directory.
+//! The keys of the tenants are wrapped with the vault key, which is kept outside of the vault,
+//! st. a copy of the vault directory alone does not reveal any connection.
+
+use crate::site_spec;
+use anyhow::{bail, Context, Result as AnyhowResult};
+use openssl::symm::{self, Cipher};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+const WRAPPED_KEY_FILE: &str = "key.wrapped";
+const ENTRY_EXTENSION: &str = "json";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Data encrypted with AES-256-GCM, base64 encoded
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct Sealed {
+    nonce: String,
+    tag: String,
+    data: String,
+}
+
+impl Sealed {
+    fn seal(key: &[u8], associated_data: &[u8], plain: &[u8]) -> AnyhowResult<Self> {
+        let mut nonce = [0; NONCE_LEN];
+        openssl::rand::rand_bytes(&mut nonce)?;
+        let mut tag = [0; TAG_LEN];
+        let data = symm::encrypt_aead(
+            Cipher::aes_256_gcm(),
+            key,
+            Some(&nonce),
+            associated_data,
+            plain,
+            &mut tag,
+        )?;
+        Ok(Self {
+            nonce: openssl::base64::encode_block(&nonce),
+            tag: openssl::base64::encode_block(&tag),
+            data: openssl::base64::encode_block(&data),
+        })
+    }
+
+    fn open(&self, key: &[u8], associated_data: &[u8]) -> AnyhowResult<Vec<u8>> {
+        Ok(symm::decrypt_aead(
+            Cipher::aes_256_gcm(),
+            key,
+            Some(&openssl::base64::decode_block(&self.nonce)?),
+            associated_data,
+            &openssl::base64::decode_block(&self.data)?,
+            &openssl::base64::decode_block(&self.tag)?,
+        )?)
+    }
+}
+
+/// An encrypted connection. Host name and site are kept in plain text, st. the entries of a
+/// tenant can be listed without its key.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct Entry {
+    site_id: String,
+    uuid: String,
+    #[serde(flatten)]
+    sealed: Sealed,
+}
+
+/// Plain text information about a stored connection
+#[derive(Debug, PartialEq, Eq)]
+pub struct StoredConnection {
+    pub host_name: String,
+    pub site_id: String,
+    pub uuid: String,
+}
+
+pub struct Vault {
+    path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl Vault {
+    /// The vault key has to be outside of the vault directory
+    pub fn new(path: &Path, key_path: &Path) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            key_path: PathBuf::from(key_path),
+        }
+    }
+
+    pub fn tenant(&self, name: &str) -> AnyhowResult<Tenant> {
+        validate_name("tenant", name)?;
+        Ok(Tenant {
+            name: String::from(name),
+            path: self.path.join(name),
+            vault_key_path: self.key_path.clone(),
+        })
+    }
+
+    pub fn tenants(&self) -> AnyhowResult<Vec<String>> {
+        if !self.path.exists() {
+            return Ok(vec![]);
+        }
+        let mut tenants = vec![];
+        for entry in
+            fs::read_dir(&self.path).context(format!("Failed to read vault at {:?}", self.path))?
+        {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                tenants.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        tenants.sort();
+        Ok(tenants)
+    }
+}
+
+pub struct Tenant {
+    name: String,
+    path: PathBuf,
+    vault_key_path: PathBuf,
+}
+
+impl Tenant {
+    fn key_path(&self) -> PathBuf {
+        self.path.join(WRAPPED_KEY_FILE)
+    }
+
+    fn entry_path(&self, host_name: &str) -> AnyhowResult<PathBuf> {
+        validate_name("host", host_name)?;
+        Ok(self.path.join(format!("{host_name}.{ENTRY_EXTENSION}")))
+    }
+
+    fn key(&self) -> AnyhowResult<Vec<u8>> {
+        let wrapped: Sealed =
+            serde_json::from_str(&fs::read_to_string(self.key_path()).context(format!(
+                "Failed to read the key of tenant '{}', access denied or no connections stored",
+                self.name
+            ))?)
+            .context(format!("Invalid key of tenant '{}'", self.name))?;
+        let key = wrapped
+            .open(&vault_key(&self.vault_key_path)?, self.name.as_bytes())
+            .context(format!(
+                "Failed to unwrap the key of tenant '{}', it was not wrapped with the vault key {:?}",
+                self.name, self.vault_key_path
+            ))?;
+        if key.len() != KEY_LEN {
+            bail!("Invalid key of tenant '{}'", self.name)
+        }
+        Ok(key)
+    }
+
+    /// The key of the tenant, created along with its directory when storing the first connection
+    fn key_or_create(&self) -> AnyhowResult<Vec<u8>> {
+        if self.key_path().exists() {
+            return self.key();
+        }
+        let vault_key = vault_key_or_create(&self.vault_key_path)?;
+        create_private_dir(&self.path)?;
+        let mut key = vec![0; KEY_LEN];
+        openssl::rand::rand_bytes(&mut key)?;
+        let wrapped = Sealed::seal(&vault_key, self.name.as_bytes(), &key)?;
+        write_private(
+            &self.key_path(),
+            serde_json::to_string_pretty(&wrapped)?.as_bytes(),
+        )
+        .context(format!("Failed to create key of tenant '{}'", self.name))?;
+        Ok(key)
+    }
+
+    /// Tenant and host name are authenticated along with the connection, st. entries cannot be
+    /// moved to another tenant or host unnoticed
+    fn associated_data(&self, host_name: &str) -> Vec<u8> {
+        format!("{}/{}", self.name, host_name).into_bytes()
+    }
+
+    /// Check that a connection of the host can be stored, creating the key if necessary
+    pub fn prepare(&self, host_name: &str) -> AnyhowResult<()> {
+        self.entry_path(host_name)?;
+        self.key_or_create()?;
+        Ok(())
+    }
+
+    /// Store a connection, replacing a previous one of the host
+    pub fn store(
+        &self,
+        host_name: &str,
+        site_id: &site_spec::SiteID,
+        uuid: &uuid::Uuid,
+        connection: &str,
+    ) -> AnyhowResult<()> {
+        let path = self.entry_path(host_name)?;
+        let entry = Entry {
+            site_id: site_id.to_string(),
+            uuid: uuid.to_string(),
+            sealed: Sealed::seal(
+                &self.key_or_create()?,
+                &self.associated_data(host_name),
+                connection.as_bytes(),
+            )?,
+        };
+        write_private(&path, serde_json::to_string_pretty(&entry)?.as_bytes()).context(format!(
+            "Failed to store connection of {} for tenant '{}'",
+            host_name, self.name
+        ))
+    }
+
+    fn entry(&self, host_name: &str) -> AnyhowResult<Entry> {
+        let path = self.entry_path(host_name)?;
+        if !path.exists() {
+            bail!(
+                "No connection of host {} stored for tenant '{}'",
+                host_name,
+                self.name
+            )
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(&path)?)?)
+    }
+
+    /// The stored connection of a host, decrypted with the key of the tenant
+    pub fn load(&self, host_name: &str) -> AnyhowResult<String> {
+        let data = self
+            .entry(host_name)?
+            .sealed
+            .open(&self.key()?, &self.associated_data(host_name))
+            .context(format!(
+                "Failed to decrypt connection of {} for tenant '{}'",
+                host_name, self.name
+            ))?;
+        Ok(String::from_utf8(data)?)
+    }
+
+    pub fn remove(&self, host_name: &str) -> AnyhowResult<()> {
+        self.entry(host_name)?;
+        fs::remove_file(self.entry_path(host_name)?)?;
+        Ok(())
+    }
+
+    pub fn connections(&self) -> AnyhowResult<Vec<StoredConnection>> {
+        if !self.path.exists() {
+            bail!("Unknown tenant '{}'", self.name)
+        }
+        let mut connections = vec![];
+        for dir_entry in fs::read_dir(&self.path)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(ENTRY_EXTENSION) {
+                continue;
+            }
+            let Some(host_name) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let entry = self.entry(host_name)?;
+            connections.push(StoredConnection {
+                host_name: String::from(host_name),
+                site_id: entry.site_id,
+                uuid: entry.uuid,
+            });
+        }
+        connections.sort_by(|a, b| a.host_name.cmp(&b.host_name));
+        Ok(connections)
+    }
+}
+
+/// Names end up in paths, so only the characters Checkmk allows in host names are accepted
+fn validate_name(kind: &str, name: &str) -> AnyhowResult<()> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+    {
+        bail!("Invalid {kind} name '{name}'")
+    }
+    Ok(())
+}
+
+fn vault_key(path: &Path) -> AnyhowResult<Vec<u8>> {
+    let key = fs::read(path).context(format!("Failed to read the vault key {:?}", path))?;
+    if key.len() != KEY_LEN {
+        bail!("Invalid vault key {:?}", path)
+    }
+    Ok(key)
+}
+
+/// The key wrapping the keys of the tenants, created along with the first tenant
+fn vault_key_or_create(path: &Path) -> AnyhowResult<Vec<u8>> {
+    if path.exists() {
+        return vault_key(path);
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context(format!("Failed to create {:?}", parent))?;
+    }
+    let mut key = vec![0; KEY_LEN];
+    openssl::rand::rand_bytes(&mut key)?;
+    write_private(path, &key).context(format!("Failed to create the vault key {:?}", path))?;
+    Ok(key)
+}
+
+fn create_private_dir(path: &Path) -> AnyhowResult<()> {
+    fs::create_dir_all(path).context(format!("Failed to create {:?}", path))?;
+    #[cfg(unix)]
+    fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+    #[cfg(windows)]
+    crate::service_account::restrict_access(path)
+        .context(format!("Failed to restrict access to {path:?}"))?;
+    Ok(())
+}
+
+/// Write a file readable by the owner only, from the moment it is created. Under Windows, the
+/// access is restricted before anything is written.
+fn write_private(path: &Path, content: &[u8]) -> AnyhowResult<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    #[cfg(windows)]
+    crate::service_account::restrict_access(path)
+        .context(format!("Failed to restrict access to {path:?}"))?;
+    file.write_all(content)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_vault {
+    use super::*;
+    use std::str::FromStr;
+
+    const UUID: &str = "0096abd7-83c9-42f8-8b3a-3ffba7ba959d";
+
+    fn store(tenant: &Tenant, host_name: &str, connection: &str) -> AnyhowResult<()> {
+        tenant.store(
+            host_name,
+            &site_spec::SiteID::from_str("server/site").unwrap(),
+            &uuid::Uuid::from_str(UUID).unwrap(),
+            connection,
+        )
+    }
+
+    #[test]
+    fn test_store_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = Vault::new(&dir.path().join("vault"), &dir.path().join("vault_key"));
+        assert!(vault.tenants().unwrap().is_empty());
+        let tenant = vault.tenant("customer-a").unwrap();
+        store(&tenant, "web01", "{\"connection\": 1}").unwrap();
+        store(&tenant, "web02.example.com", "{\"connection\": 2}").unwrap();
+        store(&tenant, "web01", "{\"connection\": 3}").unwrap();
+
+        assert_eq!(tenant.load("web01").unwrap(), "{\"connection\": 3}");
+        assert_eq!(
+            tenant.load("web02.example.com").unwrap(),
+            "{\"connection\": 2}"
+        );
+        assert!(
+            !fs::read_to_string(tenant.entry_path("web02.example.com").unwrap())
+                .unwrap()
+                .contains("connection")
+        );
+        assert_eq!(
+            tenant.connections().unwrap(),
+            ["web01", "web02.example.com"].map(|host_name| StoredConnection {
+                host_name: String::from(host_name),
+                site_id: String::from("server/site"),
+                uuid: String::from(UUID),
+            })
+        );
+        #[cfg(unix)]
+        for path in [tenant.key_path(), dir.path().join("vault_key")] {
+            assert_eq!(
+                fs::metadata(path).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+
+        tenant.remove("web01").unwrap();
+        assert!(tenant.load("web01").is_err());
+        assert!(tenant.remove("web01").is_err());
+        assert_eq!(tenant.connections().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_tenant_isolation() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = Vault::new(&dir.path().join("vault"), &dir.path().join("vault_key"));
+        let tenant_a = vault.tenant("customer-a").unwrap();
+        let tenant_b = vault.tenant("customer-b").unwrap();
+        store(&tenant_a, "web01", "secret of a").unwrap();
+        store(&tenant_b, "web01", "secret of b").unwrap();
+        assert_eq!(vault.tenants().unwrap(), ["customer-a", "customer-b"]);
+        assert_ne!(tenant_a.key().unwrap(), tenant_b.key().unwrap());
+
+        // An entry moved to another tenant cannot be decrypted with its key
+        fs::copy(
+            tenant_a.entry_path("web01").unwrap(),
+            tenant_b.entry_path("web01").unwrap(),
+        )
+        .unwrap();
+        assert!(tenant_b.load("web01").is_err());
+        // Neither can one moved to another host
+        fs::copy(
+            tenant_a.entry_path("web01").unwrap(),
+            tenant_a.entry_path("web02").unwrap(),
+        )
+        .unwrap();
+        assert!(tenant_a.load("web02").is_err());
+        assert_eq!(tenant_a.load("web01").unwrap(), "secret of a");
+
+        // Without the key, the connections can be listed, but not read
+        fs::remove_file(tenant_a.key_path()).unwrap();
+        assert_eq!(tenant_a.connections().unwrap().len(), 2);
+        assert!(tenant_a.load("web01").is_err());
+    }
+
+    #[test]
+    fn test_vault_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault_key_path = dir.path().join("keys").join("vault_key");
+        let vault = Vault::new(&dir.path().join("vault"), &vault_key_path);
+        let tenant = vault.tenant("customer-a").unwrap();
+        store(&tenant, "web01", "secret").unwrap();
+        assert_eq!(fs::read(&vault_key_path).unwrap().len(), KEY_LEN);
+
+        // The vault directory alone does not contain the key of the tenant
+        let key = tenant.key().unwrap();
+        for dir_entry in fs::read_dir(&tenant.path).unwrap() {
+            let content = fs::read(dir_entry.unwrap().path()).unwrap();
+            assert!(!content.windows(KEY_LEN).any(|window| window == key));
+            assert!(!String::from_utf8(content)
+                .unwrap()
+                .contains(&openssl::base64::encode_block(&key)));
+        }
+
+        // Another vault key does not unwrap it
+        let saved_key = fs::read(&vault_key_path).unwrap();
+        fs::write(&vault_key_path, [0; KEY_LEN]).unwrap();
+        assert!(tenant.load("web01").is_err());
+        fs::remove_file(&vault_key_path).unwrap();
+        assert!(tenant.load("web01").is_err());
+        fs::write(&vault_key_path, saved_key).unwrap();
+        assert_eq!(tenant.load("web01").unwrap(), "secret");
+
+        // Wrapped keys are bound to their tenant
+        let tenant_b = vault.tenant("customer-b").unwrap();
+        store(&tenant_b, "web01", "secret of b").unwrap();
+        fs::copy(tenant.key_path(), tenant_b.key_path()).unwrap();
+        assert!(tenant_b.load("web01").is_err());
+    }
+
+    #[test]
+    fn test_names() {
+        let vault = Vault::new(Path::new("/vault"), Path::new("/vault_key"));
+        assert!(vault.tenant("customer_a.example-1").is_ok());
+        for name in ["", "..", ".hidden", "a/b", "a b"] {
+            assert!(vault.tenant(name).is_err());
+        }
+        let tenant = vault.tenant("customer-a").unwrap();
+        assert!(tenant.entry_path("../customer-b/web01").is_err());
+    }
+}
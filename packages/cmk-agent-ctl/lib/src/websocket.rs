@@ -0,0 +1,429 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! A minimal WebSocket client (RFC 6455), just enough for the pull tunnel: text and binary
+//! messages, ping, pong and close. Extensions and subprotocols are not supported. Messages are
+//! received by one task, but may be sent by many concurrently.
+
+use anyhow::{anyhow, bail, ensure, Context, Result as AnyhowResult};
+use openssl::base64;
+use openssl::sha::sha1;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE_RESPONSE_SIZE: usize = 8192;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Answer to a ping, pings from the server are answered right away
+    Pong,
+    Close,
+}
+
+pub struct WebSocket<S> {
+    reader: ReadHalf<S>,
+    sender: Sender<S>,
+    max_message_size: usize,
+}
+
+/// Sends messages through a WebSocket, frames of concurrent senders do not interleave
+pub struct Sender<S> {
+    writer: Arc<Mutex<WriteHalf<S>>>,
+}
+
+impl<S> Clone for Sender<S> {
+    fn clone(&self) -> Self {
+        Self {
+            writer: self.writer.clone(),
+        }
+    }
+}
+
+/// Upgrade the (usually TLS) stream to a WebSocket
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    host: &str,
+    path: &str,
+    max_message_size: usize,
+) -> AnyhowResult<WebSocket<S>> {
+    let key = base64::encode_block(&rand::random::<[u8; 16]>());
+    stream
+        .write_all(
+            format!(
+                "GET {path} HTTP/1.1\r\n\
+                 Host: {host}\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Key: {key}\r\n\
+                 Sec-WebSocket-Version: 13\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.flush().await?;
+    check_handshake_response(&read_handshake_response(&mut stream).await?, &key)?;
+    let (reader, writer) = tokio::io::split(stream);
+    Ok(WebSocket {
+        reader,
+        sender: Sender {
+            writer: Arc::new(Mutex::new(writer)),
+        },
+        max_message_size,
+    })
+}
+
+/// Read up to the end of the headers, but not beyond, the frames follow right away
+async fn read_handshake_response(stream: &mut (impl AsyncRead + Unpin)) -> AnyhowResult<String> {
+    let mut response = vec![];
+    while !response.ends_with(b"\r\n\r\n") {
+        ensure!(
+            response.len() < MAX_HANDSHAKE_RESPONSE_SIZE,
+            "WebSocket handshake response exceeds {} bytes",
+            MAX_HANDSHAKE_RESPONSE_SIZE
+        );
+        response.push(
+            stream
+                .read_u8()
+                .await
+                .context("Connection closed during WebSocket handshake")?,
+        );
+    }
+    String::from_utf8(response).context("WebSocket handshake response is not valid UTF-8")
+}
+
+fn accept_key(key: &str) -> String {
+    base64::encode_block(&sha1(format!("{key}{ACCEPT_GUID}").as_bytes()))
+}
+
+fn check_handshake_response(response: &str, key: &str) -> AnyhowResult<()> {
+    let mut lines = response.lines();
+    let status_line = lines.next().unwrap_or_default();
+    if status_line.split_whitespace().nth(1) != Some("101") {
+        bail!("Server refused to upgrade to WebSocket: {}", status_line);
+    }
+    let accept = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-accept"))
+        .map(|(_, value)| value.trim())
+        .context("WebSocket handshake response lacks Sec-WebSocket-Accept")?;
+    ensure!(
+        accept == accept_key(key),
+        "WebSocket handshake response has wrong Sec-WebSocket-Accept"
+    );
+    Ok(())
+}
+
+impl<S: AsyncRead + AsyncWrite> WebSocket<S> {
+    /// The next message, None if nothing arrived for the given time
+    pub async fn receive(&mut self, idle_timeout: Duration) -> AnyhowResult<Option<Message>> {
+        let mut fragments: Option<(u8, Vec<u8>)> = None;
+        loop {
+            // Only waiting for the first byte of a frame is cancelled, st. no frame is torn apart
+            let first_byte = if fragments.is_none() {
+                match tokio::time::timeout(idle_timeout, self.reader.read_u8()).await {
+                    Ok(first_byte) => first_byte?,
+                    Err(_) => return Ok(None),
+                }
+            } else {
+                self.reader.read_u8().await?
+            };
+            let (fin, opcode, payload) = self.read_frame(first_byte).await?;
+            match opcode {
+                OPCODE_PING => {
+                    self.sender.write_frame(OPCODE_PONG, &payload).await?;
+                    continue;
+                }
+                OPCODE_PONG => return Ok(Some(Message::Pong)),
+                OPCODE_CLOSE => {
+                    // Acknowledge, the server closes the connection
+                    let _ = self.sender.write_frame(OPCODE_CLOSE, &payload).await;
+                    return Ok(Some(Message::Close));
+                }
+                OPCODE_TEXT | OPCODE_BINARY if fragments.is_none() => {
+                    fragments = Some((opcode, payload))
+                }
+                OPCODE_CONTINUATION if fragments.is_some() => {
+                    let (_, message) = fragments.as_mut().unwrap();
+                    ensure!(
+                        message.len() + payload.len() <= self.max_message_size,
+                        "WebSocket message exceeds {} bytes",
+                        self.max_message_size
+                    );
+                    message.extend(payload);
+                }
+                opcode => bail!("Unexpected WebSocket frame with opcode {:#x}", opcode),
+            }
+            if fin {
+                return fragments
+                    .map(|(opcode, message)| match opcode {
+                        OPCODE_TEXT => String::from_utf8(message)
+                            .map(Message::Text)
+                            .map_err(|_| anyhow!("WebSocket text message is not valid UTF-8")),
+                        _ => Ok(Message::Binary(message)),
+                    })
+                    .transpose();
+            }
+        }
+    }
+
+    async fn read_frame(&mut self, first_byte: u8) -> AnyhowResult<(bool, u8, Vec<u8>)> {
+        ensure!(
+            first_byte & 0x70 == 0,
+            "WebSocket extensions are not supported"
+        );
+        let second_byte = self.reader.read_u8().await?;
+        ensure!(
+            second_byte & 0x80 == 0,
+            "WebSocket frames from the server must not be masked"
+        );
+        let len = match second_byte & 0x7f {
+            126 => u64::from(self.reader.read_u16().await?),
+            127 => self.reader.read_u64().await?,
+            len => u64::from(len),
+        };
+        ensure!(
+            len <= self.max_message_size as u64,
+            "WebSocket message exceeds {} bytes",
+            self.max_message_size
+        );
+        let mut payload = vec![0; len as usize];
+        self.reader.read_exact(&mut payload).await?;
+        Ok((first_byte & 0x80 != 0, first_byte & 0x0f, payload))
+    }
+
+    pub fn sender(&self) -> Sender<S> {
+        self.sender.clone()
+    }
+
+    pub async fn ping(&self) -> AnyhowResult<()> {
+        self.sender.ping().await
+    }
+}
+
+impl<S: AsyncWrite> Sender<S> {
+    async fn write_frame(&self, opcode: u8, payload: &[u8]) -> AnyhowResult<()> {
+        let mut frame = vec![0x80 | opcode];
+        // Frames from the client are always masked
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend((len as u64).to_be_bytes());
+            }
+        }
+        let mask = rand::random::<[u8; 4]>();
+        frame.extend(mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(index, byte)| byte ^ mask[index % 4]),
+        );
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&frame).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    pub async fn send(&self, message: Message) -> AnyhowResult<()> {
+        match message {
+            Message::Text(text) => self.write_frame(OPCODE_TEXT, text.as_bytes()).await,
+            Message::Binary(data) => self.write_frame(OPCODE_BINARY, &data).await,
+            Message::Pong => self.write_frame(OPCODE_PONG, &[]).await,
+            Message::Close => self.write_frame(OPCODE_CLOSE, &[]).await,
+        }
+    }
+
+    pub async fn ping(&self) -> AnyhowResult<()> {
+        self.write_frame(OPCODE_PING, &[]).await
+    }
+}
+
+#[cfg(test)]
+mod test_websocket {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    const IDLE: Duration = Duration::from_secs(5);
+
+    fn server_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len => {
+                frame.push(126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+        }
+        frame.extend(payload);
+        frame
+    }
+
+    /// Read a frame sent by the client and unmask it
+    async fn client_frame(server: &mut DuplexStream) -> (u8, Vec<u8>) {
+        let opcode = server.read_u8().await.unwrap() & 0x0f;
+        let second_byte = server.read_u8().await.unwrap();
+        assert_eq!(second_byte & 0x80, 0x80);
+        let len = match second_byte & 0x7f {
+            126 => server.read_u16().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut mask = [0; 4];
+        server.read_exact(&mut mask).await.unwrap();
+        let mut payload = vec![0; len];
+        server.read_exact(&mut payload).await.unwrap();
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+        (opcode, payload)
+    }
+
+    async fn accept(server: &mut DuplexStream, status: &str) -> String {
+        let request = read_handshake_response(server).await.unwrap();
+        let key = request
+            .lines()
+            .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+            .unwrap();
+        server
+            .write_all(
+                format!(
+                    "HTTP/1.1 {status}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                     Sec-WebSocket-Accept: {}\r\n\r\n",
+                    accept_key(key)
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        request
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // Example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn test_messages() {
+        let (client, mut server) = duplex(1 << 16);
+        let server = tokio::spawn(async move {
+            let request = accept(&mut server, "101 Switching Protocols").await;
+            assert!(request.starts_with("GET /site/tunnel HTTP/1.1\r\nHost: server:8000\r\n"));
+            server
+                .write_all(&server_frame(true, OPCODE_PING, b"ping"))
+                .await
+                .unwrap();
+            server
+                .write_all(&server_frame(false, OPCODE_TEXT, b"Hello, "))
+                .await
+                .unwrap();
+            server
+                .write_all(&server_frame(true, OPCODE_CONTINUATION, b"client"))
+                .await
+                .unwrap();
+            assert_eq!(
+                client_frame(&mut server).await,
+                (OPCODE_PONG, b"ping".to_vec())
+            );
+            assert_eq!(
+                client_frame(&mut server).await,
+                (OPCODE_BINARY, vec![7; 300])
+            );
+            server
+                .write_all(&server_frame(true, OPCODE_CLOSE, b""))
+                .await
+                .unwrap();
+            assert_eq!(client_frame(&mut server).await, (OPCODE_CLOSE, vec![]));
+        });
+        let mut websocket = connect(client, "server:8000", "/site/tunnel", 1024)
+            .await
+            .unwrap();
+        assert_eq!(
+            websocket.receive(IDLE).await.unwrap(),
+            Some(Message::Text(String::from("Hello, client")))
+        );
+        let sender = websocket.sender();
+        tokio::spawn(async move { sender.send(Message::Binary(vec![7; 300])).await })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(websocket.receive(IDLE).await.unwrap(), Some(Message::Close));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle() {
+        let (client, mut server) = duplex(1 << 16);
+        let server = tokio::spawn(async move {
+            accept(&mut server, "101 Switching Protocols").await;
+            assert_eq!(client_frame(&mut server).await, (OPCODE_PING, vec![]));
+            server
+                .write_all(&server_frame(true, OPCODE_PONG, b""))
+                .await
+                .unwrap();
+            server
+        });
+        let mut websocket = connect(client, "server", "/", 1024).await.unwrap();
+        assert_eq!(
+            websocket.receive(Duration::from_millis(10)).await.unwrap(),
+            None
+        );
+        websocket.ping().await.unwrap();
+        assert_eq!(websocket.receive(IDLE).await.unwrap(), Some(Message::Pong));
+        drop(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_refused() {
+        let (client, mut server) = duplex(1 << 16);
+        tokio::spawn(async move {
+            accept(&mut server, "404 Not Found").await;
+            server
+        });
+        assert_eq!(
+            connect(client, "server", "/", 1024)
+                .await
+                .err()
+                .unwrap()
+                .to_string(),
+            "Server refused to upgrade to WebSocket: HTTP/1.1 404 Not Found"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_too_large() {
+        let (client, mut server) = duplex(1 << 16);
+        let server = tokio::spawn(async move {
+            accept(&mut server, "101 Switching Protocols").await;
+            server
+                .write_all(&server_frame(true, OPCODE_BINARY, &[0; 200]))
+                .await
+                .unwrap();
+            server
+        });
+        let mut websocket = connect(client, "server", "/", 100).await.unwrap();
+        assert!(websocket.receive(IDLE).await.is_err());
+        drop(server.await.unwrap());
+    }
+}
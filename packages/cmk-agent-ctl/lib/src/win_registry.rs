@@ -0,0 +1,181 @@
+// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+//! Values of keys below HKEY_LOCAL_MACHINE, eg. as written by the MSI installer or a group policy
+
+use std::ffi::OsString;
+use std::io::{Error, Result as IoResult};
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use winapi::shared::minwindef::{DWORD, HKEY};
+use winapi::shared::winerror::{ERROR_NO_MORE_ITEMS, ERROR_SUCCESS};
+use winapi::um::{winnt, winreg};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    MultiString(Vec<String>),
+    Dword(u32),
+}
+
+impl Value {
+    /// None for value types we do not read, eg. binary ones
+    fn decode(value_type: DWORD, data: &[u8]) -> Option<Self> {
+        match value_type {
+            winnt::REG_SZ | winnt::REG_EXPAND_SZ => Some(Value::String(
+                utf16(data)
+                    .split(|&c| c == 0)
+                    .next()
+                    .map(String::from_utf16_lossy)
+                    .unwrap_or_default(),
+            )),
+            winnt::REG_MULTI_SZ => Some(Value::MultiString(
+                utf16(data)
+                    .split(|&c| c == 0)
+                    .filter(|s| !s.is_empty())
+                    .map(String::from_utf16_lossy)
+                    .collect(),
+            )),
+            winnt::REG_DWORD if data.len() >= 4 => Some(Value::Dword(u32::from_le_bytes([
+                data[0], data[1], data[2], data[3],
+            ]))),
+            _ => None,
+        }
+    }
+}
+
+fn utf16(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect()
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    std::ffi::OsStr::new(s)
+        .encode_wide()
+        .chain(Some(0))
+        .collect()
+}
+
+fn check(status: i32) -> IoResult<()> {
+    match status as DWORD {
+        ERROR_SUCCESS => Ok(()),
+        _ => Err(Error::from_raw_os_error(status)),
+    }
+}
+
+/// Open key, closed when dropped
+struct Key(HKEY);
+
+impl Key {
+    fn open(subkey: &str, access: winnt::REGSAM) -> IoResult<Self> {
+        let mut key: HKEY = std::ptr::null_mut();
+        check(unsafe {
+            winreg::RegOpenKeyExW(
+                winreg::HKEY_LOCAL_MACHINE,
+                wide(subkey).as_ptr(),
+                0,
+                access,
+                &mut key,
+            )
+        })?;
+        Ok(Self(key))
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        unsafe { winreg::RegCloseKey(self.0) };
+    }
+}
+
+/// All values of the given key we can read, by name
+pub fn values(subkey: &str) -> IoResult<Vec<(String, Value)>> {
+    let key = Key::open(subkey, winnt::KEY_READ)?;
+    let mut max_name_len: DWORD = 0;
+    let mut max_data_len: DWORD = 0;
+    check(unsafe {
+        winreg::RegQueryInfoKeyW(
+            key.0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut max_name_len,
+            &mut max_data_len,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    })?;
+    let mut values = vec![];
+    for index in 0.. {
+        // The lengths exclude the terminating null
+        let mut name = vec![0u16; max_name_len as usize + 1];
+        let mut name_len = name.len() as DWORD;
+        let mut data = vec![0u8; max_data_len as usize + 2];
+        let mut data_len = data.len() as DWORD;
+        let mut value_type: DWORD = 0;
+        let status = unsafe {
+            winreg::RegEnumValueW(
+                key.0,
+                index,
+                name.as_mut_ptr(),
+                &mut name_len,
+                std::ptr::null_mut(),
+                &mut value_type,
+                data.as_mut_ptr(),
+                &mut data_len,
+            )
+        };
+        if status as DWORD == ERROR_NO_MORE_ITEMS {
+            break;
+        }
+        check(status)?;
+        if let Some(value) = Value::decode(value_type, &data[..data_len as usize]) {
+            let name = OsString::from_wide(&name[..name_len as usize]);
+            values.push((name.to_string_lossy().into_owned(), value));
+        }
+    }
+    Ok(values)
+}
+
+/// Delete the given value of the given key, if present
+pub fn delete_value(subkey: &str, name: &str) -> IoResult<()> {
+    let key = Key::open(subkey, winnt::KEY_SET_VALUE)?;
+    match check(unsafe { winreg::RegDeleteValueW(key.0, wide(name).as_ptr()) }) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test_win_registry {
+    use super::*;
+
+    fn encode(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Value::decode(winnt::REG_SZ, &encode("checkmk.example.com\0")),
+            Some(Value::String(String::from("checkmk.example.com")))
+        );
+        assert_eq!(
+            Value::decode(winnt::REG_MULTI_SZ, &encode("env:prod\0os:windows\0\0")),
+            Some(Value::MultiString(vec![
+                String::from("env:prod"),
+                String::from("os:windows")
+            ]))
+        );
+        assert_eq!(
+            Value::decode(winnt::REG_DWORD, &1u32.to_le_bytes()),
+            Some(Value::Dword(1))
+        );
+        assert_eq!(Value::decode(winnt::REG_BINARY, &[1, 2]), None);
+    }
+}
@@ -56,19 +56,19 @@ if "%worker_arg_clean%" == "1" (
 :: Check Format
 if "%worker_arg_check_format%" == "1" (
     powershell Write-Host "Run Rust check format" -Foreground White
-    cargo fmt -- --check
+    cargo fmt --all -- --check
 )
 
 :: Format
 if "%worker_arg_format%" == "1" (
     powershell Write-Host "Run Rust format" -Foreground White
-    cargo fmt
+    cargo fmt --all
 )
 
 :: Clippy
 if "%worker_arg_clippy%" == "1" (
     powershell Write-Host "Run Rust clippy" -Foreground White
-    cargo clippy --release --target %worker_target% --tests -- --deny warnings
+    cargo clippy --release --workspace --target %worker_target% --tests -- --deny warnings
     if ERRORLEVEL 1 (
         powershell Write-Host "Failed cargo clippy" -Foreground Red
         exit /b 17
@@ -111,7 +111,7 @@ rem Validate elevation, because full testing is possible only in elevated mode!
         )
     )
     powershell Write-Host "Testing Rust executables" -Foreground White
-    cargo test --release --target %worker_target% -- --test-threads=4 2>&1
+    cargo test --release --workspace --target %worker_target% -- --test-threads=4 2>&1
     if ERRORLEVEL 1  (
         powershell Write-Host "Failed cargo test" -Foreground Red
         exit /b 19
@@ -151,7 +151,7 @@ if "%worker_arg_build%" == "1" (
 :: Documentation
 if "%worker_arg_doc%" == "1" (
     powershell Write-Host "Creating documentation" -Foreground White
-    cargo doc
+    cargo doc --workspace
 ) else (
     powershell Write-Host "Skip creating documentation" -Foreground Yellow
 )
@@ -1,13 +0,0 @@
-// Copyright (C) 2019 Checkmk GmbH - License: GNU General Public License v2
-// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
-// conditions defined in the file COPYING, which is part of this source code package.
-
-pub mod daemon;
-pub mod delete_connection;
-pub mod dump;
-pub mod import_connection;
-pub mod pull;
-pub mod push;
-pub mod registration;
-pub mod renew_certificate;
-pub mod status;
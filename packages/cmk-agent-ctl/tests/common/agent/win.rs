@@ -4,14 +4,14 @@
 
 use anyhow::Error as AnyhowError;
 use anyhow::Result as AnyhowResult;
-use cmk_agent_ctl::mailslot_transport::MailSlotBackend;
+use cmk_agent_ctl_lib::mailslot_transport::MailSlotBackend;
 use mail_slot::{MailslotClient, MailslotName};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
 /// creates mailslot port simulating agent
 pub async fn make_agent_response_peer() -> AnyhowResult<MailSlotBackend> {
-    use cmk_agent_ctl::mailslot_transport;
+    use cmk_agent_ctl_lib::mailslot_transport;
     let own_mailslot = mailslot_transport::build_own_mailslot_name() + "_agent_peer";
     MailSlotBackend::new(&own_mailslot).map_err(anyhow::Error::from)
 }
@@ -13,34 +13,69 @@ use anyhow::Result as AnyhowResult;
 use common::agent;
 
 use assert_cmd::prelude::OutputAssertExt;
-use cmk_agent_ctl::configuration::config;
+use cmk_agent_ctl_lib::configuration::config;
 #[cfg(unix)]
 use predicates::prelude::predicate;
 use std::fs;
 use std::path::Path;
 
-const SUPPORTED_MODES: [&str; 12] = [
+const SUPPORTED_MODES: [&str; 34] = [
+    "bootstrap",
+    "completions",
     "daemon",
+    "data-source",
     "delete",
     "delete-all",
+    "doctor",
     "dump",
     "help",
     "import",
+    "legacy-pull",
+    "log-level",
+    "migrate-pull-to-tls",
+    "migrate-updater",
+    "paths",
     "proxy-register",
     "pull",
+    "pull-once",
     "push",
+    "push-interval",
+    "push-now",
     "register",
     "register-new",
+    "relay",
+    "reload",
+    "rename-host",
+    "self-update",
+    "source-address",
+    "spool",
     "status",
+    "support-bundle",
+    "tag",
+    "test-connection",
+    "vault",
 ];
 
 lazy_static::lazy_static! {
     static ref REQUIRED_ARGUMENTS: std::collections::HashMap<&'static str, Vec<&'static str>> = {
         std::collections::HashMap::from([
             ("delete", vec!["some-connection"]),
+            ("pull-once", vec!["some-connection"]),
+            ("legacy-pull", vec!["status"]),
+            ("push-interval", vec!["some-connection"]),
             ("register", vec!["-s", "server", "-i", "site", "-U", "user", "-H", "host"]),
             ("proxy-register", vec!["-s", "server", "-i", "site", "-U", "user", "-H", "host"]),
             ("register-new", vec!["-s", "server", "-i", "site", "-U", "user"]),
+            ("source-address", vec!["some-connection"]),
+            ("test-connection", vec!["server/site"]),
+            ("relay", vec!["list"]),
+            ("completions", vec!["bash"]),
+            ("tag", vec!["some-connection"]),
+            ("rename-host", vec!["some-connection", "new-host", "-U", "user", "-P", "password"]),
+            ("vault", vec!["list"]),
+            ("spool", vec!["list"]),
+            ("migrate-pull-to-tls", vec!["-s", "server", "-i", "site", "-U", "user", "-H", "host"]),
+            ("data-source", vec!["some-connection"]),
         ])
     };
 }
@@ -68,6 +103,57 @@ fn test_help() {
     output.assert().success();
 }
 
+#[test]
+fn test_help_json() {
+    let output = common::controller_command().arg("--help-json").unwrap();
+    let help: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let modes: Vec<&str> = help["command"]["subcommands"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|mode| mode["name"].as_str().unwrap())
+        .collect();
+    for mode in SUPPORTED_MODES {
+        assert!(modes.contains(&mode));
+    }
+    output.assert().success();
+}
+
+#[test]
+fn test_completions() {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_completions-");
+    let output = common::controller_command()
+        .env("DEBUG_HOME_DIR", test_dir.path())
+        .args(["completions", "bash"])
+        .unwrap();
+    assert!(std::str::from_utf8(&output.stdout)
+        .unwrap()
+        .contains("complete -o bashdefault -o default -F _cmk_agent_ctl cmk-agent-ctl"));
+    output.assert().success();
+    // Listing the connections does not create the registry
+    common::controller_command()
+        .env("DEBUG_HOME_DIR", test_dir.path())
+        .args(["completions", "--connections"])
+        .assert()
+        .success()
+        .stdout("");
+    assert!(!test_dir.path().join("registered_connections.json").exists());
+}
+
+#[test]
+fn test_support_bundle() {
+    let test_dir = common::setup_test_dir("cmk_agent_ctl_test_support_bundle-");
+    let bundle = test_dir.path().join("bundle.tar.gz");
+    common::controller_command()
+        .env("DEBUG_HOME_DIR", test_dir.path())
+        .args(["support-bundle", "--output"])
+        .arg(&bundle)
+        .assert()
+        .success();
+    // gzip magic number
+    assert_eq!(fs::read(&bundle).unwrap()[..2], [0x1f, 0x8b]);
+}
+
 #[cfg(unix)]
 #[tokio::test(flavor = "multi_thread")]
 async fn test_dump() -> AnyhowResult<()> {
@@ -135,7 +221,12 @@ fn test_fail_socket_missing() {
 
         match mode {
             // these commands are expected to fail due to missing socket
-            "register" | "register-new" | "import" => {
+            "register"
+            | "register-new"
+            | "import"
+            | "bootstrap"
+            | "migrate-updater"
+            | "migrate-pull-to-tls" => {
                 let err = output_res.unwrap_err();
                 let stderr = std::str::from_utf8(&err.as_output().unwrap().stderr).unwrap();
                 assert!(stderr.contains(error_message_socket));
@@ -177,7 +268,8 @@ fn test_migration_is_always_triggered() {
     let path_registry = test_dir.path().join("registered_connections.json");
 
     for mode in SUPPORTED_MODES {
-        if mode == "help" {
+        // Completing a command line and showing the paths leave the registry alone
+        if matches!(mode, "help" | "completions" | "paths") {
             continue;
         }
         write_legacy_registry(&path_registry);
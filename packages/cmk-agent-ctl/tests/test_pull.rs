@@ -7,7 +7,7 @@
 #![allow(dead_code)]
 mod common;
 use anyhow::{bail, Context, Result as AnyhowResult};
-use cmk_agent_ctl::{certs as lib_certs, configuration::config, site_spec};
+use cmk_agent_ctl_lib::{certs as lib_certs, configuration::config, site_spec};
 use common::agent;
 use common::certs::{self, X509Certs};
 use std::io::{Read, Result as IoResult, Write};
@@ -22,6 +22,9 @@ const PULL_TLS_CHECK_PORT: u16 = 9980;
 const PULL_LEGACY_PORT: u16 = 9990;
 const PULL_NO_CONNECTION_PORT: u16 = 10000;
 const PULL_RELOAD_PORT: u16 = 10010;
+const PULL_ONCE_PORT: u16 = 10020;
+const PULL_QUIC_PORT: u16 = 10030;
+const PULL_RELAY_PORT: u16 = 10040;
 
 const FREE_RANGE_PORT_START: u16 = 12400;
 const FREE_RANGE_PORT_END: u16 = FREE_RANGE_PORT_START + 4096;
@@ -41,8 +44,16 @@ fn registry(
                 private_key: String::from_utf8(certs.controller_private_key.clone()).unwrap(),
                 certificate: String::from_utf8(certs.controller_cert.clone()).unwrap(),
                 root_cert: String::from_utf8(certs.ca_cert.clone()).unwrap(),
+                source_address: None,
+                receiver_protocol: Default::default(),
+                transport: Default::default(),
+                data_source: None,
             },
             receiver_port: 1234,
+            push_interval: None,
+            hostname: None,
+            tags: Default::default(),
+            expires: None,
         },
     );
     registry
@@ -107,6 +118,27 @@ fn tls_client_connection(certs: X509Certs, address: &str) -> rustls::ClientConne
     rustls::ClientConnection::new(client_config, server_name).unwrap()
 }
 
+fn quic_client_config(certs: X509Certs) -> quinn::ClientConfig {
+    let root_cert =
+        lib_certs::rustls_certificate(&String::from_utf8(certs.ca_cert).unwrap()).unwrap();
+    let client_cert =
+        lib_certs::rustls_certificate(&String::from_utf8(certs.receiver_cert).unwrap()).unwrap();
+    let private_key =
+        lib_certs::rustls_private_key(&String::from_utf8(certs.receiver_private_key).unwrap())
+            .unwrap();
+
+    let mut root_cert_store = rustls::RootCertStore::empty();
+    root_cert_store.add(&root_cert).unwrap();
+
+    let mut client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_cert_store)
+        .with_client_auth_cert(vec![client_cert, root_cert], private_key)
+        .unwrap();
+    client_config.alpn_protocols = vec![b"cmk-agent-pull".to_vec()];
+    quinn::ClientConfig::new(std::sync::Arc::new(client_config))
+}
+
 struct PullProcessFixture {
     process: Child,
 }
@@ -194,7 +226,7 @@ struct TrustFixture {
 }
 
 impl TrustFixture {
-    fn setup(test_path: &Path) -> IoResult<Self> {
+    fn setup(test_path: &Path) -> AnyhowResult<Self> {
         let controller_uuid = uuid::Uuid::new_v4();
         let certs = certs::X509Certs::new("Test CA", "Test receiver", &controller_uuid.to_string());
         registry(
@@ -303,6 +335,47 @@ async fn _test_pull_tls_main(prefix: &str, ip_addr: IpAddr, port: u16) -> Anyhow
         .context("Teardown failed")
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_once() -> AnyhowResult<()> {
+    if agent::is_elevation_required() {
+        println!("Test is skipped, must be in elevated mode");
+        return Ok(());
+    }
+
+    let test_dir = common::setup_test_dir("test_pull_once");
+    let agent_stream_fixture = AgentStreamFixture::setup(test_dir.path());
+    let trust_fixture = TrustFixture::setup(test_dir.path())?;
+    let p = find_available_port_if_busy(PULL_ONCE_PORT);
+    let pull_proc_fixture = PullProcessFixture::setup(
+        test_dir.path(),
+        &p,
+        agent_stream_fixture.get_agent_channel(),
+    )?;
+
+    // Give it some time to provide the TCP socket
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    // Pull via the registered connection, presenting its own certificate
+    let test_path = test_dir.path().to_owned();
+    let output = tokio::task::spawn_blocking(move || {
+        common::controller_command()
+            .env("DEBUG_HOME_DIR", test_path)
+            .args(["pull-once", &trust_fixture.uuid, "--port", &p.to_string()])
+            .timeout(std::time::Duration::from_secs(5))
+            .output()
+    })
+    .await??;
+    assert!(output.status.success());
+    assert_eq!(
+        std::str::from_utf8(&output.stdout)?,
+        AgentStreamFixture::test_agent_output()
+    );
+
+    teardown(test_dir, pull_proc_fixture, Some(agent_stream_fixture))
+        .await
+        .context("Teardown failed")
+}
+
 fn find_available_port_if_busy(proposed_port: u16) -> u16 {
     let mut port = proposed_port;
     while !agent::is_port_available(port) {
@@ -542,3 +615,156 @@ async fn _test_pull_reload(prefix: &str, ip_addr: IpAddr, port: u16) -> AnyhowRe
         .await
         .context("Teardown failed")
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_quic() -> AnyhowResult<()> {
+    if agent::is_elevation_required() {
+        println!("Test is skipped, must be in elevated mode");
+        return Ok(());
+    }
+
+    let test_dir = common::setup_test_dir("test_pull_quic");
+    std::fs::write(test_dir.path().join("cmk-agent-ctl.toml"), "quic = true\n")?;
+    let agent_stream_fixture = AgentStreamFixture::setup(test_dir.path());
+    let trust_fixture = TrustFixture::setup(test_dir.path())?;
+    let p = find_available_port_if_busy(PULL_QUIC_PORT);
+    let pull_proc_fixture = PullProcessFixture::setup(
+        test_dir.path(),
+        &p,
+        agent_stream_fixture.get_agent_channel(),
+    )?;
+
+    // Give it some time to provide the UDP socket
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    let endpoint = quinn::Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))?;
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), p);
+
+    // Talk to the pull thread successfully
+    let connection = endpoint
+        .connect_with(
+            quic_client_config(trust_fixture.certs.clone()),
+            socket_addr,
+            &trust_fixture.uuid,
+        )?
+        .await?;
+    let mut stream = connection.accept_uni().await?;
+    assert_eq!(
+        stream.read_to_end(1 << 20).await?,
+        agent_stream_fixture.compressed_agent_output()?
+    );
+
+    // Request a connection which is not registered
+    assert!(endpoint
+        .connect_with(
+            quic_client_config(trust_fixture.certs.clone()),
+            socket_addr,
+            &uuid::Uuid::new_v4().to_string(),
+        )?
+        .await
+        .is_err());
+
+    teardown(test_dir, pull_proc_fixture, Some(agent_stream_fixture))
+        .await
+        .context("Teardown failed")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pull_relay() -> AnyhowResult<()> {
+    if agent::is_elevation_required() {
+        println!("Test is skipped, must be in elevated mode");
+        return Ok(());
+    }
+
+    let agent_dir = common::setup_test_dir("test_pull_relay_agent");
+    let relay_dir = common::setup_test_dir("test_pull_relay");
+    let agent_stream_fixture = AgentStreamFixture::setup(agent_dir.path());
+    let agent_port = find_available_port_if_busy(PULL_RELAY_PORT);
+    let relay_port = find_available_port_if_busy(PULL_RELAY_PORT + 1);
+
+    // Pair the relay with the agent, which imports the connection issued by the relay
+    let relay_path = relay_dir.path().to_owned();
+    let agent_path = agent_dir.path().to_owned();
+    tokio::task::spawn_blocking(move || -> AnyhowResult<()> {
+        let output = common::controller_command()
+            .env("DEBUG_HOME_DIR", &relay_path)
+            .args([
+                "relay",
+                "pair",
+                "--name",
+                "inner",
+                "--address",
+                &format!("127.0.0.1:{agent_port}"),
+            ])
+            .output()?;
+        assert!(output.status.success());
+        std::fs::write(agent_path.join("pairing.json"), output.stdout)?;
+        common::controller_command()
+            .env("DEBUG_HOME_DIR", &agent_path)
+            .args(["import", &agent_path.join("pairing.json").to_string_lossy()])
+            .assert()
+            .success();
+        Ok(())
+    })
+    .await??;
+    let pull_proc_fixture = PullProcessFixture::setup(
+        agent_dir.path(),
+        &agent_port,
+        agent_stream_fixture.get_agent_channel(),
+    )?;
+
+    // Registering with a site needs an agent receiver, add the connection directly
+    let site_uuid = uuid::Uuid::new_v4().to_string();
+    let site_certs = X509Certs::new("Site CA", "Test receiver", &site_uuid);
+    let relay_file = relay_dir.path().join("relay.json");
+    let mut relay_data: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&relay_file)?)?;
+    relay_data["agents"]["inner"]["site"] = serde_json::json!({
+        "site_id": "some_server/some_site",
+        "connection": {
+            "uuid": site_uuid,
+            "private_key": String::from_utf8(site_certs.controller_private_key.clone())?,
+            "certificate": String::from_utf8(site_certs.controller_cert.clone())?,
+            "root_cert": String::from_utf8(site_certs.ca_cert.clone())?,
+        },
+    });
+    std::fs::write(&relay_file, relay_data.to_string())?;
+    let mut relay_process = Command::new(assert_cmd::cargo::cargo_bin("cmk-agent-ctl"))
+        .env("DEBUG_HOME_DIR", relay_dir.path())
+        .env("DEBUG_CONNECTION_TIMEOUT", "1")
+        .args(["relay", "serve", "--port", &relay_port.to_string()])
+        .spawn()?;
+
+    // Give it some time to provide the TCP sockets
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+    // Pull the agent via the relay, as the site
+    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), relay_port);
+    let mut id_buf: [u8; 2] = [0; 2];
+    let mut message_buf: Vec<u8> = vec![];
+    let mut client_connection = tls_client_connection(site_certs.clone(), &site_uuid);
+    let mut tcp_stream = std::net::TcpStream::connect(socket_addr)?;
+    tcp_stream.read_exact(&mut id_buf)?;
+    assert_eq!(&id_buf, b"16");
+    let mut tls_stream = rustls::Stream::new(&mut client_connection, &mut tcp_stream);
+    tls_stream.read_to_end(&mut message_buf)?;
+    assert_eq!(message_buf, agent_stream_fixture.compressed_agent_output()?);
+
+    // The site cannot request the connection the agent trusts the relay with
+    let pairing_uuid = relay_data["agents"]["inner"]["pairing_uuid"]
+        .as_str()
+        .context("No pairing UUID")?
+        .to_owned();
+    let mut client_connection = tls_client_connection(site_certs, &pairing_uuid);
+    let mut tcp_stream = std::net::TcpStream::connect(socket_addr)?;
+    tcp_stream.read_exact(&mut id_buf)?;
+    assert_eq!(&id_buf, b"16");
+    let mut tls_stream = rustls::Stream::new(&mut client_connection, &mut tcp_stream);
+    assert!(tls_stream.read_to_end(&mut message_buf).is_err());
+
+    relay_process.kill().await?;
+    relay_dir.close()?;
+    teardown(agent_dir, pull_proc_fixture, Some(agent_stream_fixture))
+        .await
+        .context("Teardown failed")
+}
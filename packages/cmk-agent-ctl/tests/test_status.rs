@@ -51,9 +51,8 @@ fn test_status_socket_down() {
     let mut cmd = common::controller_command();
     cmd.env("DEBUG_HOME_DIR", "/hurz/barz")
         .arg("status")
-        .unwrap()
         .assert()
-        .success()
+        .code(2)
         .stdout(
             predicate::str::contains("No connections")
                 .and(predicate::str::contains("Agent socket: inoperational (!!)")),